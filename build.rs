@@ -0,0 +1,38 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::process::Command;
+
+/// Records the commit built and the date of the build for `data-sifter info`. Either is left
+/// unset if it cannot be told, such as when building from a source archive without git
+fn main() {
+    if let Some(hash) = output_of("git", &["rev-parse", "--short=12", "HEAD"]) {
+        println!("cargo:rustc-env=DATA_SIFTER_GIT_HASH={}", hash);
+    }
+    if let Some(date) = output_of("date", &["-u", "+%Y-%m-%d"]) {
+        println!("cargo:rustc-env=DATA_SIFTER_BUILD_DATE={}", date);
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    println!("cargo:rerun-if-changed=src");
+}
+
+fn output_of(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok().filter(|output| output.status.success())?;
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_owned()).filter(|output| !output.is_empty())
+}
@@ -0,0 +1,266 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use async_std::io::{Write, WriteExt};
+use eyre::Result;
+use itertools::Itertools;
+
+/// How many rows the widths of the columns are computed from. Later rows are fitted to those
+/// widths, so that results of any size are shown without holding them all
+pub(crate) const WIDTH_SAMPLE: usize = 1000;
+/// Marks a cell cut short to fit its column
+const ELLIPSIS: char = '…';
+/// Separates the columns of a row
+const SEPARATOR: &str = " | ";
+
+/// Writes query results as a table aligned in columns, for reading on a terminal
+pub(crate) struct AlignedWriter<W> {
+    writer: W,
+    /// The widest a column may be, in terminal columns
+    max_width: usize,
+    header: Vec<String>,
+    /// The rows waiting for the widths of the columns to be known
+    sample: Vec<Vec<String>>,
+    /// The widths of the columns, once computed from the sample
+    widths: Option<Vec<usize>>,
+    /// How many cells were cut short
+    truncated: u64
+}
+
+impl<W> AlignedWriter<W> where W: Write + Unpin {
+
+    pub(crate) fn new(writer: W, max_width: usize) -> Self {
+        Self { writer, max_width: max_width.max(1), header: Vec::new(), sample: Vec::new(), widths: None, truncated: 0 }
+    }
+
+    pub(crate) fn write_header(&mut self, columns: Vec<String>) {
+        self.header = columns.iter().map(|column| printable(column)).collect();
+    }
+
+    pub(crate) async fn write_row(&mut self, row: Vec<String>) -> Result<()> {
+        let row: Vec<String> = row.iter().map(|cell| printable(cell)).collect();
+        match &self.widths {
+            Some(widths) => {
+                let (line, truncated) = fit(widths, &row);
+                self.truncated += truncated;
+                self.writer.write_all(line.as_bytes()).await?;
+            },
+            None => {
+                self.sample.push(row);
+                if self.sample.len() == WIDTH_SAMPLE {
+                    self.write_sample().await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn flush(&mut self) -> Result<()> {
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    /// Writes any rows left in the sample, returning the writer and how many cells were cut
+    /// short to fit their columns
+    pub(crate) async fn finish(mut self) -> Result<(W, u64)> {
+        if self.widths.is_none() {
+            self.write_sample().await?;
+        }
+        self.writer.flush().await?;
+        Ok((self.writer, self.truncated))
+    }
+
+    /// Computes the widths of the columns from the rows so far, then writes the header and
+    /// those rows
+    async fn write_sample(&mut self) -> Result<()> {
+        let mut widths = self.header.iter().map(|column| width(column)).collect::<Vec<_>>();
+        for row in &self.sample {
+            for (column_width, cell) in widths.iter_mut().zip(row) {
+                *column_width = (*column_width).max(width(cell));
+            }
+        }
+        for column_width in &mut widths {
+            *column_width = (*column_width).clamp(1, self.max_width);
+        }
+        // The header is not counted among the truncated cells
+        let (mut table, _) = fit(&widths, &self.header);
+        table.push_str(&widths.iter().map(|column_width| "-".repeat(*column_width)).join("-+-"));
+        table.push('\n');
+        for row in std::mem::take(&mut self.sample) {
+            let (line, truncated) = fit(&widths, &row);
+            table.push_str(&line);
+            self.truncated += truncated;
+        }
+        self.writer.write_all(table.as_bytes()).await?;
+        self.widths = Some(widths);
+        Ok(())
+    }
+}
+
+/// Lays out a row in columns of the given widths, cutting short the cells which are wider.
+/// Returns the line along with how many cells were cut short
+fn fit(widths: &[usize], row: &[String]) -> (String, u64) {
+    let mut line = String::new();
+    let mut truncated = 0;
+    for (index, (column_width, cell)) in widths.iter().zip(row).enumerate() {
+        if index != 0 {
+            line.push_str(SEPARATOR);
+        }
+        let (cell, cell_width) = match truncate(cell, *column_width) {
+            Some(cut) => {
+                truncated += 1;
+                cut
+            },
+            None => (cell.clone(), width(cell))
+        };
+        line.push_str(&cell);
+        // The last column is not padded, leaving no trailing spaces
+        if index + 1 != widths.len() {
+            line.extend(std::iter::repeat_n(' ', column_width - cell_width));
+        }
+    }
+    let trimmed = line.trim_end_matches(' ').len();
+    line.truncate(trimmed);
+    line.push('\n');
+    (line, truncated)
+}
+
+/// The cell with the characters which would break the layout replaced. A line break becomes ↵,
+/// and a tab or other control character a space
+pub(crate) fn printable(cell: &str) -> String {
+    let cell = cell.replace("\r\n", "\n");
+    cell.chars()
+        .map(|c| match c {
+            '\n' | '\r' => '↵',
+            c if c.is_control() => ' ',
+            c => c
+        })
+        .collect()
+}
+
+/// The cell cut short to fit the width, ending with an ellipsis, along with its width, if it
+/// is wider
+fn truncate(cell: &str, max_width: usize) -> Option<(String, usize)> {
+    if width(cell) <= max_width {
+        return None;
+    }
+    let mut cut = String::new();
+    let mut cut_width = 0;
+    for c in cell.chars() {
+        let char_width = char_width(c);
+        if cut_width + char_width > max_width - 1 {
+            break;
+        }
+        cut.push(c);
+        cut_width += char_width;
+    }
+    cut.push(ELLIPSIS);
+    Some((cut, cut_width + 1))
+}
+
+/// How many terminal columns the text takes up
+pub(crate) fn width(text: &str) -> usize {
+    text.chars().map(char_width).sum()
+}
+
+/// How many terminal columns a character takes up: none for combining marks and other
+/// zero-width characters, two for wide characters such as CJK, and otherwise one
+pub(crate) fn char_width(c: char) -> usize {
+    const ZERO_WIDTH: [(u32, u32); 9] = [
+        (0x0300, 0x036F), (0x0483, 0x0489), (0x0591, 0x05BD), (0x1AB0, 0x1AFF), (0x1DC0, 0x1DFF),
+        (0x200B, 0x200F), (0x20D0, 0x20FF), (0xFE00, 0xFE0F), (0xFE20, 0xFE2F)
+    ];
+    const WIDE: [(u32, u32); 14] = [
+        (0x1100, 0x115F), (0x2E80, 0x303E), (0x3041, 0x33FF), (0x3400, 0x4DBF), (0x4E00, 0x9FFF),
+        (0xA000, 0xA4CF), (0xAC00, 0xD7A3), (0xF900, 0xFAFF), (0xFE30, 0xFE4F), (0xFF00, 0xFF60),
+        (0xFFE0, 0xFFE6), (0x1F300, 0x1F64F), (0x1F900, 0x1F9FF), (0x20000, 0x3FFFD)
+    ];
+    let code = u32::from(c);
+    let within = |ranges: &[(u32, u32)]| ranges.iter().any(|(start, end)| (*start..=*end).contains(&code));
+    if within(&ZERO_WIDTH) {
+        0
+    } else if within(&WIDE) {
+        2
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn render(max_width: usize, header: &[&str], rows: &[&[&str]]) -> (String, u64) {
+        let strings = |cells: &[&str]| cells.iter().map(|cell| String::from(*cell)).collect::<Vec<_>>();
+        let mut output = Vec::new();
+        let mut writer = AlignedWriter::new(&mut output, max_width);
+        writer.write_header(strings(header));
+        for row in rows {
+            writer.write_row(strings(row)).await.unwrap();
+        }
+        let (_, truncated) = writer.finish().await.unwrap();
+        (String::from_utf8(output).unwrap(), truncated)
+    }
+
+    #[async_std::test]
+    async fn align_columns() {
+        let (table, truncated) = render(10, &["id", "name"], &[&["1", "ann"], &["20", "a very long name"]]).await;
+        assert_eq!("\
+id | name
+---+-----------
+1  | ann
+20 | a very lo…
+", table);
+        assert_eq!(1, truncated);
+    }
+
+    #[async_std::test]
+    async fn measure_wide_and_combining_characters() {
+        // Each of 東京 takes two columns, and the accent of é none
+        let (table, truncated) = render(5, &["city", "x"], &[
+            &["東京", "1"], &["Cafe\u{301}", "2"], &["北京市中心", "3"]
+        ]).await;
+        assert_eq!("\
+city  | x
+------+--
+東京  | 1
+Cafe\u{301}  | 2
+北京… | 3
+", table);
+        assert_eq!(1, truncated);
+        // A wide character which would straddle the edge is left out
+        assert_eq!(Some((String::from("a…"), 2)), truncate("a東京", 3));
+        assert_eq!(None, truncate("Cafe\u{301}", 4));
+    }
+
+    #[async_std::test]
+    async fn replace_tabs_and_line_breaks() {
+        let (table, _) = render(20, &["note"], &[&["a\tb"], &["two\r\nlines\n"]]).await;
+        assert_eq!("note\n----------\na b\ntwo↵lines↵\n", table);
+    }
+
+    #[async_std::test]
+    async fn later_rows_keep_the_widths_of_the_sample() {
+        let mut rows = vec![vec!["x"]; WIDTH_SAMPLE];
+        rows.push(vec!["wider"]);
+        let rows = rows.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        let (table, truncated) = render(10, &["v"], &rows).await;
+        assert!(table.starts_with("v\n-\nx\n"), "{}", table);
+        assert!(table.ends_with("x\n…\n"), "{}", table);
+        assert_eq!(1, truncated);
+    }
+}
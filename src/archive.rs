@@ -0,0 +1,313 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use async_compression::futures::bufread::DeflateDecoder;
+use async_std::fs::File;
+use async_std::io::{self, BufRead, BufReader, Read};
+use async_std::io::prelude::{ReadExt, SeekExt};
+use async_std::path::Path;
+use eyre::{eyre, WrapErr};
+use crate::database;
+use crate::dialect::Dialect;
+use crate::error::{Categorize, Error, Result};
+
+/// The end of central directory record is at most this far from the end of the archive,
+/// since its comment is at most 65535 bytes
+const END_SEARCH_BYTES: u64 = 22 + 65535;
+const END_SIGNATURE: u32 = 0x0605_4b50;
+const CENTRAL_SIGNATURE: u32 = 0x0201_4b50;
+const LOCAL_SIGNATURE: u32 = 0x0403_4b50;
+/// How much of an entry is read to tell whether it is separated by commas or tabs
+const DETECT_BYTES: u64 = 64 * 1024;
+
+/// A CSV or TSV dataset within a ZIP archive, read from the archive as it is imported
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ArchiveEntry {
+    /// The name of the entry, with the directories it is in, such as 2022/sales.csv
+    pub(crate) name: String,
+    /// Whether the entry is compressed with deflate, rather than stored as it is
+    deflated: bool,
+    compressed_size: u64,
+    /// The size once decompressed
+    pub(crate) size: u64,
+    crc: u32,
+    /// Where the entry's local header starts
+    header_offset: u64
+}
+
+/// Whether a dataset given by the user is a ZIP archive of datasets
+pub(crate) fn is_archive(input: &str) -> bool {
+    let input = input.trim();
+    input.to_ascii_lowercase().ends_with(".zip") && !input.starts_with("http://") && !input.starts_with("https://")
+}
+
+/// Lists the CSV and TSV files in the archive, in the order the archive lists them. Folders of
+/// resource forks which macOS adds to archives are left out
+pub(crate) async fn list_datasets(path: &Path) -> Result<Vec<ArchiveEntry>> {
+    let unreadable = || format!("Unable to read ZIP archive {}", path.display());
+    let mut file = File::open(path).await.wrap_err_with(unreadable).categorize(Error::ImportIo)?;
+    let length = file.metadata().await.wrap_err_with(unreadable).categorize(Error::ImportIo)?.len();
+    let tail_start = length.saturating_sub(END_SEARCH_BYTES);
+    let mut tail = Vec::new();
+    file.seek(SeekFrom::Start(tail_start)).await.wrap_err_with(unreadable).categorize(Error::ImportIo)?;
+    file.read_to_end(&mut tail).await.wrap_err_with(unreadable).categorize(Error::ImportIo)?;
+    let end = (0..tail.len().saturating_sub(21)).rev()
+        .find(|start| u32_at(&tail, *start) == END_SIGNATURE)
+        .ok_or_else(|| invalid(path, "it has no central directory"))?;
+    let entries = u16_at(&tail, end + 10);
+    let directory_size = u32_at(&tail, end + 12);
+    let directory_offset = u32_at(&tail, end + 16);
+    if entries == u16::MAX || directory_offset == u32::MAX {
+        return Err(invalid(path, "it is a ZIP64 archive, which is not supported. Extract it first"));
+    }
+
+    let mut directory = vec![0; directory_size as usize];
+    file.seek(SeekFrom::Start(directory_offset.into())).await.wrap_err_with(unreadable).categorize(Error::ImportIo)?;
+    file.read_exact(&mut directory).await.wrap_err_with(unreadable).categorize(Error::ImportIo)?;
+    let mut datasets = Vec::new();
+    let mut start = 0;
+    for _ in 0..entries {
+        if start + 46 > directory.len() || u32_at(&directory, start) != CENTRAL_SIGNATURE {
+            return Err(invalid(path, "its central directory is cut short"));
+        }
+        let flags = u16_at(&directory, start + 8);
+        let method = u16_at(&directory, start + 10);
+        let name_length = u16_at(&directory, start + 28) as usize;
+        let extra_length = u16_at(&directory, start + 30) as usize;
+        let comment_length = u16_at(&directory, start + 32) as usize;
+        let name_end = (start + 46 + name_length).min(directory.len());
+        let name = String::from_utf8_lossy(&directory[start + 46..name_end]).into_owned();
+        let entry = ArchiveEntry {
+            name,
+            deflated: method == 8,
+            compressed_size: u32_at(&directory, start + 20).into(),
+            size: u32_at(&directory, start + 24).into(),
+            crc: u32_at(&directory, start + 16),
+            header_offset: u32_at(&directory, start + 42).into()
+        };
+        start = name_end + extra_length + comment_length;
+        if !entry.is_dataset() {
+            continue;
+        }
+        if flags & 1 != 0 {
+            return Err(invalid(path, &format!("{} is encrypted", entry.name)));
+        }
+        if !matches!(method, 0 | 8) {
+            return Err(invalid(path, &format!(
+                "{} is compressed with method {}, rather than deflated or stored", entry.name, method
+            )));
+        }
+        datasets.push(entry);
+    }
+    Ok(datasets)
+}
+
+fn invalid(path: &Path, reason: &str) -> Error {
+    Error::ImportData(eyre!("Unable to read ZIP archive {}: {}", path.display(), reason))
+}
+
+fn u16_at(bytes: &[u8], start: usize) -> u16 {
+    u16::from_le_bytes([bytes[start], bytes[start + 1]])
+}
+
+fn u32_at(bytes: &[u8], start: usize) -> u32 {
+    u32::from_le_bytes([bytes[start], bytes[start + 1], bytes[start + 2], bytes[start + 3]])
+}
+
+impl ArchiveEntry {
+
+    fn is_dataset(&self) -> bool {
+        let name = self.name.to_ascii_lowercase();
+        let file_name = name.rsplit('/').next().unwrap_or_default();
+        (name.ends_with(".csv") || name.ends_with(".tsv")) && !name.starts_with("__macosx/")
+            && !file_name.starts_with("._")
+    }
+
+    /// A name for the entry's table, with the directories it is in joined by underscores, e.g.
+    /// 2022_sales for 2022/sales.csv. Other characters which cannot be in a table name become
+    /// underscores too
+    pub(crate) fn table_stem(&self) -> String {
+        let without_extension = self.name.rsplit_once('.').map_or(self.name.as_str(), |(stem, _)| stem);
+        let stem = without_extension.to_lowercase().replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+        let stem = stem.trim_matches('_');
+        match stem {
+            "" => String::from("data"),
+            stem if database::is_plain_identifier(stem) => String::from(stem),
+            stem => format!("_{}", stem)
+        }
+    }
+
+    /// Reads the entry out of the archive, decompressing it as it is read. Its checksum is
+    /// checked once it has all been read
+    pub(crate) async fn open(&self, archive: &Path) -> Result<Box<dyn BufRead + Unpin + Send>> {
+        let unreadable = || format!("Unable to read {} in ZIP archive {}", self.name, archive.display());
+        let mut file = File::open(archive).await.wrap_err_with(unreadable).categorize(Error::ImportIo)?;
+        let mut header = [0; 30];
+        file.seek(SeekFrom::Start(self.header_offset)).await.wrap_err_with(unreadable).categorize(Error::ImportIo)?;
+        file.read_exact(&mut header).await.wrap_err_with(unreadable).categorize(Error::ImportIo)?;
+        if u32_at(&header, 0) != LOCAL_SIGNATURE {
+            return Err(invalid(archive, &format!("the header of {} is missing", self.name)));
+        }
+        let data_start = self.header_offset + 30 + u64::from(u16_at(&header, 26)) + u64::from(u16_at(&header, 28));
+        file.seek(SeekFrom::Start(data_start)).await.wrap_err_with(unreadable).categorize(Error::ImportIo)?;
+        let compressed = BufReader::new(file.take(self.compressed_size));
+        let contents: Box<dyn Read + Unpin + Send> = if self.deflated {
+            Box::new(DeflateDecoder::new(compressed))
+        } else {
+            Box::new(compressed)
+        };
+        Ok(Box::new(BufReader::new(Checked { reader: contents, entry: self.clone(), crc: 0, read: 0 })))
+    }
+
+    /// The dialect of the entry, which is the one given unless the first line of the entry
+    /// has more tabs than commas, in which case its fields are taken to be separated by tabs
+    pub(crate) async fn detect_dialect(&self, archive: &Path, dialect: Dialect) -> Result<Dialect> {
+        let mut start = Vec::new();
+        self.open(archive).await?.take(DETECT_BYTES).read_to_end(&mut start).await
+            .wrap_err_with(|| format!("Unable to read {} in ZIP archive {}", self.name, archive.display()))
+            .categorize(Error::ImportIo)?;
+        let first_line = start.split(|byte| *byte == b'\n').next().unwrap_or_default();
+        let count = |delimiter: u8| first_line.iter().filter(|byte| **byte == delimiter).count();
+        Ok(if count(b'\t') > count(b',') { dialect.with_tabs() } else { dialect })
+    }
+}
+
+/// Chooses entries by their numbers, counted from 1, as in '1,3-4', or all of them. Returns
+/// their positions in order
+pub(crate) fn choose_entries(choice: &str, entries: usize) -> eyre::Result<Vec<usize>> {
+    let choice = choice.trim();
+    if choice.is_empty() || choice.eq_ignore_ascii_case("all") {
+        return Ok((0..entries).collect());
+    }
+    let mut chosen = Vec::new();
+    for item in choice.split(',').map(str::trim) {
+        let number = |number: &str| match number.trim().parse::<usize>() {
+            Ok(number) if (1..=entries).contains(&number) => Ok(number - 1),
+            _ => Err(eyre!("Invalid dataset number {}. The datasets are numbered from 1 to {}", number.trim(), entries))
+        };
+        let (first, last) = match item.split_once('-') {
+            Some((first, last)) => (number(first)?, number(last)?),
+            None => (number(item)?, number(item)?)
+        };
+        if first > last {
+            return Err(eyre!("Invalid range {}, which ends before it starts", item));
+        }
+        chosen.extend((first..=last).filter(|position| !chosen.contains(position)).collect::<Vec<_>>());
+    }
+    Ok(chosen)
+}
+
+/// Reads the contents of an entry, checking their size and checksum once they end, so that a
+/// corrupt archive is not imported as if it were whole
+struct Checked {
+    reader: Box<dyn Read + Unpin + Send>,
+    entry: ArchiveEntry,
+    crc: u32,
+    read: u64
+}
+
+impl Read for Checked {
+    fn poll_read(mut self: Pin<&mut Self>, context: &mut Context<'_>, buffer: &mut [u8]) -> Poll<io::Result<usize>> {
+        let read = match Pin::new(&mut self.reader).poll_read(context, buffer) {
+            Poll::Ready(Ok(read)) => read,
+            poll => return poll
+        };
+        if read == 0 && (self.read != self.entry.size || self.crc != self.entry.crc) {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "{} in the ZIP archive is corrupt, since its checksum does not match", self.entry.name
+            ))));
+        }
+        self.crc = crc32(self.crc, &buffer[..read]);
+        self.read += read as u64;
+        Poll::Ready(Ok(read))
+    }
+}
+
+/// The CRC-32 checksum ZIP archives use, continued over more bytes
+fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in bytes {
+        crc = CRC_TABLE[((crc ^ u32::from(*byte)) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::path::PathBuf;
+
+    fn fixture() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/vendor.zip")
+    }
+
+    #[async_std::test]
+    async fn list_and_read_datasets() -> Result<()> {
+        let entries = list_datasets(&fixture()).await?;
+        let names = entries.iter().map(|entry| entry.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["orders.csv", "reference/regions.tsv", "reference_regions.csv", "2022/Sales Q1.csv"], names);
+        let stems = entries.iter().map(ArchiveEntry::table_stem).collect::<Vec<_>>();
+        assert_eq!(vec!["orders", "reference_regions", "reference_regions", "_2022_sales_q1"], stems);
+
+        let mut text = String::new();
+        entries[1].open(&fixture()).await?.read_to_string(&mut text).await.unwrap();
+        assert!(text.starts_with("region\tname\n"), "{}", text);
+        let dialect = entries[1].detect_dialect(&fixture(), Dialect::default()).await?;
+        assert_eq!("tab-delimited", dialect.delimiter_name());
+        let dialect = entries[0].detect_dialect(&fixture(), Dialect::default()).await?;
+        assert_eq!("comma-delimited", dialect.delimiter_name());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn corrupt_entries_are_found() {
+        let mut entries = list_datasets(&fixture()).await.unwrap();
+        entries[0].crc ^= 1;
+        let mut text = String::new();
+        let error = entries[0].open(&fixture()).await.unwrap().read_to_string(&mut text).await.unwrap_err();
+        assert_eq!("orders.csv in the ZIP archive is corrupt, since its checksum does not match", error.to_string());
+    }
+
+    #[test]
+    fn choose_some_entries() {
+        assert_eq!(vec![0, 1, 2], choose_entries("all", 3).unwrap());
+        assert_eq!(vec![0, 2, 1], choose_entries("1, 3, 2-3", 3).unwrap());
+        assert!(choose_entries("4", 3).is_err());
+        assert_eq!(0xCBF4_3926, crc32(0, b"123456789"));
+    }
+}
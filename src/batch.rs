@@ -0,0 +1,160 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashSet;
+use std::path::Component;
+use async_std::fs;
+use async_std::path::{Path, PathBuf};
+use eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+use crate::output::OutputFormat;
+
+/// Several named queries, each of whose results is written to a file of its own, as given
+/// with --batch
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BatchSpec {
+    pub(crate) queries: Vec<BatchQuery>,
+    /// Whether a failed query stops the rest from running. Otherwise, each failure is reported
+    /// and the next query runs
+    #[serde(default)]
+    pub(crate) stop_on_error: bool
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BatchQuery {
+    /// Names the output file, unless it is given, and the query in messages
+    pub(crate) name: String,
+    pub(crate) query: String,
+    /// 'csv' or 'json', defaulting to csv
+    #[serde(default)]
+    format: Option<String>,
+    /// The output file, relative to the output directory, which it may not lead out of.
+    /// Defaults to the name with the format's extension
+    #[serde(default)]
+    output: Option<String>
+}
+
+impl BatchSpec {
+
+    pub(crate) async fn load(path: &Path) -> Result<Self> {
+        let spec = fs::read_to_string(path).await
+            .wrap_err_with(|| format!("Unable to read batch spec {}", path.display()))?;
+        Self::parse(&spec).wrap_err_with(|| format!("Invalid batch spec {}", path.display()))
+    }
+
+    fn parse(spec: &str) -> Result<Self> {
+        let spec: Self = ron::from_str(spec)?;
+        if spec.queries.is_empty() {
+            return Err(eyre!("It lists no queries"));
+        }
+        let mut names = HashSet::new();
+        for query in &spec.queries {
+            if query.name.is_empty()
+                || !query.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                return Err(eyre!(
+                    "Invalid query name {:?}. Names may only contain letters, digits, underscores and dashes",
+                    query.name
+                ));
+            }
+            if !names.insert(query.name.to_ascii_lowercase()) {
+                return Err(eyre!("More than one query is named {}", query.name));
+            }
+            if query.query.trim().is_empty() {
+                return Err(eyre!("Query {} is empty", query.name));
+            }
+            query.format()?;
+            if let Some(output) = &query.output {
+                let leaves = std::path::Path::new(output).components()
+                    .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+                if leaves || output.trim().is_empty() {
+                    return Err(eyre!(
+                        "Invalid output {:?} for query {}. It must be a file in the output directory, so it can \
+                        neither be absolute nor contain ..", output, query.name
+                    ));
+                }
+            }
+        }
+        Ok(spec)
+    }
+}
+
+impl BatchQuery {
+
+    pub(crate) fn format(&self) -> Result<OutputFormat> {
+        let format = match &self.format {
+            None => return Ok(OutputFormat::Csv),
+            Some(format) => format
+        };
+        OutputFormat::ALL.into_iter()
+            .find(|candidate| candidate.extension() == format)
+            .ok_or_else(|| eyre!("Invalid format {} for query {}. Valid formats are: csv, json", format, self.name))
+    }
+
+    /// Where the results are written, in the output directory
+    pub(crate) fn output_file(&self, output_dir: &Path) -> Result<PathBuf> {
+        Ok(match &self.output {
+            Some(output) => output_dir.join(output),
+            None => output_dir.join(format!("{}.{}", self.name, self.format()?.extension()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_spec() -> Result<()> {
+        let spec = BatchSpec::parse(r#"(
+            queries: [
+                (name: "totals", query: "SELECT sum(total::int) FROM data"),
+                (name: "people", query: "SELECT * FROM data", format: Some("json"), output: Some("everyone.json")),
+            ],
+        )"#)?;
+        assert!(!spec.stop_on_error);
+        let (totals, people) = (&spec.queries[0], &spec.queries[1]);
+        assert_eq!(OutputFormat::Csv, totals.format()?);
+        assert_eq!(PathBuf::from("out/totals.csv"), totals.output_file(Path::new("out"))?);
+        assert_eq!(OutputFormat::Json, people.format()?);
+        assert_eq!(PathBuf::from("out/everyone.json"), people.output_file(Path::new("out"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_invalid_specs() {
+        let invalid = |spec: &str| BatchSpec::parse(spec).unwrap_err().to_string();
+        assert_eq!("It lists no queries", invalid("(queries: [])"));
+        assert_eq!(
+            "More than one query is named Totals",
+            invalid(r#"(queries: [(name: "totals", query: "SELECT 1"), (name: "Totals", query: "SELECT 2")])"#)
+        );
+        assert!(invalid(r#"(queries: [(name: "../totals", query: "SELECT 1")])"#).starts_with("Invalid query name"));
+        assert_eq!("Query totals is empty", invalid(r#"(queries: [(name: "totals", query: " ")])"#));
+        assert_eq!(
+            "Invalid format xlsx for query totals. Valid formats are: csv, json",
+            invalid(r#"(queries: [(name: "totals", query: "SELECT 1", format: Some("xlsx"))])"#)
+        );
+        assert!(BatchSpec::parse(r#"(queries: [], stop_on_eror: true)"#).is_err());
+        for output in ["/etc/cron.d/totals", "../totals.csv", "results/../../totals.csv", ""] {
+            let spec = format!(r#"(queries: [(name: "totals", query: "SELECT 1", output: Some({:?}))])"#, output);
+            assert!(invalid(&spec).starts_with(&format!("Invalid output {:?} for query totals", output)), "{}", output);
+        }
+        assert!(BatchSpec::parse(r#"(queries: [(name: "totals", query: "SELECT 1", output: Some("./sums.csv"))])"#).is_ok());
+    }
+}
@@ -0,0 +1,316 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::str::FromStr;
+use eyre::{eyre, Result};
+use sqlx::{Postgres, pool::PoolConnection};
+use crate::database::{column_argument, column_identifier, find_column, quote_literal, split_arguments};
+use crate::error::{self, Error};
+use crate::timestamp::Timezone;
+
+const USAGE: &str = "Usage: bucket <column> <hour|day|week|month|year> [count|sum:<column>]";
+/// How many values which cannot be cast are quoted
+const EXAMPLES_SHOWN: usize = 5;
+
+/// Counts or sums over time, written as `bucket <column> <granularity> [count|sum:<column>]`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TimeBuckets {
+    pub(crate) table: String,
+    pub(crate) column: String,
+    pub(crate) granularity: Granularity,
+    pub(crate) measure: Measure,
+    /// The timezone whose days, weeks and so on the buckets follow. Without one, the
+    /// database's timezone is used
+    pub(crate) timezone: Option<String>
+}
+
+/// How long each bucket is
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Granularity {
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year
+}
+
+impl Granularity {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Hour => "hour",
+            Self::Day => "day",
+            Self::Week => "week",
+            Self::Month => "month",
+            Self::Year => "year"
+        }
+    }
+}
+
+impl FromStr for Granularity {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Ok(match value.to_ascii_lowercase().as_str() {
+            "hour" => Self::Hour,
+            "day" => Self::Day,
+            "week" => Self::Week,
+            "month" => Self::Month,
+            "year" => Self::Year,
+            _ => return Err(eyre!("Unknown granularity {}. {}", value, USAGE))
+        })
+    }
+}
+
+/// What is aggregated in each bucket
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Measure {
+    Count,
+    Sum(String)
+}
+
+/// How a column's values are read in the generated query
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ColumnKind {
+    /// Text, which is cast. Empty values are left out
+    Text,
+    /// timestamptz, an instant in time
+    Instant,
+    /// date or timestamp, which are wall-clock times already
+    WallClock,
+    Numeric
+}
+
+impl TimeBuckets {
+
+    /// Parses the arguments following `bucket`. The columns may be named in double quotes
+    pub(crate) fn parse(table: String, arguments: &str, timezone: Timezone) -> Result<Self> {
+        let arguments = split_arguments(arguments, &[])?;
+        let mut arguments = arguments.iter();
+        let (column, granularity) = match (arguments.next(), arguments.next()) {
+            (Some(column), Some(granularity)) => (column, granularity.parse()?),
+            _ => return Err(eyre!(USAGE))
+        };
+        let measure = match arguments.next() {
+            None => Measure::Count,
+            Some(measure) if measure.eq_ignore_ascii_case("count") => Measure::Count,
+            Some(measure) => match measure.split_once(':') {
+                Some((sum, column)) if sum.eq_ignore_ascii_case("sum") => Measure::Sum(column_argument(column)?),
+                _ => return Err(eyre!("Unknown measure {}. {}", measure, USAGE))
+            }
+        };
+        if let Some(extra) = arguments.next() {
+            return Err(eyre!("Unexpected argument {}. {}", extra, USAGE));
+        }
+        let timezone = match timezone {
+            Timezone::Utc => Some(String::from("UTC")),
+            Timezone::Named(timezone) => Some(String::from(timezone.name())),
+            // Postgres does not know the local timezone by name
+            Timezone::Local => None
+        };
+        Ok(Self {
+            table,
+            column: column_argument(column)?,
+            granularity,
+            measure,
+            timezone
+        })
+    }
+
+    /// Finds the columns in the table and checks that their values can be cast, then generates
+    /// the query with the names they have there
+    pub(crate) async fn to_sql(&self, connection: &mut PoolConnection<Postgres>) -> error::Result<String> {
+        let columns: Vec<(String, String, String)> = sqlx::query_as("\
+            SELECT attribute.attname::text, type.typname::text, type.typcategory::text \
+            FROM pg_catalog.pg_attribute attribute \
+            JOIN pg_catalog.pg_type type ON type.oid = attribute.atttypid \
+            WHERE attribute.attrelid = to_regclass($1) AND attribute.attnum > 0 AND NOT attribute.attisdropped \
+            ORDER BY attribute.attnum")
+            .bind(&self.table)
+            .fetch_all(&mut *connection).await
+            .map_err(|error| Error::from_database(error, Error::QueryRuntime))?;
+        let table = &self.table;
+        let kind_of = |name: &str, numeric: bool| {
+            let (name, type_name, category) = find_column(table, name, &columns, |(column, _, _)| column)?;
+            let kind = match (category.as_str(), type_name.as_str()) {
+                ("S", _) => ColumnKind::Text,
+                ("N", _) if numeric => ColumnKind::Numeric,
+                (_, "timestamptz") if !numeric => ColumnKind::Instant,
+                (_, "timestamp" | "date") if !numeric => ColumnKind::WallClock,
+                _ => return Err(Error::Usage(eyre!(
+                    "Column {} is of type {}, so it cannot be {}", name, type_name,
+                    if numeric { "summed" } else { "bucketed by time" }
+                )))
+            };
+            Ok((name.clone(), kind))
+        };
+        let (column, time_kind) = kind_of(&self.column, false)?;
+        let sum = match &self.measure {
+            Measure::Count => None,
+            Measure::Sum(column) => Some(kind_of(column, true)?)
+        };
+        let mut resolved = Self { column, ..self.clone() };
+        if time_kind == ColumnKind::Text {
+            self.check_cast(&resolved.column, "timestamptz", connection).await?;
+        }
+        let sum_kind = match sum {
+            None => None,
+            Some((column, kind)) => {
+                if kind == ColumnKind::Text {
+                    self.check_cast(&column, "numeric", connection).await?;
+                }
+                resolved.measure = Measure::Sum(column);
+                Some(kind)
+            }
+        };
+        Ok(resolved.sql_for(time_kind, sum_kind))
+    }
+
+    /// Checks that the values of a text column can be cast to the type, quoting a few which cannot
+    async fn check_cast(&self, column: &str, type_name: &str,
+                        connection: &mut PoolConnection<Postgres>) -> error::Result<()> {
+        let invalid = uncastable(&self.table, column, type_name, connection).await?;
+        if invalid.is_empty() {
+            return Ok(());
+        }
+        Err(Error::QueryRuntime(eyre!(
+            "Column {} has values which cannot be cast to {}, such as {}", column, type_name, invalid.join(", ")
+        )))
+    }
+
+    fn sql_for(&self, time_kind: ColumnKind, sum_kind: Option<ColumnKind>) -> String {
+        let timezone = match &self.timezone {
+            Some(timezone) => quote_literal(timezone),
+            None => String::from("current_setting('TimeZone')")
+        };
+        // Buckets are truncated and stepped through in wall-clock time, so that days stay whole
+        // across daylight saving changes
+        let column = column_identifier(&self.column);
+        let (time, zoned) = match time_kind {
+            ColumnKind::Text => (format!("NULLIF({}, '')::timestamptz AT TIME ZONE {}", column, timezone), true),
+            ColumnKind::Instant => (format!("{} AT TIME ZONE {}", column, timezone), true),
+            _ => (format!("{}::timestamp", column), false)
+        };
+        let granularity = self.granularity.name();
+        let (measure, aggregate) = match (&self.measure, sum_kind) {
+            (Measure::Sum(column), Some(ColumnKind::Text)) => {
+                ("sum", format!("sum(NULLIF({}, '')::numeric)", column_identifier(column)))
+            },
+            (Measure::Sum(column), _) => ("sum", format!("sum({})", column_identifier(column))),
+            (Measure::Count, _) => ("count", String::from("count(*)"))
+        };
+        let bucket = if zoned {
+            format!("series.bucket AT TIME ZONE {}", timezone)
+        } else {
+            String::from("series.bucket")
+        };
+        format!("\
+WITH buckets AS (
+    SELECT date_trunc('{granularity}', {time}) AS bucket, {aggregate} AS {measure}
+    FROM {table}
+    WHERE {time} IS NOT NULL
+    GROUP BY 1
+)
+SELECT {bucket} AS bucket, coalesce(buckets.{measure}, 0) AS {measure}
+FROM generate_series(
+    (SELECT min(bucket) FROM buckets), (SELECT max(bucket) FROM buckets), interval '1 {granularity}'
+) AS series (bucket)
+LEFT JOIN buckets ON buckets.bucket = series.bucket
+ORDER BY series.bucket", table = self.table)
+    }
+}
+
+/// Finds a few non-empty values of a text column which cannot be cast to the type, quoted so that
+/// they can be shown
+pub(crate) async fn uncastable(table: &str, column: &str, type_name: &str,
+                               connection: &mut PoolConnection<Postgres>) -> error::Result<Vec<String>> {
+    // Casting row by row, the offending values can be told apart
+    sqlx::query("\
+        CREATE OR REPLACE FUNCTION pg_temp.data_sifter_castable(value text, target regtype) \
+        RETURNS boolean LANGUAGE plpgsql AS $$ \
+        BEGIN EXECUTE format('SELECT %L::%s', value, target); RETURN true; \
+        EXCEPTION WHEN data_exception THEN RETURN false; END $$")
+        .execute(&mut *connection).await
+        .map_err(|error| Error::from_database(error, Error::QueryRuntime))?;
+    let invalid: Vec<(String,)> = sqlx::query_as(&format!(
+        "SELECT DISTINCT {column} FROM {} WHERE {column} <> '' \
+        AND NOT pg_temp.data_sifter_castable({column}, '{}') ORDER BY 1 LIMIT {}",
+        table, type_name, EXAMPLES_SHOWN, column = column_identifier(column)
+    )).fetch_all(&mut *connection).await.map_err(Error::from_query)?;
+    Ok(invalid.iter().map(|(value,)| format!("{:?}", value)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(arguments: &str) -> Result<TimeBuckets> {
+        TimeBuckets::parse(String::from("events"), arguments, Timezone::Utc)
+    }
+
+    #[test]
+    fn parse_bucket() {
+        let buckets = parse("Created DAY").unwrap();
+        assert_eq!(("Created", Granularity::Day, Measure::Count), (
+            buckets.column.as_str(), buckets.granularity, buckets.measure
+        ));
+        assert_eq!(Some(String::from("UTC")), buckets.timezone);
+        assert_eq!(Measure::Sum(String::from("Amount")), parse("created week sum:Amount").unwrap().measure);
+        let buckets = parse("\"Created At\" week sum:\"Amount Due\"").unwrap();
+        assert_eq!(
+            ("Created At", Measure::Sum(String::from("Amount Due"))), (buckets.column.as_str(), buckets.measure)
+        );
+        assert_eq!(Measure::Count, parse("created year count").unwrap().measure);
+        assert!(parse("created").is_err());
+        assert!(parse("created fortnight").is_err());
+        assert!(parse("created day avg:amount").is_err());
+        assert!(parse("created day sum:\"amount").is_err());
+        assert!(parse("created day count extra").is_err());
+        assert!(parse("\"\" day").is_err());
+        let local = TimeBuckets::parse(String::from("events"), "created hour", Timezone::Local).unwrap();
+        assert_eq!(None, local.timezone);
+    }
+
+    #[test]
+    fn bucket_sql() {
+        assert_eq!("\
+WITH buckets AS (
+    SELECT date_trunc('month', NULLIF(created, '')::timestamptz AT TIME ZONE 'UTC') AS bucket, \
+sum(NULLIF(amount, '')::numeric) AS sum
+    FROM events
+    WHERE NULLIF(created, '')::timestamptz AT TIME ZONE 'UTC' IS NOT NULL
+    GROUP BY 1
+)
+SELECT series.bucket AT TIME ZONE 'UTC' AS bucket, coalesce(buckets.sum, 0) AS sum
+FROM generate_series(
+    (SELECT min(bucket) FROM buckets), (SELECT max(bucket) FROM buckets), interval '1 month'
+) AS series (bucket)
+LEFT JOIN buckets ON buckets.bucket = series.bucket
+ORDER BY series.bucket", parse("created month sum:amount").unwrap().sql_for(ColumnKind::Text, Some(ColumnKind::Text)));
+
+        let mut buckets = parse("created day").unwrap();
+        buckets.timezone = None;
+        let sql = buckets.sql_for(ColumnKind::WallClock, None);
+        assert!(sql.contains("SELECT date_trunc('day', created::timestamp) AS bucket, count(*) AS count\n"), "{}", sql);
+        assert!(sql.contains("SELECT series.bucket AS bucket, coalesce(buckets.count, 0) AS count\n"), "{}", sql);
+
+        let buckets = parse("\"Created At\" day sum:Amount").unwrap();
+        let sql = buckets.sql_for(ColumnKind::Instant, Some(ColumnKind::Numeric));
+        assert!(sql.contains(
+            "SELECT date_trunc('day', \"Created At\" AT TIME ZONE 'UTC') AS bucket, sum(\"Amount\") AS sum\n"
+        ), "{}", sql);
+    }
+}
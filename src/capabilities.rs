@@ -0,0 +1,173 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use eyre::{eyre, Result};
+use itertools::Itertools;
+use sqlx::{Postgres, pool::PoolConnection};
+
+/// What the database server can do, found once per session, so that a feature it lacks is
+/// refused or worked around with a clear message rather than failing with a raw SQL error
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ServerCapabilities {
+    /// As in server_version_num, e.g. 110009 for 11.9 and 90605 for 9.6.5
+    version_num: u32,
+    /// The extensions installed in the database
+    extensions: Vec<String>
+}
+
+/// A feature which not every server has
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Feature {
+    /// Stored generated columns, which computed columns are added as
+    GeneratedColumns,
+    /// COPY's FORCE_NULL option, which empty values of typed columns are copied with
+    CopyForceNull
+}
+
+impl Feature {
+
+    /// The server_version_num from which the feature is available, and that version
+    fn minimum_version(self) -> (u32, &'static str) {
+        match self {
+            Self::GeneratedColumns => (120000, "12"),
+            Self::CopyForceNull => (90400, "9.4")
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::GeneratedColumns => "generated columns",
+            Self::CopyForceNull => "COPY's FORCE_NULL option"
+        }
+    }
+
+    /// The start of the message saying which version the feature requires
+    fn requirement(self) -> &'static str {
+        match self {
+            Self::GeneratedColumns => "Generated columns require",
+            Self::CopyForceNull => "COPY's FORCE_NULL option requires"
+        }
+    }
+}
+
+impl ServerCapabilities {
+
+    pub(crate) async fn detect(connection: &mut PoolConnection<Postgres>) -> Result<Self, sqlx::Error> {
+        let version_num: String = sqlx::query_scalar("SELECT current_setting('server_version_num')")
+            .fetch_one(&mut *connection).await?;
+        let version_num = parse_version_num(&version_num)
+            .ok_or_else(|| sqlx::Error::Protocol(format!("Invalid server_version_num {:?}", version_num)))?;
+        let extensions = sqlx::query_scalar("SELECT extname::text FROM pg_catalog.pg_extension ORDER BY extname")
+            .fetch_all(connection).await?;
+        Ok(Self { version_num, extensions })
+    }
+
+    /// The server's version, e.g. 11.9, or 9.6.5 before version 10
+    pub(crate) fn version(&self) -> String {
+        format_version_num(self.version_num)
+    }
+
+    pub(crate) fn supports(&self, feature: Feature) -> bool {
+        self.version_num >= feature.minimum_version().0
+    }
+
+    /// Says which version the feature requires, unless the server has it
+    pub(crate) fn require(&self, feature: Feature) -> Result<()> {
+        if self.supports(feature) {
+            return Ok(());
+        }
+        Err(eyre!(
+            "{} PostgreSQL {}+, server is {}", feature.requirement(), feature.minimum_version().1, self.version()
+        ))
+    }
+}
+
+impl fmt::Display for ServerCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PostgreSQL {}", self.version())?;
+        let missing = [Feature::GeneratedColumns, Feature::CopyForceNull].into_iter()
+            .filter(|feature| !self.supports(*feature))
+            .map(Feature::name)
+            .join(", ");
+        if !missing.is_empty() {
+            write!(f, ", without {}", missing)?;
+        }
+        match self.extensions.as_slice() {
+            [] => write!(f, ", with no extensions"),
+            extensions => write!(f, ", with extensions {}", extensions.join(", "))
+        }
+    }
+}
+
+fn parse_version_num(version_num: &str) -> Option<u32> {
+    version_num.trim().parse().ok().filter(|version_num| *version_num >= 10000)
+}
+
+/// From version 10, the version has two parts rather than three
+fn format_version_num(version_num: u32) -> String {
+    let major = version_num / 10000;
+    if major >= 10 {
+        format!("{}.{}", major, version_num % 10000)
+    } else {
+        format!("{}.{}.{}", major, version_num / 100 % 100, version_num % 100)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(version_num: &str) -> ServerCapabilities {
+        ServerCapabilities { version_num: parse_version_num(version_num).unwrap(), extensions: Vec::new() }
+    }
+
+    #[test]
+    fn parse_versions() {
+        assert_eq!("11.9", server("110009").version());
+        assert_eq!("16.2", server(" 160002\n").version());
+        assert_eq!("9.6.5", server("90605").version());
+        assert_eq!("12.0", server("120000").version());
+        assert_eq!(None, parse_version_num("11.9"));
+        assert_eq!(None, parse_version_num("906"));
+    }
+
+    #[test]
+    fn gate_features_by_version() {
+        let old = server("110009");
+        assert!(!old.supports(Feature::GeneratedColumns));
+        assert!(old.supports(Feature::CopyForceNull));
+        assert_eq!(
+            "Generated columns require PostgreSQL 12+, server is 11.9",
+            old.require(Feature::GeneratedColumns).unwrap_err().to_string()
+        );
+        assert!(server("120000").require(Feature::GeneratedColumns).is_ok());
+        assert_eq!(
+            "COPY's FORCE_NULL option requires PostgreSQL 9.4+, server is 9.3.25",
+            server("90325").require(Feature::CopyForceNull).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn describe_capabilities() {
+        let mut capabilities = server("110009");
+        assert_eq!("PostgreSQL 11.9, without generated columns, with no extensions", capabilities.to_string());
+        capabilities.version_num = 150004;
+        capabilities.extensions = vec![String::from("pgcrypto"), String::from("plpgsql")];
+        assert_eq!("PostgreSQL 15.4, with extensions pgcrypto, plpgsql", capabilities.to_string());
+    }
+}
@@ -0,0 +1,157 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use eyre::{eyre, Result};
+use itertools::Itertools;
+use sqlx::{Postgres, pool::PoolConnection};
+use crate::database::{column_argument, column_identifier, find_column, quote_literal, split_arguments};
+use crate::error::{self, Error};
+
+/// How many distinct values columns have, written as `cardinality <column...>`. The counts are
+/// estimated from the statistics Postgres gathers with ANALYZE, which samples the table rather
+/// than reading all of it. Counting exactly reads every row, so it is only done when asked for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Cardinality {
+    pub(crate) table: String,
+    columns: Vec<String>,
+    /// Whether to count the distinct values exactly, as well as estimating them
+    pub(crate) exact: bool
+}
+
+impl Cardinality {
+
+    /// Parses the arguments following `cardinality`. The columns may be named in double quotes
+    pub(crate) fn parse(table: String, arguments: &str) -> Result<Self> {
+        let columns = split_arguments(arguments, &[','])?.iter()
+            .map(|column| column_argument(column))
+            .collect::<Result<Vec<_>>>()?;
+        if columns.is_empty() {
+            return Err(eyre!("Usage: cardinality <column...>"));
+        }
+        Ok(Self { table, columns, exact: false })
+    }
+
+    /// Finds the columns in the table, taking their names from there, then gathers fresh
+    /// statistics on them
+    pub(crate) async fn analyze(&mut self, connection: &mut PoolConnection<Postgres>) -> error::Result<()> {
+        let existing: Vec<String> = sqlx::query_scalar("\
+            SELECT attname::text FROM pg_catalog.pg_attribute \
+            WHERE attrelid = to_regclass($1) AND attnum > 0 AND NOT attisdropped \
+            ORDER BY attnum")
+            .bind(&self.table)
+            .fetch_all(&mut *connection).await
+            .map_err(|error| Error::from_database(error, Error::QueryRuntime))?;
+        let mut columns: Vec<String> = Vec::new();
+        for column in &self.columns {
+            let column = find_column(&self.table, column, &existing, String::as_str)?;
+            if columns.contains(column) {
+                return Err(Error::Usage(eyre!("Column {} is named more than once", column)));
+            }
+            columns.push(column.clone());
+        }
+        self.columns = columns;
+        sqlx::query(&self.analyze_statement())
+            .execute(connection).await
+            .map_err(|error| Error::from_database(error, Error::QueryRuntime))?;
+        Ok(())
+    }
+
+    fn analyze_statement(&self) -> String {
+        format!("ANALYZE {} ({})", self.table, self.columns.iter().map(|column| column_identifier(column)).join(", "))
+    }
+
+    /// Lists each column with its estimated count of distinct values, and its exact count if
+    /// asked for. Postgres gives the estimate as a fraction of the rows, negated, when it
+    /// expects the count to grow with the table
+    pub(crate) fn to_sql(&self) -> String {
+        let names = self.columns.iter().enumerate()
+            .map(|(index, column)| format!("({}, {})", index + 1, quote_literal(column)))
+            .join(", ");
+        let mut sql = format!("\
+WITH target AS (
+    SELECT namespace.nspname, class.relname, class.reltuples
+    FROM pg_catalog.pg_class class
+    JOIN pg_catalog.pg_namespace namespace ON namespace.oid = class.relnamespace
+    WHERE class.oid = to_regclass('{}')
+)
+SELECT columns.name AS column_name,
+    round(CASE WHEN stats.n_distinct >= 0 THEN stats.n_distinct
+        ELSE -stats.n_distinct * target.reltuples END)::bigint AS approximate_distinct", self.table);
+        if self.exact {
+            let whens = (1..=self.columns.len())
+                .map(|position| format!("WHEN {} THEN exact.exact_{}", position, position));
+            sql.push_str(&format!(",\n    CASE columns.position {} END AS exact_distinct", whens.format(" ")));
+        }
+        sql.push_str(&format!("\nFROM (VALUES {}) AS columns (position, name)\nCROSS JOIN target", names));
+        sql.push_str("\nLEFT JOIN pg_catalog.pg_stats stats ON stats.schemaname = target.nspname \
+            AND stats.tablename = target.relname AND stats.attname = columns.name");
+        if self.exact {
+            let counts = self.columns.iter().enumerate()
+                .map(|(index, column)| format!("count(DISTINCT {}) AS exact_{}", column_identifier(column), index + 1))
+                .join(", ");
+            sql.push_str(&format!("\nCROSS JOIN (SELECT {} FROM {}) AS exact", counts, self.table));
+        }
+        sql.push_str("\nORDER BY columns.position");
+        sql
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cardinality() {
+        let cardinality = Cardinality::parse(String::from("data"), " Email, country  \"Order ID\",id").unwrap();
+        assert_eq!(vec!["Email", "country", "Order ID", "id"], cardinality.columns);
+        assert!(!cardinality.exact);
+        assert_eq!("ANALYZE data (\"Email\", country, \"Order ID\", id)", cardinality.analyze_statement());
+        let invalid = |arguments: &str| Cardinality::parse(String::from("data"), arguments).unwrap_err().to_string();
+        assert_eq!("Usage: cardinality <column...>", invalid(" "));
+        assert_eq!("Invalid column name: \"country. Its quote is not closed", invalid("email \"country"));
+    }
+
+    #[test]
+    fn cardinality_sql() {
+        let mut cardinality = Cardinality::parse(String::from("sales.orders"), "email \"Ship Country\"").unwrap();
+        assert_eq!("\
+WITH target AS (
+    SELECT namespace.nspname, class.relname, class.reltuples
+    FROM pg_catalog.pg_class class
+    JOIN pg_catalog.pg_namespace namespace ON namespace.oid = class.relnamespace
+    WHERE class.oid = to_regclass('sales.orders')
+)
+SELECT columns.name AS column_name,
+    round(CASE WHEN stats.n_distinct >= 0 THEN stats.n_distinct
+        ELSE -stats.n_distinct * target.reltuples END)::bigint AS approximate_distinct
+FROM (VALUES (1, 'email'), (2, 'Ship Country')) AS columns (position, name)
+CROSS JOIN target
+LEFT JOIN pg_catalog.pg_stats stats ON stats.schemaname = target.nspname \
+AND stats.tablename = target.relname AND stats.attname = columns.name
+ORDER BY columns.position", cardinality.to_sql());
+        cardinality.exact = true;
+        let sql = cardinality.to_sql();
+        assert!(sql.contains(
+            "AS approximate_distinct,\n    CASE columns.position WHEN 1 THEN exact.exact_1 \
+            WHEN 2 THEN exact.exact_2 END AS exact_distinct\nFROM"
+        ), "{}", sql);
+        assert!(sql.ends_with(
+            "\nCROSS JOIN (SELECT count(DISTINCT email) AS exact_1, count(DISTINCT \"Ship Country\") AS exact_2 \
+            FROM sales.orders) AS exact\nORDER BY columns.position"
+        ), "{}", sql);
+    }
+}
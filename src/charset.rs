@@ -0,0 +1,389 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use async_std::io::Read;
+use csv_async::{ByteRecord, StringRecord};
+use eyre::eyre;
+use futures_lite::ready;
+use itertools::Itertools;
+use crate::error::{Error, Result};
+
+/// The characters of Windows-1252 from 0x80 to 0x9F, where it differs from Latin-1. The five
+/// bytes it leaves undefined are None
+const WINDOWS_1252_C1: [Option<char>; 32] = [
+    Some('€'), None, Some('‚'), Some('ƒ'), Some('„'), Some('…'), Some('†'), Some('‡'),
+    Some('ˆ'), Some('‰'), Some('Š'), Some('‹'), Some('Œ'), None, Some('Ž'), None,
+    None, Some('‘'), Some('’'), Some('“'), Some('”'), Some('•'), Some('–'), Some('—'),
+    Some('˜'), Some('™'), Some('š'), Some('›'), Some('œ'), None, Some('ž'), Some('Ÿ')
+];
+
+/// The UTF-8 of U+FFFD, which replaces invalid sequences
+pub(crate) const REPLACEMENT: &[u8] = "\u{FFFD}".as_bytes();
+
+/// A column name which is not UTF-8, and so was read as Windows-1252, or if it is not that
+/// either, had its invalid sequences replaced with U+FFFD
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TranscodedName {
+    pub(crate) raw: Vec<u8>,
+    pub(crate) name: String,
+    pub(crate) repaired: bool
+}
+
+impl fmt::Display for TranscodedName {
+    /// Shows the bytes beside the name read from them, e.g. Caf\xE9 → Café
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} → {}", escaped(&self.raw), self.name)
+    }
+}
+
+/// Shows printable ASCII as it is, and other bytes in hex, e.g. Caf\xE9
+fn escaped(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|byte| match byte {
+            0x20..=0x7E => String::from(char::from(*byte)),
+            byte => format!("\\x{:02X}", byte)
+        })
+        .collect()
+}
+
+/// Reads the column names of a header. Some exporters write the header in Windows-1252 even
+/// where the records are UTF-8, so a name which is not UTF-8 is read as Windows-1252 instead,
+/// and returned among the names transcoded. The records are still read as UTF-8
+pub(crate) fn decode_header(header: &ByteRecord) -> Result<(Vec<String>, Vec<TranscodedName>)> {
+    decode_names(header, None)
+}
+
+/// Reads the column names of a header as decode_header does, but where a name is neither UTF-8
+/// nor Windows-1252, takes it from the header as read through RepairUtf8 instead
+pub(crate) fn decode_repaired_header(header: &ByteRecord, repaired: &StringRecord)
+                                     -> Result<(Vec<String>, Vec<TranscodedName>)> {
+    decode_names(header, Some(repaired))
+}
+
+fn decode_names(header: &ByteRecord, repaired: Option<&StringRecord>) -> Result<(Vec<String>, Vec<TranscodedName>)> {
+    let mut names = Vec::with_capacity(header.len());
+    let mut transcoded = Vec::new();
+    for (index, field) in header.iter().enumerate() {
+        if let Ok(name) = std::str::from_utf8(field) {
+            names.push(String::from(name));
+            continue;
+        }
+        let name = match (windows_1252(field), repaired.and_then(|repaired| repaired.get(index))) {
+            (Some(name), _) => TranscodedName { raw: field.to_vec(), name, repaired: false },
+            (None, Some(name)) => TranscodedName { raw: field.to_vec(), name: String::from(name), repaired: true },
+            (None, None) => return Err(Error::ImportData(eyre!(
+                "Column {} of the header, on line 1, is neither UTF-8 nor Windows-1252: {}. \
+                Use --repair-utf8 to replace its invalid sequences with U+FFFD",
+                index + 1, escaped(field)
+            )))
+        };
+        names.push(name.name.clone());
+        transcoded.push(name);
+    }
+    Ok((names, transcoded))
+}
+
+/// Lists the names which were read as Windows-1252, and those which were repaired, beside their
+/// bytes, so that they can be checked
+pub(crate) fn transcoded_warning(transcoded: &[TranscodedName]) -> Option<String> {
+    let (repaired, windows_1252): (Vec<_>, Vec<_>) = transcoded.iter().partition(|name| name.repaired);
+    let count = |names: &[&TranscodedName]| match names.len() {
+        1 => (String::from("1 column name"), "it is"),
+        count => (format!("{} column names", count), "they are")
+    };
+    let mut warnings = Vec::new();
+    if !windows_1252.is_empty() {
+        let (names, are) = count(&windows_1252);
+        warnings.push(format!(
+            "Read {} of the header as Windows-1252, since {} not UTF-8; the records are still read as UTF-8:\n  {}",
+            names, are,
+            windows_1252.iter().join("\n  ")
+        ));
+    }
+    if !repaired.is_empty() {
+        let (names, are) = count(&repaired);
+        warnings.push(format!(
+            "Replaced invalid UTF-8 sequences in {} of the header with U+FFFD, since {} neither UTF-8 nor \
+            Windows-1252:\n  {}",
+            names, are,
+            repaired.iter().join("\n  ")
+        ));
+    }
+    (!warnings.is_empty()).then(|| warnings.join("\n"))
+}
+
+/// Decodes Windows-1252, unless it holds a byte which the encoding leaves undefined
+fn windows_1252(bytes: &[u8]) -> Option<String> {
+    bytes.iter()
+        .map(|byte| match byte {
+            0x80..=0x9F => WINDOWS_1252_C1[usize::from(byte - 0x80)],
+            byte => Some(char::from(*byte))
+        })
+        .collect()
+}
+
+/// Reads text as UTF-8, replacing invalid sequences with U+FFFD as String::from_utf8_lossy does,
+/// so that the CSV parser only sees valid text. A character may be split between two reads of
+/// the underlying reader, so bytes which may begin one are held back until the rest arrive.
+/// Unless told to repair, the text passes through as it is
+pub(crate) struct RepairUtf8<R> {
+    inner: R,
+    repair: bool,
+    /// Bytes read but not yet repaired, since they may begin a character not yet read in full
+    pending: Vec<u8>,
+    /// The repaired text waiting to be read, and how much of it has been
+    repaired: Vec<u8>,
+    read: usize,
+    /// How many bytes of repaired text came before those waiting
+    written: u64,
+    /// Where each U+FFFD starts in the repaired text, and whether it replaced a sequence, rather
+    /// than being in the text already
+    replacements: Vec<(u64, bool)>,
+    finished: bool
+}
+
+impl<R> RepairUtf8<R> {
+
+    pub(crate) fn new(inner: R, repair: bool) -> Self {
+        Self {
+            inner, repair, pending: Vec::new(), repaired: Vec::new(), read: 0, written: 0,
+            replacements: Vec::new(), finished: false
+        }
+    }
+
+    /// Whether each U+FFFD from the start offset of the repaired text to the end replaced a
+    /// sequence, in order
+    pub(crate) fn replacements_between(&self, start: u64, end: u64) -> impl Iterator<Item=bool> + '_ {
+        let before = |offset: u64| self.replacements.partition_point(|(replacement, _)| *replacement < offset);
+        self.replacements[before(start)..before(end)].iter().map(|(_, replaced)| *replaced)
+    }
+
+    /// Moves the pending bytes to the repaired text, except those which may begin a character
+    /// whose rest is still to be read
+    fn repair_pending(&mut self) {
+        let mut rest = self.pending.as_slice();
+        loop {
+            let error = match std::str::from_utf8(rest) {
+                Ok(_) => {
+                    Self::pass(&mut self.repaired, &mut self.replacements, self.written, rest);
+                    rest = &[];
+                    break;
+                },
+                Err(error) => error
+            };
+            let (valid, invalid) = rest.split_at(error.valid_up_to());
+            Self::pass(&mut self.repaired, &mut self.replacements, self.written, valid);
+            let length = match error.error_len() {
+                Some(length) => length,
+                // Cut off by the end of the text
+                None if self.finished => invalid.len(),
+                None => {
+                    rest = invalid;
+                    break;
+                }
+            };
+            self.replacements.push((self.written + self.repaired.len() as u64, true));
+            self.repaired.extend_from_slice(REPLACEMENT);
+            rest = &invalid[length..];
+        }
+        let repaired = self.pending.len() - rest.len();
+        self.pending.drain(..repaired);
+    }
+
+    /// Adds valid text to the repaired text, noting where it holds U+FFFD already
+    fn pass(repaired: &mut Vec<u8>, replacements: &mut Vec<(u64, bool)>, written: u64, valid: &[u8]) {
+        // In valid UTF-8, 0xEF only begins a character
+        let starts = valid.iter().enumerate()
+            .filter(|(index, byte)| **byte == REPLACEMENT[0] && valid[*index..].starts_with(REPLACEMENT));
+        for (index, _) in starts {
+            replacements.push((written + (repaired.len() + index) as u64, false));
+        }
+        repaired.extend_from_slice(valid);
+    }
+}
+
+impl<R> Read for RepairUtf8<R> where R: Read + Unpin {
+    fn poll_read(self: Pin<&mut Self>, context: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let reader = self.get_mut();
+        if !reader.repair {
+            return Pin::new(&mut reader.inner).poll_read(context, buf);
+        }
+        loop {
+            if reader.read < reader.repaired.len() || buf.is_empty() {
+                let count = buf.len().min(reader.repaired.len() - reader.read);
+                buf[..count].copy_from_slice(&reader.repaired[reader.read..reader.read + count]);
+                reader.read += count;
+                return Poll::Ready(Ok(count));
+            }
+            if reader.finished {
+                return Poll::Ready(Ok(0));
+            }
+            reader.written += reader.repaired.len() as u64;
+            reader.repaired.clear();
+            reader.read = 0;
+            let start = reader.pending.len();
+            reader.pending.resize(start + buf.len(), 0);
+            let polled = Pin::new(&mut reader.inner).poll_read(context, &mut reader.pending[start..]);
+            let count = match &polled {
+                Poll::Ready(Ok(count)) => *count,
+                _ => 0
+            };
+            reader.pending.truncate(start + count);
+            ready!(polled)?;
+            reader.finished = count == 0;
+            reader.repair_pending();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::ReadExt;
+    use super::*;
+
+    #[test]
+    fn transcode_names_which_are_not_utf8() -> Result<()> {
+        // UTF-8, ASCII, and Windows-1252 with bytes of Latin-1 and of the range it redefines
+        let header = ByteRecord::from(vec![&b"Gr\xc3\xb6\xc3\x9fe"[..], b"id", b"Caf\xe9", b"\x93Preis\x94 \x80"]);
+        let (names, transcoded) = decode_header(&header)?;
+        assert_eq!(vec!["Größe", "id", "Café", "“Preis” €"], names);
+        assert_eq!(vec![
+            TranscodedName { raw: b"Caf\xe9".to_vec(), name: String::from("Café"), repaired: false },
+            TranscodedName { raw: b"\x93Preis\x94 \x80".to_vec(), name: String::from("“Preis” €"), repaired: false }
+        ], transcoded);
+        assert_eq!(Some(String::from("\
+Read 2 column names of the header as Windows-1252, since they are not UTF-8; the records are still read as UTF-8:
+  Caf\\xE9 → Café
+  \\x93Preis\\x94 \\x80 → “Preis” €")), transcoded_warning(&transcoded));
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_names_which_are_not_windows_1252() {
+        let (names, transcoded) = decode_header(&ByteRecord::from(vec!["id", "name"])).unwrap();
+        assert_eq!(vec!["id", "name"], names);
+        assert_eq!(None, transcoded_warning(&transcoded));
+        // 0x81 is undefined in Windows-1252
+        let error = decode_header(&ByteRecord::from(vec![&b"id"[..], b"\x81x\xe9"])).unwrap_err();
+        assert_eq!(
+            "Column 2 of the header, on line 1, is neither UTF-8 nor Windows-1252: \\x81x\\xE9. \
+            Use --repair-utf8 to replace its invalid sequences with U+FFFD",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn repair_names_which_are_not_windows_1252() -> Result<()> {
+        let header = ByteRecord::from(vec![&b"Caf\xe9"[..], b"\x81x"]);
+        let repaired = StringRecord::from(vec!["Caf\u{FFFD}", "\u{FFFD}x"]);
+        let (names, transcoded) = decode_repaired_header(&header, &repaired)?;
+        // Windows-1252 is still tried first
+        assert_eq!(vec!["Café", "\u{FFFD}x"], names);
+        assert_eq!(Some(String::from("\
+Read 1 column name of the header as Windows-1252, since it is not UTF-8; the records are still read as UTF-8:
+  Caf\\xE9 → Café
+Replaced invalid UTF-8 sequences in 1 column name of the header with U+FFFD, since it is neither UTF-8 nor Windows-1252:
+  \\x81x → \u{FFFD}x")), transcoded_warning(&transcoded));
+        Ok(())
+    }
+
+    /// Hands out the text a few bytes at a time, so that characters are split between reads
+    struct Trickle<'t> {
+        text: &'t [u8],
+        size: usize
+    }
+
+    impl Read for Trickle<'_> {
+        fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            let count = self.size.min(buf.len()).min(self.text.len());
+            buf[..count].copy_from_slice(&self.text[..count]);
+            self.text = &self.text[count..];
+            Poll::Ready(Ok(count))
+        }
+    }
+
+    /// Reads the text through RepairUtf8 into a buffer of the given size, returning it with how
+    /// many sequences were replaced
+    async fn repair(text: &[u8], size: usize, buffer: usize) -> io::Result<(Vec<u8>, usize)> {
+        let mut reader = RepairUtf8::new(Trickle { text, size }, true);
+        let mut repaired = Vec::new();
+        let mut read = vec![0; buffer];
+        loop {
+            let count = reader.read(&mut read).await?;
+            if count == 0 {
+                break;
+            }
+            repaired.extend_from_slice(&read[..count]);
+        }
+        Ok((repaired, reader.replacements_between(0, u64::MAX).filter(|replaced| *replaced).count()))
+    }
+
+    #[async_std::test]
+    async fn repair_sequences_split_between_reads() -> io::Result<()> {
+        let texts: [(&[u8], usize); 7] = [
+            // Valid characters of two, three and four bytes
+            (b"Caf\xc3\xa9 \xe2\x82\xac \xf0\x9f\x98\x80", 0),
+            // A character cut short by a byte which cannot continue it
+            (b"a\xe2\x82b", 1),
+            (b"\xf0\x9f\x98,\xf0\x9f", 2),
+            // Bytes which cannot begin a character
+            (b"\xff\xfe\x80x", 3),
+            // A surrogate, and an overlong encoding
+            (b"\xed\xa0\x80|\xc0\xaf", 5),
+            // A character cut off by the end of the text
+            (b"x,\xe2\x82", 1),
+            (b"\xc3", 1)
+        ];
+        for (text, replacements) in texts {
+            for size in 1..=5 {
+                for buffer in [1, 2, 3, 64] {
+                    let (repaired, replaced) = repair(text, size, buffer).await?;
+                    assert_eq!(String::from_utf8_lossy(text).as_bytes(), repaired.as_slice(), "{:?} {} {}", text, size, buffer);
+                    assert_eq!(replacements, replaced, "{:?} {} {}", text, size, buffer);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn locate_replacements() -> io::Result<()> {
+        let mut reader = RepairUtf8::new(Trickle { text: b"ab\xff\ncd\n\xfe\xfe\xef\xbf\xbd", size: 2 }, true);
+        let mut repaired = String::new();
+        reader.read_to_string(&mut repaired).await?;
+        assert_eq!("ab\u{FFFD}\ncd\n\u{FFFD}\u{FFFD}\u{FFFD}", repaired);
+        assert_eq!(vec![true], reader.replacements_between(0, 6).collect::<Vec<_>>());
+        assert_eq!(Vec::<bool>::new(), reader.replacements_between(6, 9).collect::<Vec<_>>());
+        // The last U+FFFD was in the text already
+        assert_eq!(vec![true, true, false], reader.replacements_between(9, 18).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn pass_text_through_unless_told_to_repair() -> io::Result<()> {
+        let mut reader = RepairUtf8::new(Trickle { text: b"a\xffb", size: 1 }, false);
+        let mut text = Vec::new();
+        reader.read_to_end(&mut text).await?;
+        assert_eq!(b"a\xffb", text.as_slice());
+        assert_eq!(0, reader.replacements_between(0, u64::MAX).count());
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::Duration;
+use chrono::{DateTime, SecondsFormat, Utc};
+use eyre::eyre;
+use sqlx::{Executor, Row};
+use sqlx::postgres::PgConnection;
+use crate::database::quote_literal;
+use crate::progress::format_bytes;
+
+/// How the comment of each table data-sifter creates begins, followed by when it was created,
+/// so that `data-sifter clean` can tell its tables from any other
+const MARKER: &str = "data-sifter table, created ";
+
+/// The comment marking a table created at that time
+fn marker(created: DateTime<Utc>) -> String {
+    format!("{}{}", MARKER, created.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+/// When the table was created, if its comment is a marker
+fn created_at(comment: &str) -> Option<DateTime<Utc>> {
+    let created = comment.strip_prefix(MARKER)?;
+    let created = created.split_once(". ").map_or(created, |(created, _)| created);
+    DateTime::parse_from_rfc3339(created).ok().map(|created| created.with_timezone(&Utc))
+}
+
+/// Marks the table as created by data-sifter just now. A note, such as that the table holds
+/// only a sample of its dataset, may follow the marker
+pub(crate) async fn mark_created(table: &str, note: Option<&str>,
+                                 connection: &mut PgConnection) -> Result<(), sqlx::Error> {
+    let comment = match note {
+        Some(note) => format!("{}. {}", marker(Utc::now()), note),
+        None => marker(Utc::now())
+    };
+    // COMMENT takes no parameters
+    connection.execute(&*format!("COMMENT ON TABLE {} IS {}", table, quote_literal(&comment))).await?;
+    Ok(())
+}
+
+/// Parses an age such as 7d, given as a whole number of minutes (m), hours (h), days (d) or
+/// weeks (w)
+pub(crate) fn parse_age(age: &str) -> eyre::Result<Duration> {
+    let invalid = || eyre!("Invalid age {}. Use a whole number and a unit, m, h, d or w, such as 7d", age);
+    let split = age.find(|character: char| !character.is_ascii_digit()).ok_or_else(invalid)?;
+    let (number, unit) = age.split_at(split);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let unit_secs = match unit {
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid())
+    };
+    number.checked_mul(unit_secs).map(Duration::from_secs).ok_or_else(invalid)
+}
+
+/// A table which data-sifter created
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct MarkedTable {
+    /// Qualified with its schema
+    pub(crate) name: String,
+    pub(crate) created: DateTime<Utc>,
+    /// Including its indexes and TOAST
+    pub(crate) size: u64
+}
+
+impl MarkedTable {
+
+    /// Describes the table, e.g. analyst.sales, created 2022-03-01 12:00 UTC, 8.2 MB
+    pub(crate) fn describe(&self) -> String {
+        format!("{}, created {}, {}", self.name, self.created.format("%Y-%m-%d %H:%M UTC"), format_bytes(self.size))
+    }
+}
+
+/// The tables data-sifter created in the schema, or else in the first schema on the search
+/// path, oldest first
+pub(crate) async fn marked_tables(schema: Option<&str>,
+                                  connection: &mut PgConnection) -> Result<Vec<MarkedTable>, sqlx::Error> {
+    let rows = sqlx::query("\
+        SELECT quote_ident(n.nspname) || '.' || quote_ident(c.relname), obj_description(c.oid, 'pg_class'), \
+        pg_total_relation_size(c.oid) \
+        FROM pg_catalog.pg_class c JOIN pg_catalog.pg_namespace n ON n.oid = c.relnamespace \
+        WHERE c.relkind IN ('r', 'p') AND n.nspname = coalesce($1, current_schema()) \
+        AND obj_description(c.oid, 'pg_class') LIKE $2")
+        .bind(schema)
+        .bind(format!("{}%", MARKER))
+        .fetch_all(connection).await?;
+    let mut tables = Vec::with_capacity(rows.len());
+    for row in rows {
+        let comment: String = row.try_get(1)?;
+        if let Some(created) = created_at(&comment) {
+            let size: i64 = row.try_get(2)?;
+            tables.push(MarkedTable { name: row.try_get(0)?, created, size: size as u64 });
+        }
+    }
+    tables.sort_by(|first, second| first.created.cmp(&second.created).then_with(|| first.name.cmp(&second.name)));
+    Ok(tables)
+}
+
+/// The tables created longer ago than the age, as of now
+pub(crate) fn older_than(tables: Vec<MarkedTable>, age: Duration, now: DateTime<Utc>) -> Vec<MarkedTable> {
+    tables.into_iter()
+        .filter(|table| (now - table.created).to_std().is_ok_and(|elapsed| elapsed > age))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marker_holds_creation_time() {
+        let created = DateTime::parse_from_rfc3339("2022-03-01T12:30:00Z").unwrap().with_timezone(&Utc);
+        assert_eq!("data-sifter table, created 2022-03-01T12:30:00Z", marker(created));
+        assert_eq!(Some(created), created_at(&marker(created)));
+        assert_eq!(None, created_at("data-sifter table, created yesterday"));
+        let noted = format!("{}. Holds a sample of the dataset: the first 10 rows", marker(created));
+        assert_eq!(Some(created), created_at(&noted));
+        assert_eq!(None, created_at("Sales by region"));
+    }
+
+    #[test]
+    fn parse_ages() {
+        assert_eq!(Duration::from_secs(7 * 24 * 3600), parse_age("7d").unwrap());
+        assert_eq!(Duration::from_secs(90 * 60), parse_age("90m").unwrap());
+        assert_eq!(Duration::from_secs(2 * 7 * 24 * 3600), parse_age("2w").unwrap());
+        for invalid in ["7", "d", "7 d", "-7d", "7y", "1.5h", ""] {
+            assert!(parse_age(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn only_older_tables() {
+        let now = DateTime::parse_from_rfc3339("2022-03-10T00:00:00Z").unwrap().with_timezone(&Utc);
+        let table = |name: &str, created: &str| MarkedTable {
+            name: String::from(name),
+            created: DateTime::parse_from_rfc3339(created).unwrap().with_timezone(&Utc),
+            size: 8192
+        };
+        let tables = vec![
+            table("public.old", "2022-03-01T00:00:00Z"), table("public.new", "2022-03-09T00:00:00Z"),
+            table("public.future", "2022-03-11T00:00:00Z")
+        ];
+        let old = older_than(tables, parse_age("7d").unwrap(), now);
+        assert_eq!(vec!["public.old"], old.iter().map(|table| table.name.as_str()).collect::<Vec<_>>());
+        assert_eq!("public.old, created 2022-03-01 00:00 UTC, 8.2 KB", old[0].describe());
+    }
+}
@@ -0,0 +1,415 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::num::{NonZeroU64, NonZeroUsize};
+use clap::{ArgAction, ArgGroup, Parser, Subcommand};
+use clap::builder::BoolishValueParser;
+use crate::computed::ComputedColumn;
+use crate::database::OnConflict;
+use crate::decode::ArrayFormat;
+use crate::download::RequestHeader;
+use crate::filter::RowFilter;
+use crate::fixedwidth::Units;
+use crate::transform::ColumnTransforms;
+use crate::import::{RaggedRows, Strategy};
+use crate::output::HeaderCase;
+use crate::reject::{self, RejectLimit};
+use crate::sample::{self, Sampling};
+use crate::timestamp::{TimestampFormat, Timezone};
+
+/// Query data in CSV files using SQL.
+///
+/// Without arguments, data-sifter asks for everything it needs interactively.
+/// Supplying --query runs it non-interactively, in which case the answers to
+/// all prompts must be given as arguments.
+#[derive(Clone, Debug, Parser)]
+#[command(version, group(ArgGroup::new("sampling").args(["sample_rate", "sample_rows"])))]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Config file to use instead of the default
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<String>,
+    /// Keep the config and other files beside the executable, rather than in the home directory
+    #[arg(long, global = true, env = "DATA_SIFTER_PORTABLE", action = ArgAction::SetTrue,
+          value_parser = BoolishValueParser::new())]
+    pub portable: bool,
+    /// Never style output with color or bold text
+    #[arg(long, global = true)]
+    pub no_color: bool,
+    /// Show backtraces for errors, and how many statements an import executed
+    #[arg(short, long, global = true)]
+    pub verbose: bool,
+    /// Write nothing but the results, prompts, warnings and errors: no summaries, plans,
+    /// progress or server notices
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Record the options given and each answer to a prompt in this file, so that the session
+    /// can be replayed. Answers holding passwords are left out, as are --header options
+    #[arg(long, value_name = "PATH", conflicts_with = "replay")]
+    pub record: Option<String>,
+    /// Replay a session recorded with --record, answering its prompts in order. Only --config,
+    /// --portable, --no-color, --verbose, --quiet, --header and --report may be given alongside
+    #[arg(long, value_name = "PATH")]
+    pub replay: Option<String>,
+    /// Write a JSON report of the run to this file once it ends, whether or not it succeeded,
+    /// with the datasets imported, the queries run, their row counts and durations, and any error
+    #[arg(long, value_name = "PATH")]
+    pub report: Option<String>,
+    /// CSV dataset file or http(s) URL to import, or KEEP to keep the existing data
+    #[arg(long)]
+    pub input: Option<String>,
+    /// A header sent when downloading the dataset, such as 'Authorization: Bearer <token>'.
+    /// May be repeated
+    #[arg(long, value_name = "'NAME: VALUE'")]
+    pub header: Vec<RequestHeader>,
+    /// Table in which the dataset is stored
+    #[arg(long, default_value = "data")]
+    pub table: String,
+    /// Postgres schema in which tables are created, which is created too if permitted. It is
+    /// searched first, so queries need not name it. Overrides the config
+    #[arg(long)]
+    pub schema: Option<String>,
+    /// Which database holds the tables: postgres, or duckdb, which is embedded and needs no server.
+    /// Overrides the config
+    #[arg(long, value_enum)]
+    pub backend: Option<crate::config::Backend>,
+    /// Whether the connection to the database is encrypted, and how far the server's certificate
+    /// is checked. Overrides the config and the postgres_url
+    #[arg(long, value_enum, value_name = "MODE")]
+    pub ssl_mode: Option<crate::ssl::SslMode>,
+    /// Drop and replace the table if it already contains data. Also applies to --materialize, and
+    /// allows statements before the query to change or drop data
+    #[arg(long, conflicts_with = "append")]
+    pub replace: bool,
+    /// Add the dataset to the table if it already contains data. Its columns are matched to the
+    /// table's by name, in any order, and those of the table which it lacks are left NULL
+    #[arg(long)]
+    pub append: bool,
+    /// When adding to an existing table, leave out the CSV columns which the table does not
+    /// have, rather than stopping the import
+    #[arg(long, conflicts_with = "generate_sql")]
+    pub ignore_extra_columns: bool,
+    /// Resume an interrupted import of the same file into the table, from its last checkpoint
+    #[arg(long, conflicts_with_all = ["replace", "append"])]
+    pub resume: bool,
+    /// Load the dataset into an existing table, whose columns it must match by name, casting
+    /// values to their types. The table is never created or dropped
+    #[arg(long, conflicts_with_all = ["replace", "append", "computed"])]
+    pub strict_schema: bool,
+    /// The columns identifying a row of the table, separated by commas, such as 'store,day'. A
+    /// unique index on them is created unless the table has one
+    #[arg(long, value_name = "COLUMNS")]
+    pub key: Option<String>,
+    /// What to do with rows whose key is already in the table: 'error' to stop the import,
+    /// 'update' to update the row in the table with their other values, or 'ignore' to leave them
+    /// out. The key columns are asked for unless --key gives them
+    #[arg(long, value_enum, value_name = "MODE")]
+    pub on_conflict: Option<OnConflict>,
+    /// How many chunks of the CSV file are parsed at once while importing. Defaults to the
+    /// number of CPU cores
+    #[arg(long, value_name = "N")]
+    pub jobs: Option<NonZeroUsize>,
+    /// The character quoting fields in the CSV dataset, such as ' for single quotes. Defaults
+    /// to a double quote. Overrides the config
+    #[arg(long, value_name = "CHAR")]
+    pub quote: Option<char>,
+    /// The character escaping quotes within quoted fields of the CSV dataset, such as \.
+    /// Overrides the config
+    #[arg(long, value_name = "CHAR")]
+    pub escape: Option<char>,
+    /// Whether a doubled quote within a quoted field stands for one quote. Defaults to true.
+    /// Overrides the config
+    #[arg(long, value_name = "BOOL")]
+    pub double_quote: Option<bool>,
+    /// Replace invalid UTF-8 in the CSV file with U+FFFD, rather than stopping the import
+    #[arg(long)]
+    pub repair_utf8: bool,
+    /// What to do with records whose number of fields differs from the header's: 'strict' to
+    /// stop the import, 'skip' to leave them out, 'pad' to add empty fields to short records,
+    /// or 'truncate' to drop the extra fields of long records. 'skip' also leaves out rows with
+    /// values which the types of a --strict-schema table cannot hold. Overrides the config
+    #[arg(long, value_name = "MODE")]
+    pub ragged_rows: Option<RaggedRows>,
+    /// How many rows may be left out, since they cannot be imported, before the import stops:
+    /// a count of rows, a percentage of those read such as 5%, or both, as in 500,5%. The
+    /// percentage is checked once the --reject-sample is read
+    #[arg(long, value_name = "LIMIT")]
+    pub max_rejects: Option<RejectLimit>,
+    /// How many rows are read before the percentage of --max-rejects is checked, or all of
+    /// them if the dataset is smaller. Defaults to 10,000
+    #[arg(long, value_name = "ROWS", requires = "max_rejects")]
+    pub reject_sample: Option<u64>,
+    /// Read the dataset as a fixed-width text file, whose columns are sliced from each line as
+    /// the RON layout file describes, or as entered when asked for if the layout is ASK. Lines
+    /// shorter than the layout follow --ragged-rows
+    #[arg(long, value_name = "LAYOUT")]
+    pub fixed_width: Option<String>,
+    /// Whether the starts and widths of a fixed-width layout count 'bytes' or 'chars'. Overrides
+    /// the layout, which counts bytes by default
+    #[arg(long, value_enum, value_name = "UNITS")]
+    pub fixed_width_units: Option<Units>,
+    /// How rows are sent to the database: 'insert' for batched INSERT statements, which name the
+    /// line of a record which cannot be imported, 'copy' for COPY, which is faster, or 'auto' for
+    /// COPY only if the file is at least the size of the copy_threshold_mb config, 10 MB by default
+    #[arg(long, value_enum, default_value_t)]
+    pub strategy: Strategy,
+    /// A column computed from the others by a SQL expression, such as
+    /// 'amount_cents = round(amount::numeric * 100)'. It is added to the table once the dataset
+    /// is imported. May be repeated
+    #[arg(long, value_name = "'NAME = EXPRESSION'")]
+    pub computed: Vec<ComputedColumn>,
+    /// Changes made to a column's values as they are imported, in order, such as
+    /// 'email: trim, lower'. The transforms are trim, lower, upper and nullify_if("N/A"), which
+    /// empties values equal to the text. Values are transformed before they are filtered. May be
+    /// repeated, once per column
+    #[arg(long, value_name = "'COLUMN: TRANSFORM, ...'")]
+    pub transform: Vec<ColumnTransforms>,
+    /// Import only the rows matching a filter on their columns, such as
+    /// 'country = "DE" and amount > 100'. Columns are compared with =, !=, contains, <, <=, > and
+    /// >=, and comparisons combined with and, or and parentheses
+    #[arg(long, value_name = "FILTER")]
+    pub filter: Option<RowFilter>,
+    /// Import a sample of the rows, keeping each with this chance, such as 0.01 for 1%. Rows are
+    /// sampled as they are read, before they are filtered. The table is marked as a sample, and
+    /// the import cannot be resumed
+    #[arg(long, value_name = "RATE", value_parser = sample::parse_rate,
+          conflicts_with_all = ["sample_rows", "resume", "dry_run", "generate_sql"])]
+    pub sample_rate: Option<f64>,
+    /// Import a sample of this many of the rows matching any --filter, chosen at random once every
+    /// row is read, and inserted in no particular order. The table is marked as a sample, and the
+    /// import cannot be resumed
+    #[arg(long, value_name = "ROWS", conflicts_with_all = ["resume", "dry_run", "generate_sql"])]
+    pub sample_rows: Option<NonZeroU64>,
+    /// With --sample-rows, import the first rows instead of a random sample, reading no further
+    #[arg(long, requires = "sample_rows")]
+    pub sample_first: bool,
+    /// The seed choosing the rows of a random sample, so that the same rows can be chosen again.
+    /// Without one, a seed is picked and printed. Needs --sample-rate or --sample-rows
+    #[arg(long, value_name = "SEED", requires = "sampling")]
+    pub sample_seed: Option<u64>,
+    /// Validate the CSV dataset without importing it, reporting the table which would be
+    /// created and any rows which would be rejected. No database connection is made
+    #[arg(long, conflicts_with_all = ["resume", "query"])]
+    pub dry_run: bool,
+    /// Write a psql script creating the table and importing the dataset to this file, rather
+    /// than connecting to the database. Rows are inlined as INSERT statements, or read from the
+    /// CSV file with \copy when sent with COPY
+    #[arg(long, value_name = "PATH",
+          conflicts_with_all = [
+              "dry_run", "resume", "strict_schema", "computed", "key", "on_conflict", "query", "batch"
+          ])]
+    pub generate_sql: Option<String>,
+    /// Ask for the import plan to be confirmed on stdin even in non-interactive mode, where it is
+    /// otherwise only printed. The import fails unless the answer is y
+    #[arg(long)]
+    pub confirm: bool,
+    /// SQL query to run, 'pivot' to build a pivot table, 'top <column> [N]' for the most common
+    /// values of a column, or 'bucket <column> <granularity> [count|sum:<column>]' for counts
+    /// or sums over time. Implies non-interactive mode. With -, the query is read from stdin
+    /// until it ends, such as from a pipe or a heredoc
+    #[arg(long)]
+    pub query: Option<String>,
+    /// Run each query of this batch spec once the dataset is imported, writing the results of
+    /// each to a file of its own in the output directory. Implies non-interactive mode
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["query", "format", "materialize", "remote", "output"])]
+    pub batch: Option<String>,
+    /// Once the dataset is being imported, query the tables in a terminal UI, with panes for the
+    /// tables and their columns, the query and its results. stdin and stdout must be terminals
+    #[arg(long, conflicts_with_all = [
+        "query", "batch", "dry_run", "generate_sql", "record", "replay", "choose_columns"
+    ])]
+    pub tui: bool,
+    /// A value for the query's $1, $2 and so on, in order. It is parsed as the type Postgres
+    /// infers for its placeholder, unless a type of int, float, numeric, bool, date or text
+    /// follows it, as in 100:int. May be repeated
+    #[arg(long, value_name = "VALUE[:TYPE]")]
+    pub param: Vec<String>,
+    /// For the 'pivot' query: the columns identifying each row, separated by commas
+    #[arg(long, value_name = "COLUMNS")]
+    pub pivot_rows: Option<String>,
+    /// For the 'pivot' query: the column whose distinct values become columns
+    #[arg(long, value_name = "COLUMN")]
+    pub pivot_column: Option<String>,
+    /// For the 'pivot' query: the column with the values to aggregate
+    #[arg(long, value_name = "COLUMN")]
+    pub pivot_value: Option<String>,
+    /// For the 'pivot' query: how values are aggregated, one of sum, count, avg, min or max
+    #[arg(long, value_name = "AGGREGATE")]
+    pub pivot_aggregate: Option<String>,
+    /// For the 'top' query: add the cumulative percentage of rows
+    #[arg(long)]
+    pub top_cumulative: bool,
+    /// For the 'top' query: list NULL and empty values as groups of their own, labeled (NULL)
+    /// and (empty), after the top values
+    #[arg(long)]
+    pub top_group_blanks: bool,
+    /// For the 'cardinality' query: count the distinct values exactly, as well as estimating
+    /// them, without asking first. Counting exactly reads every row of the table
+    #[arg(long)]
+    pub exact_cardinality: bool,
+    /// What to do with the query results: 'csv', 'json', 'show' or 'expanded', which shows each
+    /// row as a block of lines. In non-interactive mode, defaults to 'show' when stdout is not a
+    /// terminal
+    #[arg(long)]
+    pub format: Option<String>,
+    /// Save the query results in a new table with this name, instead of outputting them.
+    /// With --replace, an existing table is dropped first
+    #[arg(long, value_name = "TABLE", conflicts_with = "format")]
+    pub materialize: Option<String>,
+    /// Copy the query results to a table of another database, instead of outputting them. The
+    /// table is created, or added to if it has the same columns
+    #[arg(long, value_name = "TABLE", conflicts_with_all = ["format", "materialize"])]
+    pub remote: Option<String>,
+    /// The database --remote copies the results to. Defaults to the remote_postgres_url config
+    #[arg(long, value_name = "URL", requires = "remote")]
+    pub remote_url: Option<String>,
+    /// Output file, for the 'csv' and 'json' formats
+    #[arg(long)]
+    pub output: Option<String>,
+    /// Directory for output files when --output is not given. Each is named after the CSV
+    /// file, or the table. Overrides the config
+    #[arg(long, value_name = "DIR")]
+    pub output_dir: Option<String>,
+    /// If the query returns no rows, write nothing and remove the output file, rather than
+    /// writing the header
+    #[arg(long)]
+    pub no_empty_file: bool,
+    /// Write a manifest beside the output file, with its SHA-256 hash and the query which
+    /// produced it
+    #[arg(long)]
+    pub manifest: bool,
+    /// Write the rows of output files in the same order every time the query is run. Queries
+    /// without an ORDER BY of their own are ordered by their _source_line column if they have
+    /// one, and otherwise sorted by data-sifter, which refuses more than the sort_row_cap config
+    /// of rows. Without it, such queries are only warned about
+    #[arg(long)]
+    pub deterministic_order: bool,
+    /// How array values are written
+    #[arg(long, value_enum, default_value_t)]
+    pub array_format: ArrayFormat,
+    /// A json or jsonb column whose top-level keys become output columns of their own
+    #[arg(long, value_name = "COLUMN")]
+    pub expand_json: Option<String>,
+    /// How many rows are scanned to discover the keys of the --expand-json column.
+    /// Keys first appearing later are not expanded
+    #[arg(long, value_name = "ROWS", default_value_t = 1000)]
+    pub expand_json_scan: usize,
+    /// Write only these columns of the results, separated by commas, in the order listed
+    #[arg(long, value_name = "COLUMNS", conflicts_with = "sort_columns")]
+    pub columns: Option<String>,
+    /// Write the columns of the results sorted by name
+    #[arg(long)]
+    pub sort_columns: bool,
+    /// Before each query runs, list the columns it returns and ask which of them to write, as in
+    /// 'keep 1-5' or 'drop 3,7'. Only for interactive mode
+    #[arg(long, conflicts_with_all = ["query", "columns", "sort_columns", "expand_json"])]
+    pub choose_columns: bool,
+    /// Fail if the query returns more than one column with the same name, rather than numbering
+    /// the later ones apart, as in id_2
+    #[arg(long)]
+    pub strict_column_names: bool,
+    /// How column names are cased in the header
+    #[arg(long, value_enum, default_value_t)]
+    pub header_case: HeaderCase,
+    /// Timezone for timestamptz values: UTC, local or an IANA name such as America/New_York.
+    /// Overrides the config
+    #[arg(long)]
+    pub timezone: Option<Timezone>,
+    /// strftime-style format for timestamptz values, such as '%Y-%m-%d %H:%M'. Plain timestamps
+    /// are written as they are. Overrides the config
+    #[arg(long, value_name = "FORMAT")]
+    pub timestamp_format: Option<TimestampFormat>,
+    /// Round floating point and numeric values to fixed decimal places, for all columns or,
+    /// given as COLUMN=PLACES, for one column. May be repeated
+    #[arg(long, value_name = "[COLUMN=]PLACES")]
+    pub decimals: Vec<String>,
+    /// Group the digits of numbers with commas, e.g. 1,234,567. Only applies to the 'show' format
+    #[arg(long)]
+    pub thousands_separators: bool,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+    /// View, change or check the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Print the version, the commit and date it was built from, the optional features it was
+    /// built with, and the formats it reads and writes
+    Info {
+        /// Print it as a JSON document, for scripts
+        #[arg(long)]
+        json: bool
+    },
+    /// Drop the tables data-sifter created in the configured schema longer ago than an age,
+    /// after listing them and asking to confirm
+    Clean {
+        /// Such as 7d. Units are m, h, d and w
+        #[arg(long, value_name = "AGE", value_parser = crate::clean::parse_age)]
+        older_than: std::time::Duration,
+        /// List the tables which would be dropped, without dropping them
+        #[arg(long)]
+        dry_run: bool
+    }
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the configuration, with passwords masked, and where it was loaded from
+    Show,
+    /// Change a configuration value
+    Set {
+        /// The config key, such as postgres_url
+        key: String,
+        value: String
+    },
+    /// Check that the configuration is valid and that the database can be reached
+    Validate,
+    /// Store the database password in the keyring, for the user in postgres_url. It is asked for
+    /// without being shown, and used when password_source is keyring
+    SetPassword
+}
+
+impl Args {
+    pub fn non_interactive(&self) -> bool {
+        self.query.is_some() || self.batch.is_some()
+    }
+
+    /// How many chunks of the CSV file are parsed at once
+    pub fn jobs(&self) -> usize {
+        self.jobs
+            .or_else(|| std::thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get)
+    }
+
+    /// How many rows may be left out before the import stops, which is any number by default
+    pub fn reject_limit(&self) -> RejectLimit {
+        let sample = self.reject_sample.unwrap_or(reject::DEFAULT_SAMPLE_ROWS);
+        RejectLimit { sample, ..self.max_rejects.unwrap_or_default() }
+    }
+
+    /// How the rows imported are sampled, if they are. Each call picks another seed if none was given
+    pub fn sampling(&self) -> Option<Sampling> {
+        let seed = || self.sample_seed.unwrap_or_else(sample::default_seed);
+        match (self.sample_rate, self.sample_rows) {
+            (Some(rate), _) => Some(Sampling::Rate { rate, seed: seed() }),
+            (None, Some(rows)) if self.sample_first => Some(Sampling::First { rows: rows.get() }),
+            (None, Some(rows)) => Some(Sampling::Rows { rows: rows.get(), seed: seed() }),
+            (None, None) => None
+        }
+    }
+}
@@ -0,0 +1,280 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// The keywords suggested, in the order they are offered
+const KEYWORDS: &[&str] = &[
+    "SELECT", "FROM", "WHERE", "GROUP", "BY", "ORDER", "HAVING", "LIMIT", "OFFSET", "JOIN", "LEFT",
+    "RIGHT", "INNER", "OUTER", "FULL", "CROSS", "ON", "USING", "AS", "AND", "OR", "NOT", "NULL", "IS",
+    "IN", "LIKE", "ILIKE", "BETWEEN", "CASE", "WHEN", "THEN", "ELSE", "END", "DISTINCT", "COUNT", "SUM",
+    "AVG", "MIN", "MAX", "CAST", "EXISTS", "UNION", "ALL", "EXCEPT", "INTERSECT", "WITH", "ASC", "DESC",
+    "TRUE", "FALSE", "INSERT", "INTO", "VALUES", "UPDATE", "SET", "DELETE", "CREATE", "TABLE", "DROP"
+];
+
+/// Keywords after which a table is named
+const BEFORE_TABLES: &[&str] = &["FROM", "JOIN", "INTO", "UPDATE", "TABLE"];
+
+/// Keywords after which a column is named
+const BEFORE_COLUMNS: &[&str] = &[
+    "SELECT", "WHERE", "BY", "ON", "AND", "OR", "NOT", "HAVING", "SET", "DISTINCT", "WHEN", "THEN",
+    "ELSE", "USING"
+];
+
+/// What the word at the cursor is expected to be
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Context {
+    Table,
+    Column,
+    Anything
+}
+
+/// Suggests how to finish the word at the cursor of a query being entered: SQL keywords, the
+/// tables loaded in the session, and their columns. After FROM or JOIN, only tables are
+/// suggested, and after SELECT or WHERE, the columns of the tables the query names come first
+#[derive(Clone, Debug, Default)]
+pub(crate) struct QueryCompleter {
+    /// Each table, as it is named in queries, with its columns
+    tables: Vec<(String, Vec<String>)>
+}
+
+impl QueryCompleter {
+
+    pub(crate) fn new(tables: Vec<(String, Vec<String>)>) -> Self {
+        Self { tables }
+    }
+
+    /// Where the word being completed starts, as a byte offset into the line, and what it may
+    /// be replaced with, in the order offered
+    pub(crate) fn complete(&self, line: &str, cursor: usize) -> (usize, Vec<String>) {
+        if !line.is_char_boundary(cursor) {
+            return (cursor, Vec::new());
+        }
+        let before = &line[..cursor];
+        let start = before.char_indices().rev()
+            .take_while(|(_, character)| is_word(*character))
+            .last()
+            .map_or(cursor, |(index, _)| index);
+        let word = &before[start..];
+        let tokens = tokenize(line);
+
+        // A column of a table, or of the alias it was given
+        if let Some((qualifier, partial)) = word.rsplit_once('.') {
+            if let Some(columns) = self.table_named(qualifier, &tokens) {
+                let candidates = matching(columns.iter().map(String::as_str), partial);
+                return (start + qualifier.len() + 1, candidates);
+            }
+        }
+        let mut candidates = Vec::new();
+        match context(&tokenize(&before[..start])) {
+            Context::Table => candidates.extend(matching(self.tables.iter().map(|(table, _)| table.as_str()), word)),
+            Context::Column => {
+                candidates.extend(matching(self.columns_of_query(&tokens), word));
+                candidates.extend(keywords(word));
+            },
+            Context::Anything => {
+                candidates.extend(keywords(word));
+                candidates.extend(matching(self.tables.iter().map(|(table, _)| table.as_str()), word));
+                candidates.extend(matching(self.columns_of_query(&tokens), word));
+            }
+        }
+        let mut unique = Vec::with_capacity(candidates.len());
+        for candidate in candidates {
+            if !unique.contains(&candidate) {
+                unique.push(candidate);
+            }
+        }
+        (start, unique)
+    }
+
+    /// The columns of the table by this name, with or without its schema, or given this alias
+    fn table_named(&self, name: &str, tokens: &[String]) -> Option<&Vec<String>> {
+        let name = name.trim_matches('"');
+        let found = self.tables.iter().find(|(table, _)| names(table, name));
+        if let Some((_, columns)) = found {
+            return Some(columns);
+        }
+        // As in FROM sales s or FROM sales AS s
+        tokens.windows(2).chain(tokens.windows(3)).find_map(|window| {
+            let (table, alias) = match window {
+                [table, alias] => (table, alias),
+                [table, as_keyword, alias] if as_keyword.eq_ignore_ascii_case("AS") => (table, alias),
+                _ => return None
+            };
+            match alias.trim_matches('"').eq_ignore_ascii_case(name) {
+                true => self.tables.iter().find(|(loaded, _)| names(loaded, table)).map(|(_, columns)| columns),
+                false => None
+            }
+        })
+    }
+
+    /// The columns of the tables the query names, or else of every table
+    fn columns_of_query<'c>(&'c self, tokens: &[String]) -> impl Iterator<Item = &'c str> {
+        let named = self.tables.iter()
+            .filter(|(table, _)| tokens.iter().any(|token| names(table, token)))
+            .collect::<Vec<_>>();
+        let tables = match named.is_empty() {
+            true => self.tables.iter().collect(),
+            false => named
+        };
+        tables.into_iter().flat_map(|(_, columns)| columns.iter().map(String::as_str))
+    }
+}
+
+/// Whether the name refers to the table, whose schema, if it has one, may be left out
+fn names(table: &str, name: &str) -> bool {
+    let name = name.trim_matches('"');
+    let unqualified = table.rsplit_once('.').map_or(table, |(_, unqualified)| unqualified);
+    table.eq_ignore_ascii_case(name) || unqualified.eq_ignore_ascii_case(name)
+}
+
+fn is_word(character: char) -> bool {
+    character.is_alphanumeric() || matches!(character, '_' | '.' | '"')
+}
+
+/// The words and symbols of the text, leaving out string literals, which could hold anything
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut characters = text.chars().peekable();
+    while let Some(character) = characters.next() {
+        if is_word(character) {
+            let mut word = String::from(character);
+            while let Some(next) = characters.next_if(|next| is_word(*next)) {
+                word.push(next);
+            }
+            tokens.push(word);
+        } else if character == '\'' {
+            for next in characters.by_ref() {
+                if next == '\'' {
+                    break;
+                }
+            }
+            tokens.push(String::from("''"));
+        } else if !character.is_whitespace() {
+            tokens.push(String::from(character));
+        }
+    }
+    tokens
+}
+
+/// What comes next after the tokens. Tables follow FROM and the commas after it, until a table
+/// has been named, and likewise columns follow SELECT
+fn context(tokens: &[String]) -> Context {
+    let is_keyword = |token: &str| KEYWORDS.iter().any(|keyword| keyword.eq_ignore_ascii_case(token));
+    match tokens.last() {
+        Some(last) if !is_keyword(last) && last.chars().all(is_word) => return Context::Anything,
+        _ => {}
+    }
+    let keyword = tokens.iter().rev().find(|token| is_keyword(token));
+    match keyword {
+        Some(keyword) if BEFORE_TABLES.iter().any(|before| before.eq_ignore_ascii_case(keyword)) => Context::Table,
+        Some(keyword) if BEFORE_COLUMNS.iter().any(|before| before.eq_ignore_ascii_case(keyword)) => Context::Column,
+        _ => Context::Anything
+    }
+}
+
+/// The names starting with the word, ignoring case unless it is quoted, in which case they
+/// are quoted too
+fn matching<'n>(names: impl Iterator<Item = &'n str>, word: &str) -> Vec<String> {
+    match word.strip_prefix('"') {
+        Some(partial) => names.filter(|name| name.starts_with(partial)).map(|name| format!("\"{}\"", name)).collect(),
+        None => {
+            let lowercase = word.to_lowercase();
+            names.filter(|name| name.to_lowercase().starts_with(&lowercase)).map(String::from).collect()
+        }
+    }
+}
+
+/// The keywords starting with the word, in lowercase if it is written in lowercase
+fn keywords(word: &str) -> Vec<String> {
+    if word.starts_with('"') {
+        return Vec::new();
+    }
+    let lowercase = word.chars().any(char::is_lowercase) && !word.chars().any(char::is_uppercase);
+    KEYWORDS.iter()
+        .filter(|keyword| keyword.starts_with(&word.to_uppercase()))
+        .map(|keyword| if lowercase { keyword.to_lowercase() } else { String::from(*keyword) })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completer() -> QueryCompleter {
+        QueryCompleter::new(vec![
+            (String::from("sales"), vec![String::from("region"), String::from("amount"), String::from("sold_at")]),
+            (String::from("analyst.regions"), vec![String::from("region"), String::from("manager")])
+        ])
+    }
+
+    /// The candidates with the cursor at the end of the line
+    fn complete(line: &str) -> Vec<String> {
+        completer().complete(line, line.len()).1
+    }
+
+    #[test]
+    fn tables_after_from() {
+        assert_eq!(vec!["sales"], complete("SELECT * FROM sa"));
+        assert_eq!(vec!["sales", "analyst.regions"], complete("SELECT * FROM "));
+        assert_eq!(vec!["analyst.regions"], complete("SELECT * FROM sales s JOIN an"));
+        assert_eq!(vec!["analyst.regions"], complete("SELECT * FROM sales, an"));
+    }
+
+    #[test]
+    fn columns_after_select_and_where() {
+        assert_eq!(vec!["region"], complete("SELECT re"));
+        // Those of the table named come first, then keywords
+        assert_eq!(vec!["amount", "AS", "AND", "AVG", "ALL", "ASC"], complete("SELECT * FROM sales WHERE A"));
+        assert_eq!(vec!["manager", "min", "max"], complete("SELECT m"));
+        assert_eq!(vec!["region", "manager"], complete("SELECT * FROM analyst.regions WHERE ")[..2].to_vec());
+    }
+
+    #[test]
+    fn keywords_once_a_name_is_given() {
+        assert_eq!(vec!["WHERE", "WHEN"], complete("SELECT * FROM sales WH"));
+        assert_eq!(vec!["from", "full", "false"], complete("select region f"));
+        assert_eq!(vec!["GROUP"], complete("SELECT region FROM sales GR"));
+    }
+
+    #[test]
+    fn columns_of_qualified_tables() {
+        let completer = completer();
+        assert_eq!((9, vec![String::from("sold_at")]), completer.complete("SELECT s.so FROM sales s", 11));
+        let line = "SELECT r.m FROM sales JOIN analyst.regions AS r";
+        assert_eq!((9, vec![String::from("manager")]), completer.complete(line, 10));
+        assert_eq!(vec!["manager"], complete("SELECT regions.m"));
+        assert_eq!(vec!["\"region\""], complete("SELECT sales.\"re"));
+    }
+
+    #[test]
+    fn cursor_within_the_line() {
+        let completer = completer();
+        assert_eq!((7, vec![String::from("amount")]), completer.complete("SELECT am FROM sales", 9));
+        assert_eq!((9, Vec::<String>::new()), completer.complete("SELECT 'é'", 9));
+    }
+
+    #[test]
+    fn string_literals_are_left_out() {
+        assert_eq!(vec!["region"], complete("SELECT 'a FROM', re"));
+    }
+
+    #[test]
+    fn candidates_follow_the_tables() {
+        assert!(complete("SELECT * FROM ne").is_empty());
+        let completer = QueryCompleter::new(vec![(String::from("new_table"), vec![String::from("id")])]);
+        assert_eq!(vec!["new_table"], completer.complete("SELECT * FROM ne", 16).1);
+    }
+}
@@ -0,0 +1,160 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use sqlx::{Postgres, pool::PoolConnection};
+use crate::database::{self, column_argument, column_identifier, find_column};
+use crate::error::{Error, Result};
+
+/// A column computed from the others by a SQL expression, declared as `name = expression`.
+/// It is added to the table as a stored generated column once the dataset is imported. The
+/// name keeps its case, as the dataset's columns do, and may be given in double quotes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ComputedColumn {
+    name: String,
+    expression: String
+}
+
+impl std::str::FromStr for ComputedColumn {
+    type Err = String;
+
+    fn from_str(declaration: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, expression) = declaration.split_once('=')
+            .ok_or_else(|| format!("Invalid computed column {}. Use the form 'name = expression'", declaration))?;
+        let name = column_argument(name).map_err(|_| format!("Invalid computed column name {:?}", name.trim()))?;
+        let expression = expression.trim();
+        if expression.is_empty() {
+            return Err(format!("Computed column {} has no expression", column_identifier(&name)));
+        }
+        Ok(Self { name, expression: expression.to_owned() })
+    }
+}
+
+impl fmt::Display for ComputedColumn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = {}", column_identifier(&self.name), self.expression)
+    }
+}
+
+impl ComputedColumn {
+
+    /// Checks that the expression is valid for the table, returning the type of its values.
+    /// The expression is evaluated for a single row of NULLs, so the table may be empty.
+    pub(crate) async fn check(&self, table: &str,
+                              connection: &mut PoolConnection<Postgres>) -> Result<String> {
+        sqlx::query_scalar(&self.type_query(table))
+            .fetch_one(connection).await
+            .map_err(|error| self.error(error))
+    }
+
+    /// Adds the column to the table, computing it for every row. Appending to a table which
+    /// already has the column, by its name or one differing only in case, leaves it as it is.
+    /// Without generated columns, the column is added as a plain one and filled in once, so it
+    /// is not kept up to date as rows change.
+    pub(crate) async fn add_to(&self, table: &str, generated: bool,
+                               connection: &mut PoolConnection<Postgres>) -> Result<()> {
+        let column_type = self.check(table, &mut *connection).await?;
+        let existing = database::table_columns(table, &mut *connection).await
+            .map_err(|error| Error::from_database(error, Error::ImportData))?;
+        let name = match find_column(table, &self.name, &existing, |column| &column.name) {
+            Ok(column) => column_identifier(&column.name),
+            Err(_) => column_identifier(&self.name)
+        };
+        let statements = if generated {
+            vec![self.add_statement(table, &name, &column_type)]
+        } else {
+            self.fill_statements(table, &name, &column_type)
+        };
+        for statement in statements {
+            sqlx::query(&statement)
+                .execute(&mut *connection).await
+                .map_err(|error| self.error(error))?;
+        }
+        Ok(())
+    }
+
+    fn type_query(&self, table: &str) -> String {
+        format!(
+            "SELECT pg_typeof(({}))::text FROM (SELECT 1) AS one LEFT JOIN {} ON false",
+            self.expression, table
+        )
+    }
+
+    /// Adds the column, given the name as statements write it
+    fn add_statement(&self, table: &str, name: &str, column_type: &str) -> String {
+        format!(
+            "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {} GENERATED ALWAYS AS (({})) STORED",
+            table, name, column_type, self.expression
+        )
+    }
+
+    fn fill_statements(&self, table: &str, name: &str, column_type: &str) -> Vec<String> {
+        vec![
+            format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {}", table, name, column_type),
+            format!("UPDATE {} SET {} = ({})", table, name, self.expression)
+        ]
+    }
+
+    /// Names the declaration, since the database's message does not
+    fn error(&self, error: sqlx::Error) -> Error {
+        let context = format!("Computed column {} failed", self);
+        match Error::from_query(error) {
+            Error::QuerySyntax(report) => Error::QuerySyntax(report.wrap_err(context)),
+            Error::QueryRuntime(report) => Error::QueryRuntime(report.wrap_err(context)),
+            other => other
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_computed_columns() {
+        let computed: ComputedColumn = " Amount_Cents = round(amount::numeric * 100) ".parse().unwrap();
+        assert_eq!("\"Amount_Cents\" = round(amount::numeric * 100)", computed.to_string());
+        let computed: ComputedColumn = "\"Unit Price\" = amount / quantity".parse().unwrap();
+        assert_eq!("Unit Price", computed.name);
+        // Only the first = separates the name
+        let computed: ComputedColumn = "same = a = b".parse().unwrap();
+        assert_eq!("a = b", computed.expression);
+        assert!("no_expression =".parse::<ComputedColumn>().is_err());
+        assert!("round(amount)".parse::<ComputedColumn>().is_err());
+        assert!("\"bad name = 1".parse::<ComputedColumn>().is_err());
+    }
+
+    #[test]
+    fn computed_column_sql() {
+        let computed: ComputedColumn = "year = substr(date, 1, 4)".parse().unwrap();
+        assert_eq!(
+            "SELECT pg_typeof((substr(date, 1, 4)))::text FROM (SELECT 1) AS one LEFT JOIN data ON false",
+            computed.type_query("data")
+        );
+        assert_eq!(
+            "ALTER TABLE data ADD COLUMN IF NOT EXISTS year text GENERATED ALWAYS AS ((substr(date, 1, 4))) STORED",
+            computed.add_statement("data", "year", "text")
+        );
+        assert_eq!(
+            vec![
+                "ALTER TABLE data ADD COLUMN IF NOT EXISTS year text",
+                "UPDATE data SET year = (substr(date, 1, 4))"
+            ],
+            computed.fill_statements("data", "year", "text")
+        );
+    }
+}
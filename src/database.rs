@@ -15,55 +15,479 @@
  * limitations under the License.
  */
 
-use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use async_std::{future, task};
 use csv_async::StringRecord;
-use sqlx::{Column, Postgres, Row, Value, ValueRef};
-use sqlx::postgres::{PgRow, PgValue};
-use eyre::Result;
+use futures_lite::FutureExt;
+use sqlx::{Acquire, Column, Connection, Executor, Postgres, Row, TypeInfo, Value, ValueRef};
+use sqlx::postgres::{PgConnection, PgRow, PgValue};
 use futures_util::{StreamExt, stream::BoxStream};
 use sqlx::pool::PoolConnection;
+use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions};
+use crate::charset::TranscodedName;
+use crate::clean;
+use crate::config::Config;
+use crate::decode::{DecodedValue, TextCast};
+use crate::error::{Categorize, Error, Result};
+use crate::parameters::{self, Parameter};
+use crate::output::{
+    self, ColumnLayout, CountingWriter, ExportOptions, JsonExpansion, OutputFormat, OutputSummary, ResultWriter,
+    RowBuffer
+};
+use crate::progress::ExportProgress;
+use crate::statements;
 
 pub(crate) type ResultSet<'r> = BoxStream<'r, Result<PgRow, sqlx::Error>>;
 
-pub(crate) struct DecodedValue<'v> {
-    data: Cow<'v, str>
-}
-
-impl<'v> From<&'v PgValue> for DecodedValue<'v> {
-    fn from(value: &'v PgValue) -> Self {
-        let data = {
-            if let Ok(decoded) = value.try_decode::<&str>() {
-                Cow::Borrowed(decoded)
-            } else if let Ok(decoded) = value.try_decode::<i32>() {
-                Cow::Owned(decoded.to_string())
-            } else if let Ok(decoded) = value.try_decode::<i64>() {
-                Cow::Owned(decoded.to_string())
-            } else if let Ok(decoded) = value.try_decode::<f32>() {
-                Cow::Owned(decoded.to_string())
-            } else if let Ok(decoded) = value.try_decode::<f64>() {
-                Cow::Owned(decoded.to_string())
-            } else if let Ok(decoded) = value.try_decode::<rust_decimal::Decimal>() {
-                Cow::Owned(decoded.to_string())
-            } else {
-                panic!("No determinable value for type info {:?}", value.type_info())
+/// How many connections the pool opens at most, unless the max_connections config says otherwise
+pub(crate) const MAX_CONNECTIONS: u32 = 10;
+/// How long a query waits for a connection while the pool's are all in use, unless the
+/// connection_wait_secs config says otherwise
+pub(crate) const CONNECTION_WAIT_SECS: u64 = 300;
+/// How often the wait for a connection is reported
+const WAIT_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a connection is kept open while unused. Firewalls and NAT gateways often drop
+/// connections idle for longer than five minutes without telling either end
+const IDLE_TIMEOUT: Duration = Duration::from_secs(4 * 60);
+/// How long a connection is kept open at most, after which it is closed once it is given back
+const MAX_LIFETIME: Duration = Duration::from_secs(30 * 60);
+/// The server's TCP keepalive settings for each connection: how many seconds idle before it
+/// probes, how many seconds between probes, and how many unanswered probes close it. sqlx
+/// cannot enable keepalive on its side, but the server's probes keep middleboxes from dropping
+/// a connection all the same. Connections over a Unix socket ignore them
+const TCP_KEEPALIVES: [(&str, &str); 3] = [
+    ("tcp_keepalives_idle", "60"), ("tcp_keepalives_interval", "10"), ("tcp_keepalives_count", "6")
+];
+
+/// How to connect to the database at the URL, with the SSL settings from the config in place of
+/// the URL's own
+pub(crate) fn connect_options(config: &Config, url: &str) -> Result<PgConnectOptions> {
+    let options = url.parse::<PgConnectOptions>().map_err(|error| Error::from_database(error, Error::Config))?;
+    config.ssl.apply(options).categorize(Error::Config)
+}
+
+/// Options for connecting to the database, with the session settings from the config
+pub(crate) fn pool_options(config: &Config) -> PgPoolOptions {
+    let client_min_messages = config.client_min_messages.clone();
+    let schema = config.schema.clone();
+    let session_settings = config.session_settings.clone();
+    let max_connections = config.max_connections.unwrap_or(MAX_CONNECTIONS);
+    PgPoolOptions::new()
+        .max_connections(max_connections)
+        .idle_timeout(IDLE_TIMEOUT)
+        .max_lifetime(MAX_LIFETIME)
+        // A connection which was dropped while idle is replaced before it is handed out
+        .test_before_acquire(true)
+        .after_connect(move |connection| {
+            let client_min_messages = client_min_messages.clone();
+            let schema = schema.clone();
+            let session_settings = session_settings.clone();
+            Box::pin(async move {
+                for (setting, value) in TCP_KEEPALIVES {
+                    sqlx::query("SELECT set_config($1, $2, false)")
+                        .bind(setting).bind(value)
+                        .execute(&mut *connection).await?;
+                }
+                if let Some(client_min_messages) = client_min_messages {
+                    sqlx::query("SELECT set_config('client_min_messages', $1, false)")
+                        .bind(client_min_messages)
+                        .execute(&mut *connection).await?;
+                }
+                if let Some(schema) = schema {
+                    create_schema(&schema, &mut *connection).await?;
+                    // Searched first, so that queries can name tables without their schema
+                    sqlx::query("SELECT set_config('search_path', $1 || ', ' || current_setting('search_path'), false)")
+                        .bind(schema)
+                        .execute(&mut *connection).await?;
+                }
+                for (setting, value) in session_settings {
+                    sqlx::query("SELECT set_config($1, $2, false)")
+                        .bind(&setting).bind(&value)
+                        .execute(&mut *connection).await
+                        // Named, since the error otherwise only says that a connection failed
+                        .map_err(|error| {
+                            let reason = error.as_database_error().map_or_else(|| error.to_string(), |error| {
+                                error.message().to_owned()
+                            });
+                            sqlx::Error::Configuration(format!(
+                                "Invalid session setting {} = {}: {}", setting, value, reason
+                            ).into())
+                        })?;
+                }
+                Ok(())
+            })
+        })
+}
+
+/// The value each session setting has on the connection, as the server shows it
+pub(crate) async fn session_setting_values(settings: impl Iterator<Item=&str>, connection: &mut PgConnection)
+                                           -> Result<Vec<(String, String)>, sqlx::Error> {
+    let mut values = Vec::new();
+    for setting in settings {
+        let value = sqlx::query_scalar("SELECT current_setting($1)").bind(setting).fetch_one(&mut *connection).await?;
+        values.push((String::from(setting), value));
+    }
+    Ok(values)
+}
+
+/// Acquires a connection, waiting for as long as max_wait while all of the pool's, of which there
+/// are at most pool_size, are in use, rather than failing once the pool times out. Every so
+/// often, the time waited so far is reported. Other failures, such as a database which cannot
+/// be reached, are returned as soon as the pool gives up
+pub(crate) async fn acquire_waiting(pool: &PgPool, pool_size: u32, max_wait: Duration,
+                                    mut report: impl FnMut(Duration)) -> Result<PoolConnection<Postgres>, sqlx::Error> {
+    let started = Instant::now();
+    let acquired = async {
+        loop {
+            match pool.acquire().await {
+                Err(sqlx::Error::PoolTimedOut) if pool.size() >= pool_size && pool.num_idle() == 0 => (),
+                acquired => return acquired
             }
-        };
-        Self { data }
+        }
+    };
+    let reported = async {
+        loop {
+            task::sleep(WAIT_REPORT_INTERVAL).await;
+            report(started.elapsed());
+        }
+    };
+    future::timeout(max_wait, acquired.or(reported)).await.unwrap_or(Err(sqlx::Error::PoolTimedOut))
+}
+
+/// Creates the schema unless it exists. Without permission to create it, it is left to the
+/// statements using it to fail if it does not exist
+async fn create_schema(schema: &str, connection: &mut PgConnection) -> Result<(), sqlx::Error> {
+    // Checked first, since Postgres notices when the schema already exists
+    let exists: bool = sqlx::query_scalar("SELECT to_regnamespace($1) IS NOT NULL")
+        .bind(schema).fetch_one(&mut *connection).await?;
+    if exists {
+        return Ok(());
+    }
+    match sqlx::query(&format!("CREATE SCHEMA IF NOT EXISTS {}", schema)).execute(connection).await {
+        Err(sqlx::Error::Database(error)) if error.code().as_deref() == Some("42501") => Ok(()),
+        // Another connection of the pool created it in the meantime
+        Err(sqlx::Error::Database(error)) if error.code().as_deref() == Some("23505") => Ok(()),
+        result => result.map(|_| ())
+    }
+}
+
+/// How the dataset is written to its table
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum TableMode {
+    /// Drop any existing table and create it anew
+    Replace,
+    /// Add rows to the existing table, creating it if necessary
+    Append,
+    /// Add rows to the existing table, whose columns the dataset must match. The table is
+    /// never created or dropped
+    Strict
+}
+
+/// What is done with a row whose key is already in the table
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum OnConflict {
+    /// The import stops, as it does without a key
+    #[default]
+    Error,
+    /// The row in the table takes the other values of the row imported
+    Update,
+    /// The row imported is left out
+    Ignore
+}
+
+impl fmt::Display for OnConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Error => "error",
+            Self::Update => "update",
+            Self::Ignore => "ignore"
+        })
+    }
+}
+
+/// The columns identifying a row of the table, and what is done with rows whose key is already in it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ConflictKey {
+    pub(crate) columns: Vec<String>,
+    pub(crate) on_conflict: OnConflict
+}
+
+impl ConflictKey {
+
+    /// Parses key columns separated by commas, such as 'store, day'
+    pub(crate) fn parse(columns: &str, on_conflict: OnConflict) -> eyre::Result<Self> {
+        let columns = columns.split(',').map(str::trim).map(String::from).collect::<Vec<_>>();
+        if let Some(invalid) = columns.iter().find(|column| !is_plain_identifier(column)) {
+            return Err(eyre::eyre!("Invalid key column {:?}. Separate key columns with commas", invalid));
+        }
+        Ok(Self { columns, on_conflict })
+    }
+}
+
+impl fmt::Display for ConflictKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, {}", self.columns.join(", "), match self.on_conflict {
+            OnConflict::Error => "stopping at rows already in the table",
+            OnConflict::Update => "updating rows already in the table",
+            OnConflict::Ignore => "skipping rows already in the table"
+        })
+    }
+}
+
+/// Creates a unique index on the key columns, unless the table already has one on exactly them,
+/// such as its primary key. ON CONFLICT needs one to tell which rows conflict
+pub(crate) async fn ensure_unique_key(table: &str, key: &[String],
+                                      connection: &mut PoolConnection<Postgres>) -> Result<(), sqlx::Error> {
+    let exists: bool = sqlx::query_scalar("\
+        SELECT EXISTS (SELECT FROM pg_catalog.pg_index index \
+        WHERE index.indrelid = to_regclass($1) AND index.indisunique \
+        AND index.indpred IS NULL AND index.indexprs IS NULL \
+        AND (SELECT array_agg(attribute.attname::text ORDER BY attribute.attname::text) \
+            FROM pg_catalog.pg_attribute attribute \
+            WHERE attribute.attrelid = index.indrelid AND attribute.attnum = ANY(index.indkey::int2[])) \
+        = (SELECT array_agg(column_name ORDER BY column_name) FROM unnest($2::text[]) column_name))")
+        .bind(table).bind(key).fetch_one(&mut *connection).await?;
+    if !exists {
+        let key = key.iter().map(|column| column_identifier(column)).collect::<Vec<_>>();
+        sqlx::query(&format!("CREATE UNIQUE INDEX ON {} ({})", table, key.join(", ")))
+            .execute(&mut *connection).await?;
+    }
+    Ok(())
+}
+
+/// Keywords which Postgres does not accept as unquoted names
+pub(crate) const RESERVED_WORDS: &[&str] = &[
+    "all", "analyse", "analyze", "and", "any", "array", "as", "asc", "asymmetric", "authorization",
+    "binary", "both", "case", "cast", "check", "collate", "collation", "column", "concurrently",
+    "constraint", "create", "cross", "current_catalog", "current_date", "current_role",
+    "current_schema", "current_time", "current_timestamp", "current_user", "default", "deferrable",
+    "desc", "distinct", "do", "else", "end", "except", "false", "fetch", "for", "foreign", "freeze",
+    "from", "full", "grant", "group", "having", "ilike", "in", "initially", "inner", "intersect",
+    "into", "is", "isnull", "join", "lateral", "leading", "left", "like", "limit", "localtime",
+    "localtimestamp", "natural", "not", "notnull", "null", "offset", "on", "only", "or", "order",
+    "outer", "overlaps", "placing", "primary", "references", "returning", "right", "select",
+    "session_user", "similar", "some", "symmetric", "system_user", "table", "tablesample", "then",
+    "to", "trailing", "true", "union", "unique", "user", "using", "variadic", "verbose", "when",
+    "where", "window", "with"
+];
+
+/// Whether the name can be used as a table name without quoting: letters, digits and
+/// underscores, and not a reserved word
+pub(crate) fn is_plain_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(first) if first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !RESERVED_WORDS.contains(&name.to_ascii_lowercase().as_str())
+}
+
+/// The column name as statements write it. A plain lower-case name is written as it is, and
+/// any other is quoted, so that it keeps its case and may hold any character
+pub(crate) fn column_identifier(name: &str) -> String {
+    if is_plain_identifier(name) && !name.bytes().any(|byte| byte.is_ascii_uppercase()) {
+        String::from(name)
+    } else {
+        quote_identifier(name)
+    }
+}
+
+/// Splits the arguments of a helper such as `top` at whitespace and at the separators given,
+/// except within double quotes, which are kept for [column_argument] to read
+pub(crate) fn split_arguments(arguments: &str, separators: &[char]) -> eyre::Result<Vec<String>> {
+    let mut split = Vec::new();
+    let mut argument = String::new();
+    let mut quoted = false;
+    for c in arguments.chars() {
+        if c == '"' {
+            // A doubled quote closes the name and opens it again at once
+            quoted = !quoted;
+        } else if !quoted && (c.is_whitespace() || separators.contains(&c)) {
+            if !argument.is_empty() {
+                split.push(std::mem::take(&mut argument));
+            }
+            continue;
+        }
+        argument.push(c);
+    }
+    if quoted {
+        return Err(eyre::eyre!("Invalid column name: {}. Its quote is not closed", argument));
+    }
+    if !argument.is_empty() {
+        split.push(argument);
+    }
+    Ok(split)
+}
+
+/// Reads a column name given to a helper. A name in double quotes is read as it is, with
+/// doubled quotes standing for one, and any other as typed. Either is then found among the
+/// table's columns by [find_column]
+pub(crate) fn column_argument(argument: &str) -> eyre::Result<String> {
+    let argument = argument.trim();
+    let name = match argument.strip_prefix('"').and_then(|name| name.strip_suffix('"')) {
+        Some(name) if !name.replace("\"\"", "").contains('"') => name.replace("\"\"", "\""),
+        Some(_) => String::new(),
+        None if argument.contains('"') => String::new(),
+        None => String::from(argument)
+    };
+    if name.is_empty() {
+        return Err(eyre::eyre!("Invalid column name: {}", argument));
+    }
+    Ok(name)
+}
+
+/// Finds the column of the table which a helper's argument names: the one with exactly that
+/// name, or else the one whose name differs from it only in case, as if it had been written
+/// without quotes. The column's own name is then used, quoted by [column_identifier]
+pub(crate) fn find_column<'c, T>(table: &str, name: &str, columns: &'c [T],
+                                 name_of: impl Fn(&T) -> &str) -> Result<&'c T> {
+    if columns.is_empty() {
+        return Err(Error::Usage(eyre::eyre!("Table {} does not exist", table)));
     }
+    if let Some(column) = columns.iter().find(|column| name_of(column) == name) {
+        return Ok(column);
+    }
+    let mut matching = columns.iter().filter(|column| name_of(column).eq_ignore_ascii_case(name));
+    match (matching.next(), matching.next()) {
+        (Some(column), None) => Ok(column),
+        (Some(first), Some(second)) => Err(Error::Usage(eyre::eyre!(
+            "Column {} is not in table {}, but {} and {} differ from it only in case. \
+            Name one of them in double quotes",
+            name, table, quote_identifier(name_of(first)), quote_identifier(name_of(second))
+        ))),
+        (None, _) => Err(Error::Usage(eyre::eyre!(
+            "Column {} is not in table {}. Its columns are: {}", name, table,
+            columns.iter().map(name_of).collect::<Vec<_>>().join(", ")
+        )))
+    }
+}
+
+/// Quotes any name as an identifier, doubling the double quotes in it
+pub(crate) fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Quotes a value as a string literal, doubling the single quotes in it
+pub(crate) fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
 }
 
-impl<'v> AsRef<[u8]> for DecodedValue<'v> {
-    fn as_ref(&self) -> &[u8] {
-        match &self.data {
-            Cow::Borrowed(borrowed) => borrowed.as_bytes(),
-            Cow::Owned(owned) => owned.as_bytes()
+/// Whether the name can be used as a table name without quoting, with or without its schema
+pub(crate) fn is_table_name(name: &str) -> bool {
+    match name.split_once('.') {
+        Some((schema, table)) => is_plain_identifier(schema) && is_plain_identifier(table),
+        None => is_plain_identifier(name)
+    }
+}
+
+/// Qualifies a table name with the schema, unless it has one already
+pub(crate) fn qualify(table: &str, schema: Option<&str>) -> String {
+    match schema {
+        Some(schema) if !table.contains('.') => format!("{}.{}", schema, table),
+        _ => String::from(table)
+    }
+}
+
+/// Estimates the number of rows in a table, or returns `None` if there is no such table.
+///
+/// Planner statistics are used where available, since counting a large table is slow.
+/// An empty table is always reported as such.
+pub(crate) async fn estimate_row_count(table: &str,
+                                       connection: &mut PoolConnection<Postgres>) -> Result<Option<u64>, sqlx::Error> {
+    let estimate: Option<f32> = sqlx::query_scalar(
+        "SELECT reltuples FROM pg_class WHERE oid = to_regclass($1) AND relkind IN ('r', 'p')"
+    ).bind(table).fetch_optional(&mut *connection).await?;
+    let estimate = match estimate {
+        None => return Ok(None),
+        Some(estimate) => estimate
+    };
+    let any_rows: bool = sqlx::query_scalar(&format!("SELECT EXISTS (SELECT 1 FROM {})", table))
+        .fetch_one(&mut *connection).await?;
+    if !any_rows {
+        return Ok(Some(0));
+    }
+    // Statistics are unavailable if the table was never analyzed
+    if estimate >= 1.0 {
+        return Ok(Some(estimate as u64));
+    }
+    let count: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {}", table))
+        .fetch_one(&mut *connection).await?;
+    Ok(Some(count as u64))
+}
+
+/// Counts the rows of the table exactly, or returns none if there is no such table
+pub(crate) async fn count_rows(table: &str,
+                               connection: &mut PoolConnection<Postgres>) -> Result<Option<u64>, sqlx::Error> {
+    let exists: bool = sqlx::query_scalar("SELECT to_regclass($1) IS NOT NULL")
+        .bind(table).fetch_one(&mut *connection).await?;
+    if !exists {
+        return Ok(None);
+    }
+    let count: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM {}", table))
+        .fetch_one(&mut *connection).await?;
+    Ok(Some(count as u64))
+}
+
+/// A column of a table, as the database describes it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TableColumn {
+    pub(crate) name: String,
+    /// The type, e.g. text or numeric(10,2)
+    pub(crate) data_type: String,
+    /// The type, without modifiers such as a length, e.g. pg_catalog.int4, to which values loaded
+    /// into the column in strict mode are cast
+    pub(crate) type_name: String,
+    /// Whether the type is a string type, such as text or varchar
+    pub(crate) textual: bool,
+    /// Whether values are always generated, so that none can be inserted
+    pub(crate) generated: bool,
+    /// The expression computing the column, if it is a generated column
+    pub(crate) computed: Option<String>
+}
+
+/// A table's columns, in order. There are none if there is no such table
+pub(crate) async fn table_columns(table: &str,
+                                  connection: &mut PoolConnection<Postgres>) -> Result<Vec<TableColumn>, sqlx::Error> {
+    let columns: Vec<(String, String, String, bool, bool, Option<String>)> = sqlx::query_as("\
+        SELECT attribute.attname::text, format_type(attribute.atttypid, attribute.atttypmod), \
+        quote_ident(type_namespace.nspname) || '.' || quote_ident(type.typname), type.typcategory = 'S', \
+        attribute.attgenerated = 's' OR attribute.attidentity = 'a', \
+        CASE WHEN attribute.attgenerated = 's' THEN pg_get_expr(default_value.adbin, default_value.adrelid) END \
+        FROM pg_catalog.pg_attribute attribute \
+        JOIN pg_catalog.pg_type type ON type.oid = attribute.atttypid \
+        JOIN pg_catalog.pg_namespace type_namespace ON type_namespace.oid = type.typnamespace \
+        LEFT JOIN pg_catalog.pg_attrdef default_value \
+        ON default_value.adrelid = attribute.attrelid AND default_value.adnum = attribute.attnum \
+        WHERE attribute.attrelid = to_regclass($1) AND attribute.attnum > 0 AND NOT attribute.attisdropped \
+        ORDER BY attribute.attnum")
+        .bind(table).fetch_all(connection).await?;
+    Ok(columns.into_iter()
+        .map(|(name, data_type, type_name, textual, generated, computed)| TableColumn {
+            name, data_type, type_name, textual, generated, computed
+        })
+        .collect())
+}
+
+/// Formats a count for display, e.g. 1.2M
+pub(crate) fn approximate_count(count: u64) -> String {
+    const UNITS: [(u64, &str); 3] = [(1_000_000_000, "B"), (1_000_000, "M"), (1_000, "K")];
+    for (magnitude, suffix) in UNITS {
+        if count >= magnitude {
+            return format!("{:.1}{}", count as f64 / magnitude as f64, suffix);
         }
     }
+    count.to_string()
 }
 
 #[derive(Debug)]
 pub(crate) struct Schema {
-    columns: Vec<Box<str>>
+    columns: Vec<Box<str>>,
+    /// The types of the columns which are not text, where a fixed-width layout gives them
+    types: Vec<Option<String>>,
+    /// The column names which were read as Windows-1252, since they are not UTF-8
+    transcoded: Vec<TranscodedName>
 }
 
 impl<'s> From<&'s StringRecord> for Schema {
@@ -75,7 +499,9 @@ impl<'s> From<&'s StringRecord> for Schema {
 impl<'s> FromIterator<&'s str> for Schema {
     fn from_iter<T: IntoIterator<Item=&'s str>>(iter: T) -> Self {
         Self {
-            columns: iter.into_iter().map(Box::from).collect()
+            columns: iter.into_iter().map(Box::from).collect(),
+            types: Vec::new(),
+            transcoded: Vec::new()
         }
     }
 }
@@ -86,101 +512,1469 @@ impl Schema {
         self.columns.len()
     }
 
-    pub(crate) async fn create_or_recreate_table(&self, connection: &mut sqlx::pool::PoolConnection<Postgres>) -> Result<()> {
-        sqlx::query("DROP TABLE IF EXISTS data").execute(&mut *connection).await?;
+    pub(crate) fn column_name(&self, index: usize) -> &str {
+        &self.columns[index]
+    }
+
+    /// Gives the columns types other than text, where they are given
+    pub(crate) fn with_types(mut self, types: Vec<Option<String>>) -> Self {
+        self.types = types;
+        self
+    }
+
+    /// Notes the column names which were read as Windows-1252
+    pub(crate) fn with_transcoded(mut self, transcoded: Vec<TranscodedName>) -> Self {
+        self.transcoded = transcoded;
+        self
+    }
+
+    pub(crate) fn transcoded(&self) -> &[TranscodedName] {
+        &self.transcoded
+    }
+
+    /// The type of the column in the table created, unless it is text
+    pub(crate) fn column_type(&self, index: usize) -> Option<&str> {
+        self.types.get(index).and_then(Option::as_deref)
+    }
+
+    /// Creates the table for this schema. Depending on the mode, an existing table is either
+    /// dropped first or kept as-is. A table which is created is marked for `data-sifter clean`,
+    /// followed by the note, if there is one
+    pub(crate) async fn create_table(&self, table: &str, mode: TableMode, note: Option<&str>,
+                                     connection: &mut PoolConnection<Postgres>) -> Result<(), sqlx::Error> {
+        let existed = match mode {
+            TableMode::Append => sqlx::query_scalar("SELECT to_regclass($1) IS NOT NULL")
+                .bind(table).fetch_one(&mut *connection).await?,
+            TableMode::Replace | TableMode::Strict => false
+        };
+        for statement in self.create_table_statements(table, mode) {
+            sqlx::query(&statement).execute(&mut *connection).await?;
+        }
+        if mode != TableMode::Strict && !existed {
+            clean::mark_created(table, note, connection).await?;
+        }
+        Ok(())
+    }
 
-        let mut create_table_query = String::from("CREATE TABLE data (");
+    /// The statements [Schema::create_table] runs, in order. There are none in strict mode
+    pub(crate) fn create_table_statements(&self, table: &str, mode: TableMode) -> Vec<String> {
+        match mode {
+            TableMode::Strict => Vec::new(),
+            TableMode::Append => vec![self.create_table_statement(table, true)],
+            TableMode::Replace => vec![
+                format!("DROP TABLE IF EXISTS {}", table),
+                self.create_table_statement(table, false)
+            ]
+        }
+    }
+
+    /// The statement creating the table, in which every column is text unless given a type.
+    /// Empty values are NULL in columns of other types, since most cannot hold them
+    pub(crate) fn create_table_statement(&self, table: &str, if_not_exists: bool) -> String {
+        let mut statement = format!("CREATE TABLE {}{} (", if if_not_exists { "IF NOT EXISTS " } else { "" }, table);
         for (index, column_name) in self.columns.iter().enumerate() {
-            if index != 0 { create_table_query.push_str(", "); }
-            create_table_query.push_str(column_name);
-            create_table_query.push_str(" VARCHAR(256) NOT NULL");
+            if index != 0 { statement.push_str(", "); }
+            statement.push_str(&column_identifier(column_name));
+            match self.column_type(index) {
+                Some(column_type) => {
+                    statement.push(' ');
+                    statement.push_str(column_type);
+                },
+                None => statement.push_str(" TEXT NOT NULL")
+            }
+        }
+        statement.push(')');
+        statement
+    }
+
+    /// Matches the CSV columns to the columns of an existing table by name, ignoring case and
+    /// order. Every column of the table must be matched, except those whose values are always
+    /// generated, and every CSV column must match one. Otherwise, the error lists every difference.
+    pub(crate) fn match_existing(&self, table: &str, existing: &[TableColumn]) -> eyre::Result<InsertTarget> {
+        if existing.is_empty() {
+            return Err(eyre::eyre!(
+                "Table {} does not exist. In strict mode, datasets are only loaded into existing tables", table
+            ));
+        }
+        let mut differences = Vec::new();
+        let mut matched = vec![None; existing.len()];
+        let mut placeholders = Vec::with_capacity(self.len());
+        for (index, header) in self.columns.iter().enumerate() {
+            let found = match existing.iter().position(|column| column.name.eq_ignore_ascii_case(header)) {
+                Some(found) => found,
+                None => {
+                    differences.push(format!("{} is in the CSV file, but not the table", header));
+                    continue;
+                }
+            };
+            let column = &existing[found];
+            if column.generated {
+                differences.push(format!("{} is generated by the table, so it cannot be loaded", column.name));
+            } else if let Some(first) = matched[found].replace(index) {
+                differences.push(format!(
+                    "{} and {} in the CSV file both match {}", self.columns[first], header, column.name
+                ));
+            }
+            placeholders.push((column.name.clone(), Placeholder::Cast {
+                type_name: column.type_name.clone(),
+                empty_is_null: !column.textual
+            }));
+        }
+        for (column, matched) in existing.iter().zip(&matched) {
+            if matched.is_none() && !column.generated {
+                differences.push(format!("{} is in the table, but not the CSV file", column.name));
+            }
         }
-        create_table_query.push_str(")");
+        if !differences.is_empty() {
+            return Err(eyre::eyre!(
+                "The columns of the CSV file do not match table {}:\n  {}", table, differences.join("\n  ")
+            ));
+        }
+        let (columns, placeholders): (Vec<_>, _) = placeholders.into_iter().unzip();
+        let fields = (0..columns.len()).collect();
+        Ok(InsertTarget { columns, placeholders, fields, key: Vec::new(), on_conflict: OnConflict::Error })
+    }
 
-        sqlx::query(&create_table_query).execute(&mut *connection).await?;
+    /// Matches the CSV columns to the columns of an existing table which the dataset is added to,
+    /// by name, ignoring case, surrounding spaces and order. Columns of the table which are not in
+    /// the CSV file are left NULL, or given their default. CSV columns which are not in the table
+    /// are an error, unless they are to be left out. The columns are inserted in the table's order
+    pub(crate) fn match_appended(&self, table: &str, existing: &[TableColumn],
+                                 ignore_extra: bool) -> eyre::Result<(InsertTarget, AppendedColumns)> {
+        let mut appended = AppendedColumns::default();
+        let mut columns = Vec::new();
+        let mut placeholders = Vec::new();
+        let mut fields = Vec::new();
+        let mut matched = vec![false; self.len()];
+        for column in existing.iter().filter(|column| !column.generated) {
+            let mut headers = self.columns.iter().enumerate()
+                .filter(|(_, header)| header.trim().eq_ignore_ascii_case(column.name.trim()));
+            let field = match (headers.next(), headers.next()) {
+                (Some((field, _)), None) => field,
+                (Some((first, _)), Some((second, _))) => return Err(eyre::eyre!(
+                    "{} and {} in the CSV file both match column {} of table {}",
+                    self.columns[first], self.columns[second], column.name, table
+                )),
+                (None, _) => {
+                    appended.missing.push(column.name.clone());
+                    continue;
+                }
+            };
+            matched[field] = true;
+            appended.matched.push((self.columns[field].trim().to_string(), column.name.clone()));
+            columns.push(column.name.clone());
+            placeholders.push(Placeholder::Cast {
+                type_name: column.type_name.clone(),
+                empty_is_null: !column.textual
+            });
+            fields.push(field);
+        }
+        appended.extra = self.columns.iter().zip(&matched)
+            .filter(|(_, matched)| !**matched)
+            .map(|(header, _)| header.to_string())
+            .collect();
+        if columns.is_empty() {
+            return Err(eyre::eyre!("None of the columns of the CSV file are in table {}", table));
+        }
+        if !appended.extra.is_empty() && !ignore_extra {
+            return Err(eyre::eyre!(
+                "The CSV file has columns which table {} does not: {}. Pass --ignore-extra-columns to leave them out",
+                table, appended.extra.join(", ")
+            ));
+        }
+        let target = InsertTarget { columns, placeholders, fields, key: Vec::new(), on_conflict: OnConflict::Error };
+        Ok((target, appended))
+    }
+}
+
+/// How the columns of a dataset added to an existing table were matched to the table's
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct AppendedColumns {
+    /// Each CSV column loaded and the column of the table it is loaded into, in the table's order
+    pub(crate) matched: Vec<(String, String)>,
+    /// The columns of the table which are not in the CSV file
+    pub(crate) missing: Vec<String>,
+    /// The columns of the CSV file which are not in the table, and are left out
+    pub(crate) extra: Vec<String>
+}
+
+impl AppendedColumns {
+    /// The warning that columns of the table are left NULL, if any are
+    pub(crate) fn warning(&self, table: &str) -> Option<String> {
+        (!self.missing.is_empty()).then(|| format!(
+            "Left {} of table {} NULL, or their default, since the CSV file does not have them",
+            self.missing.join(", "), table
+        ))
+    }
+}
+
+impl fmt::Display for AppendedColumns {
+    /// E.g. id, name from Name, total; left NULL: notes; left out: extra
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let matched = self.matched.iter().map(|(header, column)| match header == column {
+            true => column.clone(),
+            false => format!("{} from {}", column, header)
+        });
+        write!(f, "{}", matched.collect::<Vec<_>>().join(", "))?;
+        if !self.missing.is_empty() {
+            write!(f, "; left NULL: {}", self.missing.join(", "))?;
+        }
+        if !self.extra.is_empty() {
+            write!(f, "; left out: {}", self.extra.join(", "))?;
+        }
         Ok(())
     }
+}
+
+/// The columns into which the values of each CSV column are inserted, and how
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct InsertTarget {
+    /// In the order of the CSV columns, unless matched to a table which the dataset is added to
+    columns: Vec<String>,
+    placeholders: Vec<Placeholder>,
+    /// The CSV field each column's values are read from
+    fields: Vec<usize>,
+    /// The columns identifying a row, if a key is declared
+    key: Vec<String>,
+    on_conflict: OnConflict
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Placeholder {
+    /// A text column, as data-sifter creates them
+    Text,
+    /// A column of another type, which values are cast to. Empty values may be inserted as NULL,
+    /// since they are not valid for most types
+    Cast { type_name: String, empty_is_null: bool }
+}
+
+impl InsertTarget {
+
+    /// The columns of the table created for the schema, which are text unless it gives their types
+    pub(crate) fn created(schema: &Schema) -> Self {
+        Self {
+            columns: schema.columns.iter().map(|column| String::from(&**column)).collect(),
+            placeholders: (0..schema.len())
+                .map(|index| match schema.column_type(index) {
+                    Some(type_name) => Placeholder::Cast { type_name: String::from(type_name), empty_is_null: true },
+                    None => Placeholder::Text
+                })
+                .collect(),
+            fields: (0..schema.len()).collect(),
+            key: Vec::new(),
+            on_conflict: OnConflict::Error
+        }
+    }
+
+    /// Declares the key of the table, whose columns must be among those inserted
+    pub(crate) fn with_key(self, key: &ConflictKey) -> eyre::Result<Self> {
+        let columns = key.columns.iter()
+            .map(|name| self.columns.iter().find(|column| column.eq_ignore_ascii_case(name)).cloned()
+                .ok_or_else(|| eyre::eyre!("Key column {} is not a column of the dataset", name)))
+            .collect::<eyre::Result<_>>()?;
+        Ok(Self { key: columns, on_conflict: key.on_conflict, ..self })
+    }
+
+    /// The key columns, as the table names them
+    pub(crate) fn key(&self) -> &[String] {
+        &self.key
+    }
+
+    /// Whether rows whose key is already in the table are updated or left out. INSERT statements
+    /// then return whether each row they wrote was inserted
+    pub(crate) fn resolves_conflicts(&self) -> bool {
+        !self.key.is_empty() && self.on_conflict != OnConflict::Error
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    /// The name of the column in the table
+    pub(crate) fn column(&self, column: usize) -> &str {
+        &self.columns[column]
+    }
 
+    /// The CSV field which the column's values are read from
+    pub(crate) fn field(&self, column: usize) -> usize {
+        self.fields[column]
+    }
+
+    /// The records one INSERT statement writes, in order. A statement cannot update a row twice,
+    /// so when rows already in the table are updated, only the last record of each key is
+    /// written, replacing those before it
+    pub(crate) fn written_records<'r>(&self, records: &'r [StringRecord]) -> Vec<&'r StringRecord> {
+        if !self.writes_last_of_each_key() {
+            return records.iter().collect();
+        }
+        let key_fields = self.key_fields();
+        let mut seen = HashSet::new();
+        let mut written = records.iter().rev()
+            .filter(|record| {
+                seen.insert(key_fields.iter().map(|field| record.get(*field).unwrap_or_default()).collect::<Vec<_>>())
+            })
+            .collect::<Vec<_>>();
+        written.reverse();
+        written
+    }
+
+    /// The values of a record to insert, in the order of the columns
+    pub(crate) fn values<'r>(&'r self, record: &'r StringRecord) -> impl Iterator<Item = &'r str> + 'r {
+        self.fields.iter().map(|field| record.get(*field).unwrap_or_default())
+    }
+
+    /// The type which values of the CSV column are cast to, unless they are inserted as text
+    pub(crate) fn cast_type(&self, column: usize) -> Option<&str> {
+        match &self.placeholders[column] {
+            Placeholder::Text => None,
+            Placeholder::Cast { type_name, .. } => Some(type_name)
+        }
+    }
+
+    /// The column names as statements write them
     pub(crate) fn column_names_joined_by_commas(&self) -> String {
-        let mut output = String::new();
-        for (index, column_name) in self.columns.iter().enumerate() {
-            if index != 0 { output.push_str(", "); }
-            output.push_str(column_name);
+        self.columns.iter().map(|column| column_identifier(column)).collect::<Vec<_>>().join(", ")
+    }
+
+    /// The options of a COPY statement reading empty values as INSERT statements bind them: as
+    /// NULL where the column's placeholder turns them into NULL, and as empty text otherwise
+    pub(crate) fn copy_null_options(&self) -> String {
+        let (null, not_null): (Vec<_>, Vec<_>) = self.columns.iter()
+            .zip(&self.placeholders)
+            .partition(|(_, placeholder)| matches!(placeholder, Placeholder::Cast { empty_is_null: true, .. }));
+        let names = |columns: Vec<(&String, &Placeholder)>| {
+            columns.into_iter().map(|(column, _)| column_identifier(column)).collect::<Vec<_>>().join(", ")
+        };
+        let mut options = String::new();
+        if !not_null.is_empty() {
+            options.push_str(&format!(", FORCE_NOT_NULL ({})", names(not_null)));
+        }
+        if !null.is_empty() {
+            options.push_str(&format!(", FORCE_NULL ({})", names(null)));
+        }
+        options
+    }
+
+    /// INSERT INTO data (col1, col2) VALUES ($1, $2), ($3, $4), ... for the number of records,
+    /// whose values are bound in order as the parameters
+    pub(crate) fn insert_statement(&self, table: &str, records: usize) -> String {
+        let columns = self.len();
+        let rows: Vec<String> = (0..records).map(|record| {
+            let values: Vec<String> = (0..columns)
+                .map(|column| self.placeholder(column, record * columns + column + 1))
+                .collect();
+            format!("({})", values.join(", "))
+        }).collect();
+        let statement = format!(
+            "INSERT INTO {} ({}) VALUES {}", table, self.column_names_joined_by_commas(), rows.join(", ")
+        );
+        self.with_conflict_clause(statement)
+    }
+
+    /// INSERT INTO data (col1, col2) SELECT * FROM UNNEST($1::bigint[], $2::text[]), binding an
+    /// array of each column's values in order as the parameters. The arrays' types are given by
+    /// dataset column, and may cast text to the column's type
+    pub(crate) fn unnest_statement(&self, table: &str, array_types: &[String]) -> String {
+        let arrays = self.fields.iter().enumerate()
+            .map(|(column, field)| format!("${}::{}", column + 1, array_types[*field]))
+            .collect::<Vec<_>>();
+        let statement = format!(
+            "INSERT INTO {} ({}) SELECT * FROM UNNEST({})",
+            table, self.column_names_joined_by_commas(), arrays.join(", ")
+        );
+        self.with_conflict_clause(statement)
+    }
+
+    /// The key columns of the dataset's rows, in the order of the key
+    pub(crate) fn key_fields(&self) -> Vec<usize> {
+        self.key.iter()
+            .filter_map(|key| self.columns.iter().position(|column| column == key))
+            .map(|column| self.fields[column])
+            .collect()
+    }
+
+    /// Whether a statement writes only the last row of each key, since it updates rows already
+    /// in the table and cannot update a row twice
+    pub(crate) fn writes_last_of_each_key(&self) -> bool {
+        self.resolves_conflicts() && self.on_conflict == OnConflict::Update
+    }
+
+    /// Adds what is done with rows whose key is already in the table to the INSERT statement
+    fn with_conflict_clause(&self, statement: String) -> String {
+        if !self.resolves_conflicts() {
+            return statement;
+        }
+        let updated = self.columns.iter().filter(|column| !self.key.contains(column))
+            .map(|column| format!("{0} = EXCLUDED.{0}", column_identifier(column)))
+            .collect::<Vec<_>>();
+        let action = match self.on_conflict {
+            OnConflict::Update if !updated.is_empty() => format!("UPDATE SET {}", updated.join(", ")),
+            // With every column in the key, there is nothing to update
+            _ => String::from("NOTHING")
+        };
+        // xmax is only zero for rows which were inserted, rather than updated
+        let key = self.key.iter().map(|column| column_identifier(column)).collect::<Vec<_>>();
+        format!("{} ON CONFLICT ({}) DO {} RETURNING xmax = 0", statement, key.join(", "), action)
+    }
+
+    /// COPY data (col1, col2) FROM STDIN WITH (FORMAT csv, ...) with the options of the dialect.
+    /// COPY reads every field, so the columns are listed in the order of their fields
+    pub(crate) fn copy_statement(&self, table: &str, dialect_options: &str) -> String {
+        let mut columns = self.fields.iter().zip(&self.columns).collect::<Vec<_>>();
+        columns.sort_by_key(|(field, _)| **field);
+        let columns = columns.into_iter().map(|(_, column)| column_identifier(column)).collect::<Vec<_>>().join(", ");
+        format!("COPY {} ({}) FROM STDIN WITH ({}{})", table, columns, dialect_options, self.copy_null_options())
+    }
+
+    /// The expression inserting a value of the column, bound as the given parameter
+    pub(crate) fn placeholder(&self, column: usize, parameter: usize) -> String {
+        self.value(column, &format!("${}", parameter))
+    }
+
+    /// The expression inserting the value of the column, which is a text parameter or literal
+    pub(crate) fn value(&self, column: usize, text: &str) -> String {
+        match &self.placeholders[column] {
+            Placeholder::Text => String::from(text),
+            Placeholder::Cast { type_name, empty_is_null: true } => {
+                format!("CAST(NULLIF({}, '') AS {})", text, type_name)
+            },
+            Placeholder::Cast { type_name, empty_is_null: false } => format!("CAST({} AS {})", text, type_name)
         }
-        output
     }
 }
 
+/// Whether the query returns rows, yet does not limit how many. A limit within a subquery
+/// does not count
+pub(crate) fn is_unlimited_select(query: &str) -> bool {
+    returns_rows(query) && !statements::is_limited(query)
+}
+
+/// Whether the query returns rows, judged by its first word
+pub(crate) fn returns_rows(query: &str) -> bool {
+    matches!(words(query).first().map(String::as_str), Some("select" | "with" | "values" | "table"))
+}
+
+/// Whether the query only reads, as far as can be told from its words: it returns rows, and
+/// writes neither rows, as a WITH may, nor a table, as SELECT INTO does
+pub(crate) fn is_read_only(query: &str) -> bool {
+    returns_rows(query) && !words(query).iter().any(|word| {
+        matches!(word.as_str(), "insert" | "update" | "delete" | "merge" | "into" | "nextval" | "setval")
+    })
+}
+
+/// Whether Postgres can prepare the statement, and so describe it without running it. Utility
+/// statements, such as VACUUM or SET, cannot be prepared
+pub(crate) fn is_preparable(query: &str) -> bool {
+    matches!(
+        words(query).first().map(String::as_str),
+        Some("select" | "with" | "values" | "table" | "insert" | "update" | "delete" | "merge")
+    )
+}
+
+fn words(query: &str) -> Vec<String> {
+    query.split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .filter(|word| !word.is_empty())
+        .map(str::to_ascii_lowercase)
+        .collect()
+}
+
+/// The query with its rows limited. It is wrapped in a subquery, which keeps any ORDER BY,
+/// and on lines of its own, in case it ends with a comment
+pub(crate) fn limited(query: &str, rows: u64) -> String {
+    format!("SELECT * FROM (\n{}\n) AS limited LIMIT {}", without_terminator(query), rows)
+}
+
+/// The query with its rows ordered by the column, wrapped in a subquery as a limited one is
+pub(crate) fn ordered_by(query: &str, column: &str) -> String {
+    format!("SELECT * FROM (\n{}\n) AS ordered ORDER BY {}", without_terminator(query), quote_identifier(column))
+}
+
+fn without_terminator(query: &str) -> &str {
+    query.trim_end().trim_end_matches(|c: char| c == ';' || c.is_whitespace())
+}
+
+/// A query wrapped so that the array columns of its results which cannot be decoded are cast
+/// to text, as Postgres writes them
+#[derive(Debug, PartialEq, Eq)]
+struct TextFallback {
+    query: String,
+    /// How many columns the query itself returns, before those added for arrays of more than
+    /// one dimension
+    columns: usize,
+    /// The columns whose arrays may have more than one dimension, with the added column in
+    /// which they are cast to text when they do, and NULL otherwise
+    nested: Vec<(usize, usize)>
+}
+
+impl TextFallback {
+
+    /// The query wrapped to cast its columns as given, or None if none of them are cast. Its
+    /// columns are renamed inside the wrapping, since their names need not be unique
+    fn new(query: &str, columns: &[(String, TextCast)]) -> Option<Self> {
+        if columns.iter().all(|(_, cast)| *cast == TextCast::Never) {
+            return None;
+        }
+        let inner = |index: usize| format!("fallback.column_{}", index + 1);
+        let mut selected = Vec::with_capacity(columns.len());
+        let mut added = Vec::new();
+        let mut nested = Vec::new();
+        for (index, (name, cast)) in columns.iter().enumerate() {
+            let value = match cast {
+                TextCast::Always => format!("{}::text", inner(index)),
+                _ => inner(index)
+            };
+            selected.push(format!("{} AS {}", value, quote_identifier(name)));
+            if *cast == TextCast::IfNested {
+                nested.push((index, columns.len() + added.len()));
+                added.push(format!(
+                    "CASE WHEN array_ndims({0}) > 1 THEN {0}::text END AS {1}", inner(index), quote_identifier(name)
+                ));
+            }
+        }
+        selected.extend(added);
+        let renamed = (0..columns.len()).map(|index| format!("column_{}", index + 1)).collect::<Vec<_>>();
+        Some(Self {
+            query: format!(
+                "SELECT {} FROM (\n{}\n) AS fallback({})",
+                selected.join(", "), without_terminator(query), renamed.join(", ")
+            ),
+            columns: columns.len(),
+            nested
+        })
+    }
+
+    /// The value of the column, or its added column when that has one
+    fn value<'v>(&self, values: &'v [PgValue], index: usize) -> &'v PgValue {
+        self.nested.iter()
+            .find(|(column, _)| *column == index)
+            .map(|(_, added)| &values[*added])
+            .filter(|added| !added.is_null())
+            .unwrap_or(&values[index])
+    }
+}
+
+/// A query with the connection it runs on. It owns both, rather than borrowing them, so that it
+/// can be kept across awaits, write its results more than once, and be moved to another task
 #[derive(Debug)]
 pub struct Query {
     pub connection: PoolConnection<Postgres>,
-    pub query: String
+    pub query: String,
+    /// Which of several statements entered together this is, and how many there were
+    pub statement: Option<(usize, usize)>,
+    /// The values bound to the query's $n placeholders
+    pub parameters: Vec<Parameter>,
+    /// The query last described and the names of its columns, so that it is described once
+    pub described: Option<(String, Vec<String>)>
 }
 
 impl Query {
-    pub fn execute(&mut self) -> QueryOutput {
-        let results = sqlx::query(&self.query).fetch(&mut self.connection);
-        QueryOutput {
-            results
+    /// Runs a statement entered before the query, such as SET, on the same connection.
+    /// Returns how many rows it affected
+    pub async fn run_before(&mut self, statement: &str) -> Result<u64, sqlx::Error> {
+        Ok(self.connection.execute(statement).await?.rows_affected())
+    }
+
+    /// The types Postgres infers for the query's parameters, by their names such as INT4
+    pub async fn parameter_types(&mut self) -> Result<Vec<String>, sqlx::Error> {
+        let description = (&mut *self.connection).describe(&self.query).await?;
+        // The statement prepared is cached, but those bound may be of other types than inferred
+        self.connection.clear_cached_statements().await?;
+        // Postgres always describes the type of each parameter, rather than just their number
+        let types = description.parameters().and_then(|parameters| parameters.left()).unwrap_or_default();
+        Ok(types.iter().map(|parameter_type| parameter_type.name().to_owned()).collect())
+    }
+
+    /// The names of the columns the query returns, found without running it, which also finds
+    /// syntax errors and unknown tables or columns. They are kept for as long as the query is
+    /// the same, so that choosing columns, checking the query and writing the header of empty
+    /// results describe it only once
+    pub async fn column_names(&mut self) -> Result<Vec<String>, sqlx::Error> {
+        if let Some((described, columns)) = &self.described {
+            if *described == self.query {
+                return Ok(columns.clone());
+            }
         }
+        let description = (&mut *self.connection).describe(&self.query).await?;
+        self.connection.clear_cached_statements().await?;
+        let columns: Vec<String> = description.columns().iter().map(|column| String::from(column.name())).collect();
+        self.described = Some((self.query.clone(), columns.clone()));
+        Ok(columns)
     }
-}
 
-pub struct QueryOutput<'r> {
-    pub results: ResultSet<'r>
-}
+    /// The query wrapped to cast the arrays of its results which cannot be decoded to text, or
+    /// None if it need not be. A query which cannot be described is left as it is, for running
+    /// it to report why
+    async fn text_fallback(&mut self) -> Result<Option<TextFallback>> {
+        if !returns_rows(&self.query) {
+            return Ok(None);
+        }
+        let description = match (&mut *self.connection).describe(&self.query).await {
+            Ok(description) => description,
+            Err(_) => return Ok(None)
+        };
+        self.connection.clear_cached_statements().await.map_err(Error::from_query)?;
+        let columns = description.columns().iter()
+            .map(|column| (String::from(column.name()), TextCast::of_type(column.type_info())))
+            .collect::<Vec<_>>();
+        Ok(TextFallback::new(&self.query, &columns))
+    }
 
-impl QueryOutput<'_> {
-    pub async fn output_query_results<W>(mut self,
-                                         csv_output: W) -> Result<bool>
-        where W: async_std::io::Write + Unpin {
+    /// How many rows the planner expects the query to return, and their average width in bytes,
+    /// found without running it. None if the query does not return rows
+    pub async fn estimate_rows(&mut self) -> Result<Option<(u64, u64)>, sqlx::Error> {
+        if !returns_rows(&self.query) {
+            return Ok(None);
+        }
+        let explain = format!("EXPLAIN (FORMAT JSON) {}", self.query);
+        let explained = parameters::bind(sqlx::query(&explain), &self.parameters)
+            .fetch_one(&mut *self.connection).await?;
+        let plan: serde_json::Value = explained.try_get(0)?;
+        let plan = &plan[0]["Plan"];
+        Ok(plan["Plan Rows"].as_f64().zip(plan["Plan Width"].as_u64()).map(|(rows, width)| (rows as u64, width)))
+    }
+
+    /// Runs the query again with EXPLAIN (ANALYZE, FORMAT JSON), for its plan with what each step
+    /// took. It runs in a transaction which is rolled back, in case the query changes anything
+    pub async fn explain_analyze(&mut self) -> Result<serde_json::Value, sqlx::Error> {
+        let mut transaction = self.connection.begin().await?;
+        let explain = format!("EXPLAIN (ANALYZE, FORMAT JSON) {}", self.query);
+        let explained = parameters::bind(sqlx::query(&explain), &self.parameters)
+            .fetch_one(&mut transaction).await?;
+        transaction.rollback().await?;
+        explained.try_get(0)
+    }
+
+    /// Saves the query results in a new table, first dropping any existing table if replacing.
+    /// Returns how many rows were saved
+    pub async fn materialize(&mut self, table: &str, replace: bool) -> Result<u64, sqlx::Error> {
+        let mut transaction = self.connection.begin().await?;
+        if replace {
+            sqlx::query(&format!("DROP TABLE IF EXISTS {}", table)).execute(&mut transaction).await?;
+        }
+        let created = parameters::bind(sqlx::query(&format!("CREATE TABLE {} AS {}", table, self.query)), &self.parameters)
+            .execute(&mut transaction).await?;
+        clean::mark_created(table, None, &mut transaction).await?;
+        transaction.commit().await?;
+        Ok(created.rows_affected())
+    }
 
-        let mut csv_output = csv_async::AsyncWriter::from_writer(csv_output);
+    /// Copies the results to a table of another database, streaming them with COPY from one
+    /// connection to the other. The table is created with the columns of the results, unless
+    /// it has them already, in which case the rows are added to it. It is all done in one
+    /// transaction of the other database, so that nothing is written to it unless every row is
+    pub async fn export_to(mut self, destination: &mut PgConnection, table: &str,
+                           strict_column_names: bool) -> Result<RemoteExport> {
+        let exported = self.copy_to(destination, table, strict_column_names).await;
+        if exported.is_err() {
+            // The connection may be part of the way through COPY, so it is closed rather than reused
+            drop(self.connection.detach());
+        }
+        exported
+    }
 
-        let first_row = match self.results.next().await {
-            Some(row) => row?,
-            None => return Ok(false)
+    async fn copy_to(&mut self, destination: &mut PgConnection, table: &str,
+                     strict_column_names: bool) -> Result<RemoteExport> {
+        let written = |error: sqlx::Error| Error::OutputIo(error.into());
+        let description = (&mut *self.connection).describe(&self.query).await.map_err(Error::from_query)?;
+        self.connection.clear_cached_statements().await.map_err(Error::from_query)?;
+        if description.columns().is_empty() {
+            return Err(Error::Usage(eyre::eyre!("The query returns no rows, so there is nothing to export")));
+        }
+        let (names, warnings) = output::unique_names(
+            description.columns().iter().map(|column| String::from(column.name())).collect(), strict_column_names
+        ).map_err(Error::QuerySyntax)?;
+        let type_names = description.columns().iter()
+            .map(|column| column.type_info().name().to_owned())
+            .collect::<Vec<_>>();
+        // Named as they are in SQL, rather than as sqlx names them, so that tables can be compared
+        let types: Vec<Option<String>> = sqlx::query_scalar(
+            "SELECT format_type(to_regtype(name), NULL) \
+            FROM unnest($1::text[]) WITH ORDINALITY AS listed(name, position) ORDER BY position"
+        ).bind(&type_names).fetch_all(&mut *self.connection).await.map_err(Error::from_query)?;
+        let mut columns = Vec::with_capacity(names.len());
+        for ((name, sql_type), type_name) in names.into_iter().zip(types).zip(&type_names) {
+            let sql_type = sql_type.ok_or_else(|| Error::Usage(eyre::eyre!(
+                "Column {} is of type {}, which cannot be created in another database", name, type_name
+            )))?;
+            columns.push((name, sql_type));
+        }
+        let column_list = columns.iter().map(|(name, _)| quote_identifier(name)).collect::<Vec<_>>().join(", ");
+
+        // COPY cannot bind parameters, so the results of a query with them are saved first
+        let query = self.query.trim_end().trim_end_matches(|c: char| c == ';' || c.is_whitespace());
+        let source = if self.parameters.is_empty() {
+            format!("(\n{}\n)", query)
+        } else {
+            let saved = format!("CREATE TEMPORARY TABLE {} ({}) AS\n{}\n", EXPORTED_RESULTS, column_list, query);
+            parameters::bind(sqlx::query(&saved), &self.parameters)
+                .execute(&mut *self.connection).await.map_err(Error::from_query)?;
+            String::from(EXPORTED_RESULTS)
         };
 
-        // Write header first
-        csv_output.write_record(
-            first_row
+        let mut transaction = Connection::begin(destination).await.map_err(written)?;
+        let exists: bool = sqlx::query_scalar("SELECT to_regclass($1) IS NOT NULL")
+            .bind(table).fetch_one(&mut transaction).await.map_err(written)?;
+        if exists {
+            let existing: Vec<(String, String)> = sqlx::query_as(
+                "SELECT attname::text, format_type(atttypid, NULL) FROM pg_attribute \
+                WHERE attrelid = to_regclass($1) AND attnum > 0 AND NOT attisdropped ORDER BY attnum"
+            ).bind(table).fetch_all(&mut transaction).await.map_err(written)?;
+            if existing != columns {
+                let listed = |columns: &[(String, String)]| columns.iter()
+                    .map(|(name, sql_type)| format!("{} {}", name, sql_type))
+                    .collect::<Vec<_>>().join(", ");
+                return Err(Error::Usage(eyre::eyre!(
+                    "Table {} exists in the other database with columns {}, but the results have columns {}. \
+                    Choose another table", table, listed(&existing), listed(&columns)
+                )));
+            }
+        } else {
+            let definitions = columns.iter()
+                .map(|(name, sql_type)| format!("{} {}", quote_identifier(name), sql_type))
+                .collect::<Vec<_>>().join(", ");
+            sqlx::query(&format!("CREATE TABLE {} ({})", table, definitions))
+                .execute(&mut transaction).await.map_err(written)?;
+        }
+
+        let copy_in = format!("COPY {} ({}) FROM STDIN (FORMAT binary)", table, column_list);
+        let mut copy_in = transaction.copy_in_raw(&copy_in).await.map_err(written)?;
+        let mut copy_out = self.connection.copy_out_raw(&format!("COPY {} TO STDOUT (FORMAT binary)", source))
+            .await.map_err(Error::from_query);
+        let mut sent = Ok(());
+        if let Ok(chunks) = &mut copy_out {
+            while let Some(chunk) = chunks.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(error) => {
+                        sent = Err(Error::from_query(error));
+                        break;
+                    }
+                };
+                if let Err(error) = copy_in.send(chunk).await {
+                    sent = Err(written(error));
+                    break;
+                }
+            }
+        }
+        let sent = copy_out.map(drop).and(sent);
+        if let Err(error) = sent {
+            // The server answers the abort with an error, which is only expected
+            let _ = copy_in.abort("The results could not be read").await;
+            return Err(error);
+        }
+        let rows = copy_in.finish().await.map_err(written)?;
+        transaction.commit().await.map_err(written)?;
+        if !self.parameters.is_empty() {
+            sqlx::query(&format!("DROP TABLE {}", EXPORTED_RESULTS))
+                .execute(&mut *self.connection).await.map_err(Error::from_query)?;
+        }
+        Ok(RemoteExport { rows, created: !exists, warnings })
+    }
+
+    /// Whether the query may run again on a fresh connection, should its own be lost. It must
+    /// only read, and no statements may have run before it, since a fresh connection would lack
+    /// what they did
+    pub fn may_run_again(&self) -> bool {
+        self.statement.is_none() && is_read_only(&self.query)
+    }
+
+    /// Writes the results, counting the rows and bytes written towards the progress. If there
+    /// are no rows, the header is still written unless the options say otherwise, since the
+    /// columns can be found by describing the query.
+    pub async fn output_query_results<W>(&mut self,
+                                         format: OutputFormat,
+                                         options: &ExportOptions,
+                                         output: W,
+                                         progress: Arc<ExportProgress>) -> Result<OutputSummary>
+        where W: async_std::io::Write + Unpin {
+
+        let fallback = self.text_fallback().await?;
+        let query = fallback.as_ref().map_or(self.query.as_str(), |fallback| fallback.query.as_str());
+        let mut results: ResultSet<'_> = parameters::bind(sqlx::query(query), &self.parameters)
+            .fetch(&mut *self.connection);
+        if let Some(first_row) = results.next().await.transpose().map_err(Error::from_query)? {
+            let columns = first_row
                 .columns()
                 .iter()
+                .take(fallback.as_ref().map_or(first_row.len(), |fallback| fallback.columns))
                 .map(|column| String::from(column.name()))
-                .collect::<Vec<_>>()
-        ).await?;
+                .collect::<Vec<_>>();
+            let rows = PgResultRows { columns, first_row: Some(first_row), results: Some(results), fallback: fallback.as_ref() };
+            return write_rows(rows, format, options, output, progress).await;
+        }
+        drop(results);
+        if !options.header_if_empty {
+            return Ok(OutputSummary::default());
+        }
+        let described = self.described.as_ref().filter(|(described, _)| *described == self.query);
+        let columns = match described {
+            Some((_, columns)) => columns.clone(),
+            None => {
+                let description = (&mut *self.connection).describe(&self.query).await
+                    .map_err(Error::from_query)?;
+                description.columns().iter().map(|column| String::from(column.name())).collect::<Vec<_>>()
+            }
+        };
+        // Statements other than queries have nothing to write
+        if columns.is_empty() {
+            return Ok(OutputSummary::default());
+        }
+        let rows = PgResultRows { columns, first_row: None, results: None, fallback: None };
+        write_rows(rows, format, options, output, progress).await
+    }
+}
+
+/// The temporary table in which the results of a query with parameters are saved, before they
+/// are copied to another database
+const EXPORTED_RESULTS: &str = "pg_temp.data_sifter_exported";
+
+/// What was copied to a table of another database
+#[derive(Debug)]
+pub struct RemoteExport {
+    pub rows: u64,
+    /// Whether the table was created, rather than added to
+    pub created: bool,
+    /// Problems which did not prevent the export, such as renamed columns
+    pub warnings: Vec<String>
+}
+
+/// The rows of query results, read one at a time, which write_rows writes for Postgres and
+/// DuckDB alike
+pub(crate) trait ResultRows {
+    /// A row as it is read, which its values are decoded from
+    type Row;
+
+    /// The names of the columns
+    fn columns(&self) -> &[String];
+
+    /// The next row, or none once every row has been read
+    async fn next_row(&mut self) -> Result<Option<Self::Row>>;
+
+    /// The values of the row, one for each column
+    fn decode<'r>(&self, row: &'r Self::Row) -> Result<Vec<DecodedValue<'r>>>;
+}
+
+/// The rows of a query's results from Postgres. The first row is read before them, in order to
+/// find the columns, and there are none to read if the results were found to be empty
+struct PgResultRows<'q> {
+    columns: Vec<String>,
+    first_row: Option<PgRow>,
+    results: Option<ResultSet<'q>>,
+    fallback: Option<&'q TextFallback>
+}
+
+impl ResultRows for PgResultRows<'_> {
+    type Row = Vec<PgValue>;
+
+    fn columns(&self) -> &[String] {
+        &self.columns
+    }
 
-        // Write first row
-        output_query_result_row(&first_row, &mut csv_output).await?;
+    async fn next_row(&mut self) -> Result<Option<Vec<PgValue>>> {
+        let row = match (self.first_row.take(), &mut self.results) {
+            (Some(row), _) => row,
+            (None, Some(results)) => match results.next().await {
+                Some(row) => row.map_err(Error::from_query)?,
+                None => return Ok(None)
+            },
+            (None, None) => return Ok(None)
+        };
+        (0..row.len()).map(|index| owned_value(&row, index)).collect::<Result<Vec<_>>>().map(Some)
+    }
+
+    fn decode<'r>(&self, row: &'r Vec<PgValue>) -> Result<Vec<DecodedValue<'r>>> {
+        self.columns.iter().enumerate()
+            .map(|(index, column)| {
+                let value = match self.fallback {
+                    Some(fallback) => fallback.value(row, index),
+                    None => &row[index]
+                };
+                DecodedValue::decode(value, column).categorize(Error::QueryRuntime)
+            })
+            .collect()
+    }
+}
+
+/// Writes the rows, counting the rows and bytes written towards the progress. If there are no
+/// rows, the header is still written unless the options say otherwise
+pub(crate) async fn write_rows<R, W>(mut rows: R,
+                                     format: OutputFormat,
+                                     options: &ExportOptions,
+                                     output: W,
+                                     progress: Arc<ExportProgress>) -> Result<OutputSummary>
+    where R: ResultRows, W: async_std::io::Write + Unpin {
+
+    let output = RowBuffer::new(CountingWriter::new(output, progress.clone()), options.flush_rows);
+    let mut writer = match options.readable {
+        Some(layout) => ResultWriter::readable(output, layout),
+        None => ResultWriter::new(format, output)
+    };
+    let mut summary = OutputSummary::default();
+    let columns = rows.columns().to_vec();
+
+    // Rows read ahead of writing, in order to sort them or discover the keys of an expanded column
+    let mut buffered_rows = Vec::new();
+    match rows.next_row().await? {
+        Some(row) => buffered_rows.push(row),
+        None if !options.header_if_empty => return Ok(summary),
+        None => ()
+    }
+    if let Some(cap) = options.sort_rows {
+        while let Some(row) = rows.next_row().await? {
+            if buffered_rows.len() as u64 >= cap {
+                return Err(Error::Usage(eyre::eyre!(
+                    "The query returns more than {} rows, too many to sort for a deterministic order. Add an \
+                    ORDER BY to the query, or raise the sort_row_cap config", cap
+                )));
+            }
+            buffered_rows.push(row);
+        }
+        buffered_rows = sorted(&rows, buffered_rows)?;
+    }
+    let mut expansion = None;
+    if let Some(column_index) = expanded_column(&columns, options)? {
+        while buffered_rows.len() < options.expand_json_scan {
+            match rows.next_row().await? {
+                Some(row) => buffered_rows.push(row),
+                None => break
+            }
+        }
+        let mut sample = Vec::with_capacity(buffered_rows.len());
+        for row in &buffered_rows {
+            sample.push(rows.decode(row)?.swap_remove(column_index));
+        }
+        expansion = Some(JsonExpansion::discover(column_index, sample));
+    }
+
+    // Write header first
+    let layout = column_layout(columns.clone(), expansion.as_ref(), options, &mut summary.warnings)?;
+    summary.columns = layout.header.clone();
+    writer.write_header(layout.header.clone()).await.categorize(Error::OutputIo)?;
 
-        // Write remaining rows
-        while let Some(row) = self.results.next().await {
-            let row = row?;
-            output_query_result_row(&row, &mut csv_output).await?;
+    // Write buffered rows, then remaining rows
+    let scanned_rows = buffered_rows.len();
+    let written = async {
+        // Each row is decoded before writing it waits, so that the rows, which need not be Sync, are
+        // not borrowed across the wait
+        for row in buffered_rows {
+            let decoded = rows.decode(&row)?;
+            write_row(decoded, &columns, expansion.as_mut(), &layout, options, &mut writer).await?;
+            progress.add_row();
+            summary.rows += 1;
         }
-        csv_output.flush().await?;
-        Ok(true)
+        while let Some(row) = rows.next_row().await? {
+            let decoded = rows.decode(&row)?;
+            write_row(decoded, &columns, expansion.as_mut(), &layout, options, &mut writer).await?;
+            progress.add_row();
+            summary.rows += 1;
+        }
+        Ok::<_, Error>(())
+    }.await;
+    if let Err(error) = written {
+        // The rows before the error are kept, and the error matters more than one writing them.
+        // Once the output is closed, though, there is nowhere to write them, and the rest of
+        // the rows are left unread for the query to be cancelled as its connection closes
+        if !error.closed_output() {
+            let _ = writer.abandon().await;
+        }
+        return Err(error);
     }
+    summary.truncated = writer.finish().await.categorize(Error::OutputIo)?;
+
+    if let (Some(expansion), Some(expand_column)) = (&expansion, &options.expand_json) {
+        summary.warnings.extend(expansion.warning(expand_column, scanned_rows));
+    }
+    Ok(summary)
 }
 
-async fn output_query_result_row<W>(row: &PgRow,
-                                    csv_writer: &mut csv_async::AsyncWriter<W>) -> Result<()>
+/// The index of the json column to expand, if there is one
+fn expanded_column(columns: &[String], options: &ExportOptions) -> Result<Option<usize>> {
+    match &options.expand_json {
+        Some(expand_column) => columns.iter().position(|column| column == expand_column).map(Some).ok_or_else(|| {
+            Error::Usage(eyre::eyre!("Cannot expand column {}, which is not in the query results", expand_column))
+        }),
+        None => Ok(None)
+    }
+}
+
+/// The output columns, with the json column expanded, named apart and arranged as the options
+/// say. Warnings about renamed and redacted columns are added to those given
+fn column_layout(columns: Vec<String>, expansion: Option<&JsonExpansion>, options: &ExportOptions,
+                 warnings: &mut Vec<String>) -> Result<ColumnLayout> {
+    let header = match expansion {
+        Some(expansion) => expansion.header(&columns),
+        None => columns
+    };
+    let (header, renamed) = output::unique_names(header, options.strict_column_names)
+        .map_err(Error::QuerySyntax)?;
+    warnings.extend(renamed);
+    let mut layout = ColumnLayout::new(&header, &options.column_order, options.header_case).map_err(Error::Usage)?;
+    warnings.extend(layout.redact(&options.redacted));
+    Ok(layout)
+}
+
+/// The rows in the order of their values, compared column by column
+fn sorted<R: ResultRows>(rows: &R, buffered_rows: Vec<R::Row>) -> Result<Vec<R::Row>> {
+    let keys = buffered_rows.iter().map(|row| rows.decode(row)).collect::<Result<Vec<_>>>()?;
+    let mut order = (0..buffered_rows.len()).collect::<Vec<_>>();
+    order.sort_by(|first, second| {
+        keys[*first].iter().zip(&keys[*second])
+            .map(|(value, other)| value.total_cmp(other))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    });
+    let mut buffered_rows = buffered_rows.into_iter().map(Some).collect::<Vec<_>>();
+    Ok(order.into_iter().filter_map(|index| buffered_rows[index].take()).collect())
+}
+
+fn owned_value(row: &PgRow, index: usize) -> Result<PgValue> {
+    let column_data = row.try_get_raw(index).categorize(Error::QueryRuntime)?;
+    Ok(ValueRef::to_owned(&column_data))
+}
+
+/// Writes a row with its decimal places rounded, the json column expanded and the columns arranged
+async fn write_row<W>(decoded_data: Vec<DecodedValue<'_>>,
+                      columns: &[String],
+                      expansion: Option<&mut JsonExpansion>,
+                      layout: &ColumnLayout,
+                      options: &ExportOptions,
+                      writer: &mut ResultWriter<W>) -> Result<()>
     where W: async_std::io::Write + Unpin {
 
-    let mut raw_row_data = Vec::new();
-    for index in 0..row.len() {
+    let decoded_data = decoded_data.into_iter().zip(columns)
+        .map(|(decoded, column)| match options.format.decimal_places.for_column(column) {
+            Some(places) => decoded.with_decimal_places(places),
+            None => decoded
+        })
+        .collect::<Vec<_>>();
+    let expanded;
+    let arranged = match expansion {
+        Some(expansion) => {
+            expanded = expansion.expand(&decoded_data);
+            layout.arrange(&expanded)
+        },
+        None => layout.arrange(&decoded_data)
+    };
+    let shown = layout.mask(arranged, &options.format);
+    writer.write_row(&shown, &options.format).await.categorize(Error::OutputIo)?;
+    Ok(())
+}
 
-        let column_data = row.try_get_raw(index)?;
-        let column_data: PgValue = ValueRef::to_owned(&column_data);
-        raw_row_data.push(column_data);
+/// The database used by tests requiring the integration-test feature
+#[cfg(all(test, feature = "integration-test"))]
+pub(crate) fn test_url() -> String {
+    std::env::var("DATA_SIFTER_TEST_URL")
+        .unwrap_or_else(|_| String::from("postgres://postgres@localhost/postgres"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_identifiers() {
+        assert!(is_plain_identifier("data"));
+        assert!(is_plain_identifier("_data_2"));
+        assert!(!is_plain_identifier("2data"));
+        assert!(!is_plain_identifier("my data"));
+        assert!(!is_plain_identifier("data; DROP TABLE data"));
+        assert!(!is_plain_identifier(""));
+        // Reserved words must be quoted, whatever their case
+        assert!(!is_plain_identifier("select"));
+        assert!(!is_plain_identifier("Order"));
+        assert!(!is_plain_identifier("prénom"));
+        assert!(!is_plain_identifier("\"data\""));
     }
 
-    // Use 2 loops so that PgValue's remain in scope
-    let mut decoded_data = Vec::new();
-    for column_data in raw_row_data.iter() {
-        decoded_data.push(DecodedValue::from(column_data));
+    #[test]
+    fn helper_column_arguments() {
+        assert_eq!(
+            vec!["\"Order ID\"", "Country", "\"say \"\"hi\"\"\"", "10"],
+            split_arguments(" \"Order ID\", Country \"say \"\"hi\"\"\" 10", &[',']).unwrap()
+        );
+        assert!(split_arguments("\"Order ID 10", &[]).is_err());
+        assert_eq!("Order ID", column_argument("\"Order ID\"").unwrap());
+        assert_eq!("say \"hi\"", column_argument("\"say \"\"hi\"\"\"").unwrap());
+        assert_eq!("Country", column_argument(" Country ").unwrap());
+        assert!(column_argument("\"\"").is_err());
+        assert!(column_argument("\"a\"b\"").is_err());
+        assert!(column_argument("a\"b").is_err());
+
+        let columns = ["id", "Order ID", "Name", "NAME"];
+        let find = |name: &str| find_column("data", name, &columns, |column| column).copied();
+        assert_eq!("Order ID", find("Order ID").unwrap());
+        assert_eq!("Order ID", find("order id").unwrap());
+        assert_eq!("id", find("ID").unwrap());
+        assert_eq!("NAME", find("NAME").unwrap());
+        assert_eq!(
+            "Column name is not in table data, but \"Name\" and \"NAME\" differ from it only in case. \
+            Name one of them in double quotes",
+            find("name").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "Column missing is not in table data. Its columns are: id, Order ID, Name, NAME",
+            find("missing").unwrap_err().to_string()
+        );
+        let error = find_column("data", "id", &[], |column: &&str| column).unwrap_err();
+        assert_eq!("Table data does not exist", error.to_string());
+    }
+
+    #[test]
+    fn text_fallback_wraps_arrays() {
+        let columns = |casts: &[(&str, TextCast)]| casts.iter()
+            .map(|(name, cast)| (String::from(*name), *cast))
+            .collect::<Vec<_>>();
+        assert_eq!(None, TextFallback::new("SELECT 1 AS id", &columns(&[("id", TextCast::Never)])));
+        let fallback = TextFallback::new(
+            "SELECT id, spans, grid, id FROM data;",
+            &columns(&[("id", TextCast::Never), ("spans", TextCast::Always), ("grid", TextCast::IfNested), ("id", TextCast::Never)])
+        ).unwrap();
+        assert_eq!(
+            "SELECT fallback.column_1 AS \"id\", fallback.column_2::text AS \"spans\", fallback.column_3 AS \"grid\", \
+            fallback.column_4 AS \"id\", CASE WHEN array_ndims(fallback.column_3) > 1 THEN fallback.column_3::text END AS \"grid\" \
+            FROM (\nSELECT id, spans, grid, id FROM data\n) AS fallback(column_1, column_2, column_3, column_4)",
+            fallback.query
+        );
+        assert_eq!(4, fallback.columns);
+        assert_eq!(vec![(2, 4)], fallback.nested);
+    }
+
+    #[test]
+    fn create_table_by_mode() {
+        let schema: Schema = ["name", "age"].into_iter().collect();
+        assert_eq!(
+            vec![
+                "DROP TABLE IF EXISTS analyst.data",
+                "CREATE TABLE analyst.data (name TEXT NOT NULL, age TEXT NOT NULL)"
+            ],
+            schema.create_table_statements("analyst.data", TableMode::Replace)
+        );
+        assert_eq!(
+            vec!["CREATE TABLE IF NOT EXISTS data (name TEXT NOT NULL, age TEXT NOT NULL)"],
+            schema.create_table_statements("data", TableMode::Append)
+        );
+        assert!(schema.create_table_statements("data", TableMode::Strict).is_empty());
+    }
+
+    #[test]
+    fn column_names_are_written_as_in_the_header() {
+        // Names other than plain lower-case ones are quoted, so that they are kept as they are
+        let schema: Schema = ["Name", "order", "prénom", "unit_price", "my \"col\""].into_iter().collect();
+        assert_eq!(
+            "CREATE TABLE data (\"Name\" TEXT NOT NULL, \"order\" TEXT NOT NULL, \"prénom\" TEXT NOT NULL, \
+             unit_price TEXT NOT NULL, \"my \"\"col\"\"\" TEXT NOT NULL)",
+            schema.create_table_statement("data", false)
+        );
+        let target = InsertTarget::created(&schema);
+        assert_eq!(
+            "INSERT INTO data (\"Name\", \"order\", \"prénom\", unit_price, \"my \"\"col\"\"\") \
+             VALUES ($1, $2, $3, $4, $5)",
+            target.insert_statement("data", 1)
+        );
+        assert_eq!(
+            "COPY data (\"Name\", \"order\", \"prénom\", unit_price, \"my \"\"col\"\"\") FROM STDIN WITH (FORMAT csv, \
+             FORCE_NOT_NULL (\"Name\", \"order\", \"prénom\", unit_price, \"my \"\"col\"\"\"))",
+            target.copy_statement("data", "FORMAT csv")
+        );
+    }
+
+    #[test]
+    fn last_record_of_each_key_is_written() {
+        let schema: Schema = ["store", "day", "sales"].into_iter().collect();
+        let records = [
+            StringRecord::from(vec!["1", "tue", "30"]),
+            StringRecord::from(vec!["3", "wed", "1"]),
+            StringRecord::from(vec!["1", "tue", "35"]),
+            StringRecord::from(vec!["1", "wed", "2"])
+        ];
+        let target = |on_conflict| {
+            InsertTarget::created(&schema).with_key(&ConflictKey::parse("store,day", on_conflict).unwrap()).unwrap()
+        };
+        let written = target(OnConflict::Update).written_records(&records);
+        assert_eq!(vec![&records[1], &records[2], &records[3]], written);
+        // Rows left out are not written twice, so every record is given to the statement
+        assert_eq!(4, target(OnConflict::Ignore).written_records(&records).len());
+        assert_eq!(4, target(OnConflict::Error).written_records(&records).len());
+    }
+
+    #[test]
+    fn resolve_conflicts_on_the_key() {
+        let schema: Schema = ["Store", "day", "sales"].into_iter().collect();
+        let target = |columns: &str, on_conflict| {
+            InsertTarget::created(&schema).with_key(&ConflictKey::parse(columns, on_conflict).unwrap())
+        };
+        assert_eq!(
+            "INSERT INTO data (\"Store\", day, sales) VALUES ($1, $2, $3) \
+             ON CONFLICT (\"Store\", day) DO UPDATE SET sales = EXCLUDED.sales RETURNING xmax = 0",
+            target("store, day", OnConflict::Update).unwrap().insert_statement("data", 1)
+        );
+        assert_eq!(
+            "INSERT INTO data (\"Store\", day, sales) VALUES ($1, $2, $3) ON CONFLICT (day) DO NOTHING RETURNING xmax = 0",
+            target("day", OnConflict::Ignore).unwrap().insert_statement("data", 1)
+        );
+        assert_eq!(
+            "INSERT INTO data (\"Store\", day, sales) VALUES ($1, $2, $3) \
+             ON CONFLICT (\"Store\", day, sales) DO NOTHING RETURNING xmax = 0",
+            target("store,day,sales", OnConflict::Update).unwrap().insert_statement("data", 1)
+        );
+        let declared = target("day", OnConflict::Error).unwrap();
+        assert_eq!(vec!["day"], declared.key());
+        assert_eq!("INSERT INTO data (\"Store\", day, sales) VALUES ($1, $2, $3)", declared.insert_statement("data", 1));
+        assert_eq!(
+            "Key column region is not a column of the dataset",
+            target("region", OnConflict::Update).unwrap_err().to_string()
+        );
+        assert!(ConflictKey::parse("store day", OnConflict::Update).is_err());
+    }
+
+    #[test]
+    fn number_placeholders_across_records() {
+        let schema: Schema = ["a", "b", "c"].into_iter().collect();
+        let target = InsertTarget::created(&schema);
+        assert_eq!(
+            "INSERT INTO data (a, b, c) VALUES ($1, $2, $3), ($4, $5, $6), ($7, $8, $9)",
+            target.insert_statement("data", 3)
+        );
+        let table = [existing("id", "pg_catalog.int4", false), existing("name", "pg_catalog.varchar", false)];
+        let schema: Schema = ["name", "id"].into_iter().collect();
+        let target = schema.match_existing("analyst.orders", &table).unwrap();
+        assert_eq!(
+            "INSERT INTO analyst.orders (name, id) VALUES \
+             (CAST($1 AS pg_catalog.varchar), CAST(NULLIF($2, '') AS pg_catalog.int4)), \
+             (CAST($3 AS pg_catalog.varchar), CAST(NULLIF($4, '') AS pg_catalog.int4))",
+            target.insert_statement("analyst.orders", 2)
+        );
+    }
+
+    #[test]
+    fn bind_an_array_of_each_column() {
+        let array_types = [String::from("bigint[]"), String::from("text[]::jsonb[]")];
+        let table = [existing("tags", "pg_catalog.jsonb", false), existing("id", "pg_catalog.int8", false)];
+        let schema: Schema = ["id", "tags"].into_iter().collect();
+        let (target, _) = schema.match_appended("events", &table, false).unwrap();
+        let target = target.with_key(&ConflictKey::parse("id", OnConflict::Update).unwrap()).unwrap();
+        assert_eq!(
+            "INSERT INTO events (tags, id) SELECT * FROM UNNEST($1::text[]::jsonb[], $2::bigint[]) \
+             ON CONFLICT (id) DO UPDATE SET tags = EXCLUDED.tags RETURNING xmax = 0",
+            target.unnest_statement("events", &array_types)
+        );
+        assert_eq!(vec![0], target.key_fields());
+    }
+
+    #[test]
+    fn copy_empty_values_as_inserted() {
+        let table = [existing("id", "pg_catalog.int4", false), existing("name", "pg_catalog.varchar", false),
+                     existing("note", "pg_catalog.varchar", false)];
+        let schema: Schema = ["note", "id", "name"].into_iter().collect();
+        let target = schema.match_existing("orders", &table).unwrap();
+        assert_eq!(", FORCE_NOT_NULL (note, name), FORCE_NULL (id)", target.copy_null_options());
+        assert_eq!(
+            "COPY orders (note, id, name) FROM STDIN WITH (FORMAT csv, FORCE_NOT_NULL (note, name), FORCE_NULL (id))",
+            target.copy_statement("orders", "FORMAT csv")
+        );
+    }
+
+    fn existing(name: &str, type_name: &str, generated: bool) -> TableColumn {
+        TableColumn {
+            name: String::from(name),
+            data_type: String::from(type_name),
+            type_name: String::from(type_name),
+            textual: type_name == "pg_catalog.varchar",
+            generated,
+            computed: None
+        }
+    }
+
+    #[test]
+    fn match_existing_columns() {
+        let table = [
+            existing("id", "pg_catalog.int4", false),
+            existing("name", "pg_catalog.varchar", false),
+            existing("total", "pg_catalog.numeric", true)
+        ];
+        let schema: Schema = ["Name", "ID"].into_iter().collect();
+        let target = schema.match_existing("orders", &table).unwrap();
+        assert_eq!("name, id", target.column_names_joined_by_commas());
+        assert_eq!("CAST($1 AS pg_catalog.varchar)", target.placeholder(0, 1));
+        assert_eq!("CAST(NULLIF($4, '') AS pg_catalog.int4)", target.placeholder(1, 4));
+        assert_eq!((Some("pg_catalog.int4"), "id"), (target.cast_type(1), target.column(1)));
+        assert_eq!(None, InsertTarget::created(&schema).cast_type(1));
+    }
+
+    #[test]
+    fn match_appended_columns() {
+        let table = [
+            existing("id", "pg_catalog.int4", false),
+            existing("name", "pg_catalog.varchar", false),
+            existing("total", "pg_catalog.numeric", true),
+            existing("notes", "pg_catalog.varchar", false)
+        ];
+        // Reordered, and in another case
+        let schema: Schema = ["Name", " ID "].into_iter().collect();
+        let (target, appended) = schema.match_appended("orders", &table, false).unwrap();
+        assert_eq!("id, name", target.column_names_joined_by_commas());
+        assert_eq!((1, 0), (target.field(0), target.field(1)));
+        let record = StringRecord::from(vec!["Ann", "7"]);
+        assert_eq!(vec!["7", "Ann"], target.values(&record).collect::<Vec<_>>());
+        assert_eq!("COPY orders (name, id) FROM STDIN WITH (FORMAT csv, FORCE_NOT_NULL (name), FORCE_NULL (id))",
+                   target.copy_statement("orders", "FORMAT csv"));
+        // Missing from the CSV file
+        assert_eq!(vec![String::from("notes")], appended.missing);
+        assert_eq!("id from ID, name from Name; left NULL: notes", appended.to_string());
+        assert_eq!(Some("Left notes of table orders NULL, or their default, since the CSV file does not have them"),
+                   appended.warning("orders").as_deref());
+
+        // Extra CSV columns, which are only left out if asked
+        let schema: Schema = ["id", "extra", "name", "notes"].into_iter().collect();
+        let refused = schema.match_appended("orders", &table, false).unwrap_err().to_string();
+        assert!(refused.starts_with("The CSV file has columns which table orders does not: extra."), "{}", refused);
+        let (target, appended) = schema.match_appended("orders", &table, true).unwrap();
+        assert_eq!((vec![0, 2, 3], None), ((0..3).map(|column| target.field(column)).collect(), appended.warning("t")));
+        assert_eq!("id, name, notes; left out: extra", appended.to_string());
+
+        let schema: Schema = ["name", "NAME"].into_iter().collect();
+        assert!(schema.match_appended("orders", &table, true).is_err());
+        let schema: Schema = ["other"].into_iter().collect();
+        assert!(schema.match_appended("orders", &table, true).is_err());
+    }
+
+    #[test]
+    fn list_every_difference() {
+        let table = [existing("id", "pg_catalog.int4", false), existing("name", "pg_catalog.varchar", false),
+                     existing("total", "pg_catalog.numeric", true)];
+        let schema: Schema = ["id", "Id", "extra", "total"].into_iter().collect();
+        assert_eq!("\
+The columns of the CSV file do not match table orders:
+  id and Id in the CSV file both match id
+  extra is in the CSV file, but not the table
+  total is generated by the table, so it cannot be loaded
+  name is in the table, but not the CSV file", schema.match_existing("orders", &table).unwrap_err().to_string());
+        assert_eq!(
+            "Table orders does not exist. In strict mode, datasets are only loaded into existing tables",
+            schema.match_existing("orders", &[]).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn table_names() {
+        assert!(is_table_name("data"));
+        assert!(is_table_name("analyst.data"));
+        assert!(!is_table_name("analyst."));
+        assert!(!is_table_name("a.b.c"));
+        assert_eq!("analyst.data", qualify("data", Some("analyst")));
+        assert_eq!("other.data", qualify("other.data", Some("analyst")));
+        assert_eq!("data", qualify("data", None));
+    }
+
+    #[test]
+    fn detect_unlimited_selects() {
+        assert!(is_unlimited_select("SELECT * FROM data"));
+        assert!(is_unlimited_select("  with recent AS (SELECT * FROM data LIMIT 5) SELECT * FROM recent, data"));
+        assert!(is_unlimited_select("VALUES (1), (2);"));
+        assert!(!is_unlimited_select("SELECT * FROM data ORDER BY id LIMIT 10;"));
+        assert!(!is_unlimited_select("select * from data limit 10 offset 20"));
+        assert!(!is_unlimited_select("SELECT * FROM data FETCH FIRST 10 ROWS ONLY"));
+        assert!(!is_unlimited_select("DROP TABLE data"));
+        assert!(!is_unlimited_select("INSERT INTO data VALUES (1)"));
+        assert!(is_unlimited_select("SELECT * FROM data WHERE id IN (SELECT id FROM data ORDER BY id LIMIT 5)"));
+    }
+
+    #[test]
+    fn preparable_statements() {
+        assert!(is_preparable("SELECT * FROM data"));
+        assert!(is_preparable("  WITH recent AS (SELECT 1) SELECT * FROM recent"));
+        assert!(is_preparable("delete from data where id = 1 returning *"));
+        assert!(!is_preparable("VACUUM data"));
+        assert!(!is_preparable("SET search_path = analyst"));
+        assert!(!is_preparable("CREATE TABLE copied AS SELECT * FROM data"));
+    }
+
+    #[test]
+    fn read_only_queries() {
+        assert!(is_read_only("SELECT region, count(*) FROM data GROUP BY region"));
+        assert!(is_read_only("WITH recent AS (SELECT 1) SELECT * FROM recent"));
+        assert!(!is_read_only("WITH gone AS (DELETE FROM data RETURNING *) SELECT count(*) FROM gone"));
+        assert!(!is_read_only("SELECT * INTO copied FROM data"));
+        assert!(!is_read_only("SELECT nextval('ids')"));
+        assert!(!is_read_only("UPDATE data SET amount = 0"));
+    }
+
+    #[test]
+    fn limit_query() {
+        assert_eq!("SELECT * FROM (\nSELECT * FROM data ORDER BY id -- newest\n) AS limited LIMIT 100",
+                   limited("SELECT * FROM data ORDER BY id -- newest", 100));
+        assert_eq!("SELECT * FROM (\nSELECT 1\n) AS limited LIMIT 5", limited("SELECT 1 ;\n", 5));
+        assert_eq!("SELECT * FROM (\nSELECT * FROM data\n) AS ordered ORDER BY \"_source_line\"",
+                   ordered_by("SELECT * FROM data;", "_source_line"));
+    }
+
+    #[test]
+    fn approximate_counts() {
+        assert_eq!("0", approximate_count(0));
+        assert_eq!("999", approximate_count(999));
+        assert_eq!("1.0K", approximate_count(1_000));
+        assert_eq!("1.2M", approximate_count(1_234_567));
+        assert_eq!("3.0B", approximate_count(3_000_000_000));
+    }
+
+    /// Connects to the test database, which the integration-test feature requires
+    #[cfg(feature = "integration-test")]
+    mod pooled {
+        use super::*;
+
+        #[async_std::test]
+        async fn wait_for_a_connection_in_use() {
+            // The pool alone would give up after a second
+            let pool = PgPoolOptions::new().max_connections(1).connect_timeout(Duration::from_secs(1))
+                .connect(&test_url()).await.unwrap();
+            let held = pool.acquire().await.unwrap();
+            let timed_out = acquire_waiting(&pool, 1, Duration::from_millis(1_500), |_| ()).await;
+            assert!(matches!(timed_out, Err(sqlx::Error::PoolTimedOut)));
+
+            let released = task::spawn(async move {
+                task::sleep(Duration::from_millis(1_500)).await;
+                drop(held);
+            });
+            let mut connection = acquire_waiting(&pool, 1, Duration::from_secs(10), |_| ()).await.unwrap();
+            sqlx::query("SELECT 1").execute(&mut connection).await.unwrap();
+            released.await;
+        }
+
+        #[async_std::test]
+        async fn write_results_again_in_another_task() {
+            let pool = PgPoolOptions::new().max_connections(1).connect(&test_url()).await.unwrap();
+            let mut query = Query {
+                connection: pool.acquire().await.unwrap(),
+                query: String::from("SELECT n, n * 2 AS doubled FROM generate_series(1, 3) AS n"),
+                statement: None,
+                parameters: Vec::new(),
+                described: None
+            };
+            let options = ExportOptions { header_if_empty: true, ..ExportOptions::default() };
+            let task_options = options.clone();
+            let written = task::spawn(async move {
+                let mut outputs = Vec::new();
+                for _ in 0..2 {
+                    let mut output = Vec::new();
+                    let progress = Arc::new(ExportProgress::default());
+                    let summary = query.output_query_results(OutputFormat::Csv, &task_options, &mut output, progress)
+                        .await.unwrap();
+                    outputs.push((summary.rows, String::from_utf8(output).unwrap()));
+                }
+                (query, outputs)
+            });
+            let (mut query, outputs) = written.await;
+            for (rows, output) in outputs {
+                assert_eq!(3, rows);
+                assert_eq!("n,doubled\n1,2\n2,4\n3,6\n", output);
+            }
+            // The query is given back with its connection, which is still usable
+            query.query = String::from("SELECT 1 WHERE false");
+            let mut output = Vec::new();
+            let summary = query.output_query_results(
+                OutputFormat::Csv, &options, &mut output, Arc::new(ExportProgress::default())
+            ).await.unwrap();
+            assert_eq!((0, "?column?\n"), (summary.rows, String::from_utf8(output).unwrap().as_str()));
+        }
     }
-    csv_writer.write_record(decoded_data).await?;
-    Ok(())
 }
@@ -0,0 +1,676 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::str::FromStr;
+use base64::Engine;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use eyre::{eyre, Result};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Type, TypeInfo, Value};
+use sqlx::postgres::{PgTypeInfo, PgTypeKind, PgValue};
+use crate::locale::{self, DisplayLocale};
+use crate::timestamp::{self, TimestampFormat, Timezone};
+
+/// How array values are written
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum ArrayFormat {
+    /// Postgres array literals, such as {a,"b c",NULL}
+    #[default]
+    Postgres,
+    /// JSON arrays, such as ["a","b c",null]. These are native arrays in JSON output
+    Json
+}
+
+/// How bytea values are written
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ByteaFormat {
+    /// Hexadecimal prefixed with \x, as Postgres writes it
+    #[default]
+    Hex,
+    Base64
+}
+
+impl FromStr for ByteaFormat {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "hex" => Ok(Self::Hex),
+            "base64" => Ok(Self::Base64),
+            _ => Err(eyre!("Invalid bytea format {}. Valid formats are: hex, base64", value))
+        }
+    }
+}
+
+/// The largest bytea value which is output. Larger values are likely a mistake, and are
+/// better exported in slices using substring()
+const MAX_BYTEA_LENGTH: usize = 64 * 1024 * 1024;
+
+/// Options controlling how decoded values are formatted, shared by all output formats
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FormatOptions {
+    pub(crate) array_format: ArrayFormat,
+    pub(crate) bytea_format: ByteaFormat,
+    /// The timezone timestamptz values are converted to
+    pub(crate) timezone: Timezone,
+    /// Overrides how timestamptz values are formatted
+    pub(crate) timestamp_format: Option<TimestampFormat>,
+    /// Fixed decimal places for floating point and numeric columns
+    pub(crate) decimal_places: DecimalPlaces,
+    /// Whether to group the digits of numbers with commas, e.g. 1,234,567
+    pub(crate) thousands_separators: bool,
+    /// Writes numbers and dates as the locale does, which is only done for shown results
+    pub(crate) display_locale: Option<DisplayLocale>
+}
+
+/// The most decimal places a numeric value can have
+const MAX_DECIMAL_PLACES: u32 = 28;
+
+/// Fixed decimal places, for all columns or for specific ones. Without them, floating point
+/// values are written with the fewest digits which read back as the same value, and numeric
+/// values are written as Postgres writes them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct DecimalPlaces {
+    all_columns: Option<u32>,
+    columns: HashMap<String, u32>
+}
+
+impl DecimalPlaces {
+
+    /// Parses specifications of the form PLACES or COLUMN=PLACES
+    pub(crate) fn parse(specifications: &[String]) -> Result<Self> {
+        let mut decimal_places = Self::default();
+        for specification in specifications {
+            let (column, places) = match specification.rsplit_once('=') {
+                Some((column, places)) => (Some(column), places),
+                None => (None, specification.as_str())
+            };
+            let places = places.parse::<u32>().ok().filter(|places| *places <= MAX_DECIMAL_PLACES).ok_or_else(|| eyre!(
+                "Invalid decimal places {}. Expected a number from 0 to {}, optionally preceded by a \
+                column name, e.g. price=2", specification, MAX_DECIMAL_PLACES
+            ))?;
+            match column {
+                Some(column) => { decimal_places.columns.insert(String::from(column), places); },
+                None => decimal_places.all_columns = Some(places)
+            }
+        }
+        Ok(decimal_places)
+    }
+
+    pub(crate) fn for_column(&self, column_name: &str) -> Option<u32> {
+        self.columns.get(column_name).copied().or(self.all_columns)
+    }
+}
+
+/// A value from a query result
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum DecodedValue<'v> {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float4(f32),
+    Float8(f64),
+    Numeric(Decimal),
+    Text(Cow<'v, str>),
+    Bytes(Cow<'v, [u8]>),
+    Date(NaiveDate),
+    Time(NaiveTime),
+    Timestamp(NaiveDateTime),
+    TimestampTz(DateTime<Utc>),
+    /// A json or jsonb value
+    Json(Cow<'v, serde_json::Value>),
+    /// A one-dimensional array
+    Array(Vec<DecodedValue<'v>>)
+}
+
+/// Tries decoding a one-dimensional array with the given element type
+macro_rules! try_decode_array {
+    ($value:expr, $element:ty, $variant:expr) => {
+        if let Ok(decoded) = $value.try_decode::<Vec<Option<$element>>>() {
+            return Ok(DecodedValue::Array(decoded.into_iter().map(|element| {
+                element.map($variant).unwrap_or(DecodedValue::Null)
+            }).collect()));
+        }
+    }
+}
+
+/// How the values of a column of query results are cast to text, when they cannot all be
+/// decoded as they are
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum TextCast {
+    /// The values are decoded as they are
+    Never,
+    /// Arrays of a type whose elements cannot be decoded
+    Always,
+    /// Arrays whose elements can be decoded, but only when they have one dimension
+    IfNested
+}
+
+impl TextCast {
+
+    pub(crate) fn of_type(type_info: &PgTypeInfo) -> Self {
+        fn decodes<T>(type_info: &PgTypeInfo) -> bool where Vec<Option<T>>: Type<Postgres> {
+            <Vec<Option<T>> as Type<Postgres>>::compatible(type_info)
+        }
+        if !matches!(type_info.kind(), PgTypeKind::Array(_)) {
+            return Self::Never;
+        }
+        // The element types of try_decode_array
+        let decodable = decodes::<String>(type_info) || decodes::<i16>(type_info)
+            || decodes::<i32>(type_info) || decodes::<i64>(type_info)
+            || decodes::<f32>(type_info) || decodes::<f64>(type_info)
+            || decodes::<Decimal>(type_info) || decodes::<bool>(type_info)
+            || decodes::<serde_json::Value>(type_info) || decodes::<Vec<u8>>(type_info)
+            || decodes::<NaiveDateTime>(type_info) || decodes::<DateTime<Utc>>(type_info)
+            || decodes::<NaiveDate>(type_info) || decodes::<NaiveTime>(type_info);
+        if decodable { Self::IfNested } else { Self::Always }
+    }
+}
+
+impl<'v> DecodedValue<'v> {
+
+    /// Decodes a value. Values of unsupported types produce an error suggesting a cast to text.
+    pub(crate) fn decode(value: &'v PgValue, column_name: &str) -> Result<Self> {
+        if value.is_null() {
+            return Ok(Self::Null);
+        }
+        if let Ok(decoded) = value.try_decode::<&str>() {
+            return Ok(Self::Text(Cow::Borrowed(decoded)));
+        } else if let Ok(decoded) = value.try_decode::<i16>() {
+            return Ok(Self::Int(decoded.into()));
+        } else if let Ok(decoded) = value.try_decode::<i32>() {
+            return Ok(Self::Int(decoded.into()));
+        } else if let Ok(decoded) = value.try_decode::<i64>() {
+            return Ok(Self::Int(decoded));
+        } else if let Ok(decoded) = value.try_decode::<f32>() {
+            return Ok(Self::Float4(decoded));
+        } else if let Ok(decoded) = value.try_decode::<f64>() {
+            return Ok(Self::Float8(decoded));
+        } else if let Ok(mut decoded) = value.try_decode::<Decimal>() {
+            // sqlx loses the number of decimal places, turning 1.50 into 1.5000
+            if let Some(scale) = numeric_display_scale(value) {
+                decoded.rescale(scale.min(MAX_DECIMAL_PLACES));
+            }
+            return Ok(Self::Numeric(decoded));
+        } else if let Ok(decoded) = value.try_decode::<bool>() {
+            return Ok(Self::Bool(decoded));
+        } else if let Ok(decoded) = value.try_decode::<serde_json::Value>() {
+            return Ok(Self::Json(Cow::Owned(decoded)));
+        } else if let Ok(decoded) = value.try_decode::<NaiveDateTime>() {
+            return Ok(Self::Timestamp(decoded));
+        } else if let Ok(decoded) = value.try_decode::<DateTime<Utc>>() {
+            return Ok(Self::TimestampTz(decoded));
+        } else if let Ok(decoded) = value.try_decode::<NaiveDate>() {
+            return Ok(Self::Date(decoded));
+        } else if let Ok(decoded) = value.try_decode::<NaiveTime>() {
+            return Ok(Self::Time(decoded));
+        } else if let Ok(decoded) = value.try_decode::<&[u8]>() {
+            if decoded.len() > MAX_BYTEA_LENGTH {
+                return Err(eyre!(
+                    "Column {} has a value of {} bytes, more than the limit of {} bytes. \
+                    Select part of it instead, e.g. substring({} from 1 for 1024)",
+                    column_name, decoded.len(), MAX_BYTEA_LENGTH, column_name
+                ));
+            }
+            return Ok(Self::Bytes(Cow::Borrowed(decoded)));
+        }
+        try_decode_array!(value, String, |element| Self::Text(Cow::Owned(element)));
+        try_decode_array!(value, i16, |element| Self::Int(element.into()));
+        try_decode_array!(value, i32, |element| Self::Int(element.into()));
+        try_decode_array!(value, i64, Self::Int);
+        try_decode_array!(value, f32, Self::Float4);
+        try_decode_array!(value, f64, Self::Float8);
+        try_decode_array!(value, Decimal, Self::Numeric);
+        try_decode_array!(value, bool, Self::Bool);
+        try_decode_array!(value, serde_json::Value, |element| Self::Json(Cow::Owned(element)));
+        try_decode_array!(value, Vec<u8>, |element| Self::Bytes(Cow::Owned(element)));
+        try_decode_array!(value, NaiveDateTime, Self::Timestamp);
+        try_decode_array!(value, DateTime<Utc>, Self::TimestampTz);
+        try_decode_array!(value, NaiveDate, Self::Date);
+        try_decode_array!(value, NaiveTime, Self::Time);
+
+        // Arrays which cannot be decoded are cast to text as the query runs, so that this is
+        // reached by them only when the query could not be wrapped, as when it is not described
+        Err(eyre!(
+            "Column {} has type {}, which cannot be output. Cast it to text instead, e.g. {}::text",
+            column_name, value.type_info().name(), column_name
+        ))
+    }
+
+    /// Rounds floating point and numeric values, including array elements, to fixed decimal
+    /// places. Midpoints are rounded to even. Floating point values too large to be numeric
+    /// values are left as they are.
+    pub(crate) fn with_decimal_places(self, places: u32) -> Self {
+        match self {
+            Self::Float4(value) if value.is_finite() => {
+                fixed_float(format!("{:.*}", places as usize, value)).map(Self::Numeric).unwrap_or(self)
+            },
+            Self::Float8(value) if value.is_finite() => {
+                fixed_float(format!("{:.*}", places as usize, value)).map(Self::Numeric).unwrap_or(self)
+            },
+            Self::Numeric(value) => {
+                let mut rounded = value.round_dp_with_strategy(places, RoundingStrategy::MidpointNearestEven);
+                // Pads with trailing zeroes
+                rounded.rescale(places);
+                Self::Numeric(rounded)
+            },
+            Self::Array(elements) => {
+                Self::Array(elements.into_iter().map(|element| element.with_decimal_places(places)).collect())
+            },
+            value => value
+        }
+    }
+
+    /// Converts a JSON value, such as a field of a json column, into a value in its own right
+    pub(crate) fn from_json(value: &'v serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => Self::Null,
+            serde_json::Value::String(text) => Self::Text(Cow::Borrowed(text)),
+            value => Self::Json(Cow::Borrowed(value))
+        }
+    }
+
+    /// Formats this value as text, as used in CSV output. NULL becomes the empty string.
+    pub(crate) fn to_text(&self, options: &FormatOptions) -> Cow<'_, str> {
+        match self {
+            Self::Null => Cow::Borrowed(""),
+            Self::Text(text) => Cow::Borrowed(text),
+            Self::Bytes(bytes) => Cow::Owned(bytes_to_text(bytes, options.bytea_format)),
+            // Written as Postgres writes them, e.g. 2022-03-13 07:00:00, since a plain timestamp
+            // has no time zone to convert from, and the format is for timestamptz values
+            Self::Timestamp(value) => Cow::Owned(value.to_string()),
+            Self::TimestampTz(value) => Cow::Owned(timestamp::format_timestamptz(
+                value, options.timezone, options.timestamp_format.as_ref()
+            )),
+            // Compact JSON text
+            Self::Json(value) => Cow::Owned(value.to_string()),
+            Self::Array(elements) => Cow::Owned(match options.array_format {
+                ArrayFormat::Postgres => postgres_array_literal(elements, options),
+                ArrayFormat::Json => {
+                    let mut json = String::new();
+                    self.write_json(options, &mut json);
+                    json
+                }
+            }),
+            number @ (Self::Int(_) | Self::Float4(_) | Self::Float8(_) | Self::Numeric(_)) => {
+                let number = number.scalar_to_string();
+                Cow::Owned(match options.display_locale {
+                    Some(locale) => locale.format_number(&number),
+                    None if options.thousands_separators => locale::group_digits(&number, ','),
+                    None => number
+                })
+            },
+            Self::Date(date) => Cow::Owned(match options.display_locale {
+                Some(locale) => locale.format_date(date),
+                None => date.to_string()
+            }),
+            scalar => Cow::Owned(scalar.scalar_to_string())
+        }
+    }
+
+    /// Writes this value as JSON
+    pub(crate) fn write_json(&self, options: &FormatOptions, output: &mut String) {
+        match self {
+            Self::Null => output.push_str("null"),
+            Self::Bool(value) => output.push_str(if *value { "true" } else { "false" }),
+            Self::Int(value) => write!(output, "{}", value).expect("Writing to a String"),
+            // JSON has no representation of NaN or infinity
+            Self::Float4(value) if !value.is_finite() => write_json_string(&value.to_string(), output),
+            Self::Float8(value) if !value.is_finite() => write_json_string(&value.to_string(), output),
+            Self::Float4(value) => write!(output, "{}", value).expect("Writing to a String"),
+            Self::Float8(value) => write!(output, "{}", value).expect("Writing to a String"),
+            // Written as a number literal so that no precision is lost
+            Self::Numeric(value) => write!(output, "{}", value).expect("Writing to a String"),
+            Self::Text(text) => write_json_string(text, output),
+            Self::Bytes(_) | Self::Date(_) | Self::Time(_) | Self::Timestamp(_) | Self::TimestampTz(_) => {
+                write_json_string(&self.to_text(options), output)
+            },
+            // Nested as a native value
+            Self::Json(value) => output.push_str(&value.to_string()),
+            Self::Array(elements) => match options.array_format {
+                ArrayFormat::Postgres => write_json_string(&postgres_array_literal(elements, options), output),
+                ArrayFormat::Json => {
+                    output.push('[');
+                    for (index, element) in elements.iter().enumerate() {
+                        if index != 0 { output.push(','); }
+                        element.write_json(options, output);
+                    }
+                    output.push(']');
+                }
+            }
+        }
+    }
+
+    fn scalar_to_string(&self) -> String {
+        match self {
+            Self::Bool(value) => value.to_string(),
+            Self::Int(value) => value.to_string(),
+            Self::Float4(value) => value.to_string(),
+            Self::Float8(value) => value.to_string(),
+            Self::Numeric(value) => value.to_string(),
+            Self::Date(value) => value.to_string(),
+            Self::Time(value) => value.to_string(),
+            Self::Null | Self::Text(_) | Self::Bytes(_) | Self::Timestamp(_) | Self::TimestampTz(_)
+            | Self::Json(_) | Self::Array(_) => unreachable!("Not a scalar: {:?}", self)
+        }
+    }
+
+    /// Orders values the same way every time, for sorting rows which have no order of their
+    /// own. Values compare as their type does, floats by total_cmp and json by its text, and
+    /// NULL comes last. Values of different types, which one column never holds, compare by type
+    pub(crate) fn total_cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Bool(value), Self::Bool(other)) => value.cmp(other),
+            (Self::Int(value), Self::Int(other)) => value.cmp(other),
+            (Self::Float4(value), Self::Float4(other)) => value.total_cmp(other),
+            (Self::Float8(value), Self::Float8(other)) => value.total_cmp(other),
+            (Self::Numeric(value), Self::Numeric(other)) => value.cmp(other),
+            (Self::Text(value), Self::Text(other)) => value.cmp(other),
+            (Self::Bytes(value), Self::Bytes(other)) => value.cmp(other),
+            (Self::Date(value), Self::Date(other)) => value.cmp(other),
+            (Self::Time(value), Self::Time(other)) => value.cmp(other),
+            (Self::Timestamp(value), Self::Timestamp(other)) => value.cmp(other),
+            (Self::TimestampTz(value), Self::TimestampTz(other)) => value.cmp(other),
+            (Self::Json(value), Self::Json(other)) => value.to_string().cmp(&other.to_string()),
+            (Self::Array(values), Self::Array(others)) => values.iter().zip(others)
+                .map(|(value, other)| value.total_cmp(other))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or_else(|| values.len().cmp(&others.len())),
+            _ => self.type_rank().cmp(&other.type_rank())
+        }
+    }
+
+    fn type_rank(&self) -> usize {
+        match self {
+            Self::Bool(_) => 0,
+            Self::Int(_) => 1,
+            Self::Float4(_) => 2,
+            Self::Float8(_) => 3,
+            Self::Numeric(_) => 4,
+            Self::Text(_) => 5,
+            Self::Bytes(_) => 6,
+            Self::Date(_) => 7,
+            Self::Time(_) => 8,
+            Self::Timestamp(_) => 9,
+            Self::TimestampTz(_) => 10,
+            Self::Json(_) => 11,
+            Self::Array(_) => 12,
+            Self::Null => 13
+        }
+    }
+}
+
+/// Parses a float formatted with fixed decimal places, if it fits in a numeric value
+fn fixed_float(formatted: String) -> Option<Decimal> {
+    let digits = formatted.bytes().filter(u8::is_ascii_digit).count();
+    if digits > MAX_DECIMAL_PLACES as usize {
+        return None;
+    }
+    formatted.parse().ok()
+}
+
+/// The number of decimal places in a numeric value, as Postgres would display it
+fn numeric_display_scale(value: &PgValue) -> Option<u32> {
+    // Binary numerics begin with the number of digits, the weight, the sign and then the scale
+    let bytes = value.try_decode_unchecked::<&[u8]>().ok()?;
+    let scale = bytes.get(6..8)?;
+    Some(u16::from_be_bytes([scale[0], scale[1]]).into())
+}
+
+fn bytes_to_text(bytes: &[u8], format: ByteaFormat) -> String {
+    match format {
+        ByteaFormat::Hex => {
+            let mut hex = String::with_capacity(2 + bytes.len() * 2);
+            hex.push_str("\\x");
+            for byte in bytes {
+                write!(hex, "{:02x}", byte).expect("Writing to a String");
+            }
+            hex
+        },
+        ByteaFormat::Base64 => base64::engine::general_purpose::STANDARD.encode(bytes)
+    }
+}
+
+pub(crate) fn write_json_string(text: &str, output: &mut String) {
+    output.push_str(&serde_json::to_string(text).expect("Strings are always serializable"));
+}
+
+/// Formats an array the way Postgres does, e.g. {1,NULL,"a b"}
+fn postgres_array_literal(elements: &[DecodedValue<'_>], options: &FormatOptions) -> String {
+    let mut literal = String::from("{");
+    for (index, element) in elements.iter().enumerate() {
+        if index != 0 { literal.push(','); }
+        match element {
+            DecodedValue::Null => literal.push_str("NULL"),
+            // Postgres abbreviates booleans inside arrays
+            DecodedValue::Bool(value) => literal.push(if *value { 't' } else { 'f' }),
+            element => push_array_element(&element.to_text(options), &mut literal)
+        }
+    }
+    literal.push('}');
+    literal
+}
+
+/// Appends an array element, quoting it if Postgres would
+fn push_array_element(element: &str, literal: &mut String) {
+    let needs_quotes = element.is_empty()
+        || element.eq_ignore_ascii_case("NULL")
+        || element.chars().any(|c| matches!(c, '{' | '}' | ',' | '"' | '\\') || c.is_ascii_whitespace());
+    if !needs_quotes {
+        literal.push_str(element);
+        return;
+    }
+    literal.push('"');
+    for c in element.chars() {
+        if c == '"' || c == '\\' {
+            literal.push('\\');
+        }
+        literal.push(c);
+    }
+    literal.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(value: &str) -> DecodedValue<'_> {
+        DecodedValue::Text(Cow::Borrowed(value))
+    }
+
+    #[test]
+    fn postgres_array_literals() {
+        let options = FormatOptions::default();
+        let array = DecodedValue::Array(vec![
+            text("plain"), text("a,b"), text(""), text("null"), text("say \"hi\""), text("back\\slash"),
+            DecodedValue::Null
+        ]);
+        assert_eq!(
+            r#"{plain,"a,b","","null","say \"hi\"","back\\slash",NULL}"#,
+            array.to_text(&options)
+        );
+        let booleans = DecodedValue::Array(vec![DecodedValue::Bool(true), DecodedValue::Bool(false)]);
+        assert_eq!("{t,f}", booleans.to_text(&options));
+        assert_eq!("{}", DecodedValue::Array(vec![]).to_text(&options));
+    }
+
+    #[test]
+    fn text_casts_by_type() {
+        use sqlx::postgres::types::PgInterval;
+        let type_info = |info: PgTypeInfo| TextCast::of_type(&info);
+        assert_eq!(TextCast::IfNested, type_info(<Vec<i32> as Type<Postgres>>::type_info()));
+        assert_eq!(TextCast::IfNested, type_info(<Vec<String> as Type<Postgres>>::type_info()));
+        assert_eq!(TextCast::IfNested, type_info(<Vec<NaiveDate> as Type<Postgres>>::type_info()));
+        assert_eq!(TextCast::Always, type_info(<[PgInterval] as Type<Postgres>>::type_info()));
+        assert_eq!(TextCast::Never, type_info(<i32 as Type<Postgres>>::type_info()));
+        assert_eq!(TextCast::Never, type_info(<PgInterval as Type<Postgres>>::type_info()));
+    }
+
+    #[test]
+    fn json_arrays() {
+        let options = FormatOptions { array_format: ArrayFormat::Json, ..Default::default() };
+        let array = DecodedValue::Array(vec![
+            DecodedValue::Int(1), DecodedValue::Null, text("a\"b"), DecodedValue::Numeric(Decimal::new(150, 2))
+        ]);
+        assert_eq!(r#"[1,null,"a\"b",1.50]"#, array.to_text(&options));
+    }
+
+    #[test]
+    fn postgres_arrays_in_json() {
+        let mut json = String::new();
+        let array = DecodedValue::Array(vec![text("a b"), DecodedValue::Int(2)]);
+        array.write_json(&FormatOptions::default(), &mut json);
+        assert_eq!(r#""{\"a b\",2}""#, json);
+    }
+
+    #[test]
+    fn json_values() {
+        let value: serde_json::Value = serde_json::from_str(r#"{"b": [1, {"c": null}], "a": "x"}"#).unwrap();
+        let decoded = DecodedValue::Json(Cow::Borrowed(&value));
+        let options = FormatOptions::default();
+        // Key order is preserved
+        assert_eq!(r#"{"b":[1,{"c":null}],"a":"x"}"#, decoded.to_text(&options));
+        let mut json = String::new();
+        decoded.write_json(&options, &mut json);
+        assert_eq!(r#"{"b":[1,{"c":null}],"a":"x"}"#, json);
+
+        assert_eq!(text("x"), DecodedValue::from_json(&value["a"]));
+        assert_eq!("[1,{\"c\":null}]", DecodedValue::from_json(&value["b"]).to_text(&options));
+    }
+
+    #[test]
+    fn bytea_formats() {
+        let bytes = DecodedValue::Bytes(Cow::Borrowed(&[0xde, 0xad, 0xbe, 0xef, 0x00]));
+        assert_eq!("\\xdeadbeef00", bytes.to_text(&FormatOptions::default()));
+        let base64 = FormatOptions { bytea_format: ByteaFormat::Base64, ..Default::default() };
+        assert_eq!("3q2+7wA=", bytes.to_text(&base64));
+        let mut json = String::new();
+        bytes.write_json(&FormatOptions::default(), &mut json);
+        assert_eq!(r#""\\xdeadbeef00""#, json);
+        assert_eq!("\\x", DecodedValue::Bytes(Cow::Borrowed(&[])).to_text(&FormatOptions::default()));
+    }
+
+    fn to_text(value: DecodedValue<'_>) -> String {
+        value.to_text(&FormatOptions::default()).into_owned()
+    }
+
+    #[test]
+    fn plain_timestamps_pass_through() {
+        let value = NaiveDate::from_ymd_opt(2022, 3, 13).unwrap().and_hms_opt(7, 0, 0).unwrap();
+        let options = FormatOptions {
+            timezone: "America/New_York".parse().unwrap(),
+            timestamp_format: Some("%Y-%m-%d %H:%M".parse().unwrap()),
+            ..FormatOptions::default()
+        };
+        assert_eq!("2022-03-13 07:00:00", DecodedValue::Timestamp(value).to_text(&options));
+        let instant = DateTime::<Utc>::from_naive_utc_and_offset(value, Utc);
+        assert_eq!("2022-03-13 03:00", DecodedValue::TimestampTz(instant).to_text(&options));
+    }
+
+    #[test]
+    fn shortest_round_trip_floats() {
+        assert_eq!("0.30000000000000004", to_text(DecodedValue::Float8(0.1 + 0.2)));
+        assert_eq!("0.3", to_text(DecodedValue::Float4(0.3)));
+        assert_eq!("0.0000001", to_text(DecodedValue::Float8(1e-7)));
+        assert_eq!("100000000000000000000", to_text(DecodedValue::Float8(1e20)));
+        assert_eq!("-0", to_text(DecodedValue::Float8(-0.0)));
+        assert_eq!("1", to_text(DecodedValue::Float8(1.0)));
+    }
+
+    #[test]
+    fn fixed_decimal_places() {
+        let fixed = |value: DecodedValue<'_>, places| to_text(value.with_decimal_places(places));
+        assert_eq!("0.30", fixed(DecodedValue::Float8(0.1 + 0.2), 2));
+        // Midpoints round to even
+        assert_eq!("0.12", fixed(DecodedValue::Float8(0.125), 2));
+        assert_eq!("2", fixed(DecodedValue::Float8(2.5), 0));
+        assert_eq!("2.24", fixed(DecodedValue::Numeric(Decimal::new(2245, 3)), 2));
+        assert_eq!("2.26", fixed(DecodedValue::Numeric(Decimal::new(2255, 3)), 2));
+        // Exact decimals are padded
+        assert_eq!("1.500", fixed(DecodedValue::Numeric(Decimal::new(15, 1)), 3));
+        assert_eq!("0.00", fixed(DecodedValue::Float8(1e-30), 2));
+        assert_eq!("0.00", fixed(DecodedValue::Float8(-0.0), 2));
+        assert_eq!("-1234.57", fixed(DecodedValue::Float4(-1234.5678), 2));
+        // Too large to be numeric
+        assert_eq!(DecodedValue::Float8(1e300), DecodedValue::Float8(1e300).with_decimal_places(2));
+        assert_eq!("NaN", fixed(DecodedValue::Float8(f64::NAN), 2));
+    }
+
+    #[test]
+    fn parse_decimal_places() {
+        let specifications = [String::from("2"), String::from("price=4")];
+        let decimal_places = DecimalPlaces::parse(&specifications).unwrap();
+        assert_eq!(Some(4), decimal_places.for_column("price"));
+        assert_eq!(Some(2), decimal_places.for_column("weight"));
+        assert_eq!(None, DecimalPlaces::default().for_column("price"));
+        assert!(DecimalPlaces::parse(&[String::from("price=-1")]).is_err());
+        assert!(DecimalPlaces::parse(&[String::from("29")]).is_err());
+    }
+
+    #[test]
+    fn thousands_separators() {
+        let options = FormatOptions { thousands_separators: true, ..Default::default() };
+        let grouped = |value: DecodedValue<'_>| value.to_text(&options).into_owned();
+        assert_eq!("1,234,567", grouped(DecodedValue::Int(1234567)));
+        assert_eq!("-123,456.789", grouped(DecodedValue::Numeric(Decimal::new(-123456789, 3))));
+        assert_eq!("100", grouped(DecodedValue::Int(100)));
+        assert_eq!("-1,000.5", grouped(DecodedValue::Float8(-1000.5)));
+        assert_eq!("inf", grouped(DecodedValue::Float8(f64::INFINITY)));
+    }
+
+    #[test]
+    fn display_locale() {
+        let options = FormatOptions {
+            thousands_separators: true, display_locale: Some(DisplayLocale::DeDe), ..Default::default()
+        };
+        let localized = |value: DecodedValue<'_>| value.to_text(&options).into_owned();
+        assert_eq!("-123.456,789", localized(DecodedValue::Numeric(Decimal::new(-123456789, 3))));
+        assert_eq!("1.000", localized(DecodedValue::Int(1000)));
+        assert_eq!("05.01.2022", localized(DecodedValue::Date(NaiveDate::from_ymd_opt(2022, 1, 5).unwrap())));
+        // Only numbers and dates are localized
+        assert_eq!("1234.5", localized(DecodedValue::Text(Cow::Borrowed("1234.5"))));
+    }
+
+    #[test]
+    fn total_order() {
+        let mut values = [
+            DecodedValue::Null, DecodedValue::Float8(f64::NAN), DecodedValue::Float8(-0.5), DecodedValue::Float8(2.0)
+        ];
+        values.sort_by(DecodedValue::total_cmp);
+        assert_eq!("-0.5 2 NaN NULL", values.iter().map(|value| match value {
+            DecodedValue::Null => String::from("NULL"),
+            value => value.scalar_to_string()
+        }).collect::<Vec<_>>().join(" "));
+        let array = |values: &[i64]| DecodedValue::Array(values.iter().copied().map(DecodedValue::Int).collect());
+        assert_eq!(Ordering::Less, array(&[1, 2]).total_cmp(&array(&[1, 2, 0])));
+        assert_eq!(Ordering::Greater, array(&[2]).total_cmp(&array(&[1, 9])));
+        assert_eq!(Ordering::Less, text("b").total_cmp(&DecodedValue::Null));
+    }
+
+    #[test]
+    fn non_finite_floats_in_json() {
+        let mut json = String::new();
+        DecodedValue::Float8(f64::NAN).write_json(&FormatOptions::default(), &mut json);
+        assert_eq!(r#""NaN""#, json);
+    }
+}
@@ -0,0 +1,324 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use csv_async::AsyncReaderBuilder;
+use eyre::{eyre, Result};
+
+/// How fields of the CSV dataset are quoted. By default, fields are quoted as RFC 4180 says,
+/// with double quotes, and a quote within a quoted field is escaped by doubling it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Dialect {
+    /// A comma, unless the dataset is detected to be separated by tabs
+    delimiter: u8,
+    quote: u8,
+    /// A character escaping the next one within quoted fields, such as a backslash
+    escape: Option<u8>,
+    /// Whether a doubled quote within a quoted field stands for one quote
+    double_quote: bool,
+    limits: SizeLimits
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Self { delimiter: b',', quote: b'"', escape: None, double_quote: true, limits: SizeLimits::default() }
+    }
+}
+
+/// The largest field and record which are read, in bytes, so that a file with a huge field,
+/// or an unclosed quote, cannot take up unbounded memory
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SizeLimits {
+    pub(crate) field: u64,
+    pub(crate) record: u64
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        Self { field: 16_000_000, record: 64_000_000 }
+    }
+}
+
+impl Dialect {
+
+    pub(crate) fn new(quote: char, escape: Option<char>, double_quote: bool) -> Result<Self> {
+        let quote = special_byte(quote, "quote")?;
+        let escape = escape.map(|escape| special_byte(escape, "escape")).transpose()?;
+        if escape == Some(quote) {
+            return Err(eyre!(
+                "The escape character must differ from the quote character. To escape quotes by doubling them, \
+                leave the escape character unset"
+            ));
+        }
+        Ok(Self { delimiter: b',', quote, escape, double_quote, limits: SizeLimits::default() })
+    }
+
+    /// The same dialect, quoting fields otherwise
+    pub(crate) fn with_quoting(self, quote: u8, escape: Option<u8>, double_quote: bool) -> Self {
+        Self { quote, escape, double_quote, ..self }
+    }
+
+    /// Whether fields are quoted as RFC 4180 says, which they are unless told otherwise
+    pub(crate) fn is_rfc_4180(&self) -> bool {
+        (self.quote, self.escape, self.double_quote) == (b'"', None, true)
+    }
+
+    /// The options which read fields quoted this way, e.g. --escape '\' --double-quote false
+    pub(crate) fn quoting_options(&self) -> String {
+        let quoted = |byte: u8| match byte {
+            b'\'' => String::from("\"'\""),
+            byte => format!("'{}'", byte as char)
+        };
+        let mut options = Vec::new();
+        if self.quote != b'"' {
+            options.push(format!("--quote {}", quoted(self.quote)));
+        }
+        if let Some(escape) = self.escape {
+            options.push(format!("--escape {}", quoted(escape)));
+        }
+        if !self.double_quote {
+            options.push(String::from("--double-quote false"));
+        }
+        options.join(" ")
+    }
+
+    /// The same dialect with fields separated by tabs, as in TSV files
+    pub(crate) fn with_tabs(self) -> Self {
+        Self { delimiter: b'\t', ..self }
+    }
+
+    /// Describes the delimiter, e.g. comma-delimited
+    pub(crate) fn delimiter_name(&self) -> &'static str {
+        match self.delimiter {
+            b'\t' => "tab-delimited",
+            _ => "comma-delimited"
+        }
+    }
+
+    pub(crate) fn with_limits(self, limits: SizeLimits) -> Self {
+        Self { limits, ..self }
+    }
+
+    pub(crate) fn limits(&self) -> SizeLimits {
+        self.limits
+    }
+
+    /// A reader builder parsing records in this dialect
+    pub(crate) fn reader_builder(&self) -> AsyncReaderBuilder {
+        let mut builder = AsyncReaderBuilder::new();
+        builder.delimiter(self.delimiter).quote(self.quote).escape(self.escape).double_quote(self.double_quote);
+        builder
+    }
+
+    /// The options of a COPY statement reading CSV in this dialect, unless COPY cannot. It
+    /// escapes quotes either by doubling them or with an escape character, but not both
+    pub(crate) fn copy_options(&self) -> Option<String> {
+        let escape = self.single_escape()?;
+        let delimiter = match self.delimiter {
+            b',' => String::new(),
+            delimiter => format!(", DELIMITER {}", literal(delimiter))
+        };
+        Some(format!("FORMAT csv{}, QUOTE {}, ESCAPE {}", delimiter, literal(self.quote), literal(escape)))
+    }
+
+    /// The options of DuckDB's read_csv reading CSV in this dialect, unless it cannot. Like COPY,
+    /// it escapes quotes in one way only
+    #[cfg(feature = "duckdb")]
+    pub(crate) fn read_csv_options(&self) -> Option<String> {
+        let escape = self.single_escape()?;
+        Some(format!(
+            "delim = {}, quote = {}, escape = {}", literal(self.delimiter), literal(self.quote), literal(escape)
+        ))
+    }
+
+    /// The one character escaping quotes, which is the quote itself if they are doubled, unless
+    /// they may be escaped both ways
+    fn single_escape(&self) -> Option<u8> {
+        match (self.escape, self.double_quote) {
+            (None, true) => Some(self.quote),
+            (Some(escape), false) => Some(escape),
+            _ => None
+        }
+    }
+
+    /// Follows the quotes and fields of a record as it is read, line by line
+    pub(crate) fn quote_tracker(self) -> QuoteTracker {
+        QuoteTracker { dialect: self, in_quotes: false, escaped: false, field: 0, field_bytes: 0, longest: (0, 0) }
+    }
+}
+
+impl fmt::Display for Dialect {
+    /// Describes the quoting, e.g. quoted with ", escaped with \
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "quoted with {}", self.quote as char)?;
+        if let Some(escape) = self.escape {
+            write!(f, ", escaped with {}", escape as char)?;
+        }
+        if !self.double_quote {
+            f.write_str(", without doubled quotes")?;
+        }
+        Ok(())
+    }
+}
+
+/// Quote and escape characters must be single bytes, which are not otherwise part of the format
+fn special_byte(character: char, name: &str) -> Result<u8> {
+    match u8::try_from(character) {
+        Ok(byte) if byte.is_ascii() && !matches!(byte, b',' | b'\t' | b'\r' | b'\n') => Ok(byte),
+        _ => Err(eyre!(
+            "Invalid {} character {:?}. Use an ASCII character other than a comma, tab or line break", name, character
+        ))
+    }
+}
+
+/// A SQL string literal holding the character
+fn literal(byte: u8) -> String {
+    match byte {
+        b'\'' => String::from("''''"),
+        byte => format!("'{}'", byte as char)
+    }
+}
+
+/// Whether the text read so far ends within a quoted field, in which case a line break does
+/// not end the record. A doubled quote toggles quoting twice, so it needs no special case.
+/// The longest field so far is followed too, counting its quotes but not line breaks between
+/// records
+#[derive(Clone, Debug)]
+pub(crate) struct QuoteTracker {
+    dialect: Dialect,
+    in_quotes: bool,
+    /// Whether the last byte was an escape character, in which case the next byte is literal
+    escaped: bool,
+    field: usize,
+    field_bytes: u64,
+    /// The index and size of the longest field
+    longest: (usize, u64)
+}
+
+impl QuoteTracker {
+
+    pub(crate) fn feed(&mut self, text: &[u8]) {
+        for byte in text {
+            if self.escaped {
+                self.escaped = false;
+            } else if self.in_quotes && Some(*byte) == self.dialect.escape {
+                self.escaped = true;
+            } else if *byte == self.dialect.quote {
+                self.in_quotes = !self.in_quotes;
+            } else if !self.in_quotes && *byte == self.dialect.delimiter {
+                self.field += 1;
+                self.field_bytes = 0;
+                continue;
+            } else if !self.in_quotes && matches!(*byte, b'\r' | b'\n') {
+                continue;
+            }
+            self.field_bytes += 1;
+            if self.field_bytes > self.longest.1 {
+                self.longest = (self.field, self.field_bytes);
+            }
+        }
+    }
+
+    pub(crate) fn in_quotes(&self) -> bool {
+        self.in_quotes
+    }
+
+    /// The index of the longest field, counting from 0, and its size in bytes
+    pub(crate) fn longest_field(&self) -> (usize, u64) {
+        self.longest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ends_in_quotes(dialect: Dialect, lines: &[&str]) -> bool {
+        let mut tracker = dialect.quote_tracker();
+        lines.iter().for_each(|line| tracker.feed(line.as_bytes()));
+        tracker.in_quotes()
+    }
+
+    #[test]
+    fn track_quotes() {
+        let standard = Dialect::default();
+        assert!(!ends_in_quotes(standard, &["a,\"b \"\"c\"\"\",d\n"]));
+        assert!(ends_in_quotes(standard, &["a,\"b\n"]));
+        assert!(!ends_in_quotes(standard, &["a,\"b\n", "c\"\n"]));
+
+        let backslash = Dialect::new('"', Some('\\'), false).unwrap();
+        assert!(!ends_in_quotes(backslash, &["a,\"b \\\"c\\\"\",d\n"]));
+        assert!(ends_in_quotes(backslash, &["a,\"b \\\"\n"]));
+        // An escaped line break continues the field on the next line
+        assert!(ends_in_quotes(backslash, &["a,\"b \\", "\n"]));
+        // Escapes outside quotes are ordinary characters
+        assert!(!ends_in_quotes(backslash, &["a\\,b\n"]));
+
+        let single = Dialect::new('\'', None, true).unwrap();
+        assert!(!ends_in_quotes(single, &["'it''s',\"\n"]));
+    }
+
+    #[test]
+    fn describe_quoting() {
+        assert!(Dialect::default().is_rfc_4180());
+        assert!(Dialect::default().with_tabs().is_rfc_4180());
+        let backslash = Dialect::new('"', Some('\\'), false).unwrap();
+        assert!(!backslash.is_rfc_4180());
+        assert_eq!("quoted with \", escaped with \\, without doubled quotes", backslash.to_string());
+        assert_eq!("--escape '\\' --double-quote false", backslash.quoting_options());
+        assert_eq!("--quote \"'\"", Dialect::new('\'', None, true).unwrap().quoting_options());
+        assert_eq!(backslash, Dialect::default().with_quoting(b'"', Some(b'\\'), false));
+    }
+
+    #[test]
+    fn track_longest_field() {
+        let mut tracker = Dialect::default().quote_tracker();
+        tracker.feed(b"a,bcd,\"e,f\n");
+        assert_eq!((2, 5), tracker.longest_field());
+        // Quoted line breaks are part of the field, but the one ending the record is not
+        tracker.feed(b"g\"\r\n");
+        assert_eq!((2, 7), tracker.longest_field());
+    }
+
+    #[test]
+    fn copy_options() {
+        assert_eq!(Some("FORMAT csv, QUOTE '\"', ESCAPE '\"'"), Dialect::default().copy_options().as_deref());
+        let backslash = Dialect::new('\'', Some('\\'), false).unwrap();
+        assert_eq!(Some("FORMAT csv, QUOTE '''', ESCAPE '\\'"), backslash.copy_options().as_deref());
+        assert_eq!(None, Dialect::new('"', Some('\\'), true).unwrap().copy_options());
+        assert_eq!(None, Dialect::new('"', None, false).unwrap().copy_options());
+        let tabs = Dialect::default().with_tabs().copy_options();
+        assert_eq!(Some("FORMAT csv, DELIMITER '\t', QUOTE '\"', ESCAPE '\"'"), tabs.as_deref());
+    }
+
+    #[cfg(feature = "duckdb")]
+    #[test]
+    fn read_csv_options() {
+        assert_eq!(Some("delim = ',', quote = '\"', escape = '\"'"), Dialect::default().read_csv_options().as_deref());
+        let backslash = Dialect::new('\'', Some('\\'), false).unwrap().with_tabs();
+        assert_eq!(Some("delim = '\t', quote = '''', escape = '\\'"), backslash.read_csv_options().as_deref());
+        assert_eq!(None, Dialect::new('"', Some('\\'), true).unwrap().read_csv_options());
+    }
+
+    #[test]
+    fn invalid_dialects() {
+        assert!(Dialect::new(',', None, true).is_err());
+        assert!(Dialect::new('«', None, true).is_err());
+        assert!(Dialect::new('"', Some('\n'), true).is_err());
+        assert!(Dialect::new('"', Some('"'), true).is_err());
+    }
+}
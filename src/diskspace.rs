@@ -0,0 +1,148 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::time::Duration;
+use async_std::path::PathBuf;
+use async_std::task;
+use crate::platform;
+use crate::progress::{format_bytes, ExportProgress};
+
+/// How many rows are written before their size is extrapolated to the rest
+const SAMPLE_ROWS: u64 = 1_000;
+/// How often the space is checked again while writing
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How much more than the projected size must be free, in percent, since the rows the planner
+/// expects are only an estimate
+const MARGIN_PERCENT: u64 = 10;
+
+/// The space which the rest of an export is projected to take, and the space free for it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Projection {
+    /// Of the whole export, including what is written so far
+    pub(crate) total_bytes: u64,
+    /// Of what is left to write
+    pub(crate) remaining_bytes: u64,
+    pub(crate) free_bytes: u64
+}
+
+impl Projection {
+
+    /// Projects an export before anything is written, from the rows the planner expects and
+    /// their average width in bytes
+    pub(crate) fn before_writing(estimated_rows: u64, row_width: u64, free_bytes: u64) -> Self {
+        let total_bytes = estimated_rows.saturating_mul(row_width);
+        Self { total_bytes, remaining_bytes: total_bytes, free_bytes }
+    }
+
+    /// Projects an export part of the way through, extrapolating the bytes per row written so far
+    /// to the rest of the rows expected. None until enough rows are written to go by. Once more
+    /// rows are written than expected, nothing more is projected
+    pub(crate) fn while_writing(estimated_rows: u64, rows_written: u64, bytes_written: u64,
+                                free_bytes: u64) -> Option<Self> {
+        if rows_written < SAMPLE_ROWS {
+            return None;
+        }
+        let remaining_rows = estimated_rows.saturating_sub(rows_written);
+        let remaining_bytes = (u128::from(remaining_rows) * u128::from(bytes_written) / u128::from(rows_written))
+            .try_into().unwrap_or(u64::MAX);
+        Some(Self { total_bytes: bytes_written.saturating_add(remaining_bytes), remaining_bytes, free_bytes })
+    }
+
+    /// Whether the space left to write, with the margin, is more than is free
+    pub(crate) fn is_short(&self) -> bool {
+        let margin = self.remaining_bytes / 100 * MARGIN_PERCENT;
+        self.remaining_bytes.saturating_add(margin) > self.free_bytes
+    }
+}
+
+impl fmt::Display for Projection {
+    /// E.g. estimated 12.0 GB, 4.0 GB free
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.remaining_bytes == self.total_bytes {
+            write!(f, "estimated {}, {} free", format_bytes(self.total_bytes), format_bytes(self.free_bytes))
+        } else {
+            write!(
+                f, "estimated {}, with {} still to write and {} free",
+                format_bytes(self.total_bytes), format_bytes(self.remaining_bytes), format_bytes(self.free_bytes)
+            )
+        }
+    }
+}
+
+/// The free space in the directory, if it can be told
+pub(crate) async fn available_space(directory: &PathBuf) -> Option<u64> {
+    let directory = std::path::PathBuf::from(directory.as_os_str());
+    task::spawn_blocking(move || platform::available_space(&directory)).await
+}
+
+/// Checks the space free where an export is written as it goes
+#[derive(Clone)]
+pub(crate) struct SpaceWatch {
+    pub(crate) directory: PathBuf,
+    pub(crate) estimated_rows: u64
+}
+
+impl SpaceWatch {
+
+    /// Waits until the rest of the export is projected to take more space than is free
+    pub(crate) async fn shortfall(&self, progress: &ExportProgress) -> Projection {
+        loop {
+            task::sleep(CHECK_INTERVAL).await;
+            let free_bytes = match available_space(&self.directory).await {
+                Some(free_bytes) => free_bytes,
+                None => continue
+            };
+            let projection = Projection::while_writing(
+                self.estimated_rows, progress.rows(), progress.bytes(), free_bytes
+            );
+            if let Some(projection) = projection.filter(Projection::is_short) {
+                return projection;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_before_writing() {
+        let projection = Projection::before_writing(100_000_000, 120, 4_000_000_000);
+        assert_eq!(12_000_000_000, projection.total_bytes);
+        assert!(projection.is_short());
+        assert_eq!("estimated 12.0 GB, 4.0 GB free", projection.to_string());
+        assert!(!Projection::before_writing(1_000, 100, 1_000_000).is_short());
+        // Within the margin
+        assert!(Projection::before_writing(1_000, 100, 105_000).is_short());
+    }
+
+    #[test]
+    fn extrapolate_rows_written() {
+        assert_eq!(None, Projection::while_writing(1_000_000, 999, 99_900, 1_000));
+        let projection = Projection::while_writing(1_000_000, 10_000, 2_000_000, 150_000_000).unwrap();
+        assert_eq!(198_000_000, projection.remaining_bytes);
+        assert_eq!(200_000_000, projection.total_bytes);
+        assert!(projection.is_short());
+        assert_eq!("estimated 200.0 MB, with 198.0 MB still to write and 150.0 MB free", projection.to_string());
+        // More rows than expected leaves nothing to project
+        let projection = Projection::while_writing(1_000, 5_000, 500_000, 0).unwrap();
+        assert_eq!(0, projection.remaining_bytes);
+        assert!(!projection.is_short());
+    }
+}
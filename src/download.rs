@@ -0,0 +1,176 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use async_compression::futures::bufread::GzipDecoder;
+use async_std::io::{self, BufRead, BufReader, Read};
+use eyre::eyre;
+use surf::http::headers::HeaderName;
+use url::Url;
+use crate::error::{Error, Result};
+
+/// How many redirects are followed before giving up
+const MAX_REDIRECTS: usize = 5;
+
+/// A header sent with download requests, given as `Name: value`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RequestHeader {
+    name: HeaderName,
+    value: String
+}
+
+impl std::str::FromStr for RequestHeader {
+    type Err = String;
+
+    fn from_str(header: &str) -> std::result::Result<Self, Self::Err> {
+        let (name, value) = header.split_once(':')
+            .ok_or_else(|| format!("Invalid header {}. Use the form 'Name: value'", header))?;
+        let name = name.trim();
+        let is_token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        if name.is_empty() || !name.chars().all(is_token) {
+            return Err(format!("Invalid header name {:?}", name));
+        }
+        let name = name.parse::<HeaderName>().map_err(|error| error.to_string())?;
+        Ok(Self { name, value: value.trim().to_owned() })
+    }
+}
+
+/// The URL without its query or fragment, which may contain secrets such as signatures
+pub(crate) fn redacted(url: &Url) -> String {
+    let mut url = url.clone();
+    url.set_query(None);
+    url.set_fragment(None);
+    url.to_string()
+}
+
+/// The body of a response, streamed as it arrives
+pub(crate) struct Download {
+    /// The body, decompressed if it was sent compressed
+    pub(crate) reader: Box<dyn BufRead + Unpin + Send>,
+    /// The length of the body as sent, if the server gave it
+    pub(crate) length: Option<u64>,
+    /// How many bytes of the body have been received, before decompression
+    pub(crate) received: Arc<AtomicU64>
+}
+
+impl Download {
+
+    /// Requests the URL, failing unless the response is successful. The headers are only sent
+    /// to the URL's origin, so once a redirect leads to another scheme, host or port, they are
+    /// left out, as credentials such as Authorization are meant for the URL given
+    pub(crate) async fn start(url: &Url, headers: &[RequestHeader]) -> Result<Self> {
+        let client = surf::client();
+        let mut location = url.clone();
+        let mut redirects = 0;
+        let mut cross_origin = false;
+        let mut response = loop {
+            cross_origin |= location.origin() != url.origin();
+            let mut request = client.get(location.as_str());
+            for header in headers.iter().filter(|_| !cross_origin) {
+                request = request.header(&header.name, header.value.as_str());
+            }
+            let response = request.await
+                .map_err(|error| Error::ImportIo(eyre!("Unable to download {}: {}", redacted(url), error)))?;
+            let redirect = response.header("Location")
+                .filter(|_| response.status().is_redirection() && redirects < MAX_REDIRECTS)
+                .and_then(|next| location.join(next.as_str()).ok());
+            match redirect {
+                Some(next) => {
+                    location = next;
+                    redirects += 1;
+                },
+                None => break response
+            }
+        };
+        if !response.status().is_success() {
+            // Error bodies tend to explain themselves in their first line
+            let body = response.body_string().await.unwrap_or_default();
+            let first_line = body.lines().map(str::trim).find(|line| !line.is_empty()).unwrap_or_default();
+            return Err(Error::ImportIo(eyre!(
+                "Unable to download {}: the server responded {} {}{}{}", redacted(url), response.status(),
+                response.status().canonical_reason(), if first_line.is_empty() { "" } else { ": " }, first_line
+            )));
+        }
+
+        let gzip = response.header("Content-Encoding")
+            .is_some_and(|encoding| encoding.as_str().trim().eq_ignore_ascii_case("gzip"));
+        let length = response.len().map(|length| length as u64);
+        let received = Arc::new(AtomicU64::new(0));
+        let body = Counted { reader: response.take_body().into_reader(), received: received.clone() };
+        let reader: Box<dyn BufRead + Unpin + Send> = if gzip {
+            Box::new(BufReader::new(GzipDecoder::new(body)))
+        } else {
+            Box::new(body)
+        };
+        Ok(Self { reader, length, received })
+    }
+}
+
+/// Counts the bytes read through it
+struct Counted<R> {
+    reader: R,
+    received: Arc<AtomicU64>
+}
+
+impl<R> Read for Counted<R> where R: Read + Unpin {
+    fn poll_read(mut self: Pin<&mut Self>, context: &mut Context<'_>, buffer: &mut [u8]) -> Poll<io::Result<usize>> {
+        let read = Pin::new(&mut self.reader).poll_read(context, buffer);
+        if let Poll::Ready(Ok(read)) = read {
+            self.received.fetch_add(read as u64, Ordering::Relaxed);
+        }
+        read
+    }
+}
+
+impl<R> BufRead for Counted<R> where R: BufRead + Unpin {
+    fn poll_fill_buf(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().reader).poll_fill_buf(context)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amount: usize) {
+        self.received.fetch_add(amount as u64, Ordering::Relaxed);
+        Pin::new(&mut self.reader).consume(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request_header() {
+        let header: RequestHeader = "Authorization:  Bearer abc:def ".parse().unwrap();
+        assert_eq!("authorization", header.name.as_str());
+        assert_eq!("Bearer abc:def", header.value);
+        assert!("Authorization".parse::<RequestHeader>().is_err());
+        assert!("Bad Name: value".parse::<RequestHeader>().is_err());
+    }
+
+    #[async_std::test]
+    async fn count_bytes_read() {
+        use futures_lite::AsyncReadExt;
+
+        let received = Arc::new(AtomicU64::new(0));
+        let mut counted = Counted { reader: b"a,b\n1,2\n" as &[u8], received: received.clone() };
+        let mut text = String::new();
+        counted.read_to_string(&mut text).await.unwrap();
+        assert_eq!(8, received.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,591 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::{Path, PathBuf};
+use crate::config::Config;
+use crate::dialect::Dialect;
+
+#[cfg(not(feature = "duckdb"))]
+pub(crate) use self::absent::Embedded;
+#[cfg(feature = "duckdb")]
+pub(crate) use self::duck::Embedded;
+
+/// The file the duckdb backend keeps its tables in, within the directory for data-sifter's
+/// files, unless the config names another
+const DEFAULT_FILE: &str = "data-sifter.duckdb";
+
+/// The file the duckdb backend keeps its tables in
+pub(crate) fn database_file(config: &Config, data_dir: &Path) -> PathBuf {
+    config.duckdb_file.clone().unwrap_or_else(|| data_dir.join(DEFAULT_FILE))
+}
+
+/// A dataset DuckDB reads by itself
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Source {
+    Csv(PathBuf, Dialect),
+    Parquet(PathBuf)
+}
+
+#[cfg(not(feature = "duckdb"))]
+mod absent {
+    use std::path::Path;
+    use std::sync::Arc;
+    use eyre::eyre;
+    use futures_util::future::BoxFuture;
+    use crate::database::{TableColumn, TableMode};
+    use crate::error::{Error, Result};
+    use crate::import::ImportSummary;
+    use crate::observer::ImportObserver;
+    use crate::output::{ExportOptions, OutputFormat, OutputSummary};
+    use crate::progress::ExportProgress;
+    use super::Source;
+
+    /// DuckDB, which this build of data-sifter cannot open, so that there is never one
+    pub(crate) enum Embedded {}
+
+    impl Embedded {
+        pub(crate) fn open(_path: &Path) -> Result<Self> {
+            Err(Error::Config(eyre!(
+                "data-sifter was built without DuckDB support. Build it with --features duckdb to use the duckdb backend"
+            )))
+        }
+
+        pub(crate) fn description(&self) -> String {
+            match *self {}
+        }
+
+        pub(crate) fn import(&self, _source: Source, _table: String, _mode: TableMode,
+                             _observer: Arc<dyn ImportObserver>) -> BoxFuture<'static, Result<ImportSummary>> {
+            match *self {}
+        }
+
+        pub(crate) async fn row_count(&self, _table: &str) -> Result<Option<u64>> {
+            match *self {}
+        }
+
+        pub(crate) async fn table_columns(&self, _table: &str) -> Result<Vec<TableColumn>> {
+            match *self {}
+        }
+
+        pub(crate) async fn execute(&self, _statement: &str) -> Result<u64> {
+            match *self {}
+        }
+
+        pub(crate) async fn materialize(&self, _query: &str, _table: &str, _replace: bool) -> Result<u64> {
+            match *self {}
+        }
+
+        pub(crate) async fn output_query_results<W>(&self, _query: &str, _format: OutputFormat, _options: &ExportOptions,
+                                                    _output: W, _progress: Arc<ExportProgress>) -> Result<OutputSummary>
+            where W: async_std::io::Write + Unpin {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(feature = "duckdb")]
+mod duck {
+    use std::borrow::Cow;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use async_std::channel::{self, Receiver};
+    use async_std::task;
+    use chrono::{DateTime, NaiveDate, NaiveTime};
+    use duckdb::Connection;
+    use duckdb::types::{TimeUnit, Value};
+    use eyre::eyre;
+    use futures_util::future::BoxFuture;
+    use rust_decimal::Decimal;
+    use crate::database::{self, ResultRows, TableColumn, TableMode};
+    use crate::decode::DecodedValue;
+    use crate::error::{Error, Result};
+    use crate::import::ImportSummary;
+    use crate::observer::{ImportEvent, ImportObserver};
+    use crate::output::{ExportOptions, OutputFormat, OutputSummary};
+    use crate::progress::ExportProgress;
+    use super::Source;
+
+    /// How many rows DuckDB reads ahead of those written
+    const ROWS_AHEAD: usize = 1024;
+
+    /// DuckDB, embedded in data-sifter, with its tables in a file of its own. Its connection is
+    /// cloned for each statement, which runs on a thread of its own, since DuckDB blocks
+    pub(crate) struct Embedded {
+        path: PathBuf,
+        connection: Mutex<Connection>
+    }
+
+    /// What a query returns, as DuckDB reads it
+    enum Fetched {
+        Columns(Vec<String>),
+        Row(Vec<Value>)
+    }
+
+    impl Embedded {
+        /// Opens the database file, creating it if there is none
+        pub(crate) fn open(path: &Path) -> Result<Self> {
+            let connection = Connection::open(path).map_err(|error| {
+                Error::Connect(eyre!(error)).wrap_err(format!("Unable to open the DuckDB database {}", path.display()))
+            })?;
+            Ok(Self { path: path.to_path_buf(), connection: Mutex::new(connection) })
+        }
+
+        /// Which database holds the tables, for showing in the import plan
+        pub(crate) fn description(&self) -> String {
+            format!("DuckDB database {}", self.path.display())
+        }
+
+        fn connection(&self) -> Result<Connection> {
+            let connection = self.connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            connection.try_clone().map_err(|error| Error::Connect(eyre!(error)))
+        }
+
+        /// Reads the dataset into the table with DuckDB's own reader, which detects the types
+        /// of its columns. The table is replaced, or added to with the columns matched by name,
+        /// all in one transaction. The rows inserted are reported to the observer once committed
+        pub(crate) fn import(&self, source: Source, table: String, mode: TableMode,
+                             observer: Arc<dyn ImportObserver>) -> BoxFuture<'static, Result<ImportSummary>> {
+            let connection = self.connection();
+            Box::pin(async move {
+                let mut connection = connection?;
+                task::spawn_blocking(move || {
+                    let reader = match &source {
+                        Source::Csv(path, dialect) => {
+                            let options = dialect.read_csv_options().ok_or_else(|| Error::Usage(eyre!(
+                                "DuckDB cannot read quotes which are both doubled and escaped. Pass --double-quote \
+                                false, or leave out --escape"
+                            )))?;
+                            format!("read_csv_auto({}, header = true, {})", string_literal(path)?, options)
+                        },
+                        Source::Parquet(path) => format!("read_parquet({})", string_literal(path)?)
+                    };
+                    let mut imported = || -> duckdb::Result<(u64, u64)> {
+                        let transaction = connection.transaction()?;
+                        if let Some((schema, _)) = table.split_once('.') {
+                            transaction.execute_batch(&format!("CREATE SCHEMA IF NOT EXISTS {}", schema))?;
+                        }
+                        let exists = table_exists(&transaction, &table)?;
+                        let (statement, created) = match mode {
+                            TableMode::Replace => {
+                                (format!("CREATE OR REPLACE TABLE {} AS SELECT * FROM {}", table, reader), true)
+                            },
+                            TableMode::Append if !exists => {
+                                (format!("CREATE TABLE {} AS SELECT * FROM {}", table, reader), true)
+                            },
+                            TableMode::Append | TableMode::Strict => {
+                                (format!("INSERT INTO {} BY NAME SELECT * FROM {}", table, reader), false)
+                            }
+                        };
+                        let inserted = transaction.execute(&statement, [])? as u64;
+                        let rows: i64 = transaction.query_row(&format!("SELECT count(*) FROM {}", table), [], |row| row.get(0))?;
+                        transaction.commit()?;
+                        // DuckDB counts no changed rows for CREATE TABLE AS, whose rows are all inserted
+                        Ok((if created { rows as u64 } else { inserted }, rows as u64))
+                    };
+                    let (inserted, rows) = imported().map_err(|error| {
+                        let message = error.to_string();
+                        match message.starts_with("IO Error") {
+                            true => Error::ImportIo(eyre!(message)),
+                            false => Error::ImportData(eyre!(message))
+                        }
+                    })?;
+                    observer.observe(&ImportEvent::Progress { rows: inserted, bytes: 0 });
+                    Ok(ImportSummary { rows: Some(rows), ..ImportSummary::default() })
+                }).await
+            })
+        }
+
+        /// Counts the rows of the table, or returns none if there is no such table. DuckDB
+        /// counts them quickly, so they are not estimated
+        pub(crate) async fn row_count(&self, table: &str) -> Result<Option<u64>> {
+            let connection = self.connection()?;
+            let table = String::from(table);
+            task::spawn_blocking(move || {
+                if !table_exists(&connection, &table)? {
+                    return Ok(None);
+                }
+                let rows: i64 = connection.query_row(&format!("SELECT count(*) FROM {}", table), [], |row| row.get(0))?;
+                Ok(Some(rows as u64))
+            }).await.map_err(|error: duckdb::Error| Error::QueryRuntime(eyre!(error)))
+        }
+
+        /// A table's columns, in order. There are none if there is no such table
+        pub(crate) async fn table_columns(&self, table: &str) -> Result<Vec<TableColumn>> {
+            let connection = self.connection()?;
+            let (schema, name) = split_table(table);
+            task::spawn_blocking(move || {
+                let mut statement = connection.prepare(
+                    "SELECT column_name, data_type FROM duckdb_columns() \
+                    WHERE schema_name = coalesce(?, current_schema()) AND table_name = ? ORDER BY column_index"
+                )?;
+                let columns = statement.query_map(duckdb::params![schema, name], |row| {
+                    let data_type = row.get::<_, String>(1)?.to_lowercase();
+                    Ok(TableColumn {
+                        name: row.get(0)?,
+                        type_name: data_type.clone(),
+                        textual: data_type == "varchar",
+                        data_type,
+                        generated: false,
+                        computed: None
+                    })
+                })?;
+                columns.collect::<duckdb::Result<Vec<_>>>()
+            }).await.map_err(|error| Error::QueryRuntime(eyre!(error)))
+        }
+
+        /// Runs a statement, such as one entered before the query. Returns how many rows it
+        /// changed
+        pub(crate) async fn execute(&self, statement: &str) -> Result<u64> {
+            let connection = self.connection()?;
+            let statement = String::from(statement);
+            task::spawn_blocking(move || connection.execute(&statement, []))
+                .await
+                .map(|rows| rows as u64)
+                .map_err(query_error)
+        }
+
+        /// Saves the query results in a new table, replacing any existing table if asked to.
+        /// Returns how many rows were saved
+        pub(crate) async fn materialize(&self, query: &str, table: &str, replace: bool) -> Result<u64> {
+            let mut connection = self.connection()?;
+            let create = match replace {
+                true => "CREATE OR REPLACE TABLE",
+                false => "CREATE TABLE"
+            };
+            let statement = format!("{} {} AS {}", create, table, without_terminator(query));
+            let count = format!("SELECT count(*) FROM {}", table);
+            task::spawn_blocking(move || {
+                let transaction = connection.transaction()?;
+                transaction.execute_batch(&statement)?;
+                let rows: i64 = transaction.query_row(&count, [], |row| row.get(0))?;
+                transaction.commit()?;
+                Ok(rows as u64)
+            }).await.map_err(query_error)
+        }
+
+        /// Writes the results as Query::output_query_results does, decoding each value DuckDB
+        /// returns to the same values as Postgres's. A statement which is not a query writes
+        /// nothing
+        pub(crate) async fn output_query_results<W>(&self,
+                                                    query: &str,
+                                                    format: OutputFormat,
+                                                    options: &ExportOptions,
+                                                    output: W,
+                                                    progress: Arc<ExportProgress>) -> Result<OutputSummary>
+            where W: async_std::io::Write + Unpin {
+
+            if !database::returns_rows(query) {
+                self.execute(query).await?;
+                return Ok(OutputSummary::default());
+            }
+            let results = self.fetch(query)?;
+            let columns = match results.recv().await {
+                Ok(Ok(Fetched::Columns(columns))) => columns,
+                Ok(Err(error)) => return Err(error),
+                _ => return Ok(OutputSummary::default())
+            };
+            database::write_rows(DuckResultRows { columns, results }, format, options, output, progress).await
+        }
+
+        /// Runs the query on a thread of its own, which sends the names of its columns and then
+        /// its rows. Once they are no longer received, it stops reading them
+        fn fetch(&self, query: &str) -> Result<Receiver<Result<Fetched>>> {
+            let connection = self.connection()?;
+            let query = String::from(query);
+            let (sender, receiver) = channel::bounded(ROWS_AHEAD);
+            task::spawn_blocking(move || {
+                let send = |fetched| futures_lite::future::block_on(sender.send(fetched)).is_ok();
+                let fetched = (|| {
+                    let mut statement = connection.prepare(&query)?;
+                    let mut rows = statement.query([])?;
+                    let columns = rows.as_ref().map(|statement| statement.column_names()).unwrap_or_default();
+                    let count = columns.len();
+                    if !send(Ok(Fetched::Columns(columns))) {
+                        return Ok(());
+                    }
+                    while let Some(row) = rows.next()? {
+                        let values = (0..count).map(|index| row.get::<_, Value>(index)).collect::<duckdb::Result<_>>()?;
+                        if !send(Ok(Fetched::Row(values))) {
+                            break;
+                        }
+                    }
+                    Ok(())
+                })();
+                if let Err(error) = fetched {
+                    send(Err(query_error(error)));
+                }
+            });
+            Ok(receiver)
+        }
+    }
+
+    /// A query DuckDB refused to run is told apart from one which failed as it ran, as for Postgres
+    fn query_error(error: duckdb::Error) -> Error {
+        let message = error.to_string();
+        let refused = ["Parser Error", "Binder Error", "Catalog Error"].iter().any(|kind| message.starts_with(kind));
+        match refused {
+            true => Error::QuerySyntax(eyre!(message)),
+            false => Error::QueryRuntime(eyre!(message))
+        }
+    }
+
+    fn table_exists(connection: &Connection, table: &str) -> duckdb::Result<bool> {
+        let (schema, name) = split_table(table);
+        connection.query_row(
+            "SELECT count(*) > 0 FROM duckdb_tables() WHERE schema_name = coalesce(?, current_schema()) AND table_name = ?",
+            duckdb::params![schema, name],
+            |row| row.get(0)
+        )
+    }
+
+    /// The schema of a qualified table name, if it has one, and the table's own name
+    fn split_table(table: &str) -> (Option<String>, String) {
+        match table.split_once('.') {
+            Some((schema, name)) => (Some(String::from(schema)), String::from(name)),
+            None => (None, String::from(table))
+        }
+    }
+
+    /// The path as an SQL string, since DuckDB's readers take no parameters
+    fn string_literal(path: &Path) -> Result<String> {
+        let path = path.to_str().ok_or_else(|| Error::ImportIo(eyre!(
+            "DuckDB can only read files whose paths are UTF-8, unlike {}", path.display()
+        )))?;
+        Ok(format!("'{}'", path.replace('\'', "''")))
+    }
+
+    fn without_terminator(query: &str) -> &str {
+        query.trim_end().trim_end_matches(|c: char| c == ';' || c.is_whitespace())
+    }
+
+    /// The rows DuckDB sends from the thread running the query, after the names of their columns
+    struct DuckResultRows {
+        columns: Vec<String>,
+        results: Receiver<Result<Fetched>>
+    }
+
+    impl ResultRows for DuckResultRows {
+        type Row = Vec<Value>;
+
+        fn columns(&self) -> &[String] {
+            &self.columns
+        }
+
+        async fn next_row(&mut self) -> Result<Option<Vec<Value>>> {
+            match self.results.recv().await {
+                Ok(Ok(Fetched::Row(values))) => Ok(Some(values)),
+                Ok(Err(error)) => Err(error),
+                _ => Ok(None)
+            }
+        }
+
+        fn decode<'r>(&self, row: &'r Vec<Value>) -> Result<Vec<DecodedValue<'r>>> {
+            Ok(row.iter().map(decode).collect())
+        }
+    }
+
+    /// The value as Postgres's value of the nearest type would be decoded. Structs, maps, unions
+    /// and any other values Postgres lacks become JSON, and intervals text
+    fn decode(value: &Value) -> DecodedValue<'_> {
+        match *value {
+            Value::Null => DecodedValue::Null,
+            Value::Boolean(value) => DecodedValue::Bool(value),
+            Value::TinyInt(value) => DecodedValue::Int(value.into()),
+            Value::SmallInt(value) => DecodedValue::Int(value.into()),
+            Value::Int(value) => DecodedValue::Int(value.into()),
+            Value::BigInt(value) => DecodedValue::Int(value),
+            Value::UTinyInt(value) => DecodedValue::Int(value.into()),
+            Value::USmallInt(value) => DecodedValue::Int(value.into()),
+            Value::UInt(value) => DecodedValue::Int(value.into()),
+            Value::UBigInt(value) => whole_number(value.into()),
+            Value::HugeInt(value) => whole_number(value),
+            Value::UHugeInt(value) => match i128::try_from(value) {
+                Ok(value) => whole_number(value),
+                Err(_) => DecodedValue::Text(Cow::Owned(value.to_string()))
+            },
+            Value::Float(value) => DecodedValue::Float4(value),
+            Value::Double(value) => DecodedValue::Float8(value),
+            Value::Decimal(value) => {
+                match Decimal::try_from_i128_with_scale(value.value(), value.scale().into()) {
+                    Ok(decimal) => DecodedValue::Numeric(decimal),
+                    Err(_) => DecodedValue::Text(Cow::Owned(value.to_string()))
+                }
+            },
+            Value::Timestamp(unit, value) => match micros(unit, value).and_then(DateTime::from_timestamp_micros) {
+                Some(timestamp) => DecodedValue::Timestamp(timestamp.naive_utc()),
+                None => DecodedValue::Text(Cow::Owned(value.to_string()))
+            },
+            Value::Date32(days) => {
+                let date = NaiveDate::from_ymd_opt(1970, 1, 1)
+                    .and_then(|epoch| epoch.checked_add_signed(chrono::Duration::days(days.into())));
+                match date {
+                    Some(date) => DecodedValue::Date(date),
+                    None => DecodedValue::Text(Cow::Owned(days.to_string()))
+                }
+            },
+            Value::Time64(unit, value) => {
+                let time = micros(unit, value).and_then(|micros| NaiveTime::from_num_seconds_from_midnight_opt(
+                    u32::try_from(micros.div_euclid(1_000_000)).ok()?, (micros.rem_euclid(1_000_000) * 1000) as u32
+                ));
+                match time {
+                    Some(time) => DecodedValue::Time(time),
+                    None => DecodedValue::Text(Cow::Owned(value.to_string()))
+                }
+            },
+            Value::Interval { months, days, nanos } => DecodedValue::Text(Cow::Owned(interval(months, days, nanos))),
+            Value::Text(ref text) | Value::Enum(ref text) => DecodedValue::Text(Cow::Borrowed(text)),
+            Value::Blob(ref bytes) | Value::Geometry(ref bytes) => DecodedValue::Bytes(Cow::Borrowed(bytes)),
+            Value::List(ref elements) | Value::Array(ref elements) => {
+                DecodedValue::Array(elements.iter().map(decode).collect())
+            },
+            _ => DecodedValue::Json(Cow::Owned(json(value)))
+        }
+    }
+
+    /// A whole number, as numeric if it is too large for bigint
+    fn whole_number(value: i128) -> DecodedValue<'static> {
+        match i64::try_from(value) {
+            Ok(value) => DecodedValue::Int(value),
+            Err(_) => match Decimal::try_from_i128_with_scale(value, 0) {
+                Ok(decimal) => DecodedValue::Numeric(decimal),
+                Err(_) => DecodedValue::Text(Cow::Owned(value.to_string()))
+            }
+        }
+    }
+
+    /// The value in microseconds, unless it overflows
+    fn micros(unit: TimeUnit, value: i64) -> Option<i64> {
+        match unit {
+            TimeUnit::Second => value.checked_mul(1_000_000),
+            TimeUnit::Millisecond => value.checked_mul(1000),
+            TimeUnit::Microsecond => Some(value),
+            TimeUnit::Nanosecond => Some(value / 1000)
+        }
+    }
+
+    /// The interval as Postgres writes it, such as 1 mon 2 days 03:04:05.5
+    fn interval(months: i32, days: i32, nanos: i64) -> String {
+        let mut parts = Vec::new();
+        let (years, months) = (months / 12, months % 12);
+        let plural = |count: i32, unit: &str| format!("{} {}{}", count, unit, if count.abs() == 1 { "" } else { "s" });
+        if years != 0 {
+            parts.push(plural(years, "year"));
+        }
+        if months != 0 {
+            parts.push(format!("{} mon{}", months, if months.abs() == 1 { "" } else { "s" }));
+        }
+        if days != 0 {
+            parts.push(plural(days, "day"));
+        }
+        if nanos != 0 || parts.is_empty() {
+            let sign = if nanos < 0 { "-" } else { "" };
+            let micros = (nanos / 1000).unsigned_abs();
+            let (seconds, fraction) = (micros / 1_000_000, micros % 1_000_000);
+            let mut time = format!("{}{:02}:{:02}:{:02}", sign, seconds / 3600, seconds / 60 % 60, seconds % 60);
+            if fraction != 0 {
+                time.push_str(format!(".{:06}", fraction).trim_end_matches('0'));
+            }
+            parts.push(time);
+        }
+        parts.join(" ")
+    }
+
+    /// The value as JSON, for values which have no counterpart in Postgres
+    fn json(value: &Value) -> serde_json::Value {
+        let options = crate::decode::FormatOptions::default();
+        match value {
+            Value::Struct(fields) => serde_json::Value::Object(
+                fields.iter().map(|(name, value)| (name.clone(), json(value))).collect()
+            ),
+            Value::Map(entries) => serde_json::Value::Object(entries.iter()
+                .map(|(key, value)| (decode(key).to_text(&options).into_owned(), json(value)))
+                .collect()),
+            Value::Union(value) => json(value),
+            Value::List(elements) | Value::Array(elements) => serde_json::Value::Array(elements.iter().map(json).collect()),
+            value => match decode(value) {
+                DecodedValue::Null => serde_json::Value::Null,
+                DecodedValue::Bool(value) => serde_json::Value::Bool(value),
+                DecodedValue::Int(value) => value.into(),
+                DecodedValue::Float8(value) => value.into(),
+                DecodedValue::Float4(value) => f64::from(value).into(),
+                DecodedValue::Json(value) => value.into_owned(),
+                decoded => serde_json::Value::String(decoded.to_text(&options).into_owned())
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::progress::ImportProgress;
+
+        #[test]
+        fn decode_values() {
+            assert_eq!(DecodedValue::Int(7), decode(&Value::UTinyInt(7)));
+            assert_eq!(DecodedValue::Numeric(Decimal::from(u64::MAX)), decode(&Value::UBigInt(u64::MAX)));
+            assert_eq!(DecodedValue::Text(Cow::Borrowed("340282366920938463463374607431768211455")),
+                       decode(&Value::UHugeInt(u128::MAX)));
+            assert_eq!(DecodedValue::Date(NaiveDate::from_ymd_opt(2022, 3, 13).unwrap()), decode(&Value::Date32(19064)));
+            let timestamp = NaiveDate::from_ymd_opt(2022, 3, 13).unwrap().and_hms_milli_opt(7, 0, 0, 500).unwrap();
+            assert_eq!(DecodedValue::Timestamp(timestamp),
+                       decode(&Value::Timestamp(TimeUnit::Millisecond, 1_647_154_800_500)));
+            assert_eq!(DecodedValue::Time(NaiveTime::from_hms_opt(1, 2, 3).unwrap()),
+                       decode(&Value::Time64(TimeUnit::Microsecond, 3_723_000_000)));
+            assert_eq!(DecodedValue::Array(vec![DecodedValue::Int(1), DecodedValue::Null]),
+                       decode(&Value::List(vec![Value::Int(1), Value::Null])));
+        }
+
+        #[test]
+        fn intervals_are_written_as_postgres_writes_them() {
+            assert_eq!("1 year 2 mons 3 days 04:05:06.5", interval(14, 3, 14_706_500_000_000));
+            assert_eq!("1 day", interval(0, 1, 0));
+            assert_eq!("-00:00:01", interval(0, 0, -1_000_000_000));
+            assert_eq!("00:00:00", interval(0, 0, 0));
+        }
+
+        #[async_std::test]
+        async fn import_then_query() -> Result<()> {
+            let directory = tempfile::tempdir().unwrap();
+            let csv = directory.path().join("sales.csv");
+            std::fs::write(&csv, "store,amount,day\nnorth,1.50,2022-03-01\nsouth,2,2022-03-02\n").unwrap();
+            let embedded = Embedded::open(&directory.path().join("test.duckdb"))?;
+            let source = Source::Csv(csv, crate::dialect::Dialect::default());
+            let progress = Arc::new(ImportProgress::default());
+            let summary = embedded.import(source.clone(), String::from("sales"), TableMode::Replace, progress.clone()).await?;
+            assert_eq!((Some(2), 2), (summary.rows, progress.rows()));
+            let progress = Arc::new(ImportProgress::default());
+            let summary = embedded.import(source, String::from("sales"), TableMode::Append, progress.clone()).await?;
+            assert_eq!((Some(4), 2), (summary.rows, progress.rows()));
+            let columns = embedded.table_columns("sales").await?;
+            assert_eq!(vec!["store", "amount", "day"], columns.iter().map(|column| column.name.as_str()).collect::<Vec<_>>());
+            assert_eq!(Some(4), embedded.row_count("sales").await?);
+            assert_eq!(None, embedded.row_count("missing").await?);
+
+            let query = "SELECT store, sum(amount) AS total, min(day) AS first FROM sales GROUP BY store ORDER BY store";
+            let mut output = Vec::new();
+            let progress = Arc::new(ExportProgress::default());
+            let summary = embedded.output_query_results(
+                query, OutputFormat::Csv, &ExportOptions::default(), &mut output, progress
+            ).await?;
+            assert_eq!(2, summary.rows);
+            assert_eq!("store,total,first\nnorth,3,2022-03-01\nsouth,4,2022-03-02\n", String::from_utf8(output).unwrap());
+            let error = embedded.execute("SELECT missing FROM sales").await.unwrap_err();
+            assert!(matches!(error, Error::QuerySyntax(_)), "{:?}", error);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,249 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use eyre::Report;
+
+pub type Result<T, E = Error> = core::result::Result<T, E>;
+
+/// A failure categorized by the phase in which it happened.
+///
+/// Each category maps to its own process exit code, so that scripts wrapping
+/// data-sifter can tell a bad query apart from a missing file.
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid command-line usage, such as a missing answer in non-interactive mode
+    Usage(Report),
+    /// The config file is missing, unreadable or invalid
+    Config(Report),
+    /// The database could not be reached
+    Connect(Report),
+    /// The CSV dataset contains data which cannot be imported
+    ImportData(Report),
+    /// The CSV dataset could not be read
+    ImportIo(Report),
+    /// The query was rejected by the database before it ran
+    QuerySyntax(Report),
+    /// The query failed while running
+    QueryRuntime(Report),
+    /// The query results could not be written
+    OutputIo(Report),
+}
+
+impl Error {
+    /// The process exit code for this error. These are documented in the README.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Usage(_) => 2,
+            Self::Config(_) => 3,
+            Self::Connect(_) => 4,
+            Self::ImportData(_) => 5,
+            Self::ImportIo(_) => 6,
+            Self::QuerySyntax(_) => 7,
+            Self::QueryRuntime(_) => 8,
+            Self::OutputIo(_) => 9,
+        }
+    }
+
+    /// The name of the category, as written in run reports
+    pub fn category(&self) -> &'static str {
+        match self {
+            Self::Usage(_) => "usage",
+            Self::Config(_) => "config",
+            Self::Connect(_) => "connect",
+            Self::ImportData(_) => "import_data",
+            Self::ImportIo(_) => "import_io",
+            Self::QuerySyntax(_) => "query_syntax",
+            Self::QueryRuntime(_) => "query_runtime",
+            Self::OutputIo(_) => "output_io",
+        }
+    }
+
+    /// Adds context to the error, keeping its category
+    pub fn wrap_err<D>(self, context: D) -> Self where D: fmt::Display + Send + Sync + 'static {
+        match self {
+            Self::Usage(report) => Self::Usage(report.wrap_err(context)),
+            Self::Config(report) => Self::Config(report.wrap_err(context)),
+            Self::Connect(report) => Self::Connect(report.wrap_err(context)),
+            Self::ImportData(report) => Self::ImportData(report.wrap_err(context)),
+            Self::ImportIo(report) => Self::ImportIo(report.wrap_err(context)),
+            Self::QuerySyntax(report) => Self::QuerySyntax(report.wrap_err(context)),
+            Self::QueryRuntime(report) => Self::QueryRuntime(report.wrap_err(context)),
+            Self::OutputIo(report) => Self::OutputIo(report.wrap_err(context)),
+        }
+    }
+
+    /// The error followed by its causes, one per line, without a backtrace
+    pub fn render(&self) -> String {
+        let mut rendered = self.report().to_string();
+        let mut previous = rendered.clone();
+        for cause in self.report().chain().skip(1) {
+            let cause = cause.to_string();
+            // Some errors repeat their source in their own message
+            if !previous.ends_with(&cause) {
+                rendered.push_str("\n  caused by: ");
+                rendered.push_str(&cause);
+            }
+            previous = cause;
+        }
+        rendered
+    }
+
+    /// The same error in another category
+    pub fn recategorize(self, category: fn(Report) -> Error) -> Self {
+        match self {
+            Self::Usage(report) | Self::Config(report) | Self::Connect(report)
+            | Self::ImportData(report) | Self::ImportIo(report) | Self::QuerySyntax(report)
+            | Self::QueryRuntime(report) | Self::OutputIo(report) => category(report)
+        }
+    }
+
+    pub fn report(&self) -> &Report {
+        match self {
+            Self::Usage(report) | Self::Config(report) | Self::Connect(report)
+            | Self::ImportData(report) | Self::ImportIo(report) | Self::QuerySyntax(report)
+            | Self::QueryRuntime(report) | Self::OutputIo(report) => report
+        }
+    }
+
+    /// Whether the output could not be written since whatever reads it closed it, as `head` does
+    /// once it has read enough. Nothing more can be written, but nothing went wrong either
+    pub fn closed_output(&self) -> bool {
+        matches!(self, Self::OutputIo(_)) && self.report().chain().any(|cause| {
+            cause.downcast_ref::<std::io::Error>().is_some_and(|error| error.kind() == std::io::ErrorKind::BrokenPipe)
+        })
+    }
+
+    /// Whether the connection to the database was lost, such as reset by a firewall or ended by
+    /// the server, rather than refused or timed out waiting for
+    pub fn lost_connection(&self) -> bool {
+        matches!(self, Self::Connect(_)) && self.report().chain().any(|cause| {
+            match cause.downcast_ref::<sqlx::Error>() {
+                // A failed handshake would only fail again
+                Some(error @ sqlx::Error::Io(_)) => crate::ssl::explain_handshake(error).is_none(),
+                Some(sqlx::Error::Database(error)) => error.code().is_some_and(|code| ends_connection(&code)),
+                _ => false
+            }
+        })
+    }
+
+    /// Categorizes a database error. Connection problems are always categorized as
+    /// such, and every other error receives the given category.
+    pub fn from_database(error: sqlx::Error, category: fn(Report) -> Error) -> Self {
+        match error {
+            sqlx::Error::Configuration(_) => Self::Config(error.into()),
+            sqlx::Error::Database(ref db_error) if db_error.code().is_some_and(|code| ends_connection(&code)) => {
+                Self::Connect(error.into())
+            },
+            sqlx::Error::Io(_) | sqlx::Error::Tls(_) => match crate::ssl::explain_handshake(&error) {
+                Some(explanation) => Self::Connect(Report::from(error).wrap_err(explanation)),
+                None => Self::Connect(error.into())
+            },
+            sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed => Self::Connect(error.into()),
+            _ => category(error.into())
+        }
+    }
+
+    /// Categorizes an error returned by the database while executing a query.
+    ///
+    /// SQLSTATE class 42 covers syntax errors and references to unknown tables or
+    /// columns, all of which are detected before the query runs.
+    pub fn from_query(error: sqlx::Error) -> Self {
+        let syntax = matches!(
+            &error, sqlx::Error::Database(db_error) if db_error.code().is_some_and(|code| code.starts_with("42"))
+        );
+        if syntax {
+            Self::QuerySyntax(error.into())
+        } else {
+            Self::from_database(error, Self::QueryRuntime)
+        }
+    }
+
+    /// Categorizes an error reading the CSV dataset
+    pub fn from_csv(error: csv_async::Error) -> Self {
+        if let csv_async::ErrorKind::Io(_) = error.kind() {
+            Self::ImportIo(error.into())
+        } else {
+            Self::ImportData(error.into())
+        }
+    }
+}
+
+/// Whether the server ends the connection with an error of this SQLSTATE: class 08 is for
+/// connection exceptions, and the others for a connection terminated by an administrator, by
+/// the server crashing or by idle_session_timeout
+fn ends_connection(code: &str) -> bool {
+    code.starts_with("08") || matches!(code, "57P01" | "57P02" | "57P05")
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.report(), f)
+    }
+}
+
+/// Attaches an error category to a fallible result
+pub trait Categorize<T> {
+    fn categorize(self, category: fn(Report) -> Error) -> Result<T>;
+}
+
+impl<T, E> Categorize<T> for core::result::Result<T, E> where E: Into<Report> {
+    fn categorize(self, category: fn(Report) -> Error) -> Result<T> {
+        self.map_err(|error| category(error.into()))
+    }
+}
+
+/// Adds context to a categorized error, such as the phase in which it happened
+pub trait Context<T> {
+    fn context_with<D, F>(self, context: F) -> Result<T>
+        where D: fmt::Display + Send + Sync + 'static, F: FnOnce() -> D;
+}
+
+impl<T> Context<T> for Result<T> {
+    fn context_with<D, F>(self, context: F) -> Result<T>
+        where D: fmt::Display + Send + Sync + 'static, F: FnOnce() -> D {
+        self.map_err(|error| error.wrap_err(context()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::{eyre, WrapErr};
+    use super::*;
+
+    #[test]
+    fn render_causes() {
+        let io_error = std::io::Error::new(std::io::ErrorKind::NotFound, "No such file or directory");
+        let error = Err::<(), _>(io_error)
+            .wrap_err("Unable to open CSV file \"data.csv\"")
+            .categorize(Error::ImportIo)
+            .context_with(|| "Unable to import data.csv into table data")
+            .unwrap_err();
+        assert_eq!(6, error.exit_code());
+        assert_eq!("\
+Unable to import data.csv into table data
+  caused by: Unable to open CSV file \"data.csv\"
+  caused by: No such file or directory", error.render());
+    }
+
+    #[test]
+    fn render_without_repeated_causes() {
+        let error = Error::QuerySyntax(eyre!("syntax error at or near \"SELEC\"")
+            .wrap_err("error returned from database: syntax error at or near \"SELEC\""));
+        assert_eq!("error returned from database: syntax error at or near \"SELEC\"", error.render());
+    }
+}
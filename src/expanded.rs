@@ -0,0 +1,166 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use async_std::io::{Write, WriteExt};
+use eyre::Result;
+use crate::aligned::{char_width, printable, width};
+
+/// Marks a NULL value, which would otherwise look like an empty string
+const NULL_MARK: &str = "(null)";
+/// The narrowest values are wrapped at, however narrow the terminal
+const MIN_VALUE_WIDTH: usize = 10;
+
+/// Writes query results with each row as a block of lines, one for each column, for reading
+/// rows too wide to be shown in columns
+pub(crate) struct ExpandedWriter<W> {
+    writer: W,
+    /// The width which values are wrapped to fit, in terminal columns
+    width: usize,
+    header: Vec<String>,
+    records: u64
+}
+
+impl<W> ExpandedWriter<W> where W: Write + Unpin {
+
+    pub(crate) fn new(writer: W, width: usize) -> Self {
+        Self { writer, width, header: Vec::new(), records: 0 }
+    }
+
+    pub(crate) fn write_header(&mut self, columns: Vec<String>) {
+        self.header = columns;
+    }
+
+    /// Writes a row, in which None stands for NULL
+    pub(crate) async fn write_row(&mut self, row: Vec<Option<String>>) -> Result<()> {
+        self.records += 1;
+        let record = record(self.records, &self.header, &row, self.width);
+        self.writer.write_all(record.as_bytes()).await?;
+        Ok(())
+    }
+
+    pub(crate) async fn flush(&mut self) -> Result<()> {
+        self.writer.flush().await?;
+        Ok(())
+    }
+
+    pub(crate) async fn finish(mut self) -> Result<W> {
+        self.writer.flush().await?;
+        Ok(self.writer)
+    }
+}
+
+/// Lays out the numbered row as a block of lines, each naming a column followed by its value.
+/// Values are lined up after the longest name, and long values are wrapped to fit the width,
+/// continuing beneath the start of the value.
+fn record(number: u64, header: &[String], row: &[Option<String>], width: usize) -> String {
+    let names = header.iter().map(|column| printable(column)).collect::<Vec<_>>();
+    let indent = names.iter().map(|name| self::width(name)).max().unwrap_or(0) + 2;
+    let value_width = width.saturating_sub(indent).max(MIN_VALUE_WIDTH);
+    let mut record = format!("-[ RECORD {} ]-\n", number);
+    for (name, value) in names.iter().zip(row) {
+        let value = value.as_deref().unwrap_or(NULL_MARK).replace("\r\n", "\n");
+        let lines = value.split('\n')
+            .flat_map(|line| wrap(&printable(line), value_width))
+            .collect::<Vec<_>>();
+        for (index, line) in lines.iter().enumerate() {
+            if index == 0 {
+                record.push_str(name);
+                record.push(':');
+                if !line.is_empty() {
+                    record.push_str(&" ".repeat(indent - self::width(name) - 1));
+                }
+            } else {
+                record.push_str(&" ".repeat(indent));
+            }
+            record.push_str(line);
+            record.push('\n');
+        }
+    }
+    record
+}
+
+/// Breaks the line into lines no wider than the width, after the last space which fits if
+/// there is one, and otherwise within a word
+fn wrap(line: &str, max_width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    let mut current_width = 0;
+    for c in line.chars() {
+        let char_width = char_width(c);
+        if current_width + char_width > max_width && !current.is_empty() {
+            // The space a line is broken at is left out
+            if c == ' ' {
+                lines.push(std::mem::take(&mut current));
+                current_width = 0;
+                continue;
+            }
+            let rest = match current.rfind(' ') {
+                Some(space) if space > 0 => {
+                    let rest = current[space + 1..].to_owned();
+                    current.truncate(space);
+                    rest
+                },
+                _ => String::new()
+            };
+            lines.push(std::mem::replace(&mut current, rest));
+            current_width = width(&current);
+        }
+        current.push(c);
+        current_width += char_width;
+    }
+    lines.push(current);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn write_records() {
+        let mut output = Vec::new();
+        let mut writer = ExpandedWriter::new(&mut output, 25);
+        writer.write_header(vec![String::from("id"), String::from("name"), String::from("note")]);
+        writer.write_row(vec![Some(String::from("1")), Some(String::from("Ann")), None]).await.unwrap();
+        writer.write_row(vec![
+            Some(String::from("2")), Some(String::new()),
+            Some(String::from("Ordered twice, then\nreturned the second parcel unopened"))
+        ]).await.unwrap();
+        writer.finish().await.unwrap();
+        assert_eq!("\
+-[ RECORD 1 ]-
+id:   1
+name: Ann
+note: (null)
+-[ RECORD 2 ]-
+id:   2
+name:
+note: Ordered twice, then
+      returned the second
+      parcel unopened
+", String::from_utf8(output).unwrap());
+    }
+
+    #[test]
+    fn wrap_lines() {
+        assert_eq!(vec!["one two", "three"], wrap("one two three", 9));
+        assert_eq!(vec!["abcde", "fghij", "k"], wrap("abcdefghijk", 5));
+        assert_eq!(vec!["ab", "cd"], wrap("ab cd", 2));
+        assert_eq!(vec!["漢字", "漢"], wrap("漢字漢", 5));
+        assert_eq!(vec![""], wrap("", 5));
+    }
+}
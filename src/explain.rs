@@ -0,0 +1,215 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use eyre::{eyre, Result};
+use serde_json::Value;
+use crate::locale::group_digits;
+
+/// A step of a plan from EXPLAIN (ANALYZE, FORMAT JSON), with what it took to run
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PlanNode {
+    /// Such as Seq Scan on orders, or Hash Join (Inner)
+    pub(crate) label: String,
+    /// In milliseconds, over every loop and including the steps beneath. Steps run by parallel
+    /// workers add up the time of each worker
+    pub(crate) total_ms: f64,
+    /// Per loop, as Postgres counts them
+    pub(crate) rows: f64,
+    pub(crate) loops: u64,
+    pub(crate) children: Vec<PlanNode>
+}
+
+impl PlanNode {
+
+    fn parse(node: &Value) -> Result<Self> {
+        let node_type = node["Node Type"].as_str().ok_or_else(|| eyre!("A step of the plan has no Node Type"))?;
+        let mut label = String::from(node_type);
+        if let Some(index) = node["Index Name"].as_str() {
+            label.push_str(" using ");
+            label.push_str(index);
+        }
+        let scanned = ["Relation Name", "Function Name", "CTE Name"].iter().find_map(|key| node[key].as_str());
+        if let Some(relation) = scanned {
+            label.push_str(" on ");
+            label.push_str(relation);
+            match node["Alias"].as_str() {
+                Some(alias) if alias != relation => {
+                    label.push(' ');
+                    label.push_str(alias);
+                },
+                _ => {}
+            }
+        }
+        if let Some(join_type) = node["Join Type"].as_str() {
+            label.push_str(&format!(" ({})", join_type));
+        }
+        let loops = node["Actual Loops"].as_u64().unwrap_or(0);
+        let children = match node["Plans"].as_array() {
+            Some(plans) => plans.iter().map(Self::parse).collect::<Result<_>>()?,
+            None => Vec::new()
+        };
+        Ok(Self {
+            label,
+            total_ms: node["Actual Total Time"].as_f64().unwrap_or(0.0) * loops as f64,
+            rows: node["Actual Rows"].as_f64().unwrap_or(0.0),
+            loops,
+            children
+        })
+    }
+
+    /// The time taken by this step itself, leaving out the steps beneath
+    pub(crate) fn own_ms(&self) -> f64 {
+        let children_ms: f64 = self.children.iter().map(|child| child.total_ms).sum();
+        (self.total_ms - children_ms).max(0.0)
+    }
+
+    /// Each step beneath, and this one, in the order shown, with how deep they are
+    fn flatten<'n>(&'n self, depth: usize, nodes: &mut Vec<(usize, &'n PlanNode)>) {
+        nodes.push((depth, self));
+        for child in &self.children {
+            child.flatten(depth + 1, nodes);
+        }
+    }
+}
+
+/// The plan of a query which was run with EXPLAIN ANALYZE
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct AnalyzedPlan {
+    pub(crate) root: PlanNode,
+    pub(crate) planning_ms: Option<f64>,
+    pub(crate) execution_ms: Option<f64>
+}
+
+impl AnalyzedPlan {
+
+    /// Parses what EXPLAIN (ANALYZE, FORMAT JSON) returns, an array holding the plan
+    pub(crate) fn parse(explained: &Value) -> Result<Self> {
+        let explained = explained.get(0).unwrap_or(explained);
+        let plan = explained.get("Plan").ok_or_else(|| eyre!("The output of EXPLAIN has no plan"))?;
+        Ok(Self {
+            root: PlanNode::parse(plan)?,
+            planning_ms: explained["Planning Time"].as_f64(),
+            execution_ms: explained["Execution Time"].as_f64()
+        })
+    }
+
+    /// The step which took the most time of its own, first in the order shown if tied
+    pub(crate) fn slowest(&self) -> &PlanNode {
+        let mut nodes = Vec::new();
+        self.root.flatten(0, &mut nodes);
+        nodes.into_iter().map(|(_, node)| node)
+            .fold(&self.root, |slowest, node| if node.own_ms() > slowest.own_ms() { node } else { slowest })
+    }
+
+    /// Shows the plan as an indented tree of its steps, one per line, giving the slowest's line
+    /// to the highlight. E.g.
+    ///
+    /// Planning 0.166 ms, execution 9.808 ms
+    /// Sort  9.767 ms, 100 rows
+    ///   -> Seq Scan on orders  1.439 ms, 20,000 rows
+    pub(crate) fn render(&self, highlight: impl Fn(&str) -> String) -> String {
+        let mut nodes = Vec::new();
+        self.root.flatten(0, &mut nodes);
+        let slowest = self.slowest();
+        let whole_ms = self.execution_ms.unwrap_or(self.root.total_ms);
+        let mut lines = Vec::with_capacity(nodes.len() + 1);
+        match (self.planning_ms, self.execution_ms) {
+            (Some(planning), Some(execution)) => {
+                lines.push(format!("Planning {:.3} ms, execution {:.3} ms", planning, execution));
+            },
+            (None, Some(execution)) => lines.push(format!("Execution {:.3} ms", execution)),
+            _ => {}
+        }
+        for (depth, node) in nodes {
+            let mut line = match depth {
+                0 => String::new(),
+                depth => format!("{}-> ", " ".repeat(3 * depth - 1))
+            };
+            line.push_str(&format!("{}  {:.3} ms, {} rows", node.label, node.total_ms, format_rows(node.rows)));
+            if node.loops != 1 {
+                line.push_str(&format!(" × {} loops", node.loops));
+            }
+            if std::ptr::eq(node, slowest) {
+                let share = match whole_ms > 0.0 {
+                    true => format!(" ({:.0}%)", node.own_ms() / whole_ms * 100.0),
+                    false => String::new()
+                };
+                line.push_str(&format!("  <- most time, {:.3} ms of its own{}", node.own_ms(), share));
+                line = highlight(&line);
+            }
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+}
+
+/// Rows are whole per loop, except from Postgres 18 on, which averages them to two places
+fn format_rows(rows: f64) -> String {
+    match rows.fract() == 0.0 {
+        true => group_digits(&format!("{}", rows as u64), ','),
+        false => group_digits(&format!("{:.2}", rows), ',')
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> AnalyzedPlan {
+        let path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name);
+        let explained = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        AnalyzedPlan::parse(&explained).unwrap()
+    }
+
+    #[test]
+    fn parse_plan() {
+        let plan = fixture("explain_hash_join.json");
+        assert_eq!(Some(0.166), plan.planning_ms);
+        assert_eq!(Some(9.808), plan.execution_ms);
+        assert_eq!("Sort", plan.root.label);
+        let join = &plan.root.children[0].children[0];
+        assert_eq!("Hash Join (Inner)", join.label);
+        let labels = join.children.iter().map(|node| &node.label).collect::<Vec<_>>();
+        assert_eq!(vec!["Seq Scan on orders", "Hash"], labels);
+        assert_eq!(20_000.0, join.rows);
+        assert!(AnalyzedPlan::parse(&serde_json::json!([{"Planning Time": 1.0}])).is_err());
+    }
+
+    #[test]
+    fn time_taken_over_loops() {
+        let plan = fixture("explain_nested_loop.json");
+        let memoize = &plan.root.children[1];
+        assert_eq!(49, memoize.loops);
+        assert!((memoize.total_ms - 0.049).abs() < 1e-9);
+        assert_eq!("Index Scan using regions_region_idx on regions", memoize.children[0].label);
+        assert_eq!("Seq Scan on orders", plan.slowest().label);
+    }
+
+    #[test]
+    fn render_plan() {
+        let plan = fixture("explain_hash_join.json");
+        assert_eq!("Aggregate", plan.slowest().label);
+        assert_eq!("\
+Planning 0.166 ms, execution 9.808 ms
+Sort  9.767 ms, 100 rows
+  -> Aggregate  9.739 ms, 100 rows  <- most time, 4.659 ms of its own (48%)!
+     -> Hash Join (Inner)  5.080 ms, 20,000 rows
+        -> Seq Scan on orders  1.439 ms, 20,000 rows
+        -> Hash  0.023 ms, 100 rows
+           -> Seq Scan on regions  0.010 ms, 100 rows", plan.render(|line| format!("{}!", line)));
+    }
+}
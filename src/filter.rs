@@ -0,0 +1,390 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::iter::Peekable;
+use std::str::CharIndices;
+use csv_async::StringRecord;
+use eyre::{eyre, Result};
+use itertools::Itertools;
+use crate::database::{is_plain_identifier, Schema};
+
+/// A filter on the rows of a dataset, applied while importing it, such as
+/// `country = "DE" and amount > 100`. Comparisons are on named columns, and may be combined
+/// with `and`, `or` and parentheses, with `and` binding tighter.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RowFilter {
+    expression: String,
+    condition: Condition<String>
+}
+
+/// A filter whose columns have been found in the dataset's header
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct BoundFilter(Condition<usize>);
+
+/// A condition on a row, referring to its columns by name or index
+#[derive(Clone, Debug, PartialEq)]
+enum Condition<C> {
+    And(Box<Condition<C>>, Box<Condition<C>>),
+    Or(Box<Condition<C>>, Box<Condition<C>>),
+    Compare { column: C, operator: Operator, value: Value }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Contains,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual
+}
+
+/// The value a column is compared with
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    /// A quoted string, compared as text
+    Text(String),
+    /// A number, compared as a number. Fields which are not numbers never match
+    Number(f64)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Number(f64),
+    Operator(Operator),
+    Open,
+    Close
+}
+
+impl std::str::FromStr for RowFilter {
+    type Err = String;
+
+    fn from_str(expression: &str) -> std::result::Result<Self, Self::Err> {
+        let tokens = tokenize(expression).map_err(|error| format!("Invalid filter {}: {}", expression, error))?;
+        let mut tokens = tokens.into_iter().peekable();
+        let condition = parse_or(&mut tokens).map_err(|error| format!("Invalid filter {}: {}", expression, error))?;
+        if let Some(token) = tokens.next() {
+            return Err(format!("Invalid filter {}: unexpected {}", expression, describe(&token)));
+        }
+        Ok(Self { expression: expression.trim().to_owned(), condition })
+    }
+}
+
+impl fmt::Display for RowFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+impl RowFilter {
+
+    /// Finds the filter's columns in the dataset's header, ignoring case as Postgres does.
+    /// Every column which is not there is named in the error
+    pub(crate) fn bind(&self, schema: &Schema) -> Result<BoundFilter> {
+        let find = |name: &String| {
+            (0..schema.len()).find(|index| schema.column_name(*index).eq_ignore_ascii_case(name))
+        };
+        let mut missing = Vec::new();
+        self.condition.columns(&mut |name| if find(name).is_none() && !missing.contains(name) {
+            missing.push(name.clone());
+        });
+        if !missing.is_empty() {
+            return Err(eyre!(
+                "The filter {} names columns which are not in the CSV file: {}. Its columns are: {}",
+                self.expression, missing.join(", "), (0..schema.len()).map(|index| schema.column_name(index)).join(", ")
+            ));
+        }
+        Ok(BoundFilter(self.condition.map_columns(&|name| find(name).expect("Column was found"))))
+    }
+}
+
+impl BoundFilter {
+    pub(crate) fn matches(&self, record: &StringRecord) -> bool {
+        self.0.matches(record)
+    }
+}
+
+impl<C> Condition<C> {
+    fn columns(&self, visit: &mut impl FnMut(&C)) {
+        match self {
+            Self::And(left, right) | Self::Or(left, right) => {
+                left.columns(visit);
+                right.columns(visit);
+            },
+            Self::Compare { column, .. } => visit(column)
+        }
+    }
+
+    fn map_columns<D>(&self, map: &impl Fn(&C) -> D) -> Condition<D> {
+        match self {
+            Self::And(left, right) => Condition::And(Box::new(left.map_columns(map)), Box::new(right.map_columns(map))),
+            Self::Or(left, right) => Condition::Or(Box::new(left.map_columns(map)), Box::new(right.map_columns(map))),
+            Self::Compare { column, operator, value } => Condition::Compare {
+                column: map(column), operator: *operator, value: value.clone()
+            }
+        }
+    }
+}
+
+impl Condition<usize> {
+    fn matches(&self, record: &StringRecord) -> bool {
+        match self {
+            Self::And(left, right) => left.matches(record) && right.matches(record),
+            Self::Or(left, right) => left.matches(record) || right.matches(record),
+            Self::Compare { column, operator, value } => {
+                let field = record.get(*column).unwrap_or_default();
+                match value {
+                    Value::Text(text) => match operator {
+                        Operator::Equal => field == text,
+                        Operator::NotEqual => field != text,
+                        Operator::Contains => field.contains(text.as_str()),
+                        // Text cannot be ordered, which is rejected when the filter is parsed
+                        _ => false
+                    },
+                    Value::Number(number) => {
+                        let field = match field.trim().parse::<f64>() {
+                            Ok(field) if !field.is_nan() => field,
+                            // Not being a number, the field neither equals nor differs from one
+                            _ => return false
+                        };
+                        match operator {
+                            Operator::Equal => field == *number,
+                            Operator::NotEqual => field != *number,
+                            Operator::Less => field < *number,
+                            Operator::LessOrEqual => field <= *number,
+                            Operator::Greater => field > *number,
+                            Operator::GreaterOrEqual => field >= *number,
+                            Operator::Contains => false
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '"' | '\'' => Token::Text(quoted(c, &mut chars)?),
+            '=' => {
+                chars.next_if(|(_, next)| *next == '=');
+                Token::Operator(Operator::Equal)
+            },
+            '!' if chars.next_if(|(_, next)| *next == '=').is_some() => Token::Operator(Operator::NotEqual),
+            '<' if chars.next_if(|(_, next)| *next == '>').is_some() => Token::Operator(Operator::NotEqual),
+            '<' if chars.next_if(|(_, next)| *next == '=').is_some() => Token::Operator(Operator::LessOrEqual),
+            '<' => Token::Operator(Operator::Less),
+            '>' if chars.next_if(|(_, next)| *next == '=').is_some() => Token::Operator(Operator::GreaterOrEqual),
+            '>' => Token::Operator(Operator::Greater),
+            c if c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.' => {
+                let mut end = start + c.len_utf8();
+                let continues = |(_, next): &(usize, char)| next.is_ascii_alphanumeric() || "_.+-".contains(*next);
+                while let Some((index, next)) = chars.next_if(continues) {
+                    end = index + next.len_utf8();
+                }
+                let word = &expression[start..end];
+                if c.is_ascii_alphabetic() || c == '_' {
+                    if word.eq_ignore_ascii_case("contains") {
+                        Token::Operator(Operator::Contains)
+                    } else {
+                        Token::Word(word.to_owned())
+                    }
+                } else {
+                    Token::Number(word.parse().map_err(|_| format!("{} is not a number", word))?)
+                }
+            },
+            c => return Err(format!("unexpected character {}", c))
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Reads a string up to its closing quote. The quote is written twice to include it
+fn quoted(quote: char, chars: &mut Peekable<CharIndices<'_>>) -> Result<String, String> {
+    let mut text = String::new();
+    loop {
+        match chars.next() {
+            Some((_, c)) if c == quote => match chars.next_if(|(_, next)| *next == quote) {
+                Some(_) => text.push(quote),
+                None => return Ok(text)
+            },
+            Some((_, c)) => text.push(c),
+            None => return Err(format!("missing closing {}", quote))
+        }
+    }
+}
+
+type Tokens = Peekable<std::vec::IntoIter<Token>>;
+
+fn parse_or(tokens: &mut Tokens) -> Result<Condition<String>, String> {
+    let mut condition = parse_and(tokens)?;
+    while tokens.next_if(|token| is_keyword(token, "or")).is_some() {
+        condition = Condition::Or(Box::new(condition), Box::new(parse_and(tokens)?));
+    }
+    Ok(condition)
+}
+
+fn parse_and(tokens: &mut Tokens) -> Result<Condition<String>, String> {
+    let mut condition = parse_comparison(tokens)?;
+    while tokens.next_if(|token| is_keyword(token, "and")).is_some() {
+        condition = Condition::And(Box::new(condition), Box::new(parse_comparison(tokens)?));
+    }
+    Ok(condition)
+}
+
+fn parse_comparison(tokens: &mut Tokens) -> Result<Condition<String>, String> {
+    let column = match tokens.next() {
+        Some(Token::Open) => {
+            let condition = parse_or(tokens)?;
+            return match tokens.next() {
+                Some(Token::Close) => Ok(condition),
+                Some(token) => Err(format!("expected ) but found {}", describe(&token))),
+                None => Err(String::from("missing )"))
+            };
+        },
+        Some(Token::Word(column)) if is_plain_identifier(&column) => column,
+        Some(token) => return Err(format!("expected a column name but found {}", describe(&token))),
+        None => return Err(String::from("expected a column name"))
+    };
+    let operator = match tokens.next() {
+        Some(Token::Operator(operator)) => operator,
+        Some(token) => return Err(format!("expected an operator after {} but found {}", column, describe(&token))),
+        None => return Err(format!("expected an operator after {}", column))
+    };
+    let value = match tokens.next() {
+        Some(Token::Text(text)) => Value::Text(text),
+        Some(Token::Number(number)) => Value::Number(number),
+        Some(token) => return Err(format!(
+            "expected a quoted string or a number after {} but found {}", column, describe(&token)
+        )),
+        None => return Err(format!("expected a quoted string or a number after {}", column))
+    };
+    let valid = match (&value, operator) {
+        (Value::Text(_), operator) => matches!(operator, Operator::Equal | Operator::NotEqual | Operator::Contains),
+        (Value::Number(_), operator) => operator != Operator::Contains
+    };
+    if !valid {
+        return Err(match value {
+            Value::Text(_) => format!("{} can only be compared with a number, not a string", column),
+            Value::Number(_) => format!("contains needs a quoted string after {}", column)
+        });
+    }
+    Ok(Condition::Compare { column, operator, value })
+}
+
+fn is_keyword(token: &Token, keyword: &str) -> bool {
+    matches!(token, Token::Word(word) if word.eq_ignore_ascii_case(keyword))
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Word(word) => word.clone(),
+        Token::Text(text) => format!("{:?}", text),
+        Token::Number(number) => number.to_string(),
+        Token::Operator(operator) => String::from(match operator {
+            Operator::Equal => "=",
+            Operator::NotEqual => "!=",
+            Operator::Contains => "contains",
+            Operator::Less => "<",
+            Operator::LessOrEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterOrEqual => ">="
+        }),
+        Token::Open => String::from("("),
+        Token::Close => String::from(")")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(filter: &str, header: &[&str], record: &[&str]) -> bool {
+        let filter: RowFilter = filter.parse().unwrap();
+        let schema = header.iter().copied().collect::<Schema>();
+        filter.bind(&schema).unwrap().matches(&StringRecord::from(record.to_vec()))
+    }
+
+    #[test]
+    fn compare_fields() {
+        let header = ["country", "amount", "name"];
+        let row = ["DE", "150.5", "Zoë's shop"];
+        assert!(matches("country = \"DE\"", &header, &row));
+        assert!(matches("country == 'DE'", &header, &row));
+        assert!(!matches("country != 'DE'", &header, &row));
+        assert!(matches("Country <> 'de'", &header, &row));
+        assert!(matches("name contains 'ë''s'", &header, &row));
+        assert!(matches("amount > 100 and amount <= 150.5", &header, &row));
+        assert!(matches("amount = 1.505e2", &header, &row));
+        assert!(!matches("amount < -1", &header, &row));
+        // Text is never a number, so it neither equals nor differs from one
+        assert!(!matches("country = 1", &header, &row));
+        assert!(!matches("country != 1", &header, &row));
+    }
+
+    #[test]
+    fn combine_conditions() {
+        let header = ["country", "amount"];
+        // and binds tighter than or
+        assert!(matches("country = 'FR' and amount > 1000 or amount > 100", &header, &["DE", "150"]));
+        assert!(!matches("country = 'FR' and (amount > 1000 or amount > 100)", &header, &["DE", "150"]));
+        assert!(matches("country = 'FR' OR country = 'DE' AND amount >= 150", &header, &["DE", "150"]));
+    }
+
+    #[test]
+    fn reject_invalid_filters() {
+        for (filter, error) in [
+            ("country", "expected an operator after country"),
+            ("country = DE", "expected a quoted string or a number after country but found DE"),
+            ("country = 'DE", "missing closing '"),
+            ("amount > 'ten'", "amount can only be compared with a number, not a string"),
+            ("name contains 5", "contains needs a quoted string after name"),
+            ("(amount > 1", "missing )"),
+            ("amount > 1 amount", "unexpected amount"),
+            ("amount ~ 1", "unexpected character ~"),
+            ("amount > 1.2.3", "1.2.3 is not a number"),
+            ("", "expected a column name")
+        ] {
+            let parsed = filter.parse::<RowFilter>();
+            assert_eq!(Err(format!("Invalid filter {}: {}", filter, error)), parsed);
+        }
+    }
+
+    #[test]
+    fn name_missing_columns() {
+        let filter: RowFilter = "country = 'DE' and Amount > 1 or region = 'north' or region = 'south'"
+            .parse().unwrap();
+        let schema = ["country", "AMOUNT"].into_iter().collect::<Schema>();
+        let error = filter.bind(&schema).unwrap_err();
+        assert_eq!(
+            "The filter country = 'DE' and Amount > 1 or region = 'north' or region = 'south' names columns which \
+            are not in the CSV file: region. Its columns are: country, AMOUNT",
+            error.to_string()
+        );
+    }
+}
@@ -0,0 +1,405 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashSet;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use async_std::fs;
+use async_std::io::{BufRead, Read};
+use async_std::path::Path;
+use eyre::{eyre, Result, WrapErr};
+use futures_lite::ready;
+use serde::Deserialize;
+
+/// The columns of a fixed-width file, each at the same place on every line, as given with
+/// --fixed-width. The file has no header, so the columns are named here
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Layout {
+    pub(crate) columns: Vec<LayoutColumn>,
+    /// What the starts and widths of the columns count. Layouts usually count bytes
+    #[serde(default)]
+    pub(crate) units: Units
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LayoutColumn {
+    pub(crate) name: String,
+    /// Where the column starts on the line, counting from 1
+    pub(crate) start: usize,
+    pub(crate) width: usize,
+    /// The type of the table's column, which is text unless given. Blank values are NULL
+    #[serde(default, rename = "type")]
+    pub(crate) column_type: Option<String>,
+    /// Whether the value is also kept as it was in the file, in a text column named after this
+    /// one with __raw, for audits of how the type read it
+    #[serde(default)]
+    pub(crate) keep_raw: bool
+}
+
+impl LayoutColumn {
+
+    /// The column holding the value as it was in the file, if it is kept
+    fn raw_name(&self) -> Option<String> {
+        self.keep_raw.then(|| format!("{}__raw", self.name))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+pub(crate) enum Units {
+    #[default]
+    Bytes,
+    /// Characters of UTF-8
+    Chars
+}
+
+impl fmt::Display for Units {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Bytes => "bytes",
+            Self::Chars => "characters"
+        })
+    }
+}
+
+impl Layout {
+
+    pub(crate) async fn load(path: &Path) -> Result<Self> {
+        let layout = fs::read_to_string(path).await
+            .wrap_err_with(|| format!("Unable to read fixed-width layout {}", path.display()))?;
+        let layout: Self = ron::from_str(&layout)
+            .wrap_err_with(|| format!("Invalid fixed-width layout {}", path.display()))?;
+        layout.check().wrap_err_with(|| format!("Invalid fixed-width layout {}", path.display()))?;
+        Ok(layout)
+    }
+
+    /// A column entered as 'name start width [type] [keep_raw]'
+    pub(crate) fn parse_column(definition: &str) -> Result<LayoutColumn> {
+        let mut words = definition.split_whitespace().collect::<Vec<_>>();
+        let (name, start, width) = match words[..] {
+            [name, start, width, ..] => (name, start, width),
+            _ => return Err(eyre!(
+                "Enter the column as 'name start width [type] [keep_raw]', such as 'opened 9 10 date keep_raw'"
+            ))
+        };
+        let number = |value: &str, what: &str| value.parse::<usize>()
+            .map_err(|_| eyre!("Invalid {} {} of column {}. Use a whole number", what, value, name));
+        let keep_raw = words.len() > 3 && words.last() == Some(&"keep_raw");
+        if keep_raw {
+            words.pop();
+        }
+        let column_type = words[3..].join(" ");
+        Ok(LayoutColumn {
+            name: String::from(name),
+            start: number(start, "start")?,
+            width: number(width, "width")?,
+            column_type: (!column_type.is_empty()).then_some(column_type),
+            keep_raw
+        })
+    }
+
+    /// Columns must be listed in the order they start in, so that the columns missing from a
+    /// short line are the last ones
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.columns.is_empty() {
+            return Err(eyre!("It lists no columns"));
+        }
+        let mut names = HashSet::new();
+        let mut previous_start = 0;
+        for column in &self.columns {
+            if column.name.is_empty() {
+                return Err(eyre!("A column has no name"));
+            }
+            for name in std::iter::once(column.name.clone()).chain(column.raw_name()) {
+                if !names.insert(name.to_lowercase()) {
+                    return Err(eyre!("More than one column is named {}", name));
+                }
+            }
+            if column.keep_raw && column.column_type.is_none() {
+                return Err(eyre!(
+                    "Column {} keeps its raw values, but has no type, so its values are kept as they are anyway",
+                    column.name
+                ));
+            }
+            if column.start == 0 || column.width == 0 {
+                return Err(eyre!(
+                    "Column {} starts at {} and is {} wide. Starts count from 1, and widths must be at least 1",
+                    column.name, column.start, column.width
+                ));
+            }
+            if column.start < previous_start {
+                return Err(eyre!("Column {} starts before the column listed before it", column.name));
+            }
+            previous_start = column.start;
+            let valid_type = column.column_type.as_deref().is_none_or(|column_type| {
+                !column_type.trim().is_empty() && column_type.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | ' ' | '(' | ')' | ',' | '.' | '[' | ']'))
+            });
+            if !valid_type {
+                return Err(eyre!(
+                    "Invalid type {:?} of column {}", column.column_type.as_deref().unwrap_or_default(), column.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The names of the columns, each followed by its __raw column if it keeps one
+    pub(crate) fn column_names(&self) -> Vec<String> {
+        self.columns.iter()
+            .flat_map(|column| std::iter::once(column.name.clone()).chain(column.raw_name()))
+            .collect()
+    }
+
+    /// The types of the columns, where given. The __raw columns are text
+    pub(crate) fn column_types(&self) -> Vec<Option<String>> {
+        self.columns.iter()
+            .flat_map(|column| std::iter::once(column.column_type.clone()).chain(column.keep_raw.then_some(None)))
+            .collect()
+    }
+
+    /// The fields of the line, without its line break and with the padding after each value
+    /// trimmed. A line which ends before a column starts lacks that column and those after it.
+    /// A column keeping its raw value has the same field twice, the second for its __raw column
+    pub(crate) fn fields<'l>(&self, line: &'l [u8]) -> Vec<&'l [u8]> {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        // Where each character starts, for layouts counting them. Continuation bytes are the
+        // only ones of the form 10xxxxxx, so invalid UTF-8 is still sliced somewhere
+        let char_starts = match self.units {
+            Units::Bytes => None,
+            Units::Chars => Some(line.iter().enumerate()
+                .filter(|(_, byte)| **byte & 0xC0 != 0x80)
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>())
+        };
+        let length = char_starts.as_ref().map_or(line.len(), Vec::len);
+        let byte_at = |offset: usize| match &char_starts {
+            None => offset.min(line.len()),
+            Some(starts) => starts.get(offset).copied().unwrap_or(line.len())
+        };
+        self.columns.iter()
+            .take_while(|column| column.start - 1 < length)
+            .flat_map(|column| {
+                let field = &line[byte_at(column.start - 1)..byte_at(column.start - 1 + column.width)];
+                let padding = field.iter().rev().take_while(|byte| **byte == b' ').count();
+                let field = &field[..field.len() - padding];
+                std::iter::once(field).chain(column.keep_raw.then_some(field))
+            })
+            .collect()
+    }
+
+    /// The line as a CSV record, quoted as RFC 4180 says
+    pub(crate) fn write_record<'f>(fields: impl IntoIterator<Item=&'f [u8]>, record: &mut Vec<u8>) {
+        for (index, field) in fields.into_iter().enumerate() {
+            if index != 0 {
+                record.push(b',');
+            }
+            if field.iter().any(|byte| matches!(byte, b'"' | b',' | b'\r' | b'\n')) {
+                record.push(b'"');
+                for byte in field {
+                    if *byte == b'"' {
+                        record.push(b'"');
+                    }
+                    record.push(*byte);
+                }
+                record.push(b'"');
+            } else {
+                record.extend_from_slice(field);
+            }
+        }
+        record.push(b'\n');
+    }
+}
+
+/// Reads a fixed-width file as CSV, a record for each line, so that it is imported as a CSV file
+/// would be. There is no header, since the layout names the columns
+pub(crate) struct FixedWidthReader<R> {
+    lines: R,
+    layout: Arc<Layout>,
+    /// The CSV text waiting to be read, and how much of it has been
+    converted: Vec<u8>,
+    read: usize,
+    /// The line being read, which may arrive in parts
+    line: Vec<u8>,
+    finished: bool,
+    /// How many bytes of the file have been read
+    consumed: Arc<AtomicU64>
+}
+
+impl<R> FixedWidthReader<R> where R: BufRead + Unpin {
+
+    pub(crate) fn new(lines: R, layout: Arc<Layout>, consumed: Arc<AtomicU64>) -> Self {
+        Self { lines, layout, converted: Vec::new(), read: 0, line: Vec::new(), finished: false, consumed }
+    }
+}
+
+impl<R> Read for FixedWidthReader<R> where R: BufRead + Unpin {
+    fn poll_read(self: Pin<&mut Self>, context: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        let reader = self.get_mut();
+        loop {
+            if reader.read < reader.converted.len() {
+                let count = buf.len().min(reader.converted.len() - reader.read);
+                buf[..count].copy_from_slice(&reader.converted[reader.read..reader.read + count]);
+                reader.read += count;
+                return Poll::Ready(Ok(count));
+            }
+            if reader.finished {
+                return Poll::Ready(Ok(0));
+            }
+            reader.converted.clear();
+            reader.read = 0;
+            loop {
+                let available = ready!(Pin::new(&mut reader.lines).poll_fill_buf(context))?;
+                if available.is_empty() {
+                    reader.finished = true;
+                    break;
+                }
+                let (taken, ended) = match available.iter().position(|byte| *byte == b'\n') {
+                    Some(line_break) => (line_break + 1, true),
+                    None => (available.len(), false)
+                };
+                reader.line.extend_from_slice(&available[..taken]);
+                Pin::new(&mut reader.lines).consume(taken);
+                reader.consumed.fetch_add(taken as u64, Ordering::Relaxed);
+                if ended {
+                    break;
+                }
+            }
+            if !reader.line.is_empty() {
+                Layout::write_record(reader.layout.fields(&reader.line), &mut reader.converted);
+                reader.line.clear();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::ReadExt;
+    use super::*;
+
+    fn layout(units: Units) -> Layout {
+        let column = |name: &str, start, width| LayoutColumn {
+            name: String::from(name), start, width, column_type: None, keep_raw: false
+        };
+        Layout { columns: vec![column("id", 1, 4), column("name", 5, 6), column("city", 11, 5)], units }
+    }
+
+    fn fields(layout: &Layout, line: &str) -> Vec<String> {
+        layout.fields(line.as_bytes()).into_iter().map(|field| String::from_utf8_lossy(field).into_owned()).collect()
+    }
+
+    #[test]
+    fn slice_lines() {
+        let layout = layout(Units::Bytes);
+        assert_eq!(vec!["0001", "Ann", "Oslo"], fields(&layout, "0001Ann   Oslo \n"));
+        assert_eq!(vec!["0002", "Bo", "Rome"], fields(&layout, "0002Bo    Rome\r\n"));
+        // Values fill their columns, and anything after the last column is left out
+        assert_eq!(vec!["0003", "Ingrid", "Paris"], fields(&layout, "0003IngridParisXYZ"));
+        // A line ending within a column keeps what there is of it
+        assert_eq!(vec!["0004", "Cy", "Li"], fields(&layout, "0004Cy    Li"));
+        assert_eq!(vec!["0005", "Dee"], fields(&layout, "0005Dee"));
+        assert_eq!(Vec::<String>::new(), fields(&layout, "\n"));
+    }
+
+    #[test]
+    fn slice_multibyte_lines() {
+        // é is two bytes, so counting bytes starts the city a character early
+        let line = "0006José  Köln\n";
+        assert_eq!(vec!["0006", "José", "Köln"], fields(&layout(Units::Chars), line));
+        assert_eq!(vec!["0006", "José", " Köl"], fields(&layout(Units::Bytes), line));
+    }
+
+    #[test]
+    fn slice_raw_values_twice() {
+        let mut layout = layout(Units::Bytes);
+        layout.columns[0].column_type = Some(String::from("integer"));
+        layout.columns[0].keep_raw = true;
+        assert_eq!(vec!["id", "id__raw", "name", "city"], layout.column_names());
+        assert_eq!(vec![Some(String::from("integer")), None, None, None], layout.column_types());
+        assert_eq!(vec!["0001", "0001", "Ann", "Oslo"], fields(&layout, "0001Ann   Oslo\n"));
+        assert_eq!(vec!["0002", "0002"], fields(&layout, "0002"));
+    }
+
+    #[test]
+    fn parse_layouts() -> Result<()> {
+        let layout: Layout = ron::from_str(r#"(
+            columns: [
+                (name: "account", start: 1, width: 8),
+                (name: "opened", start: 9, width: 10, type: Some("date")),
+            ],
+            units: Chars,
+        )"#)?;
+        layout.check()?;
+        assert_eq!(Units::Chars, layout.units);
+        assert_eq!(vec![None, Some(String::from("date"))], layout.column_types());
+
+        let parsed = Layout::parse_column("balance 19 12 numeric(12, 2)")?;
+        assert_eq!(Some("numeric(12, 2)"), parsed.column_type.as_deref());
+        assert_eq!((19, 12), (parsed.start, parsed.width));
+        assert_eq!("Invalid width ten of column opened. Use a whole number",
+                   Layout::parse_column("opened 9 ten").unwrap_err().to_string());
+        assert!(Layout::parse_column("opened 9").is_err());
+        let raw = Layout::parse_column("opened 9 10 timestamp with time zone keep_raw")?;
+        assert_eq!((Some("timestamp with time zone"), true), (raw.column_type.as_deref(), raw.keep_raw));
+        assert!(Layout::parse_column("account 1 8 keep_raw").is_ok_and(|column| column.keep_raw));
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_invalid_layouts() {
+        let invalid = |columns: &[(&str, usize, usize, Option<&str>)]| Layout {
+            columns: columns.iter().map(|(name, start, width, column_type)| LayoutColumn {
+                name: String::from(*name), start: *start, width: *width, column_type: column_type.map(String::from),
+                keep_raw: column_type.is_some()
+            }).collect(),
+            units: Units::Bytes
+        }.check().unwrap_err().to_string();
+        assert_eq!("It lists no columns", invalid(&[]));
+        assert_eq!(
+            "More than one column is named id__raw",
+            invalid(&[("id", 1, 2, Some("integer")), ("id__raw", 3, 2, None)])
+        );
+        assert_eq!("More than one column is named ID", invalid(&[("id", 1, 2, None), ("ID", 3, 2, None)]));
+        assert!(invalid(&[("id", 0, 2, None)]).starts_with("Column id starts at 0"));
+        assert_eq!(
+            "Column name starts before the column listed before it",
+            invalid(&[("id", 5, 2, None), ("name", 1, 4, None)])
+        );
+        assert_eq!(
+            "Invalid type \"int; DROP TABLE data\" of column id",
+            invalid(&[("id", 1, 2, Some("int; DROP TABLE data"))])
+        );
+    }
+
+    #[async_std::test]
+    async fn read_as_csv() -> Result<()> {
+        let text = "0001Ann   Oslo \n0002\"Bo\", Rome\n\n0003Cy";
+        let consumed = Arc::new(AtomicU64::new(0));
+        let mut reader = FixedWidthReader::new(text.as_bytes(), Arc::new(layout(Units::Bytes)), consumed.clone());
+        let mut csv = String::new();
+        reader.read_to_string(&mut csv).await?;
+        assert_eq!("0001,Ann,Oslo\n0002,\"\"\"Bo\"\",\",Rome\n\n0003,Cy\n", csv);
+        assert_eq!(text.len() as u64, consumed.load(Ordering::Relaxed));
+        Ok(())
+    }
+}
@@ -0,0 +1,355 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use csv_async::StringRecord;
+use crate::database::InsertTarget;
+
+/// A type whose values are checked as records are parsed, so that a value its column cannot
+/// hold is found on its line, rather than failing a whole batch in the database. Only values
+/// which Postgres surely rejects are found, and other types are left to it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum CheckedType {
+    Integer,
+    Number
+}
+
+impl CheckedType {
+
+    /// The check for a column of the type, qualified by its schema, if it has one
+    /// The checks of the type, as the catalog names it, or as a fixed-width layout does
+    pub(crate) fn of(type_name: &str) -> Option<Self> {
+        let name = built_in(type_name).unwrap_or(type_name);
+        let name = name.split('(').next().unwrap_or_default().trim().to_ascii_lowercase();
+        match name.as_str() {
+            "int2" | "int4" | "int8" | "smallint" | "integer" | "int" | "bigint" => Some(Self::Integer),
+            "numeric" | "decimal" | "float4" | "float8" | "real" | "double precision" => Some(Self::Number),
+            _ => None
+        }
+    }
+
+    /// Whether Postgres may accept the value. Empty values are inserted as NULL
+    pub(crate) fn may_hold(self, value: &str) -> bool {
+        let value = value.trim();
+        if value.is_empty() {
+            return true;
+        }
+        let unsigned = value.strip_prefix(['+', '-']).unwrap_or(value);
+        let digits = |part: &str, radix: u32| {
+            !part.is_empty() && part.chars().all(|c| c.is_digit(radix) || c == '_')
+        };
+        let prefixed = [("0x", 16), ("0o", 8), ("0b", 2)].into_iter().any(|(prefix, radix)| {
+            unsigned.get(..2).is_some_and(|start| start.eq_ignore_ascii_case(prefix)) && digits(&unsigned[2..], radix)
+        });
+        match self {
+            Self::Integer => digits(unsigned, 10) || prefixed,
+            Self::Number => {
+                let special = ["nan", "inf", "infinity"].iter()
+                    .any(|special| unsigned.eq_ignore_ascii_case(special));
+                let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+                    Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+                    None => (unsigned, None)
+                };
+                let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+                let mantissa_valid = (digits(whole, 10) || whole.is_empty())
+                    && (digits(fraction, 10) || fraction.is_empty())
+                    && !(whole.is_empty() && fraction.is_empty());
+                let exponent_valid = exponent.is_none_or(|exponent| {
+                    digits(exponent.strip_prefix(['+', '-']).unwrap_or(exponent), 10)
+                });
+                special || prefixed || (mantissa_valid && exponent_valid)
+            }
+        }
+    }
+}
+
+/// A value which its column's type cannot hold
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Mismatch {
+    pub(crate) line: u64,
+    /// The column of the table
+    pub(crate) column: String,
+    pub(crate) value: String,
+    /// The type of the column, such as numeric
+    pub(crate) type_name: String
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "Line {} has {:?} in column {}, which is not a valid {}",
+            self.line, self.value, self.column, self.type_name
+        )
+    }
+}
+
+/// What is done with the values of a column which its type cannot hold
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum FixUp {
+    /// The rows holding them are skipped
+    SkipRow,
+    /// Commas are removed from them, as thousands separators are
+    StripCommas
+}
+
+/// How to go on after a value its column's type cannot hold
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Decision {
+    /// The row is left out
+    Skip,
+    /// The value is replaced, and the row imported
+    Replace(String),
+    /// The import stops, naming the value, unless a fix-up is chosen for it
+    Stop
+}
+
+/// The fix-ups chosen for values which their columns' types cannot hold, so that later
+/// values of the same columns are handled the same way
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct FixUpPolicy {
+    /// By column of the table. A column can have both, so that values which are still invalid
+    /// once their commas are removed are skipped
+    columns: HashMap<String, Vec<FixUp>>,
+    /// The lines chosen to be skipped one at a time
+    skipped_lines: Vec<u64>,
+    /// The fix-ups for columns which have none of their own. If there are none, the import
+    /// stops at their values, so that one can be chosen
+    default: Vec<FixUp>
+}
+
+impl FixUpPolicy {
+
+    /// Uses the fix-up for every column
+    pub(crate) fn always(fix_up: FixUp) -> Self {
+        Self { default: vec![fix_up], ..Self::default() }
+    }
+
+    /// Uses the fix-up for the column's values from now on, as well as those chosen before
+    pub(crate) fn choose(&mut self, column: &str, fix_up: FixUp) {
+        let fix_ups = self.columns.entry(column.to_owned()).or_default();
+        if !fix_ups.contains(&fix_up) {
+            fix_ups.push(fix_up);
+        }
+    }
+
+    /// Skips only the row on the line
+    pub(crate) fn skip_line(&mut self, line: u64) {
+        self.skipped_lines.push(line);
+    }
+
+    pub(crate) fn decide(&self, mismatch: &Mismatch, checked: CheckedType) -> Decision {
+        if self.skipped_lines.contains(&mismatch.line) {
+            return Decision::Skip;
+        }
+        let fix_ups = self.columns.get(&mismatch.column).unwrap_or(&self.default);
+        if fix_ups.contains(&FixUp::StripCommas) {
+            let stripped = mismatch.value.replace(',', "");
+            if checked.may_hold(&stripped) {
+                return Decision::Replace(stripped);
+            }
+        }
+        // A value which is still invalid is skipped, or else asked about again
+        if fix_ups.contains(&FixUp::SkipRow) { Decision::Skip } else { Decision::Stop }
+    }
+}
+
+/// Checks the values of a strict import's numeric columns as records are parsed, deciding
+/// what is done with those which their column's type cannot hold
+#[derive(Clone, Debug)]
+pub(crate) struct TypeChecks {
+    columns: Vec<ColumnCheck>,
+    policy: FixUpPolicy
+}
+
+#[derive(Clone, Debug)]
+struct ColumnCheck {
+    /// The CSV column
+    index: usize,
+    /// The column of the table
+    name: String,
+    /// As SQL names it, e.g. integer
+    type_name: String,
+    checked: CheckedType
+}
+
+/// A record, once its values are checked
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Checked {
+    /// The record is imported, with any values replaced
+    Valid(StringRecord),
+    Skipped(Mismatch),
+    Stopped(Mismatch)
+}
+
+impl TypeChecks {
+
+    /// The checks for the columns into which values are inserted, unless none of their types
+    /// are checked
+    pub(crate) fn new(target: &InsertTarget, policy: FixUpPolicy) -> Option<Self> {
+        let columns: Vec<ColumnCheck> = (0..target.len())
+            .filter_map(|index| {
+                let type_name = target.cast_type(index)?;
+                Some(ColumnCheck {
+                    index: target.field(index),
+                    name: target.column(index).to_owned(),
+                    type_name: sql_name(type_name).to_owned(),
+                    checked: CheckedType::of(type_name)?
+                })
+            })
+            .collect();
+        (!columns.is_empty()).then_some(Self { columns, policy })
+    }
+
+    pub(crate) fn check(&self, line: u64, mut record: StringRecord) -> Checked {
+        for column in &self.columns {
+            let value = record.get(column.index).unwrap_or_default();
+            if column.checked.may_hold(value) {
+                continue;
+            }
+            let mismatch = Mismatch {
+                line, column: column.name.clone(), value: value.to_owned(), type_name: column.type_name.clone()
+            };
+            match self.policy.decide(&mismatch, column.checked) {
+                Decision::Skip => return Checked::Skipped(mismatch),
+                Decision::Stop => return Checked::Stopped(mismatch),
+                Decision::Replace(replacement) => {
+                    record = record.iter().enumerate()
+                        .map(|(index, value)| if index == column.index { replacement.as_str() } else { value })
+                        .collect();
+                }
+            }
+        }
+        Checked::Valid(record)
+    }
+}
+
+/// The name of a type in pg_catalog, unqualified and unquoted, as numeric is quoted since it is a
+/// keyword
+fn built_in(type_name: &str) -> Option<&str> {
+    type_name.strip_prefix("pg_catalog.").map(|name| name.trim_matches('"'))
+}
+
+/// The name SQL gives the type, rather than its internal one
+fn sql_name(type_name: &str) -> &str {
+    match built_in(type_name).unwrap_or(type_name) {
+        "int2" => "smallint",
+        "int4" => "integer",
+        "int8" => "bigint",
+        "float4" => "real",
+        "float8" => "double precision",
+        type_name => type_name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Schema, TableColumn};
+
+    #[test]
+    fn check_values() {
+        let integer = CheckedType::of("pg_catalog.int4").unwrap();
+        let number = CheckedType::of("pg_catalog.\"numeric\"").unwrap();
+        assert_eq!(Some(number), CheckedType::of("pg_catalog.float8"));
+        assert_eq!(None, CheckedType::of("pg_catalog.date"));
+        assert_eq!(None, CheckedType::of("public.int4"));
+        assert_eq!(Some(integer), CheckedType::of("integer"));
+        assert_eq!(Some(number), CheckedType::of("numeric(12, 2)"));
+        for valid in ["", " 12 ", "-7", "+0", "1_000", "0x1F"] {
+            assert!(integer.may_hold(valid), "{}", valid);
+        }
+        for invalid in ["12,5", "1.5", "N/A", "$3", "-"] {
+            assert!(!integer.may_hold(invalid), "{}", invalid);
+        }
+        for valid in ["12.5", ".5", "5.", "-1e-3", "NaN", "-Infinity", "1_000.5"] {
+            assert!(number.may_hold(valid), "{}", valid);
+        }
+        for invalid in ["12,5", ".", "1e", "1.2.3", "abc", "€5"] {
+            assert!(!number.may_hold(invalid), "{}", invalid);
+        }
+    }
+
+    fn mismatch(line: u64, column: &str, value: &str) -> Mismatch {
+        Mismatch { line, column: column.to_owned(), value: value.to_owned(), type_name: String::from("numeric") }
+    }
+
+    #[test]
+    fn decide_by_column() {
+        let mut policy = FixUpPolicy::default();
+        assert_eq!(Decision::Stop, policy.decide(&mismatch(5, "amount", "12,5"), CheckedType::Number));
+        policy.choose("amount", FixUp::StripCommas);
+        assert_eq!(
+            Decision::Replace(String::from("1234.5")),
+            policy.decide(&mismatch(5, "amount", "1,234.5"), CheckedType::Number)
+        );
+        // Still invalid once stripped
+        assert_eq!(Decision::Stop, policy.decide(&mismatch(9, "amount", "N/A"), CheckedType::Number));
+        policy.skip_line(9);
+        assert_eq!(Decision::Skip, policy.decide(&mismatch(9, "amount", "N/A"), CheckedType::Number));
+        assert_eq!(Decision::Stop, policy.decide(&mismatch(10, "price", "N/A"), CheckedType::Number));
+        policy.choose("price", FixUp::SkipRow);
+        assert_eq!(Decision::Skip, policy.decide(&mismatch(12, "price", "N/A"), CheckedType::Number));
+        // Values are still stripped of commas once invalid ones are skipped
+        policy.choose("amount", FixUp::SkipRow);
+        assert_eq!(Decision::Skip, policy.decide(&mismatch(14, "amount", "N/A"), CheckedType::Number));
+        assert_eq!(
+            Decision::Replace(String::from("2000")),
+            policy.decide(&mismatch(15, "amount", "2,000"), CheckedType::Number)
+        );
+    }
+
+    #[test]
+    fn default_for_every_column() {
+        let skip = FixUpPolicy::always(FixUp::SkipRow);
+        assert_eq!(Decision::Skip, skip.decide(&mismatch(5, "amount", "12,5"), CheckedType::Number));
+        assert_eq!(Decision::Skip, skip.decide(&mismatch(6, "price", "N/A"), CheckedType::Integer));
+    }
+
+    #[test]
+    fn check_records() {
+        let schema: Schema = ["ID", "Amount", "note"].into_iter().collect();
+        let table = [("id", "pg_catalog.int4", false), ("amount", "pg_catalog.\"numeric\"", false),
+                     ("note", "pg_catalog.text", true)]
+            .map(|(name, type_name, textual)| TableColumn {
+                name: name.to_owned(), data_type: type_name.to_owned(), type_name: type_name.to_owned(), textual,
+                generated: false, computed: None
+            });
+        let target = schema.match_existing("orders", &table).unwrap();
+        assert!(TypeChecks::new(&InsertTarget::created(&schema), FixUpPolicy::default()).is_none());
+        let mut policy = FixUpPolicy::default();
+        let checks = TypeChecks::new(&target, policy.clone()).unwrap();
+        let valid = StringRecord::from(vec!["1", "12.5", "12,5"]);
+        assert_eq!(Checked::Valid(valid.clone()), checks.check(3, valid));
+        let invalid = StringRecord::from(vec!["2", "1,234.5", "x"]);
+        let mismatch = Mismatch {
+            line: 4, column: String::from("amount"), value: String::from("1,234.5"), type_name: String::from("numeric")
+        };
+        assert_eq!(Checked::Stopped(mismatch), checks.check(4, invalid.clone()));
+        policy.choose("amount", FixUp::StripCommas);
+        let checks = TypeChecks::new(&target, policy).unwrap();
+        assert_eq!(Checked::Valid(StringRecord::from(vec!["2", "1234.5", "x"])), checks.check(4, invalid));
+    }
+
+    #[test]
+    fn describe_mismatch() {
+        assert_eq!(
+            "Line 48112 has \"12,5\" in column amount, which is not a valid numeric",
+            mismatch(48112, "amount", "12,5").to_string()
+        );
+    }
+}
@@ -0,0 +1,315 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use eyre::{eyre, Result};
+use sqlx::{Postgres, pool::PoolConnection};
+use crate::bucket::uncastable;
+use crate::database::{column_argument, column_identifier, find_column, quote_literal, split_arguments};
+use crate::error::{self, Error};
+
+const USAGE: &str = "Usage: hist <column> [buckets]";
+/// How many buckets the values are split into unless stated otherwise
+const DEFAULT_BUCKETS: u32 = 10;
+/// The most buckets which can be asked for, more than which would not fit a terminal's height
+const MAX_BUCKETS: u32 = 100;
+/// The narrowest bars are drawn, however narrow the terminal
+const MIN_BAR_WIDTH: usize = 10;
+
+/// The distribution of a numeric column, written as `hist <column> [buckets]`. The range from
+/// its least to its greatest value is split into buckets of equal width, and the values in each
+/// are counted
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Histogram {
+    pub(crate) table: String,
+    pub(crate) column: String,
+    buckets: u32
+}
+
+/// The values of the column, as found before they are bucketed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Bounds {
+    /// The column's values as numeric, NULL when empty
+    value: String,
+    /// The least and greatest values, or None if every value is empty
+    range: Option<(String, String)>,
+    /// How many values are NULL or empty
+    pub(crate) blanks: i64
+}
+
+/// One bar of the chart
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct Bucket {
+    lower: f64,
+    upper: f64,
+    count: i64
+}
+
+impl Histogram {
+
+    /// Parses the arguments following `hist`. The column may be named in double quotes
+    pub(crate) fn parse(table: String, arguments: &str) -> Result<Self> {
+        let arguments = split_arguments(arguments, &[])?;
+        let mut arguments = arguments.iter();
+        let column = arguments.next().ok_or_else(|| eyre!(USAGE))?;
+        let buckets = match arguments.next() {
+            Some(buckets) => buckets.parse().ok().filter(|buckets| (1..=MAX_BUCKETS).contains(buckets))
+                .ok_or_else(|| eyre!("Invalid number of buckets: {}. It must be from 1 to {}", buckets, MAX_BUCKETS))?,
+            None => DEFAULT_BUCKETS
+        };
+        if let Some(extra) = arguments.next() {
+            return Err(eyre!("Unexpected argument {}. {}", extra, USAGE));
+        }
+        Ok(Self { table, column: column_argument(column)?, buckets })
+    }
+
+    /// Finds the column in the table, taking its name from there, and checks that it is numeric,
+    /// or text whose values are all numbers, then finds its least and greatest values
+    pub(crate) async fn bounds(&mut self, connection: &mut PoolConnection<Postgres>) -> error::Result<Bounds> {
+        let columns: Vec<(String, String, String)> = sqlx::query_as("\
+            SELECT attribute.attname::text, type.typname::text, type.typcategory::text \
+            FROM pg_catalog.pg_attribute attribute \
+            JOIN pg_catalog.pg_type type ON type.oid = attribute.atttypid \
+            WHERE attribute.attrelid = to_regclass($1) AND attribute.attnum > 0 AND NOT attribute.attisdropped \
+            ORDER BY attribute.attnum")
+            .bind(&self.table)
+            .fetch_all(&mut *connection).await
+            .map_err(|error| Error::from_database(error, Error::QueryRuntime))?;
+        let (column, type_name, category) = find_column(&self.table, &self.column, &columns, |(column, _, _)| column)?;
+        self.column = column.clone();
+        let identifier = column_identifier(&self.column);
+        let value = match category.as_str() {
+            "N" => format!("{}::numeric", identifier),
+            "S" => {
+                let invalid = uncastable(&self.table, &self.column, "numeric", connection).await?;
+                if !invalid.is_empty() {
+                    return Err(Error::QueryRuntime(eyre!(
+                        "Column {} has values which are not numbers, such as {}. For its most common values, \
+                        use 'top {}' instead", self.column, invalid.join(", "), identifier
+                    )));
+                }
+                format!("NULLIF({}, '')::numeric", identifier)
+            },
+            _ => return Err(Error::Usage(eyre!(
+                "Column {} is of type {}, which is not numeric. For its most common values, use 'top {}' instead",
+                self.column, type_name, identifier
+            )))
+        };
+        let (low, high, blanks): (Option<String>, Option<String>, i64) = sqlx::query_as(&self.bounds_sql(&value))
+            .fetch_one(connection).await
+            .map_err(Error::from_query)?;
+        Ok(Bounds { value, range: low.zip(high), blanks })
+    }
+
+    fn bounds_sql(&self, value: &str) -> String {
+        format!(
+            "SELECT min({value})::text, max({value})::text, count(*) FILTER (WHERE {value} IS NULL) FROM {}",
+            self.table, value = value
+        )
+    }
+
+    /// Generates the query counting the values in each bucket, or None if there are no values.
+    /// width_bucket puts the greatest value in a bucket of its own past the last, so it is moved
+    /// into the last. It refuses a range with equal bounds, such as that of a constant column or
+    /// a single row, whose values are all counted in one bucket instead
+    pub(crate) fn to_sql(&self, bounds: &Bounds) -> Option<String> {
+        let (low, high) = bounds.range.as_ref()?;
+        let bucket = if low == high {
+            String::from("1")
+        } else {
+            format!(
+                "least(width_bucket({}, {}::numeric, {}::numeric, {buckets}), {buckets})",
+                bounds.value, quote_literal(low), quote_literal(high), buckets = self.buckets
+            )
+        };
+        Some(format!("\
+SELECT {} AS bucket, count(*) AS count
+FROM {}
+WHERE {} IS NOT NULL
+GROUP BY 1
+ORDER BY 1", bucket, self.table, bounds.value))
+    }
+
+    /// Runs the generated query, giving every bucket, including those without values
+    pub(crate) async fn count(&self, bounds: &Bounds, sql: &str,
+                              connection: &mut PoolConnection<Postgres>) -> error::Result<Vec<Bucket>> {
+        let counts: Vec<(i32, i64)> = sqlx::query_as(sql)
+            .fetch_all(connection).await
+            .map_err(Error::from_query)?;
+        Ok(self.buckets_for(bounds, &counts))
+    }
+
+    fn buckets_for(&self, bounds: &Bounds, counts: &[(i32, i64)]) -> Vec<Bucket> {
+        let (low, high) = match &bounds.range {
+            Some((low, high)) => (low.parse().unwrap_or(f64::NAN), high.parse().unwrap_or(f64::NAN)),
+            None => return Vec::new()
+        };
+        let count_of = |bucket: u32| counts.iter()
+            .find(|(number, _)| *number as u32 == bucket)
+            .map_or(0, |(_, count)| *count);
+        if bounds.range.as_ref().is_some_and(|(low, high)| low == high) {
+            return vec![Bucket { lower: low, upper: high, count: count_of(1) }];
+        }
+        let width = (high - low) / f64::from(self.buckets);
+        (1..=self.buckets).map(|bucket| Bucket {
+            lower: low + width * f64::from(bucket - 1),
+            upper: if bucket == self.buckets { high } else { low + width * f64::from(bucket) },
+            count: count_of(bucket)
+        }).collect()
+    }
+}
+
+/// Draws the buckets as horizontal bars, labeled with their ranges and counts, in lines at most
+/// the given width unless it is too narrow. The bars are scaled to the bucket with the most values
+pub(crate) fn render(buckets: &[Bucket], width: usize) -> Vec<String> {
+    let step = buckets.first().map_or(0.0, |bucket| bucket.upper - bucket.lower);
+    let precision = decimal_places(step);
+    let labels: Vec<String> = buckets.iter().enumerate()
+        .map(|(index, bucket)| {
+            let closing = if index + 1 == buckets.len() { ']' } else { ')' };
+            format!("[{:.*}, {:.*}{}", precision, bucket.lower, precision, bucket.upper, closing)
+        })
+        .collect();
+    let label_width = labels.iter().map(String::len).max().unwrap_or(0);
+    let count_width = buckets.iter().map(|bucket| bucket.count.to_string().len()).max().unwrap_or(0);
+    let bar_width = width.saturating_sub(label_width + count_width + 3).max(MIN_BAR_WIDTH);
+    let most = buckets.iter().map(|bucket| bucket.count).max().unwrap_or(0).max(1) as u128;
+    buckets.iter().zip(labels)
+        .map(|(bucket, label)| {
+            let count = bucket.count.max(0) as u128;
+            // Rounded to the nearest, except that a bucket with any values has at least a sliver
+            let length = ((count * bar_width as u128 + most / 2) / most) as usize;
+            let length = if count > 0 { length.max(1) } else { 0 };
+            format!("{:>label_width$} {:>count_width$} |{}", label, bucket.count, "#".repeat(length))
+        })
+        .collect()
+}
+
+/// How many decimal places tell apart bounds the given distance apart
+fn decimal_places(step: f64) -> usize {
+    /// The most places shown, beyond which the bounds are too close to be worth telling apart
+    const MAX_PLACES: f64 = 6.0;
+
+    if !step.is_finite() || step <= 0.0 {
+        return 0;
+    }
+    (1.0 - step.log10().floor()).clamp(0.0, MAX_PLACES) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds(low: &str, high: &str) -> Bounds {
+        Bounds {
+            value: String::from("NULLIF(price, '')::numeric"),
+            range: Some((String::from(low), String::from(high))),
+            blanks: 0
+        }
+    }
+
+    #[test]
+    fn parse_hist() {
+        let hist = Histogram::parse(String::from("data"), "Price 20").unwrap();
+        assert_eq!(("Price", 20), (hist.column.as_str(), hist.buckets));
+        let hist = Histogram::parse(String::from("data"), "\"Unit Price\"").unwrap();
+        assert_eq!(("Unit Price", DEFAULT_BUCKETS), (hist.column.as_str(), hist.buckets));
+        assert_eq!(DEFAULT_BUCKETS, Histogram::parse(String::from("data"), " price ").unwrap().buckets);
+        let invalid = |arguments: &str| Histogram::parse(String::from("data"), arguments).unwrap_err().to_string();
+        assert_eq!(USAGE, invalid(""));
+        assert_eq!("Invalid number of buckets: 0. It must be from 1 to 100", invalid("price 0"));
+        assert_eq!("Invalid number of buckets: 101. It must be from 1 to 100", invalid("price 101"));
+        assert_eq!("Unexpected argument 6. Usage: hist <column> [buckets]", invalid("price 5 6"));
+        assert_eq!("Invalid column name: \"\"", invalid("\"\" 5"));
+    }
+
+    #[test]
+    fn hist_sql() {
+        let hist = Histogram::parse(String::from("data"), "price 4").unwrap();
+        assert_eq!(
+            "SELECT min(price::numeric)::text, max(price::numeric)::text, \
+            count(*) FILTER (WHERE price::numeric IS NULL) FROM data",
+            hist.bounds_sql("price::numeric")
+        );
+        assert_eq!(Some(String::from("\
+SELECT least(width_bucket(NULLIF(price, '')::numeric, '0.5'::numeric, '10'::numeric, 4), 4) AS bucket, count(*) AS count
+FROM data
+WHERE NULLIF(price, '')::numeric IS NOT NULL
+GROUP BY 1
+ORDER BY 1")), hist.to_sql(&bounds("0.5", "10")));
+    }
+
+    #[test]
+    fn hist_of_constant_or_empty_column() {
+        let hist = Histogram::parse(String::from("data"), "price 4").unwrap();
+        let constant = bounds("7", "7");
+        let sql = hist.to_sql(&constant).unwrap();
+        assert!(sql.starts_with("SELECT 1 AS bucket, count(*) AS count\nFROM data\n"), "{}", sql);
+        assert_eq!(vec![Bucket { lower: 7.0, upper: 7.0, count: 3 }], hist.buckets_for(&constant, &[(1, 3)]));
+
+        let empty = Bounds { range: None, ..constant };
+        assert_eq!(None, hist.to_sql(&empty));
+        assert!(hist.buckets_for(&empty, &[]).is_empty());
+    }
+
+    #[test]
+    fn empty_buckets_are_counted_as_zero() {
+        let hist = Histogram::parse(String::from("data"), "price 4").unwrap();
+        let buckets = hist.buckets_for(&bounds("0", "10"), &[(1, 5), (4, 2)]);
+        assert_eq!(vec![
+            Bucket { lower: 0.0, upper: 2.5, count: 5 },
+            Bucket { lower: 2.5, upper: 5.0, count: 0 },
+            Bucket { lower: 5.0, upper: 7.5, count: 0 },
+            Bucket { lower: 7.5, upper: 10.0, count: 2 }
+        ], buckets);
+    }
+
+    #[test]
+    fn render_bars() {
+        let buckets = [
+            Bucket { lower: 0.0, upper: 2.5, count: 40 },
+            Bucket { lower: 2.5, upper: 5.0, count: 0 },
+            Bucket { lower: 5.0, upper: 7.5, count: 1 },
+            Bucket { lower: 7.5, upper: 10.0, count: 20 }
+        ];
+        assert_eq!(vec![
+            " [0.0, 2.5) 40 |####################",
+            " [2.5, 5.0)  0 |",
+            " [5.0, 7.5)  1 |#",
+            "[7.5, 10.0] 20 |##########"
+        ], render(&buckets, 36));
+        // The widest bar fills what the labels leave of the width
+        assert!(render(&buckets, 80).iter().all(|line| line.len() <= 80));
+        assert_eq!(80, render(&buckets, 80)[0].len());
+        // A narrow terminal still gets bars
+        assert!(render(&buckets, 5)[0].ends_with(&"#".repeat(MIN_BAR_WIDTH)));
+        assert!(render(&[], 80).is_empty());
+    }
+
+    #[test]
+    fn render_constant_column() {
+        assert_eq!(vec!["[7, 7] 3 |##########"], render(&[Bucket { lower: 7.0, upper: 7.0, count: 3 }], 10));
+    }
+
+    #[test]
+    fn label_precision() {
+        assert_eq!(0, decimal_places(10.0));
+        assert_eq!(1, decimal_places(2.5));
+        assert_eq!(2, decimal_places(0.25));
+        assert_eq!(6, decimal_places(1e-9));
+        assert_eq!(0, decimal_places(0.0));
+    }
+}
@@ -0,0 +1,2234 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::SeekFrom;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, UNIX_EPOCH};
+use async_std::fs::{self, File};
+use async_std::io::{self, BufRead, BufReader};
+use async_std::io::prelude::SeekExt;
+use async_std::path::{Path, PathBuf};
+use async_std::task;
+use csv_async::{ByteRecord, Position, StringRecord};
+use eyre::{eyre, WrapErr};
+use futures_lite::AsyncBufReadExt;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use sqlx::{Acquire, Postgres, Transaction};
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::{PgArguments, PgPool};
+use url::Url;
+use crate::archive::ArchiveEntry;
+use crate::charset::{self, RepairUtf8, REPLACEMENT};
+use crate::computed::ComputedColumn;
+use crate::config::Config;
+use crate::database::{self, ConflictKey, InsertTarget, Schema, TableMode};
+use crate::dialect::{Dialect, SizeLimits};
+use crate::download::{self, Download, RequestHeader};
+use crate::filter::{BoundFilter, RowFilter};
+use crate::error::{Categorize, Error, Result};
+use crate::fixedwidth::{FixedWidthReader, Layout};
+use crate::fixup::{Checked, FixUpPolicy, Mismatch, TypeChecks};
+use crate::observer::{ImportEvent, ImportObserver, ImportStats};
+use crate::parquet::{self, ColumnValues, ParquetFile};
+use crate::profile::DatasetProfile;
+use crate::progress::format_bytes;
+use crate::reject::{RejectLimit, RejectTally};
+use crate::remote::{self, RemoteDataset, RemoteReader};
+use crate::sample::{Reservoir, Sampling};
+use crate::timing::StageTimings;
+use crate::transform::{BoundTransforms, ColumnTransforms, TransformCounts};
+use crate::validate::Validation;
+
+/// Postgres allows at most this many parameters in a statement
+const MAX_PARAMETERS: usize = 65535;
+/// How many records are inserted by each statement, unless there are too many columns
+const BATCH_RECORDS: usize = 1000;
+/// How many batches are committed together, after which a checkpoint is recorded
+const CHECKPOINT_BATCHES: usize = 10;
+/// How many lines with invalid UTF-8 are named after repairing them
+const REPAIRED_LINES_SHOWN: usize = 5;
+/// How many lines are named after padding, cutting short or skipping records
+const RAGGED_LINES_SHOWN: usize = 5;
+/// How often the observer is told of the bytes read while a long record is read
+const READING_REPORT_BYTES: u64 = 4_000_000;
+/// The size in megabytes from which files are imported with COPY, unless the config says otherwise
+pub(crate) const COPY_THRESHOLD_MB: u64 = 10;
+
+/// How the rows of a dataset are sent to the database. Batched INSERT statements name the line
+/// of a record which cannot be imported, while COPY is faster, but leaves Postgres to parse the
+/// records, so that a failure can only be traced to the group of records being copied.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Strategy {
+    /// COPY for files of at least the threshold size, and batched INSERT statements otherwise
+    #[default]
+    Auto,
+    Insert,
+    Copy
+}
+
+impl Strategy {
+
+    /// Chooses how to import a dataset of the given size, in bytes, if it is known, and says why.
+    /// The blocker is what the import asks for which COPY cannot do, if anything
+    pub(crate) fn choose(self, size: Option<u64>, threshold: u64,
+                         blocker: Option<&str>) -> eyre::Result<(Self, String)> {
+        match (self, size, blocker) {
+            (Self::Insert, _, _) => Ok((Self::Insert, String::from("as --strategy insert asks"))),
+            (Self::Copy, _, Some(blocker)) => Err(eyre!(
+                "COPY cannot {}, so --strategy copy cannot be used. Use --strategy insert instead", blocker
+            )),
+            (Self::Copy, _, None) => Ok((Self::Copy, String::from("as --strategy copy asks"))),
+            (Self::Auto, _, Some(blocker)) => Ok((Self::Insert, format!("since COPY cannot {}", blocker))),
+            (Self::Auto, None, None) => Ok((Self::Insert, String::from("since the size of a download is not known"))),
+            (Self::Auto, Some(size), None) if size < threshold => Ok((Self::Insert, format!(
+                "since the file is smaller than the COPY threshold of {}", format_bytes(threshold)
+            ))),
+            (Self::Auto, Some(_), None) => Ok((Self::Copy, format!(
+                "since the file is at least the COPY threshold of {}", format_bytes(threshold)
+            )))
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "batched INSERT statements or COPY, by size",
+            Self::Insert => "batched INSERT statements",
+            Self::Copy => "COPY"
+        })
+    }
+}
+
+/// What the import asks for which COPY cannot do, if anything. COPY sends records unparsed, so
+/// they cannot be repaired, fitted to the header, transformed or filtered on the way
+pub(crate) fn copy_blocker(dialect: Dialect, repair_utf8: bool, ragged_rows: RaggedRows, transformed: bool,
+                           filtered: bool) -> Option<&'static str> {
+    if dialect.copy_options().is_none() {
+        Some("read fields escaped both with an escape character and by doubling quotes")
+    } else if repair_utf8 {
+        Some("repair invalid UTF-8")
+    } else if ragged_rows != RaggedRows::Strict {
+        Some("fit records to the header")
+    } else if transformed {
+        Some("transform values")
+    } else if filtered {
+        Some("filter rows")
+    } else {
+        None
+    }
+}
+
+/// Finds the columns of the transforms in the dataset's header, unless there are none
+fn bind_transforms(transforms: &[ColumnTransforms], schema: &Schema) -> Result<Option<BoundTransforms>> {
+    if transforms.is_empty() {
+        return Ok(None);
+    }
+    ColumnTransforms::bind(transforms, schema).categorize(Error::Usage).map(Some)
+}
+
+/// Marks an error as a failure of COPY, after which the import can be retried with batched
+/// INSERT statements to find the line at fault
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct CopyFailed;
+
+impl fmt::Display for CopyFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("COPY failed, and cannot name the line at fault. Import with --strategy insert to find it")
+    }
+}
+
+/// Whether the import failed since COPY did
+pub(crate) fn copy_failed(error: &Error) -> bool {
+    error.report().downcast_ref::<CopyFailed>().is_some()
+}
+
+/// The value which stopped the import, if it failed since a column's type cannot hold it
+pub(crate) fn type_mismatch(error: &Error) -> Option<&Mismatch> {
+    error.report().downcast_ref::<Mismatch>()
+}
+
+impl CopyFailed {
+
+    fn mark(error: Error) -> Error {
+        match error {
+            Error::ImportData(report) => Error::ImportData(report.wrap_err(Self)),
+            error => error
+        }
+    }
+}
+
+/// What is done with a record whose number of fields differs from the header's. Short records
+/// are often written by exporters which leave out empty trailing fields, and long ones by
+/// free text containing unquoted delimiters.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RaggedRows {
+    /// The import stops
+    #[default]
+    Strict,
+    /// The record is left out
+    Skip,
+    /// A record with too few fields has empty fields added at the end. Longer records still
+    /// stop the import
+    Pad,
+    /// A record with too many fields has those beyond the header's dropped. Shorter records
+    /// still stop the import
+    Truncate
+}
+
+impl std::str::FromStr for RaggedRows {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> eyre::Result<Self> {
+        match value {
+            "strict" => Ok(Self::Strict),
+            "skip" => Ok(Self::Skip),
+            "pad" => Ok(Self::Pad),
+            "truncate" => Ok(Self::Truncate),
+            _ => Err(eyre!("Invalid value {} for ragged rows. Valid values are: strict, skip, pad, truncate", value))
+        }
+    }
+}
+
+impl RaggedRows {
+
+    /// Fits the record to the number of columns, returning false if it is to be skipped
+    /// instead, or none if the record cannot be imported
+    fn fit(self, record: &mut ByteRecord, columns: usize) -> Option<bool> {
+        match self {
+            Self::Skip => Some(false),
+            Self::Pad if record.len() < columns => {
+                while record.len() < columns {
+                    record.push_field(b"");
+                }
+                Some(true)
+            },
+            Self::Truncate if record.len() > columns => {
+                record.truncate(columns);
+                Some(true)
+            },
+            _ => None
+        }
+    }
+}
+
+/// The kinds of dataset which can be imported, one for each kind of CsvInput, with how each is
+/// given
+pub(crate) const INPUT_FORMATS: [(&str, &str); 5] = [
+    ("csv", "A CSV file"),
+    ("url", "A CSV file downloaded from an http(s) URL, decompressed if served with gzip"),
+    ("fixed-width", "A file of fixed-width columns, with --fixed-width giving the layout"),
+    ("zip", "A CSV file within a ZIP archive"),
+    ("parquet", "An Apache Parquet file, whose columns keep their types")
+];
+
+/// Where a CSV dataset is read from
+#[derive(Clone, Debug)]
+pub(crate) enum CsvInput {
+    File(PathBuf),
+    /// Downloaded as it is imported. Such imports cannot be resumed
+    Url {
+        url: Url,
+        headers: Vec<RequestHeader>
+    },
+    /// A file of fixed-width columns, read as CSV headed by the names of the layout's columns.
+    /// Such imports cannot be resumed either, since positions in the CSV are not those in the file
+    FixedWidth {
+        path: PathBuf,
+        layout: Arc<Layout>
+    },
+    /// A dataset within a ZIP archive, decompressed as it is imported. Such imports cannot be
+    /// resumed, since archives cannot be read from the middle of an entry
+    ZipEntry {
+        archive: PathBuf,
+        entry: Arc<ArchiveEntry>
+    },
+    /// A Parquet file, whose columns keep their types. Its rows are read as batches of typed
+    /// values rather than as CSV. Such imports cannot be resumed, since the file is read a row
+    /// group at a time
+    Parquet {
+        path: PathBuf,
+        file: Arc<ParquetFile>
+    },
+    /// A table or query of another database, read as CSV copied out of it, whose columns keep
+    /// their types. Such imports cannot be resumed either, since the rows may have changed
+    Remote(Arc<RemoteDataset>)
+}
+
+impl CsvInput {
+
+    /// The dataset named by the user, which is a URL if it starts with http:// or https://, a
+    /// table or query of another database if it starts with postgres://, and a Parquet file if
+    /// it ends with .parquet. Other databases are connected to as the config says
+    pub(crate) async fn resolve(input: &str, headers: &[RequestHeader], config: &Config) -> Result<Self> {
+        if remote::is_remote(input) {
+            Ok(Self::Remote(Arc::new(RemoteDataset::describe(input, config).await?)))
+        } else if input.starts_with("http://") || input.starts_with("https://") {
+            let url = Url::parse(input)
+                .map_err(|error| Error::Usage(eyre!("Invalid URL {}: {}", input, error)))?;
+            if parquet::is_parquet(url.path()) {
+                return Err(Error::Usage(eyre!(
+                    "Parquet files are read from files, not downloads, since they describe themselves at their end"
+                )));
+            }
+            Ok(Self::Url { url, headers: headers.to_vec() })
+        } else if parquet::is_parquet(input) {
+            let path = PathBuf::from(input).canonicalize().await
+                .wrap_err_with(|| format!("Specified Parquet file {:?} does not exist", input))
+                .categorize(Error::ImportIo)?;
+            let file = Arc::new(ParquetFile::open(&path).await?);
+            Ok(Self::Parquet { path, file })
+        } else {
+            Ok(Self::File(PathBuf::from(input).canonicalize().await
+                .wrap_err_with(|| format!("Specified CSV file {:?} does not exist", input))
+                .categorize(Error::ImportIo)?))
+        }
+    }
+    /// The same dataset, sliced into columns by the fixed-width layout. Only files can be
+    pub(crate) fn with_layout(self, layout: Arc<Layout>) -> Result<Self> {
+        match self {
+            Self::File(path) | Self::FixedWidth { path, .. } => Ok(Self::FixedWidth { path, layout }),
+            Self::Url { .. } => Err(Error::Usage(eyre!("Fixed-width datasets are read from files, not downloads"))),
+            Self::ZipEntry { .. } => Err(Error::Usage(eyre!("Fixed-width datasets cannot be read from ZIP archives"))),
+            Self::Parquet { .. } => Err(Error::Usage(eyre!(
+                "Parquet files name their own columns, so they have no layout"
+            ))),
+            Self::Remote(_) => Err(Error::Usage(eyre!("Tables of other databases name their own columns")))
+        }
+    }
+
+    /// The file the dataset is read from, unless it is downloaded, in an archive or in another database
+    pub(crate) fn path(&self) -> Option<&Path> {
+        match self {
+            Self::File(path) | Self::FixedWidth { path, .. } | Self::Parquet { path, .. } => Some(path),
+            Self::Url { .. } | Self::ZipEntry { .. } | Self::Remote(_) => None
+        }
+    }
+
+    /// The file name without its extension, e.g. sales for https://example.com/drops/sales.csv
+    pub(crate) fn stem(&self) -> Option<String> {
+        let file_name = match self {
+            Self::File(path) | Self::FixedWidth { path, .. } | Self::Parquet { path, .. } => path.file_name()?,
+            Self::Url { url, .. } => url.path_segments()?.next_back().filter(|segment| !segment.is_empty())?.as_ref(),
+            Self::ZipEntry { entry, .. } => entry.name.rsplit('/').next()?.as_ref(),
+            Self::Remote(dataset) => return dataset.table_name().map(String::from)
+        };
+        Some(Path::new(file_name).file_stem()?.to_string_lossy().into_owned())
+    }
+}
+
+impl fmt::Display for CsvInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) | Self::FixedWidth { path, .. } | Self::Parquet { path, .. } => {
+                write!(f, "{}", path.display())
+            },
+            Self::Url { url, .. } => f.write_str(&download::redacted(url)),
+            Self::ZipEntry { archive, entry } => write!(f, "{} in {}", entry.name, archive.display()),
+            Self::Remote(dataset) => write!(f, "{}", dataset)
+        }
+    }
+}
+
+/// How far an import has progressed. Checkpoints are recorded after each commit, so that an
+/// interrupted import can be resumed from the last one.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Checkpoint {
+    pub(crate) input: std::path::PathBuf,
+    pub(crate) table: String,
+    /// The size and modification time of the input, which must be unchanged when resuming
+    file_size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+    /// The position of the first record not yet imported
+    byte: u64,
+    line: u64,
+    record: u64,
+    /// How many rows the table contained after the last commit
+    pub(crate) table_rows: u64
+}
+
+/// The size and modification time of a file
+async fn fingerprint(input: &Path) -> Result<(u64, u64, u32)> {
+    let metadata = fs::metadata(input).await
+        .wrap_err_with(|| format!("Unable to read metadata of CSV file {:?}", input))
+        .categorize(Error::ImportIo)?;
+    let modified = metadata.modified().ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    Ok((metadata.len(), modified.as_secs(), modified.subsec_nanos()))
+}
+
+impl Checkpoint {
+
+    /// Whether the checkpoint was recorded while importing the given file
+    pub(crate) fn is_for(&self, input: &Path) -> bool {
+        self.input.as_path() == AsRef::<std::path::Path>::as_ref(input)
+    }
+
+    /// Where the checkpoint for an import into the given table is kept
+    pub(crate) fn path_for(data_dir: &Path, table: &str) -> PathBuf {
+        data_dir.join(format!("{}.import-checkpoint.ron", table))
+    }
+
+    pub(crate) async fn load(path: &Path) -> eyre::Result<Option<Self>> {
+        if !path.exists().await {
+            return Ok(None);
+        }
+        let checkpoint = fs::read_to_string(path).await?;
+        let checkpoint = ron::from_str(&checkpoint)
+            .wrap_err_with(|| format!("Invalid import checkpoint {:?}. Delete it to start over", path))?;
+        Ok(Some(checkpoint))
+    }
+
+    async fn save(&self, path: &Path) -> eyre::Result<()> {
+        // Replaced in one step, so that a checkpoint is never half written
+        let mut temp_path = path.as_os_str().to_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        fs::write(&temp_path, ron::to_string(self)?).await?;
+        fs::rename(&temp_path, path).await?;
+        Ok(())
+    }
+
+    /// Fails unless the input is the same file as when the checkpoint was recorded
+    pub(crate) async fn verify_unchanged(&self) -> Result<()> {
+        let (file_size, modified_secs, modified_nanos) = fingerprint(self.input.as_path().into()).await?;
+        if (file_size, modified_secs, modified_nanos) != (self.file_size, self.modified_secs, self.modified_nanos) {
+            return Err(Error::Usage(eyre!(
+                "CSV file {:?} has changed since the import into table {} stopped, so the import cannot \
+                be resumed. Import it again from the start instead", self.input, self.table
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn position(&self) -> Position {
+        let mut position = Position::new();
+        position.set_byte(self.byte).set_line(self.line).set_record(self.record);
+        position
+    }
+}
+
+/// Reads whole records from a CSV file, without parsing them, so that the parsing of each
+/// chunk can be left to a task of its own
+struct ChunkReader {
+    reader: Box<dyn BufRead + Unpin + Send>,
+    dialect: Dialect,
+    position: Position,
+    finished: bool,
+    /// For downloads, how many bytes have been received, and for other databases, how many rows
+    received: Option<Arc<AtomicU64>>,
+    /// Whether blank lines are left out of the chunks, as COPY would read them as records
+    drop_blank_records: bool,
+    /// The names and types of the columns of a fixed-width or Parquet file, or of another
+    /// database, which has no header
+    columns: Option<(Vec<String>, Vec<Option<String>>)>,
+    /// Told of the bytes read while a long record is read, between chunks
+    observer: Option<Arc<dyn ImportObserver>>
+}
+
+/// The unparsed text of consecutive records
+struct Chunk {
+    text: Vec<u8>,
+    dialect: Dialect,
+    /// The byte at which the chunk starts
+    first_byte: u64,
+    /// The line on which the first record starts
+    first_line: u64,
+    /// The position of the first record after the chunk
+    end: Position,
+    /// The record ending the chunk if it was too large to read, whose text is left out
+    oversized: Option<Reject>
+}
+
+/// What is done with each record once parsed, in order
+#[derive(Default)]
+struct RecordSteps {
+    /// Rows left out of a sample at a rate are passed over before they are even parsed
+    sampling: Option<Sampling>,
+    transforms: Option<BoundTransforms>,
+    filter: Option<BoundFilter>,
+    /// Rows the filter leaves out are not checked
+    checks: Option<TypeChecks>
+}
+
+/// The records of a chunk, ready to be bound to an INSERT statement
+struct ParsedChunk {
+    /// The records which matched the filter, if there is one, after transforming their values
+    records: Vec<StringRecord>,
+    /// How many records were parsed, whether or not they matched the filter
+    read: usize,
+    /// How many records were passed over, since a sample at a rate left them out
+    unsampled: usize,
+    /// How many values were transformed, in records which matched the filter or not
+    transformed: TransformCounts,
+    repairs: Utf8Repairs,
+    profile: DatasetProfile,
+    /// Records which were skipped, if the parser was told to keep going
+    rejects: Vec<Reject>,
+    /// Records which were padded, cut short or skipped since their fields did not match the header
+    ragged: RaggedRecords,
+    /// The records which were skipped since their fields did not match the header
+    skipped: Vec<Reject>,
+    /// How long the records took to parse, and then to transform, filter, check and profile
+    timings: StageTimings,
+    end: Position
+}
+
+/// The records fitted to the header, or skipped, since their fields did not match it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct RaggedRecords {
+    count: u64,
+    /// The first lines on which there were such records
+    lines: Vec<u64>
+}
+
+impl RaggedRecords {
+
+    fn record(&mut self, line: u64) {
+        self.count += 1;
+        if self.lines.len() < RAGGED_LINES_SHOWN {
+            self.lines.push(line);
+        }
+    }
+
+    /// Adds the records found later in the file
+    fn extend(&mut self, later: &Self) {
+        self.count += later.count;
+        let shown = RAGGED_LINES_SHOWN.saturating_sub(self.lines.len());
+        self.lines.extend(later.lines.iter().take(shown));
+    }
+
+    fn warning(&self, ragged_rows: RaggedRows, schema: &Schema) -> Option<String> {
+        if self.count == 0 {
+            return None;
+        }
+        let action = match ragged_rows {
+            RaggedRows::Strict => return None,
+            RaggedRows::Skip => format!("Skipped {} records whose fields did not match the header", self.count),
+            RaggedRows::Pad => format!("Padded {} records with too few fields with empty fields", self.count),
+            RaggedRows::Truncate => format!(
+                "Dropped the fields beyond the header's {} from {} records with too many fields", schema.len(), self.count
+            )
+        };
+        Some(format!("{}, first on lines {}", action, self.lines.iter().join(", ")))
+    }
+
+    /// Warns of the records skipped since they were too large to read
+    fn oversized_warning(&self) -> Option<String> {
+        (self.count != 0).then(|| format!(
+            "Skipped {} records larger than the max_field_mb or max_record_mb config, first on lines {}",
+            self.count, self.lines.iter().join(", ")
+        ))
+    }
+}
+
+/// Invalid UTF-8 sequences which were replaced with U+FFFD
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Utf8Repairs {
+    /// Replacements in each column
+    per_column: Vec<u64>,
+    /// The first lines on which there were replacements
+    lines: Vec<u64>
+}
+
+impl Utf8Repairs {
+
+    fn record(&mut self, column: usize, line: u64, replacements: usize) {
+        if self.per_column.len() <= column {
+            self.per_column.resize(column + 1, 0);
+        }
+        self.per_column[column] += replacements as u64;
+        if self.lines.len() < REPAIRED_LINES_SHOWN && self.lines.last() != Some(&line) {
+            self.lines.push(line);
+        }
+    }
+
+    /// Adds the repairs made later in the file
+    fn extend(&mut self, later: &Self) {
+        if self.per_column.len() < later.per_column.len() {
+            self.per_column.resize(later.per_column.len(), 0);
+        }
+        for (total, replacements) in self.per_column.iter_mut().zip(&later.per_column) {
+            *total += replacements;
+        }
+        for line in &later.lines {
+            if self.lines.len() < REPAIRED_LINES_SHOWN && self.lines.last() != Some(line) {
+                self.lines.push(*line);
+            }
+        }
+    }
+
+    fn warning(&self, schema: &Schema) -> Option<String> {
+        let total: u64 = self.per_column.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let columns = self.per_column.iter().enumerate()
+            .filter(|(_, replacements)| **replacements != 0)
+            .map(|(column, replacements)| format!("{} in {}", replacements, schema.column_name(column)))
+            .join(", ");
+        let lines = self.lines.iter().join(", ");
+        Some(format!(
+            "Replaced {} invalid UTF-8 sequences with U+FFFD ({}), first on lines {}", total, columns, lines
+        ))
+    }
+}
+
+/// What happened while importing
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ImportSummary {
+    /// Problems which did not prevent the import
+    pub(crate) warnings: Vec<String>,
+    /// The INSERT statements prepared and executed, if they were counted
+    pub(crate) statements: Option<StatementCounts>,
+    /// How many rows the table has, unless the existing data was kept
+    pub(crate) rows: Option<u64>,
+    /// The profile of the rows imported, unless the existing data was kept
+    pub(crate) profile: Option<DatasetProfile>,
+    /// How many rows were read and kept, if the dataset was filtered
+    pub(crate) filtered: Option<FilteredRows>,
+    /// How the rows were sampled and how many were, if only a sample was imported
+    pub(crate) sampled: Option<SampledRows>,
+    /// How many values were changed in each column with transforms, by column name
+    pub(crate) transformed: Vec<(String, u64)>,
+    /// How many rows were inserted, updated and left out, if rows whose key was already in the
+    /// table were updated or left out
+    pub(crate) conflicts: Option<ConflictCounts>,
+    /// How long the import spent in each stage
+    pub(crate) timings: StageTimings
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct FilteredRows {
+    pub(crate) read: u64,
+    pub(crate) kept: u64
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct SampledRows {
+    pub(crate) sampling: Sampling,
+    /// Every row read, including those rejected or left out by the filter
+    pub(crate) read: u64,
+    /// The rows chosen for the sample. A sample at a rate is chosen as rows are read, so some of
+    /// it may then be rejected or left out by the filter, while the others are chosen from the
+    /// rows left once they are
+    pub(crate) sampled: u64,
+    /// The rows of the sample sent to the table
+    pub(crate) imported: u64
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ConflictCounts {
+    pub(crate) inserted: u64,
+    pub(crate) updated: u64,
+    pub(crate) skipped: u64
+}
+
+impl ConflictCounts {
+
+    fn add(&mut self, other: Self) {
+        self.inserted += other.inserted;
+        self.updated += other.updated;
+        self.skipped += other.skipped;
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct StatementCounts {
+    pub(crate) prepared: u64,
+    pub(crate) executed: u64
+}
+
+impl ChunkReader {
+
+    async fn open(csv_input: &Path, dialect: Dialect, start: Position) -> Result<Self> {
+        let mut file = File::open(csv_input).await
+            .wrap_err_with(|| format!("Unable to open CSV file {:?}", csv_input))
+            .categorize(Error::ImportIo)?;
+        file.seek(SeekFrom::Start(start.byte())).await
+            .wrap_err_with(|| format!("Unable to read CSV file {:?}", csv_input))
+            .categorize(Error::ImportIo)?;
+        Ok(Self { reader: Box::new(BufReader::new(file)), dialect, position: start, finished: false, received: None,
+                   drop_blank_records: false, columns: None, observer: None })
+    }
+
+    fn from_download(download: Download, dialect: Dialect) -> Self {
+        Self {
+            reader: download.reader, dialect, position: Position::new(), finished: false,
+            received: Some(download.received), drop_blank_records: false, columns: None, observer: None
+        }
+    }
+
+    /// Reads the entry out of the archive, which is never written to disk
+    async fn from_entry(archive: &Path, entry: &ArchiveEntry, dialect: Dialect) -> Result<Self> {
+        Ok(Self {
+            reader: entry.open(archive).await?, dialect, position: Position::new(), finished: false,
+            received: None, drop_blank_records: false, columns: None, observer: None
+        })
+    }
+
+    /// Reads the fixed-width file as CSV, in which quotes are always doubled. Each line is one
+    /// record, so lines are numbered as in the file
+    async fn fixed_width(path: &Path, layout: Arc<Layout>, limits: SizeLimits) -> Result<Self> {
+        let file = File::open(path).await
+            .wrap_err_with(|| format!("Unable to open fixed-width file {:?}", path))
+            .categorize(Error::ImportIo)?;
+        let consumed = Arc::new(AtomicU64::new(0));
+        let reader = FixedWidthReader::new(BufReader::new(file), layout.clone(), consumed.clone());
+        Ok(Self {
+            reader: Box::new(BufReader::new(reader)), dialect: Dialect::default().with_limits(limits),
+            position: Position::new(), finished: false, received: Some(consumed), drop_blank_records: false,
+            columns: Some((layout.column_names(), layout.column_types())), observer: None
+        })
+    }
+
+    /// Reads no records, only naming the columns of the Parquet file, whose rows are inserted as
+    /// batches of typed values instead
+    fn parquet(file: &ParquetFile) -> Self {
+        Self {
+            reader: Box::new(io::empty()), dialect: Dialect::default(), position: Position::new(), finished: true,
+            received: Some(Arc::default()), drop_blank_records: false,
+            columns: Some((file.column_names(), file.column_types())), observer: None
+        }
+    }
+
+    /// Reads the rows of the other database as CSV, once they are copied out of it. How much
+    /// has been read is counted in rows, as the size of the dataset is
+    fn remote(dataset: Arc<RemoteDataset>, limits: SizeLimits) -> Self {
+        let copied = Arc::new(AtomicU64::new(0));
+        let columns = (dataset.column_names(), dataset.column_types());
+        let reader = RemoteReader::new(dataset, copied.clone());
+        Self {
+            reader: Box::new(BufReader::new(reader)), dialect: Dialect::default().with_limits(limits),
+            position: Position::new(), finished: false, received: Some(copied), drop_blank_records: false,
+            columns: Some(columns), observer: None
+        }
+    }
+
+    /// Reads the dataset from its start
+    async fn start(csv_input: &CsvInput, dialect: Dialect) -> Result<Self> {
+        match csv_input {
+            CsvInput::File(path) => Self::open(path, dialect, Position::new()).await,
+            CsvInput::Url { url, headers } => Ok(Self::from_download(Download::start(url, headers).await?, dialect)),
+            CsvInput::FixedWidth { path, layout } => Self::fixed_width(path, layout.clone(), dialect.limits()).await,
+            CsvInput::ZipEntry { archive, entry } => Self::from_entry(archive, entry, dialect).await,
+            CsvInput::Parquet { .. } => Err(Error::Usage(eyre!(
+                "Parquet files are read as batches of typed values, not as CSV records"
+            ))),
+            CsvInput::Remote(dataset) => Ok(Self::remote(dataset.clone(), dialect.limits()))
+        }
+    }
+
+    /// Reads the header, which must be the first record, as the schema of the dataset. A
+    /// fixed-width file's schema is its layout's instead, and a Parquet file's or another
+    /// database's its own. If told to repair, names which are not UTF-8 or Windows-1252 have
+    /// their invalid sequences replaced
+    async fn read_schema(&mut self, repair: bool) -> Result<Schema> {
+        if let Some((names, types)) = &self.columns {
+            return Ok(names.iter().map(String::as_str).collect::<Schema>().with_types(types.clone()));
+        }
+        let text = match self.next_chunk(1).await? {
+            Some(Chunk { oversized: Some(_), .. }) => return Err(Error::ImportData(eyre!(
+                "The header is larger than the max_field_mb or max_record_mb config"
+            ))),
+            Some(chunk) => chunk.text,
+            None => Vec::new()
+        };
+        let mut csv_reader = self.dialect.reader_builder().create_reader(text.as_slice());
+        let header = csv_reader.byte_headers().await.map_err(Error::from_csv)?;
+        let (names, transcoded) = match charset::decode_header(header) {
+            // Names which are not Windows-1252 either are read again, repairing them
+            Err(_) if repair => {
+                let mut repairing = self.dialect.reader_builder().create_reader(RepairUtf8::new(text.as_slice(), true));
+                charset::decode_repaired_header(header, repairing.headers().await.map_err(Error::from_csv)?)?
+            },
+            decoded => decoded?
+        };
+        Ok(names.iter().map(String::as_str).collect::<Schema>().with_transcoded(transcoded))
+    }
+
+    /// How much of the input has been read. For downloads, this is the bytes received, which
+    /// differ from the position if the download is compressed, and likewise for fixed-width files
+    fn consumed(&self) -> u64 {
+        self.received.as_ref().map_or(self.position.byte(), |received| received.load(Ordering::Relaxed))
+    }
+
+    /// Reads up to the given number of records. Blank lines are skipped by the CSV parser, so
+    /// they are not counted as records. A record larger than the dialect's limits is read to its
+    /// end but left out, and ends the chunk, so that the records after it keep their lines.
+    async fn next_chunk(&mut self, records: usize) -> Result<Option<Chunk>> {
+        if self.finished {
+            return Ok(None);
+        }
+        let first_byte = self.position.byte();
+        let first_line = self.position.line();
+        let limits = self.dialect.limits();
+        let mut text = Vec::new();
+        let mut chunk_records = 0;
+        let mut oversized = None;
+        while chunk_records < records {
+            // A line break only ends a record outside quotes
+            let record_start = text.len();
+            let record_line = self.position.line();
+            let mut quotes = self.dialect.quote_tracker();
+            let mut record_bytes = 0;
+            let mut reported = 0;
+            loop {
+                let part_start = text.len();
+                let read = self.read_part(&mut text).await?;
+                if read == 0 {
+                    self.finished = true;
+                    break;
+                }
+                self.position.set_byte(self.position.byte() + read as u64);
+                let line_ended = text.last() == Some(&b'\n');
+                if line_ended {
+                    self.position.set_line(self.position.line() + 1);
+                }
+                quotes.feed(&text[part_start..]);
+                record_bytes += read as u64;
+                if oversized.is_none() {
+                    let (column, field_bytes) = quotes.longest_field();
+                    let reason = if field_bytes > limits.field {
+                        Some(RejectReason::FieldTooLong { column, limit: limits.field })
+                    } else if record_bytes > limits.record {
+                        Some(RejectReason::RecordTooLong { limit: limits.record })
+                    } else {
+                        None
+                    };
+                    oversized = reason.map(|reason| Reject { line: record_line, reason });
+                }
+                if oversized.is_some() {
+                    text.truncate(record_start);
+                }
+                if record_bytes - reported >= READING_REPORT_BYTES {
+                    reported = record_bytes;
+                    if let Some(observer) = &self.observer {
+                        observer.observe(&ImportEvent::Reading { bytes: self.consumed() });
+                    }
+                }
+                if line_ended && !quotes.in_quotes() {
+                    break;
+                }
+            }
+            let record = &text[record_start..];
+            if oversized.is_some() {
+                self.position.set_record(self.position.record() + 1);
+                break;
+            } else if !record.iter().all(|byte| *byte == b'\r' || *byte == b'\n') {
+                chunk_records += 1;
+                self.position.set_record(self.position.record() + 1);
+            } else if self.drop_blank_records {
+                text.truncate(record_start);
+            }
+            if self.finished {
+                break;
+            }
+        }
+        if text.is_empty() && oversized.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Chunk { text, dialect: self.dialect, first_byte, first_line, end: self.position.clone(), oversized }))
+    }
+
+    /// Reads up to the end of the line, or of what is buffered if the line is longer, so that a
+    /// long line is read a part at a time. Returns how many bytes were read
+    async fn read_part(&mut self, text: &mut Vec<u8>) -> Result<usize> {
+        let buffered = self.reader.fill_buf().await
+            .wrap_err("Unable to read CSV file")
+            .categorize(Error::ImportIo)?;
+        let part = buffered.iter().position(|byte| *byte == b'\n').map_or(buffered.len(), |end| end + 1);
+        text.extend_from_slice(&buffered[..part]);
+        self.reader.consume(part);
+        Ok(part)
+    }
+}
+
+impl Chunk {
+
+    /// Parses the records of the chunk. Positions are reported relative to the whole file.
+    /// Records which cannot be imported are an error, unless told to keep going, in which case
+    /// they are skipped and returned as rejects.
+    async fn parse(self, schema: Arc<Schema>, repair: bool, keep_going: bool, ragged_rows: RaggedRows,
+                   steps: Arc<RecordSteps>) -> Result<ParsedChunk> {
+        let started = Instant::now();
+        let mut csv_reader = self.dialect.reader_builder()
+            .has_headers(false)
+            .flexible(true)
+            .create_reader(RepairUtf8::new(self.text.as_slice(), repair));
+        let line_of = |position: Option<&Position>| {
+            self.first_line + position.map(|position| position.line()).unwrap_or(1) - 1
+        };
+        let mut records = Vec::new();
+        let mut lines = Vec::new();
+        let mut repairs = Utf8Repairs::default();
+        let mut rejects = Vec::new();
+        let mut ragged = RaggedRecords::default();
+        let mut skipped = Vec::new();
+        let mut unsampled = 0;
+        let mut record = ByteRecord::new();
+        loop {
+            match csv_reader.read_byte_record(&mut record).await {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(error) => return Err(Error::ImportData(eyre!(
+                    "Invalid record on line {}: {}", line_of(error.position()), error
+                )))
+            }
+            let line = line_of(record.position());
+            if steps.sampling.as_ref().is_some_and(|sampling| !sampling.keeps_line(line)) {
+                unsampled += 1;
+                continue;
+            }
+            if record.len() != schema.len() {
+                let fields = record.len();
+                match ragged_rows.fit(&mut record, schema.len()) {
+                    Some(kept) => {
+                        ragged.record(line);
+                        if !kept {
+                            skipped.push(Reject { line, reason: RejectReason::FieldCount { fields } });
+                            continue;
+                        }
+                    },
+                    None => {
+                        let reject = Reject { line, reason: RejectReason::FieldCount { fields: record.len() } };
+                        if !keep_going {
+                            return Err(reject.into_error(&schema));
+                        }
+                        rejects.push(reject);
+                        continue;
+                    }
+                }
+            }
+            let start = record.position().map_or(0, Position::byte);
+            // Each U+FFFD in the record's fields is in the text at the same place in their order,
+            // so that those which replaced a sequence are told from those which were there already
+            let mut replacements = csv_reader.get_ref().replacements_between(start, csv_reader.position().byte());
+            for (column, field) in record.iter().enumerate() {
+                let characters = field.windows(REPLACEMENT.len()).filter(|bytes| *bytes == REPLACEMENT).count();
+                let replaced = replacements.by_ref().take(characters).filter(|replaced| *replaced).count();
+                if replaced != 0 {
+                    repairs.record(column, line, replaced);
+                }
+            }
+            match StringRecord::from_byte_record(record.clone()) {
+                Ok(string_record) => {
+                    records.push(string_record);
+                    lines.push(line);
+                },
+                Err(_) => {
+                    // The fields before the invalid one are valid, so the first invalid byte
+                    // of the record's text is the one at fault
+                    let start = start as usize;
+                    let text = &self.text[start..csv_reader.position().byte() as usize];
+                    let invalid = std::str::from_utf8(text).err().map_or(0, |error| error.valid_up_to());
+                    let line_break = b'\n';
+                    let line = line + text[..invalid].iter().filter(|byte| **byte == line_break).count() as u64;
+                    let column = record.iter().position(|field| std::str::from_utf8(field).is_err()).unwrap_or(0);
+                    let byte = self.first_byte + (start + invalid) as u64;
+                    let reject = Reject { line, reason: RejectReason::InvalidUtf8 { column, byte } };
+                    if !keep_going {
+                        return Err(reject.into_error(&schema));
+                    }
+                    rejects.push(reject);
+                }
+            }
+        }
+        // The record ending the chunk, if it was too large to read
+        match self.oversized {
+            Some(reject) if keep_going => rejects.push(reject),
+            Some(reject) if ragged_rows == RaggedRows::Skip => skipped.push(reject),
+            Some(reject) => return Err(reject.into_error(&schema)),
+            None => ()
+        }
+        let read = records.len();
+        let parsed = Instant::now();
+        // Transformed, filtered, checked and profiled here, so that the work is spread over the parsing
+        // tasks. The filter and the checks see the values as they are imported
+        let mut transformed = TransformCounts::default();
+        if let Some(transforms) = &steps.transforms {
+            records = records.into_iter().map(|record| transforms.apply(record, &mut transformed)).collect();
+        }
+        if steps.filter.is_some() || steps.checks.is_some() {
+            let mut kept = Vec::with_capacity(records.len());
+            for (record, line) in records.into_iter().zip(lines) {
+                if steps.filter.as_ref().is_some_and(|filter| !filter.matches(&record)) {
+                    continue;
+                }
+                let checked = match &steps.checks {
+                    Some(checks) => checks.check(line, record),
+                    None => Checked::Valid(record)
+                };
+                match checked {
+                    Checked::Valid(record) => kept.push(record),
+                    Checked::Skipped(mismatch) => skipped.push(Reject::invalid_value(mismatch)),
+                    Checked::Stopped(mismatch) if keep_going => rejects.push(Reject::invalid_value(mismatch)),
+                    Checked::Stopped(mismatch) => return Err(Error::ImportData(eyre::Report::msg(mismatch)))
+                }
+            }
+            records = kept;
+        }
+        let mut profile = DatasetProfile::new(&schema);
+        records.iter().for_each(|record| profile.record(record));
+        let timings = StageTimings { read: parsed - started, transform: parsed.elapsed(), ..StageTimings::default() };
+        Ok(ParsedChunk {
+            records, read, unsampled, transformed, repairs, profile, rejects, ragged, skipped, timings, end: self.end
+        })
+    }
+}
+
+/// Why a record cannot be imported
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RejectReason {
+    /// The record has more or fewer fields than the header
+    FieldCount { fields: usize },
+    /// The record contains invalid UTF-8, the first byte of which is given
+    InvalidUtf8 { column: usize, byte: u64 },
+    /// The type of the table's column cannot hold the value
+    InvalidValue { column: String, value: String, type_name: String },
+    /// A field of the record is larger than the limit, in bytes
+    FieldTooLong { column: usize, limit: u64 },
+    /// The record is larger than the limit, in bytes
+    RecordTooLong { limit: u64 }
+}
+
+/// A record which cannot be imported
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Reject {
+    pub(crate) line: u64,
+    pub(crate) reason: RejectReason
+}
+
+impl Reject {
+
+    fn invalid_value(mismatch: Mismatch) -> Self {
+        let Mismatch { line, column, value, type_name } = mismatch;
+        Self { line, reason: RejectReason::InvalidValue { column, value, type_name } }
+    }
+
+    /// Describes the problem, e.g. line 5 has 3 fields, but the header has 2
+    pub(crate) fn describe(&self, schema: &Schema) -> String {
+        match &self.reason {
+            RejectReason::FieldCount { fields } => format!(
+                "line {} has {} fields, but the header has {}", self.line, fields, schema.len()
+            ),
+            RejectReason::InvalidUtf8 { column, byte } => format!(
+                "line {} has invalid UTF-8 in column {}, at byte {}", self.line, schema.column_name(*column), byte
+            ),
+            RejectReason::InvalidValue { column, value, type_name } => format!(
+                "line {} has {:?} in column {}, which is not a valid {}", self.line, value, column, type_name
+            ),
+            RejectReason::FieldTooLong { column, limit } => format!(
+                "line {} has a {} larger than {}", self.line, field_name(schema, *column), format_bytes(*limit)
+            ),
+            RejectReason::RecordTooLong { limit } => format!(
+                "line {} starts a record larger than {}", self.line, format_bytes(*limit)
+            )
+        }
+    }
+
+    fn into_error(self, schema: &Schema) -> Error {
+        Error::ImportData(match self.reason {
+            RejectReason::FieldCount { fields } => eyre!(
+                "Record on line {} has {} fields, but the header has {}. To pad, cut short or skip such records \
+                instead, pass --ragged-rows", self.line, fields, schema.len()
+            ),
+            RejectReason::InvalidUtf8 { column, byte } => eyre!(
+                "Invalid UTF-8 in column {} on line {}, at byte {}. To replace invalid sequences \
+                with U+FFFD instead, pass --repair-utf8", schema.column_name(column), self.line, byte
+            ),
+            RejectReason::InvalidValue { column, value, type_name } => {
+                eyre::Report::msg(Mismatch { line: self.line, column, value, type_name })
+            },
+            RejectReason::FieldTooLong { column, limit } => eyre!(
+                "The {} on line {} is larger than {}. To read larger fields, raise the max_field_mb config, \
+                or to skip such records instead, pass --ragged-rows skip",
+                field_name(schema, column), self.line, format_bytes(limit)
+            ),
+            RejectReason::RecordTooLong { limit } => eyre!(
+                "The record on line {} is larger than {}. To read larger records, raise the max_record_mb config, \
+                or to skip such records instead, pass --ragged-rows skip", self.line, format_bytes(limit)
+            )
+        })
+    }
+}
+
+/// Names the field by its column, unless the record has more fields than the header
+fn field_name(schema: &Schema, column: usize) -> String {
+    if column < schema.len() {
+        format!("field in column {}", schema.column_name(column))
+    } else {
+        format!("field {}", column + 1)
+    }
+}
+
+/// Copies a CSV file into a table, committing in batches and recording a checkpoint after
+/// each commit. Given a checkpoint, the import resumes from it.
+///
+/// Up to `jobs` chunks of the file are parsed at once, each by a task of its own, while the
+/// parsed batches are inserted in order over a single connection. That way, each checkpoint
+/// follows every row before it.
+#[derive(Clone)]
+pub(crate) struct Import {
+    pub(crate) pool: PgPool,
+    pub(crate) csv_input: CsvInput,
+    pub(crate) dialect: Dialect,
+    pub(crate) table: String,
+    pub(crate) mode: TableMode,
+    pub(crate) checkpoint_path: PathBuf,
+    pub(crate) resume_from: Option<Checkpoint>,
+    pub(crate) jobs: usize,
+    /// Whether invalid UTF-8 is replaced, rather than stopping the import
+    pub(crate) repair_utf8: bool,
+    /// What is done with records whose fields do not match the header
+    pub(crate) ragged_rows: RaggedRows,
+    /// Whether records are sent with COPY, rather than batched INSERT statements. COPY cannot
+    /// be asked to do what [copy_blocker] names
+    pub(crate) copy: bool,
+    /// Whether the INSERT statements prepared and executed are counted
+    pub(crate) count_statements: bool,
+    /// Columns added to the table once the dataset is imported
+    pub(crate) computed: Vec<ComputedColumn>,
+    /// Whether the server has generated columns, which computed columns are added as. Otherwise
+    /// they are filled in once
+    pub(crate) generated_columns: bool,
+    /// Changes made to the values of columns as they are imported
+    pub(crate) transforms: Vec<ColumnTransforms>,
+    /// Only rows matching the filter are imported
+    pub(crate) filter: Option<RowFilter>,
+    /// Whether only a sample of the rows is imported, and how it is chosen. A sample is never
+    /// checkpointed, since a sample at a rate resumed with another seed would not be one sample,
+    /// and a random sample of a count of rows is only inserted once every row is read
+    pub(crate) sampling: Option<Sampling>,
+    /// The key of the table, if one is declared, for which a unique index is ensured
+    pub(crate) conflict: Option<ConflictKey>,
+    /// What is done with values which the types of an existing table's columns cannot hold
+    pub(crate) fix_ups: FixUpPolicy,
+    /// How many rows may be skipped, since they cannot be imported, before the import stops
+    pub(crate) reject_limit: RejectLimit,
+    /// Whether CSV columns which an existing table does not have are left out when adding to
+    /// it, rather than stopping the import
+    pub(crate) ignore_extra_columns: bool,
+    /// Told of events as the import goes, such as to show its progress
+    pub(crate) observer: Arc<dyn ImportObserver>
+}
+
+impl Import {
+
+    /// Imports the dataset, telling the observer of its events. The last is always that the
+    /// import finished, even if it failed, so that partial progress can be shown.
+    pub(crate) async fn read_csv_then_write_to_database(self) -> Result<ImportSummary> {
+        let observer = self.observer.clone();
+        let mut stats = ImportStats::default();
+        let imported = self.import(&mut stats).await;
+        if let Err(error) = &imported {
+            stats.error = Some(error.render());
+        }
+        observer.observe(&ImportEvent::Finished { stats });
+        imported
+    }
+
+    async fn import(self, stats: &mut ImportStats) -> Result<ImportSummary> {
+        let Self {
+            pool, csv_input, dialect, table, mode, checkpoint_path, resume_from, jobs, repair_utf8, ragged_rows,
+            copy, count_statements, computed, generated_columns, transforms, filter, sampling, conflict, fix_ups,
+            reject_limit, ignore_extra_columns, observer
+        } = self;
+        // Only files can be checkpointed, since they can be read again from any position
+        let (mut chunks, file, total_bytes) = match &csv_input {
+            CsvInput::File(path) => {
+                let fingerprint = fingerprint(path).await?;
+                (ChunkReader::open(path, dialect, Position::new()).await?, Some((path, fingerprint)), fingerprint.0)
+            },
+            CsvInput::Url { url, headers } => {
+                let download = Download::start(url, headers).await?;
+                let length = download.length.unwrap_or_default();
+                (ChunkReader::from_download(download, dialect), None, length)
+            },
+            CsvInput::FixedWidth { path, layout } => {
+                let length = fs::metadata(path).await.map_or(0, |metadata| metadata.len());
+                (ChunkReader::fixed_width(path, layout.clone(), dialect.limits()).await?, None, length)
+            },
+            CsvInput::ZipEntry { archive, entry } => {
+                (ChunkReader::from_entry(archive, entry, dialect).await?, None, entry.size)
+            },
+            CsvInput::Parquet { file, .. } => (ChunkReader::parquet(file), None, file.data_bytes()),
+            CsvInput::Remote(dataset) => {
+                (ChunkReader::remote(dataset.clone(), dialect.limits()), None, dataset.estimated_rows())
+            }
+        };
+
+        let schema = Arc::new(chunks.read_schema(repair_utf8).await?);
+        let bound_transforms = bind_transforms(&transforms, &schema)?;
+        let bound_filter = filter.as_ref().map(|filter| filter.bind(&schema)).transpose().categorize(Error::Usage)?;
+        // Every statement is executed on this connection, so that its statement cache keeps
+        // the INSERT statements prepared for the whole import
+        let mut connection = pool.acquire().await
+            .map_err(|error| Error::from_database(error, Error::ImportData))?;
+        // Checked before any rows are inserted, even when resuming, since the table may have changed
+        let mut appended = None;
+        let target = match mode {
+            TableMode::Strict => {
+                let existing = database::table_columns(&table, &mut connection).await
+                    .map_err(|error| Error::from_database(error, Error::ImportData))?;
+                schema.match_existing(&table, &existing).categorize(Error::ImportData)?
+            },
+            TableMode::Append => {
+                let existing = database::table_columns(&table, &mut connection).await
+                    .map_err(|error| Error::from_database(error, Error::ImportData))?;
+                if existing.is_empty() {
+                    InsertTarget::created(&schema)
+                } else {
+                    let (target, columns) = schema.match_appended(&table, &existing, ignore_extra_columns)
+                        .categorize(Error::ImportData)?;
+                    appended = Some(columns);
+                    target
+                }
+            },
+            TableMode::Replace => InsertTarget::created(&schema)
+        };
+        let target = match &conflict {
+            Some(key) => target.with_key(key).categorize(Error::Usage)?,
+            None => target
+        };
+        let steps = Arc::new(RecordSteps {
+            sampling: sampling.filter(|sampling| matches!(sampling, Sampling::Rate { .. })),
+            transforms: bound_transforms,
+            filter: bound_filter,
+            checks: TypeChecks::new(&target, fix_ups)
+        });
+        let mut table_rows = match (&resume_from, file) {
+            (Some(checkpoint), Some((path, _))) => {
+                let (existing_rows,): (i64,) = sqlx::query_as(&format!("SELECT count(*) FROM {}", table))
+                    .fetch_one(&mut connection).await
+                    .map_err(|error| Error::from_database(error, Error::ImportData))?;
+                if existing_rows as u64 != checkpoint.table_rows {
+                    return Err(Error::Usage(eyre!(
+                        "Table {} has {} rows, but had {} when the import stopped. Since it has changed, \
+                        the import cannot be resumed", table, existing_rows, checkpoint.table_rows
+                    )));
+                }
+                chunks = ChunkReader::open(path, dialect, checkpoint.position()).await?;
+                checkpoint.table_rows
+            },
+            _ => {
+                // Marked as a sample, so that it is not mistaken for the whole dataset later on
+                let note = sampling.map(|sampling| format!("Holds a sample of the dataset: {}", sampling));
+                schema.create_table(&table, mode, note.as_deref(), &mut connection).await
+                    .map_err(|error| Error::from_database(error, Error::ImportData))?;
+                let (existing_rows,): (i64,) = sqlx::query_as(&format!("SELECT count(*) FROM {}", table))
+                    .fetch_one(&mut connection).await
+                    .map_err(|error| Error::from_database(error, Error::ImportData))?;
+                existing_rows as u64
+            }
+        };
+        stats.table_rows = Some(table_rows);
+        if conflict.is_some() {
+            database::ensure_unique_key(&table, target.key(), &mut connection).await
+                .map_err(|error| Error::from_database(error, Error::ImportData))?;
+        }
+        // Checked before any rows are inserted, so that a mistake does not cost a whole import
+        for computed in &computed {
+            computed.check(&table, &mut connection).await?;
+        }
+        let prepared_before = if count_statements && !copy {
+            Some(prepared_insert_statements(&mut connection).await?)
+        } else {
+            None
+        };
+        chunks.observer = Some(observer.clone());
+        observer.observe(&ImportEvent::Started {
+            columns: (0..schema.len()).map(|column| schema.column_name(column).to_owned()).collect(),
+            total_bytes,
+            bytes: chunks.consumed()
+        });
+
+        let batch_records = BATCH_RECORDS.min(MAX_PARAMETERS / schema.len().max(1));
+        // Only the last batch can be smaller, so there are at most two distinct statements
+        let full_batch_query = target.insert_statement(&table, batch_records);
+        let mut parsing = VecDeque::with_capacity(jobs);
+        let mut repairs = Utf8Repairs::default();
+        let mut ragged = RaggedRecords::default();
+        // Rows skipped since a column's type cannot hold one of their values, or since they were too large
+        let mut invalid = RaggedRecords::default();
+        let mut oversized = RaggedRecords::default();
+        let mut rejects = RejectTally::default();
+        let mut profile = DatasetProfile::new(&schema);
+        let mut transformed = TransformCounts::default();
+        let mut rows_read = 0;
+        let mut rows_kept = 0;
+        // Every row read, as far as a sample is concerned, and those left out of a sample at a rate
+        let mut rows_seen = 0;
+        let mut rows_unsampled = 0;
+        let mut rows_imported = 0;
+        // A sample of a count of rows is chosen from those left once they are filtered and checked,
+        // and profiled once chosen
+        let mut reservoir = match sampling {
+            Some(Sampling::Rows { rows, seed }) => Some(Reservoir::new(rows, seed)),
+            _ => None
+        };
+        let profiles_chosen_rows = matches!(sampling, Some(Sampling::Rows { .. } | Sampling::First { .. }));
+        let mut conflicts = ConflictCounts::default();
+        let mut timings = StageTimings::default();
+        let mut executed = 0;
+        let mut finished = false;
+        if let CsvInput::Parquet { file, .. } = &csv_input {
+            (executed, conflicts) = insert_parquet(
+                &mut connection, file, &table, &target, &mut table_rows, stats, &mut timings, observer.as_ref()
+            ).await?;
+            finished = true;
+        }
+        if copy {
+            let options = dialect.copy_options()
+                .ok_or_else(|| Error::Usage(eyre!("COPY cannot read CSV files {}", dialect)))?;
+            let statement = target.copy_statement(&table, &options);
+            chunks.drop_blank_records = true;
+            let mut records = chunks.position.record();
+            while !finished {
+                let committed_rows = table_rows;
+                // Each group of chunks is copied and committed together, after which a checkpoint is recorded
+                let mut transaction = connection.begin().await
+                    .map_err(|error| Error::from_database(error, Error::ImportData))?;
+                let copied = copy_chunks(
+                    &mut transaction, &statement, &mut chunks, &schema, batch_records, &mut timings, |chunk, consumed| {
+                        stats.rows += chunk.end.record() - records;
+                        records = chunk.end.record();
+                        observer.observe(&ImportEvent::Progress { rows: stats.rows, bytes: consumed });
+                    }
+                ).await;
+                let (copied, end) = match copied {
+                    Ok(copied) => copied,
+                    Err(error) => {
+                        // A failed COPY leaves the connection out of step with the server, so it is
+                        // closed rather than returned to the pool
+                        drop(transaction);
+                        drop(connection.detach());
+                        return Err(error);
+                    }
+                };
+                table_rows += copied;
+                executed += 1;
+                let committing = Instant::now();
+                transaction.commit().await
+                    .map_err(|error| Error::from_database(error, Error::ImportData))?;
+                timings.commit += committing.elapsed();
+                stats.table_rows = Some(table_rows);
+                if table_rows > committed_rows {
+                    observer.observe(&ImportEvent::BatchCommitted { rows: committed_rows..table_rows });
+                }
+                finished = chunks.finished;
+                if let (false, Some(end), Some((path, fingerprint))) = (finished, end, file) {
+                    save_checkpoint(&checkpoint_path, path, fingerprint, &table, &end, table_rows).await?;
+                }
+            }
+        }
+        while !finished {
+            let committed_rows = table_rows;
+            // Each group of batches is committed together, after which a checkpoint is recorded
+            let mut transaction = connection.begin().await
+                .map_err(|error| Error::from_database(error, Error::ImportData))?;
+            let mut batches = 0;
+            let mut end = None;
+            // Filtered chunks are gathered into full batches, which keeps to two distinct statements
+            // between checkpoints. Whatever is left is inserted before committing
+            let mut kept = Vec::new();
+            while batches < CHECKPOINT_BATCHES {
+                while parsing.len() < jobs {
+                    let reading = Instant::now();
+                    let chunk = chunks.next_chunk(batch_records).await?;
+                    timings.read += reading.elapsed();
+                    match chunk {
+                        Some(chunk) => parsing.push_back(task::spawn(
+                            chunk.parse(schema.clone(), repair_utf8, false, ragged_rows, steps.clone())
+                        )),
+                        None => break
+                    }
+                }
+                let batch = match parsing.pop_front() {
+                    Some(parsed) => parsed.await?,
+                    None => {
+                        // A dataset smaller than the sample is checked once it ends
+                        rejects.check(&reject_limit, true)?;
+                        finished = true;
+                        // A random sample is only known once every row is read
+                        if let Some(reservoir) = reservoir.take() {
+                            let chosen = reservoir.into_rows();
+                            chosen.iter().for_each(|record| profile.record(record));
+                            rows_imported += chosen.len() as u64;
+                            kept.extend(chosen);
+                            let inserting = Instant::now();
+                            let (statements, counts) = insert_full_batches(
+                                &mut transaction, &table, &full_batch_query, &mut kept, batch_records, &target
+                            ).await?;
+                            timings.database += inserting.elapsed();
+                            executed += statements;
+                            table_rows += counts.inserted;
+                            conflicts.add(counts);
+                            stats.rows = rows_imported;
+                        }
+                        break;
+                    }
+                };
+                repairs.extend(&batch.repairs);
+                ragged.extend(&batch.ragged);
+                timings.merge(&batch.timings);
+                if !profiles_chosen_rows {
+                    profile.extend(&batch.profile);
+                }
+                transformed.extend(&batch.transformed);
+                // Only rows with values which the types cannot hold were parsed, and so are among those read
+                rejects.read(batch.read as u64);
+                rows_seen += (batch.read + batch.unsampled) as u64;
+                rows_unsampled += batch.unsampled as u64;
+                for reject in &batch.skipped {
+                    match reject.reason {
+                        RejectReason::InvalidValue { .. } => invalid.record(reject.line),
+                        RejectReason::FieldTooLong { .. } | RejectReason::RecordTooLong { .. } => {
+                            oversized.record(reject.line);
+                            rejects.read(1);
+                            rows_seen += 1;
+                        },
+                        RejectReason::FieldCount { .. } | RejectReason::InvalidUtf8 { .. } => {
+                            rejects.read(1);
+                            rows_seen += 1;
+                        }
+                    }
+                    rejects.reject(&reject.reason);
+                    stats.rejected += 1;
+                    observer.observe(&ImportEvent::RowRejected { line: reject.line, reason: reject.describe(&schema) });
+                }
+                rejects.check(&reject_limit, false)?;
+                if batch.read == 0 {
+                    continue;
+                }
+                let batch_kept = batch.records.len() as u64;
+                rows_read += batch.read as u64;
+                rows_kept += batch_kept;
+                let mut records = batch.records;
+                match (&mut reservoir, sampling) {
+                    (Some(reservoir), _) => records.drain(..).for_each(|record| reservoir.offer(record)),
+                    (None, Some(Sampling::First { rows })) => {
+                        records.truncate((rows - rows_imported) as usize);
+                        records.iter().for_each(|record| profile.record(record));
+                        // Nothing more is read once the sample is full
+                        finished = rows_imported + records.len() as u64 == rows;
+                    },
+                    _ => ()
+                }
+                rows_imported += records.len() as u64;
+                kept.extend(records);
+                let inserting = Instant::now();
+                let (statements, counts) = insert_full_batches(
+                    &mut transaction, &table, &full_batch_query, &mut kept, batch_records, &target
+                ).await?;
+                timings.database += inserting.elapsed();
+                executed += statements;
+                table_rows += counts.inserted;
+                conflicts.add(counts);
+                stats.rows = rows_imported;
+                observer.observe(&ImportEvent::Progress { rows: rows_imported, bytes: chunks.consumed() });
+                batches += 1;
+                end = Some(batch.end);
+                if finished {
+                    break;
+                }
+            }
+            if !kept.is_empty() {
+                let partial_batch_query = target.insert_statement(&table, kept.len());
+                let inserting = Instant::now();
+                let counts = insert_batch(&mut transaction, &table, &partial_batch_query, &kept, &target).await?;
+                timings.database += inserting.elapsed();
+                executed += 1;
+                table_rows += counts.inserted;
+                conflicts.add(counts);
+            }
+            let committing = Instant::now();
+            transaction.commit().await
+                .map_err(|error| Error::from_database(error, Error::ImportData))?;
+            timings.commit += committing.elapsed();
+            stats.table_rows = Some(table_rows);
+            if table_rows > committed_rows {
+                observer.observe(&ImportEvent::BatchCommitted { rows: committed_rows..table_rows });
+            }
+
+            if batches < CHECKPOINT_BATCHES || sampling.is_some() {
+                continue;
+            }
+            if let (Some(end), Some((path, fingerprint))) = (end, file) {
+                save_checkpoint(&checkpoint_path, path, fingerprint, &table, &end, table_rows).await?;
+            }
+        }
+        if checkpoint_path.exists().await {
+            fs::remove_file(&checkpoint_path).await.categorize(Error::ImportIo)?;
+        }
+        for computed in &computed {
+            computed.add_to(&table, generated_columns, &mut connection).await?;
+        }
+        let statements = match prepared_before {
+            Some(prepared_before) => Some(StatementCounts {
+                prepared: prepared_insert_statements(&mut connection).await? - prepared_before,
+                executed
+            }),
+            None => None
+        };
+        // Given back to the pool before the import finishes, so that the query, which waits for
+        // it, can run even when the pool has only one connection
+        drop(connection);
+        let invalid = (invalid.count != 0).then(|| format!(
+            "Skipped {} rows with values which their columns' types cannot hold, first on lines {}",
+            invalid.count, invalid.lines.iter().join(", ")
+        ));
+        Ok(ImportSummary {
+            warnings: charset::transcoded_warning(schema.transcoded()).into_iter()
+                .chain(repairs.warning(&schema)).chain(ragged.warning(ragged_rows, &schema))
+                .chain(invalid).chain(oversized.oversized_warning()).chain(rejects.summary())
+                .chain(appended.and_then(|appended| appended.warning(&table))).collect(),
+            statements,
+            rows: Some(table_rows),
+            // Records sent with COPY are never parsed, so nothing is known of their values, and
+            // the typed values of Parquet files are not profiled as text
+            profile: (!copy && !matches!(csv_input, CsvInput::Parquet { .. })).then_some(profile),
+            filtered: filter.map(|_| FilteredRows { read: rows_read, kept: rows_kept }),
+            sampled: sampling.map(|sampling| SampledRows {
+                sampling,
+                read: rows_seen,
+                sampled: match sampling {
+                    Sampling::Rate { .. } => rows_seen - rows_unsampled,
+                    Sampling::Rows { .. } | Sampling::First { .. } => rows_imported
+                },
+                imported: rows_imported
+            }),
+            transformed: steps.transforms.as_ref()
+                .map_or_else(Vec::new, |bound| bound.changed_values(&transformed, &schema)),
+            conflicts: target.resolves_conflicts().then_some(conflicts),
+            timings
+        })
+    }
+}
+
+/// Copies a group of chunks with one COPY statement, telling the closure of each chunk sent and
+/// how much of the input has been read. Returns how many rows were copied, and where the last
+/// chunk ended, if any were sent. Postgres parses the records, so that is timed with the database
+async fn copy_chunks(transaction: &mut Transaction<'_, Postgres>, statement: &str, chunks: &mut ChunkReader,
+                     schema: &Schema, batch_records: usize, timings: &mut StageTimings,
+                     mut sent: impl FnMut(&Chunk, u64)) -> Result<(u64, Option<Position>)> {
+    let copy_failed = |error| CopyFailed::mark(Error::from_database(error, Error::ImportData));
+    let mut copy_in = transaction.copy_in_raw(statement).await.map_err(copy_failed)?;
+    let mut end = None;
+    for _ in 0..CHECKPOINT_BATCHES {
+        let reading = Instant::now();
+        let chunk = chunks.next_chunk(batch_records).await?;
+        timings.read += reading.elapsed();
+        let chunk = match chunk {
+            Some(chunk) => chunk,
+            None => break
+        };
+        let sending = Instant::now();
+        copy_in.send(chunk.text.as_slice()).await.map_err(copy_failed)?;
+        timings.database += sending.elapsed();
+        if let Some(reject) = chunk.oversized {
+            let error = reject.into_error(schema);
+            copy_in.abort(error.to_string()).await.map_err(copy_failed)?;
+            return Err(error);
+        }
+        sent(&chunk, chunks.consumed());
+        end = Some(chunk.end);
+    }
+    let finishing = Instant::now();
+    let copied = copy_in.finish().await.map_err(copy_failed)?;
+    timings.database += finishing.elapsed();
+    Ok((copied, end))
+}
+
+/// Records a checkpoint, after every record before the end is committed
+async fn save_checkpoint(checkpoint_path: &Path, input: &Path, fingerprint: (u64, u64, u32), table: &str,
+                         end: &Position, table_rows: u64) -> Result<()> {
+    let (file_size, modified_secs, modified_nanos) = fingerprint;
+    let checkpoint = Checkpoint {
+        input: input.to_path_buf().into(),
+        table: String::from(table),
+        file_size,
+        modified_secs,
+        modified_nanos,
+        byte: end.byte(),
+        line: end.line(),
+        record: end.record(),
+        table_rows
+    };
+    checkpoint.save(checkpoint_path).await
+        .wrap_err("Unable to record import checkpoint")
+        .categorize(Error::ImportIo)
+}
+
+/// Inserts a batch of records in one statement. Values are bound as parameters, so they need
+/// no escaping. The statement is persistent, so it is only prepared the first time it is executed.
+/// Returns how many rows were inserted, and updated or left out if the target resolves conflicts.
+/// Records replaced by a later one of the same key count as updated
+async fn insert_batch(transaction: &mut Transaction<'_, Postgres>, table: &str, statement: &str,
+                      records: &[StringRecord], target: &InsertTarget) -> Result<ConflictCounts> {
+    let written = target.written_records(records);
+    let replaced = (records.len() - written.len()) as u64;
+    // The statement is built for every record of the batch, so another is needed for fewer
+    let statement = match replaced {
+        0 => Cow::Borrowed(statement),
+        _ => Cow::Owned(target.insert_statement(table, written.len()))
+    };
+    let values = written.iter().flat_map(|record| target.values(record));
+    if !target.resolves_conflicts() {
+        let mut query = sqlx::query(&statement);
+        for value in values {
+            query = query.bind(value);
+        }
+        query.execute(&mut *transaction).await
+            .map_err(|error| Error::from_database(error, Error::ImportData))?;
+        return Ok(ConflictCounts { inserted: records.len() as u64, ..ConflictCounts::default() });
+    }
+    // Each row written returns whether it was inserted. Rows left out return nothing
+    let mut query = sqlx::query_scalar(&statement);
+    for value in values {
+        query = query.bind(value);
+    }
+    let returned: Vec<bool> = query.fetch_all(&mut *transaction).await
+        .map_err(|error| Error::from_database(error, Error::ImportData))?;
+    let inserted = returned.iter().filter(|inserted| **inserted).count() as u64;
+    Ok(ConflictCounts {
+        inserted,
+        updated: returned.len() as u64 - inserted + replaced,
+        skipped: (written.len() - returned.len()) as u64
+    })
+}
+
+/// Inserts the rows of the Parquet file a batch at a time, binding an array of each column's
+/// typed values, and commits once each row group is inserted. Returns how many statements were
+/// executed, and what they did
+#[allow(clippy::too_many_arguments)]
+async fn insert_parquet(connection: &mut PoolConnection<Postgres>, file: &ParquetFile, table: &str,
+                        target: &InsertTarget, table_rows: &mut u64, stats: &mut ImportStats,
+                        timings: &mut StageTimings, observer: &dyn ImportObserver) -> Result<(u64, ConflictCounts)> {
+    let statement = target.unnest_statement(table, &file.array_types());
+    let mut executed = 0;
+    let mut conflicts = ConflictCounts::default();
+    let mut consumed = 0;
+    for row_group in 0..file.row_groups() {
+        let committed_rows = *table_rows;
+        let mut transaction = connection.begin().await
+            .map_err(|error| Error::from_database(error, Error::ImportData))?;
+        let mut batches = file.read_row_group(row_group, BATCH_RECORDS);
+        loop {
+            let reading = Instant::now();
+            let batch = match batches.next_batch().await? {
+                Some(batch) => batch,
+                None => break
+            };
+            let written = match target.writes_last_of_each_key() {
+                true => parquet::last_of_each_key(&batch, &target.key_fields())?,
+                false => batch.clone()
+            };
+            let values = file.values(&written)?;
+            timings.read += reading.elapsed();
+            let inserting = Instant::now();
+            let mut counts = insert_values(&mut transaction, &statement, values, written.num_rows(), target).await?;
+            // Rows replaced by a later one of the same key count as updated
+            counts.updated += (batch.num_rows() - written.num_rows()) as u64;
+            timings.database += inserting.elapsed();
+            executed += 1;
+            *table_rows += counts.inserted;
+            conflicts.add(counts);
+            stats.rows += batch.num_rows() as u64;
+            observer.observe(&ImportEvent::Progress { rows: stats.rows, bytes: consumed });
+        }
+        let committing = Instant::now();
+        transaction.commit().await
+            .map_err(|error| Error::from_database(error, Error::ImportData))?;
+        timings.commit += committing.elapsed();
+        consumed += file.row_group_bytes(row_group);
+        stats.table_rows = Some(*table_rows);
+        observer.observe(&ImportEvent::Progress { rows: stats.rows, bytes: consumed });
+        if *table_rows > committed_rows {
+            observer.observe(&ImportEvent::BatchCommitted { rows: committed_rows..*table_rows });
+        }
+    }
+    Ok((executed, conflicts))
+}
+
+/// Inserts the rows of a batch of typed values in one statement, binding an array of each
+/// column's values in the order of the table's columns. Returns what it did, as [insert_batch] does
+async fn insert_values(transaction: &mut Transaction<'_, Postgres>, statement: &str, values: Vec<ColumnValues>,
+                       rows: usize, target: &InsertTarget) -> Result<ConflictCounts> {
+    let mut values = values.into_iter().map(Some).collect::<Vec<_>>();
+    let mut arguments = PgArguments::default();
+    for column in 0..target.len() {
+        if let Some(values) = values[target.field(column)].take() {
+            values.add_to(&mut arguments);
+        }
+    }
+    if !target.resolves_conflicts() {
+        sqlx::query_with(statement, arguments).execute(&mut *transaction).await
+            .map_err(|error| Error::from_database(error, Error::ImportData))?;
+        return Ok(ConflictCounts { inserted: rows as u64, ..ConflictCounts::default() });
+    }
+    // Each row written returns whether it was inserted. Rows left out return nothing
+    let returned: Vec<bool> = sqlx::query_scalar_with(statement, arguments).fetch_all(&mut *transaction).await
+        .map_err(|error| Error::from_database(error, Error::ImportData))?;
+    let inserted = returned.iter().filter(|inserted| **inserted).count() as u64;
+    Ok(ConflictCounts {
+        inserted,
+        updated: returned.len() as u64 - inserted,
+        skipped: (rows - returned.len()) as u64
+    })
+}
+
+/// Inserts as many full batches as there are from the start of the records, leaving the rest.
+/// Returns how many statements were executed, and what they did
+async fn insert_full_batches(transaction: &mut Transaction<'_, Postgres>, table: &str, statement: &str,
+                             records: &mut Vec<StringRecord>, batch_records: usize,
+                             target: &InsertTarget) -> Result<(u64, ConflictCounts)> {
+    let mut executed = 0;
+    let mut conflicts = ConflictCounts::default();
+    while records.len() >= batch_records {
+        conflicts.add(insert_batch(transaction, table, statement, &records[..batch_records], target).await?);
+        records.drain(..batch_records);
+        executed += 1;
+    }
+    Ok((executed, conflicts))
+}
+
+/// How many INSERT statements are prepared on the connection
+async fn prepared_insert_statements(connection: &mut PoolConnection<Postgres>) -> Result<u64> {
+    let (prepared,): (i64,) = sqlx::query_as(
+        "SELECT count(*) FROM pg_prepared_statements WHERE statement LIKE 'INSERT INTO %'"
+    ).fetch_one(connection).await.map_err(|error| Error::from_database(error, Error::ImportData))?;
+    Ok(prepared as u64)
+}
+
+/// Reads the columns of the dataset from its header, or its layout
+pub(crate) async fn read_columns(csv_input: &CsvInput, dialect: Dialect, repair_utf8: bool) -> Result<Schema> {
+    if let CsvInput::Parquet { file, .. } = csv_input {
+        return ChunkReader::parquet(file).read_schema(repair_utf8).await;
+    }
+    ChunkReader::start(csv_input, dialect).await?.read_schema(repair_utf8).await
+}
+
+/// Reads the header and the first record of a file as an import would, so that a file which
+/// cannot be imported at all is found before the rest is imported in the background. For a
+/// Parquet file, that is the first batch of rows
+pub(crate) async fn check_start(csv_input: &CsvInput, dialect: Dialect, repair_utf8: bool,
+                                 ragged_rows: RaggedRows) -> Result<()> {
+    if let CsvInput::Parquet { file, .. } = csv_input {
+        if file.row_groups() > 0 {
+            if let Some(batch) = file.read_row_group(0, 1).next_batch().await? {
+                file.values(&batch)?;
+            }
+        }
+        return Ok(());
+    }
+    let mut chunks = ChunkReader::start(csv_input, dialect).await?;
+    let schema = Arc::new(chunks.read_schema(repair_utf8).await?);
+    if let Some(chunk) = chunks.next_chunk(1).await? {
+        chunk.parse(schema, repair_utf8, false, ragged_rows, Arc::default()).await?;
+    }
+    Ok(())
+}
+
+/// Reads and parses the whole dataset as an import would, but without creating the table or
+/// inserting anything. Records which would stop an import are rejected rather than an error,
+/// so that all of them are found.
+pub(crate) async fn dry_run(csv_input: CsvInput, dialect: Dialect, jobs: usize, repair_utf8: bool,
+                            ragged_rows: RaggedRows, transforms: &[ColumnTransforms],
+                            filter: Option<&RowFilter>) -> Result<Validation> {
+    let mut chunks = ChunkReader::start(&csv_input, dialect).await?;
+    let schema = Arc::new(chunks.read_schema(repair_utf8).await?);
+    let steps = Arc::new(RecordSteps {
+        sampling: None,
+        transforms: bind_transforms(transforms, &schema)?,
+        filter: filter.map(|filter| filter.bind(&schema)).transpose().categorize(Error::Usage)?,
+        checks: None
+    });
+
+    let batch_records = BATCH_RECORDS.min(MAX_PARAMETERS / schema.len().max(1));
+    let mut validation = Validation::new(schema.clone());
+    let mut parsing = VecDeque::with_capacity(jobs);
+    let mut repairs = Utf8Repairs::default();
+    let mut ragged = RaggedRecords::default();
+    let mut transformed = TransformCounts::default();
+    loop {
+        while parsing.len() < jobs {
+            match chunks.next_chunk(batch_records).await? {
+                Some(chunk) => parsing.push_back(task::spawn(
+                    chunk.parse(schema.clone(), repair_utf8, true, ragged_rows, steps.clone())
+                )),
+                None => break
+            }
+        }
+        let batch = match parsing.pop_front() {
+            Some(parsed) => parsed.await?,
+            None => break
+        };
+        repairs.extend(&batch.repairs);
+        ragged.extend(&batch.ragged);
+        validation.add_profile(&batch.profile);
+        transformed.extend(&batch.transformed);
+        if steps.filter.is_some() {
+            validation.filter_out((batch.read - batch.records.len()) as u64);
+        }
+        batch.rejects.iter().for_each(|reject| validation.reject(reject));
+    }
+    if let Some(transforms) = &steps.transforms {
+        validation.transformed = transforms.changed_values(&transformed, &schema);
+    }
+    validation.warnings.extend(charset::transcoded_warning(schema.transcoded()));
+    validation.warnings.extend(repairs.warning(&schema));
+    validation.warnings.extend(ragged.warning(ragged_rows, &schema));
+    Ok(validation)
+}
+
+/// The records of a dataset, parsed, transformed and filtered as an import would before
+/// inserting them, batch by batch. A record which would stop an import is an error
+pub(crate) struct ParsedRecords {
+    schema: Arc<Schema>,
+    chunks: ChunkReader,
+    parsing: VecDeque<task::JoinHandle<Result<ParsedChunk>>>,
+    jobs: usize,
+    batch_records: usize,
+    repair_utf8: bool,
+    ragged_rows: RaggedRows,
+    steps: Arc<RecordSteps>,
+    repairs: Utf8Repairs,
+    ragged: RaggedRecords,
+    /// The records skipped since they were too large to read
+    oversized: RaggedRecords,
+    transformed: TransformCounts,
+    rows_read: u64,
+    rows_kept: u64
+}
+
+impl ParsedRecords {
+
+    pub(crate) async fn open(csv_input: &CsvInput, dialect: Dialect, jobs: usize, repair_utf8: bool,
+                             ragged_rows: RaggedRows, transforms: &[ColumnTransforms],
+                             filter: Option<&RowFilter>) -> Result<Self> {
+        let mut chunks = ChunkReader::start(csv_input, dialect).await?;
+        let schema = Arc::new(chunks.read_schema(repair_utf8).await?);
+        let steps = Arc::new(RecordSteps {
+            sampling: None,
+            transforms: bind_transforms(transforms, &schema)?,
+            filter: filter.map(|filter| filter.bind(&schema)).transpose().categorize(Error::Usage)?,
+            checks: None
+        });
+        Ok(Self {
+            batch_records: BATCH_RECORDS.min(MAX_PARAMETERS / schema.len().max(1)),
+            schema, chunks, parsing: VecDeque::with_capacity(jobs), jobs, repair_utf8, ragged_rows, steps,
+            repairs: Utf8Repairs::default(),
+            ragged: RaggedRecords::default(),
+            oversized: RaggedRecords::default(),
+            transformed: TransformCounts::default(),
+            rows_read: 0,
+            rows_kept: 0
+        })
+    }
+
+    /// The columns of the dataset, as in its header
+    pub(crate) fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// The records of the next batch, which are none if the filter left out all of them. None
+    /// once every record has been read
+    pub(crate) async fn next_batch(&mut self) -> Result<Option<Vec<StringRecord>>> {
+        while self.parsing.len() < self.jobs {
+            match self.chunks.next_chunk(self.batch_records).await? {
+                Some(chunk) => self.parsing.push_back(task::spawn(chunk.parse(
+                    self.schema.clone(), self.repair_utf8, false, self.ragged_rows, self.steps.clone()
+                ))),
+                None => break
+            }
+        }
+        let batch = match self.parsing.pop_front() {
+            Some(parsed) => parsed.await?,
+            None => return Ok(None)
+        };
+        self.repairs.extend(&batch.repairs);
+        self.ragged.extend(&batch.ragged);
+        batch.skipped.iter()
+            .filter(|reject| {
+                matches!(reject.reason, RejectReason::FieldTooLong { .. } | RejectReason::RecordTooLong { .. })
+            })
+            .for_each(|reject| self.oversized.record(reject.line));
+        self.transformed.extend(&batch.transformed);
+        self.rows_read += batch.read as u64;
+        self.rows_kept += batch.records.len() as u64;
+        Ok(Some(batch.records))
+    }
+
+    /// What happened to the records, once all of them have been read
+    pub(crate) fn summary(&self) -> ImportSummary {
+        ImportSummary {
+            warnings: charset::transcoded_warning(self.schema.transcoded()).into_iter()
+                .chain(self.repairs.warning(&self.schema))
+                .chain(self.ragged.warning(self.ragged_rows, &self.schema))
+                .chain(self.oversized.oversized_warning()).collect(),
+            rows: Some(self.rows_kept),
+            filtered: self.steps.filter.as_ref().map(|_| FilteredRows { read: self.rows_read, kept: self.rows_kept }),
+            transformed: self.steps.transforms.as_ref()
+                .map_or_else(Vec::new, |bound| bound.changed_values(&self.transformed, &self.schema)),
+            ..ImportSummary::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_insert_query() {
+        let schema: Schema = ["name", "age"].into_iter().collect();
+        let target = InsertTarget::created(&schema);
+        assert_eq!("INSERT INTO data (name, age) VALUES ($1, $2), ($3, $4)", target.insert_statement("data", 2));
+    }
+
+    #[test]
+    fn copy_statement() {
+        let schema: Schema = ["name", "age"].into_iter().collect();
+        let target = InsertTarget::created(&schema);
+        let options = Dialect::default().copy_options().unwrap();
+        assert_eq!(
+            "COPY data (name, age) FROM STDIN WITH (FORMAT csv, QUOTE '\"', ESCAPE '\"', FORCE_NOT_NULL (name, age))",
+            target.copy_statement("data", &options)
+        );
+    }
+
+    #[test]
+    fn strategy_is_chosen_by_size() {
+        let threshold = COPY_THRESHOLD_MB * 1_000_000;
+        let chosen = |strategy: Strategy, size, blocker| strategy.choose(size, threshold, blocker).unwrap().0;
+        assert_eq!(Strategy::Insert, chosen(Strategy::Auto, Some(threshold - 1), None));
+        assert_eq!(Strategy::Copy, chosen(Strategy::Auto, Some(threshold), None));
+        assert_eq!(Strategy::Insert, chosen(Strategy::Auto, None, None));
+        assert_eq!(Strategy::Insert, chosen(Strategy::Auto, Some(threshold), Some("filter rows")));
+        assert_eq!(Strategy::Copy, chosen(Strategy::Copy, Some(0), None));
+        assert_eq!(Strategy::Insert, chosen(Strategy::Insert, Some(threshold), None));
+        assert_eq!("since the file is smaller than the COPY threshold of 10.0 MB",
+                   Strategy::Auto.choose(Some(threshold - 1), threshold, None).unwrap().1);
+        assert!(Strategy::Copy.choose(Some(threshold), threshold, Some("filter rows")).is_err());
+
+        let blocker = |repair_utf8, ragged_rows| {
+            copy_blocker(Dialect::default(), repair_utf8, ragged_rows, false, false)
+        };
+        assert_eq!(None, blocker(false, RaggedRows::Strict));
+        assert_eq!(Some("repair invalid UTF-8"), blocker(true, RaggedRows::Strict));
+        assert_eq!(Some("fit records to the header"), blocker(false, RaggedRows::Pad));
+        assert_eq!(Some("transform values"), copy_blocker(Dialect::default(), false, RaggedRows::Strict, true, true));
+    }
+
+    #[async_std::test]
+    async fn chunks_end_on_record_boundaries() -> Result<()> {
+        let tempdir = tempfile::tempdir().unwrap();
+        let input = PathBuf::from(tempdir.path().join("input.csv"));
+        fs::write(&input, "a,b\n1,\"x\ny\"\n\n2,\"\"\"\"\n3,z\n4,\"w\n").await.unwrap();
+        let mut start = Position::new();
+        start.set_byte(4).set_line(2).set_record(1);
+        let mut chunks = ChunkReader::open(&input, Dialect::default(), start).await?;
+
+        let chunk = chunks.next_chunk(2).await?.unwrap();
+        assert_eq!(b"1,\"x\ny\"\n\n2,\"\"\"\"\n" as &[u8], chunk.text);
+        assert_eq!((2, 6, 3), (chunk.first_line, chunk.end.line(), chunk.end.record()));
+        let schema = Arc::new(["a", "b"].into_iter().collect());
+        let parsed = chunk.parse(schema, false, false, RaggedRows::Strict, Arc::default()).await?;
+        assert_eq!(vec![vec!["1", "x\ny"], vec!["2", "\""]], parsed.records.iter()
+            .map(|record| record.iter().collect::<Vec<_>>()).collect::<Vec<_>>());
+
+        let chunk = chunks.next_chunk(2).await?.unwrap();
+        assert_eq!(b"3,z\n4,\"w\n" as &[u8], chunk.text);
+        assert!(chunks.next_chunk(2).await?.is_none());
+        let schema = Arc::new(["a", "b", "c"].into_iter().collect());
+        let parsed = chunk.parse(schema, false, false, RaggedRows::Strict, Arc::default()).await;
+        assert!(parsed.is_err_and(|error| error.to_string().starts_with("Record on line 6 has 2 fields")));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn chunks_follow_escaped_quotes() -> Result<()> {
+        let tempdir = tempfile::tempdir().unwrap();
+        let input = PathBuf::from(tempdir.path().join("input.csv"));
+        // The escaped quote leaves the field open, so the first line break is part of it
+        fs::write(&input, "a,b\n1,\"x\\\"\n2,y\"\n3,z\n").await.unwrap();
+        let dialect = Dialect::new('"', Some('\\'), false).unwrap();
+        let mut chunks = ChunkReader::open(&input, dialect, Position::new()).await?;
+        let schema = chunks.read_schema(false).await?;
+        assert_eq!(("a", "b"), (schema.column_name(0), schema.column_name(1)));
+
+        let chunk = chunks.next_chunk(1).await?.unwrap();
+        assert_eq!((2, 4), (chunk.first_line, chunk.end.line()));
+        let schema = Arc::new(["a", "b"].into_iter().collect());
+        let parsed = chunk.parse(schema, false, false, RaggedRows::Strict, Arc::default()).await?;
+        assert_eq!(vec!["1", "x\"\n2,y"], parsed.records[0].iter().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn read_windows_1252_header_of_utf8_records() -> Result<()> {
+        let tempdir = tempfile::tempdir().unwrap();
+        let input = PathBuf::from(tempdir.path().join("input.csv"));
+        fs::write(&input, b"id,\"Caf\xe9\"\n1,cr\xc3\xa8me\n").await.unwrap();
+        let mut chunks = ChunkReader::open(&input, Dialect::default(), Position::new()).await?;
+        let schema = Arc::new(chunks.read_schema(false).await?);
+        assert_eq!(("id", "Café"), (schema.column_name(0), schema.column_name(1)));
+        assert_eq!(b"Caf\xe9", schema.transcoded()[0].raw.as_slice());
+
+        let chunk = chunks.next_chunk(1).await?.unwrap();
+        let parsed = chunk.parse(schema, false, false, RaggedRows::Strict, Arc::default()).await?;
+        assert_eq!(vec!["1", "crème"], parsed.records[0].iter().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn oversized_records_are_left_out() -> Result<()> {
+        let tempdir = tempfile::tempdir().unwrap();
+        let input = PathBuf::from(tempdir.path().join("input.csv"));
+        // Longer than the reader's buffer, with a quoted line break, so that it is read in parts
+        let field = format!("\"{}\n{}\"", "x".repeat(20_000), "y".repeat(20_000));
+        let record = vec!["z".repeat(600); 100].join(",");
+        fs::write(&input, format!("a,b\n1,2\n3,{}\n4,5\n{}\n6,7\n", field, record)).await.unwrap();
+        let dialect = Dialect::default().with_limits(SizeLimits { field: 5_000, record: 50_000 });
+        let mut start = Position::new();
+        start.set_byte(4).set_line(2).set_record(1);
+        let mut chunks = ChunkReader::open(&input, dialect, start).await?;
+        let schema: Arc<Schema> = Arc::new(["a", "b"].into_iter().collect());
+
+        // The oversized record ends the chunk, and the next starts after it
+        let chunk = chunks.next_chunk(10).await?.unwrap();
+        assert_eq!(b"1,2\n" as &[u8], chunk.text);
+        assert_eq!((5, 3), (chunk.end.line(), chunk.end.record()));
+        let error = chunk.parse(schema.clone(), false, false, RaggedRows::Strict, Arc::default()).await;
+        assert!(error.is_err_and(|error| error.to_string().starts_with(
+            "The field in column b on line 3 is larger than 5.0 KB. To read larger fields"
+        )));
+
+        let chunk = chunks.next_chunk(10).await?.unwrap();
+        assert_eq!(b"4,5\n" as &[u8], chunk.text);
+        let parsed = chunk.parse(schema.clone(), false, false, RaggedRows::Skip, Arc::default()).await?;
+        assert_eq!(1, parsed.records.len());
+        assert_eq!(vec![Reject { line: 6, reason: RejectReason::RecordTooLong { limit: 50_000 } }], parsed.skipped);
+
+        let chunk = chunks.next_chunk(10).await?.unwrap();
+        assert_eq!((b"6,7\n" as &[u8], 8), (chunk.text.as_slice(), chunk.end.line()));
+        assert!(chunk.oversized.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn csv_input_stem_and_display() {
+        let file = CsvInput::File(PathBuf::from("/data/sales.2022.csv"));
+        assert_eq!(Some(String::from("sales.2022")), file.stem());
+        let url = |url: &str| CsvInput::Url { url: Url::parse(url).unwrap(), headers: Vec::new() };
+        let signed = url("https://example.com/drops/sales.csv?signature=secret#part");
+        assert_eq!(Some(String::from("sales")), signed.stem());
+        assert_eq!("https://example.com/drops/sales.csv", signed.to_string());
+        assert_eq!(None, url("https://example.com/").stem());
+    }
+
+    async fn parse(text: &[u8], repair: bool) -> Result<ParsedChunk> {
+        parse_ragged(text, repair, RaggedRows::Strict).await
+    }
+
+    async fn parse_ragged(text: &[u8], repair: bool, ragged_rows: RaggedRows) -> Result<ParsedChunk> {
+        let mut end = Position::new();
+        end.set_byte(100 + text.len() as u64);
+        let chunk = Chunk {
+            text: text.to_vec(), dialect: Dialect::default(), first_byte: 100, first_line: 10, end, oversized: None
+        };
+        chunk.parse(Arc::new(["name", "city"].into_iter().collect()), repair, false, ragged_rows, Arc::default()).await
+    }
+
+    #[async_std::test]
+    async fn invalid_utf8_is_located() {
+        // The invalid byte follows a quoted line break, in the second column
+        let text = b"a,b\n\"c\nd\",\"e\xFFf\"\n";
+        let error = parse(text, false).await.err().unwrap();
+        assert_eq!("Invalid UTF-8 in column city on line 12, at byte 112. To replace invalid sequences \
+            with U+FFFD instead, pass --repair-utf8", error.to_string());
+    }
+
+    #[async_std::test]
+    async fn invalid_utf8_is_repaired() -> Result<()> {
+        let parsed = parse(b"\xFF,b\nc,\xE2\x82\n\xFE,\xFE\xFE\n", true).await?;
+        assert_eq!(vec![vec!["\u{FFFD}", "b"], vec!["c", "\u{FFFD}"], vec!["\u{FFFD}", "\u{FFFD}\u{FFFD}"]],
+                   parsed.records.iter().map(|record| record.iter().collect::<Vec<_>>()).collect::<Vec<_>>());
+
+        assert_eq!(Utf8Repairs { per_column: vec![2, 3], lines: vec![10, 11, 12] }, parsed.repairs);
+
+        // Only the first lines are named
+        let mut repairs = parsed.repairs;
+        repairs.extend(&Utf8Repairs { per_column: vec![0, 1], lines: vec![12, 15, 16, 17] });
+        let schema: Schema = ["name", "city"].into_iter().collect();
+        assert_eq!(
+            Some(String::from("Replaced 6 invalid UTF-8 sequences with U+FFFD (2 in name, 4 in city), \
+                first on lines 10, 11, 12, 15, 16")),
+            repairs.warning(&schema)
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn repairs_are_counted_in_their_columns() -> Result<()> {
+        // A U+FFFD already in the text is not counted, and a quoted field may span lines
+        let mut text = "\u{FFFD},\"x\ny".as_bytes().to_vec();
+        text.extend_from_slice(b"\xFF\"\nz\xC3,");
+        text.extend_from_slice("\u{FFFD}\n".as_bytes());
+        let parsed = parse(&text, true).await?;
+        assert_eq!(vec![vec!["\u{FFFD}", "x\ny\u{FFFD}"], vec!["z\u{FFFD}", "\u{FFFD}"]],
+                   parsed.records.iter().map(|record| record.iter().collect::<Vec<_>>()).collect::<Vec<_>>());
+        assert_eq!(Utf8Repairs { per_column: vec![1, 1], lines: vec![10, 12] }, parsed.repairs);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn ragged_records_are_fitted() -> Result<()> {
+        let text = b"Ann,Oslo\nBo\nCy,Rome,Italy,Europe\nDi,Kyiv\n";
+        let records = |parsed: &ParsedChunk| parsed.records.iter()
+            .map(|record| record.iter().map(String::from).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let error = parse_ragged(text, false, RaggedRows::Strict).await.err().unwrap();
+        assert!(error.to_string().starts_with("Record on line 11 has 1 fields, but the header has 2"), "{}", error);
+
+        let skipped = parse_ragged(text, false, RaggedRows::Skip).await?;
+        assert_eq!(vec![vec!["Ann", "Oslo"], vec!["Di", "Kyiv"]], records(&skipped));
+        assert_eq!(RaggedRecords { count: 2, lines: vec![11, 12] }, skipped.ragged);
+
+        // Pad and truncate each fit records in one direction only
+        let error = parse_ragged(text, false, RaggedRows::Pad).await.err().unwrap();
+        assert!(error.to_string().starts_with("Record on line 12 has 4 fields"), "{}", error);
+        let padded = parse_ragged(b"Ann,Oslo\nBo\n", false, RaggedRows::Pad).await?;
+        assert_eq!(vec![vec!["Ann", "Oslo"], vec!["Bo", ""]], records(&padded));
+        let truncated = parse_ragged(b"Cy,Rome,Italy,Europe\nDi,Kyiv\n", false, RaggedRows::Truncate).await?;
+        assert_eq!(vec![vec!["Cy", "Rome"], vec!["Di", "Kyiv"]], records(&truncated));
+
+        let schema: Schema = ["name", "city"].into_iter().collect();
+        assert_eq!(Some(String::from("Dropped the fields beyond the header's 2 from 1 records with too many fields, \
+            first on lines 10")), truncated.ragged.warning(RaggedRows::Truncate, &schema));
+        assert_eq!(None, truncated.ragged.warning(RaggedRows::Strict, &schema));
+        Ok(())
+    }
+
+    /// Imports into the test database, which the integration-test feature requires
+    #[cfg(feature = "integration-test")]
+    mod observed {
+        use std::sync::Mutex;
+        use super::*;
+
+        #[derive(Default)]
+        struct CollectingObserver(Mutex<Vec<ImportEvent>>);
+
+        impl ImportObserver for CollectingObserver {
+            fn observe(&self, event: &ImportEvent) {
+                self.0.lock().unwrap().push(event.clone());
+            }
+        }
+
+        async fn import(table: &str, csv: &str, ragged_rows: RaggedRows) -> (Result<ImportSummary>, Vec<ImportEvent>) {
+            let tempdir = tempfile::tempdir().unwrap();
+            let input = PathBuf::from(tempdir.path().join("observed.csv"));
+            fs::write(&input, csv).await.unwrap();
+            let observer = Arc::new(CollectingObserver::default());
+            let pool = PgPool::connect(&database::test_url()).await.unwrap();
+            let import = Import {
+                pool: pool.clone(),
+                csv_input: CsvInput::File(input),
+                dialect: Dialect::default(),
+                table: String::from(table),
+                mode: TableMode::Replace,
+                checkpoint_path: Checkpoint::path_for(&PathBuf::from(tempdir.path()), table),
+                resume_from: None,
+                jobs: 2,
+                repair_utf8: false,
+                ragged_rows,
+                copy: false,
+                count_statements: false,
+                computed: Vec::new(),
+                generated_columns: true,
+                transforms: Vec::new(),
+                filter: None,
+                sampling: None,
+                conflict: None,
+                fix_ups: FixUpPolicy::default(),
+                reject_limit: RejectLimit::default(),
+                ignore_extra_columns: false,
+                observer: observer.clone()
+            };
+            let imported = import.read_csv_then_write_to_database().await;
+            sqlx::query(&format!("DROP TABLE IF EXISTS {}", table)).execute(&pool).await.unwrap();
+            let events = observer.0.lock().unwrap().clone();
+            (imported, events)
+        }
+
+        #[async_std::test]
+        async fn events_of_an_import() {
+            let (imported, events) = import("observed_test", "id,name\n1,Ann\n2\n3,Cy\n", RaggedRows::Skip).await;
+            assert_eq!(Some(2), imported.unwrap().rows);
+            assert_eq!(vec![
+                ImportEvent::Started { columns: vec![String::from("id"), String::from("name")], total_bytes: 21, bytes: 8 },
+                ImportEvent::RowRejected { line: 3, reason: String::from("line 3 has 1 fields, but the header has 2") },
+                ImportEvent::Progress { rows: 2, bytes: 21 },
+                ImportEvent::BatchCommitted { rows: 0..2 },
+                ImportEvent::Finished { stats: ImportStats { rows: 2, rejected: 1, table_rows: Some(2), error: None } }
+            ], events);
+        }
+
+        #[async_std::test]
+        async fn events_of_a_failed_import() {
+            let (imported, events) = import("observed_failure_test", "id,name\n1,Ann\n2\n", RaggedRows::Strict).await;
+            let error = imported.err().unwrap().render();
+            assert!(error.starts_with("Record on line 3 has 1 fields"), "{}", error);
+            assert_eq!(2, events.len());
+            assert!(matches!(events[0], ImportEvent::Started { .. }));
+            assert_eq!(ImportEvent::Finished {
+                stats: ImportStats { rows: 0, rejected: 0, table_rows: Some(0), error: Some(error) }
+            }, events[1]);
+        }
+    }
+
+    #[async_std::test]
+    async fn detect_changed_input() -> Result<()> {
+        let tempdir = tempfile::tempdir().unwrap();
+        let input = PathBuf::from(tempdir.path().join("input.csv"));
+        fs::write(&input, "a\n1\n").await.unwrap();
+        let (file_size, modified_secs, modified_nanos) = fingerprint(&input).await?;
+        let checkpoint = Checkpoint {
+            input: input.clone().into(), table: String::from("data"), file_size, modified_secs, modified_nanos,
+            byte: 2, line: 2, record: 1, table_rows: 0
+        };
+        checkpoint.verify_unchanged().await?;
+
+        let path = Checkpoint::path_for(&PathBuf::from(tempdir.path()), "data");
+        checkpoint.save(&path).await.unwrap();
+        assert_eq!(Some(&checkpoint), Checkpoint::load(&path).await.unwrap().as_ref());
+
+        fs::write(&input, "a\n1\n2\n").await.unwrap();
+        assert!(matches!(checkpoint.verify_unchanged().await, Err(Error::Usage(_))));
+        Ok(())
+    }
+}
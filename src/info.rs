@@ -0,0 +1,113 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use serde::Serialize;
+use crate::import::INPUT_FORMATS;
+use crate::output::{Destination, OutputFormat};
+
+/// The version of the document's layout, raised whenever a field is renamed, removed or changes
+/// meaning. Fields may be added without raising it
+const INFO_VERSION: u32 = 1;
+
+/// The optional features of the crate, each of which a binary may or may not be built with
+const FEATURES: [(&str, bool); 2] = [("keyring", cfg!(feature = "keyring")), ("duckdb", cfg!(feature = "duckdb"))];
+
+/// The databases which datasets are imported into and results are copied to
+const BACKENDS: [(&str, bool); 2] = [("postgres", true), ("duckdb", cfg!(feature = "duckdb"))];
+
+/// What a binary of data-sifter can do, as printed by `data-sifter info`, so that scripts can
+/// check before relying on it
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct BuildInfo {
+    info_version: u32,
+    version: &'static str,
+    /// The commit built, unless it was built without git
+    git_hash: Option<&'static str>,
+    /// When it was built, as YYYY-MM-DD in UTC
+    build_date: Option<&'static str>,
+    /// The optional features it was built with
+    features: Vec<&'static str>,
+    backends: Vec<&'static str>,
+    input_formats: Vec<Entry>,
+    output_formats: Vec<Entry>,
+    /// What can be done with the results of a query, as offered once it is entered
+    destinations: Vec<Entry>
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Entry {
+    name: String,
+    description: String
+}
+
+impl BuildInfo {
+
+    pub(crate) fn of_this_build() -> Self {
+        let entry = |(name, description): (String, String)| Entry { name, description };
+        Self {
+            info_version: INFO_VERSION,
+            version: env!("CARGO_PKG_VERSION"),
+            git_hash: option_env!("DATA_SIFTER_GIT_HASH"),
+            build_date: option_env!("DATA_SIFTER_BUILD_DATE"),
+            features: FEATURES.iter().filter(|(_, enabled)| *enabled).map(|(feature, _)| *feature).collect(),
+            backends: BACKENDS.iter().filter(|(_, enabled)| *enabled).map(|(backend, _)| *backend).collect(),
+            input_formats: INPUT_FORMATS.iter()
+                .map(|(name, description)| entry((String::from(*name), String::from(*description))))
+                .collect(),
+            output_formats: OutputFormat::ALL.iter()
+                .map(|format| entry((String::from(format.extension()), String::from(format.description()))))
+                .collect(),
+            destinations: Destination::options().into_iter().map(entry).collect()
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Build info is always serializable")
+    }
+
+    /// The info for reading, such as
+    ///
+    /// data-sifter 0.1.0 (commit 1a2b3c4d5e6f, built 2022-03-01)
+    /// Features: keyring
+    /// Backends: postgres
+    pub(crate) fn to_text(&self) -> String {
+        let mut text = format!("data-sifter {}", self.version);
+        match (self.git_hash, self.build_date) {
+            (Some(hash), Some(date)) => text.push_str(&format!(" (commit {}, built {})", hash, date)),
+            (Some(hash), None) => text.push_str(&format!(" (commit {})", hash)),
+            (None, Some(date)) => text.push_str(&format!(" (built {})", date)),
+            (None, None) => {}
+        }
+        let list = |names: &[&str]| match names.is_empty() {
+            true => String::from("none"),
+            false => names.join(", ")
+        };
+        text.push_str(&format!("\nFeatures: {}", list(&self.features)));
+        text.push_str(&format!("\nBackends: {}", list(&self.backends)));
+        for (heading, entries) in [
+            ("Input formats", &self.input_formats),
+            ("Output formats", &self.output_formats),
+            ("Destinations", &self.destinations)
+        ] {
+            text.push_str(&format!("\n{}:", heading));
+            for entry in entries {
+                text.push_str(&format!("\n  {} - {}", entry.name, entry.description));
+            }
+        }
+        text
+    }
+}
@@ -0,0 +1,170 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::str::FromStr;
+use chrono::NaiveDate;
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+
+/// How numbers and dates are written in shown results, for reading them as is usual in a
+/// country. Output files are never localized, so that other programs can read them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum DisplayLocale {
+    EnUs,
+    EnGb,
+    DeDe,
+    FrFr,
+    ItIt,
+    NlNl,
+    PtBr,
+    SvSe
+}
+
+impl DisplayLocale {
+    const ALL: [Self; 8] = [
+        Self::EnUs, Self::EnGb, Self::DeDe, Self::FrFr, Self::ItIt, Self::NlNl, Self::PtBr, Self::SvSe
+    ];
+
+    fn tag(self) -> &'static str {
+        match self {
+            Self::EnUs => "en-US",
+            Self::EnGb => "en-GB",
+            Self::DeDe => "de-DE",
+            Self::FrFr => "fr-FR",
+            Self::ItIt => "it-IT",
+            Self::NlNl => "nl-NL",
+            Self::PtBr => "pt-BR",
+            Self::SvSe => "sv-SE"
+        }
+    }
+
+    /// The separators between groups of digits and before the fraction. French and Swedish
+    /// group digits with spaces which do not break
+    fn separators(self) -> (char, char) {
+        match self {
+            Self::EnUs | Self::EnGb => (',', '.'),
+            Self::DeDe | Self::ItIt | Self::NlNl | Self::PtBr => ('.', ','),
+            Self::FrFr => ('\u{202F}', ','),
+            Self::SvSe => ('\u{A0}', ',')
+        }
+    }
+
+    /// The short date format, with the year in full
+    fn date_format(self) -> &'static str {
+        match self {
+            Self::EnUs => "%-m/%-d/%Y",
+            Self::EnGb | Self::FrFr | Self::ItIt | Self::PtBr => "%d/%m/%Y",
+            Self::DeDe => "%d.%m.%Y",
+            Self::NlNl => "%d-%m-%Y",
+            Self::SvSe => "%Y-%m-%d"
+        }
+    }
+
+    /// Groups the digits of a number written as Postgres writes it, such as -1234.5, and
+    /// replaces its decimal point. Numbers such as NaN are left as they are
+    pub(crate) fn format_number(self, number: &str) -> String {
+        let (group, decimal) = self.separators();
+        match number.split_once('.') {
+            Some((integer, fraction)) => format!("{}{}{}", group_digits(integer, group), decimal, fraction),
+            None => group_digits(number, group)
+        }
+    }
+
+    pub(crate) fn format_date(self, date: &NaiveDate) -> String {
+        date.format(self.date_format()).to_string()
+    }
+}
+
+/// Puts the separator between each group of three digits of the integer part of a number
+pub(crate) fn group_digits(number: &str, separator: char) -> String {
+    let (sign, unsigned) = match number.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", number)
+    };
+    let integer_length = unsigned.find('.').unwrap_or(unsigned.len());
+    let (integer, fraction) = unsigned.split_at(integer_length);
+    if !integer.bytes().all(|byte| byte.is_ascii_digit()) {
+        // Such as NaN or inf
+        return String::from(number);
+    }
+    let mut grouped = String::from(sign);
+    for (index, digit) in integer.chars().enumerate() {
+        if index != 0 && (integer_length - index) % 3 == 0 {
+            grouped.push(separator);
+        }
+        grouped.push(digit);
+    }
+    grouped.push_str(fraction);
+    grouped
+}
+
+impl FromStr for DisplayLocale {
+    type Err = eyre::Report;
+
+    /// Parses a locale tag such as de-DE, in any case, with a hyphen or an underscore
+    fn from_str(value: &str) -> Result<Self> {
+        let tag = value.replace('_', "-");
+        Self::ALL.into_iter().find(|locale| locale.tag().eq_ignore_ascii_case(&tag)).ok_or_else(|| eyre!(
+            "Unknown display locale {}. Supported locales are: {}",
+            value, Self::ALL.iter().map(|locale| locale.tag()).collect::<Vec<_>>().join(", ")
+        ))
+    }
+}
+
+impl TryFrom<String> for DisplayLocale {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<DisplayLocale> for String {
+    fn from(locale: DisplayLocale) -> Self {
+        String::from(locale.tag())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_numbers() {
+        assert_eq!("1.234.567,89", DisplayLocale::DeDe.format_number("1234567.89"));
+        assert_eq!("-1,234.5", DisplayLocale::EnUs.format_number("-1234.5"));
+        assert_eq!("12\u{202F}345,5", DisplayLocale::FrFr.format_number("12345.5"));
+        assert_eq!("100", DisplayLocale::SvSe.format_number("100"));
+        assert_eq!("NaN", DisplayLocale::DeDe.format_number("NaN"));
+    }
+
+    #[test]
+    fn format_dates() {
+        let date = NaiveDate::from_ymd_opt(2022, 1, 5).unwrap();
+        assert_eq!("1/5/2022", DisplayLocale::EnUs.format_date(&date));
+        assert_eq!("05.01.2022", DisplayLocale::DeDe.format_date(&date));
+        assert_eq!("05/01/2022", DisplayLocale::EnGb.format_date(&date));
+    }
+
+    #[test]
+    fn parse_tags() {
+        assert_eq!(DisplayLocale::DeDe, "de-DE".parse().unwrap());
+        assert_eq!(DisplayLocale::PtBr, "pt_br".parse().unwrap());
+        assert!("de".parse::<DisplayLocale>().is_err_and(|error| error.to_string().contains("en-US, en-GB")));
+    }
+}
@@ -15,211 +15,3672 @@
  * limitations under the License.
  */
 
-mod database;
+mod aligned;
+mod archive;
+mod batch;
+mod bucket;
+mod capabilities;
+mod cardinality;
+mod charset;
+mod clean;
+mod cli;
+mod completion;
+mod computed;
 mod config;
+mod database;
+mod decode;
+mod dialect;
+mod diskspace;
+mod download;
+mod embedded;
+mod error;
+mod expanded;
+mod explain;
+mod filter;
+mod fixedwidth;
+mod fixup;
+mod histogram;
+mod import;
+mod info;
+mod locale;
+mod manifest;
+mod notice;
+mod observer;
+mod output;
+mod parameters;
+mod parquet;
+mod password;
+mod pivot;
+mod platform;
+mod plan;
+mod progress;
+mod profile;
+mod reject;
+mod remote;
+mod report;
+mod sample;
+mod script;
+mod session;
+mod ssl;
+mod statements;
+mod staged;
+mod state;
+mod style;
+mod timestamp;
+mod timing;
+mod top;
+mod transform;
+mod tui;
+mod validate;
 
-use eyre::Result;
-use async_std::path::PathBuf;
-use async_std::{io, fs::{File, OpenOptions}};
+use clap::Parser;
+use indexmap::IndexMap;
+use itertools::Itertools;
+use eyre::{eyre, WrapErr};
+use async_std::path::{Path, PathBuf};
+use async_std::{io, fs};
+use std::collections::HashMap;
+use std::io::IsTerminal;
 use std::os::unix::ffi::OsStrExt;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use async_std::channel::{self, Receiver, Sender};
 use async_std::task::{self, JoinHandle};
-use futures_lite::{AsyncBufReadExt, AsyncWriteExt};
-use futures_util::{StreamExt, stream::FuturesUnordered};
-use itertools::Itertools;
-use sqlx::postgres::PgPool;
-use crate::config::Config;
-use crate::database::Query;
+use futures_lite::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, FutureExt};
+use futures_util::future::{self, BoxFuture};
+use sqlx::{Connection, Postgres};
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::{PgConnectOptions, PgConnection, PgPool};
+use crate::batch::{BatchQuery, BatchSpec};
+use crate::bucket::TimeBuckets;
+use crate::capabilities::{Feature, ServerCapabilities};
+use crate::cardinality::Cardinality;
+use crate::cli::{Args, Command, ConfigCommand};
+use crate::config::{Backend, Config, LocationOverrides};
+use crate::database::{AppendedColumns, ConflictKey, OnConflict, Query, TableColumn, TableMode};
+use crate::decode::{DecimalPlaces, FormatOptions};
+use crate::dialect::{Dialect, SizeLimits};
+use crate::diskspace::{Projection, SpaceWatch};
+use crate::embedded::Embedded;
+use crate::fixedwidth::Layout;
+use crate::fixup::{FixUp, FixUpPolicy, Mismatch};
+use crate::histogram::Histogram;
+use crate::info::BuildInfo;
+use crate::import::{Checkpoint, CsvInput, Import, ImportSummary, ParsedRecords, RaggedRows, Strategy};
+use crate::manifest::{HashingWriter, Manifest};
+use crate::output::{ColumnOrder, Destination, ExportOptions, OutputFormat, OutputSummary, ReadableLayout};
+use crate::parameters::Parameter;
+use crate::password::PasswordSource;
+use crate::pivot::Pivot;
+use crate::plan::ImportPlan;
+use crate::profile::DatasetProfile;
+use crate::report::{ErrorReport, QueryReport, RunReport};
+use crate::script::{CopyFrom, SqlScript};
+use crate::session::{Recording, Replay, Script, Session};
+use crate::staged::StagedFile;
+use crate::state::{Fingerprint, RecordedImport, SessionState};
+use crate::progress::{ExportProgress, ImportProgress, RateEstimator};
+use crate::top::TopValues;
+use crate::error::{Categorize, Context, Error, Result};
+use crate::explain::AnalyzedPlan;
+use crate::completion::QueryCompleter;
+use crate::clean::MarkedTable;
+use crate::style::Style;
+use crate::tui::{Action, Results, TerminalUi, View};
 
-fn main() -> core::result::Result<(), eyre::Error> {
-    use std::env;
+fn main() -> ExitCode {
+    use stable_eyre::BacktraceExt;
 
-    if let Err(env::VarError::NotPresent) = env::var("RUST_BACKTRACE") {
-        env::set_var("RUST_BACKTRACE", "1");
-        println!("Enabled RUST_BACKTRACE");
+    let args = Args::parse();
+    // Backtraces are captured if RUST_BACKTRACE asks for them, or else with --verbose
+    if let Err(error) = stable_eyre::HookBuilder::default().capture_backtrace_by_default(args.verbose).install() {
+        eprintln!("Error: {:#}", error);
+        return ExitCode::FAILURE;
     }
-    stable_eyre::install()?;
 
+    let color = style::use_color(args.no_color, std::io::stderr().is_terminal());
+    let verbosity = if args.quiet { Verbosity::Quiet } else { Verbosity::Normal };
+    notice::install(color, verbosity == Verbosity::Quiet);
     let io = IO {
         input: io::BufReader::new(io::stdin()),
-        output: io::stdout()
+        data: io::stdout(),
+        messages: io::stderr(),
+        data_is_terminal: std::io::stdout().is_terminal(),
+        messages_are_terminal: std::io::stderr().is_terminal(),
+        color,
+        verbosity,
+        script: None
     };
-    task::block_on(async_main(io))
+    let report_path = args.report.clone();
+    let (started, clock) = (chrono::Utc::now(), Instant::now());
+    let mut report = RunReport::default();
+    let mut result = task::block_on(async_main(io, args, &mut report));
+    // Once a reader such as head closes the output, the run ends quietly, as with other commands
+    if result.as_ref().is_err_and(Error::closed_output) {
+        result = Ok(());
+    }
+    // The report is written however the run ended, so that failures can be told apart too
+    if let Some(path) = report_path {
+        report.finish(started, clock.elapsed(), &result);
+        let written = std::fs::write(&path, report.to_json())
+            .wrap_err_with(|| format!("Unable to write the report to {}", path))
+            .categorize(Error::OutputIo);
+        match (written, &result) {
+            (Ok(()), _) => (),
+            (Err(error), Ok(())) => result = Err(error),
+            (Err(error), Err(_)) => eprintln!("{}", style::paint(
+                color, Style::Error, &format!("Warning: {}", error.render())
+            ))
+        }
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            let prefix = style::paint(color, Style::Error, "Error:");
+            eprintln!("{} {}", prefix, error.render());
+            // A lost connection is a matter of connectivity, which a backtrace would not shed light on
+            if let Some(backtrace) = error.report().backtrace().filter(|_| !error.lost_connection()) {
+                eprintln!("\nStack backtrace:\n{:?}", backtrace);
+            }
+            ExitCode::from(error.exit_code())
+        }
+    }
 }
 
-async fn async_main<R>(mut io: IO<R>) -> Result<()> where R: io::BufRead + Unpin + Send {
-    let config_path = Config::default_path(&mut io).await?;
-    let config = Config::load(&config_path).await?;
-    let config = match config {
+async fn async_main<R>(mut io: IO<R>, args: Args,
+                       report: &mut RunReport) -> Result<()> where R: io::BufRead + Unpin + Send {
+    // Nothing else is needed to tell what the binary can do
+    if let Some(Command::Info { json }) = args.command {
+        let info = BuildInfo::of_this_build();
+        let shown = if json { info.to_json() } else { info.to_text() };
+        return io.write_data(&shown).await.categorize(Error::OutputIo);
+    }
+    // The UI draws on stdout and reads keys from stdin, which other programs cannot take part in
+    if args.tui && !(std::io::stdin().is_terminal() && std::io::stdout().is_terminal()) {
+        return Err(Error::Usage(eyre!(
+            "--tui draws on the terminal, so stdin and stdout must both be terminals. Leave it out to be prompted \
+            instead, or pass --query to run a query non-interactively"
+        )));
+    }
+    let (args, replay) = match args.replay.clone() {
+        Some(path) => {
+            let (args, session) = replay_args(args, PathBuf::from(&path)).await?;
+            (args, Some(Replay::new(PathBuf::from(path), session)))
+        },
+        None => (args, None)
+    };
+    let args = read_query(&mut io, args).await?;
+    let overrides = LocationOverrides {
+        config: args.config.as_ref().map(PathBuf::from),
+        portable: args.portable
+    };
+    let locations = Config::locate(&mut io, &overrides, !args.non_interactive()).await.categorize(Error::Config)?;
+    let config_path = &locations.config;
+    if let Some(Command::Config(command)) = args.command {
+        return run_config_command(&mut io, config_path, &locations.data_dir, command).await;
+    }
+    // Prompts about where files are kept depend on the machine, so they are never recorded
+    if let Some(replay) = replay {
+        io.script = Some(Script::Replay(replay));
+    } else if let Some(path) = &args.record {
+        let (arguments, withheld) = session::recorded_arguments(&command_line());
+        for option in withheld {
+            io.write_styled(Style::Error, &format!(
+                "Warning: {} is not recorded, since it holds a password", option
+            )).await.categorize(Error::OutputIo)?;
+        }
+        let recording = Recording::start(PathBuf::from(path), arguments).await.categorize(Error::OutputIo)?;
+        io.script = Some(Script::Record(recording));
+    }
+    let config = Config::load(config_path).await
+        .wrap_err_with(|| format!("Unable to load config from {}", config_path.display()))
+        .categorize(Error::Config)?;
+    // A dry run needs neither the config nor a connection, but follows the config's dialect
+    if args.dry_run {
+        let config = config.unwrap_or_default();
+        // An invalid dialect is reported before the dataset is asked for
+        csv_dialect(&args, &config)?;
+        let csv_input = io.answer(
+            args.input.clone(), args.non_interactive(), "--input", "Enter CSV dataset file or http(s) URL to validate."
+        ).await?;
+        return dry_run(&mut io, &args, &config, &csv_input, report).await;
+    }
+    // Nor does a script, which is written instead of importing
+    if let Some(output) = args.generate_sql.clone() {
+        let config = config.unwrap_or_default();
+        csv_dialect(&args, &config)?;
+        let csv_input = io.answer(
+            args.input.clone(), false, "--input", "Enter CSV dataset file or http(s) URL to write an import script for."
+        ).await?;
+        return generate_sql(&mut io, &args, &config, &csv_input, PathBuf::from(output)).await;
+    }
+    let mut config = match config {
         None => {
-            Config::default().write_to(&config_path).await?;
-            io.write_output(
-                "The default config has been created. Please configure and then restart data-sifter"
-            ).await?;
-            return Ok(());
+            Config::default().write_to(config_path).await.categorize(Error::Config)?;
+            return Err(Error::Config(eyre!(
+                "The default config has been created at {}. Please configure and then restart data-sifter",
+                config_path.display()
+            )));
         },
         Some(config) => config
     };
-    let Config { postgres_url } = config;
+    if let Some(schema) = args.schema.clone() {
+        config.schema = Some(config::check_schema(schema).map_err(Error::Usage)?);
+    }
+    if let Some(ssl_mode) = args.ssl_mode {
+        config.ssl.mode = Some(ssl_mode);
+    }
+    if let Some(backend) = args.backend {
+        config.backend = backend;
+    }
+    if config.backend == Backend::Duckdb {
+        return run_embedded(io, args, config, locations.data_dir, report).await;
+    }
+    let database = config.masked().postgres_url;
+    let postgres_url = connection_url(&mut io, &config, !args.non_interactive()).await?;
+    if let Some(Command::Clean { older_than, dry_run }) = args.command {
+        let options = database::connect_options(&config, &postgres_url)?;
+        return clean_tables(&mut io, &config, options, older_than, dry_run).await
+            .map_err(|error| match error {
+                Error::Connect(_) => error.wrap_err(format!("Unable to connect to the database at {}", database)),
+                error => error
+            });
+    }
+    let mut app = App {
+        io,
+        args,
+        data_dir: locations.data_dir.clone(),
+        database: Database::Postgres(database::pool_options(&config)
+            .connect_lazy_with(database::connect_options(&config, &postgres_url)?)),
+        capabilities: None,
+        config,
+        tables: IndexMap::new(),
+        pending_import: None,
+        connection_waits: channel::unbounded(),
+        outputs: Vec::new(),
+        profiles: HashMap::new(),
+        report: RunReport::default(),
+        expanded: false,
+        redacted: Vec::new()
+    };
+    let result = app.run().await;
+    *report = app.report;
+    result.map_err(|error| match error {
+        Error::Connect(_) => error.wrap_err(format!("Unable to connect to the database at {}", database)),
+        error if session::replay_stopped(&error) => error.recategorize(Error::Usage),
+        error => error
+    })
+}
+
+/// Runs the session with the tables in DuckDB, in a file of its own, rather than in Postgres
+async fn run_embedded<R>(io: IO<R>, args: Args, config: Config, data_dir: PathBuf,
+                         report: &mut RunReport) -> Result<()> where R: io::BufRead + Unpin + Send {
+    if let Some(Command::Clean { .. }) = args.command {
+        return Err(Error::Usage(eyre!("Only tables in Postgres are cleaned, so clean needs the postgres backend")));
+    }
+    let embedded = Embedded::open(&embedded::database_file(&config, data_dir.as_ref()))?;
     let mut app = App {
         io,
-        connection_pool: sqlx::postgres::PgPool::connect_lazy(&postgres_url)?
+        args,
+        data_dir,
+        database: Database::Embedded(Arc::new(embedded)),
+        capabilities: None,
+        config,
+        tables: IndexMap::new(),
+        pending_import: None,
+        connection_waits: channel::unbounded(),
+        outputs: Vec::new(),
+        profiles: HashMap::new(),
+        report: RunReport::default(),
+        expanded: false,
+        redacted: Vec::new()
+    };
+    let result = app.run().await;
+    *report = app.report;
+    result.map_err(|error| match error {
+        error if session::replay_stopped(&error) => error.recategorize(Error::Usage),
+        error => error
+    })
+}
+
+/// The URL to connect to the database with. If the config keeps the password in the keyring, it
+/// is taken from there, or else from PGPASSWORD, or else asked for, and is only ever held in memory
+async fn connection_url<R>(io: &mut IO<R>, config: &Config,
+                           interactive: bool) -> Result<String> where R: io::BufRead + Unpin {
+    if config.password_source == PasswordSource::Url {
+        return Ok(config.postgres_url.clone());
+    }
+    let user = password::keyring_user(&config.postgres_url).categorize(Error::Config)?;
+    let variable = std::env::var(password::PASSWORD_VARIABLE).ok();
+    let (password, error) = match password::system_keyring() {
+        Ok(keyring) => password::stored_password(keyring.as_ref(), &user, variable),
+        Err(error) => (variable, Some(error))
+    };
+    if let Some(error) = error {
+        io.write_styled(Style::Error, &format!("Warning: {}", error)).await.categorize(Error::OutputIo)?;
+    }
+    let password = match password {
+        Some(password) => password,
+        None if interactive => io.prompt_hidden(&format!("Enter the database password for {}.", user)).await
+            .categorize(Error::OutputIo)?,
+        None => return Err(Error::Config(eyre!(
+            "No password for {} was found in the keyring or {}", user, password::PASSWORD_VARIABLE
+        )))
+    };
+    password::with_password(&config.postgres_url, &password).categorize(Error::Config)
+}
+
+/// The command-line arguments, without the program name
+fn command_line() -> Vec<String> {
+    std::env::args_os().skip(1).map(|argument| argument.to_string_lossy().into_owned()).collect()
+}
+
+/// The arguments recorded in the session, along with those saying where and how data-sifter
+/// runs, which are the only ones allowed alongside --replay
+async fn replay_args(args: Args, path: PathBuf) -> Result<(Args, Session)> {
+    let (others, withheld) = session::recorded_arguments(&command_line());
+    if !others.is_empty() || !withheld.is_empty() {
+        return Err(Error::Usage(eyre!(
+            "The options for a replayed session are those it was recorded with. Only --config, --portable, \
+            --no-color, --verbose, --quiet, --header and --report may be given with --replay"
+        )));
+    }
+    let session = Session::load(&path).await.categorize(Error::Usage)?;
+    let arguments = std::iter::once("data-sifter").chain(session.arguments.iter().map(String::as_str));
+    let recorded = Args::try_parse_from(arguments)
+        .wrap_err_with(|| format!("The options recorded in session {} are invalid", path.display()))
+        .categorize(Error::Usage)?;
+    let replayed = Args {
+        config: args.config,
+        portable: args.portable,
+        no_color: args.no_color,
+        verbose: args.verbose,
+        quiet: args.quiet,
+        header: args.header,
+        report: args.report,
+        record: None,
+        replay: args.replay,
+        ..recorded
+    };
+    Ok((replayed, session))
+}
+
+/// The dialect of CSV datasets, given by the arguments or else the config
+fn csv_dialect(args: &Args, config: &Config) -> Result<Dialect> {
+    let dialect = Dialect::new(
+        args.quote.or(config.quote).unwrap_or('"'),
+        args.escape.or(config.escape),
+        args.double_quote.or(config.double_quote).unwrap_or(true)
+    ).map_err(Error::Usage)?;
+    let defaults = SizeLimits::default();
+    Ok(dialect.with_limits(SizeLimits {
+        field: config.max_field_mb.map_or(defaults.field, |megabytes| megabytes * 1_000_000),
+        record: config.max_record_mb.map_or(defaults.record, |megabytes| megabytes * 1_000_000)
+    }))
+}
+
+/// Resolves the dataset, which is read as a fixed-width file if --fixed-width gives its layout
+async fn resolve_input<R>(io: &mut IO<R>, args: &Args, config: &Config, csv_input: &str) -> Result<CsvInput>
+    where R: io::BufRead + Unpin {
+    if archive::is_archive(csv_input) {
+        return Err(Error::Usage(eyre!(
+            "The datasets of a ZIP archive can only be loaded at the dataset prompt, each into a table of its own"
+        )));
+    }
+    let asked;
+    let csv_input = match remote::is_remote(csv_input) && !remote::names_relation(csv_input) {
+        true if args.non_interactive() => return Err(Error::Usage(eyre!(
+            "Name the table or query to copy after a #, as in postgres://host/db#schema.table"
+        ))),
+        true => {
+            let relation = io.prompt(&format!(
+                "Enter the table, or a query, of {} to import.", config::mask_password(csv_input.trim_end_matches('#'))
+            )).await.categorize(Error::OutputIo)?;
+            asked = format!("{}#{}", csv_input.trim_end_matches('#'), relation.trim());
+            asked.as_str()
+        },
+        false => csv_input
+    };
+    let csv_input = CsvInput::resolve(csv_input, &args.header, config).await?;
+    if let CsvInput::Parquet { file, .. } = &csv_input {
+        let nested = file.nested_columns();
+        if !nested.is_empty() {
+            let warning = format!(
+                "Warning: {} {} groups or lists, which are imported as JSON", nested.join(", "),
+                if nested.len() == 1 { "holds" } else { "hold" }
+            );
+            io.write_styled(Style::Error, &warning).await.categorize(Error::OutputIo)?;
+        }
+    }
+    let mut layout = match args.fixed_width.as_deref() {
+        None => return Ok(csv_input),
+        Some("ASK") => ask_layout(io, args.non_interactive()).await?,
+        Some(path) => Layout::load(Path::new(path)).await.categorize(Error::Usage)?
+    };
+    if let Some(units) = args.fixed_width_units {
+        layout.units = units;
+    }
+    csv_input.with_layout(Arc::new(layout))
+}
+
+/// Asks for the columns of a fixed-width layout one at a time, until an empty answer
+async fn ask_layout<R>(io: &mut IO<R>, non_interactive: bool) -> Result<Layout> where R: io::BufRead + Unpin {
+    if non_interactive {
+        return Err(Error::Usage(eyre!("--fixed-width must name a layout file in non-interactive mode")));
+    }
+    let mut layout = Layout { columns: Vec::new(), units: Default::default() };
+    loop {
+        let answer = io.prompt(&format!(
+            "Enter column {} of the fixed-width layout as 'name start width [type] [keep_raw]', counting \
+            from 1, or nothing once all are entered. keep_raw also keeps the value as it was in a __raw column.",
+            layout.columns.len() + 1
+        )).await.categorize(Error::OutputIo)?;
+        if answer.trim().is_empty() {
+            break;
+        }
+        match Layout::parse_column(&answer) {
+            Ok(column) => layout.columns.push(column),
+            Err(error) => io.write_styled(Style::Error, &error.to_string()).await.categorize(Error::OutputIo)?
+        }
+    }
+    layout.check().wrap_err("Invalid fixed-width layout").categorize(Error::Usage)?;
+    Ok(layout)
+}
+
+/// Reads the query from stdin if it is given as -. Nothing else can then be read from stdin,
+/// so options which would read from it are refused
+async fn read_query<R>(io: &mut IO<R>, mut args: Args) -> Result<Args> where R: io::BufRead + Unpin {
+    if args.query.as_deref() != Some("-") {
+        return Ok(args);
+    }
+    if args.input.as_deref() == Some("-") {
+        return Err(Error::Usage(eyre!(
+            "The query and the CSV dataset cannot both be read from stdin. Pass the dataset's file with --input"
+        )));
+    }
+    if args.confirm {
+        return Err(Error::Usage(eyre!(
+            "--confirm asks for the import plan to be confirmed on stdin, which --query - reads the query from"
+        )));
+    }
+    let query = io.read_rest().await.wrap_err("Unable to read the query from stdin").categorize(Error::OutputIo)?;
+    let query = query.trim_end();
+    if query.trim_start().is_empty() {
+        return Err(Error::Usage(eyre!("The query read from stdin with --query - is empty")));
+    }
+    args.query = Some(String::from(query));
+    Ok(args)
+}
+
+/// Where an import which stopped can be retried from: the last checkpoint, if one was recorded,
+/// and whether the whole file can be, which it can if that adds no row twice
+async fn retry_points(import: &Import) -> Result<(Option<Checkpoint>, bool)> {
+    let checkpoint = match &import.csv_input {
+        CsvInput::File(path) => Checkpoint::load(&import.checkpoint_path).await.categorize(Error::ImportIo)?
+            .filter(|checkpoint| checkpoint.is_for(path)),
+        CsvInput::Url { .. } | CsvInput::FixedWidth { .. } | CsvInput::ZipEntry { .. }
+        | CsvInput::Parquet { .. } | CsvInput::Remote(_) => None
+    };
+    let whole = import.mode == TableMode::Replace
+        || (checkpoint.is_none() && matches!(import.csv_input, CsvInput::File(_)));
+    Ok((checkpoint, whole))
+}
+
+/// Reads and parses the dataset as an import would, then reports what would be imported
+async fn dry_run<R>(io: &mut IO<R>, args: &Args, config: &Config, csv_input: &str,
+                    report: &mut RunReport) -> Result<()> where R: io::BufRead + Unpin {
+    let dialect = csv_dialect(args, config)?;
+    if csv_input == "KEEP" {
+        return Err(Error::Usage(eyre!("A dry run validates a CSV dataset, so KEEP cannot be used")));
+    }
+    if !database::is_table_name(&args.table) {
+        return Err(Error::Usage(eyre!("Invalid table name: {}", args.table)));
+    }
+    let csv_input = resolve_input(io, args, config, csv_input).await?;
+    if let CsvInput::Parquet { .. } = csv_input {
+        return Err(Error::Usage(eyre!(
+            "A dry run validates the text of CSV records, so it cannot read a Parquet file, whose values are typed"
+        )));
+    }
+    let source = csv_input.to_string();
+    let reported = report.start_import(Some(source.clone()), args.table.clone());
+    reported.dry_run = true;
+    let validation = import::dry_run(
+        csv_input, dialect, args.jobs(), args.repair_utf8, args.ragged_rows.unwrap_or(config.ragged_rows),
+        &args.transform, args.filter.as_ref()
+    ).await?;
+    let (rows, filtered_out, rejected) = validation.row_counts();
+    reported.rows_imported = Some(rows);
+    reported.rows_filtered_out = filtered_out;
+    reported.values_transformed = validation.transformed.iter().cloned().collect();
+    reported.rejects = rejected;
+    reported.finish();
+    io.write_message(&validation.report(&args.table)).await.categorize(Error::OutputIo)?;
+    for warning in &validation.warnings {
+        io.write_styled(Style::Error, &format!("Warning: {}", warning)).await.categorize(Error::OutputIo)?;
+    }
+    if !validation.passed() {
+        return Err(Error::ImportData(eyre!("{} did not pass validation", source)));
+    }
+    let source = style::paint(io.color, Style::Highlight, &source);
+    io.write_message(&format!("{} passed validation. Nothing was written to the database", source)).await
+        .categorize(Error::OutputIo)?;
+    Ok(())
+}
+
+/// Writes a psql script which imports the dataset as data-sifter would, having parsed, transformed
+/// and filtered its records the same way. Without --replace, the rows are added to the table if
+/// it exists, since whether it does cannot be known without connecting
+async fn generate_sql<R>(io: &mut IO<R>, args: &Args, config: &Config, csv_input: &str,
+                         output: PathBuf) -> Result<()> where R: io::BufRead + Unpin {
+    let dialect = csv_dialect(args, config)?;
+    if csv_input == "KEEP" {
+        return Err(Error::Usage(eyre!("A script imports a CSV dataset, so KEEP cannot be used")));
+    }
+    if !database::is_table_name(&args.table) {
+        return Err(Error::Usage(eyre!("Invalid table name: {}", args.table)));
+    }
+    let schema = match args.schema.clone() {
+        Some(schema) => Some(config::check_schema(schema).map_err(Error::Usage)?),
+        None => config.schema.clone()
+    };
+    let table = database::qualify(&args.table, schema.as_deref());
+    let csv_input = resolve_input(io, args, config, csv_input).await?;
+    let ragged_rows = args.ragged_rows.unwrap_or(config.ragged_rows);
+    let (size, blocker) = match &csv_input {
+        CsvInput::File(path) => (
+            fs::metadata(path).await.ok().map(|metadata| metadata.len()),
+            import::copy_blocker(
+                dialect, args.repair_utf8, ragged_rows, !args.transform.is_empty(), args.filter.is_some()
+            )
+        ),
+        CsvInput::Url { .. } => (None, Some("read a download, since \\copy names a file")),
+        CsvInput::FixedWidth { .. } => (None, Some("slice the lines of a fixed-width file, since \\copy reads CSV")),
+        CsvInput::ZipEntry { .. } => (None, Some("decompress a ZIP archive, since \\copy reads CSV")),
+        CsvInput::Parquet { .. } => return Err(Error::Usage(eyre!(
+            "A script inserts the text of CSV records, so it cannot be written for a Parquet file"
+        ))),
+        CsvInput::Remote(_) => (None, Some("read another database, since \\copy names a file"))
+    };
+    let threshold = config.copy_threshold_mb.unwrap_or(import::COPY_THRESHOLD_MB) * 1_000_000;
+    let (strategy, reason) = args.strategy.choose(size, threshold, blocker).categorize(Error::Usage)?;
+    let copy_from = match (strategy, &csv_input) {
+        (Strategy::Copy, CsvInput::File(path)) => Some(CopyFrom {
+            path: path.canonicalize().await.categorize(Error::ImportIo)?,
+            dialect_options: dialect.copy_options()
+                .ok_or_else(|| Error::Usage(eyre!("COPY cannot read CSV files {}", dialect)))?
+        }),
+        _ => None
+    };
+    let records = ParsedRecords::open(
+        &csv_input, dialect, args.jobs(), args.repair_utf8, ragged_rows, &args.transform, args.filter.as_ref()
+    ).await?;
+    let script = SqlScript {
+        table: table.clone(),
+        mode: if args.replace { TableMode::Replace } else { TableMode::Append },
+        schema,
+        source: csv_input.to_string(),
+        copy_from
+    };
+    let summary = script.write(records, &output).await
+        .context_with(|| format!("Unable to write a script importing {}", csv_input))?;
+    for warning in &summary.warnings {
+        io.write_styled(Style::Error, &format!("Warning: {}", warning)).await.categorize(Error::OutputIo)?;
+    }
+    if !summary.transformed.is_empty() {
+        io.write_message(&transform::describe_changes(&summary.transformed)).await.categorize(Error::OutputIo)?;
+    }
+    if let Some(filtered) = summary.filtered {
+        io.write_message(&format!(
+            "Kept {} of the {} rows read, which matched the filter", filtered.kept, filtered.read
+        )).await.categorize(Error::OutputIo)?;
+    }
+    let rows = match (&script.copy_from, summary.rows) {
+        (None, Some(rows)) => format!("inserts {} rows", rows),
+        _ => String::from("copies the rows with \\copy")
+    };
+    let shown_output = output.display().to_string();
+    let shown_output = style::paint(io.color, Style::Highlight, &shown_output);
+    io.write_message(&format!(
+        "Wrote {}, which creates table {} and {}, {}. Run it with psql -f. Nothing was written to the database",
+        shown_output, table, rows, reason
+    )).await.categorize(Error::OutputIo)?;
+    Ok(())
+}
+
+async fn run_config_command<R>(io: &mut IO<R>, config_path: &Path, data_dir: &Path,
+                               command: ConfigCommand) -> Result<()> where R: io::BufRead + Unpin {
+    // The config is saved again after setting a value, which must not write out what the
+    // environment variables it refers to hold
+    let config = match command {
+        ConfigCommand::Set { .. } => Config::load_unexpanded(config_path).await,
+        _ => Config::load(config_path).await
     };
-    app.run().await
+    let config = config
+        .wrap_err_with(|| format!("Unable to load config from {}", config_path.display()))
+        .categorize(Error::Config)?;
+    match command {
+        ConfigCommand::Show => {
+            let config = config.ok_or_else(|| {
+                Error::Config(eyre!("No config exists at {:?}", config_path))
+            })?;
+            let shown = config.masked().to_pretty_string().categorize(Error::Config)?;
+            io.write_message(&format!("Config loaded from {}", config_path.display())).await
+                .categorize(Error::OutputIo)?;
+            io.write_data(&shown).await.categorize(Error::OutputIo)?;
+        },
+        ConfigCommand::Set { key, value } => {
+            let mut config = config.unwrap_or_default();
+            config.set(&key, value).categorize(Error::Usage)?;
+            config.save(config_path).await
+                .wrap_err_with(|| format!("Unable to write config to {:?}", config_path))
+                .categorize(Error::Config)?;
+            io.write_message(&format!("Set {} in {}", key, config_path.display())).await
+                .categorize(Error::OutputIo)?;
+        },
+        ConfigCommand::SetPassword => {
+            let config = config.ok_or_else(|| {
+                Error::Config(eyre!("No config exists at {:?}", config_path))
+            })?;
+            let user = password::keyring_user(&config.postgres_url).categorize(Error::Config)?;
+            let keyring = password::system_keyring().categorize(Error::Config)?;
+            let password = io.prompt_hidden(&format!("Enter the database password for {}.", user)).await
+                .categorize(Error::OutputIo)?;
+            if password.is_empty() {
+                return Err(Error::Usage(eyre!("No password was entered, so none was stored")));
+            }
+            keyring.set(password::SERVICE, &user, &password).categorize(Error::Config)?;
+            let mut message = format!("Stored the password for {} in the keyring", user);
+            if config.password_source != PasswordSource::Keyring {
+                message.push_str(". Run data-sifter config set password_source keyring to use it");
+            }
+            io.write_message(&message).await.categorize(Error::OutputIo)?;
+        },
+        ConfigCommand::Validate => {
+            let config = config.ok_or_else(|| {
+                Error::Config(eyre!("No config exists at {:?}", config_path))
+            })?;
+            if config.backend == Backend::Duckdb {
+                let embedded = Embedded::open(&embedded::database_file(&config, data_dir.as_ref()))?;
+                return io.write_message(&format!(
+                    "The config at {} is valid, and the {} can be opened", config_path.display(), embedded.description()
+                )).await.categorize(Error::OutputIo);
+            }
+            let postgres_url = connection_url(io, &config, true).await?;
+            let pool = database::pool_options(&config)
+                .connect_with(database::connect_options(&config, &postgres_url)?).await
+                .map_err(|error| Error::from_database(error, Error::Connect))?;
+            sqlx::query("SELECT 1").execute(&pool).await
+                .map_err(|error| Error::from_database(error, Error::Connect))?;
+            io.write_message(&format!(
+                "The config at {} is valid, and the database can be reached", config_path.display()
+            )).await.categorize(Error::OutputIo)?;
+        }
+    }
+    Ok(())
+}
+
+/// Lists the tables data-sifter created in the configured schema longer ago than the age, and
+/// drops them once confirmed, unless it is a dry run
+async fn clean_tables<R>(io: &mut IO<R>, config: &Config, options: PgConnectOptions, age: Duration,
+                         dry_run: bool) -> Result<()> where R: io::BufRead + Unpin {
+    let mut connection = PgConnection::connect_with(&options).await
+        .map_err(|error| Error::from_database(error, Error::Connect))?;
+    let tables = clean::marked_tables(config.schema.as_deref(), &mut connection).await
+        .map_err(|error| Error::from_database(error, Error::QueryRuntime))?;
+    let old = clean::older_than(tables, age, chrono::Utc::now());
+    if old.is_empty() {
+        return io.write_message("No table which data-sifter created is that old").await.categorize(Error::OutputIo);
+    }
+    io.write_data(&old.iter().map(MarkedTable::describe).join("\n")).await.categorize(Error::OutputIo)?;
+    let size = progress::format_bytes(old.iter().map(|table| table.size).sum());
+    if dry_run {
+        return io.write_message(&format!(
+            "Would drop {} tables, freeing {}. Run again without --dry-run to drop them", old.len(), size
+        )).await.categorize(Error::OutputIo);
+    }
+    let answer = io.prompt(&format!("Drop these {} tables, freeing {}? (y/n)", old.len(), size)).await
+        .categorize(Error::OutputIo)?;
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        return io.write_message("Left the tables as they are").await.categorize(Error::OutputIo);
+    }
+    for table in &old {
+        sqlx::query(&format!("DROP TABLE IF EXISTS {}", table.name)).execute(&mut connection).await
+            .map_err(|error| Error::from_database(error, Error::QueryRuntime))?;
+    }
+    io.write_styled(Style::Highlight, &format!("Dropped {} tables, freeing {}", old.len(), size)).await
+        .categorize(Error::OutputIo)
 }
 
+/// Terminal input and output. Query results are the only thing written to stdout, so that
+/// they can be piped elsewhere; everything meant for the user goes to stderr.
 pub struct IO<R> {
     input: R,
-    output: io::Stdout
+    /// Where query results are written
+    data: io::Stdout,
+    /// Where prompts, warnings and summaries are written
+    messages: io::Stderr,
+    /// Whether query results are seen by a person rather than another program
+    data_is_terminal: bool,
+    /// Whether messages are seen by a person, who can be shown progress
+    messages_are_terminal: bool,
+    /// Whether messages are styled
+    color: bool,
+    /// Which messages are written
+    verbosity: Verbosity,
+    /// The session being recorded or replayed, if any
+    script: Option<Script>
+}
+
+/// How much data-sifter says about what it is doing. Prompts, warnings and errors are always
+/// written, whatever the verbosity
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Verbosity {
+    /// Only prompts, warnings and errors, so that logs hold nothing else
+    Quiet,
+    /// Summaries, plans and progress as well
+    Normal
 }
 
 impl<R> IO<R> where R: io::BufRead + Unpin {
 
-    async fn write_output(&mut self, line: &str) -> Result<()> {
-        self.output.write_all(line.as_bytes()).await?;
-        self.output.write_all(b"\n").await?;
-        Ok(self.output.flush().await?)
+    /// Whether progress is drawn, which needs a person watching the messages
+    fn shows_progress(&self) -> bool {
+        self.messages_are_terminal && self.verbosity != Verbosity::Quiet
+    }
+
+    /// Reads all that is left of the input, which is only done when nothing is prompted for
+    async fn read_rest(&mut self) -> eyre::Result<String> {
+        let mut rest = String::new();
+        self.input.read_to_string(&mut rest).await?;
+        Ok(rest)
+    }
+
+    async fn write_data(&mut self, line: &str) -> eyre::Result<()> {
+        self.data.write_all(line.as_bytes()).await?;
+        self.data.write_all(b"\n").await?;
+        Ok(self.data.flush().await?)
+    }
+
+    /// Writes an informational message, such as a summary, unless quiet
+    async fn write_message(&mut self, line: &str) -> eyre::Result<()> {
+        if self.verbosity == Verbosity::Quiet {
+            return Ok(());
+        }
+        self.write_always(line).await
+    }
+
+    /// Writes what the user asked to see, such as the tables listed by 'tables', or an error.
+    /// Unlike informational messages, these are written even when quiet
+    async fn write_always(&mut self, line: &str) -> eyre::Result<()> {
+        self.messages.write_all(line.as_bytes()).await?;
+        self.messages.write_all(b"\n").await?;
+        Ok(self.messages.flush().await?)
+    }
+
+    /// Writes a message in the given style. Prompts, errors and warnings are written even when
+    /// quiet, but highlighted messages are informational
+    async fn write_styled(&mut self, style: Style, line: &str) -> eyre::Result<()> {
+        let painted = style::paint(self.color, style, line);
+        match style {
+            Style::Prompt | Style::Error => self.write_always(&painted).await,
+            Style::Highlight => self.write_message(&painted).await
+        }
+    }
+
+    /// Uses the answer given on the command line, or prompts for one if there is none.
+    /// In non-interactive mode, a missing answer is an error.
+    async fn answer(&mut self, given: Option<String>, non_interactive: bool,
+                    flag: &str, question: &str) -> Result<String> {
+        if let Some(given) = given {
+            return Ok(given);
+        }
+        if non_interactive {
+            return Err(Error::Usage(eyre!("{} must be specified in non-interactive mode", flag)));
+        }
+        self.prompt(question).await.categorize(Error::OutputIo)
+    }
+
+    async fn prompt(&mut self, question: &str) -> eyre::Result<String> {
+        Ok(self.prompt_until_end(question).await?.unwrap_or_default())
     }
 
-    async fn prompt(&mut self, question: &str) -> Result<String> {
-        self.write_output(question).await?;
+    /// Prompts for a password, which is not shown as it is typed on a terminal. Unlike other
+    /// answers, it is never recorded or replayed
+    async fn prompt_hidden(&mut self, question: &str) -> eyre::Result<String> {
+        let hidden = if std::io::stdin().is_terminal() { Some(HiddenTyping::start()?) } else { None };
+        let answer = self.prompt_unscripted(question).await;
+        if let Some(hidden) = hidden {
+            hidden.stop()?;
+            // The line typed ended without being shown
+            self.write_always("").await?;
+        }
+        answer
+    }
 
+    /// Prompts for an answer which is neither recorded nor replayed, since it is private or
+    /// depends on the machine
+    async fn prompt_unscripted(&mut self, question: &str) -> eyre::Result<String> {
+        self.write_styled(Style::Prompt, question).await?;
         let mut buffer = String::new();
         self.input.read_line(&mut buffer).await?;
-        buffer.pop(); // Remove trailing \n
+        if buffer.ends_with('\n') {
+            buffer.pop();
+        }
         Ok(buffer)
     }
+
+    /// Prompts for an answer, or returns none if the input has ended. When replaying a
+    /// session, the answer is the recorded one, and is shown after the prompt
+    async fn prompt_until_end(&mut self, question: &str) -> eyre::Result<Option<String>> {
+        self.write_styled(Style::Prompt, question).await?;
+
+        if let Some(Script::Replay(replay)) = &mut self.script {
+            let answer = replay.answer(question)?;
+            if let Some(answer) = &answer {
+                self.write_always(answer).await?;
+            }
+            return Ok(answer);
+        }
+        let mut buffer = String::new();
+        if self.input.read_line(&mut buffer).await? == 0 {
+            return Ok(None);
+        }
+        if buffer.ends_with('\n') {
+            buffer.pop();
+        }
+        if let Some(Script::Record(recording)) = &mut self.script {
+            recording.record(question, &buffer).await?;
+        }
+        Ok(Some(buffer))
+    }
+}
+
+/// What is typed on the terminal going unshown, until stopped or dropped, so that the terminal
+/// is never left hiding it
+struct HiddenTyping;
+
+impl HiddenTyping {
+    fn start() -> eyre::Result<Self> {
+        set_echo(false)?;
+        Ok(Self)
+    }
+
+    /// Shows what is typed again, reporting if it cannot
+    fn stop(self) -> eyre::Result<()> {
+        std::mem::forget(self);
+        set_echo(true)
+    }
+}
+
+impl Drop for HiddenTyping {
+    fn drop(&mut self) {
+        let _ = set_echo(true);
+    }
+}
+
+/// Turns the terminal's showing of what is typed on or off
+fn set_echo(on: bool) -> eyre::Result<()> {
+    let status = std::process::Command::new("stty").arg(if on { "echo" } else { "-echo" }).status()
+        .wrap_err("Unable to run stty, which hides the password as it is typed")?;
+    if !status.success() {
+        return Err(eyre!("stty was unable to change whether the terminal shows what is typed"));
+    }
+    Ok(())
+}
+
+const DATASET_QUESTION: &str = "Enter CSV dataset file or http(s) URL. Use the value KEEP to keep your existing \
+data, or DRY-RUN <file or URL> to validate a dataset without importing it.";
+
+/// How many rows are shown on the terminal, if the user chooses to limit a query without a LIMIT
+const SHOWN_ROWS: u64 = 100;
+/// The widest a column of results shown on the terminal may be, in terminal columns
+const SHOWN_COLUMN_WIDTH: usize = 40;
+/// How many columns the results may have before writing them is confirmed
+const WIDE_RESULTS: usize = 50;
+/// How many rows are sorted by data-sifter to write them in a deterministic order, unless the
+/// config says otherwise
+const SORT_ROW_CAP: u64 = 100_000;
+/// The column which a deterministic order is by, if the results have it
+const SOURCE_LINE: &str = "_source_line";
+/// The width expanded results are wrapped to fit, unless the shell exports COLUMNS
+const DEFAULT_TERMINAL_WIDTH: usize = 80;
+/// How many seconds a query takes before the user is offered to see where the time went,
+/// unless the config says otherwise
+const SLOW_QUERY_SECS: u64 = 10;
+
+/// Queries which data-sifter writes on the user's behalf
+enum QueryGenerator {
+    Pivot(Pivot),
+    Top(TopValues),
+    Bucket(TimeBuckets),
+    Cardinality(Cardinality)
+}
+
+/// The database which holds the tables
+enum Database {
+    Postgres(PgPool),
+    /// DuckDB, which holds the tables instead of Postgres with the duckdb backend
+    Embedded(Arc<Embedded>)
 }
 
 struct App<R> {
     io: IO<R>,
-    connection_pool: PgPool
+    args: Args,
+    config: Config,
+    /// Where files such as import checkpoints are kept
+    data_dir: PathBuf,
+    database: Database,
+    /// What the server can do, once it has been asked
+    capabilities: Option<ServerCapabilities>,
+    /// The tables loaded during the session, the one queried by default last
+    tables: IndexMap<String, LoadedTable>,
+    /// A dataset being copied to its table in the background
+    pending_import: Option<PendingImport>,
+    /// Reports of waiting for a connection while all of the pool's are in use, which
+    /// query_connection sends to be written as messages are
+    connection_waits: (Sender<String>, Receiver<String>),
+    /// The output files written during the session, canonicalized
+    outputs: Vec<PathBuf>,
+    /// The profiles of the datasets imported during the session, by table
+    profiles: HashMap<String, DatasetProfile>,
+    /// What the session did, for --report
+    report: RunReport,
+    /// Whether shown results are expanded, with each row as a block of lines, as toggled by \x
+    expanded: bool,
+    /// The columns whose values are masked in shown results, as set by \redact
+    redacted: Vec<String>
 }
 
-impl<R> App<R> where R: io::BufRead + Unpin + Send {
+/// A dataset being copied to its table
+struct PendingImport {
+    table: String,
+    source: Option<CsvInput>,
+    handle: JoinHandle<Result<ImportSummary>>,
+    /// Shown while waiting for the import, unless the existing data was kept
+    progress: Option<Arc<ImportProgress>>,
+    /// Whether the progress is only reported once the import ends, as DuckDB's is, and so is
+    /// not worth showing
+    counted_at_end: bool,
+    /// The same import with batched INSERT statements, which can be retried if COPY fails or a
+    /// value stops it
+    fallback: Option<Import>,
+    /// The file imported and its fingerprint, under which the import is recorded once it finishes
+    fingerprint: Option<(std::path::PathBuf, Fingerprint)>
+}
 
-    async fn run(&mut self) -> Result<()> {
+impl PendingImport {
 
-        // Spawn a separate task so that the CSV is copied to the database in the background
-        let csv_to_database: JoinHandle<Result<()>>= {
-            let csv_input = self.io.prompt(
-                "Enter CSV dataset file. Use the value KEEP to keep your existing data."
-            ).await?;
-            if csv_input == "KEEP" {
-                task::spawn(async { Ok(()) })
-            } else {
-                let csv_input = PathBuf::from(csv_input).canonicalize().await?;
-                let pool = self.connection_pool.clone();
-                task::spawn(read_csv_then_write_to_database(pool, csv_input))
-            }
-        };
+    /// Whether the import has ended, keeping its result for finish_import to await
+    async fn has_ended(&mut self) -> bool {
+        match futures_lite::future::poll_once(&mut self.handle).await {
+            Some(imported) => {
+                self.handle = task::spawn(async { imported });
+                true
+            },
+            None => false
+        }
+    }
+}
 
-        let query = self.io.prompt("Enter SQL query. Your data is in the \"data\" table").await?;
-        let query = {
-            let pool = self.connection_pool.clone();
-            async move {
-                // Wait for the data to be ready before executing a query
-                csv_to_database.await?;
+/// Results written to a temporary file, which is kept once they are all written
+struct WrittenFile {
+    staged: StagedFile,
+    file_writer: HashingWriter<fs::File>,
+    output_file: PathBuf,
+    manifest_file: Option<PathBuf>,
+    format: OutputFormat,
+    summary: OutputSummary,
+    progress: Arc<ExportProgress>,
+    query: String
+}
 
-                Ok::<_, eyre::Report>(Query {
-                    query,
-                    connection: pool.acquire().await?
-                })
-            }
+/// A table which was loaded during the session
+struct LoadedTable {
+    /// The dataset imported into the table, unless it already existed or holds query results
+    source: Option<CsvInput>,
+    columns: Vec<TableColumn>,
+    rows: u64,
+    rows_estimated: bool
+}
+
+impl LoadedTable {
+
+    /// Describes the table, e.g. sales: 1000 rows with columns id, amount, cents (computed),
+    /// from sales.csv
+    fn summary(&self, table: &str) -> String {
+        let rows = if self.rows_estimated {
+            format!("~{}", database::approximate_count(self.rows))
+        } else {
+            self.rows.to_string()
         };
+        let source = self.source.as_ref().map(|source| format!(", from {}", source)).unwrap_or_default();
+        let columns = self.columns.iter()
+            .map(|column| match column.computed {
+                Some(_) => format!("{} (computed)", column.name),
+                None => column.name.clone()
+            })
+            .join(", ");
+        format!("{}: {} rows with columns {}{}", table, rows, columns, source)
+    }
+}
 
-        let next = self.io.prompt("
-        What would you like to do with this query?
-        'csv' - Query the dataset and output the results to CSV.
-        'show' - Query the dataset and show the results here.
-        ").await?;
-        match next.as_str() {
-            "csv" => {
-                let csv_file = self.io.prompt("Enter output CSV file").await?;
-                let csv_file = PathBuf::from(csv_file);
-                if csv_file.exists().await {
-                    eyre::bail!("Delete existing file first")
-                }
-                let any_results = {
-                    let file_writer = OpenOptions::new()
-                        .write(true)
-                        .create_new(true)
-                        .open(&csv_file).await?;
-                    query.await?
-                        .execute()
-                        .output_query_results(file_writer)
-                        .await?
-                };
-                if any_results {
-                    let csv_file = csv_file.canonicalize().await?.into_os_string();
-                    let stdout = &mut self.io.output;
-                    stdout.write_all(b"Wrote output CSV to ").await?;
-                    stdout.write_all(csv_file.as_bytes()).await?;
-                    stdout.flush().await?;
-                } else {
-                    self.io.write_output("No results").await?;
-                }
-                Ok(())
-            },
-            "show" => {
-                let any_results = query.await?
-                    .execute()
-                    .output_query_results(&mut self.io.output)
-                    .await?;
+impl<R> App<R> where R: io::BufRead + Unpin + Send {
+
+    async fn answer(&mut self, given: Option<String>, flag: &str, question: &str) -> Result<String> {
+        self.io.answer(given, self.args.non_interactive(), flag, question).await
+    }
 
-                if !any_results {
-                    self.io.write_output("No results").await?;
+    /// The table name qualified with the configured schema, if there is one
+    fn qualify(&self, table: &str) -> String {
+        database::qualify(table, self.config.schema.as_deref())
+    }
+
+    /// Decides which table the dataset is imported into, and whether an interrupted import is
+    /// resumed. A table which already contains data is never dropped without confirmation, or
+    /// the --replace flag in non-interactive mode. In strict mode, the table is never dropped.
+    async fn choose_import_target(&mut self, csv_input: &CsvInput,
+                                  table: String) -> Result<(String, TableMode, Option<Checkpoint>)> {
+        if !database::is_table_name(&table) {
+            return Err(Error::Usage(eyre!("Invalid table name: {}", table)));
+        }
+        let mut table = self.qualify(&table);
+        loop {
+            if let Some(checkpoint) = self.resumable_import(csv_input, &table).await? {
+                let mode = if self.args.strict_schema { TableMode::Strict } else { TableMode::Append };
+                return Ok((table, mode, Some(checkpoint)));
+            }
+            if self.args.strict_schema {
+                return Ok((table, TableMode::Strict, None));
+            }
+            let row_count = self.table_row_count(&table, false, Error::ImportData).await?;
+            let row_count = match row_count {
+                None | Some(0) => return Ok((table, TableMode::Replace, None)),
+                Some(row_count) => row_count
+            };
+            if self.args.replace {
+                return Ok((table, TableMode::Replace, None));
+            }
+            if self.args.append {
+                return Ok((table, TableMode::Append, None));
+            }
+            if self.args.non_interactive() {
+                return Err(Error::Usage(eyre!(
+                    "Table {} already contains data. Use --replace to drop it, --append to add to it, \
+                    or --table to choose another table", table
+                )));
+            }
+            let replace = self.io.prompt(&format!(
+                "Table {} exists with ~{} rows — drop and replace? (y/n)",
+                table, database::approximate_count(row_count)
+            )).await.categorize(Error::OutputIo)?;
+            if replace.trim().eq_ignore_ascii_case("y") {
+                return Ok((table, TableMode::Replace, None));
+            }
+            loop {
+                let alternative = self.io.prompt(&format!(
+                    "Enter 'append' to add the dataset to table {}, or enter a different table name", table
+                )).await.categorize(Error::OutputIo)?;
+                let alternative = alternative.trim();
+                if alternative == "append" {
+                    return Ok((table, TableMode::Append, None));
+                }
+                if database::is_table_name(alternative) {
+                    table = self.qualify(alternative);
+                    break;
                 }
-                Ok(())
+                self.io.write_styled(
+                    Style::Error, "Table names may only contain letters, digits and underscores"
+                ).await.categorize(Error::OutputIo)?;
             }
-            unknown_option => eyre::bail!("Invalid option: {}", unknown_option)
         }
     }
-}
-
-async fn read_csv_then_write_to_database(pool: PgPool, csv_input: PathBuf) -> Result<()> {
-    use crate::database::Schema;
 
-    assert!(csv_input.exists().await, "Specified CSV file {:?} does not exist", csv_input);
+    async fn run(&mut self) -> Result<()> {
 
-    let csv_input = io::BufReader::new(File::open(csv_input).await?);
-    let mut csv_input = csv_async::AsyncReader::from_reader(csv_input);
+        // Checked before anything is imported
+        let decimal_places = DecimalPlaces::parse(&self.args.decimals).map_err(Error::Usage)?;
+        let mut batch = match &self.args.batch {
+            Some(path) => Some(BatchSpec::load(Path::new(path)).await.categorize(Error::Usage)?),
+            None => None
+        };
 
-    let schema = &{
-        let first_record = csv_input.headers().await?;
-        let schema = Schema::from(first_record);
+        let csv_input = self.answer(self.args.input.clone(), "--input", DATASET_QUESTION).await?;
+        if let Some(dry_run_input) = csv_input.strip_prefix("DRY-RUN ") {
+            // The pool is lazy, so no connection has been made
+            return dry_run(&mut self.io, &self.args, &self.config, dry_run_input.trim(), &mut self.report).await;
+        }
+        // Each session setting is tried before anything is imported, so that one which the server
+        // refuses is reported by name rather than failing the first query
+        if let Database::Postgres(pool) = &self.database {
+            if !self.config.session_settings.is_empty() {
+                pool.acquire().await.map_err(|error| Error::from_database(error, Error::Connect))?;
+            }
+        }
+        let mut csv_input = csv_input;
+        let first_query = loop {
+            let table = self.args.table.clone();
+            let started = if archive::is_archive(&csv_input) {
+                self.load_archive(&csv_input).await
+            } else {
+                self.start_import(&csv_input, table).await
+            };
+            match started.context_with(|| format!("Unable to import {}", remote::shown(&csv_input))) {
+                Ok(true) => break match batch.take() {
+                    Some(batch) => self.run_batch(batch, &decimal_places).await,
+                    None if self.args.tui => self.run_tui(&decimal_places).await,
+                    None => self.run_query(&decimal_places).await
+                },
+                Ok(false) => (),
+                Err(error) => break Err(error)
+            }
+            // The plan was declined, so another dataset is asked for
+            csv_input = self.io.prompt(DATASET_QUESTION).await.categorize(Error::OutputIo)?;
+            if let Some(dry_run_input) = csv_input.strip_prefix("DRY-RUN ") {
+                return dry_run(&mut self.io, &self.args, &self.config, dry_run_input.trim(), &mut self.report).await;
+            }
+        };
+        // The session ends when the terminal UI is quit
+        if self.args.non_interactive() || self.args.tui {
+            return first_query;
+        }
+        if let Err(error) = first_query {
+            self.show_error(error).await?;
+        }
+        // Answers given as arguments are only for the first dataset and query
+        self.args.input = None;
+        self.args.replace = false;
+        self.args.append = false;
+        self.args.resume = false;
+        self.args.key = None;
+        self.args.on_conflict = None;
+        self.args.computed.clear();
+        self.args.transform.clear();
+        self.args.filter = None;
+        self.args.pivot_rows = None;
+        self.args.pivot_column = None;
+        self.args.pivot_value = None;
+        self.args.pivot_aggregate = None;
+        self.args.format = None;
+        self.args.materialize = None;
+        self.args.output = None;
+        self.args.columns = None;
+        self.menu(&decimal_places).await
+    }
 
-        let mut connection = pool.acquire().await?;
-        schema.create_or_recreate_table(&mut connection).await?;
+    /// Offers what to do next, until the user quits or the input ends
+    async fn menu(&mut self, decimal_places: &DecimalPlaces) -> Result<()> {
+        loop {
+            // An import is left unfinished if the query after it could not be run
+            if let Err(error) = self.finish_import().await {
+                self.show_error(error).await?;
+            }
+            let choice = self.io.prompt_until_end("\
+What next?
+'load' - Load another dataset into a table of its own.
+'query' - Query the loaded tables.
+'tables' or '\\dt' - List the loaded tables.
+'\\d [table]' - Describe the columns of a table, by default the one queried.
+'\\x' - Toggle expanded display, showing each row of the results as a block of lines.
+'\\redact <columns>' - Mask the values of these columns in shown results. 'off' unmasks them.
+'\\set [name] [value]' - Apply a server setting, such as work_mem, to every query. Lists them without a name.
+'drop <table>' - Drop a table.
+'set <key> <value>' - Change a config setting for this session, such as 'set timezone local'.
+'quit' - Exit data-sifter.").await.categorize(Error::OutputIo)?;
+            let choice = match choice {
+                None => return Ok(()),
+                Some(choice) => choice
+            };
+            let choice = choice.trim();
+            let (action, argument) = choice.split_once(' ').unwrap_or((choice, ""));
+            let result = match action {
+                "load" => self.load_dataset().await,
+                "query" => self.run_query(decimal_places).await,
+                "tables" | "\\dt" => self.list_tables().await,
+                "\\d" => self.describe_table(argument.trim()).await,
+                "\\x" => self.toggle_expanded().await,
+                "\\redact" => self.set_redaction(argument.trim()).await,
+                "\\set" => self.session_setting(argument.trim()).await,
+                "drop" => self.drop_table(argument.trim()).await,
+                "set" => self.change_setting(argument.trim()).await,
+                "quit" | "exit" => return Ok(()),
+                "" => Ok(()),
+                unknown => Err(Error::Usage(eyre!("Unknown choice: {}", unknown)))
+            };
+            if let Err(error) = result {
+                self.show_error(error).await?;
+            }
+        }
+    }
 
-        schema
-    };
-    let column_names = schema.column_names_joined_by_commas();
+    /// Queries the loaded tables in the terminal UI until the user quits. The import goes on
+    /// meanwhile, its progress drawn below the panes. Once it ends, the UI is suspended while it
+    /// is finished, since that writes a summary and may ask whether to retry it
+    async fn run_tui(&mut self, decimal_places: &DecimalPlaces) -> Result<()> {
+        let mut view = View::new(self.io.color);
+        let mut terminal = TerminalUi::enter().categorize(Error::OutputIo)?;
+        let mut estimator = RateEstimator::default();
+        loop {
+            if let Some(pending) = &mut self.pending_import {
+                if pending.has_ended().await {
+                    let table = pending.table.clone();
+                    terminal.suspend().categorize(Error::OutputIo)?;
+                    let finished = self.finish_import().await;
+                    terminal.resume().categorize(Error::OutputIo)?;
+                    match finished {
+                        Ok(()) => view.say(format!(
+                            "Table {} is ready to query. What the import did is shown once you quit", table
+                        )),
+                        Err(error) => view.fail(error.render())
+                    }
+                }
+            }
+            view.set_tables(self.tables.iter()
+                .map(|(table, loaded)| {
+                    (table.clone(), loaded.columns.iter().map(|column| column.name.clone()).collect())
+                })
+                .collect());
+            let importing = self.pending_import.as_ref()
+                .map(|pending| (pending, pending.progress.as_ref().filter(|_| !pending.counted_at_end)));
+            view.set_import(importing.map(|(pending, progress)| match progress {
+                Some(progress) => (
+                    format!("{}: {}", pending.table, progress.report(&mut estimator)), progress.fraction()
+                ),
+                None => (format!("Importing into {}…", pending.table), None)
+            }));
+            terminal.draw(&mut view).categorize(Error::OutputIo)?;
+            let key = match tui::next_key().await.categorize(Error::OutputIo)? {
+                Some(key) => key,
+                None => continue
+            };
+            match view.handle(key) {
+                Action::Nothing => (),
+                Action::Quit => break,
+                Action::Run(_) if self.pending_import.is_some() => {
+                    view.fail("The query can run once the import is done, which it is not yet");
+                },
+                Action::Run(query) => {
+                    view.say("Running the query…");
+                    terminal.draw(&mut view).categorize(Error::OutputIo)?;
+                    match self.tui_query(query, decimal_places).await {
+                        Ok((results, message)) => {
+                            view.show_results(results);
+                            view.say(message);
+                        },
+                        Err(error) => view.fail(error.render())
+                    }
+                }
+            }
+        }
+        terminal.leave().categorize(Error::OutputIo)
+    }
 
-    let futures = FuturesUnordered::new();
-    let mut records = csv_input.records();
-    while let Some(record) = records.next().await {
-        let record = record?;
-        assert_eq!(schema.len(), record.len(), "Field list must match");
+    /// Runs a query written in the terminal UI, returning its results and what to say of them.
+    /// Nothing can be asked in the UI, so only one statement is run, and without parameters
+    async fn tui_query(&mut self, query: String, decimal_places: &DecimalPlaces) -> Result<(Results, String)> {
+        if statements::split(&query).len() > 1 {
+            return Err(Error::Usage(eyre!("The terminal UI runs one statement at a time")));
+        }
+        if statements::parameter_count(&query) > 0 {
+            return Err(Error::Usage(eyre!(
+                "Parameters such as $1 need values, which the terminal UI cannot ask for. Write the values instead"
+            )));
+        }
+        self.report.start_query(query.clone());
+        let limited = database::is_unlimited_select(&query);
+        let query = match limited {
+            true => database::limited(&query, tui::SHOWN_ROWS),
+            false => query
+        };
+        self.reported_query().query = query.clone();
+        let mut options = self.export_options(decimal_places);
+        self.show_options(&mut options, Destination::Show);
+        // The UI lays the values out in columns of its own
+        options.readable = None;
+        options.flush_rows = false;
+        let progress = Arc::new(ExportProgress::default());
+        let mut csv = Vec::new();
+        let summary = match self.embedded() {
+            Some(embedded) => {
+                embedded.output_query_results(&query, OutputFormat::Csv, &options, &mut csv, progress.clone()).await
+                    .map_err(|error| in_query(error, &query, None))?
+            },
+            None => {
+                let connection = self.query_connection().await?;
+                let mut query = Query { query, connection, statement: None, parameters: Vec::new(), described: None };
+                write_results(&mut query, OutputFormat::Csv, &options, &mut csv, progress.clone(), false).await?
+            }
+        };
+        self.reported_query().finish("show", summary.rows);
+        let results = Results::from_csv(&csv).await
+            .wrap_err("Unable to read back the results").categorize(Error::QueryRuntime)?;
+        let mut message = match summary.rows {
+            0 if results.columns.is_empty() => String::from("Ran the statement"),
+            0 => String::from("No results"),
+            rows if limited && rows == tui::SHOWN_ROWS => format!(
+                "Showed the first {} rows ({}). Add a LIMIT of your own to show others", rows, progress.totals()
+            ),
+            rows => format!("Showed {} rows ({})", rows, progress.totals())
+        };
+        for warning in &summary.warnings {
+            message.push_str(&format!(". Warning: {}", warning));
+        }
+        Ok((results, message))
+    }
 
-        // Pool is an Arc
-        let pool = pool.clone();
-        let column_names = column_names.clone();
+    /// Starts copying the dataset to the table in the background, so that the user can write
+    /// a query meanwhile. Returns false if the import plan was declined, so nothing was started.
+    async fn start_import(&mut self, csv_input: &str, table: String) -> Result<bool> {
+        if csv_input == "KEEP" {
+            if !self.args.computed.is_empty() {
+                return Err(Error::Usage(eyre!("Computed columns can only be added to an imported dataset")));
+            }
+            if !self.args.transform.is_empty() {
+                return Err(Error::Usage(eyre!("Values can only be transformed as a dataset is imported")));
+            }
+            if self.args.filter.is_some() {
+                return Err(Error::Usage(eyre!("A filter can only be applied to an imported dataset")));
+            }
+            self.report.start_import(None, self.qualify(&table));
+            self.pending_import = Some(PendingImport {
+                table: self.qualify(&table), source: None,
+                handle: task::spawn(async { Ok(ImportSummary::default()) }), progress: None, counted_at_end: false,
+                fallback: None, fingerprint: None
+            });
+            return Ok(true);
+        }
+        let dialect = csv_dialect(&self.args, &self.config)?;
+        let csv_input = resolve_input(&mut self.io, &self.args, &self.config, csv_input).await?;
+        self.start_import_of(csv_input, dialect, table).await
+    }
 
-        futures.push(async move {
-            let mut connection = pool.acquire().await?;
-            // INSERT INTO data (col1, col2) VALUES ('val1', 'val2')
-            let query = format!(
-                "INSERT INTO data ({}) VALUES ({})",
-                column_names,
-                record.iter().map(|value| format!("'{}'", value)).join(", "));
-            sqlx::query(&query).execute(&mut connection).await?;
-            Ok::<_, eyre::Report>(())
+    /// Starts importing the resolved dataset, as start_import does
+    async fn start_import_of(&mut self, csv_input: CsvInput, mut dialect: Dialect, table: String) -> Result<bool> {
+        if self.embedded().is_some() {
+            self.check_embedded_import(&csv_input)?;
+        } else if let CsvInput::Parquet { .. } = csv_input {
+            self.check_parquet_import()?;
+        }
+        if let Some(path) = csv_input.path() {
+            self.confirm_not_output(path).await?;
+        }
+        let fingerprint = self.fingerprint(&csv_input).await;
+        if let Some((input, fingerprint)) = &fingerprint {
+            if let Some(reused) = self.offer_reuse(input, fingerprint).await? {
+                self.report.start_import(Some(csv_input.to_string()), reused.table.clone());
+                let rows = reused.rows;
+                self.pending_import = Some(PendingImport {
+                    table: reused.table, source: Some(csv_input),
+                    handle: task::spawn(async move {
+                        Ok(ImportSummary { rows: Some(rows), ..ImportSummary::default() })
+                    }),
+                    progress: None, counted_at_end: false, fallback: None, fingerprint: None
+                });
+                return Ok(true);
+            }
+        }
+        let (table, mode, resume_from) = self.choose_import_target(&csv_input, table).await?;
+        let conflict = self.conflict_key().await?;
+        let mut plan = plan::plan_import(
+            &csv_input, dialect, &table, mode, resume_from.as_ref(), self.database_description()
+        ).await?;
+        // Quoting which the records appear to use instead is offered, or else warned of in the plan
+        let quoting = plan.sniff.as_ref().and_then(|sniff| sniff.quoting).filter(|_| !self.args.non_interactive());
+        if let Some(quoting) = quoting {
+            let answer = self.io.prompt(&format!(
+                "The records of {} appear to be {}. Read them so? (y/n)", csv_input, quoting
+            )).await.categorize(Error::OutputIo)?;
+            if answer.trim().eq_ignore_ascii_case("y") {
+                dialect = quoting;
+                plan = plan::plan_import(
+                    &csv_input, dialect, &table, mode, resume_from.as_ref(), self.database_description()
+                ).await?;
+            }
+        }
+        if let Some(embedded) = self.embedded() {
+            return self.start_embedded_import(&embedded, csv_input, dialect, table, mode, plan, fingerprint).await;
+        }
+        plan.transforms = self.args.transform.iter().map(ToString::to_string).collect();
+        plan.filter = self.args.filter.as_ref().map(ToString::to_string);
+        plan.key = conflict.as_ref().map(ToString::to_string);
+        let appended = self.appended_columns(&csv_input, dialect, &table, mode).await?;
+        plan.columns = appended.as_ref().map(ToString::to_string);
+        let ragged_rows = self.args.ragged_rows.unwrap_or(self.config.ragged_rows);
+        let sampling = self.args.sampling();
+        plan.sampling = sampling.map(|sampling| sampling.to_string());
+        let capabilities = self.capabilities().await?;
+        let parquet = matches!(csv_input, CsvInput::Parquet { .. });
+        let blocker = parquet.then_some("bind the typed values of a Parquet file").or_else(|| import::copy_blocker(
+            dialect, self.args.repair_utf8, ragged_rows, !self.args.transform.is_empty(), self.args.filter.is_some()
+        )).or_else(|| {
+            let typed = mode == TableMode::Strict || appended.is_some()
+                || matches!(csv_input, CsvInput::FixedWidth { .. } | CsvInput::Remote(_));
+            let force_null = !typed || capabilities.supports(Feature::CopyForceNull);
+            (!force_null).then_some("copy empty values as NULL on servers before PostgreSQL 9.4")
+        }).or_else(|| {
+            sampling.is_some().then_some("import a sample of the rows")
+        }).or_else(|| {
+            let resolves = conflict.as_ref().is_some_and(|key| key.on_conflict != OnConflict::Error);
+            resolves.then_some("update or leave out rows whose key is already in the table")
+        }).or_else(|| {
+            // The columns of a download are only known once it starts
+            let leaves_out = match &appended {
+                Some(appended) => !appended.extra.is_empty(),
+                None => self.args.ignore_extra_columns && matches!(csv_input, CsvInput::Url { .. })
+            };
+            leaves_out.then_some("leave out columns which the table does not have")
+        });
+        let threshold = self.config.copy_threshold_mb.unwrap_or(import::COPY_THRESHOLD_MB) * 1_000_000;
+        let (strategy, reason) = self.args.strategy.choose(plan.size, threshold, blocker).categorize(Error::Usage)?;
+        plan.strategy = Some(format!("{}, {}", strategy, reason));
+        if !self.confirm_plan(&plan).await? {
+            return Ok(false);
+        }
+        let generated_columns = capabilities.supports(Feature::GeneratedColumns);
+        let missing = capabilities.require(Feature::GeneratedColumns);
+        if let (false, Err(missing)) = (self.args.computed.is_empty(), missing) {
+            self.io.write_styled(Style::Error, &format!(
+                "Warning: {} — computed columns are filled in once instead, so they are not kept up to date \
+                as rows change", missing
+            )).await.categorize(Error::OutputIo)?;
+        }
+        // Problems with the header or the dialect are shown before the query is asked for, rather
+        // than once it has been written. Downloads are only read once, in the background
+        if csv_input.path().is_some() {
+            import::check_start(&csv_input, dialect, self.args.repair_utf8, ragged_rows).await?;
+        }
+        self.report.start_import(Some(csv_input.to_string()), table.clone());
+        let progress = Arc::new(ImportProgress::default());
+        let import = Import {
+            pool: self.pool()?.clone(),
+            csv_input: csv_input.clone(),
+            dialect,
+            table: table.clone(),
+            mode,
+            checkpoint_path: Checkpoint::path_for(&self.data_dir, &table),
+            resume_from,
+            jobs: self.args.jobs(),
+            repair_utf8: self.args.repair_utf8,
+            ragged_rows,
+            copy: strategy == Strategy::Copy,
+            count_statements: self.args.verbose,
+            computed: self.args.computed.clone(),
+            generated_columns,
+            transforms: self.args.transform.clone(),
+            filter: self.args.filter.clone(),
+            sampling,
+            conflict,
+            // Otherwise, the user is asked what to do, unless in non-interactive mode
+            fix_ups: match ragged_rows {
+                RaggedRows::Skip => FixUpPolicy::always(FixUp::SkipRow),
+                _ => FixUpPolicy::default()
+            },
+            reject_limit: self.args.reject_limit(),
+            ignore_extra_columns: self.args.ignore_extra_columns,
+            observer: progress.clone()
+        };
+        let fallback = Some(Import { copy: false, ..import.clone() });
+        self.pending_import = Some(PendingImport {
+            table, source: Some(csv_input), handle: task::spawn(import.read_csv_then_write_to_database()),
+            progress: Some(progress), counted_at_end: false, fallback, fingerprint
         });
+        Ok(true)
     }
-    for result in futures.collect::<Vec<_>>().await {
-        result?;
+
+    /// Refuses to import with the duckdb backend what only data-sifter's own import can, before
+    /// anything is asked. DuckDB reads CSV and Parquet files by itself, detecting the types of
+    /// their columns, and does nothing else to the rows
+    fn check_embedded_import(&self, csv_input: &CsvInput) -> Result<()> {
+        let unsupported = [
+            (!self.args.computed.is_empty(), "--computed"),
+            (!self.args.transform.is_empty(), "--transform"),
+            (self.args.filter.is_some(), "--filter"),
+            (self.args.key.is_some() || self.args.on_conflict.is_some(), "--key and --on-conflict"),
+            (self.args.sampling().is_some(), "sampling"),
+            (self.args.resume, "--resume"),
+            (self.args.strategy != Strategy::default(), "--strategy")
+        ];
+        if let Some((_, option)) = unsupported.iter().find(|(given, _)| *given) {
+            return Err(Error::Usage(eyre!("{} needs the postgres backend, rather than duckdb", option)));
+        }
+        match csv_input {
+            CsvInput::File(_) | CsvInput::Parquet { .. } => Ok(()),
+            csv_input => Err(Error::Usage(eyre!(
+                "The duckdb backend can only import CSV and Parquet files, not {}", csv_input
+            )))
+        }
     }
-    Ok(())
-}
 
+    /// Refuses what is done to parsed CSV records, since the rows of a Parquet file are inserted
+    /// as batches of typed values
+    fn check_parquet_import(&self) -> Result<()> {
+        let unsupported = [
+            (!self.args.transform.is_empty(), "--transform"),
+            (self.args.filter.is_some(), "--filter"),
+            (self.args.sampling().is_some(), "--sample-rate and --sample-rows")
+        ];
+        if let Some((_, option)) = unsupported.iter().find(|(given, _)| *given) {
+            return Err(Error::Usage(eyre!(
+                "{} applies to CSV records, so it cannot be used with Parquet files, whose rows are inserted \
+                as batches of typed values", option
+            )));
+        }
+        Ok(())
+    }
+
+    /// Starts DuckDB reading the dataset into the table in the background, once the plan is
+    /// confirmed, as start_import_of does for Postgres
+    #[allow(clippy::too_many_arguments)]
+    async fn start_embedded_import(&mut self, embedded: &Embedded, csv_input: CsvInput, dialect: Dialect,
+                                   table: String, mode: TableMode, mut plan: ImportPlan,
+                                   fingerprint: Option<(std::path::PathBuf, Fingerprint)>) -> Result<bool> {
+        if mode == TableMode::Strict {
+            // DuckDB inserts by name whatever columns match, so the table is checked as it is for Postgres
+            let existing = embedded.table_columns(&table).await?;
+            import::read_columns(&csv_input, dialect, self.args.repair_utf8).await
+                .and_then(|schema| schema.match_existing(&table, &existing).categorize(Error::ImportData))
+                .context_with(|| format!("Unable to import {} into table {}", csv_input, table))?;
+        }
+        plan.strategy = Some(String::from("DuckDB's own reader, which detects the types of the columns"));
+        if !self.confirm_plan(&plan).await? {
+            return Ok(false);
+        }
+        let source = match &csv_input {
+            CsvInput::Parquet { path, .. } => embedded::Source::Parquet(path.clone().into()),
+            CsvInput::File(path) => {
+                let ragged_rows = self.args.ragged_rows.unwrap_or(self.config.ragged_rows);
+                import::check_start(&csv_input, dialect, self.args.repair_utf8, ragged_rows).await?;
+                embedded::Source::Csv(path.clone().into(), dialect)
+            },
+            csv_input => unreachable!("{} was refused by check_embedded_import", csv_input)
+        };
+        self.report.start_import(Some(csv_input.to_string()), table.clone());
+        let progress = Arc::new(ImportProgress::default());
+        self.pending_import = Some(PendingImport {
+            handle: task::spawn(embedded.import(source, table.clone(), mode, progress.clone())),
+            table, source: Some(csv_input), progress: Some(progress), counted_at_end: true, fallback: None, fingerprint
+        });
+        Ok(true)
+    }
+
+    /// Which database holds the tables, for showing in the import plan
+    fn database_description(&self) -> String {
+        match &self.database {
+            Database::Postgres(_) => self.config.database_description(),
+            Database::Embedded(embedded) => embedded.description()
+        }
+    }
+
+    /// DuckDB, if it holds the tables rather than Postgres
+    fn embedded(&self) -> Option<Arc<Embedded>> {
+        match &self.database {
+            Database::Postgres(_) => None,
+            Database::Embedded(embedded) => Some(embedded.clone())
+        }
+    }
+
+    /// The pool of connections to Postgres. What needs it is refused with the duckdb backend
+    /// before it is asked for, so the error is only a safeguard
+    fn pool(&self) -> Result<&PgPool> {
+        match &self.database {
+            Database::Postgres(pool) => Ok(pool),
+            Database::Embedded(_) => Err(Error::Usage(eyre!("This needs the postgres backend, rather than duckdb")))
+        }
+    }
+
+    /// The key of the table, if one is declared with --key, and what is done with rows whose key
+    /// is already in it. The key columns are asked for if --on-conflict is given without --key
+    async fn conflict_key(&mut self) -> Result<Option<ConflictKey>> {
+        let on_conflict = self.args.on_conflict.unwrap_or_default();
+        let columns = match (&self.args.key, self.args.on_conflict) {
+            (Some(columns), _) => columns.clone(),
+            (None, None) => return Ok(None),
+            (None, Some(_)) if self.args.non_interactive() => return Err(Error::Usage(eyre!(
+                "--on-conflict {} needs the key columns, given with --key", on_conflict
+            ))),
+            (None, Some(_)) => self.io.prompt(
+                "Enter the key columns identifying a row, separated by commas, such as store,day"
+            ).await.categorize(Error::OutputIo)?
+        };
+        ConflictKey::parse(&columns, on_conflict).map(Some).map_err(Error::Usage)
+    }
+
+    /// How the columns of the dataset match those of the table it is added to, if the table
+    /// exists, so that the plan can show it. A column which the table does not have stops the
+    /// import before it starts, unless left out. Downloads are only read once, so their columns
+    /// are matched as they are imported
+    async fn appended_columns(&mut self, csv_input: &CsvInput, dialect: Dialect, table: &str,
+                              mode: TableMode) -> Result<Option<AppendedColumns>> {
+        if mode != TableMode::Append || matches!(csv_input, CsvInput::Url { .. }) {
+            return Ok(None);
+        }
+        let mut connection = self.pool()?.acquire().await
+            .map_err(|error| Error::from_database(error, Error::Connect))?;
+        let existing = database::table_columns(table, &mut connection).await
+            .map_err(|error| Error::from_database(error, Error::ImportData))?;
+        if existing.is_empty() {
+            return Ok(None);
+        }
+        let schema = import::read_columns(csv_input, dialect, self.args.repair_utf8).await?;
+        let (_, appended) = schema.match_appended(table, &existing, self.args.ignore_extra_columns)
+            .wrap_err_with(|| format!("Unable to import {} into table {}", csv_input, table))
+            .categorize(Error::ImportData)?;
+        Ok(Some(appended))
+    }
+
+    /// What the server can do, asked the first time it is needed and shown with --verbose
+    async fn capabilities(&mut self) -> Result<ServerCapabilities> {
+        if let Some(capabilities) = &self.capabilities {
+            return Ok(capabilities.clone());
+        }
+        let mut connection = self.pool()?.acquire().await
+            .map_err(|error| Error::from_database(error, Error::Connect))?;
+        let capabilities = ServerCapabilities::detect(&mut connection).await
+            .map_err(|error| Error::from_database(error, Error::Connect))?;
+        if self.args.verbose {
+            self.io.write_message(&format!("Server: {}", capabilities)).await.categorize(Error::OutputIo)?;
+        }
+        self.capabilities = Some(capabilities.clone());
+        Ok(capabilities)
+    }
+
+    /// The file's canonical path and fingerprint, under which its import is recorded so that
+    /// a later session can reuse it. Imports shaped by a filter, transforms or computed columns
+    /// are not recorded, nor are downloads
+    async fn fingerprint(&self, csv_input: &CsvInput) -> Option<(std::path::PathBuf, Fingerprint)> {
+        let path = match csv_input {
+            CsvInput::File(path) if self.args.filter.is_none() && self.args.transform.is_empty()
+                && self.args.computed.is_empty() => path,
+            _ => return None
+        };
+        let path = path.canonicalize().await.ok()?;
+        let fingerprint = Fingerprint::of(&path).await.ok()?;
+        Some((path.into(), fingerprint))
+    }
+
+    /// Offers to reuse the table into which the file was imported before, if it has not changed
+    /// since and the table still has as many rows as then. Not asked in non-interactive mode, nor
+    /// while replaying, since whether it is asked depends on what earlier sessions did
+    async fn offer_reuse(&mut self, input: &std::path::Path,
+                         fingerprint: &Fingerprint) -> Result<Option<RecordedImport>> {
+        let replaying = matches!(self.io.script, Some(Script::Replay(_)));
+        if self.args.non_interactive() || replaying {
+            return Ok(None);
+        }
+        let state = match SessionState::load(&SessionState::path_in(&self.data_dir)).await {
+            Ok(state) => state,
+            Err(error) => {
+                self.io.write_styled(Style::Error, &format!("Warning: {:#}", error)).await.categorize(Error::OutputIo)?;
+                return Ok(None);
+            }
+        };
+        let recorded = match state.import_of(input, fingerprint) {
+            Some(recorded) => recorded.clone(),
+            None => return Ok(None)
+        };
+        let rows = self.table_row_count(&recorded.table, true, Error::ImportData).await?;
+        if rows != Some(recorded.rows) {
+            return Ok(None);
+        }
+        let summary = recorded.summary(&chrono::Local, chrono::Local::now().date_naive());
+        let answer = self.io.prompt_unscripted(&format!(
+            "This file appears to already be loaded in table {} - reuse it? (y/n)", summary
+        )).await.categorize(Error::OutputIo)?;
+        Ok(answer.trim().eq_ignore_ascii_case("y").then_some(recorded))
+    }
+
+    /// Records the finished import in the session state, so that a later session can reuse it.
+    /// Failing to do so only warrants a warning
+    async fn remember_import(&mut self, import: RecordedImport) -> Result<()> {
+        let path = SessionState::path_in(&self.data_dir);
+        let remembered = async {
+            let mut state = SessionState::load(&path).await?;
+            state.record(import);
+            state.save(&path).await
+        }.await;
+        if let Err(error) = remembered {
+            self.io.write_styled(Style::Error, &format!("Warning: Unable to record the import in {}: {:#}",
+                                                       path.display(), error)).await.categorize(Error::OutputIo)?;
+        }
+        Ok(())
+    }
+
+    /// Shows the import plan and, unless in non-interactive mode without --confirm, asks whether
+    /// to go ahead. Declining in non-interactive mode is an error, as there is no other dataset
+    /// to ask for.
+    async fn confirm_plan(&mut self, plan: &ImportPlan) -> Result<bool> {
+        self.io.write_message(&plan.to_string()).await.categorize(Error::OutputIo)?;
+        if self.args.non_interactive() && !self.args.confirm {
+            return Ok(true);
+        }
+        let confirm = self.io.prompt("Import as planned? (y/n)").await.categorize(Error::OutputIo)?;
+        if confirm.trim().eq_ignore_ascii_case("y") {
+            Ok(true)
+        } else if self.args.non_interactive() {
+            Err(Error::Usage(eyre!("The import of {} was not confirmed", plan.source)))
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Asks before importing a file which looks like the results of an earlier query, since
+    /// importing it may well replace the data it came from. In non-interactive mode, the file
+    /// was named deliberately, so only a warning is shown.
+    async fn confirm_not_output(&mut self, path: &Path) -> Result<()> {
+        let output_dir = match self.output_dir() {
+            Some(output_dir) => output_dir.canonicalize().await.ok(),
+            None => None
+        };
+        let has_manifest = Manifest::path_for(path).exists().await;
+        let reason = output::previous_output_reason(path, &self.outputs, has_manifest, output_dir.as_deref());
+        let reason = match reason {
+            None => return Ok(()),
+            Some(reason) => reason
+        };
+        let warning = format!("{} looks like the results of an earlier query, since {}", path.display(), reason);
+        if self.args.non_interactive() {
+            return self.io.write_styled(Style::Error, &format!("Warning: {}", warning)).await
+                .categorize(Error::OutputIo);
+        }
+        let confirm = self.io.prompt(&format!("{}. Import it anyway? (y/n)", warning)).await
+            .categorize(Error::OutputIo)?;
+        if confirm.trim().eq_ignore_ascii_case("y") {
+            Ok(())
+        } else {
+            Err(Error::Usage(eyre!("Not importing {}", path.display())))
+        }
+    }
+
+    /// Waits for the import in progress, if there is one, showing its progress on a terminal
+    async fn finish_import(&mut self) -> Result<()> {
+        let mut pending = match self.pending_import.take() {
+            None => return Ok(()),
+            Some(pending) => pending
+        };
+        let summary = loop {
+            let shown = pending.progress.clone().filter(|_| !pending.counted_at_end && self.io.shows_progress());
+            let imported = match shown {
+                Some(progress) => {
+                    let mut estimator = RateEstimator::default();
+                    let imported = pending.handle.or(progress::show(move || progress.report(&mut estimator))).await;
+                    progress::clear().await?;
+                    imported
+                },
+                None => pending.handle.await
+            };
+            let retried = match &imported {
+                Err(error) if import::copy_failed(error) => {
+                    self.retry_with_insert(pending.fallback.clone(), error).await?
+                },
+                Err(error) => match import::type_mismatch(error) {
+                    Some(mismatch) => self.fix_up(pending.fallback.clone(), mismatch).await?,
+                    None => None
+                },
+                _ => None
+            };
+            match retried {
+                Some(retried) => {
+                    // Fix-ups chosen now still apply if the import stops again
+                    pending.fallback = Some(retried.clone());
+                    let progress = Arc::new(ImportProgress::default());
+                    let retried = Import { observer: progress.clone(), ..retried };
+                    pending.handle = task::spawn(retried.read_csv_then_write_to_database());
+                    pending.progress = Some(progress);
+                },
+                None => break imported
+            }
+        };
+        let counted = pending.progress.clone();
+        let summary = summary.context_with(|| match &pending.source {
+            Some(source) => format!("Unable to import {} into table {}", source, pending.table),
+            None => format!("Unable to import into table {}", pending.table)
+        })?;
+        if let Some(reported) = self.report.pending_import() {
+            reported.rows_imported = counted.map(|progress| progress.rows());
+            reported.rows_filtered_out = summary.filtered.map(|filtered| filtered.read - filtered.kept);
+            reported.sample = summary.sampled.map(|sampled| sampled.sampling.to_string());
+            reported.rows_read = summary.sampled.map(|sampled| sampled.read);
+            reported.rows_sampled = summary.sampled.map(|sampled| sampled.sampled);
+            reported.values_transformed = summary.transformed.iter().cloned().collect();
+            reported.rows_updated = summary.conflicts.map(|conflicts| conflicts.updated);
+            reported.rows_skipped_on_conflict = summary.conflicts.map(|conflicts| conflicts.skipped);
+            reported.table_rows = summary.rows;
+            reported.stage_seconds = Some(summary.timings.seconds());
+            reported.finish();
+        }
+        for warning in &summary.warnings {
+            self.io.write_styled(Style::Error, &format!("Warning: {}", warning)).await.categorize(Error::OutputIo)?;
+        }
+        if let (Some((input, fingerprint)), Some(rows)) = (pending.fingerprint.take(), summary.rows) {
+            self.remember_import(RecordedImport {
+                input, table: pending.table.clone(), fingerprint, rows, finished_secs: chrono::Utc::now().timestamp()
+            }).await?;
+        }
+        if !summary.transformed.is_empty() {
+            self.io.write_message(&transform::describe_changes(&summary.transformed)).await
+                .categorize(Error::OutputIo)?;
+        }
+        if let Some(filtered) = summary.filtered {
+            self.io.write_message(&format!(
+                "Kept {} of the {} rows read, which matched the filter", filtered.kept, filtered.read
+            )).await.categorize(Error::OutputIo)?;
+        }
+        if let Some(sampled) = summary.sampled {
+            self.io.write_styled(Style::Highlight, &format!(
+                "Table {} holds a sample, not the whole dataset: {}. Of the {} rows read, {} were sampled and {} imported",
+                pending.table, sampled.sampling, sampled.read, sampled.sampled, sampled.imported
+            )).await.categorize(Error::OutputIo)?;
+        }
+        if let Some(conflicts) = summary.conflicts {
+            self.io.write_message(&format!(
+                "Inserted {} rows. Of those whose key was already in the table, updated {} and skipped {}",
+                conflicts.inserted, conflicts.updated, conflicts.skipped
+            )).await.categorize(Error::OutputIo)?;
+        }
+        if let Some(statements) = summary.statements {
+            self.io.write_message(&format!(
+                "Executed {} INSERT statements, of which {} had to be prepared",
+                statements.executed, statements.prepared
+            )).await.categorize(Error::OutputIo)?;
+        }
+        if self.args.verbose {
+            self.io.write_message(&format!("Time spent importing, summed over the parsing tasks: {}", summary.timings))
+                .await.categorize(Error::OutputIo)?;
+        }
+        match summary.profile {
+            Some(profile) => {
+                if !self.args.non_interactive() || self.args.verbose {
+                    self.io.write_message(&format!("Profile of {}:\n{}", pending.table, profile.table())).await
+                        .categorize(Error::OutputIo)?;
+                }
+                self.profiles.insert(pending.table.clone(), profile);
+            },
+            // The existing data was kept, which the profile of an earlier import may not describe
+            None => {
+                self.profiles.remove(&pending.table);
+            }
+        }
+        self.record_table(pending.table, pending.source, summary.rows).await
+    }
+
+    /// Offers to retry an import which COPY failed with batched INSERT statements, which name the
+    /// line at fault. The rest of the file can be retried from the last checkpoint, and the whole
+    /// file if that adds no row twice. Returns the import to retry, if the user chooses one
+    async fn retry_with_insert(&mut self, fallback: Option<Import>, error: &Error) -> Result<Option<Import>> {
+        let fallback = match fallback {
+            Some(fallback) if !self.args.non_interactive() => fallback,
+            _ => return Ok(None)
+        };
+        let (checkpoint, whole) = retry_points(&fallback).await?;
+        if checkpoint.is_none() && !whole {
+            return Ok(None);
+        }
+        let cause = error.report().chain().last().map(ToString::to_string).unwrap_or_default();
+        let mut question = format!(
+            "COPY failed: {}. Retry with batched INSERT statements, which name the line at fault?", cause
+        );
+        if let Some(checkpoint) = &checkpoint {
+            question.push_str(&format!(
+                "\n'rest' - Retry from line {}, keeping the {} rows committed.",
+                checkpoint.position().line(), checkpoint.table_rows
+            ));
+        }
+        if whole {
+            question.push_str("\n'all' - Retry the whole file.");
+        }
+        question.push_str("\n'n' - Stop the import.");
+        loop {
+            let answer = self.io.prompt(&question).await.categorize(Error::OutputIo)?;
+            match (answer.trim(), &checkpoint) {
+                ("rest", Some(checkpoint)) => {
+                    return Ok(Some(Import { resume_from: Some(checkpoint.clone()), ..fallback }));
+                },
+                ("all", _) if whole => return Ok(Some(Import { resume_from: None, ..fallback })),
+                ("n" | "", _) => return Ok(None),
+                (unknown, _) => self.io.write_styled(Style::Error, &format!("Unknown choice: {}", unknown)).await
+                    .categorize(Error::OutputIo)?
+            }
+        }
+    }
+
+    /// Asks what to do with a value which its column's type cannot hold, then retries the import
+    /// with the choice made, from the last checkpoint or else the start of the file. Choices for
+    /// a whole column apply to its later values too. Not asked in non-interactive mode, in which
+    /// --ragged-rows skip skips such rows instead
+    async fn fix_up(&mut self, fallback: Option<Import>, mismatch: &Mismatch) -> Result<Option<Import>> {
+        let mut fallback = match fallback {
+            Some(fallback) if !self.args.non_interactive() => fallback,
+            _ => return Ok(None)
+        };
+        let resume_from = match retry_points(&fallback).await? {
+            (Some(checkpoint), _) => Some(checkpoint),
+            (None, true) => None,
+            (None, false) => return Ok(None)
+        };
+        let column = &mismatch.column;
+        let strip = mismatch.value.contains(',');
+        let mut question = format!(
+            "{}.\n'skip' - Skip the row on line {}.\n'skip all' - Skip every row with an invalid {} in column {}.\
+            \n'text' - Change column {} to text, which holds any value.",
+            mismatch, mismatch.line, mismatch.type_name, column, column
+        );
+        if strip {
+            question.push_str(&format!("\n'strip' - Remove commas from the values of column {}.", column));
+        }
+        question.push_str("\n'n' - Stop the import.");
+        loop {
+            let answer = self.io.prompt(&question).await.categorize(Error::OutputIo)?;
+            match answer.trim() {
+                "skip" => fallback.fix_ups.skip_line(mismatch.line),
+                "skip all" => fallback.fix_ups.choose(column, FixUp::SkipRow),
+                "strip" if strip => fallback.fix_ups.choose(column, FixUp::StripCommas),
+                "text" => {
+                    let statement = format!(
+                        "ALTER TABLE {} ALTER COLUMN {} TYPE text", fallback.table, database::quote_identifier(column)
+                    );
+                    sqlx::query(&statement).execute(self.pool()?).await
+                        .map_err(|error| Error::from_database(error, Error::ImportData))?;
+                },
+                "n" | "" => return Ok(None),
+                unknown => {
+                    self.io.write_styled(Style::Error, &format!("Unknown choice: {}", unknown)).await
+                        .categorize(Error::OutputIo)?;
+                    continue;
+                }
+            }
+            return Ok(Some(Import { resume_from, ..fallback }));
+        }
+    }
+
+    /// Remembers a table for the rest of the session, unless there is no such table. Not
+    /// needed in non-interactive mode, which ends after one query
+    async fn record_table(&mut self, table: String, source: Option<CsvInput>, rows: Option<u64>) -> Result<()> {
+        if self.args.non_interactive() {
+            return Ok(());
+        }
+        let (rows, rows_estimated) = match rows {
+            Some(rows) => (Some(rows), false),
+            None => (self.table_row_count(&table, false, Error::QueryRuntime).await?, self.embedded().is_none())
+        };
+        // Tables loaded again move to the end, becoming the table queried by default
+        self.tables.shift_remove(&table);
+        if let Some(rows) = rows {
+            let columns = self.table_columns(&table).await?;
+            self.tables.insert(table, LoadedTable { source, columns, rows, rows_estimated });
+        }
+        Ok(())
+    }
+
+    /// How many rows the table has, or none if there is no such table. Postgres's are only
+    /// estimated, unless counted exactly, while DuckDB always counts them
+    async fn table_row_count(&self, table: &str, exact: bool, category: fn(eyre::Report) -> Error) -> Result<Option<u64>> {
+        let pool = match &self.database {
+            Database::Postgres(pool) => pool,
+            Database::Embedded(embedded) => return embedded.row_count(table).await
+        };
+        let mut connection = pool.acquire().await
+            .map_err(|error| Error::from_database(error, Error::Connect))?;
+        let row_count = match exact {
+            true => database::count_rows(table, &mut connection).await,
+            false => database::estimate_row_count(table, &mut connection).await
+        };
+        row_count.map_err(|error| Error::from_database(error, category))
+    }
+
+    /// The columns of the table, in order, which are none if there is no such table
+    async fn table_columns(&self, table: &str) -> Result<Vec<TableColumn>> {
+        let pool = match &self.database {
+            Database::Postgres(pool) => pool,
+            Database::Embedded(embedded) => return embedded.table_columns(table).await
+        };
+        let mut connection = pool.acquire().await
+            .map_err(|error| Error::from_database(error, Error::Connect))?;
+        database::table_columns(table, &mut connection).await
+            .map_err(|error| Error::from_database(error, Error::QueryRuntime))
+    }
+
+    /// Completes queries from the tables loaded so far, including the one being imported, whose
+    /// columns are looked up since it is only recorded once the import finishes. It is made anew
+    /// each time, so that it follows the tables loaded
+    async fn completer(&mut self) -> Result<QueryCompleter> {
+        let mut tables = self.tables.iter()
+            .map(|(table, loaded)| (table.clone(), loaded.columns.iter().map(|column| column.name.clone()).collect()))
+            .collect::<Vec<_>>();
+        let importing = self.pending_import.as_ref().map(|pending| pending.table.clone())
+            .filter(|table| !self.tables.contains_key(table));
+        if let Some(table) = importing {
+            let columns = self.table_columns(&table).await?;
+            tables.push((table, columns.into_iter().map(|column| column.name).collect()));
+        }
+        Ok(QueryCompleter::new(tables))
+    }
+
+    /// The table being imported, or else the table loaded last
+    fn current_table(&self) -> (String, Option<CsvInput>) {
+        match (&self.pending_import, self.tables.last()) {
+            (Some(pending), _) => (pending.table.clone(), pending.source.clone()),
+            (None, Some((table, loaded))) => (table.clone(), loaded.source.clone()),
+            (None, None) => (self.qualify(&self.args.table), None)
+        }
+    }
+
+    /// Loads another dataset. It goes into a table of its own, unless the user chooses to
+    /// replace an existing table
+    async fn load_dataset(&mut self) -> Result<()> {
+        self.finish_import().await?;
+        loop {
+            let csv_input = self.io.prompt(DATASET_QUESTION).await.categorize(Error::OutputIo)?;
+            if let Some(dry_run_input) = csv_input.strip_prefix("DRY-RUN ") {
+                return dry_run(&mut self.io, &self.args, &self.config, dry_run_input.trim(), &mut self.report).await;
+            }
+            if archive::is_archive(&csv_input) {
+                if self.load_archive(&csv_input).await.context_with(|| format!("Unable to import {}", csv_input))? {
+                    return Ok(());
+                }
+                continue;
+            }
+            let default_table = self.unused_table_name(&csv_input);
+            let table = self.io.prompt(&format!(
+                "Enter a table name for the dataset, or nothing for {}", default_table
+            )).await.categorize(Error::OutputIo)?;
+            let table = match table.trim() {
+                "" => default_table,
+                table => String::from(table)
+            };
+            let started = self.start_import(&csv_input, table).await;
+            if started.context_with(|| format!("Unable to import {}", remote::shown(&csv_input)))? {
+                break;
+            }
+        }
+        let (table, _) = self.current_table();
+        self.finish_import().await?;
+        if let Some(loaded) = self.tables.get(&table) {
+            let summary = loaded.summary(&table);
+            self.io.write_styled(Style::Highlight, &format!("Loaded {}", summary)).await
+                .categorize(Error::OutputIo)?;
+        }
+        Ok(())
+    }
+
+    /// Imports the datasets chosen from the ZIP archive one after another, each into a table
+    /// named after it, then lists the tables. In non-interactive mode, all of them are imported.
+    /// Returns false if none was
+    async fn load_archive(&mut self, input: &str) -> Result<bool> {
+        let path = PathBuf::from(input.trim()).canonicalize().await
+            .wrap_err_with(|| format!("Specified ZIP archive {:?} does not exist", input))
+            .categorize(Error::ImportIo)?;
+        let entries = archive::list_datasets(&path).await?;
+        if entries.is_empty() {
+            return Err(Error::ImportData(eyre!("{} contains no CSV or TSV files", path.display())));
+        }
+        let listing = entries.iter().enumerate()
+            .map(|(index, entry)| format!("{}. {} ({})", index + 1, entry.name, progress::format_bytes(entry.size)))
+            .join("\n");
+        self.io.write_message(&format!("{} contains:\n{}", path.display(), listing)).await
+            .categorize(Error::OutputIo)?;
+        let chosen = if self.args.non_interactive() {
+            (0..entries.len()).collect()
+        } else {
+            let answer = self.io.prompt(
+                "Enter 'all' to import every dataset, or the numbers of those to import, such as 1,3-4"
+            ).await.categorize(Error::OutputIo)?;
+            archive::choose_entries(&answer, entries.len()).map_err(Error::Usage)?
+        };
+        let dialect = csv_dialect(&self.args, &self.config)?;
+        let mut loaded = Vec::new();
+        for position in chosen {
+            let entry = Arc::new(entries[position].clone());
+            // Tables are not recorded in non-interactive mode, so those loaded so far are passed too
+            let table = self.unused_name(entry.table_stem(), &loaded);
+            let entry_dialect = entry.detect_dialect(&path, dialect).await?;
+            let csv_input = CsvInput::ZipEntry { archive: path.clone(), entry: entry.clone() };
+            let started = self.start_import_of(csv_input, entry_dialect, table).await
+                .context_with(|| format!("Unable to import {}", entry.name))?;
+            if started {
+                let (table, _) = self.current_table();
+                self.finish_import().await?;
+                loaded.push(table);
+            }
+        }
+        if !loaded.is_empty() {
+            let summaries = loaded.iter()
+                .map(|table| self.tables.get(table).map_or_else(|| table.clone(), |loaded| loaded.summary(table)))
+                .join("\n");
+            self.io.write_styled(Style::Highlight, &format!("Loaded from {}:\n{}", path.display(), summaries)).await
+                .categorize(Error::OutputIo)?;
+        }
+        Ok(!loaded.is_empty())
+    }
+
+    /// A table name for the dataset which is not taken by another loaded table, based on the
+    /// name of its file
+    fn unused_table_name(&self, csv_input: &str) -> String {
+        // A table of another database names it, rather than the database does
+        let name = match csv_input.split_once('#') {
+            Some((url, relation)) if remote::is_remote(url) => relation.trim().rsplit('.').next().unwrap_or_default(),
+            _ => csv_input.split(['?', '#']).next().unwrap_or_default()
+        };
+        let stem = Path::new(name).file_stem()
+            .map(|stem| stem.to_string_lossy().to_lowercase().replace(|c: char| !c.is_ascii_alphanumeric(), "_"))
+            .filter(|stem| database::is_plain_identifier(stem))
+            .unwrap_or_else(|| String::from("data"));
+        self.unused_name(stem, &[])
+    }
+
+    /// The stem, or the stem followed by the first number from 2 which makes a name not taken
+    /// by another loaded table, nor by the other tables given
+    fn unused_name(&self, stem: String, taken: &[String]) -> String {
+        let mut table = stem.clone();
+        let mut suffix = 2;
+        while self.tables.contains_key(&self.qualify(&table)) || taken.contains(&self.qualify(&table)) {
+            table = format!("{}{}", stem, suffix);
+            suffix += 1;
+        }
+        table
+    }
+
+    async fn list_tables(&mut self) -> Result<()> {
+        let listing = if self.tables.is_empty() {
+            String::from("No tables are loaded")
+        } else {
+            self.tables.iter().map(|(table, loaded)| loaded.summary(table)).join("\n")
+        };
+        self.io.write_always(&listing).await.categorize(Error::OutputIo)
+    }
+
+    /// Switches shown results between being aligned in columns and expanded
+    async fn toggle_expanded(&mut self) -> Result<()> {
+        self.expanded = !self.expanded;
+        self.io.write_message(if self.expanded { "Expanded display is on" } else { "Expanded display is off" }).await
+            .categorize(Error::OutputIo)
+    }
+
+    /// Sets the columns whose values are masked in shown results, so that they can be shown while
+    /// screen-sharing. Output files are written in full
+    async fn set_redaction(&mut self, columns: &str) -> Result<()> {
+        self.redacted = match columns {
+            "" => return Err(Error::Usage(eyre!(
+                "Name the columns to redact, separated by commas, such as '\\redact email,name', or 'off'"
+            ))),
+            "off" => Vec::new(),
+            columns => columns.split(',').map(str::trim).filter(|column| !column.is_empty()).map(String::from).collect()
+        };
+        let message = if self.redacted.is_empty() {
+            String::from("Redaction is off")
+        } else {
+            format!("Redacting {} in shown results", self.redacted.join(", "))
+        };
+        self.io.write_message(&message).await.categorize(Error::OutputIo)
+    }
+
+    /// Sets a session setting for every connection, or removes it without a value. Without a
+    /// name, lists the session settings with the values they have on the server
+    async fn session_setting(&mut self, argument: &str) -> Result<()> {
+        if self.embedded().is_some() {
+            return Err(Error::Usage(eyre!("Server settings are those of Postgres, so \\set needs the postgres backend")));
+        }
+        if !argument.is_empty() {
+            let (setting, value) = argument.split_once(' ').unwrap_or((argument, ""));
+            // Quoted as SET quotes it, though the value is never part of a statement
+            let value = value.trim();
+            let value = value.strip_prefix('\'').and_then(|value| value.strip_suffix('\'')).unwrap_or(value);
+            return self.change_setting(&format!("{}{} {}", config::SESSION_SETTINGS_PREFIX, setting, value)).await;
+        }
+        if self.config.session_settings.is_empty() {
+            return self.io.write_message(
+                "No session settings are applied. Apply one with '\\set <name> <value>', such as '\\set work_mem 512MB'"
+            ).await.categorize(Error::OutputIo);
+        }
+        let mut connection = self.reporting_waits(self.query_connection()).await?;
+        let settings = self.config.session_settings.keys().map(String::as_str);
+        let values = database::session_setting_values(settings, &mut connection).await
+            .map_err(|error| Error::from_database(error, Error::QueryRuntime))?;
+        let mut listing = String::from("Session settings, applied to every connection:");
+        for (setting, value) in values {
+            listing.push_str(&format!("\n  {} = {}", setting, value));
+        }
+        self.io.write_always(&listing).await.categorize(Error::OutputIo)
+    }
+
+    /// Lists the columns of a table with their types, and how computed columns are computed
+    async fn describe_table(&mut self, table: &str) -> Result<()> {
+        let table = match table {
+            "" => self.current_table().0,
+            table if database::is_table_name(table) => self.qualify(table),
+            table => return Err(Error::Usage(eyre!("Invalid table name: {}", table)))
+        };
+        let columns = self.table_columns(&table).await?;
+        if columns.is_empty() {
+            return Err(Error::Usage(eyre!("There is no table {}", table)));
+        }
+        let mut description = format!("Table {}:", table);
+        for column in columns {
+            description.push_str(&format!("\n  {}: {}", column.name, column.data_type));
+            if let Some(computed) = column.computed {
+                description.push_str(&format!(", computed as {}", computed));
+            }
+        }
+        self.io.write_always(&description).await.categorize(Error::OutputIo)
+    }
+
+    /// Drops a table after asking for confirmation
+    async fn drop_table(&mut self, table: &str) -> Result<()> {
+        if !database::is_table_name(table) {
+            return Err(Error::Usage(eyre!("Invalid table name: {}", table)));
+        }
+        let table = &self.qualify(table);
+        let row_count = self.table_row_count(table, false, Error::QueryRuntime).await?
+            .ok_or_else(|| Error::Usage(eyre!("There is no table {}", table)))?;
+        let drop = self.io.prompt(&format!(
+            "Drop table {} with ~{} rows? (y/n)", table, database::approximate_count(row_count)
+        )).await.categorize(Error::OutputIo)?;
+        if !drop.trim().eq_ignore_ascii_case("y") {
+            return self.io.write_message(&format!("Left table {} as it is", table)).await
+                .categorize(Error::OutputIo);
+        }
+        match &self.database {
+            Database::Embedded(embedded) => {
+                embedded.execute(&format!("DROP TABLE {}", table)).await?;
+            },
+            Database::Postgres(pool) => {
+                let mut connection = pool.acquire().await
+                    .map_err(|error| Error::from_database(error, Error::Connect))?;
+                sqlx::query(&format!("DROP TABLE {}", table)).execute(&mut connection).await
+                    .map_err(Error::from_query)?;
+            }
+        }
+        self.tables.shift_remove(table);
+        self.profiles.remove(table);
+        self.io.write_styled(Style::Highlight, &format!("Dropped table {}", table)).await
+            .categorize(Error::OutputIo)
+    }
+
+    /// Changes a config setting until data-sifter exits, taking precedence over any argument
+    /// for the same setting
+    async fn change_setting(&mut self, setting: &str) -> Result<()> {
+        let (key, value) = setting.split_once(' ').unwrap_or((setting, ""));
+        if matches!(key, "backend" | "duckdb_file") {
+            return Err(Error::Usage(eyre!(
+                "The tables are opened as data-sifter starts, so {} cannot be changed for this session", key
+            )));
+        }
+        let previous = self.config.clone();
+        self.config.set(key, String::from(value.trim())).categorize(Error::Usage)?;
+        match key {
+            "timezone" => self.args.timezone = None,
+            "timestamp_format" => self.args.timestamp_format = None,
+            "output_dir" => self.args.output_dir = None,
+            "manifest" => self.args.manifest = false,
+            "quote" => self.args.quote = None,
+            "escape" => self.args.escape = None,
+            "double_quote" => self.args.double_quote = None,
+            // DuckDB is not connected to, so these only apply to Postgres
+            "postgres_url" | "client_min_messages" | "schema" | "max_connections" | "ssl.mode" | "ssl.root_cert" => {
+                if let Database::Postgres(pool) = &mut self.database {
+                    let options = database::connect_options(&self.config, &self.config.postgres_url)?;
+                    *pool = database::pool_options(&self.config).connect_lazy_with(options);
+                    if key == "postgres_url" {
+                        self.tables.clear();
+                    }
+                }
+            },
+            // Tried at once on a new connection, so that a setting the server refuses is never kept
+            key if key.starts_with(config::SESSION_SETTINGS_PREFIX) => {
+                if let Database::Postgres(pool) = &mut self.database {
+                    let options = database::connect_options(&self.config, &self.config.postgres_url)?;
+                    *pool = database::pool_options(&self.config).connect_lazy_with(options.clone());
+                    if let Err(error) = pool.acquire().await {
+                        self.config = previous;
+                        *pool = database::pool_options(&self.config).connect_lazy_with(options);
+                        return Err(Error::from_database(error, Error::Usage));
+                    }
+                }
+            },
+            _ => ()
+        }
+        self.io.write_message(&format!(
+            "Set {} for this session. To keep it, run: data-sifter config set {} <value>", key, key
+        )).await.categorize(Error::OutputIo)
+    }
+
+    /// Shows an error without ending the session. If the query named a table or column which
+    /// does not exist, the loaded tables are listed. A stopped replay or closed output does end
+    /// the session
+    async fn show_error(&mut self, error: Error) -> Result<()> {
+        if session::replay_stopped(&error) {
+            return Err(error.recategorize(Error::Usage));
+        }
+        // Nothing more can be shown once the output is closed
+        if error.closed_output() {
+            return Err(error);
+        }
+        let prefix = style::paint(self.io.color, Style::Error, "Error:");
+        let message = error.render();
+        self.io.write_always(&format!("{} {}", prefix, message)).await.categorize(Error::OutputIo)?;
+        if matches!(error, Error::QuerySyntax(_)) && message.contains("does not exist") {
+            if self.tables.is_empty() {
+                self.io.write_always("No tables are loaded. Enter 'load' to load a dataset").await
+                    .categorize(Error::OutputIo)?;
+            } else {
+                self.io.write_always("The loaded tables are:").await.categorize(Error::OutputIo)?;
+                self.list_tables().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Acquires a connection to run a query on. While all of the pool's are in use, such as by
+    /// an import which has yet to give them back, it is waited for, saying so every so often,
+    /// for as long as the connection_wait_secs config allows. What is said is written by
+    /// reporting_waits, which the future is awaited with
+    fn query_connection(&self) -> impl Future<Output = Result<PoolConnection<Postgres>>> + Send + 'static {
+        let pool = self.pool().cloned();
+        let pool_size = self.config.max_connections.unwrap_or(database::MAX_CONNECTIONS);
+        let max_wait = self.config.connection_wait_secs.unwrap_or(database::CONNECTION_WAIT_SECS);
+        let waits = self.connection_waits.0.clone();
+        async move {
+            let report = |waited: Duration| {
+                // The channel is unbounded, and the App holds its receiver
+                let _ = waits.try_send(format!(
+                    "Waiting for a database connection, since all {} are in use… {}s", pool_size, waited.as_secs()
+                ));
+            };
+            database::acquire_waiting(&pool?, pool_size, Duration::from_secs(max_wait), report).await
+                .map_err(|error| match error {
+                    sqlx::Error::PoolTimedOut => Error::Connect(eyre!(
+                        "Gave up waiting for a database connection after {}s, since all {} were in use. Raise the \
+                        max_connections config, or connection_wait_secs to wait longer",
+                        max_wait, pool_size
+                    )),
+                    error => Error::from_database(error, Error::Connect)
+                })
+        }
+    }
+
+    /// Awaits the future, meanwhile writing what query_connection says while it waits for a
+    /// connection
+    async fn reporting_waits<T>(&mut self, awaited: impl Future<Output = Result<T>>) -> Result<T> {
+        let waits = self.connection_waits.1.clone();
+        let mut awaited = Box::pin(awaited);
+        loop {
+            match future::select(awaited, Box::pin(waits.recv())).await {
+                future::Either::Left((result, _)) => return result,
+                future::Either::Right((report, unfinished)) => {
+                    awaited = unfinished;
+                    if let Ok(report) = report {
+                        self.io.write_styled(Style::Highlight, &report).await.categorize(Error::OutputIo)?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Waits for the dataset to be imported, then for a connection to run the query on
+    async fn ready(&mut self, query: BoxFuture<'static, Result<Query>>) -> Result<Query> {
+        self.finish_import().await?;
+        self.reporting_waits(query).await
+    }
+
+    /// Asks for a query and what to do with its results, then does it
+    async fn run_query(&mut self, decimal_places: &DecimalPlaces) -> Result<()> {
+        let (table, source) = self.current_table();
+        let others = self.tables.keys()
+            .filter(|other| **other != table)
+            .map(|other| format!("\"{}\"", other))
+            .join(", ");
+        let location = if others.is_empty() {
+            format!("Your data is in the \"{}\" table", table)
+        } else {
+            format!("Your data is in the \"{}\" table, and also {}", table, others)
+        };
+        // Only the start of a file is checked before its import goes on in the background
+        let importing = self.pending_import.as_ref().and_then(|pending| pending.source.as_ref());
+        if let (Some(source), false) = (importing, self.args.non_interactive()) {
+            let checked = match source {
+                CsvInput::File(_) => "Its header and first row are valid. ",
+                CsvInput::FixedWidth { .. } => "Its first line is valid. ",
+                CsvInput::ZipEntry { .. } => "Its header and first row are valid. ",
+                CsvInput::Parquet { .. } => "Its first row is valid. ",
+                CsvInput::Url { .. } | CsvInput::Remote(_) => ""
+            };
+            let message = format!(
+                "{} is imported as you write the query. {}The query runs once the import is done, and not if it fails",
+                source, checked
+            );
+            self.io.write_message(&message).await.categorize(Error::OutputIo)?;
+        }
+        let question = format!(
+            "Enter SQL query, 'pivot' to build a pivot table, 'top <column> [N]' for the most common values, \
+            'hist <column> [buckets]' for a chart of a numeric column, 'bucket <column> <granularity>' for \
+            counts over time, or 'cardinality <column...>' for counts of distinct values. {}", location
+        );
+        let mut query = self.answer(self.args.query.clone(), "--query", &question).await?;
+        // There is no line editor, so a query typed with a tab at its end lists what its last word
+        // may be finished as, and is then entered again
+        let replaying = matches!(self.io.script, Some(Script::Replay(_)));
+        while query.ends_with('\t') && !self.args.non_interactive() && (replaying || std::io::stdin().is_terminal()) {
+            let line = query.trim_end_matches('\t');
+            let (start, candidates) = self.completer().await?.complete(line, line.len());
+            let message = match candidates.is_empty() {
+                true => format!("Nothing completes '{}'", &line[start..]),
+                false => format!("'{}' may be finished as: {}", &line[start..], candidates.join(", "))
+            };
+            self.io.write_always(&message).await.categorize(Error::OutputIo)?;
+            query = self.answer(None, "--query", &question).await?;
+        }
+        self.report.start_query(query.clone());
+        let embedded = self.embedded();
+        if embedded.is_some() {
+            self.check_embedded_query(&query)?;
+        }
+        if let Some(arguments) = query.trim().strip_prefix("hist ") {
+            let histogram = Histogram::parse(table.clone(), arguments).map_err(Error::Usage)?;
+            return self.show_histogram(histogram).await;
+        }
+        let generator = if query.trim() == "pivot" {
+            Some(QueryGenerator::Pivot(self.choose_pivot(&table).await?))
+        } else if let Some(arguments) = query.trim().strip_prefix("top ") {
+            let mut top = TopValues::parse(table.clone(), arguments).map_err(Error::Usage)?;
+            top.cumulative = self.args.top_cumulative;
+            top.group_blanks = self.args.top_group_blanks;
+            Some(QueryGenerator::Top(top))
+        } else if let Some(arguments) = query.trim().strip_prefix("bucket ") {
+            let timezone = self.args.timezone.unwrap_or(self.config.timezone);
+            let buckets = TimeBuckets::parse(table.clone(), arguments, timezone).map_err(Error::Usage)?;
+            Some(QueryGenerator::Bucket(buckets))
+        } else if let Some(arguments) = query.trim().strip_prefix("cardinality ") {
+            let cardinality = Cardinality::parse(table.clone(), arguments).map_err(Error::Usage)?;
+            Some(QueryGenerator::Cardinality(cardinality))
+        } else {
+            None
+        };
+        // Statements before the last are run first, and only the last one's results are output
+        let mut before = match generator {
+            None => statements::split(&query).into_iter().map(String::from).collect(),
+            Some(_) => Vec::new()
+        };
+        let query = match before.len() {
+            0 | 1 => {
+                before.clear();
+                query
+            },
+            _ => before.pop().expect("There are several statements")
+        };
+        let count = before.len() + 1;
+        if !self.confirm_statements(&before).await? {
+            return self.io.write_message("Left the data as it is, running none of the statements").await
+                .categorize(Error::OutputIo);
+        }
+        if let Some(embedded) = embedded {
+            return self.run_embedded_query(&embedded, before, query, &table, source, decimal_places).await;
+        }
+        let values = self.choose_parameter_values(&query).await?;
+        let query = {
+            let connection = self.query_connection();
+            async move {
+                Ok::<_, Error>(Query {
+                    query,
+                    connection: connection.await?,
+                    statement: (count > 1).then_some((count, count)),
+                    parameters: Vec::new(),
+                    described: None
+                })
+            }
+        };
+        let mut query: BoxFuture<'static, Result<Query>> = match generator {
+            None if !before.is_empty() || !values.is_empty() => {
+                // The statements may use the data, and the parameters' types are inferred from
+                // it, so it must be ready first
+                let mut query = self.ready(Box::pin(query)).await?;
+                for (index, statement) in before.iter().enumerate() {
+                    let rows = query.run_before(statement).await
+                        .map_err(|error| in_query(Error::from_query(error), statement, Some((index + 1, count))))?;
+                    self.io.write_message(&format!(
+                        "Ran statement {} of {} ({} rows affected)", index + 1, count, rows
+                    )).await.categorize(Error::OutputIo)?;
+                }
+                if !values.is_empty() {
+                    let types = query.parameter_types().await
+                        .map_err(|error| in_query(Error::from_query(error), &query.query, query.statement))?;
+                    query.parameters = values.iter().enumerate()
+                        .map(|(index, value)| {
+                            Parameter::parse(value, types.get(index).map(String::as_str))
+                                .map_err(|error| Error::Usage(eyre!("Invalid value for ${}: {}", index + 1, error)))
+                        })
+                        .collect::<Result<_>>()?;
+                }
+                Box::pin(future::ready(Ok(query)))
+            },
+            None => Box::pin(query),
+            Some(generator) => {
+                // Queries are generated from the data, so it must be ready first
+                let mut query = self.ready(Box::pin(query)).await?;
+                query.query = match generator {
+                    QueryGenerator::Pivot(mut pivot) => {
+                        let values = pivot.distinct_values(&mut query.connection).await?;
+                        pivot.to_sql(&values)
+                    },
+                    QueryGenerator::Top(top) => top.to_sql(&mut query.connection).await?,
+                    QueryGenerator::Bucket(buckets) => buckets.to_sql(&mut query.connection).await?,
+                    QueryGenerator::Cardinality(mut cardinality) => {
+                        cardinality.analyze(&mut query.connection).await?;
+                        cardinality.exact = self.args.exact_cardinality || self.offer_exact_count(&cardinality).await?;
+                        cardinality.to_sql()
+                    }
+                };
+                self.io.write_message(&format!("Generated query:\n{}", query.query)).await
+                    .categorize(Error::OutputIo)?;
+                self.reported_query().query = query.query.clone();
+                Box::pin(future::ready(Ok(query)))
+            }
+        };
+
+        let destination = self.choose_destination(self.given_destination()).await?;
+        let mut options = self.export_options(decimal_places);
+        // Saved tables keep every column, so that nothing in them is lost
+        if self.args.choose_columns && !matches!(destination, Destination::Table(_) | Destination::Remote { .. }) {
+            let mut ready = self.ready(query).await?;
+            options.column_order = self.choose_columns(&mut ready).await?;
+            query = Box::pin(future::ready(Ok(ready)));
+        }
+        let format = match destination {
+            Destination::File(format) => format,
+            Destination::Table(target_table) => {
+                let query = self.ready(query).await?;
+                return self.materialize(query, &target_table).await;
+            },
+            Destination::Remote { url, table: target_table } => {
+                let query = self.ready(query).await?;
+                return self.export_remote(query, url, &target_table).await;
+            },
+            destination @ (Destination::Show | Destination::Expanded) => {
+                self.show_options(&mut options, destination);
+                let mut query = self.ready(query).await?;
+                if !self.check_query(&mut query, &options.column_order).await? {
+                    return Ok(());
+                }
+                let limited = self.io.data_is_terminal && !self.args.non_interactive()
+                    && database::is_unlimited_select(&query.query) && self.choose_to_limit().await?;
+                if limited {
+                    query.query = database::limited(&query.query, SHOWN_ROWS);
+                    self.reported_query().query = query.query.clone();
+                }
+                // Progress would be drawn amid the results if both went to the same terminal
+                let show_progress = self.io.shows_progress() && !self.io.data_is_terminal;
+                let progress = Arc::new(ExportProgress::default());
+                let written = write_results(
+                    &mut query, OutputFormat::Csv, &options, &mut self.io.data, progress.clone(), show_progress
+                ).await;
+                let (summary, progress) = match written {
+                    Err(error) if may_run_again(&query, &error, &progress) => {
+                        query = self.reconnect(query).await?;
+                        let progress = Arc::new(ExportProgress::default());
+                        let summary = write_results(
+                            &mut query, OutputFormat::Csv, &options, &mut self.io.data, progress.clone(), show_progress
+                        ).await.map_err(lost_again)?;
+                        (summary, progress)
+                    },
+                    written => (written?, progress)
+                };
+                self.report_shown(&summary, limited, &progress).await?;
+                return self.offer_explain(&mut query, progress.elapsed()).await;
+            }
+        };
+        let output_file = self.choose_output_file(format, source.as_ref(), &table).await?;
+        self.write_file(query, format, &options, output_file, source, &table).await
+    }
+
+    /// Refuses with the duckdb backend what only Postgres can do with a query, before anything
+    /// is asked. Queries are only generated from Postgres's statistics, and neither take
+    /// parameters nor have their columns chosen
+    fn check_embedded_query(&self, query: &str) -> Result<()> {
+        let command = query.split_whitespace().next().unwrap_or_default();
+        if matches!(command, "pivot" | "top" | "hist" | "bucket" | "cardinality") {
+            return Err(Error::Usage(eyre!("'{}' needs the postgres backend, rather than duckdb", command)));
+        }
+        if self.args.choose_columns {
+            return Err(Error::Usage(eyre!("--choose-columns needs the postgres backend, rather than duckdb")));
+        }
+        if statements::parameter_count(query) > 0 || !self.args.param.is_empty() {
+            return Err(Error::Usage(eyre!("Parameters such as $1 need the postgres backend, rather than duckdb")));
+        }
+        Ok(())
+    }
+
+    /// Runs the statements before the query and then the query in DuckDB, once run_query has
+    /// confirmed them, writing the results as run_query does for Postgres
+    async fn run_embedded_query(&mut self, embedded: &Embedded, before: Vec<String>, query: String, table: &str,
+                                source: Option<CsvInput>, decimal_places: &DecimalPlaces) -> Result<()> {
+        let count = before.len() + 1;
+        let statement = (count > 1).then_some((count, count));
+        self.finish_import().await?;
+        for (index, statement) in before.iter().enumerate() {
+            let rows = embedded.execute(statement).await
+                .map_err(|error| in_query(error, statement, Some((index + 1, count))))?;
+            self.io.write_message(&format!(
+                "Ran statement {} of {} ({} rows affected)", index + 1, count, rows
+            )).await.categorize(Error::OutputIo)?;
+        }
+
+        let destination = self.choose_destination(self.given_destination()).await?;
+        let mut options = self.export_options(decimal_places);
+        let format = match destination {
+            Destination::File(format) => format,
+            Destination::Table(target_table) => {
+                return self.materialize_embedded(embedded, &query, statement, &target_table).await;
+            },
+            Destination::Remote { .. } => {
+                return Err(Error::Usage(eyre!(
+                    "Results are only copied to another database from Postgres, so remote needs the postgres backend"
+                )));
+            },
+            destination @ (Destination::Show | Destination::Expanded) => {
+                self.show_options(&mut options, destination);
+                let mut query = query;
+                let limited = self.io.data_is_terminal && !self.args.non_interactive()
+                    && database::is_unlimited_select(&query) && self.choose_to_limit().await?;
+                if limited {
+                    query = database::limited(&query, SHOWN_ROWS);
+                    self.reported_query().query = query.clone();
+                }
+                let progress = Arc::new(ExportProgress::default());
+                let summary = embedded.output_query_results(
+                    &query, OutputFormat::Csv, &options, &mut self.io.data, progress.clone()
+                ).await.map_err(|error| in_query(error, &query, statement))?;
+                return self.report_shown(&summary, limited, &progress).await;
+            }
+        };
+        let output_file = self.choose_output_file(format, source.as_ref(), table).await?;
+        self.write_embedded_file(embedded, query, format, &options, output_file, source, table).await
+    }
+
+    /// The destination given as arguments, if there is one
+    fn given_destination(&self) -> Option<String> {
+        match (&self.args.materialize, &self.args.remote, &self.args.format) {
+            (Some(table), _, _) => Some(format!("table {}", table)),
+            (None, Some(table), _) => Some(match &self.args.remote_url {
+                Some(url) => format!("remote {} {}", url, table),
+                None => format!("remote {}", table)
+            }),
+            // When piped to another program, showing the results is the obvious choice
+            (None, None, None) if self.args.non_interactive() && !self.io.data_is_terminal => {
+                Some(String::from("show"))
+            },
+            (None, None, format) => format.clone()
+        }
+    }
+
+    /// Shown results are for reading, rather than for other programs
+    fn show_options(&self, options: &mut ExportOptions, destination: Destination) {
+        options.format.thousands_separators = self.args.thousands_separators;
+        options.format.display_locale = self.config.display_locale;
+        options.flush_rows = self.io.data_is_terminal;
+        options.redacted = self.redacted.clone();
+        if destination == Destination::Expanded || self.expanded {
+            options.readable = Some(ReadableLayout::Expanded(terminal_width()));
+        } else if self.io.data_is_terminal {
+            options.readable = Some(ReadableLayout::Aligned(SHOWN_COLUMN_WIDTH));
+        }
+    }
+
+    /// Says how many rows were shown, and whether more could have been
+    async fn report_shown(&mut self, summary: &OutputSummary, limited: bool, progress: &ExportProgress) -> Result<()> {
+        self.reported_query().finish("show", summary.rows);
+
+        self.write_warnings(summary).await?;
+        if summary.rows == 0 {
+            self.io.write_styled(Style::Highlight, "No results").await.categorize(Error::OutputIo)?;
+        } else if limited && summary.rows == SHOWN_ROWS {
+            self.io.write_message(&format!(
+                "Showed the first {} rows ({}). Add a LIMIT of your own, or answer 'all', to show more",
+                summary.rows, progress.totals()
+            )).await.categorize(Error::OutputIo)?;
+        } else {
+            self.io.write_message(&format!("Showed {} rows ({})", summary.rows, progress.totals())).await
+                .categorize(Error::OutputIo)?;
+        }
+        if summary.truncated > 0 {
+            self.io.write_message(&format!(
+                "Cut short {} values to fit their columns. Write the results to a file to see them in full",
+                summary.truncated
+            )).await.categorize(Error::OutputIo)?;
+        }
+        Ok(())
+    }
+
+    /// Runs each query of the batch spec once the import is done, writing its results to a file
+    /// of its own. Unless the spec says to stop at the first failure, a failed query is reported
+    /// and the rest still run, but the run fails in the end
+    async fn run_batch(&mut self, batch: BatchSpec, decimal_places: &DecimalPlaces) -> Result<()> {
+        self.finish_import().await?;
+        let (table, source) = self.current_table();
+        let output_dir = self.output_dir().unwrap_or_else(|| PathBuf::from("."));
+        fs::create_dir_all(&output_dir).await
+            .wrap_err_with(|| format!("Unable to create output directory {}", output_dir.display()))
+            .categorize(Error::OutputIo)?;
+        let options = self.export_options(decimal_places);
+        let count = batch.queries.len();
+        let mut failed = Vec::new();
+        let mut first_failure = None;
+        for batch_query in &batch.queries {
+            self.report.start_query(batch_query.query.clone());
+            self.reported_query().name = Some(batch_query.name.clone());
+            let result = self.run_batch_query(batch_query, &options, &output_dir, source.clone(), &table).await
+                .context_with(|| format!("Query {} failed", batch_query.name));
+            let error = match result {
+                Ok(()) => continue,
+                Err(error) => error
+            };
+            self.reported_query().error = Some(ErrorReport::of(&error));
+            if batch.stop_on_error {
+                return Err(error);
+            }
+            let prefix = style::paint(self.io.color, Style::Error, "Error:");
+            self.io.write_always(&format!("{} {}", prefix, error.render())).await.categorize(Error::OutputIo)?;
+            failed.push(batch_query.name.as_str());
+            first_failure.get_or_insert(error);
+        }
+        match first_failure {
+            None => Ok(()),
+            Some(error) => Err(error.wrap_err(format!(
+                "{} of the {} queries in the batch failed: {}", failed.len(), count, failed.join(", ")
+            )))
+        }
+    }
+
+    async fn run_batch_query(&mut self, batch_query: &BatchQuery, options: &ExportOptions, output_dir: &Path,
+                             source: Option<CsvInput>, table: &str) -> Result<()> {
+        let format = batch_query.format().categorize(Error::Usage)?;
+        let output_file = batch_query.output_file(output_dir).categorize(Error::Usage)?;
+        if let Some(embedded) = self.embedded() {
+            return self.write_embedded_file(&embedded, batch_query.query.clone(), format, options, output_file,
+                                            source, table).await;
+        }
+        let query = Query {
+            query: batch_query.query.clone(),
+            connection: self.reporting_waits(self.query_connection()).await?,
+            statement: None,
+            parameters: Vec::new(),
+            described: None
+        };
+        self.write_file(Box::pin(future::ready(Ok(query))), format, options, output_file, source, table).await
+    }
+
+    /// How results are written, as the arguments and the config say
+    fn export_options(&self, decimal_places: &DecimalPlaces) -> ExportOptions {
+        ExportOptions {
+            format: FormatOptions {
+                array_format: self.args.array_format,
+                bytea_format: self.config.bytea_format,
+                timezone: self.args.timezone.unwrap_or(self.config.timezone),
+                timestamp_format: self.args.timestamp_format.clone().or_else(|| self.config.timestamp_format.clone()),
+                decimal_places: decimal_places.clone(),
+                thousands_separators: false,
+                display_locale: None
+            },
+            expand_json: self.args.expand_json.clone(),
+            expand_json_scan: self.args.expand_json_scan,
+            header_case: self.args.header_case,
+            column_order: match &self.args.columns {
+                Some(columns) => ColumnOrder::Listed(
+                    columns.split(',').map(|column| column.trim().to_owned()).collect()
+                ),
+                None if self.args.sort_columns => ColumnOrder::Alphabetical,
+                None => ColumnOrder::AsIs
+            },
+            header_if_empty: !self.args.no_empty_file,
+            readable: None,
+            redacted: Vec::new(),
+            strict_column_names: self.args.strict_column_names,
+            flush_rows: false,
+            sort_rows: None
+        }
+    }
+
+    /// Writes the results of the query to the output file, along with its manifest if one is
+    /// asked for, then says where they went
+    async fn write_file(&mut self, query: BoxFuture<'static, Result<Query>>, format: OutputFormat,
+                        options: &ExportOptions, output_file: PathBuf, source: Option<CsvInput>,
+                        table: &str) -> Result<()> {
+        let manifest_file = self.check_output_file(&output_file, source.as_ref()).await?;
+        // The results are written to a temporary file, which is removed if writing them fails
+        let (staged, file) = StagedFile::create(&output_file).await.categorize(Error::OutputIo)?;
+        // The manifest's hash is computed as the output is written
+        let mut file_writer = HashingWriter::new(file);
+        let mut query = self.ready(query).await?;
+        if !self.check_query(&mut query, &options.column_order).await? {
+            return Ok(());
+        }
+        let options = &ExportOptions { sort_rows: self.order_rows(&mut query).await?, ..options.clone() };
+        let directory = match output_file.parent() {
+            Some(directory) if !directory.as_os_str().is_empty() => directory.to_path_buf(),
+            _ => PathBuf::from(".")
+        };
+        let watch = self.check_space(&mut query, directory).await?;
+        let progress = Arc::new(ExportProgress::default());
+        let written = self.write_watching_space(
+            &mut query, format, options, &mut file_writer, watch.clone(), progress.clone()
+        ).await;
+        let written = match written {
+            Err(error) if may_run_again(&query, &error, &progress) => {
+                query = self.reconnect(query).await?;
+                let progress = Arc::new(ExportProgress::default());
+                self.write_watching_space(&mut query, format, options, &mut file_writer, watch, progress.clone())
+                    .await.map_err(lost_again)?
+                    .map(|summary| (summary, progress))
+            },
+            written => written?.map(|summary| (summary, progress))
+        };
+        let (summary, progress) = match written {
+            Some(written) => written,
+            None => return Ok(())
+        };
+        let elapsed = progress.elapsed();
+        let written = WrittenFile {
+            staged, file_writer, output_file, manifest_file, format, summary, progress, query: query.query.clone()
+        };
+        let output_file = match self.finish_file(written, source, table).await? {
+            Some(output_file) => output_file,
+            None => return Ok(())
+        };
+        self.offer_explain(&mut query, elapsed).await?;
+        self.offer_open(&output_file).await
+    }
+
+    /// Writes the results of a query in DuckDB to the output file, as write_file does for
+    /// Postgres. There is no estimate of their size, so the space free is not checked
+    #[allow(clippy::too_many_arguments)]
+    async fn write_embedded_file(&mut self, embedded: &Embedded, query: String, format: OutputFormat,
+                                 options: &ExportOptions, output_file: PathBuf, source: Option<CsvInput>,
+                                 table: &str) -> Result<()> {
+        let manifest_file = self.check_output_file(&output_file, source.as_ref()).await?;
+        let (staged, file) = StagedFile::create(&output_file).await.categorize(Error::OutputIo)?;
+        let mut file_writer = HashingWriter::new(file);
+        self.finish_import().await?;
+        let progress = Arc::new(ExportProgress::default());
+        let shown = progress.clone();
+        let written = embedded.output_query_results(&query, format, options, &mut file_writer, progress.clone());
+        let summary = match self.io.shows_progress() {
+            true => {
+                let summary = written.or(progress::show(move || shown.report())).await;
+                progress::clear().await?;
+                summary
+            },
+            false => written.await
+        };
+        let summary = summary.map_err(|error| in_query(error, &query, None))?;
+        let written = WrittenFile { staged, file_writer, output_file, manifest_file, format, summary, progress, query };
+        match self.finish_file(written, source, table).await? {
+            Some(output_file) => self.offer_open(&output_file).await,
+            None => Ok(())
+        }
+    }
+
+    /// Refuses to write over a dataset or an existing file. Returns where the manifest goes, if
+    /// one is written
+    async fn check_output_file(&self, output_file: &Path, source: Option<&CsvInput>) -> Result<Option<PathBuf>> {
+        let manifest_file = if self.args.manifest || self.config.manifest {
+            Some(Manifest::path_for(output_file))
+        } else {
+            None
+        };
+        // An existing output file is refused below anyway, but not with advice to delete it
+        if let Ok(existing) = output_file.canonicalize().await {
+            let datasets = source.into_iter().chain(self.tables.values().filter_map(|loaded| loaded.source.as_ref()));
+            for dataset in datasets {
+                if dataset.path() == Some(&existing) {
+                    return Err(Error::OutputIo(eyre!(
+                        "Output file {} is the CSV dataset {}, so it cannot be written", output_file.display(), dataset
+                    )));
+                }
+            }
+        }
+        for file in std::iter::once(output_file).chain(manifest_file.as_deref()) {
+            if file.exists().await {
+                return Err(Error::OutputIo(eyre!("Delete existing file {} first", file.display())));
+            }
+        }
+        Ok(manifest_file)
+    }
+
+    /// Keeps the file the results were written to, along with its manifest, and says where
+    /// they went. Returns the file, unless nothing was written
+    async fn finish_file(&mut self, written: WrittenFile, source: Option<CsvInput>,
+                         table: &str) -> Result<Option<PathBuf>> {
+        let WrittenFile { staged, file_writer, output_file, manifest_file, format, summary, progress, query } = written;
+        let (file, sha256) = file_writer.into_parts();
+        self.reported_query().finish(&format.label().to_lowercase(), summary.rows);
+        self.write_warnings(&summary).await?;
+        // Nothing was written if there were no rows, and the header was left out too
+        if summary.rows == 0 && summary.columns.is_empty() {
+            drop(staged);
+            self.io.write_styled(Style::Highlight, "No results").await.categorize(Error::OutputIo)?;
+            let not_written = match &manifest_file {
+                Some(manifest_file) => format!(
+                    "Neither {} nor its manifest {} was written", output_file.display(), manifest_file.display()
+                ),
+                None => format!("{} was not written", output_file.display())
+            };
+            self.io.write_message(&not_written).await.categorize(Error::OutputIo)?;
+            return Ok(None);
+        }
+        staged.persist(file).await.categorize(Error::OutputIo)?;
+        let output_file = output_file.canonicalize().await.categorize(Error::OutputIo)?;
+        self.outputs.push(output_file.clone());
+        self.reported_query().output_files.push(output_file.display().to_string());
+        let manifest_note = match manifest_file {
+            Some(manifest_file) => {
+                let manifest = Manifest {
+                    file: output_file.display().to_string(),
+                    sha256,
+                    rows: summary.rows,
+                    columns: summary.columns.clone(),
+                    query,
+                    source: source.map(|source| source.to_string()),
+                    source_profile: self.profiles.get(table).map(|profile| profile.columns.clone()),
+                    created: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+                };
+                let (staged, mut file) = StagedFile::create(&manifest_file).await.categorize(Error::OutputIo)?;
+                file.write_all(manifest.to_json().as_bytes()).await.categorize(Error::OutputIo)?;
+                staged.persist(file).await.categorize(Error::OutputIo)?;
+                let manifest_file = manifest_file.display().to_string();
+                self.reported_query().output_files.push(manifest_file.clone());
+                format!(", with manifest {}", style::paint(self.io.color, Style::Highlight, &manifest_file))
+            },
+            None => String::new()
+        };
+        let shown_file = String::from_utf8_lossy(output_file.as_os_str().as_bytes());
+        let shown_file = style::paint(self.io.color, Style::Highlight, &shown_file);
+        self.io.write_message(&format!(
+            "Wrote {} rows of {} to {}{} ({})",
+            summary.rows, format.label(), shown_file, manifest_note, progress.totals()
+        )).await
+            .categorize(Error::OutputIo)?;
+        if summary.rows == 0 {
+            self.io.write_styled(Style::Highlight, "No results").await.categorize(Error::OutputIo)?;
+        }
+        Ok(Some(output_file))
+    }
+
+    /// Warns if the space the planner expects the results to take is more than is free in the
+    /// directory they are written to. The planner's estimate can be far off, so the results are
+    /// written anyway. Returns how to check the space as they are written, if it can be told
+    async fn check_space(&mut self, query: &mut Query, directory: PathBuf) -> Result<Option<SpaceWatch>> {
+        // Only a warning depends on the estimate, so a query which cannot be explained is written anyway
+        let (estimated_rows, row_width) = match query.estimate_rows().await {
+            Ok(Some(estimate)) => estimate,
+            Ok(None) | Err(_) => return Ok(None)
+        };
+        let free_bytes = match diskspace::available_space(&directory).await {
+            Some(free_bytes) => free_bytes,
+            None => return Ok(None)
+        };
+        let projection = Projection::before_writing(estimated_rows, row_width, free_bytes);
+        if projection.is_short() {
+            self.io.write_styled(Style::Error, &format!(
+                "Warning: The results are {} in {}, so they may not fit. The space is checked again as they \
+                are written", projection, directory.display()
+            )).await.categorize(Error::OutputIo)?;
+        }
+        Ok(Some(SpaceWatch { directory, estimated_rows }))
+    }
+
+    /// Writes the results as write_results does, while checking every so often that the space
+    /// free is enough for the rest of them, as projected from those written so far. A full disk
+    /// would cut the file short, so if it is not, the user is asked whether to go on, and
+    /// writing waits for the answer. Returns None if the user stops it
+    async fn write_watching_space<W>(&mut self, query: &mut Query, format: OutputFormat, options: &ExportOptions,
+                                     output: W, mut watch: Option<SpaceWatch>, progress: Arc<ExportProgress>)
+                                     -> Result<Option<OutputSummary>>
+        where W: io::Write + Unpin {
+
+        enum Event {
+            Written(Result<OutputSummary>),
+            Short(Projection)
+        }
+        let show_progress = self.io.shows_progress();
+        let mut written = Box::pin(query.output_query_results(format, options, output, progress.clone()));
+        let summary = loop {
+            let shown = progress.clone();
+            let event = async { Event::Written(written.as_mut().await) }
+                .or(async {
+                    match &watch {
+                        Some(watch) => Event::Short(watch.shortfall(&progress).await),
+                        None => future::pending().await
+                    }
+                })
+                .or(async move {
+                    match show_progress {
+                        true => Event::Written(progress::show(move || shown.report()).await),
+                        false => future::pending().await
+                    }
+                })
+                .await;
+            if show_progress {
+                progress::clear().await?;
+            }
+            match event {
+                Event::Written(summary) => break summary,
+                Event::Short(projection) => {
+                    let directory = watch.take().map(|watch| watch.directory).unwrap_or_default();
+                    if !self.confirm_space(&projection, &directory).await? {
+                        return Ok(None);
+                    }
+                }
+            }
+        };
+        drop(written);
+        let summary = summary.map_err(|error| in_query(error, &query.query, query.statement))?;
+        Ok(Some(summary))
+    }
+
+    /// Replaces the connection of a query which was lost, so that it can run again
+    async fn reconnect(&mut self, query: Query) -> Result<Query> {
+        self.io.write_styled(
+            Style::Error, "Warning: The connection to the database was lost, so the query runs again on a new one"
+        ).await.categorize(Error::OutputIo)?;
+        let Query { query, connection, statement, parameters, described } = query;
+        // Given back to the pool, the lost connection would only be tried and closed later
+        drop(connection.detach());
+        let connection = self.reporting_waits(self.query_connection()).await?;
+        Ok(Query { query, connection, statement, parameters, described })
+    }
+
+    /// Asks whether to go on writing results which, projected from those written so far, take
+    /// more space than is free. In non-interactive mode, they are stopped
+    async fn confirm_space(&mut self, projection: &Projection, directory: &Path) -> Result<bool> {
+        if self.args.non_interactive() {
+            return Err(Error::OutputIo(eyre!(
+                "Stopped writing the results to {}, which has too little space for them: {}. Free some up, \
+                or write them elsewhere with --output-dir", directory.display(), projection
+            )));
+        }
+        let answer = self.io.prompt(&format!(
+            "The results are {} in {} — continue? (y/n)", projection, directory.display()
+        )).await.categorize(Error::OutputIo)?;
+        if answer.trim().eq_ignore_ascii_case("y") {
+            return Ok(true);
+        }
+        self.io.write_message("Stopped writing the results, and left no file behind").await
+            .categorize(Error::OutputIo)?;
+        Ok(false)
+    }
+
+    /// Offers to open the output file, or the directory holding it, in the desktop's application
+    /// for it. Only a person at a terminal is asked, and failing to open it is not an error
+    async fn offer_open(&mut self, output_file: &Path) -> Result<()> {
+        let attended = !self.args.non_interactive() && self.io.messages_are_terminal && std::io::stdin().is_terminal();
+        // Whether it is asked depends on the terminal, so a replayed session could not answer it
+        let replaying = matches!(self.io.script, Some(Script::Replay(_)));
+        if !attended || replaying || !self.config.offer_open.unwrap_or(true) {
+            return Ok(());
+        }
+        let answer = self.io.prompt_unscripted("Open it now? (y/n/folder)").await.categorize(Error::OutputIo)?;
+        let path = match answer.trim() {
+            "y" => output_file,
+            "folder" => match output_file.parent() {
+                Some(directory) => directory,
+                None => return Ok(())
+            },
+            _ => return Ok(())
+        };
+        if let Err(error) = platform::open_path(path.as_ref()) {
+            self.io.write_message(&format!("Note: {} could not be opened: {}", path.display(), error)).await
+                .categorize(Error::OutputIo)?;
+        }
+        Ok(())
+    }
+
+    /// Offers to show where the time went, once a query took longer than the slow_query_secs
+    /// config. The query is run again with EXPLAIN ANALYZE, and the plan may be saved as JSON to
+    /// share it. Whether it is asked depends on how long the query took, so it is neither asked
+    /// in non-interactive mode nor recorded, and failing to explain the query is not an error
+    async fn offer_explain(&mut self, query: &mut Query, elapsed: Duration) -> Result<()> {
+        let threshold = Duration::from_secs(self.config.slow_query_secs.unwrap_or(SLOW_QUERY_SECS));
+        let replaying = matches!(self.io.script, Some(Script::Replay(_)));
+        if elapsed < threshold || self.args.non_interactive() || replaying || !database::returns_rows(&query.query) {
+            return Ok(());
+        }
+        let answer = self.io.prompt_unscripted(&format!(
+            "The query took {}. Show where the time went? This runs it again with EXPLAIN ANALYZE (y/n)",
+            progress::format_duration(elapsed)
+        )).await.categorize(Error::OutputIo)?;
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            return Ok(());
+        }
+        let explained = query.explain_analyze().await.map_err(eyre::Report::from)
+            .and_then(|explained| AnalyzedPlan::parse(&explained).map(|plan| (explained, plan)));
+        let (explained, plan) = match explained {
+            Ok(explained) => explained,
+            Err(error) => {
+                return self.io.write_message(&format!("Note: the query could not be explained: {}", error)).await
+                    .categorize(Error::OutputIo);
+            }
+        };
+        let color = self.io.color;
+        self.io.write_message(&plan.render(|line| style::paint(color, Style::Highlight, line).into_owned())).await
+            .categorize(Error::OutputIo)?;
+        let answer = self.io.prompt_unscripted(
+            "Save the plan as JSON to share it? Enter a file name, or nothing to skip"
+        ).await.categorize(Error::OutputIo)?;
+        let plan_file = PathBuf::from(answer.trim());
+        if plan_file.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let saved = match plan_file.exists().await {
+            true => Err(eyre!("it exists already")),
+            false => {
+                let json = serde_json::to_string_pretty(&explained).expect("JSON values are always serializable");
+                fs::write(&plan_file, json + "\n").await.map_err(eyre::Report::from)
+            }
+        };
+        let message = match saved {
+            Ok(()) => format!("Saved the plan to {}", plan_file.display()),
+            Err(error) => format!("Note: the plan could not be saved to {}: {}", plan_file.display(), error)
+        };
+        self.io.write_message(&message).await.categorize(Error::OutputIo)
+    }
 
+    /// Asks before running statements which change or drop existing data, ahead of the query
+    async fn confirm_statements(&mut self, statements: &[String]) -> Result<bool> {
+        for (index, statement) in statements.iter().enumerate() {
+            if statements::is_destructive(statement) {
+                let position = format!("Statement {} of {}", index + 1, statements.len() + 1);
+                let question = format!("{} changes or drops existing data:\n{}\nRun it?", position, statement);
+                let refusal = format!(
+                    "{} changes or drops existing data, so it is only run with --replace: {}", position, statement
+                );
+                if !self.confirm_write(&question, &refusal).await? {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Draws a chart of how a column's values are distributed, which is written in place of
+    /// results, followed by the count of values which are NULL or empty
+    async fn show_histogram(&mut self, mut histogram: Histogram) -> Result<()> {
+        // The chart is of the data, so it must be ready first
+        self.finish_import().await?;
+        let mut connection = self.reporting_waits(self.query_connection()).await?;
+        let bounds = histogram.bounds(&mut connection).await?;
+        let buckets = match histogram.to_sql(&bounds) {
+            Some(sql) => {
+                self.io.write_message(&format!("Generated query:\n{}", sql)).await.categorize(Error::OutputIo)?;
+                self.reported_query().query = sql.clone();
+                histogram.count(&bounds, &sql, &mut connection).await?
+            },
+            None => Vec::new()
+        };
+        let mut lines = histogram::render(&buckets, terminal_width());
+        if buckets.is_empty() {
+            lines.push(format!("Column {} has no values to chart", histogram.column));
+        }
+        lines.push(format!("NULL or empty: {}", bounds.blanks));
+        for line in lines {
+            self.io.write_data(&line).await.categorize(Error::OutputIo)?;
+        }
+        Ok(())
+    }
+
+    /// Asks whether to count distinct values exactly, which reads the whole table. In
+    /// non-interactive mode, only --exact-cardinality does
+    async fn offer_exact_count(&mut self, cardinality: &Cardinality) -> Result<bool> {
+        if self.args.non_interactive() {
+            return Ok(false);
+        }
+        let question = format!(
+            "The distinct values are estimated from a sample. Count them exactly too? This reads every row of {} (y/n)",
+            cardinality.table
+        );
+        let confirm = self.io.prompt(&question).await.categorize(Error::OutputIo)?;
+        Ok(confirm.trim().eq_ignore_ascii_case("y"))
+    }
+
+    /// Asks before changing or dropping existing data. In non-interactive mode, --replace gives
+    /// permission in advance, and without it the refusal is an error
+    async fn confirm_write(&mut self, question: &str, refusal: &str) -> Result<bool> {
+        if self.args.replace {
+            return Ok(true);
+        }
+        if self.args.non_interactive() {
+            return Err(Error::Usage(eyre!("{}", refusal)));
+        }
+        let confirm = self.io.prompt(&format!("{} (y/n)", question)).await.categorize(Error::OutputIo)?;
+        Ok(confirm.trim().eq_ignore_ascii_case("y"))
+    }
+
+    /// The report of the query being run
+    fn reported_query(&mut self) -> &mut QueryReport {
+        self.report.current_query().expect("The query was reported when it was entered")
+    }
+
+    /// Uses the destination given, or else asks for one until a valid option is chosen
+    async fn choose_destination(&mut self, given: Option<String>) -> Result<Destination> {
+        if let Some(given) = given {
+            return Destination::parse(&given).ok_or_else(|| Error::Usage(eyre!("Invalid option: {}", given)));
+        }
+        if self.args.non_interactive() {
+            return Err(Error::Usage(eyre!("--format must be specified in non-interactive mode")));
+        }
+        let menu = Destination::menu();
+        loop {
+            let answer = self.io.prompt_until_end(&menu).await.categorize(Error::OutputIo)?
+                .ok_or_else(|| Error::Usage(eyre!("The input ended before an option was chosen")))?;
+            if let Some(destination) = Destination::parse(&answer) {
+                return Ok(destination);
+            }
+            self.io.write_styled(Style::Error, &format!(
+                "Invalid option: {}. Enter one of the options, or its number", answer.trim()
+            )).await.categorize(Error::OutputIo)?;
+        }
+    }
+
+    /// Lists the columns the query returns, and asks which of them to write, in what order
+    async fn choose_columns(&mut self, query: &mut Query) -> Result<ColumnOrder> {
+        let columns = query.column_names().await.map_err(Error::from_query)?;
+        // Statements other than queries have no columns to choose from
+        if columns.is_empty() {
+            return Ok(ColumnOrder::AsIs);
+        }
+        let question = format!(
+            "The query returns these columns:\n{}\nEnter 'keep 1-5' or 'drop 3,7' to write only some of them, \
+            in the order given, or nothing to write them all.",
+            columns.iter().enumerate().map(|(index, column)| format!("  {}. {}", index + 1, column)).join("\n")
+        );
+        loop {
+            let answer = self.io.prompt(&question).await.categorize(Error::OutputIo)?;
+            if answer.trim().is_empty() {
+                return Ok(ColumnOrder::AsIs);
+            }
+            match output::choose_columns(&answer, columns.len()) {
+                Ok(picked) => return Ok(ColumnOrder::Picked(picked)),
+                Err(error) => self.io.write_styled(Style::Error, &error.to_string()).await.categorize(Error::OutputIo)?
+            }
+        }
+    }
+
+    /// Uses the output file given, or else a file in the output directory named after the
+    /// CSV file or the table. The output directory is created if needed, before the query runs
+    async fn choose_output_file(&mut self, format: OutputFormat, source: Option<&CsvInput>,
+                                table: &str) -> Result<PathBuf> {
+        let output_dir = match (&self.args.output, self.output_dir()) {
+            (None, Some(output_dir)) => output_dir,
+            (output, None) => {
+                let output_file = self.answer(
+                    output.clone(), "--output", &format!("Enter output {} file", format.label())
+                ).await?;
+                return Ok(PathBuf::from(output_file));
+            },
+            (Some(output), Some(_)) => return Ok(PathBuf::from(output))
+        };
+        // Named after the table without its schema
+        let stem = source.and_then(CsvInput::stem)
+            .unwrap_or_else(|| table.rsplit('.').next().unwrap_or(table).to_owned());
+        fs::create_dir_all(&output_dir).await
+            .wrap_err_with(|| format!("Unable to create output directory {}", output_dir.display()))
+            .categorize(Error::OutputIo)?;
+        let derived_file = output_dir.join(format!("{}.{}", stem, format.extension()));
+        let output_file = if self.args.non_interactive() {
+            derived_file
+        } else {
+            let output_file = self.io.prompt(&format!(
+                "Enter output {} file, or nothing for {}", format.label(), derived_file.display()
+            )).await.categorize(Error::OutputIo)?;
+            if output_file.is_empty() { derived_file } else { PathBuf::from(output_file) }
+        };
+        Ok(output_file)
+    }
+
+    /// The directory for output files, if one is set
+    fn output_dir(&self) -> Option<PathBuf> {
+        self.args.output_dir.as_ref().map(PathBuf::from)
+            .or_else(|| self.config.output_dir.clone().map(PathBuf::from))
+    }
+
+    /// Finds an interrupted import of the same file into the table, and asks whether to resume it.
+    /// DuckDB imports in one transaction, so there is never one to resume
+    async fn resumable_import(&mut self, csv_input: &CsvInput, table: &str) -> Result<Option<Checkpoint>> {
+        if self.embedded().is_some() {
+            return Ok(None);
+        }
+        let csv_input = match csv_input {
+            CsvInput::File(path) => path,
+            CsvInput::Url { .. } if self.args.resume => return Err(Error::Usage(eyre!(
+                "Only imports of files can be resumed, not downloads"
+            ))),
+            CsvInput::FixedWidth { .. } if self.args.resume => return Err(Error::Usage(eyre!(
+                "Only imports of CSV files can be resumed, not fixed-width files"
+            ))),
+            CsvInput::ZipEntry { .. } if self.args.resume => return Err(Error::Usage(eyre!(
+                "Only imports of CSV files can be resumed, not datasets in ZIP archives"
+            ))),
+            CsvInput::Parquet { .. } if self.args.resume => return Err(Error::Usage(eyre!(
+                "Only imports of CSV files can be resumed, not Parquet files"
+            ))),
+            CsvInput::Remote(_) if self.args.resume => return Err(Error::Usage(eyre!(
+                "Only imports of CSV files can be resumed, not copies from other databases"
+            ))),
+            CsvInput::Url { .. } | CsvInput::FixedWidth { .. } | CsvInput::ZipEntry { .. }
+            | CsvInput::Parquet { .. } | CsvInput::Remote(_) => return Ok(None)
+        };
+        let checkpoint = Checkpoint::load(&Checkpoint::path_for(&self.data_dir, table)).await
+            .categorize(Error::ImportIo)?;
+        let checkpoint = match checkpoint {
+            Some(checkpoint) if checkpoint.is_for(csv_input) => checkpoint,
+            _ if self.args.resume => return Err(Error::Usage(eyre!(
+                "There is no interrupted import of {:?} into table {} to resume", csv_input, table
+            ))),
+            _ => return Ok(None)
+        };
+        if self.args.resume {
+            checkpoint.verify_unchanged().await?;
+            return Ok(Some(checkpoint));
+        }
+        if self.args.non_interactive() {
+            return Ok(None);
+        }
+        if let Err(error) = checkpoint.verify_unchanged().await {
+            self.io.write_styled(Style::Error, &format!("Warning: {}", error)).await.categorize(Error::OutputIo)?;
+            return Ok(None);
+        }
+        let resume = self.io.prompt(&format!(
+            "An import of this file into table {} stopped with {} rows in the table. Resume it? (y/n)",
+            table, checkpoint.table_rows
+        )).await.categorize(Error::OutputIo)?;
+        Ok(resume.trim().eq_ignore_ascii_case("y").then_some(checkpoint))
+    }
+
+    /// Asks how to pivot the table
+    async fn choose_pivot(&mut self, table: &str) -> Result<Pivot> {
+        let row_keys = self.answer(
+            self.args.pivot_rows.clone(), "--pivot-rows",
+            "Enter the columns identifying each row, separated by commas"
+        ).await?;
+        let column = self.answer(
+            self.args.pivot_column.clone(), "--pivot-column",
+            &format!("Enter the column whose values become columns. At most {} values are allowed",
+                     pivot::MAX_PIVOT_COLUMNS)
+        ).await?;
+        let value = self.answer(
+            self.args.pivot_value.clone(), "--pivot-value", "Enter the column with the values to aggregate"
+        ).await?;
+        let aggregate = self.answer(
+            self.args.pivot_aggregate.clone(), "--pivot-aggregate",
+            "Enter how values are aggregated: sum, count, avg, min or max"
+        ).await?;
+        let aggregate = aggregate.parse().map_err(Error::Usage)?;
+        Pivot::new(String::from(table), &row_keys, &column, &value, aggregate).map_err(Error::Usage)
+    }
+
+    /// Asks for a value for each of the query's $n placeholders, unless given as arguments
+    async fn choose_parameter_values(&mut self, query: &str) -> Result<Vec<String>> {
+        let count = statements::parameter_count(query);
+        let given = std::mem::take(&mut self.args.param);
+        if given.len() > count {
+            return Err(Error::Usage(eyre!(
+                "{} values were given with --param, but the query has {} parameters", given.len(), count
+            )));
+        }
+        let mut given = given.into_iter();
+        let mut values = Vec::with_capacity(count);
+        for number in 1..=count {
+            values.push(self.answer(given.next(), "--param", &format!("Value for ${}:", number)).await?);
+        }
+        Ok(values)
+    }
+
+    /// Saves the query results in a new table, asking before replacing an existing table
+    async fn materialize(&mut self, mut query: Query, table: &str) -> Result<()> {
+        if !database::is_table_name(table) {
+            return Err(Error::Usage(eyre!("Invalid table name: {}", table)));
+        }
+        let table = &self.qualify(table);
+        let row_count = database::estimate_row_count(table, &mut query.connection).await
+            .map_err(|error| Error::from_database(error, Error::QueryRuntime))?;
+        let replace = match self.confirm_replace(table, row_count).await? {
+            Some(replace) => replace,
+            None => return Ok(())
+        };
+        let saved_rows = query.materialize(table, replace).await
+            .map_err(|error| in_query(Error::from_query(error), &query.query, query.statement))?;
+        // Its connection is given back first, since recording the table needs one, and the
+        // pool may have no other
+        drop(query);
+        self.saved_table(table, saved_rows).await
+    }
+
+    /// Saves the query results in a new table of DuckDB, as materialize does in Postgres
+    async fn materialize_embedded(&mut self, embedded: &Embedded, query: &str, statement: Option<(usize, usize)>,
+                                  table: &str) -> Result<()> {
+        if !database::is_table_name(table) {
+            return Err(Error::Usage(eyre!("Invalid table name: {}", table)));
+        }
+        let table = &self.qualify(table);
+        let row_count = embedded.row_count(table).await?;
+        let replace = match self.confirm_replace(table, row_count).await? {
+            Some(replace) => replace,
+            None => return Ok(())
+        };
+        let saved_rows = embedded.materialize(query, table, replace).await
+            .map_err(|error| in_query(error, query, statement))?;
+        self.saved_table(table, saved_rows).await
+    }
+
+    /// Asks before replacing a table the results are saved in, if it exists. Returns whether to
+    /// replace it, or None if it is left as it is
+    async fn confirm_replace(&mut self, table: &str, row_count: Option<u64>) -> Result<Option<bool>> {
+        let row_count = match row_count {
+            None => return Ok(Some(false)),
+            Some(row_count) => row_count
+        };
+        let question = format!(
+            "Table {} exists with ~{} rows — drop and replace?", table, database::approximate_count(row_count)
+        );
+        let refusal = format!(
+            "Table {} already exists. Use --replace to drop it, or choose another name with --materialize", table
+        );
+        if self.confirm_write(&question, &refusal).await? {
+            return Ok(Some(true));
+        }
+        self.io.write_message(&format!("Left table {} as it is", table)).await.categorize(Error::OutputIo)?;
+        Ok(None)
+    }
+
+    /// Says where the results were saved, and remembers the table they were saved in
+    async fn saved_table(&mut self, table: &str, saved_rows: u64) -> Result<()> {
+        let reported = self.reported_query();
+        reported.finish("table", saved_rows);
+        reported.output_table = Some(String::from(table));
+        self.io.write_styled(Style::Highlight, &format!("Saved {} rows to table {}", saved_rows, table)).await
+            .categorize(Error::OutputIo)?;
+        self.profiles.remove(table);
+        self.record_table(String::from(table), None, Some(saved_rows)).await
+    }
+
+    /// Copies the query results to a table of another database, which is connected to only for
+    /// this. Its URL is the one given, or else the config's, or else asked for
+    async fn export_remote(&mut self, query: Query, url: Option<String>, table: &str) -> Result<()> {
+        if !database::is_table_name(table) {
+            return Err(Error::Usage(eyre!("Invalid table name: {}", table)));
+        }
+        let url = match url.or_else(|| self.config.remote_postgres_url.clone()) {
+            Some(url) => url,
+            None if self.args.non_interactive() => return Err(Error::Usage(eyre!(
+                "--remote-url must be given to copy the results to another database, unless the config has a \
+                remote_postgres_url"
+            ))),
+            None => self.io.prompt("Enter the connection URL of the database to copy the results to.").await
+                .categorize(Error::OutputIo)?.trim().to_owned()
+        };
+        let shown_url = config::mask_password(&url);
+        // Failing to reach it is not failing to reach the database queried, so it is an output error
+        let mut destination = PgConnection::connect(&url).await
+            .map_err(|error| {
+                Error::OutputIo(error.into()).wrap_err(format!("Unable to connect to the database at {}", shown_url))
+            })?;
+        let (text, statement) = (query.query.clone(), query.statement);
+        let exported = query.export_to(&mut destination, table, self.args.strict_column_names).await
+            .map_err(|error| in_query(error, &text, statement)
+                .wrap_err(format!("Nothing was copied to table {} of {}", table, shown_url)))?;
+        // The transaction is committed, so a failure to close the connection cleanly changes nothing
+        let _ = destination.close().await;
+        for warning in &exported.warnings {
+            self.io.write_styled(Style::Error, &format!("Warning: {}", warning)).await.categorize(Error::OutputIo)?;
+        }
+        let reported = self.reported_query();
+        reported.finish("remote", exported.rows);
+        reported.output_table = Some(String::from(table));
+        self.io.write_styled(Style::Highlight, &format!(
+            "Copied {} rows to {} table {} of {}",
+            exported.rows, if exported.created { "new" } else { "existing" }, table, shown_url
+        )).await.categorize(Error::OutputIo)
+    }
+
+    /// Describes the query before it runs, so that a mistake in it is reported before any of its
+    /// results are written. Results with more than WIDE_RESULTS columns are likely a whole table
+    /// exported by mistake, so they are only written if confirmed, or with a warning in
+    /// non-interactive mode. Statements which cannot be prepared are run without the check
+    async fn check_query(&mut self, query: &mut Query, column_order: &ColumnOrder) -> Result<bool> {
+        if !database::is_preparable(&query.query) {
+            return Ok(true);
+        }
+        let columns = query.column_names().await
+            .map_err(|error| in_query(Error::from_query(error), &query.query, query.statement))?;
+        let all_columns = matches!(column_order, ColumnOrder::AsIs | ColumnOrder::Alphabetical);
+        if columns.len() <= WIDE_RESULTS || !all_columns {
+            return Ok(true);
+        }
+        if self.args.non_interactive() {
+            let warning = format!("Warning: The query returns {} columns", columns.len());
+            self.io.write_styled(Style::Error, &warning).await.categorize(Error::OutputIo)?;
+            return Ok(true);
+        }
+        let question = format!("This query returns {} columns — continue? (y/n)", columns.len());
+        let answer = self.io.prompt(&question).await.categorize(Error::OutputIo)?;
+        if answer.trim().eq_ignore_ascii_case("y") {
+            return Ok(true);
+        }
+        self.io.write_message(
+            "Left the query unrun. Name the columns it needs, or use --choose-columns to write only some of them"
+        ).await.categorize(Error::OutputIo)?;
+        Ok(false)
+    }
+
+    /// Rows come in whatever order Postgres finds them, which may differ each time a query without
+    /// an ORDER BY of its own runs, so such queries are warned about. With --deterministic-order,
+    /// they are ordered by their SOURCE_LINE column instead, or else sorted by data-sifter, which
+    /// refuses to read more than the row cap returned
+    async fn order_rows(&mut self, query: &mut Query) -> Result<Option<u64>> {
+        if !database::returns_rows(&query.query) || statements::is_ordered(&query.query) {
+            return Ok(None);
+        }
+        if !self.args.deterministic_order && !self.config.deterministic_order {
+            self.io.write_styled(
+                Style::Error,
+                "Warning: The query has no ORDER BY, so its rows may be written in another order each time it \
+                runs. Pass --deterministic-order to write them in the same order"
+            ).await.categorize(Error::OutputIo)?;
+            return Ok(None);
+        }
+        let columns = query.column_names().await
+            .map_err(|error| in_query(Error::from_query(error), &query.query, query.statement))?;
+        if columns.iter().any(|column| column == SOURCE_LINE) {
+            query.query = database::ordered_by(&query.query, SOURCE_LINE);
+            return Ok(None);
+        }
+        Ok(Some(self.config.sort_row_cap.unwrap_or(SORT_ROW_CAP)))
+    }
+
+    /// Asks whether a query without a LIMIT, shown on the terminal, is limited to its first rows.
+    /// The limit is added to the query, so that the database does less work
+    async fn choose_to_limit(&mut self) -> Result<bool> {
+        loop {
+            let answer = self.io.prompt(&format!(
+                "No LIMIT — show only the first {} rows? (Y/n/all)", SHOWN_ROWS
+            )).await.categorize(Error::OutputIo)?;
+            match answer.trim().to_ascii_lowercase().as_str() {
+                "" | "y" | "yes" => return Ok(true),
+                "n" | "no" | "all" => return Ok(false),
+                _ => ()
+            }
+        }
+    }
+
+    async fn write_warnings(&mut self, summary: &OutputSummary) -> Result<()> {
+        for warning in &summary.warnings {
+            self.io.write_styled(Style::Error, &format!("Warning: {}", warning)).await.categorize(Error::OutputIo)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the query results, showing how far writing has got if asked to. Returns the summary,
+/// and the progress for its totals
+async fn write_results<W>(query: &mut Query, format: OutputFormat, options: &ExportOptions, output: W,
+                          progress: Arc<ExportProgress>, show_progress: bool) -> Result<OutputSummary>
+    where W: io::Write + Unpin {
+
+    let written = query.output_query_results(format, options, output, progress.clone());
+    let summary = if show_progress {
+        let shown = progress.clone();
+        let summary = written.or(progress::show(move || shown.report())).await;
+        progress::clear().await?;
+        summary
+    } else {
+        written.await
+    };
+    summary.map_err(|error| in_query(error, &query.query, query.statement))
+}
+
+/// Whether a query which failed may run again on a fresh connection: its own was lost, it
+/// only reads, and none of its results were written, so none would be written twice
+fn may_run_again(query: &Query, error: &Error, progress: &ExportProgress) -> bool {
+    error.lost_connection() && query.may_run_again() && progress.bytes() == 0
+}
+
+/// Suggests checking that the database can be reached, once a query lost its connection again
+/// after running again on a fresh one
+fn lost_again(error: Error) -> Error {
+    match error.lost_connection() {
+        true => error.wrap_err(
+            "The connection to the database was lost again. Check that the database can be reached, such as \
+            with data-sifter config validate"
+        ),
+        false => error
+    }
+}
+
+/// The width of the terminal, if the shell exports COLUMNS
+fn terminal_width() -> usize {
+    std::env::var("COLUMNS").ok()
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(DEFAULT_TERMINAL_WIDTH)
+}
+
+/// Adds the query to an error running it, but not to other errors, such as those writing
+/// its results
+fn in_query(error: Error, query: &str, statement: Option<(usize, usize)>) -> Error {
+    /// How much of the query is quoted
+    const QUOTED_CHARS: usize = 60;
+
+    match error {
+        Error::QuerySyntax(_) | Error::QueryRuntime(_) => {
+            let first_line = query.trim().lines().next().unwrap_or_default();
+            let mut quoted = first_line.chars().take(QUOTED_CHARS).collect::<String>();
+            if quoted.len() < query.trim().len() {
+                quoted.push_str("...");
+            }
+            match statement {
+                Some((index, count)) => {
+                    error.wrap_err(format!("Unable to run statement {} of {}, {}", index, count, quoted))
+                },
+                None => error.wrap_err(format!("Unable to run query {}", quoted))
+            }
+        },
+        error => error
+    }
+}
@@ -0,0 +1,111 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt::Write as _;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use async_std::io::Write;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::profile::ColumnProfile;
+
+/// Describes an exported file, so that its integrity can be verified and its origin traced.
+/// Written beside the file as <file>.manifest.json
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Manifest {
+    pub(crate) file: String,
+    pub(crate) sha256: String,
+    pub(crate) rows: u64,
+    pub(crate) columns: Vec<String>,
+    pub(crate) query: String,
+    /// The imported CSV file, unless existing data was kept
+    pub(crate) source: Option<String>,
+    /// What the columns of the imported CSV file looked like, unless existing data was kept
+    pub(crate) source_profile: Option<Vec<ColumnProfile>>,
+    /// When the file was written, in ISO-8601
+    pub(crate) created: String
+}
+
+impl Manifest {
+    pub(crate) fn path_for(file: &async_std::path::Path) -> async_std::path::PathBuf {
+        let mut path = file.as_os_str().to_os_string();
+        path.push(".manifest.json");
+        path.into()
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        let mut json = serde_json::to_string_pretty(self).expect("Manifests are always serializable");
+        json.push('\n');
+        json
+    }
+}
+
+/// Computes the SHA-256 hash of everything written, as it is written
+pub(crate) struct HashingWriter<W> {
+    writer: W,
+    hasher: Sha256
+}
+
+impl<W> HashingWriter<W> {
+    pub(crate) fn new(writer: W) -> Self {
+        Self { writer, hasher: Sha256::new() }
+    }
+
+    /// The writer, along with the hash in lowercase hexadecimal
+    pub(crate) fn into_parts(self) -> (W, String) {
+        let mut hex = String::new();
+        for byte in self.hasher.finalize() {
+            write!(hex, "{:02x}", byte).expect("Writing to a String");
+        }
+        (self.writer, hex)
+    }
+}
+
+impl<W> Write for HashingWriter<W> where W: Write + Unpin {
+    fn poll_write(mut self: Pin<&mut Self>, context: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.writer).poll_write(context, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            // Only what was actually written is hashed
+            self.hasher.update(&buf[..written]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(context)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_close(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::WriteExt;
+    use super::*;
+
+    #[async_std::test]
+    async fn hash_while_writing() -> std::io::Result<()> {
+        let mut writer = HashingWriter::new(Vec::new());
+        writer.write_all(b"hello ").await?;
+        writer.write_all(b"world").await?;
+        assert_eq!(b"hello world", writer.writer.as_slice());
+        assert_eq!("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9", writer.into_parts().1);
+        Ok(())
+    }
+}
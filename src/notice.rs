@@ -0,0 +1,84 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io::Write;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use crate::style::{self, Style};
+
+/// Where sqlx logs the notices sent by the server, such as those from RAISE NOTICE.
+/// sqlx has no other means of receiving them.
+const NOTICE_MODULE: &str = "sqlx::postgres::notice";
+
+/// Prints server notices and warnings to stderr as they arrive. Other log records are ignored.
+struct NoticeLogger {
+    color: bool,
+    /// Whether only warnings and errors are printed, leaving out notices
+    quiet: bool
+}
+
+impl NoticeLogger {
+    /// Recovers the severity from the log level sqlx chose for it
+    fn severity(level: Level) -> &'static str {
+        match level {
+            Level::Error => "ERROR",
+            Level::Warn => "WARNING",
+            Level::Info => "NOTICE",
+            Level::Debug => "DEBUG",
+            Level::Trace => "INFO"
+        }
+    }
+}
+
+impl Log for NoticeLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.target() == NOTICE_MODULE
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if record.module_path() != Some(NOTICE_MODULE) || (self.quiet && record.level() > Level::Warn) {
+            return;
+        }
+        let severity = Self::severity(record.level());
+        let style = if record.level() <= Level::Warn { Style::Error } else { Style::Highlight };
+        let line = format!("{} {}\n", style::paint(self.color, style, &format!("{}:", severity)), record.args());
+        // Written in one go, so that notices are not torn apart by other messages
+        let _ = std::io::stderr().lock().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Starts printing server notices. To silence them, set client_min_messages in the config.
+/// When quiet, only warnings and errors are printed
+pub fn install(color: bool, quiet: bool) {
+    if log::set_boxed_logger(Box::new(NoticeLogger { color, quiet })).is_ok() {
+        log::set_max_level(LevelFilter::Trace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notice_severities() {
+        assert_eq!("NOTICE", NoticeLogger::severity(Level::Info));
+        assert_eq!("WARNING", NoticeLogger::severity(Level::Warn));
+    }
+}
@@ -0,0 +1,56 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::ops::Range;
+
+/// Something which happened during an import, reported to an observer as it happens
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ImportEvent {
+    /// The header was read, and rows are about to be imported. The size of the input is 0 if
+    /// it is not known, and the bytes already read include the header and, if resuming, the
+    /// records imported before
+    Started { columns: Vec<String>, total_bytes: u64, bytes: u64 },
+    /// Rows were imported since the import started, from the input up to the given byte
+    Progress { rows: u64, bytes: u64 },
+    /// A long record is being read, up to the given byte, between the rows imported
+    Reading { bytes: u64 },
+    /// A record on the given line was left out
+    RowRejected { line: u64, reason: String },
+    /// The rows of the table in the range were committed, counting from its first row
+    BatchCommitted { rows: Range<u64> },
+    /// The import ended, whether or not it succeeded. This is always the last event
+    Finished { stats: ImportStats }
+}
+
+/// What an import did, as far as it got
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ImportStats {
+    /// Rows imported since the import started, though they may not have been committed
+    pub(crate) rows: u64,
+    /// Records which were left out
+    pub(crate) rejected: u64,
+    /// How many rows the table has after the last commit, once it is known
+    pub(crate) table_rows: Option<u64>,
+    /// Why the import failed, if it did
+    pub(crate) error: Option<String>
+}
+
+/// Receives the events of an import, such as to show its progress. Events are reported from
+/// the task importing the dataset, so observing them ought to be quick.
+pub(crate) trait ImportObserver: Send + Sync {
+    fn observe(&self, event: &ImportEvent);
+}
@@ -0,0 +1,962 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::borrow::Cow;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use async_std::io::{Write, WriteExt};
+use async_std::path::{Path, PathBuf};
+use eyre::{eyre, Result};
+use futures_lite::{future, ready};
+use indexmap::IndexSet;
+use crate::aligned::AlignedWriter;
+use crate::decode::{DecodedValue, FormatOptions, write_json_string};
+use crate::expanded::ExpandedWriter;
+use crate::progress::ExportProgress;
+
+/// Options for writing query results
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ExportOptions {
+    pub(crate) format: FormatOptions,
+    /// A json column whose top-level keys are expanded into output columns
+    pub(crate) expand_json: Option<String>,
+    /// How many rows are scanned to discover the keys of the expanded column
+    pub(crate) expand_json_scan: usize,
+    pub(crate) header_case: HeaderCase,
+    pub(crate) column_order: ColumnOrder,
+    /// Whether the header is written even if there are no rows
+    pub(crate) header_if_empty: bool,
+    /// How the results are laid out for reading, rather than written as CSV
+    pub(crate) readable: Option<ReadableLayout>,
+    /// Columns whose values are masked, named in any case. Only shown results are redacted
+    pub(crate) redacted: Vec<String>,
+    /// Whether columns sharing a name are an error, rather than numbered apart
+    pub(crate) strict_column_names: bool,
+    /// Whether each row is written as soon as it is, for results read as they come on a terminal
+    pub(crate) flush_rows: bool,
+    /// Whether the rows are sorted before they are written, and how many may be
+    pub(crate) sort_rows: Option<u64>
+}
+
+/// How results shown for reading are laid out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ReadableLayout {
+    /// Aligned in columns, none wider than this
+    Aligned(usize),
+    /// Each row as a block of lines, with values wrapped to fit this width
+    Expanded(usize)
+}
+
+/// How column names are cased in the header
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum HeaderCase {
+    /// As the query named them
+    #[default]
+    AsIs,
+    Lower,
+    Upper
+}
+
+impl HeaderCase {
+    fn apply(self, column: &str) -> String {
+        match self {
+            Self::AsIs => column.to_owned(),
+            Self::Lower => column.to_lowercase(),
+            Self::Upper => column.to_uppercase()
+        }
+    }
+}
+
+/// The order in which columns are written
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) enum ColumnOrder {
+    /// As the query returned them
+    #[default]
+    AsIs,
+    /// Sorted by name
+    Alphabetical,
+    /// Only the columns listed, in the order listed
+    Listed(Vec<String>),
+    /// Only the columns at these positions, counted from 0, in this order, as chosen with
+    /// [choose_columns]
+    Picked(Vec<usize>)
+}
+
+/// Chooses columns of the results by their numbers, counted from 1, as in 'keep 1-5,7' for those
+/// columns in that order, or 'drop 3,7' for the others in their order. Returns their positions
+pub(crate) fn choose_columns(choice: &str, columns: usize) -> Result<Vec<usize>> {
+    let (action, numbers) = choice.trim().split_once(' ')
+        .ok_or_else(|| eyre!("Enter 'keep' or 'drop' followed by column numbers, such as 'drop 3,7'"))?;
+    let mut listed = Vec::new();
+    for item in numbers.split(',').map(str::trim) {
+        let number = |number: &str| match number.trim().parse::<usize>() {
+            Ok(number) if (1..=columns).contains(&number) => Ok(number - 1),
+            _ => Err(eyre!("Invalid column number {}. The columns are numbered from 1 to {}", number.trim(), columns))
+        };
+        let (first, last) = match item.split_once('-') {
+            Some((first, last)) => (number(first)?, number(last)?),
+            None => (number(item)?, number(item)?)
+        };
+        if first > last {
+            return Err(eyre!("Invalid range {}, which ends before it starts", item));
+        }
+        for position in first..=last {
+            if listed.contains(&position) {
+                return Err(eyre!("Column {} is chosen more than once", position + 1));
+            }
+            listed.push(position);
+        }
+    }
+    match action {
+        "keep" => Ok(listed),
+        "drop" if listed.len() == columns => Err(eyre!("At least one column must be kept")),
+        "drop" => Ok((0..columns).filter(|position| !listed.contains(position)).collect()),
+        _ => Err(eyre!("Unknown choice {}. Enter 'keep' or 'drop' followed by column numbers", action))
+    }
+}
+
+/// Tells apart columns of the results which share a name, as a join of tables with an id each
+/// returns, since CSV readers would confuse them and JSON would keep only one. The first keeps
+/// its name, and the others are numbered from 2, as in id_2, skipping names already taken.
+/// Returns the names along with a warning for each renamed column, or an error if strict
+pub(crate) fn unique_names(columns: Vec<String>, strict: bool) -> Result<(Vec<String>, Vec<String>)> {
+    let mut taken = columns.iter().cloned().collect::<IndexSet<_>>();
+    if taken.len() == columns.len() {
+        return Ok((columns, Vec::new()));
+    }
+    if strict {
+        let shared = columns.iter()
+            .filter(|column| columns.iter().filter(|other| other == column).count() > 1)
+            .collect::<IndexSet<_>>();
+        return Err(eyre!(
+            "The query returns more than one column named {}. Give them distinct names with AS",
+            shared.into_iter().map(String::as_str).collect::<Vec<_>>().join(", ")
+        ));
+    }
+    let mut names = Vec::with_capacity(columns.len());
+    let mut warnings = Vec::new();
+    for (index, column) in columns.iter().enumerate() {
+        if !columns[..index].contains(column) {
+            names.push(column.clone());
+            continue;
+        }
+        let renamed = (2..).map(|number| format!("{}_{}", column, number))
+            .find(|name| !taken.contains(name))
+            .expect("Some number is free");
+        taken.insert(renamed.clone());
+        warnings.push(format!(
+            "Column {} of the results is named {} like an earlier one, so it is written as {}",
+            index + 1, column, renamed
+        ));
+        names.push(renamed);
+    }
+    Ok((names, warnings))
+}
+
+/// What a redacted value is shown as, by whether it is short, medium or long, so that redacted
+/// results are laid out much as they would be otherwise
+const REDACTED: [(usize, &str); 3] = [
+    (8, "*****"),
+    (24, "************"),
+    (usize::MAX, "************************")
+];
+
+/// Masks the text of a redacted value, keeping only how long it roughly is
+pub(crate) fn redact(text: &str) -> &'static str {
+    let length = text.chars().count();
+    REDACTED.iter().find(|(longest, _)| length <= *longest).map_or(REDACTED[2].1, |(_, mask)| mask)
+}
+
+/// Which columns of the results are written, and in what order, with their header. Every
+/// row's fields are arranged alike.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ColumnLayout {
+    pub(crate) header: Vec<String>,
+    /// For each output column, the index of the result column, or none if they are the same
+    indices: Option<Vec<usize>>,
+    /// Whether each output column is redacted, or none if no column is
+    redacted: Option<Vec<bool>>
+}
+
+impl ColumnLayout {
+
+    /// Arranges the columns of the results. A listed column which is not in the results is
+    /// an error, so that it is found before any rows are written
+    pub(crate) fn new(columns: &[String], order: &ColumnOrder, case: HeaderCase) -> Result<Self> {
+        let indices = match order {
+            ColumnOrder::AsIs => None,
+            ColumnOrder::Alphabetical => {
+                let mut indices = (0..columns.len()).collect::<Vec<_>>();
+                indices.sort_by(|left, right| columns[*left].cmp(&columns[*right]));
+                Some(indices)
+            },
+            ColumnOrder::Listed(listed) => {
+                let mut indices = Vec::with_capacity(listed.len());
+                for name in listed {
+                    let index = columns.iter().position(|column| column == name).ok_or_else(|| eyre!(
+                        "Cannot write column {}, which is not in the query results. They are: {}",
+                        name, columns.join(", ")
+                    ))?;
+                    if indices.contains(&index) {
+                        return Err(eyre!("Column {} is listed more than once", name));
+                    }
+                    indices.push(index);
+                }
+                Some(indices)
+            },
+            ColumnOrder::Picked(picked) => {
+                if let Some(position) = picked.iter().find(|position| **position >= columns.len()) {
+                    return Err(eyre!(
+                        "Cannot write column {}, since the query results have {} columns", position + 1, columns.len()
+                    ));
+                }
+                Some(picked.clone())
+            }
+        };
+        let header = match &indices {
+            Some(indices) => indices.iter().map(|index| case.apply(&columns[*index])).collect(),
+            None => columns.iter().map(|column| case.apply(column)).collect()
+        };
+        Ok(Self { header, indices, redacted: None })
+    }
+
+    /// Redacts the output columns with these names, regardless of case. Returns warnings for
+    /// the names which are not among the columns
+    pub(crate) fn redact(&mut self, names: &[String]) -> Vec<String> {
+        if names.is_empty() {
+            return Vec::new();
+        }
+        let names = names.iter().map(|name| name.to_lowercase()).collect::<Vec<_>>();
+        let header = self.header.iter().map(|column| column.to_lowercase()).collect::<Vec<_>>();
+        self.redacted = Some(header.iter().map(|column| names.contains(column)).collect());
+        names.into_iter()
+            .filter(|name| !header.contains(name))
+            .map(|name| format!("Column {} is not in the query results, so nothing of it was redacted", name))
+            .collect()
+    }
+
+    /// The fields of a row in output order
+    pub(crate) fn arrange<'r, 'v>(&self, row: &'r [DecodedValue<'v>]) -> Cow<'r, [DecodedValue<'v>]> {
+        match &self.indices {
+            Some(indices) => Cow::Owned(indices.iter().map(|index| row[*index].clone()).collect()),
+            None => Cow::Borrowed(row)
+        }
+    }
+
+    /// Masks the values of redacted columns in a row in output order. NULL is left as it is
+    pub(crate) fn mask<'r, 'v>(&self, row: Cow<'r, [DecodedValue<'v>]>,
+                               options: &FormatOptions) -> Cow<'r, [DecodedValue<'v>]> {
+        let redacted = match &self.redacted {
+            Some(redacted) if redacted.contains(&true) => redacted,
+            _ => return row
+        };
+        let mut row = row.into_owned();
+        for (value, redacted) in row.iter_mut().zip(redacted) {
+            if *redacted && !matches!(value, DecodedValue::Null) {
+                *value = DecodedValue::Text(Cow::Borrowed(redact(&value.to_text(options))));
+            }
+        }
+        Cow::Owned(row)
+    }
+}
+
+/// What happened while writing query results
+#[derive(Clone, Debug, Default)]
+pub(crate) struct OutputSummary {
+    pub(crate) rows: u64,
+    /// The names of the columns written, if there were any rows
+    pub(crate) columns: Vec<String>,
+    /// Problems which did not prevent writing the results
+    pub(crate) warnings: Vec<String>,
+    /// How many cells were cut short to fit their columns, if the results were aligned
+    pub(crate) truncated: u64
+}
+
+/// The file formats query results can be written in
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum OutputFormat {
+    Csv,
+    /// An array of objects, one per row, keyed by column name
+    Json
+}
+
+impl OutputFormat {
+    /// Every format, in the order they are offered
+    pub(crate) const ALL: [Self; 2] = [Self::Csv, Self::Json];
+
+    /// The name of the format when choosing it, which is also its file extension
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Json => "json"
+        }
+    }
+
+    /// What a file of the format holds, as listed by `data-sifter info`
+    pub(crate) fn description(self) -> &'static str {
+        match self {
+            Self::Csv => "Comma-separated values, headed by the column names",
+            Self::Json => "An array of objects, one per row, keyed by column name"
+        }
+    }
+
+    /// The name of the format in messages
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            Self::Csv => "CSV",
+            Self::Json => "JSON"
+        }
+    }
+}
+
+/// Why a dataset looks like the results of an earlier query, if it does. Output files are
+/// named after their datasets in the output directory, so files there count too. All paths
+/// must be canonical.
+pub(crate) fn previous_output_reason(dataset: &Path, written: &[PathBuf], has_manifest: bool,
+                                     output_dir: Option<&Path>) -> Option<&'static str> {
+    if written.iter().any(|output| output == dataset) {
+        return Some("it was written by a query earlier in this session");
+    }
+    if has_manifest {
+        return Some("it has a manifest beside it");
+    }
+    let extension = dataset.extension().and_then(|extension| extension.to_str());
+    let is_output_format = OutputFormat::ALL.iter().any(|format| Some(format.extension()) == extension);
+    if is_output_format && output_dir.is_some() && dataset.parent() == output_dir {
+        return Some("it is in the output directory");
+    }
+    None
+}
+
+/// What is done with the results of a query
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Destination {
+    File(OutputFormat),
+    /// The results are shown on stdout, aligned in columns on a terminal and otherwise as CSV
+    Show,
+    /// The results are saved in a new table
+    Table(String),
+    /// The results are shown on stdout with each row as a block of lines
+    Expanded,
+    /// The results are copied to a table of another database, given by its URL or else the config
+    Remote {
+        url: Option<String>,
+        table: String
+    }
+}
+
+impl Destination {
+
+    /// The options for the user to choose from, in the order they are listed, with what each does
+    pub(crate) fn options() -> Vec<(String, String)> {
+        OutputFormat::ALL.iter().map(|format| (
+            format.extension().to_owned(),
+            format!("Query the dataset and output the results to {}.", format.label())
+        )).chain([
+            (String::from("show"), String::from("Query the dataset and show the results here.")),
+            (String::from("table <name>"), String::from("Save the query results in a new table, for further queries.")),
+            (String::from("expanded"), String::from("Query the dataset and show each row of the results as a block of lines.")),
+            (String::from("remote [url] <table>"),
+             String::from("Copy the query results to a table of another database."))
+        ]).collect()
+    }
+
+    /// The options for the user to choose from, numbered in the order they are listed
+    pub(crate) fn menu() -> String {
+        let mut menu = String::from("What would you like to do with this query?");
+        for (number, (option, description)) in Self::options().into_iter().enumerate() {
+            menu.push_str(&format!("\n{}. '{}' - {}", number + 1, option, description));
+        }
+        menu
+    }
+
+    /// Parses an option from the menu, by name or number. Names are not case-sensitive,
+    /// though table names are kept as they are
+    pub(crate) fn parse(answer: &str) -> Option<Self> {
+        let answer = answer.trim();
+        let (option, argument) = match answer.split_once(char::is_whitespace) {
+            Some((option, argument)) => (option, Some(argument.trim())),
+            None => (answer, None)
+        };
+        let option = option.to_ascii_lowercase();
+        let formats = OutputFormat::ALL.len();
+        let number = option.parse::<usize>().ok();
+        let chosen = |name: &str, position: usize| option == name || number == Some(position);
+        match argument {
+            None => {
+                for (index, format) in OutputFormat::ALL.into_iter().enumerate() {
+                    if chosen(format.extension(), index + 1) {
+                        return Some(Self::File(format));
+                    }
+                }
+                if chosen("show", formats + 1) {
+                    Some(Self::Show)
+                } else {
+                    chosen("expanded", formats + 3).then_some(Self::Expanded)
+                }
+            },
+            Some(argument) if chosen("remote", formats + 4) => match argument.split_once(char::is_whitespace) {
+                Some((url, table)) if !table.trim().contains(char::is_whitespace) => Some(Self::Remote {
+                    url: Some(url.to_owned()), table: table.trim().to_owned()
+                }),
+                Some(_) => None,
+                None => Some(Self::Remote { url: None, table: argument.to_owned() })
+            },
+            Some(table) => chosen("table", formats + 2).then(|| Self::Table(table.to_owned()))
+        }
+    }
+}
+
+/// Writes query results in an output format
+pub(crate) enum ResultWriter<W> where W: Write + Unpin {
+    Csv(Box<csv_async::AsyncWriter<RowBuffer<W>>>),
+    Json {
+        writer: RowBuffer<W>,
+        columns: Vec<String>,
+        any_rows: bool
+    },
+    Aligned(Box<AlignedWriter<RowBuffer<W>>>),
+    Expanded(Box<ExpandedWriter<RowBuffer<W>>>)
+}
+
+impl<W> ResultWriter<W> where W: Write + Unpin {
+
+    pub(crate) fn new(format: OutputFormat, writer: RowBuffer<W>) -> Self {
+        match format {
+            OutputFormat::Csv => Self::Csv(Box::new(csv_async::AsyncWriter::from_writer(writer))),
+            OutputFormat::Json => Self::Json { writer, columns: Vec::new(), any_rows: false }
+        }
+    }
+
+    /// Lays out the results for reading
+    pub(crate) fn readable(writer: RowBuffer<W>, layout: ReadableLayout) -> Self {
+        match layout {
+            ReadableLayout::Aligned(max_width) => Self::Aligned(Box::new(AlignedWriter::new(writer, max_width))),
+            ReadableLayout::Expanded(width) => Self::Expanded(Box::new(ExpandedWriter::new(writer, width)))
+        }
+    }
+
+    pub(crate) async fn write_header(&mut self, columns: Vec<String>) -> Result<()> {
+        match self {
+            Self::Csv(writer) => writer.write_record(columns).await?,
+            Self::Json { columns: json_columns, .. } => {
+                // Keys are encoded once rather than for every row
+                *json_columns = columns.into_iter().map(|column| {
+                    let mut key = String::new();
+                    write_json_string(&column, &mut key);
+                    key
+                }).collect();
+            },
+            Self::Aligned(writer) => writer.write_header(columns),
+            Self::Expanded(writer) => writer.write_header(columns)
+        }
+        self.end_row().await
+    }
+
+    pub(crate) async fn write_row(&mut self, row: &[DecodedValue<'_>], options: &FormatOptions) -> Result<()> {
+        match self {
+            Self::Csv(writer) => {
+                // Collected first, since a lazy iterator borrowing the options, held across the
+                // await, would keep the future from being sent to another task
+                let record = row.iter().map(|value| value.to_text(options).into_owned()).collect::<Vec<_>>();
+                writer.write_record(record).await?;
+            },
+            Self::Json { writer, columns, any_rows } => {
+                let mut object = String::from(if *any_rows { ",\n{" } else { "[\n{" });
+                for (index, (key, value)) in columns.iter().zip(row).enumerate() {
+                    if index != 0 { object.push(','); }
+                    object.push_str(key);
+                    object.push(':');
+                    value.write_json(options, &mut object);
+                }
+                object.push('}');
+                *any_rows = true;
+                writer.write_all(object.as_bytes()).await?;
+            },
+            Self::Aligned(writer) => {
+                writer.write_row(row.iter().map(|value| value.to_text(options).into_owned()).collect()).await?;
+            },
+            Self::Expanded(writer) => {
+                writer.write_row(row.iter().map(|value| match value {
+                    DecodedValue::Null => None,
+                    value => Some(value.to_text(options).into_owned())
+                }).collect()).await?;
+            }
+        }
+        self.end_row().await
+    }
+
+    /// Flushing the buffer beneath marks the end of a row, so that only whole rows are written
+    async fn end_row(&mut self) -> Result<()> {
+        match self {
+            Self::Csv(writer) => writer.flush().await?,
+            Self::Json { writer, .. } => writer.flush().await?,
+            Self::Aligned(writer) => writer.flush().await?,
+            Self::Expanded(writer) => writer.flush().await?
+        }
+        Ok(())
+    }
+
+    /// Writes the rest of the results, returning how many cells were cut short to fit their
+    /// columns, which only aligned results may be
+    pub(crate) async fn finish(self) -> Result<u64> {
+        let (buffer, truncated) = match self {
+            Self::Csv(writer) => (writer.into_inner().await?, 0),
+            Self::Json { mut writer, any_rows, .. } => {
+                writer.write_all(if any_rows { b"\n]\n" as &[u8] } else { b"[]\n" }).await?;
+                (writer, 0)
+            },
+            Self::Aligned(writer) => writer.finish().await?,
+            Self::Expanded(writer) => (writer.finish().await?, 0)
+        };
+        buffer.finish().await?;
+        Ok(truncated)
+    }
+
+    /// Writes the whole rows so far, once an error has cut the results short. JSON is left
+    /// unclosed, so that the results cannot be mistaken for complete ones
+    pub(crate) async fn abandon(self) -> Result<()> {
+        let buffer = match self {
+            Self::Csv(writer) => writer.into_inner().await?,
+            Self::Json { writer, .. } => writer,
+            Self::Aligned(writer) => writer.finish().await?.0,
+            Self::Expanded(writer) => writer.finish().await?
+        };
+        buffer.abandon().await?;
+        Ok(())
+    }
+}
+
+/// How many bytes of results are held before they are written, unless each row is written
+const OUTPUT_BUFFER: usize = 64 * 1024;
+
+/// Holds the results written, and writes them on in whole rows, OUTPUT_BUFFER bytes at a time
+/// rather than a few bytes for each field. Flushing it marks the end of a row, rather than
+/// writing what it holds, unless each row is to be written as soon as it ends. Results cut
+/// short by an error are only written up to the end of the last whole row
+pub(crate) struct RowBuffer<W> {
+    writer: W,
+    buffer: Vec<u8>,
+    /// How much of the buffer holds whole rows
+    whole_rows: usize,
+    /// How much of the buffer is being written, and how much of that has been
+    writing: Option<(usize, usize)>,
+    each_row: bool
+}
+
+impl<W> RowBuffer<W> where W: Write + Unpin {
+
+    pub(crate) fn new(writer: W, each_row: bool) -> Self {
+        Self { writer, buffer: Vec::with_capacity(OUTPUT_BUFFER), whole_rows: 0, writing: None, each_row }
+    }
+
+    /// Writes everything held, then returns the writer
+    pub(crate) async fn finish(mut self) -> std::io::Result<W> {
+        let end = self.buffer.len();
+        future::poll_fn(|context| self.poll_write_out(context, end)).await?;
+        Ok(self.writer)
+    }
+
+    /// Writes the whole rows held, leaving out any row which did not end
+    pub(crate) async fn abandon(mut self) -> std::io::Result<W> {
+        let end = self.whole_rows;
+        future::poll_fn(|context| self.poll_write_out(context, end)).await?;
+        Ok(self.writer)
+    }
+
+    /// Writes the start of the buffer, up to the end given, then flushes the writer. Once
+    /// started, the same part is written until it all has been
+    fn poll_write_out(&mut self, context: &mut Context<'_>, end: usize) -> Poll<std::io::Result<()>> {
+        loop {
+            let (end, written) = *self.writing.get_or_insert((end, 0));
+            if written == end {
+                break;
+            }
+            let count = ready!(Pin::new(&mut self.writer).poll_write(context, &self.buffer[written..end]))?;
+            if count == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.writing = Some((end, written + count));
+        }
+        ready!(Pin::new(&mut self.writer).poll_flush(context))?;
+        let (end, _) = self.writing.take().expect("The part written was chosen");
+        self.buffer.drain(..end);
+        self.whole_rows = self.whole_rows.saturating_sub(end);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W> Write for RowBuffer<W> where W: Write + Unpin {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        self.get_mut().buffer.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let buffer = self.get_mut();
+        if buffer.writing.is_none() {
+            buffer.whole_rows = buffer.buffer.len();
+            if !buffer.each_row && buffer.whole_rows < OUTPUT_BUFFER {
+                return Poll::Ready(Ok(()));
+            }
+        }
+        buffer.poll_write_out(context, buffer.whole_rows)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let buffer = self.get_mut();
+        let end = buffer.buffer.len();
+        ready!(buffer.poll_write_out(context, end))?;
+        Pin::new(&mut buffer.writer).poll_close(context)
+    }
+}
+
+/// Counts the bytes written towards the progress of an export
+pub(crate) struct CountingWriter<W> {
+    writer: W,
+    progress: Arc<ExportProgress>
+}
+
+impl<W> CountingWriter<W> {
+    pub(crate) fn new(writer: W, progress: Arc<ExportProgress>) -> Self {
+        Self { writer, progress }
+    }
+}
+
+impl<W> Write for CountingWriter<W> where W: Write + Unpin {
+    fn poll_write(mut self: Pin<&mut Self>, context: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.writer).poll_write(context, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.progress.add_bytes(written as u64);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(context)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_close(context)
+    }
+}
+
+/// Expands the top-level keys of a json column into output columns, taking the place of
+/// the column itself. Rows lacking a key, or whose value is not an object, get NULL.
+///
+/// Since results are streamed, keys are discovered from an initial sample of rows.
+/// Keys which only appear after the sample are not expanded, but they are recorded so
+/// that a warning can be given.
+#[derive(Debug)]
+pub(crate) struct JsonExpansion {
+    column_index: usize,
+    keys: IndexSet<String>,
+    undiscovered_keys: IndexSet<String>
+}
+
+impl JsonExpansion {
+
+    /// Discovers keys from the values of the json column in the sample rows
+    pub(crate) fn discover<'v>(column_index: usize,
+                               sample: impl IntoIterator<Item = DecodedValue<'v>>) -> Self {
+        let mut keys = IndexSet::new();
+        for value in sample {
+            if let DecodedValue::Json(json) = value {
+                if let serde_json::Value::Object(object) = json.as_ref() {
+                    keys.extend(object.keys().cloned());
+                }
+            }
+        }
+        Self { column_index, keys, undiscovered_keys: IndexSet::new() }
+    }
+
+    /// The output columns. Keys named the same as another column are prefixed with the
+    /// expanded column's name, e.g. payload.id
+    pub(crate) fn header(&self, columns: &[String]) -> Vec<String> {
+        let expanded_column = &columns[self.column_index];
+        let mut header = Vec::with_capacity(columns.len() + self.keys.len());
+        header.extend_from_slice(&columns[..self.column_index]);
+        for key in &self.keys {
+            let collides = columns.iter().enumerate()
+                .any(|(index, column)| index != self.column_index && column == key);
+            header.push(if collides { format!("{}.{}", expanded_column, key) } else { key.clone() });
+        }
+        header.extend_from_slice(&columns[self.column_index + 1..]);
+        header
+    }
+
+    pub(crate) fn expand<'a>(&mut self, row: &'a [DecodedValue<'_>]) -> Vec<DecodedValue<'a>> {
+        let object = match &row[self.column_index] {
+            DecodedValue::Json(json) => json.as_object(),
+            _ => None
+        };
+        if let Some(object) = object {
+            for key in object.keys() {
+                if !self.keys.contains(key) {
+                    self.undiscovered_keys.insert(key.clone());
+                }
+            }
+        }
+        let mut expanded = Vec::with_capacity(row.len() + self.keys.len());
+        expanded.extend(row[..self.column_index].iter().cloned());
+        for key in &self.keys {
+            expanded.push(match object.and_then(|object| object.get(key)) {
+                Some(value) => DecodedValue::from_json(value),
+                None => DecodedValue::Null
+            });
+        }
+        expanded.extend(row[self.column_index + 1..].iter().cloned());
+        expanded
+    }
+
+    pub(crate) fn warning(&self, column_name: &str, scanned_rows: usize) -> Option<String> {
+        if self.undiscovered_keys.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "Keys of {} which first appeared after the first {} rows were not expanded: {}",
+            column_name, scanned_rows, self.undiscovered_keys.iter().cloned().collect::<Vec<_>>().join(", ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(text: &str) -> serde_json::Value {
+        serde_json::from_str(text).unwrap()
+    }
+
+    fn text(value: &str) -> DecodedValue<'_> {
+        DecodedValue::Text(Cow::Borrowed(value))
+    }
+
+    #[test]
+    fn arrange_columns() {
+        let columns = vec![String::from("Zone"), String::from("id"), String::from("Amount")];
+        let row = [text("north"), text("1"), text("2.5")];
+        let fields = |layout: &ColumnLayout| layout.arrange(&row).iter()
+            .map(|field| field.to_text(&FormatOptions::default()).into_owned())
+            .collect::<Vec<_>>();
+
+        let as_is = ColumnLayout::new(&columns, &ColumnOrder::AsIs, HeaderCase::AsIs).unwrap();
+        assert_eq!(columns, as_is.header);
+        assert_eq!(vec!["north", "1", "2.5"], fields(&as_is));
+
+        let sorted = ColumnLayout::new(&columns, &ColumnOrder::Alphabetical, HeaderCase::Lower).unwrap();
+        assert_eq!(vec!["amount", "zone", "id"], sorted.header);
+        assert_eq!(vec!["2.5", "north", "1"], fields(&sorted));
+
+        let listed = ColumnOrder::Listed(vec![String::from("id"), String::from("Zone")]);
+        let listed = ColumnLayout::new(&columns, &listed, HeaderCase::Upper).unwrap();
+        assert_eq!(vec!["ID", "ZONE"], listed.header);
+        assert_eq!(vec!["1", "north"], fields(&listed));
+
+        let unknown = ColumnOrder::Listed(vec![String::from("zone")]);
+        assert_eq!(
+            "Cannot write column zone, which is not in the query results. They are: Zone, id, Amount",
+            ColumnLayout::new(&columns, &unknown, HeaderCase::AsIs).unwrap_err().to_string()
+        );
+        let twice = ColumnOrder::Listed(vec![String::from("id"), String::from("id")]);
+        assert!(ColumnLayout::new(&columns, &twice, HeaderCase::AsIs).is_err());
+
+        let picked = ColumnLayout::new(&columns, &ColumnOrder::Picked(vec![2, 0]), HeaderCase::AsIs).unwrap();
+        assert_eq!(vec!["Amount", "Zone"], picked.header);
+        assert_eq!(vec!["2.5", "north"], fields(&picked));
+        assert!(ColumnLayout::new(&columns, &ColumnOrder::Picked(vec![3]), HeaderCase::AsIs).is_err());
+    }
+
+    #[test]
+    fn keep_and_drop_columns() {
+        assert_eq!(vec![0, 1, 2, 3, 4], choose_columns("keep 1-5", 8).unwrap());
+        assert_eq!(vec![6, 0, 1], choose_columns("keep 7, 1-2", 8).unwrap());
+        assert_eq!(vec![0, 1, 3, 4, 5, 7], choose_columns("drop 3,7", 8).unwrap());
+        assert_eq!(vec![2], choose_columns(" drop 1-2 ", 3).unwrap());
+        assert_eq!(
+            "Invalid column number 9. The columns are numbered from 1 to 8",
+            choose_columns("keep 1,9", 8).unwrap_err().to_string()
+        );
+        assert!(choose_columns("keep 0", 8).is_err());
+        assert!(choose_columns("keep 3-1", 8).is_err());
+        assert!(choose_columns("keep 1,1", 8).is_err());
+        assert!(choose_columns("drop 1-3", 3).is_err());
+        assert!(choose_columns("hide 1", 3).is_err());
+        assert!(choose_columns("keep", 3).is_err());
+    }
+
+    #[test]
+    fn name_columns_apart() {
+        let names = |columns: &[&str]| columns.iter().map(|column| column.to_string()).collect::<Vec<_>>();
+        let (unique, warnings) = unique_names(names(&["id", "name"]), true).unwrap();
+        assert_eq!(names(&["id", "name"]), unique);
+        assert!(warnings.is_empty());
+
+        let (unique, warnings) = unique_names(names(&["id", "name", "id", "id_2", "id", "name"]), false).unwrap();
+        assert_eq!(names(&["id", "name", "id_3", "id_2", "id_4", "name_2"]), unique);
+        assert_eq!(
+            "Column 3 of the results is named id like an earlier one, so it is written as id_3",
+            warnings[0]
+        );
+        assert_eq!(3, warnings.len());
+
+        assert_eq!(
+            "The query returns more than one column named id, name. Give them distinct names with AS",
+            unique_names(names(&["id", "name", "id", "name", "id"]), true).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn redact_columns() {
+        let columns = vec![String::from("id"), String::from("Email"), String::from("name")];
+        let mut layout = ColumnLayout::new(&columns, &ColumnOrder::AsIs, HeaderCase::AsIs).unwrap();
+        let warnings = layout.redact(&[String::from("email"), String::from("NAME"), String::from("phone")]);
+        assert_eq!(vec!["Column phone is not in the query results, so nothing of it was redacted"], warnings);
+
+        let row = [text("1"), text("ann@example.com"), DecodedValue::Null];
+        let shown = layout.mask(layout.arrange(&row), &FormatOptions::default());
+        assert_eq!(vec![text("1"), text("************"), DecodedValue::Null], shown.into_owned());
+
+        assert_eq!("*****", redact("Ann"));
+        // Lengths are counted in characters rather than bytes
+        assert_eq!("*****", redact("Åsa Öber"));
+        assert_eq!("************", redact("Annabelle Smith"));
+        assert_eq!("************************", redact("a description well over twenty-four characters"));
+    }
+
+    #[test]
+    fn recognize_previous_outputs() {
+        let dataset = Path::new("/results/sales.csv");
+        let written = [PathBuf::from("/results/sales.csv")];
+        assert_eq!(Some("it was written by a query earlier in this session"),
+                   previous_output_reason(dataset, &written, false, None));
+        assert_eq!(Some("it has a manifest beside it"), previous_output_reason(dataset, &[], true, None));
+        assert_eq!(Some("it is in the output directory"),
+                   previous_output_reason(dataset, &[], false, Some(Path::new("/results"))));
+        // Only files directly inside the output directory are named like its outputs
+        assert_eq!(None, previous_output_reason(
+            Path::new("/results/raw/sales.csv"), &[], false, Some(Path::new("/results"))
+        ));
+        assert_eq!(None, previous_output_reason(
+            Path::new("/results/sales.tsv"), &[], false, Some(Path::new("/results"))
+        ));
+        assert_eq!(None, previous_output_reason(dataset, &[], false, Some(Path::new("/data"))));
+    }
+
+    #[test]
+    fn parse_destinations() {
+        assert_eq!(Some(Destination::File(OutputFormat::Csv)), Destination::parse(" CSV"));
+        assert_eq!(Some(Destination::File(OutputFormat::Json)), Destination::parse("2"));
+        assert_eq!(Some(Destination::Show), Destination::parse("Show\t"));
+        assert_eq!(Some(Destination::Show), Destination::parse("3"));
+        assert_eq!(Some(Destination::Table(String::from("Totals"))), Destination::parse("TABLE  Totals"));
+        assert_eq!(Some(Destination::Table(String::from("totals"))), Destination::parse("4 totals"));
+        assert_eq!(None, Destination::parse("table"));
+        assert_eq!(None, Destination::parse("csv file"));
+        assert_eq!(Some(Destination::Expanded), Destination::parse("expanded"));
+        assert_eq!(Some(Destination::Expanded), Destination::parse("5"));
+        assert_eq!(Some(Destination::Remote { url: None, table: String::from("totals") }),
+                   Destination::parse("remote totals"));
+        let remote = Destination::Remote {
+            url: Some(String::from("postgres://reports/db")), table: String::from("totals")
+        };
+        assert_eq!(Some(remote), Destination::parse("6 postgres://reports/db  totals"));
+        assert_eq!(None, Destination::parse("remote postgres://reports/db totals extra"));
+        assert_eq!(None, Destination::parse("remote"));
+        assert_eq!(None, Destination::parse("7"));
+        assert_eq!(None, Destination::parse(""));
+    }
+
+    #[test]
+    fn destination_menu_lists_every_format() {
+        assert_eq!("\
+What would you like to do with this query?
+1. 'csv' - Query the dataset and output the results to CSV.
+2. 'json' - Query the dataset and output the results to JSON.
+3. 'show' - Query the dataset and show the results here.
+4. 'table <name>' - Save the query results in a new table, for further queries.
+5. 'expanded' - Query the dataset and show each row of the results as a block of lines.
+6. 'remote [url] <table>' - Copy the query results to a table of another database.", Destination::menu());
+    }
+
+    #[async_std::test]
+    async fn count_bytes_written() {
+        let progress = Arc::new(ExportProgress::default());
+        let output = RowBuffer::new(CountingWriter::new(Vec::new(), progress.clone()), false);
+        let mut writer = ResultWriter::new(OutputFormat::Json, output);
+        writer.write_header(vec![String::from("a")]).await.unwrap();
+        writer.write_row(&[text("1")], &FormatOptions::default()).await.unwrap();
+        writer.finish().await.unwrap();
+        assert_eq!("14 bytes in 0s", progress.totals());
+    }
+
+    #[async_std::test]
+    async fn only_whole_rows_are_written_once_cut_short() {
+        let mut buffer = RowBuffer::new(Vec::new(), false);
+        buffer.write_all(b"id,name\n1,").await.unwrap();
+        buffer.write_all(b"Ann\n").await.unwrap();
+        buffer.flush().await.unwrap();
+        buffer.write_all(b"2,B").await.unwrap();
+        assert_eq!(b"id,name\n1,Ann\n", buffer.abandon().await.unwrap().as_slice());
+
+        // Rows read as they come are written as soon as they end
+        let mut buffer = RowBuffer::new(Vec::new(), true);
+        buffer.write_all(b"1,Ann\n").await.unwrap();
+        buffer.flush().await.unwrap();
+        buffer.write_all(b"2,Bo\n").await.unwrap();
+        assert_eq!(b"1,Ann\n", buffer.writer.as_slice());
+        assert_eq!(b"1,Ann\n2,Bo\n", buffer.finish().await.unwrap().as_slice());
+    }
+
+    #[test]
+    fn expand_json_columns() {
+        let first = json(r#"{"id": 7, "tags": ["a", "b"], "nested": {"x": 1}}"#);
+        let second = json(r#"{"name": "bob", "late": true}"#);
+        let third = json("[1, 2]");
+        let rows = [
+            vec![text("1"), DecodedValue::Json(Cow::Borrowed(&first)), text("end")],
+            vec![text("2"), DecodedValue::Json(Cow::Borrowed(&second)), text("end")],
+            vec![text("3"), DecodedValue::Json(Cow::Borrowed(&third)), text("end")],
+            vec![text("4"), DecodedValue::Null, text("end")]
+        ];
+        // The sample excludes the late key
+        let sample_first = json(r#"{"id": 1, "tags": null, "nested": null}"#);
+        let sample_second = json(r#"{"name": null}"#);
+        let mut expansion = JsonExpansion::discover(1, vec![
+            DecodedValue::Json(Cow::Borrowed(&sample_first)), DecodedValue::Json(Cow::Borrowed(&sample_second))
+        ]);
+
+        let columns: Vec<String> = ["id", "payload", "last"].iter().map(|column| String::from(*column)).collect();
+        assert_eq!(vec!["id", "payload.id", "tags", "nested", "name", "last"], expansion.header(&columns));
+
+        let options = FormatOptions::default();
+        let expanded: Vec<Vec<String>> = rows.iter().map(|row| {
+            expansion.expand(row).iter().map(|value| value.to_text(&options).into_owned()).collect()
+        }).collect();
+        assert_eq!(vec![
+            vec!["1", "7", r#"["a","b"]"#, r#"{"x":1}"#, "", "end"],
+            vec!["2", "", "", "", "bob", "end"],
+            vec!["3", "", "", "", "", "end"],
+            vec!["4", "", "", "", "", "end"]
+        ], expanded);
+        assert_eq!(
+            Some(String::from("Keys of payload which first appeared after the first 2 rows were not expanded: late")),
+            expansion.warning("payload", 2)
+        );
+    }
+}
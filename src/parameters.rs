@@ -0,0 +1,125 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::str::FromStr;
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use sqlx::Postgres;
+use sqlx::postgres::PgArguments;
+use sqlx::query::Query;
+
+/// The types which may be named after a value, as in 42:int, and the types they are bound as
+const EXPLICIT_TYPES: [(&str, &str); 6] = [
+    ("int", "INT8"), ("float", "FLOAT8"), ("numeric", "NUMERIC"), ("bool", "BOOL"), ("date", "DATE"), ("text", "TEXT")
+];
+
+/// A value bound to a $n placeholder of a query
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Parameter {
+    SmallInt(i16),
+    Integer(i32),
+    BigInt(i64),
+    Real(f32),
+    Double(f64),
+    Numeric(Decimal),
+    Boolean(bool),
+    Date(NaiveDate),
+    Text(String)
+}
+
+impl Parameter {
+    /// Parses the value given for a placeholder. A type may follow the value, as in 42:int,
+    /// 2.5:float, true:bool or 2022-03-01:date. Otherwise, the value is parsed as the type
+    /// Postgres inferred for the placeholder, and bound as text if that type is unknown or has
+    /// no binding of its own, leaving Postgres to cast it.
+    pub(crate) fn parse(value: &str, inferred_type: Option<&str>) -> Result<Self, String> {
+        let explicit = value.rsplit_once(':').and_then(|(value, type_name)| {
+            EXPLICIT_TYPES.iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(type_name.trim()))
+                .map(|(_, bound_type)| (value, *bound_type))
+        });
+        let (value, bound_type) = match explicit {
+            Some(explicit) => explicit,
+            None => (value, inferred_type.unwrap_or("TEXT"))
+        };
+        let invalid = |description: &str| format!("{:?} is not {}", value, description);
+        let trimmed = value.trim();
+        Ok(match bound_type.to_ascii_uppercase().as_str() {
+            "INT2" => Self::SmallInt(trimmed.parse().map_err(|_| invalid("a small integer"))?),
+            "INT4" => Self::Integer(trimmed.parse().map_err(|_| invalid("an integer"))?),
+            "INT8" => Self::BigInt(trimmed.parse().map_err(|_| invalid("an integer"))?),
+            "FLOAT4" => Self::Real(trimmed.parse().map_err(|_| invalid("a number"))?),
+            "FLOAT8" => Self::Double(trimmed.parse().map_err(|_| invalid("a number"))?),
+            "NUMERIC" => Self::Numeric(Decimal::from_str(trimmed).map_err(|_| invalid("a number"))?),
+            "BOOL" => Self::Boolean(match trimmed.to_ascii_lowercase().as_str() {
+                "true" | "t" | "yes" | "y" | "on" | "1" => true,
+                "false" | "f" | "no" | "n" | "off" | "0" => false,
+                _ => return Err(invalid("true or false"))
+            }),
+            "DATE" => Self::Date(NaiveDate::parse_from_str(trimmed, "%Y-%m-%d")
+                .map_err(|_| invalid("a date such as 2022-03-01"))?),
+            _ => Self::Text(value.to_owned())
+        })
+    }
+}
+
+/// Binds the parameters to the query, in the order of their placeholders
+pub(crate) fn bind<'q>(mut query: Query<'q, Postgres, PgArguments>,
+                       parameters: &[Parameter]) -> Query<'q, Postgres, PgArguments> {
+    for parameter in parameters {
+        query = match parameter.clone() {
+            Parameter::SmallInt(value) => query.bind(value),
+            Parameter::Integer(value) => query.bind(value),
+            Parameter::BigInt(value) => query.bind(value),
+            Parameter::Real(value) => query.bind(value),
+            Parameter::Double(value) => query.bind(value),
+            Parameter::Numeric(value) => query.bind(value),
+            Parameter::Boolean(value) => query.bind(value),
+            Parameter::Date(value) => query.bind(value),
+            Parameter::Text(value) => query.bind(value)
+        };
+    }
+    query
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_as_the_inferred_type() {
+        assert_eq!(Ok(Parameter::Integer(42)), Parameter::parse(" 42", Some("INT4")));
+        assert_eq!(Ok(Parameter::Numeric(Decimal::new(1005, 1))), Parameter::parse("100.5", Some("NUMERIC")));
+        assert_eq!(Ok(Parameter::Boolean(false)), Parameter::parse("No", Some("BOOL")));
+        assert_eq!(Ok(Parameter::Text(String::from(" North "))), Parameter::parse(" North ", Some("TEXT")));
+        // Without a binding of its own, the value is left for Postgres to cast
+        assert_eq!(Ok(Parameter::Text(String::from("10:30"))), Parameter::parse("10:30", Some("TIME")));
+        assert_eq!(Ok(Parameter::Text(String::from("5"))), Parameter::parse("5", None));
+        assert!(Parameter::parse("five", Some("INT8")).is_err());
+    }
+
+    #[test]
+    fn parse_explicit_types() {
+        assert_eq!(Ok(Parameter::BigInt(42)), Parameter::parse("42:int", Some("TEXT")));
+        assert_eq!(Ok(Parameter::Double(2.5)), Parameter::parse("2.5:FLOAT", None));
+        assert_eq!(Ok(Parameter::Boolean(true)), Parameter::parse("true:bool", None));
+        assert_eq!(Ok(Parameter::Date(NaiveDate::from_ymd_opt(2022, 3, 1).unwrap())), Parameter::parse("2022-03-01:date", None));
+        assert_eq!(Ok(Parameter::Text(String::from("42"))), Parameter::parse("42:text", Some("INT4")));
+        assert_eq!(Err(String::from("\"March\" is not a date such as 2022-03-01")),
+                   Parameter::parse("March:date", None));
+    }
+}
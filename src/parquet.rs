@@ -0,0 +1,612 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::fs::File;
+use arrow::array::{Array, ArrayRef, AsArray, UInt32Array};
+use arrow::compute::{self, CastOptions};
+use arrow::datatypes::{
+    DataType, Date32Type, Float32Type, Float64Type, Int16Type, Int32Type, Int64Type, Time64MicrosecondType,
+    TimeUnit, TimestampMicrosecondType
+};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use arrow::row::{RowConverter, SortField};
+use arrow::util::display::{ArrayFormatter, FormatOptions};
+use async_std::path::{Path, PathBuf};
+use async_std::task;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use eyre::eyre;
+use parquet::arrow::arrow_reader::{
+    ArrowReaderMetadata, ArrowReaderOptions, ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder
+};
+use parquet::basic::{ConvertedType, LogicalType};
+use parquet::schema::types::Type;
+use sqlx::Arguments;
+use sqlx::postgres::PgArguments;
+use crate::error::{Error, Result};
+
+/// Whether a dataset given by the user is a Parquet file
+pub(crate) fn is_parquet(input: &str) -> bool {
+    input.trim().to_ascii_lowercase().ends_with(".parquet")
+}
+
+/// The schema and row groups of a Parquet file, read from its footer. Each top-level column of
+/// the file is a column of the table: a column of groups or lists is imported as JSON
+#[derive(Debug)]
+pub(crate) struct ParquetFile {
+    path: PathBuf,
+    metadata: ArrowReaderMetadata,
+    columns: Vec<ParquetColumn>
+}
+
+#[derive(Debug)]
+struct ParquetColumn {
+    name: String,
+    kind: ColumnKind
+}
+
+/// What a column's values are, and so how they are bound for Postgres
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ColumnKind {
+    Boolean,
+    SmallInt,
+    Integer,
+    BigInt,
+    /// Decimals, and unsigned integers too large for bigint
+    Numeric { precision: u8, scale: u8 },
+    Real,
+    Double,
+    Date,
+    Time,
+    Timestamp,
+    TimestampTz,
+    Text,
+    Json,
+    Uuid,
+    Bytes,
+    /// Groups, lists and maps, written as JSON
+    Nested
+}
+
+/// The values of one column of a batch, bound to the INSERT statement as a single array
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ColumnValues {
+    Boolean(Vec<Option<bool>>),
+    SmallInt(Vec<Option<i16>>),
+    Integer(Vec<Option<i32>>),
+    BigInt(Vec<Option<i64>>),
+    Real(Vec<Option<f32>>),
+    Double(Vec<Option<f64>>),
+    Date(Vec<Option<NaiveDate>>),
+    Time(Vec<Option<NaiveTime>>),
+    Timestamp(Vec<Option<NaiveDateTime>>),
+    TimestampTz(Vec<Option<DateTime<Utc>>>),
+    Bytes(Vec<Option<Vec<u8>>>),
+    /// Text which Postgres casts to the column's type, for decimals, UUIDs and JSON
+    Text(Vec<Option<String>>)
+}
+
+impl ParquetFile {
+
+    /// Reads the footer of the file, failing if the file holds anything which cannot be imported
+    pub(crate) async fn open(path: &Path) -> Result<Self> {
+        let file = open_file(path).await?;
+        let metadata = task::spawn_blocking(move || ArrowReaderMetadata::load(&file, ArrowReaderOptions::new())).await
+            .map_err(|error| invalid(path, &error.to_string()))?;
+        let fields = metadata.parquet_schema().root_schema().get_fields();
+        let columns = metadata.schema().fields().iter().zip(fields)
+            .map(|(field, parquet_field)| {
+                let kind = ColumnKind::of(field.data_type(), parquet_field)
+                    .map_err(|reason| invalid(path, &format!("column {} {}", field.name(), reason)))?;
+                Ok(ParquetColumn { name: field.name().clone(), kind })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if columns.is_empty() {
+            return Err(invalid(path, "it has no columns"));
+        }
+        Ok(Self { path: path.to_path_buf(), metadata, columns })
+    }
+
+    pub(crate) fn column_names(&self) -> Vec<String> {
+        self.columns.iter().map(|column| column.name.clone()).collect()
+    }
+
+    /// The types of the table's columns, which are always given
+    pub(crate) fn column_types(&self) -> Vec<Option<String>> {
+        self.columns.iter().map(|column| Some(column.kind.column_type())).collect()
+    }
+
+    /// The types of the arrays bound for each column, with any cast to the column's type
+    pub(crate) fn array_types(&self) -> Vec<String> {
+        self.columns.iter().map(|column| column.kind.array_type()).collect()
+    }
+
+    /// The columns of groups or lists, which are imported as JSON
+    pub(crate) fn nested_columns(&self) -> Vec<&str> {
+        self.columns.iter()
+            .filter(|column| column.kind == ColumnKind::Nested)
+            .map(|column| column.name.as_str())
+            .collect()
+    }
+
+    pub(crate) fn rows(&self) -> u64 {
+        self.metadata.metadata().row_groups().iter().map(|row_group| row_group.num_rows().max(0) as u64).sum()
+    }
+
+    pub(crate) fn row_groups(&self) -> usize {
+        self.metadata.metadata().num_row_groups()
+    }
+
+    /// The compressed bytes of the row group
+    pub(crate) fn row_group_bytes(&self, row_group: usize) -> u64 {
+        self.metadata.metadata().row_group(row_group).compressed_size().max(0) as u64
+    }
+
+    /// The bytes of the row groups, which are read one row group at a time
+    pub(crate) fn data_bytes(&self) -> u64 {
+        (0..self.row_groups()).map(|row_group| self.row_group_bytes(row_group)).sum()
+    }
+
+    /// Reads the rows of the row group, in batches of up to the given number of rows
+    pub(crate) fn read_row_group(&self, row_group: usize, batch_rows: usize) -> RowGroupBatches {
+        RowGroupBatches {
+            path: self.path.clone(), metadata: self.metadata.clone(), row_group, batch_rows, reader: None, done: false
+        }
+    }
+
+    /// The values of each column of the batch, ready to bind
+    pub(crate) fn values(&self, batch: &RecordBatch) -> Result<Vec<ColumnValues>> {
+        self.columns.iter().zip(batch.columns())
+            .map(|(column, array)| {
+                column.kind.values(array)
+                    .map_err(|error| invalid(&self.path, &format!("column {} {}", column.name, error)))
+            })
+            .collect()
+    }
+}
+
+async fn open_file(path: &Path) -> Result<File> {
+    let std_path = std::path::PathBuf::from(path);
+    task::spawn_blocking(move || File::open(std_path)).await
+        .map_err(|error| Error::ImportIo(eyre!("Unable to read Parquet file {}: {}", path.display(), error)))
+}
+
+fn invalid(path: &Path, reason: &str) -> Error {
+    Error::ImportData(eyre!("Unable to read Parquet file {}: {}", path.display(), reason))
+}
+
+/// The batches of rows of a row group, decoded by a blocking task as each is asked for
+pub(crate) struct RowGroupBatches {
+    path: PathBuf,
+    metadata: ArrowReaderMetadata,
+    row_group: usize,
+    batch_rows: usize,
+    /// The reader, once it has started, which is handed to the task reading each batch
+    reader: Option<ParquetRecordBatchReader>,
+    done: bool
+}
+
+impl RowGroupBatches {
+
+    /// The next batch of rows, none once the row group has been read
+    pub(crate) async fn next_batch(&mut self) -> Result<Option<RecordBatch>> {
+        if self.done {
+            return Ok(None);
+        }
+        let reader = match self.reader.take() {
+            Some(reader) => reader,
+            None => {
+                let file = open_file(&self.path).await?;
+                ParquetRecordBatchReaderBuilder::new_with_metadata(file, self.metadata.clone())
+                    .with_row_groups(vec![self.row_group])
+                    .with_batch_size(self.batch_rows)
+                    .build()
+                    .map_err(|error| invalid(&self.path, &error.to_string()))?
+            }
+        };
+        let (reader, batch) = task::spawn_blocking(move || {
+            let mut reader = reader;
+            let batch = reader.next().transpose();
+            (reader, batch)
+        }).await;
+        let batch = batch.map_err(|error| invalid(&self.path, &error.to_string()))?;
+        match batch {
+            Some(_) => self.reader = Some(reader),
+            None => self.done = true
+        }
+        Ok(batch)
+    }
+}
+
+impl ColumnKind {
+
+    /// The kind of a top-level column, from its type as Arrow reads it and its annotation in
+    /// the file, which tells JSON and UUIDs apart from other text and bytes
+    fn of(data_type: &DataType, field: &Type) -> std::result::Result<Self, String> {
+        let info = field.get_basic_info();
+        let logical = info.logical_type_ref();
+        Ok(match data_type {
+            DataType::Boolean => Self::Boolean,
+            DataType::Int8 | DataType::Int16 | DataType::UInt8 => Self::SmallInt,
+            DataType::Int32 | DataType::UInt16 => Self::Integer,
+            DataType::Int64 | DataType::UInt32 => Self::BigInt,
+            DataType::UInt64 => Self::Numeric { precision: 20, scale: 0 },
+            DataType::Decimal32(precision, scale) | DataType::Decimal64(precision, scale)
+            | DataType::Decimal128(precision, scale) | DataType::Decimal256(precision, scale) => {
+                let scale = (*scale).max(0) as u8;
+                Self::Numeric { precision: (*precision).max(scale).max(1), scale }
+            },
+            DataType::Float16 | DataType::Float32 => Self::Real,
+            DataType::Float64 => Self::Double,
+            DataType::Date32 | DataType::Date64 => Self::Date,
+            DataType::Time32(_) | DataType::Time64(_) => Self::Time,
+            DataType::Timestamp(_, None) => Self::Timestamp,
+            DataType::Timestamp(_, Some(_)) => Self::TimestampTz,
+            DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View => {
+                match matches!(logical, Some(LogicalType::Json)) || info.converted_type() == ConvertedType::JSON {
+                    true => Self::Json,
+                    false => Self::Text
+                }
+            },
+            DataType::FixedSizeBinary(16) if matches!(logical, Some(LogicalType::Uuid)) => Self::Uuid,
+            DataType::Binary | DataType::LargeBinary | DataType::BinaryView | DataType::FixedSizeBinary(_) => {
+                Self::Bytes
+            },
+            // Arrow reads only the days and milliseconds of Parquet intervals, leaving out their months
+            DataType::Interval(_) => return Err(String::from("holds intervals, which cannot be read in full")),
+            DataType::List(_) | DataType::LargeList(_) | DataType::FixedSizeList(..) | DataType::ListView(_)
+            | DataType::LargeListView(_) | DataType::Struct(_) | DataType::Map(..) => Self::Nested,
+            DataType::Dictionary(_, values) => Self::of(values, field)?,
+            _ => Self::Text
+        })
+    }
+
+    /// The type of the table's column holding these values
+    fn column_type(self) -> String {
+        String::from(match self {
+            Self::Boolean => "boolean",
+            Self::SmallInt => "smallint",
+            Self::Integer => "integer",
+            Self::BigInt => "bigint",
+            Self::Numeric { precision, scale } => return format!("numeric({},{})", precision, scale),
+            Self::Real => "real",
+            Self::Double => "double precision",
+            Self::Date => "date",
+            Self::Time => "time",
+            Self::Timestamp => "timestamp",
+            Self::TimestampTz => "timestamptz",
+            Self::Text => "text",
+            Self::Json | Self::Nested => "jsonb",
+            Self::Uuid => "uuid",
+            Self::Bytes => "bytea"
+        })
+    }
+
+    /// The type of the array bound for the column, which text is cast from
+    fn array_type(self) -> String {
+        match self {
+            Self::Numeric { .. } | Self::Json | Self::Uuid | Self::Nested => {
+                format!("text[]::{}[]", self.column_type())
+            },
+            _ => format!("{}[]", self.column_type())
+        }
+    }
+
+    fn values(self, array: &ArrayRef) -> std::result::Result<ColumnValues, ArrowError> {
+        Ok(match self {
+            Self::Boolean => ColumnValues::Boolean(cast(array, DataType::Boolean)?.as_boolean().iter().collect()),
+            Self::SmallInt => ColumnValues::SmallInt(
+                cast(array, DataType::Int16)?.as_primitive::<Int16Type>().iter().collect()
+            ),
+            Self::Integer => ColumnValues::Integer(
+                cast(array, DataType::Int32)?.as_primitive::<Int32Type>().iter().collect()
+            ),
+            Self::BigInt => ColumnValues::BigInt(
+                cast(array, DataType::Int64)?.as_primitive::<Int64Type>().iter().collect()
+            ),
+            Self::Real => ColumnValues::Real(
+                cast(array, DataType::Float32)?.as_primitive::<Float32Type>().iter().collect()
+            ),
+            Self::Double => ColumnValues::Double(
+                cast(array, DataType::Float64)?.as_primitive::<Float64Type>().iter().collect()
+            ),
+            Self::Date => {
+                let days = cast(array, DataType::Date32)?;
+                let days = days.as_primitive::<Date32Type>();
+                ColumnValues::Date(in_range(days, |row| days.value_as_date(row))?)
+            },
+            Self::Time => {
+                let times = cast(array, DataType::Time64(TimeUnit::Microsecond))?;
+                let times = times.as_primitive::<Time64MicrosecondType>();
+                ColumnValues::Time(in_range(times, |row| times.value_as_time(row))?)
+            },
+            // Postgres keeps microseconds, and timestamps adjusted to UTC are read in UTC
+            Self::Timestamp | Self::TimestampTz => {
+                let zone = match array.data_type() {
+                    DataType::Timestamp(_, zone) => zone.clone(),
+                    _ => None
+                };
+                let timestamps = cast(array, DataType::Timestamp(TimeUnit::Microsecond, zone))?;
+                let timestamps = timestamps.as_primitive::<TimestampMicrosecondType>();
+                let values = in_range(timestamps, |row| timestamps.value_as_datetime(row))?;
+                match self {
+                    Self::Timestamp => ColumnValues::Timestamp(values),
+                    _ => ColumnValues::TimestampTz(values.into_iter().map(|value| value.map(|value| value.and_utc()))
+                        .collect())
+                }
+            },
+            Self::Numeric { .. } | Self::Text | Self::Json => ColumnValues::Text(
+                cast(array, DataType::Utf8)?.as_string::<i32>().iter().map(|value| value.map(String::from)).collect()
+            ),
+            Self::Uuid => ColumnValues::Text(
+                array.as_fixed_size_binary().iter().map(|value| value.map(uuid)).collect()
+            ),
+            Self::Bytes => ColumnValues::Bytes(
+                cast(array, DataType::Binary)?.as_binary::<i32>().iter()
+                    .map(|value| value.map(<[u8]>::to_vec))
+                    .collect()
+            ),
+            Self::Nested => {
+                let json = json_values(array.as_ref())?;
+                ColumnValues::Text(json.into_iter().enumerate()
+                    .map(|(row, value)| array.is_valid(row).then_some(value))
+                    .collect())
+            }
+        })
+    }
+}
+
+/// The rows of the batch which one INSERT statement writes, when it updates rows already in the
+/// table. A statement cannot update a row twice, so only the last row of each key is written
+pub(crate) fn last_of_each_key(batch: &RecordBatch, key_fields: &[usize]) -> Result<RecordBatch> {
+    let failed = |error: ArrowError| Error::ImportData(eyre!("Unable to compare the keys of the rows: {}", error));
+    let keys = key_fields.iter().map(|field| batch.column(*field).clone()).collect::<Vec<_>>();
+    let converter = RowConverter::new(keys.iter().map(|key| SortField::new(key.data_type().clone())).collect())
+        .map_err(failed)?;
+    let rows = converter.convert_columns(&keys).map_err(failed)?;
+    let mut seen = HashSet::new();
+    let mut written = (0..rows.num_rows()).rev()
+        .filter(|row| seen.insert(rows.row(*row)))
+        .map(|row| row as u32)
+        .collect::<Vec<_>>();
+    written.reverse();
+    compute::take_record_batch(batch, &UInt32Array::from(written)).map_err(failed)
+}
+
+/// Casts the array, failing rather than making null any value which the type cannot hold
+fn cast(array: &ArrayRef, data_type: DataType) -> std::result::Result<ArrayRef, ArrowError> {
+    compute::cast_with_options(array, &data_type, &CastOptions { safe: false, ..CastOptions::default() })
+}
+
+/// The values of the array as chrono reads them, failing for a value out of chrono's range
+fn in_range<T>(array: &dyn Array,
+               value: impl Fn(usize) -> Option<T>) -> std::result::Result<Vec<Option<T>>, ArrowError> {
+    (0..array.len())
+        .map(|row| match array.is_valid(row) {
+            true => value(row).map(Some).ok_or_else(|| {
+                ArrowError::ComputeError(String::from("has a value out of the range of its type"))
+            }),
+            false => Ok(None)
+        })
+        .collect()
+}
+
+fn uuid(bytes: &[u8]) -> String {
+    let hex = bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    });
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Writes each value of the array as JSON, null where the value is. Lists and maps become JSON
+/// arrays and objects, and groups objects keyed by the names of their fields
+fn json_values(array: &dyn Array) -> std::result::Result<Vec<String>, ArrowError> {
+    let null = |row| array.is_null(row);
+    let values = match array.data_type() {
+        DataType::Null => vec![String::from("null"); array.len()],
+        DataType::Boolean => array.as_boolean().iter()
+            .map(|value| value.map_or_else(|| String::from("null"), |value| value.to_string()))
+            .collect(),
+        DataType::Int8 | DataType::Int16 | DataType::Int32 | DataType::Int64 | DataType::UInt8
+        | DataType::UInt16 | DataType::UInt32 | DataType::UInt64 | DataType::Decimal32(..) | DataType::Decimal64(..)
+        | DataType::Decimal128(..) | DataType::Decimal256(..) => {
+            cast(&arrow::array::make_array(array.to_data()), DataType::Utf8)?.as_string::<i32>().iter()
+                .map(|value| value.map_or_else(|| String::from("null"), String::from))
+                .collect()
+        },
+        // JSON has no infinities, nor NaN
+        DataType::Float16 | DataType::Float32 | DataType::Float64 => {
+            cast(&arrow::array::make_array(array.to_data()), DataType::Float64)?.as_primitive::<Float64Type>().iter()
+                .map(|value| match value.and_then(serde_json::Number::from_f64) {
+                    Some(number) => number.to_string(),
+                    None => String::from("null")
+                })
+                .collect()
+        },
+        DataType::List(_) | DataType::LargeList(_) | DataType::FixedSizeList(..) => {
+            let elements = |range: std::ops::Range<usize>, values: &[String]| {
+                format!("[{}]", values[range].join(","))
+            };
+            match array.data_type() {
+                DataType::List(_) => {
+                    let list = array.as_list::<i32>();
+                    let values = json_values(list.values().as_ref())?;
+                    list.offsets().windows(2)
+                        .map(|offsets| elements(offsets[0] as usize..offsets[1] as usize, &values))
+                        .collect()
+                },
+                DataType::LargeList(_) => {
+                    let list = array.as_list::<i64>();
+                    let values = json_values(list.values().as_ref())?;
+                    list.offsets().windows(2)
+                        .map(|offsets| elements(offsets[0] as usize..offsets[1] as usize, &values))
+                        .collect()
+                },
+                _ => {
+                    let list = array.as_fixed_size_list();
+                    let values = json_values(list.values().as_ref())?;
+                    let size = list.value_length() as usize;
+                    (0..list.len()).map(|row| elements(row * size..(row + 1) * size, &values)).collect()
+                }
+            }
+        },
+        DataType::Struct(fields) => {
+            let group = array.as_struct();
+            let children = group.columns().iter()
+                .map(|child| json_values(child.as_ref()))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            (0..group.len())
+                .map(|row| {
+                    let members = fields.iter().zip(&children)
+                        .map(|(field, values)| format!("{}:{}", json_string(field.name()), values[row]));
+                    format!("{{{}}}", members.collect::<Vec<_>>().join(","))
+                })
+                .collect()
+        },
+        // Keys are written as JSON strings, as object keys must be
+        DataType::Map(..) => {
+            let map = array.as_map();
+            let keys = json_values(map.keys().as_ref())?;
+            let values = json_values(map.values().as_ref())?;
+            map.offsets().windows(2)
+                .map(|offsets| {
+                    let members = (offsets[0] as usize..offsets[1] as usize).map(|entry| {
+                        let key = match keys[entry].starts_with('"') {
+                            true => keys[entry].clone(),
+                            false => json_string(&keys[entry])
+                        };
+                        format!("{}:{}", key, values[entry])
+                    });
+                    format!("{{{}}}", members.collect::<Vec<_>>().join(","))
+                })
+                .collect()
+        },
+        // Anything else, such as text, dates and bytes, is written as a string
+        _ => {
+            let formatter = ArrayFormatter::try_new(array, &FormatOptions::default())?;
+            (0..array.len()).map(|row| json_string(&formatter.value(row).to_string())).collect()
+        }
+    };
+    Ok(values.into_iter().enumerate()
+        .map(|(row, value)| if null(row) { String::from("null") } else { value })
+        .collect())
+}
+
+fn json_string(text: &str) -> String {
+    serde_json::Value::from(text).to_string()
+}
+
+impl ColumnValues {
+
+    /// Binds the values as the next parameter
+    pub(crate) fn add_to(self, arguments: &mut PgArguments) {
+        match self {
+            Self::Boolean(values) => arguments.add(values),
+            Self::SmallInt(values) => arguments.add(values),
+            Self::Integer(values) => arguments.add(values),
+            Self::BigInt(values) => arguments.add(values),
+            Self::Real(values) => arguments.add(values),
+            Self::Double(values) => arguments.add(values),
+            Self::Date(values) => arguments.add(values),
+            Self::Time(values) => arguments.add(values),
+            Self::Timestamp(values) => arguments.add(values),
+            Self::TimestampTz(values) => arguments.add(values),
+            Self::Bytes(values) => arguments.add(values),
+            Self::Text(values) => arguments.add(values)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn read_fixture() -> Result<()> {
+        let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/events.parquet"));
+        let file = ParquetFile::open(path).await?;
+        assert_eq!(
+            vec!["id", "name", "amount", "happened_at", "day", "active", "tags", "location", "price"],
+            file.column_names()
+        );
+        assert_eq!(vec!["tags", "location"], file.nested_columns());
+        assert_eq!((4, 2), (file.rows(), file.row_groups()));
+        let mut values = Vec::new();
+        for row_group in 0..file.row_groups() {
+            let mut batches = file.read_row_group(row_group, 1000);
+            while let Some(batch) = batches.next_batch().await? {
+                values.push(file.values(&batch)?);
+            }
+        }
+        assert_eq!(2, values.len());
+        let column = |index: usize| values.iter().map(|batch| batch[index].clone()).collect::<Vec<_>>();
+        let text = |values: &[Option<&str>]| values.iter().map(|value| value.map(String::from)).collect();
+        assert_eq!(
+            vec![ColumnValues::Text(text(&[Some("[\"a\",\"b\"]"), Some("[]")])),
+                 ColumnValues::Text(text(&[Some("[null,\"c\"]"), None]))],
+            column(6)
+        );
+        assert_eq!(
+            vec![ColumnValues::Text(text(&[Some("{\"city\":\"Oslo\",\"zip\":1}"), Some("{\"city\":null,\"zip\":2}")])),
+                 ColumnValues::Text(text(&[None, Some("{\"city\":\"Rome\",\"zip\":null}")]))],
+            column(7)
+        );
+        assert_eq!(
+            vec![ColumnValues::Text(text(&[Some("1.25"), Some("-3.00")])),
+                 ColumnValues::Text(text(&[None, Some("0.00")]))],
+            column(8)
+        );
+        let happened_at = DateTime::from_timestamp(1_646_137_800, 0).unwrap();
+        match &column(3)[0] {
+            ColumnValues::TimestampTz(values) => assert_eq!(Some(happened_at), values[0]),
+            values => panic!("{:?}", values)
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bind_types_as_postgres_names_them() {
+        let timestamp = Type::primitive_type_builder("ts", parquet::basic::Type::INT64).build().unwrap();
+        let kind = |data_type| ColumnKind::of(&data_type, &timestamp).unwrap();
+        assert_eq!("numeric(20,0)", kind(DataType::UInt64).column_type());
+        assert_eq!("text[]::numeric(9,3)[]", kind(DataType::Decimal128(9, 3)).array_type());
+        assert_eq!("real[]", kind(DataType::Float16).array_type());
+        let utc = DataType::Timestamp(TimeUnit::Millisecond, Some("+00:00".into()));
+        assert_eq!("timestamptz", kind(utc).column_type());
+        let dictionary = DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8));
+        assert_eq!("text", kind(dictionary).column_type());
+        assert!(ColumnKind::of(&DataType::Interval(arrow::datatypes::IntervalUnit::DayTime), &timestamp).is_err());
+    }
+
+    #[test]
+    fn write_nested_values_as_json() -> std::result::Result<(), ArrowError> {
+        use arrow::array::{ListArray, StringArray};
+        let list = ListArray::from_iter_primitive::<Float64Type, _, _>(vec![
+            Some(vec![Some(1.5), None, Some(f64::INFINITY)]), None, Some(vec![])
+        ]);
+        assert_eq!(vec!["[1.5,null,null]", "null", "[]"], json_values(&list)?);
+        let text = StringArray::from(vec![Some("say \"hi\""), None]);
+        assert_eq!(vec!["\"say \\\"hi\\\"\"", "null"], json_values(&text)?);
+        assert_eq!("00112233-4455-6677-8899-aabbccddeeff", uuid(&[
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff
+        ]));
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::str::FromStr;
+use eyre::{eyre, Result};
+use sqlx::{Postgres, pool::PoolConnection};
+use crate::database::{
+    column_argument, column_identifier, find_column, quote_identifier, quote_literal, split_arguments
+};
+use crate::error::{self, Error};
+
+/// The most distinct values which can become pivot columns
+pub(crate) const MAX_PIVOT_COLUMNS: usize = 200;
+
+/// How the values in each cell of a pivot table are combined
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Aggregate {
+    Sum,
+    Count,
+    Avg,
+    Min,
+    Max
+}
+
+impl FromStr for Aggregate {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Ok(match value.trim().to_ascii_lowercase().as_str() {
+            "sum" => Self::Sum,
+            "count" => Self::Count,
+            "avg" => Self::Avg,
+            "min" => Self::Min,
+            "max" => Self::Max,
+            _ => return Err(eyre!("Invalid aggregate {}. Valid aggregates are: sum, count, avg, min, max", value))
+        })
+    }
+}
+
+impl Aggregate {
+    /// Applies the aggregate to a column. Values are treated as numbers, since imported
+    /// columns are text
+    fn apply(self, column: &str) -> String {
+        match self {
+            Self::Sum => format!("sum({}::numeric)", column),
+            Self::Count => format!("count({})", column),
+            Self::Avg => format!("avg({}::numeric)", column),
+            Self::Min => format!("min({}::numeric)", column),
+            Self::Max => format!("max({}::numeric)", column)
+        }
+    }
+}
+
+/// Turns long data into wide data: one row per row key, one column per distinct value of
+/// the pivot column, with the aggregated values in the cells
+#[derive(Clone, Debug)]
+pub(crate) struct Pivot {
+    pub(crate) table: String,
+    pub(crate) row_keys: Vec<String>,
+    pub(crate) column: String,
+    pub(crate) value: String,
+    pub(crate) aggregate: Aggregate
+}
+
+impl Pivot {
+
+    /// Reads the column names, given the row keys separated by commas. Any of them may be named
+    /// in double quotes
+    pub(crate) fn new(table: String, row_keys: &str, column: &str,
+                      value: &str, aggregate: Aggregate) -> Result<Self> {
+        let row_keys = split_arguments(row_keys, &[','])?.iter()
+            .map(|key| column_argument(key))
+            .collect::<Result<Vec<_>>>()?;
+        if row_keys.is_empty() {
+            return Err(eyre!("No columns identify the rows"));
+        }
+        Ok(Self { table, row_keys, column: column_argument(column)?, value: column_argument(value)?, aggregate })
+    }
+
+    /// Finds the columns in the table, taking their names from there, then the distinct values of
+    /// the pivot column, which become the output columns
+    pub(crate) async fn distinct_values(&mut self, connection: &mut PoolConnection<Postgres>)
+            -> error::Result<Vec<Option<String>>> {
+        let existing: Vec<String> = sqlx::query_scalar("\
+            SELECT attname::text FROM pg_catalog.pg_attribute \
+            WHERE attrelid = to_regclass($1) AND attnum > 0 AND NOT attisdropped \
+            ORDER BY attnum")
+            .bind(&self.table)
+            .fetch_all(&mut *connection).await
+            .map_err(|error| Error::from_database(error, Error::QueryRuntime))?;
+        let find = |name: &str| find_column(&self.table, name, &existing, String::as_str).cloned();
+        self.row_keys = self.row_keys.iter().map(|key| find(key)).collect::<error::Result<_>>()?;
+        self.column = find(&self.column)?;
+        self.value = find(&self.value)?;
+        let values: Vec<(Option<String>,)> = sqlx::query_as(&format!(
+            "SELECT DISTINCT {}::text FROM {} ORDER BY 1 LIMIT {}",
+            column_identifier(&self.column), self.table, MAX_PIVOT_COLUMNS + 1
+        )).fetch_all(connection).await.map_err(Error::from_query)?;
+        if values.len() > MAX_PIVOT_COLUMNS {
+            return Err(Error::Usage(eyre!(
+                "Column {} has more than {} distinct values, too many to become columns. \
+                Choose a column with fewer values", self.column, MAX_PIVOT_COLUMNS
+            )));
+        }
+        Ok(values.into_iter().map(|(value,)| value).collect())
+    }
+
+    /// Generates the query, with a FILTER aggregate for each of the given pivot column values
+    pub(crate) fn to_sql(&self, values: &[Option<String>]) -> String {
+        let row_keys = self.row_keys.iter().map(|key| column_identifier(key)).collect::<Vec<_>>().join(", ");
+        let column = column_identifier(&self.column);
+        let aggregate = self.aggregate.apply(&column_identifier(&self.value));
+        let mut sql = format!("SELECT {}", row_keys);
+        for value in values {
+            let (condition, name) = match value {
+                Some(value) => (format!("{}::text = {}", column, quote_literal(value)), value.as_str()),
+                None => (format!("{} IS NULL", column), "NULL")
+            };
+            sql.push_str(&format!(",\n    {} FILTER (WHERE {}) AS {}", aggregate, condition, quote_identifier(name)));
+        }
+        sql.push_str(&format!("\nFROM {}\nGROUP BY {}\nORDER BY {}", self.table, row_keys, row_keys));
+        sql
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pivot_sql() {
+        let pivot = Pivot::new(String::from("sales"), "year, month", "region", "amount", Aggregate::Sum).unwrap();
+        let values = [Some(String::from("east")), Some(String::from("it's \"west\"")), None];
+        assert_eq!("\
+SELECT year, month,
+    sum(amount::numeric) FILTER (WHERE region::text = 'east') AS \"east\",
+    sum(amount::numeric) FILTER (WHERE region::text = 'it''s \"west\"') AS \"it's \"\"west\"\"\",
+    sum(amount::numeric) FILTER (WHERE region IS NULL) AS \"NULL\"
+FROM sales
+GROUP BY year, month
+ORDER BY year, month", pivot.to_sql(&values));
+
+        let pivot = Pivot::new(
+            String::from("sales"), "\"Sales Year\", Month", "\"Region\"", "Amount", Aggregate::Avg
+        ).unwrap();
+        assert_eq!("\
+SELECT \"Sales Year\", \"Month\",
+    avg(\"Amount\"::numeric) FILTER (WHERE \"Region\"::text = 'east') AS \"east\"
+FROM sales
+GROUP BY \"Sales Year\", \"Month\"
+ORDER BY \"Sales Year\", \"Month\"", pivot.to_sql(&values[..1]));
+    }
+
+    #[test]
+    fn pivot_validation() {
+        let pivot = |row_keys: &str, column: &str| Pivot::new(
+            String::from("data"), row_keys, column, "amount", Aggregate::Count
+        );
+        assert!(pivot("month", "region").is_ok());
+        assert!(pivot("month, \"region", "region").is_err());
+        assert!(pivot(" , ", "region").is_err());
+        assert!(pivot("month", "").is_err());
+        assert_eq!(Aggregate::Avg, "AVG".parse().unwrap());
+        assert!("median".parse::<Aggregate>().is_err());
+    }
+}
@@ -0,0 +1,404 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use async_std::fs::{self, File};
+use async_std::io::ReadExt;
+use csv_async::{ByteRecord, StringRecord};
+use eyre::WrapErr;
+use itertools::Itertools;
+use crate::charset;
+use crate::database::{Schema, TableMode};
+use crate::dialect::Dialect;
+use crate::error::{Categorize, Error, Result};
+use crate::fixedwidth::Units;
+use crate::import::{Checkpoint, CsvInput};
+use crate::profile::DatasetProfile;
+use crate::progress::format_bytes;
+
+/// How much of the file is read to summarize it
+const SNIFF_BYTES: u64 = 64 * 1024;
+/// How many column names are listed
+const NAMES_SHOWN: usize = 3;
+
+/// What an import is about to do, shown before anything is dropped or inserted
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ImportPlan {
+    pub(crate) source: String,
+    /// The size of the file, unless the dataset is downloaded. For another database, it is the
+    /// size the planner estimates
+    pub(crate) size: Option<u64>,
+    /// What the start of the file looks like, unless the dataset is downloaded
+    pub(crate) sniff: Option<Sniff>,
+    /// The columns of a fixed-width file, and what their starts and widths count
+    pub(crate) fixed_width: Option<(Vec<String>, Units)>,
+    /// The columns of a Parquet file, with how many rows it holds in how many row groups
+    pub(crate) parquet: Option<(Vec<String>, u64, usize)>,
+    /// The columns of a table or query of another database, with about how many rows it has
+    pub(crate) remote: Option<(Vec<String>, u64)>,
+    pub(crate) dialect: Dialect,
+    pub(crate) table: String,
+    pub(crate) mode: TableMode,
+    /// The line an interrupted import resumes from
+    pub(crate) resume_line: Option<u64>,
+    /// The transforms of each column which has them
+    pub(crate) transforms: Vec<String>,
+    pub(crate) filter: Option<String>,
+    /// Which of the rows are imported, if only a sample is
+    pub(crate) sampling: Option<String>,
+    /// The key of the table, and what is done with rows whose key is already in it
+    pub(crate) key: Option<String>,
+    /// How the columns are matched to those of the existing table the dataset is added to
+    pub(crate) columns: Option<String>,
+    /// How rows are sent to the database, and why, once chosen
+    pub(crate) strategy: Option<String>,
+    pub(crate) database: String
+}
+
+/// What was found at the start of the file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Sniff {
+    /// The encoding, such as UTF-8, or what is wrong with it
+    pub(crate) encoding: String,
+    pub(crate) header: Vec<String>,
+    /// How many rows follow the header in the part of the file read
+    pub(crate) rows: u64,
+    /// How many columns appear to hold each kind of value, such as 3 integer
+    pub(crate) kinds: Vec<(String, usize)>,
+    /// A delimiter other than a comma, if the header appears to use one
+    pub(crate) other_delimiter: Option<&'static str>,
+    /// Quoting other than RFC 4180's, if the records appear to use it while none was given
+    pub(crate) quoting: Option<Dialect>
+}
+
+/// Summarizes what importing the dataset into the table would do, reading no more than the
+/// start of the file, and without touching the database
+pub(crate) async fn plan_import(csv_input: &CsvInput, dialect: Dialect, table: &str, mode: TableMode,
+                                resume_from: Option<&Checkpoint>, database: String) -> Result<ImportPlan> {
+    let mut parquet = None;
+    let mut remote = None;
+    let (size, sniff, fixed_width) = match csv_input {
+        CsvInput::File(path) => {
+            let mut file = File::open(path).await
+                .wrap_err_with(|| format!("Unable to open CSV file {}", path.display()))
+                .categorize(Error::ImportIo)?;
+            let size = file.metadata().await.categorize(Error::ImportIo)?.len();
+            let mut start = Vec::new();
+            (&mut file).take(SNIFF_BYTES).read_to_end(&mut start).await.categorize(Error::ImportIo)?;
+            (Some(size), Some(sniff(&start, size <= SNIFF_BYTES, dialect).await), None)
+        },
+        CsvInput::FixedWidth { path, layout } => {
+            let size = fs::metadata(path).await
+                .wrap_err_with(|| format!("Unable to open fixed-width file {}", path.display()))
+                .categorize(Error::ImportIo)?.len();
+            (Some(size), None, Some((layout.column_names(), layout.units)))
+        },
+        CsvInput::ZipEntry { archive, entry } => {
+            let mut start = Vec::new();
+            entry.open(archive).await?.take(SNIFF_BYTES).read_to_end(&mut start).await.categorize(Error::ImportIo)?;
+            (Some(entry.size), Some(sniff(&start, entry.size <= SNIFF_BYTES, dialect).await), None)
+        },
+        CsvInput::Parquet { path, file } => {
+            let size = fs::metadata(path).await
+                .wrap_err_with(|| format!("Unable to open Parquet file {}", path.display()))
+                .categorize(Error::ImportIo)?.len();
+            parquet = Some((file.column_names(), file.rows(), file.row_groups()));
+            (Some(size), None, None)
+        },
+        CsvInput::Remote(dataset) => {
+            remote = Some((dataset.column_names(), dataset.estimated_rows()));
+            (Some(dataset.estimated_bytes()), None, None)
+        },
+        CsvInput::Url { .. } => (None, None, None)
+    };
+    Ok(ImportPlan {
+        source: csv_input.to_string(),
+        size,
+        sniff,
+        fixed_width,
+        parquet,
+        remote,
+        dialect,
+        table: String::from(table),
+        mode,
+        resume_line: resume_from.map(|checkpoint| checkpoint.position().line()),
+        transforms: Vec::new(),
+        filter: None,
+        sampling: None,
+        key: None,
+        columns: None,
+        strategy: None,
+        database
+    })
+}
+
+/// Reads the header and the rows which follow it, as far as the bytes go
+async fn sniff(start: &[u8], whole_file: bool, dialect: Dialect) -> Sniff {
+    let (text, encoding) = match start.strip_prefix(b"\xef\xbb\xbf") {
+        Some(text) => (text, "UTF-8 with a byte order mark"),
+        None => (start, "UTF-8")
+    };
+    // The last line read may be cut off
+    let lines = match text.iter().rposition(|byte| *byte == b'\n') {
+        Some(end) if !whole_file => &text[..=end],
+        _ => text
+    };
+    let mut reader = dialect.reader_builder().has_headers(false).flexible(true).create_reader(lines);
+    let mut record = ByteRecord::new();
+    let (header, transcoded) = match reader.read_byte_record(&mut record).await {
+        Ok(true) => charset::decode_header(&record).unwrap_or_else(|_| (lossy(&record), Vec::new())),
+        _ => (Vec::new(), Vec::new())
+    };
+    // A header read as Windows-1252 leaves the records to say whether they are UTF-8
+    let (records_start, encoding) = match transcoded.is_empty() {
+        true => (0, String::from(encoding)),
+        false => (reader.position().byte() as usize, format!("{}, with a Windows-1252 header", encoding))
+    };
+    let encoding = match std::str::from_utf8(&text[records_start..]) {
+        Ok(_) => encoding,
+        // A character may be cut off where the read stopped
+        Err(error) if error.error_len().is_none() => encoding,
+        Err(error) => format!(
+            "not UTF-8, with an invalid byte at offset {}",
+            start.len() - text.len() + records_start + error.valid_up_to()
+        )
+    };
+    let schema: Schema = header.iter().map(String::as_str).collect();
+    let mut profile = DatasetProfile::new(&schema);
+    while let Ok(true) = reader.read_byte_record(&mut record).await {
+        if record.len() == header.len() {
+            profile.record(&StringRecord::from(lossy(&record)));
+        }
+    }
+    let kinds = profile.columns.iter()
+        .map(|column| column.kind.map_or("empty", |kind| kind.name()))
+        .counts()
+        .into_iter()
+        .map(|(kind, count)| (String::from(kind), count))
+        .sorted_by(|(kind, count), (other_kind, other_count)| other_count.cmp(count).then(kind.cmp(other_kind)))
+        .collect();
+    let other_delimiter = match header.as_slice() {
+        [only] => [(';', "semicolons"), ('\t', "tabs"), ('|', "vertical bars")].into_iter()
+            .find(|(delimiter, _)| only.contains(*delimiter))
+            .map(|(_, name)| name),
+        _ => None
+    };
+    let quoting = sniff_quoting(lines, dialect).await;
+    Sniff { encoding, header, rows: profile.rows, kinds, other_delimiter, quoting }
+}
+
+/// Looks for quotes escaped with backslashes, as in "say \"hi\"", or fields quoted with single
+/// quotes, unless the quoting was given. Either is only taken if it finds as many records as
+/// RFC 4180's quoting does, each with as many fields as the header, and single quotes only
+/// where RFC 4180's quoting leaves some records with more or fewer fields
+async fn sniff_quoting(lines: &[u8], dialect: Dialect) -> Option<Dialect> {
+    if !dialect.is_rfc_4180() {
+        return None;
+    }
+    let (records, even) = split(lines, dialect).await;
+    let backslashed = dialect.with_quoting(b'"', Some(b'\\'), false);
+    if lines.windows(2).any(|pair| pair == b"\\\"") && split(lines, backslashed).await == (records, true) {
+        return Some(backslashed);
+    }
+    let single = dialect.with_quoting(b'\'', None, true);
+    if lines.contains(&b'\'') && !even && split(lines, single).await == (records, true) {
+        return Some(single);
+    }
+    None
+}
+
+/// How many records there are, and whether they all have as many fields as the first, of
+/// which there are several
+async fn split(lines: &[u8], dialect: Dialect) -> (usize, bool) {
+    let mut reader = dialect.reader_builder().has_headers(false).flexible(true).create_reader(lines);
+    let mut record = ByteRecord::new();
+    let mut fields = None;
+    let mut records = 0;
+    let mut even = true;
+    while let Ok(true) = reader.read_byte_record(&mut record).await {
+        even &= *fields.get_or_insert(record.len()) == record.len();
+        records += 1;
+    }
+    (records, even && records > 1 && fields.is_some_and(|fields| fields > 1))
+}
+
+/// The first few column names, followed by ... if there are more
+fn shown_names(names: &[String]) -> String {
+    let mut shown = names.iter().take(NAMES_SHOWN).join(", ");
+    if names.len() > NAMES_SHOWN {
+        shown.push_str(", ...");
+    }
+    shown
+}
+
+fn lossy(record: &ByteRecord) -> Vec<String> {
+    record.iter().map(|field| String::from_utf8_lossy(field).into_owned()).collect()
+}
+
+impl fmt::Display for ImportPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Import plan:")?;
+        match (self.size, &self.remote) {
+            (Some(size), Some(_)) => {
+                writeln!(f, "  Dataset: {} (about {}), copied as it is imported", self.source, format_bytes(size))?
+            },
+            (Some(size), None) => writeln!(f, "  Dataset: {} ({})", self.source, format_bytes(size))?,
+            (None, _) => writeln!(f, "  Dataset: {}, downloaded as it is imported", self.source)?
+        }
+        match (&self.sniff, &self.fixed_width, &self.parquet, &self.remote) {
+            (_, _, _, Some((columns, rows))) => {
+                writeln!(f, "  Format: another database, about {} rows", rows)?;
+                writeln!(f, "  Columns: {} ({})", columns.len(), shown_names(columns))?;
+            },
+            (_, _, Some((columns, rows, row_groups)), None) => {
+                writeln!(f, "  Format: Parquet, {} rows in {} row groups", rows, row_groups)?;
+                writeln!(f, "  Columns: {} ({})", columns.len(), shown_names(columns))?;
+            },
+            (_, Some((columns, units)), None, None) => {
+                writeln!(f, "  Format: fixed-width, with starts and widths in {}", units)?;
+                writeln!(f, "  Columns: {} ({})", columns.len(), shown_names(columns))?;
+            },
+            (Some(sniff), None, None, None) => {
+                writeln!(f, "  Format: {}, {}, {}", sniff.encoding, self.dialect.delimiter_name(), self.dialect)?;
+                if let Some(delimiter) = sniff.other_delimiter {
+                    writeln!(f, "  Warning: The header is one column holding {}, but fields are only split at commas",
+                             delimiter)?;
+                }
+                if let Some(quoting) = sniff.quoting {
+                    writeln!(f, "  Warning: The records appear to be {}. Pass {} to read them so",
+                             quoting, quoting.quoting_options())?;
+                }
+                writeln!(f, "  Columns: {} ({})", sniff.header.len(), shown_names(&sniff.header))?;
+                if sniff.rows > 0 {
+                    let kinds = sniff.kinds.iter().map(|(kind, count)| format!("{} {}", count, kind)).join(", ");
+                    writeln!(f, "  Types in the first {} rows: {}", sniff.rows, kinds)?;
+                }
+            },
+            (None, None, None, None) => {
+                writeln!(
+                    f, "  Format: {}, {}. Columns are read once the download starts",
+                    self.dialect.delimiter_name(), self.dialect
+                )?
+            }
+        }
+        let mode = match (self.mode, self.resume_line) {
+            (_, Some(line)) => format!("resuming an interrupted import at line {}", line),
+            (TableMode::Replace, None) => String::from("dropping and replacing it if it exists"),
+            (TableMode::Append, None) => String::from("adding to it if it exists"),
+            (TableMode::Strict, None) => String::from("loading into its existing columns")
+        };
+        writeln!(f, "  Table: {}, {}", self.table, mode)?;
+        if let Some(columns) = &self.columns {
+            writeln!(f, "  Columns loaded: {}", columns)?;
+        }
+        for transform in &self.transforms {
+            writeln!(f, "  Transform: {}", transform)?;
+        }
+        if let Some(filter) = &self.filter {
+            writeln!(f, "  Filter: {}", filter)?;
+        }
+        if let Some(sampling) = &self.sampling {
+            writeln!(f, "  Sample: {}", sampling)?;
+        }
+        if let Some(key) = &self.key {
+            writeln!(f, "  Key: {}", key)?;
+        }
+        if let Some(strategy) = &self.strategy {
+            writeln!(f, "  Rows sent with: {}", strategy)?;
+        }
+        write!(f, "  Database: {}", self.database)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::path::PathBuf;
+    use super::*;
+
+    async fn plan_for(csv: &[u8]) -> ImportPlan {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("sales.csv");
+        std::fs::write(&path, csv).unwrap();
+        let csv_input = CsvInput::File(PathBuf::from(path.clone()));
+        let mut plan = plan_import(&csv_input, Dialect::default(), "sales", TableMode::Replace, None,
+                                   String::from("postgres on localhost:5432")).await.unwrap();
+        plan.source = String::from("sales.csv");
+        plan
+    }
+
+    #[async_std::test]
+    async fn plan_local_import() {
+        let plan = plan_for(b"id,region,amount,when\n1,north,12.50,2022-03-13\n2,south,8,\n").await;
+        assert_eq!("\
+Import plan:
+  Dataset: sales.csv (58 bytes)
+  Format: UTF-8, comma-delimited, quoted with \"
+  Columns: 4 (id, region, amount, ...)
+  Types in the first 2 rows: 1 date, 1 decimal, 1 integer, 1 text
+  Table: sales, dropping and replacing it if it exists
+  Database: postgres on localhost:5432", plan.to_string());
+    }
+
+    #[async_std::test]
+    async fn sniff_encoding_and_delimiter() {
+        let plan = plan_for(b"\xef\xbb\xbfid;name\n1;Zo\xff\n").await;
+        let sniff = plan.sniff.unwrap();
+        assert_eq!("not UTF-8, with an invalid byte at offset 15", sniff.encoding);
+        assert_eq!(vec![String::from("id;name")], sniff.header);
+        assert_eq!(Some("semicolons"), sniff.other_delimiter);
+    }
+
+    #[async_std::test]
+    async fn sniff_windows_1252_header() {
+        let sniff = plan_for(b"id,Gr\xf6\xdfe\n1,gro\xc3\x9f\n").await.sniff.unwrap();
+        assert_eq!("UTF-8, with a Windows-1252 header", sniff.encoding);
+        assert_eq!(vec![String::from("id"), String::from("Größe")], sniff.header);
+        let sniff = plan_for(b"id,Gr\xf6\xdfe\n1,gro\xdf\n").await.sniff.unwrap();
+        assert_eq!("not UTF-8, with an invalid byte at offset 14", sniff.encoding);
+    }
+
+    #[async_std::test]
+    async fn sniff_quoting() {
+        let quoting = |csv: &'static [u8]| async move { plan_for(csv).await.sniff.unwrap().quoting };
+        assert_eq!(None, quoting(b"id,note\n1,\"a, b\"\n2,\"say \"\"hi\"\"\"\n").await);
+        let backslashed = Dialect::new('"', Some('\\'), false).unwrap();
+        assert_eq!(Some(backslashed), quoting(b"id,note\n1,\"a, b\"\n2,\"say \\\"hi, you\\\"\"\n").await);
+        // Even where the fields split alike either way
+        assert_eq!(Some(backslashed), quoting(b"id,note\n2,\"say \\\"hi\\\"\"\n3,x\n").await);
+        // A backslash ending a field is not an escape
+        assert_eq!(None, quoting(b"id,path\n1,\"C:\\dir\\\"\n2,\"D:\\\"\n").await);
+
+        let single = Dialect::new('\'', None, true).unwrap();
+        assert_eq!(Some(single), quoting(b"id,note\n1,'a, b'\n2,'it''s'\n").await);
+        assert_eq!(None, quoting(b"id,note\n1,it's\n2,x\n").await);
+
+        let plan = plan_for(b"id,note\n1,'a, b'\n2,'it''s'\n").await.to_string();
+        assert!(plan.contains("\n  Warning: The records appear to be quoted with '. Pass --quote \"'\" to read them so\n"),
+                "{}", plan);
+    }
+
+    #[async_std::test]
+    async fn sniff_only_the_start() {
+        let mut csv = String::from("id,name\n");
+        while csv.len() as u64 <= SNIFF_BYTES {
+            csv.push_str("1234,\"quoted name\"\n");
+        }
+        let sniff = plan_for(csv.as_bytes()).await.sniff.unwrap();
+        assert_eq!((SNIFF_BYTES - 8) / 19, sniff.rows);
+        assert_eq!(vec![(String::from("integer"), 1), (String::from("text"), 1)], sniff.kinds);
+    }
+}
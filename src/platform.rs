@@ -0,0 +1,110 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// The command opening a file or directory with the application the desktop associates with it
+#[cfg(target_os = "macos")]
+fn opener(path: &OsStr) -> Command {
+    let mut command = Command::new("open");
+    command.arg(path);
+    command
+}
+
+/// The command opening a file or directory with the application the desktop associates with it
+#[cfg(windows)]
+fn opener(path: &OsStr) -> Command {
+    // start is built into cmd, and takes its first quoted argument as the window title
+    let mut command = Command::new("cmd");
+    command.args([OsStr::new("/C"), OsStr::new("start"), OsStr::new(""), path]);
+    command
+}
+
+/// The command opening a file or directory with the application the desktop associates with it
+#[cfg(not(any(target_os = "macos", windows)))]
+fn opener(path: &OsStr) -> Command {
+    let mut command = Command::new("xdg-open");
+    command.arg(path);
+    command
+}
+
+/// The space free to unprivileged users on the filesystem holding the directory, in bytes, as
+/// statvfs reports it. None if it cannot be told
+#[cfg(unix)]
+pub fn available_space(directory: &Path) -> Option<u64> {
+    let stats = rustix::fs::statvfs(directory).ok()?;
+    stats.f_bavail.checked_mul(stats.f_frsize)
+}
+
+/// The space free on the filesystem holding the directory, which cannot be told but on Unix
+#[cfg(not(unix))]
+pub fn available_space(_directory: &Path) -> Option<u64> {
+    None
+}
+
+/// Opens the file or directory with the application the desktop associates with it, without
+/// waiting for that application. Fails if there is no opener to run
+pub fn open_path(path: &Path) -> std::io::Result<()> {
+    let mut child = opener(path.as_os_str())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    // The opener is reaped once it exits, which it may not do until the application does
+    std::thread::spawn(move || child.wait());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_line(command: &Command) -> Vec<&OsStr> {
+        std::iter::once(command.get_program()).chain(command.get_args()).collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn space_is_available() {
+        let directory = tempfile::tempdir().unwrap();
+        assert!(available_space(directory.path()).is_some_and(|bytes| bytes > 0));
+        assert_eq!(None, available_space(&directory.path().join("missing")));
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn macos_opener() {
+        assert_eq!(vec!["open", "/tmp/sales.csv"], command_line(&opener(OsStr::new("/tmp/sales.csv"))));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn windows_opener() {
+        assert_eq!(
+            vec!["cmd", "/C", "start", "", "C:\\data\\sales.csv"],
+            command_line(&opener(OsStr::new("C:\\data\\sales.csv")))
+        );
+    }
+
+    #[cfg(not(any(target_os = "macos", windows)))]
+    #[test]
+    fn xdg_opener() {
+        assert_eq!(vec!["xdg-open", "/tmp/sales.csv"], command_line(&opener(OsStr::new("/tmp/sales.csv"))));
+    }
+}
@@ -0,0 +1,278 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use chrono::{NaiveDate, NaiveDateTime};
+use csv_async::StringRecord;
+use serde::Serialize;
+use crate::database::Schema;
+
+/// What the values of a column look like. Every column is imported as text, but knowing
+/// this helps with writing queries which cast it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ValueKind {
+    Boolean,
+    Integer,
+    Decimal,
+    Date,
+    Timestamp,
+    Text
+}
+
+impl ValueKind {
+
+    /// The kind of a value, or none if it is empty
+    pub(crate) fn of(value: &str) -> Option<Self> {
+        if value.is_empty() {
+            return None;
+        }
+        Some(if value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("false") {
+            Self::Boolean
+        } else if value.parse::<i64>().is_ok() {
+            Self::Integer
+        } else if is_decimal(value) {
+            Self::Decimal
+        } else if NaiveDate::parse_from_str(value, "%Y-%m-%d").is_ok() {
+            Self::Date
+        } else if NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f").is_ok()
+            || NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f").is_ok() {
+            Self::Timestamp
+        } else {
+            Self::Text
+        })
+    }
+
+    /// The kind of a column having values of both kinds
+    fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (kind, other) if kind == other => kind,
+            (Self::Integer, Self::Decimal) | (Self::Decimal, Self::Integer) => Self::Decimal,
+            (Self::Date, Self::Timestamp) | (Self::Timestamp, Self::Date) => Self::Timestamp,
+            _ => Self::Text
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::Boolean => "boolean",
+            Self::Integer => "integer",
+            Self::Decimal => "decimal",
+            Self::Date => "date",
+            Self::Timestamp => "timestamp",
+            Self::Text => "text"
+        }
+    }
+}
+
+/// Digits with an optional sign, decimal point and exponent, as Postgres accepts for numeric
+fn is_decimal(value: &str) -> bool {
+    let unsigned = value.strip_prefix(['+', '-']).unwrap_or(value);
+    let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+        None => (unsigned, None)
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    let digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+    let exponent_valid = exponent.is_none_or(|exponent| {
+        let exponent = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+        !exponent.is_empty() && digits(exponent)
+    });
+    !(whole.is_empty() && fraction.is_empty()) && digits(whole) && digits(fraction) && exponent_valid
+}
+
+/// What was learned about a column from its values
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct ColumnProfile {
+    pub(crate) name: String,
+    /// The kind every non-empty value has, or none if all values were empty
+    pub(crate) kind: Option<ValueKind>,
+    /// The length of the longest value, in bytes
+    pub(crate) max_bytes: usize,
+    /// The length of the longest value, in characters, as VARCHAR lengths are measured
+    pub(crate) max_chars: usize,
+    /// How many values were empty
+    pub(crate) empty: u64
+}
+
+impl ColumnProfile {
+
+    fn new(name: &str) -> Self {
+        Self { name: name.to_owned(), kind: None, max_bytes: 0, max_chars: 0, empty: 0 }
+    }
+
+    fn observe(&mut self, value: &str) {
+        if value.len() > self.max_bytes {
+            self.max_bytes = value.len();
+        }
+        // A value has no more characters than bytes, so most values need not be counted
+        if value.len() > self.max_chars {
+            self.max_chars = self.max_chars.max(value.chars().count());
+        }
+        match ValueKind::of(value) {
+            None => self.empty += 1,
+            Some(kind) => self.kind = Some(self.kind.map_or(kind, |seen| seen.merge(kind)))
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.max_bytes = self.max_bytes.max(other.max_bytes);
+        self.max_chars = self.max_chars.max(other.max_chars);
+        self.empty += other.empty;
+        self.kind = match (self.kind, other.kind) {
+            (Some(kind), Some(other)) => Some(kind.merge(other)),
+            (kind, other) => kind.or(other)
+        };
+    }
+}
+
+/// Profiles of every column of a dataset, built up record by record. Parts of the dataset
+/// can be profiled apart, then combined.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct DatasetProfile {
+    pub(crate) rows: u64,
+    pub(crate) columns: Vec<ColumnProfile>
+}
+
+impl DatasetProfile {
+
+    pub(crate) fn new(schema: &Schema) -> Self {
+        let columns = (0..schema.len()).map(|index| ColumnProfile::new(schema.column_name(index))).collect();
+        Self { rows: 0, columns }
+    }
+
+    pub(crate) fn record(&mut self, record: &StringRecord) {
+        self.rows += 1;
+        for (column, value) in self.columns.iter_mut().zip(record.iter()) {
+            column.observe(value);
+        }
+    }
+
+    /// Adds the profile of a later part of the dataset
+    pub(crate) fn extend(&mut self, later: &Self) {
+        self.rows += later.rows;
+        for (column, later) in self.columns.iter_mut().zip(&later.columns) {
+            column.merge(later);
+        }
+    }
+
+    /// A compact table of the profile, one line per column
+    pub(crate) fn table(&self) -> String {
+        let header = ["column", "values", "max bytes", "max chars", "empty"];
+        let rows = self.columns.iter().map(|column| [
+            column.name.clone(),
+            column.kind.map_or("empty", ValueKind::name).to_owned(),
+            column.max_bytes.to_string(),
+            column.max_chars.to_string(),
+            column.empty.to_string()
+        ]).collect::<Vec<_>>();
+        let mut widths = header.map(str::len);
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let line = |cells: [&str; 5]| {
+            // Names and kinds are aligned left, and counts right
+            format!(
+                "{:<w0$}  {:<w1$}  {:>w2$}  {:>w3$}  {:>w4$}", cells[0], cells[1], cells[2], cells[3], cells[4],
+                w0 = widths[0], w1 = widths[1], w2 = widths[2], w3 = widths[3], w4 = widths[4]
+            )
+        };
+        let mut table = line(header);
+        for row in &rows {
+            table.push('\n');
+            table.push_str(&line([&row[0], &row[1], &row[2], &row[3], &row[4]]));
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_kinds() {
+        assert_eq!(None, ValueKind::of(""));
+        assert_eq!(Some(ValueKind::Boolean), ValueKind::of("TRUE"));
+        assert_eq!(Some(ValueKind::Integer), ValueKind::of("-42"));
+        assert_eq!(Some(ValueKind::Decimal), ValueKind::of("99999999999999999999"));
+        assert_eq!(Some(ValueKind::Decimal), ValueKind::of("3.25"));
+        assert_eq!(Some(ValueKind::Decimal), ValueKind::of(".5e-3"));
+        assert_eq!(Some(ValueKind::Date), ValueKind::of("2022-03-13"));
+        assert_eq!(Some(ValueKind::Timestamp), ValueKind::of("2022-03-13T07:00:00.5"));
+        assert_eq!(Some(ValueKind::Text), ValueKind::of("NaN"));
+        assert_eq!(Some(ValueKind::Text), ValueKind::of("1e"));
+        assert_eq!(Some(ValueKind::Text), ValueKind::of("."));
+        assert_eq!(ValueKind::Decimal, ValueKind::Integer.merge(ValueKind::Decimal));
+        assert_eq!(ValueKind::Text, ValueKind::Integer.merge(ValueKind::Date));
+    }
+
+    fn records(rows: &[[&str; 3]]) -> Vec<StringRecord> {
+        rows.iter().map(|row| StringRecord::from(row.to_vec())).collect()
+    }
+
+    #[test]
+    fn profile_records() {
+        let schema: Schema = ["id", "name", "when"].into_iter().collect();
+        let mut profile = DatasetProfile::new(&schema);
+        for record in records(&[["1", "Zoë", "2022-03-13"], ["20", "", "2022-03-14"], ["3", "Bo", ""]]) {
+            profile.record(&record);
+        }
+        assert_eq!(3, profile.rows);
+        assert_eq!(
+            ColumnProfile { name: String::from("id"), kind: Some(ValueKind::Integer), max_bytes: 2, max_chars: 2, empty: 0 },
+            profile.columns[0]
+        );
+        assert_eq!(
+            ColumnProfile { name: String::from("name"), kind: Some(ValueKind::Text), max_bytes: 4, max_chars: 3, empty: 1 },
+            profile.columns[1]
+        );
+        assert_eq!((Some(ValueKind::Date), 1), (profile.columns[2].kind, profile.columns[2].empty));
+    }
+
+    #[test]
+    fn combine_profiles() {
+        let schema: Schema = ["id", "name", "when"].into_iter().collect();
+        let all = records(&[["1", "a", ""], ["2.5", "", ""], ["3", "ccc", ""], ["x", "dd", ""]]);
+        let mut whole = DatasetProfile::new(&schema);
+        all.iter().for_each(|record| whole.record(record));
+
+        // Profiled in parts, as the chunks of an import are
+        let mut combined = DatasetProfile::new(&schema);
+        for part in all.chunks(3) {
+            let mut profile = DatasetProfile::new(&schema);
+            part.iter().for_each(|record| profile.record(record));
+            combined.extend(&profile);
+        }
+        assert_eq!(whole, combined);
+        assert_eq!(Some(ValueKind::Text), combined.columns[0].kind);
+        assert_eq!((None, 4), (combined.columns[2].kind, combined.columns[2].empty));
+    }
+
+    #[test]
+    fn profile_table() {
+        let schema: Schema = ["id", "description"].into_iter().collect();
+        let mut profile = DatasetProfile::new(&schema);
+        profile.record(&StringRecord::from(vec!["1", "well over ten characters"]));
+        profile.record(&StringRecord::from(vec!["2", ""]));
+        assert_eq!("\
+column       values   max bytes  max chars  empty
+id           integer          1          1      0
+description  text            24         24      1", profile.table());
+    }
+}
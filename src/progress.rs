@@ -0,0 +1,295 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use async_std::io::{self, WriteExt};
+use async_std::task;
+use crate::error::{Categorize, Error, Result};
+use crate::observer::{ImportEvent, ImportObserver};
+
+/// How much weight the latest sample carries in the smoothed rate
+const SMOOTHING: f64 = 0.3;
+/// How often the progress line is redrawn
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Estimates the rate at which a file is read, and how long is left, from samples of how many
+/// bytes were read by when. The rate is smoothed so that the estimate does not jump about
+/// with every batch.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RateEstimator {
+    last_sample: Option<(u64, Duration)>,
+    /// Bytes per second
+    rate: Option<f64>
+}
+
+impl RateEstimator {
+
+    /// Records that the given number of bytes had been read after the given time
+    pub(crate) fn sample(&mut self, bytes: u64, elapsed: Duration) {
+        if let Some((last_bytes, last_elapsed)) = self.last_sample {
+            let interval = elapsed.saturating_sub(last_elapsed).as_secs_f64();
+            if interval <= 0.0 {
+                return;
+            }
+            let rate = bytes.saturating_sub(last_bytes) as f64 / interval;
+            self.rate = Some(match self.rate {
+                Some(smoothed) => SMOOTHING * rate + (1.0 - SMOOTHING) * smoothed,
+                None => rate
+            });
+        }
+        self.last_sample = Some((bytes, elapsed));
+    }
+
+    /// Bytes per second, once there are two samples
+    pub(crate) fn rate(&self) -> Option<f64> {
+        self.rate
+    }
+
+    /// How long reading the remaining bytes should take
+    pub(crate) fn eta(&self, total_bytes: u64) -> Option<Duration> {
+        let (bytes, _) = self.last_sample?;
+        let rate = self.rate().filter(|rate| *rate > 0.0)?;
+        Some(Duration::from_secs_f64(total_bytes.saturating_sub(bytes) as f64 / rate))
+    }
+}
+
+/// How far an import has got, shared between the import and whatever reports on it
+#[derive(Debug)]
+pub(crate) struct ImportProgress {
+    started: Instant,
+    /// The size of the CSV file, or 0 if it is not known
+    total_bytes: AtomicU64,
+    bytes: AtomicU64,
+    /// Rows imported since the import started, excluding those imported before resuming
+    rows: AtomicU64
+}
+
+impl Default for ImportProgress {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            total_bytes: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            rows: AtomicU64::new(0)
+        }
+    }
+}
+
+impl ImportObserver for ImportProgress {
+    fn observe(&self, event: &ImportEvent) {
+        match event {
+            ImportEvent::Started { total_bytes, bytes, .. } => {
+                self.bytes.store(*bytes, Ordering::Relaxed);
+                self.total_bytes.store(*total_bytes, Ordering::Relaxed);
+            },
+            ImportEvent::Progress { rows, bytes } => {
+                self.bytes.store(*bytes, Ordering::Relaxed);
+                self.rows.store(*rows, Ordering::Relaxed);
+            },
+            ImportEvent::Reading { bytes } => self.bytes.store(*bytes, Ordering::Relaxed),
+            ImportEvent::RowRejected { .. } | ImportEvent::BatchCommitted { .. } | ImportEvent::Finished { .. } => ()
+        }
+    }
+}
+
+impl ImportProgress {
+
+    /// The rows imported so far
+    pub(crate) fn rows(&self) -> u64 {
+        self.rows.load(Ordering::Relaxed)
+    }
+
+    /// How much of the input has been read, from 0 to 1, if its size is known
+    pub(crate) fn fraction(&self) -> Option<f64> {
+        let total_bytes = self.total_bytes.load(Ordering::Relaxed);
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        (total_bytes > 0).then(|| bytes.min(total_bytes) as f64 / total_bytes as f64)
+    }
+
+    /// Samples the progress, returning the progress line to show. Without the size of the
+    /// input, such as for a download of unknown length, only the rows are counted.
+    pub(crate) fn report(&self, estimator: &mut RateEstimator) -> String {
+        let total_bytes = self.total_bytes.load(Ordering::Relaxed);
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let rows = self.rows.load(Ordering::Relaxed);
+        let elapsed = self.started.elapsed();
+        estimator.sample(bytes, elapsed);
+
+        let rows_per_second = rows as f64 / elapsed.as_secs_f64().max(0.001);
+        if total_bytes == 0 {
+            return format!("Imported {} rows ({:.0} rows/s)", rows, rows_per_second);
+        }
+        let percent = 100.0 * bytes.min(total_bytes) as f64 / total_bytes as f64;
+        let mut line = format!("Imported {} rows ({:.1}%, {:.0} rows/s", rows, percent, rows_per_second);
+        if let Some(eta) = estimator.eta(total_bytes) {
+            line.push_str(&format!(", about {} left", format_duration(eta)));
+        }
+        line.push(')');
+        line
+    }
+}
+
+/// How far writing query results has got, shared between the writer and whatever reports on
+/// it. The total is not known in advance, so only what was written so far is reported.
+#[derive(Debug)]
+pub(crate) struct ExportProgress {
+    started: Instant,
+    rows: AtomicU64,
+    bytes: AtomicU64
+}
+
+impl Default for ExportProgress {
+    fn default() -> Self {
+        Self { started: Instant::now(), rows: AtomicU64::new(0), bytes: AtomicU64::new(0) }
+    }
+}
+
+impl ExportProgress {
+
+    pub(crate) fn add_row(&self) {
+        self.rows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rows(&self) -> u64 {
+        self.rows.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The progress line to show, e.g. Wrote 120000 rows (14.2 MB, 5s elapsed)
+    pub(crate) fn report(&self) -> String {
+        format!(
+            "Wrote {} rows ({}, {} elapsed)",
+            self.rows(), format_bytes(self.bytes.load(Ordering::Relaxed)), format_duration(self.started.elapsed())
+        )
+    }
+
+    /// How much was written and how long it took, e.g. 14.2 MB in 5s
+    pub(crate) fn totals(&self) -> String {
+        format!("{} in {}", format_bytes(self.bytes.load(Ordering::Relaxed)), format_duration(self.started.elapsed()))
+    }
+}
+
+/// Shows progress on a single terminal line, redrawn every second until cancelled
+pub(crate) async fn show<T>(mut report: impl FnMut() -> String) -> Result<T> {
+    let mut stderr = io::stderr();
+    loop {
+        task::sleep(REPORT_INTERVAL).await;
+        let line = report();
+        // Erases the rest of the previous line, which may have been longer
+        stderr.write_all(format!("\r{}\x1b[K", line).as_bytes()).await.categorize(Error::OutputIo)?;
+        stderr.flush().await.categorize(Error::OutputIo)?;
+    }
+}
+
+/// Erases the progress line
+pub(crate) async fn clear() -> Result<()> {
+    let mut stderr = io::stderr();
+    stderr.write_all(b"\r\x1b[K").await.categorize(Error::OutputIo)?;
+    stderr.flush().await.categorize(Error::OutputIo)
+}
+
+/// Sizes such as 512 bytes, 3.4 KB or 14.2 MB
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..1_000 => format!("{} bytes", bytes),
+        1_000..1_000_000 => format!("{:.1} KB", bytes as f64 / 1e3),
+        1_000_000..1_000_000_000 => format!("{:.1} MB", bytes as f64 / 1e6),
+        _ => format!("{:.1} GB", bytes as f64 / 1e9)
+    }
+}
+
+/// Durations such as 1h 02m, 3m 05s or 12s
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs_f64().round() as u64;
+    match (seconds / 3600, seconds % 3600 / 60, seconds % 60) {
+        (0, 0, seconds) => format!("{}s", seconds),
+        (0, minutes, seconds) => format!("{}m {:02}s", minutes, seconds),
+        (hours, minutes, _) => format!("{}h {:02}m", hours, minutes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_rate_and_eta() {
+        let mut estimator = RateEstimator::default();
+        estimator.sample(0, Duration::ZERO);
+        assert_eq!(None, estimator.rate());
+        assert_eq!(None, estimator.eta(1000));
+
+        estimator.sample(100, Duration::from_secs(1));
+        assert_eq!(Some(100.0), estimator.rate());
+        assert_eq!(Some(Duration::from_secs(9)), estimator.eta(1000));
+
+        // A slower second is smoothed, rather than taken at face value
+        estimator.sample(150, Duration::from_secs(2));
+        assert_eq!(Some(85.0), estimator.rate());
+        assert_eq!(Some(Duration::from_secs(10)), estimator.eta(1000));
+
+        // Samples at the same time are ignored
+        estimator.sample(200, Duration::from_secs(2));
+        assert_eq!(Some(85.0), estimator.rate());
+    }
+
+    #[test]
+    fn eta_when_stalled() {
+        let mut estimator = RateEstimator::default();
+        estimator.sample(100, Duration::from_secs(1));
+        estimator.sample(100, Duration::from_secs(2));
+        assert_eq!(None, estimator.eta(1000));
+    }
+
+    #[test]
+    fn format_sizes() {
+        assert_eq!("999 bytes", format_bytes(999));
+        assert_eq!("3.4 KB", format_bytes(3_400));
+        assert_eq!("14.2 MB", format_bytes(14_200_000));
+        assert_eq!("2.0 GB", format_bytes(2_000_000_000));
+    }
+
+    #[test]
+    fn report_export_progress() {
+        let progress = ExportProgress::default();
+        progress.add_row();
+        progress.add_row();
+        progress.add_bytes(1_500);
+        assert_eq!(2, progress.rows());
+        assert_eq!("Wrote 2 rows (1.5 KB, 0s elapsed)", progress.report());
+        assert_eq!("1.5 KB in 0s", progress.totals());
+    }
+
+    #[test]
+    fn format_durations() {
+        assert_eq!("12s", format_duration(Duration::from_millis(11_600)));
+        assert_eq!("3m 05s", format_duration(Duration::from_secs(185)));
+        assert_eq!("1h 02m", format_duration(Duration::from_secs(3725)));
+    }
+}
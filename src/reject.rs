@@ -0,0 +1,269 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use eyre::eyre;
+use itertools::Itertools;
+use crate::error::{Error, Result};
+use crate::import::RejectReason;
+
+/// How many rows are read before the share of them rejected is checked, unless the dataset is smaller
+pub(crate) const DEFAULT_SAMPLE_ROWS: u64 = 10_000;
+
+/// How many rows an import which skips the rows it cannot import may reject before it stops,
+/// given as a count, a percentage of the rows read, or both, as in 500,5%
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct RejectLimit {
+    /// The import stops once more rows than this are rejected
+    pub(crate) count: Option<u64>,
+    /// The import stops once more than this percentage of the rows read are rejected, checked
+    /// once the sample is read
+    pub(crate) percent: Option<f64>,
+    /// How many rows are read before the percentage is checked
+    pub(crate) sample: u64
+}
+
+impl Default for RejectLimit {
+    fn default() -> Self {
+        Self { count: None, percent: None, sample: DEFAULT_SAMPLE_ROWS }
+    }
+}
+
+impl std::str::FromStr for RejectLimit {
+    type Err = String;
+
+    fn from_str(limit: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parsed = Self::default();
+        for part in limit.split(',').map(str::trim) {
+            let invalid = || {
+                format!("Invalid reject limit {}. Use a count of rows, a percentage such as 5%, or both", part)
+            };
+            match part.strip_suffix('%') {
+                Some(percent) if parsed.percent.is_none() => {
+                    let percent = percent.trim().parse::<f64>().ok()
+                        .filter(|percent| (0.0..=100.0).contains(percent))
+                        .ok_or_else(invalid)?;
+                    parsed.percent = Some(percent);
+                },
+                None if parsed.count.is_none() => parsed.count = Some(part.parse().map_err(|_| invalid())?),
+                _ => return Err(format!("Reject limit {} gives more than one count or percentage", limit))
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+impl fmt::Display for RejectLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.count.map(|count| count.to_string());
+        let percent = self.percent.map(|percent| format!("{}%", percent));
+        f.write_str(&count.into_iter().chain(percent).join(","))
+    }
+}
+
+/// The kinds of reasons a row is rejected for, by which the rejects are summarized
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum RejectKind {
+    FieldCount,
+    InvalidUtf8,
+    InvalidValue,
+    TooLarge
+}
+
+impl From<&RejectReason> for RejectKind {
+    fn from(reason: &RejectReason) -> Self {
+        match reason {
+            RejectReason::FieldCount { .. } => Self::FieldCount,
+            RejectReason::InvalidUtf8 { .. } => Self::InvalidUtf8,
+            RejectReason::InvalidValue { .. } => Self::InvalidValue,
+            RejectReason::FieldTooLong { .. } | RejectReason::RecordTooLong { .. } => Self::TooLarge
+        }
+    }
+}
+
+impl fmt::Display for RejectKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::FieldCount => "whose fields did not match the header",
+            Self::InvalidUtf8 => "with invalid UTF-8",
+            Self::InvalidValue => "with values which their columns' types cannot hold",
+            Self::TooLarge => "too large to read"
+        })
+    }
+}
+
+/// The rows read and rejected so far, with how many were rejected for each kind of reason
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct RejectTally {
+    rows: u64,
+    rejected: u64,
+    /// In the order the kinds were first seen
+    kinds: Vec<(RejectKind, u64)>
+}
+
+impl RejectTally {
+
+    /// Counts rows read, including those rejected
+    pub(crate) fn read(&mut self, rows: u64) {
+        self.rows += rows;
+    }
+
+    pub(crate) fn reject(&mut self, reason: &RejectReason) {
+        let kind = RejectKind::from(reason);
+        self.rejected += 1;
+        match self.kinds.iter_mut().find(|(seen, _)| *seen == kind) {
+            Some((_, count)) => *count += 1,
+            None => self.kinds.push((kind, 1))
+        }
+    }
+
+    /// Stops the import if more rows were rejected than the limit allows. The percentage is
+    /// checked once the sample is read, or once the dataset ends if it is smaller
+    pub(crate) fn check(&self, limit: &RejectLimit, finished: bool) -> Result<()> {
+        let over_count = limit.count.is_some_and(|count| self.rejected > count);
+        let over_percent = limit.percent.is_some_and(|percent| {
+            let sampled = self.rows >= limit.sample || finished;
+            sampled && self.rows != 0 && self.rejected as f64 * 100.0 > percent * self.rows as f64
+        });
+        if over_count || over_percent {
+            return Err(Error::ImportData(eyre!(
+                "Stopped the import after rejecting {}, more than --max-rejects {} allows: {}",
+                self.rejected_rows(), limit, self.kinds()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Summarizes the rejects, grouped by why the rows were rejected, if there were any
+    pub(crate) fn summary(&self) -> Option<String> {
+        (self.rejected != 0).then(|| format!("Rejected {}: {}", self.rejected_rows(), self.kinds()))
+    }
+
+    fn rejected_rows(&self) -> String {
+        let percent = self.rejected as f64 * 100.0 / self.rows.max(1) as f64;
+        format!("{} of {} rows ({:.1}%)", self.rejected, self.rows, percent)
+    }
+
+    fn kinds(&self) -> String {
+        self.kinds.iter().map(|(kind, count)| format!("{} {}", count, kind)).join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIELD_COUNT: RejectReason = RejectReason::FieldCount { fields: 3 };
+    const TOO_LARGE: RejectReason = RejectReason::RecordTooLong { limit: 64 };
+
+    /// Reads the rows in batches, rejecting those the closure gives a reason for, until the
+    /// limit stops the import. Returns the error, and how many rows were read by then
+    fn read_until_stopped(limit: &str, rows: u64, batch: u64,
+                          reason: impl Fn(u64) -> Option<RejectReason>) -> (Result<()>, u64) {
+        let limit = limit.parse::<RejectLimit>().unwrap();
+        let mut tally = RejectTally::default();
+        for start in (0..rows).step_by(batch as usize) {
+            let end = (start + batch).min(rows);
+            tally.read(end - start);
+            (start..end).filter_map(&reason).for_each(|reason| tally.reject(&reason));
+            if let Err(error) = tally.check(&limit, end == rows) {
+                return (Err(error), end);
+            }
+        }
+        (Ok(()), rows)
+    }
+
+    #[test]
+    fn parse_reject_limits() {
+        let limit = |count, percent| RejectLimit { count, percent, sample: DEFAULT_SAMPLE_ROWS };
+        assert_eq!(Ok(limit(Some(500), None)), "500".parse());
+        assert_eq!(Ok(limit(None, Some(5.0))), "5%".parse());
+        assert_eq!(Ok(limit(Some(500), Some(2.5))), " 2.5% , 500".parse());
+        assert_eq!("500,2.5%", limit(Some(500), Some(2.5)).to_string());
+        assert_eq!(
+            Err(String::from("Invalid reject limit 120%. Use a count of rows, a percentage such as 5%, or both")),
+            "120%".parse::<RejectLimit>()
+        );
+        assert!("five".parse::<RejectLimit>().is_err());
+        assert_eq!(
+            Err(String::from("Reject limit 5%,6% gives more than one count or percentage")),
+            "5%,6%".parse::<RejectLimit>()
+        );
+    }
+
+    #[test]
+    fn stop_once_the_sample_has_too_many_rejects() {
+        // 1 in 10 rows is rejected, which is only checked once 10,000 rows are read
+        let (stopped, read) = read_until_stopped("5%", 50_000, 1_000, |row| (row % 10 == 0).then_some(FIELD_COUNT));
+        assert_eq!(10_000, read);
+        assert_eq!(
+            "Stopped the import after rejecting 1000 of 10000 rows (10.0%), more than --max-rejects 5% allows: \
+            1000 whose fields did not match the header",
+            stopped.unwrap_err().to_string()
+        );
+        // 1 in 50 is within the limit
+        let (finished, read) = read_until_stopped("5%", 50_000, 1_000, |row| (row % 50 == 0).then_some(FIELD_COUNT));
+        assert!(finished.is_ok());
+        assert_eq!(50_000, read);
+    }
+
+    #[test]
+    fn early_rejects_do_not_stop_the_import_before_the_sample() {
+        // The first rows are all rejected, though not enough of the sample is
+        let reason = |row| (row < 400).then_some(TOO_LARGE);
+        assert!(read_until_stopped("5%", 20_000, 100, reason).0.is_ok());
+        // A dataset smaller than the sample is checked once it ends
+        let (stopped, read) = read_until_stopped("5%", 1_000, 100, reason);
+        assert!(stopped.is_err());
+        assert_eq!(1_000, read);
+    }
+
+    #[test]
+    fn stop_once_too_many_rows_are_rejected() {
+        let reason = |row| match row % 100 {
+            0 => Some(FIELD_COUNT),
+            1 => Some(TOO_LARGE),
+            _ => None
+        };
+        let (stopped, read) = read_until_stopped("30", 1_000_000, 500, reason);
+        assert_eq!(2_000, read);
+        assert_eq!(
+            "Stopped the import after rejecting 40 of 2000 rows (2.0%), more than --max-rejects 30 allows: \
+            20 whose fields did not match the header, 20 too large to read",
+            stopped.unwrap_err().to_string()
+        );
+        // Either limit stops the import
+        assert_eq!(2_000, read_until_stopped("30,5%", 1_000_000, 500, reason).1);
+        assert_eq!(1_000_000, read_until_stopped("5%", 1_000_000, 500, reason).1);
+    }
+
+    #[test]
+    fn summarize_rejects_by_kind() {
+        let mut tally = RejectTally::default();
+        assert_eq!(None, tally.summary());
+        tally.read(400);
+        for reason in [&TOO_LARGE, &FIELD_COUNT, &TOO_LARGE, &RejectReason::InvalidUtf8 { column: 0, byte: 10 }] {
+            tally.reject(reason);
+        }
+        assert_eq!(
+            Some(String::from("Rejected 4 of 400 rows (1.0%): 2 too large to read, \
+            1 whose fields did not match the header, 1 with invalid UTF-8")),
+            tally.summary()
+        );
+        assert!(tally.check(&RejectLimit::default(), true).is_ok());
+    }
+}
@@ -0,0 +1,196 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use crate::error::{Error, Result};
+use crate::timing::StageSeconds;
+
+/// The version of the report's layout, raised whenever a field is renamed, removed or changes
+/// meaning. Fields may be added without raising it
+const REPORT_VERSION: u32 = 1;
+
+/// What happened during a run, written as JSON with --report so that pipelines need not
+/// parse the messages. It is written whether or not the run succeeded
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct RunReport {
+    report_version: u32,
+    /// When the run started, in ISO-8601
+    started: String,
+    duration_seconds: f64,
+    success: bool,
+    imports: Vec<ImportReport>,
+    queries: Vec<QueryReport>,
+    /// What ended the run, if it failed
+    error: Option<ErrorReport>
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ImportReport {
+    /// The CSV file or URL, unless the existing data was kept
+    pub(crate) input: Option<String>,
+    pub(crate) table: String,
+    /// Whether the dataset was only validated, without importing it
+    pub(crate) dry_run: bool,
+    /// The rows imported, or which would be, once the import finished
+    pub(crate) rows_imported: Option<u64>,
+    /// The rows left out by --filter, if there is one
+    pub(crate) rows_filtered_out: Option<u64>,
+    /// Which of the rows were imported, if only a sample was, with every row read and those
+    /// chosen for the sample
+    pub(crate) sample: Option<String>,
+    pub(crate) rows_read: Option<u64>,
+    pub(crate) rows_sampled: Option<u64>,
+    /// How many values --transform changed in each column it was given for
+    pub(crate) values_transformed: BTreeMap<String, u64>,
+    /// The rows whose key was already in the table, and which were updated or left out as
+    /// --on-conflict asked, if it did
+    pub(crate) rows_updated: Option<u64>,
+    pub(crate) rows_skipped_on_conflict: Option<u64>,
+    /// Rows which could not be imported. Only a dry run goes on past them
+    pub(crate) rejects: u64,
+    /// How many rows the table has once the import finished
+    pub(crate) table_rows: Option<u64>,
+    /// Seconds spent reading and parsing, transforming, in the database and committing, once
+    /// the import finished. Parsing is spread over several tasks, whose times are added up
+    pub(crate) stage_seconds: Option<StageSeconds>,
+    /// Unless the import did not finish
+    pub(crate) duration_seconds: Option<f64>,
+    #[serde(skip)]
+    started: Instant
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct QueryReport {
+    /// The name of the query in the batch spec, if it was run from one
+    pub(crate) name: Option<String>,
+    /// The query as it was run, after generating it for pivot, top and bucket
+    pub(crate) query: String,
+    /// Where the results went: show, csv, json or table
+    pub(crate) destination: Option<String>,
+    pub(crate) rows_exported: Option<u64>,
+    /// The output file and its manifest, if they were written
+    pub(crate) output_files: Vec<String>,
+    /// The table the results were saved in, for the table destination
+    pub(crate) output_table: Option<String>,
+    /// Unless the query did not finish
+    pub(crate) duration_seconds: Option<f64>,
+    /// Why the query failed, if it did without ending the run, as a batch query may
+    pub(crate) error: Option<ErrorReport>,
+    #[serde(skip)]
+    started: Instant
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ErrorReport {
+    /// The category of the error, such as query_syntax
+    category: &'static str,
+    exit_code: u8,
+    /// The error and each of its causes, as printed
+    message: String
+}
+
+impl RunReport {
+
+    pub(crate) fn start_import(&mut self, input: Option<String>, table: String) -> &mut ImportReport {
+        self.imports.push(ImportReport {
+            input, table, dry_run: false, rows_imported: None, rows_filtered_out: None, sample: None,
+            rows_read: None, rows_sampled: None,
+            values_transformed: BTreeMap::new(), rows_updated: None, rows_skipped_on_conflict: None, rejects: 0,
+            table_rows: None, stage_seconds: None, duration_seconds: None,
+            started: Instant::now()
+        });
+        self.imports.last_mut().expect("An import was just added")
+    }
+
+    /// The import started last, if it has yet to finish
+    pub(crate) fn pending_import(&mut self) -> Option<&mut ImportReport> {
+        self.imports.last_mut().filter(|import| import.duration_seconds.is_none())
+    }
+
+    pub(crate) fn start_query(&mut self, query: String) {
+        self.queries.push(QueryReport {
+            name: None, query, destination: None, rows_exported: None, output_files: Vec::new(), output_table: None,
+            duration_seconds: None, error: None, started: Instant::now()
+        });
+    }
+
+    /// The query started last
+    pub(crate) fn current_query(&mut self) -> Option<&mut QueryReport> {
+        self.queries.last_mut()
+    }
+
+    /// Completes the report once the run has ended
+    pub(crate) fn finish(&mut self, started: DateTime<Utc>, duration: Duration, result: &Result<()>) {
+        self.report_version = REPORT_VERSION;
+        self.started = started.to_rfc3339_opts(SecondsFormat::Millis, true);
+        self.duration_seconds = duration.as_secs_f64();
+        self.success = result.is_ok();
+        self.error = result.as_ref().err().map(ErrorReport::of);
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        let mut json = serde_json::to_string_pretty(self).expect("Reports are always serializable");
+        json.push('\n');
+        json
+    }
+}
+
+impl ErrorReport {
+    pub(crate) fn of(error: &Error) -> Self {
+        Self { category: error.category(), exit_code: error.exit_code(), message: error.render() }
+    }
+}
+
+impl ImportReport {
+    pub(crate) fn finish(&mut self) {
+        self.duration_seconds = Some(self.started.elapsed().as_secs_f64());
+    }
+}
+
+impl QueryReport {
+    pub(crate) fn finish(&mut self, destination: &str, rows: u64) {
+        self.destination = Some(String::from(destination));
+        self.rows_exported = Some(rows);
+        self.duration_seconds = Some(self.started.elapsed().as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::eyre;
+    use super::*;
+
+    #[test]
+    fn report_failed_query() {
+        let mut report = RunReport::default();
+        report.start_query(String::from("SELECT nothing"));
+        let error = Error::QueryRuntime(eyre!("column \"nothing\" does not exist"));
+        report.finish(Utc::now(), Duration::from_millis(1500), &Err(error));
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(serde_json::json!(1), json["report_version"]);
+        assert_eq!(serde_json::json!(false), json["success"]);
+        assert_eq!(serde_json::json!(1.5), json["duration_seconds"]);
+        assert_eq!(serde_json::json!("SELECT nothing"), json["queries"][0]["query"]);
+        assert_eq!(serde_json::Value::Null, json["queries"][0]["duration_seconds"]);
+        assert_eq!(serde_json::json!({
+            "category": "query_runtime", "exit_code": 8, "message": "column \"nothing\" does not exist"
+        }), json["error"]);
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How an import keeps only some of the rows it reads, for a look at a dataset too large to
+/// import whole
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Sampling {
+    /// Each row is kept with this probability. Whether it is depends only on the seed and its
+    /// line, so that the same seed chooses the same rows, however the file is split into chunks
+    Rate { rate: f64, seed: u64 },
+    /// This many rows, chosen at random once every row is read, each as likely as any other.
+    /// They are imported in no particular order
+    Rows { rows: u64, seed: u64 },
+    /// The first this many rows, after which nothing more is read
+    First { rows: u64 }
+}
+
+impl Sampling {
+
+    /// Whether the row on the line is kept. Only a sample at a rate leaves out rows as they are
+    /// parsed; the others choose from the rows once they are filtered
+    pub(crate) fn keeps_line(&self, line: u64) -> bool {
+        match self {
+            Self::Rate { rate, seed } => {
+                // The top 53 bits give a fraction evenly spread from 0 to 1
+                let fraction = (mix(seed ^ mix(line)) >> 11) as f64 / (1u64 << 53) as f64;
+                fraction < *rate
+            },
+            Self::Rows { .. } | Self::First { .. } => true
+        }
+    }
+}
+
+impl fmt::Display for Sampling {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // Rounded, so that 0.07 is not shown as 7.000000000000001%
+            Self::Rate { rate, seed } => {
+                write!(f, "{}% of the rows, seed {}", (rate * 100.0 * 1e6).round() / 1e6, seed)
+            },
+            Self::Rows { rows, seed } => write!(f, "{} rows chosen at random, seed {}", rows, seed),
+            Self::First { rows } => write!(f, "the first {} rows", rows)
+        }
+    }
+}
+
+/// Parses a sampling rate, a fraction greater than 0 and at most 1
+pub(crate) fn parse_rate(rate: &str) -> Result<f64, String> {
+    rate.parse().ok()
+        .filter(|rate| *rate > 0.0 && *rate <= 1.0)
+        .ok_or_else(|| format!("Invalid sampling rate {}. Use a fraction above 0 and at most 1, such as 0.01", rate))
+}
+
+/// A seed which differs from run to run, for when none is given
+pub(crate) fn default_seed() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    // Kept to what a JSON number holds exactly, so that the seed reported can be given again
+    mix(now.as_nanos() as u64) >> 11
+}
+
+/// SplitMix64, which spreads any change to its input across every bit of its output
+fn mix(value: u64) -> u64 {
+    let mut value = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
+
+/// A random sample of a fixed number of the rows offered to it, however many there are, each
+/// as likely to be kept as any other
+pub(crate) struct Reservoir<T> {
+    rows: Vec<T>,
+    capacity: u64,
+    offered: u64,
+    state: u64
+}
+
+impl<T> Reservoir<T> {
+
+    pub(crate) fn new(capacity: u64, seed: u64) -> Self {
+        Self { rows: Vec::new(), capacity, offered: 0, state: seed }
+    }
+
+    /// Keeps the row in place of one kept before, or not at all, with the chance that leaves
+    /// every row offered so far as likely to be kept
+    pub(crate) fn offer(&mut self, row: T) {
+        self.offered += 1;
+        if self.offered <= self.capacity {
+            self.rows.push(row);
+            return;
+        }
+        self.state = self.state.wrapping_add(1);
+        let index = mix(self.state) % self.offered;
+        if index < self.capacity {
+            self.rows[index as usize] = row;
+        }
+    }
+
+    pub(crate) fn into_rows(self) -> Vec<T> {
+        self.rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_keeps_about_its_share() {
+        let sampling = Sampling::Rate { rate: 0.1, seed: 42 };
+        let kept = (1..=100_000).filter(|line| sampling.keeps_line(*line)).count();
+        assert!((9_500..=10_500).contains(&kept), "{}", kept);
+        assert!((1..=1000).all(|line| Sampling::Rate { rate: 1.0, seed: 7 }.keeps_line(line)));
+        assert!((1..=1000).all(|line| Sampling::First { rows: 1 }.keeps_line(line)));
+    }
+
+    #[test]
+    fn seed_chooses_the_same_rows() {
+        let lines = |seed: u64| {
+            let sampling = Sampling::Rate { rate: 0.01, seed };
+            (1..=10_000).filter(|line| sampling.keeps_line(*line)).collect::<Vec<u64>>()
+        };
+        assert_eq!(lines(42), lines(42));
+        assert_ne!(lines(42), lines(43));
+    }
+
+    #[test]
+    fn reservoir_keeps_capacity() {
+        let sample = |seed: u64| {
+            let mut reservoir = Reservoir::new(10, seed);
+            (0..1000).for_each(|row| reservoir.offer(row));
+            reservoir.into_rows()
+        };
+        let rows = sample(42);
+        assert_eq!(10, rows.len());
+        assert_eq!(rows, sample(42));
+        assert_ne!(rows, sample(43));
+        // Later rows are as likely to be kept as earlier ones
+        assert!(rows.iter().any(|row| *row >= 10), "{:?}", rows);
+
+        let mut reservoir = Reservoir::new(10, 42);
+        (0..3).for_each(|row| reservoir.offer(row));
+        assert_eq!(vec![0, 1, 2], reservoir.into_rows());
+    }
+
+    #[test]
+    fn reservoir_is_uniform() {
+        // Over many samples of 2 from 10, each row is kept about a fifth of the time
+        let mut kept = [0u32; 10];
+        for seed in 0..10_000 {
+            let mut reservoir = Reservoir::new(2, seed);
+            (0..10).for_each(|row| reservoir.offer(row));
+            reservoir.into_rows().into_iter().for_each(|row: usize| kept[row] += 1);
+        }
+        assert!(kept.iter().all(|count| (1_800..=2_200).contains(count)), "{:?}", kept);
+    }
+
+    #[test]
+    fn parse_rates() {
+        assert_eq!(Ok(0.01), parse_rate("0.01"));
+        assert_eq!(Ok(1.0), parse_rate("1"));
+        for invalid in ["0", "1.5", "-0.1", "1%", "NaN", ""] {
+            assert!(parse_rate(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn describe_sampling() {
+        assert_eq!("7% of the rows, seed 42", Sampling::Rate { rate: 0.07, seed: 42 }.to_string());
+        assert_eq!("0.5% of the rows, seed 1", Sampling::Rate { rate: 0.005, seed: 1 }.to_string());
+        assert_eq!("100 rows chosen at random, seed 9", Sampling::Rows { rows: 100, seed: 9 }.to_string());
+        assert_eq!("the first 100 rows", Sampling::First { rows: 100 }.to_string());
+    }
+}
@@ -0,0 +1,187 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use async_std::path::{Path, PathBuf};
+use csv_async::StringRecord;
+use futures_lite::AsyncWriteExt;
+use futures_lite::io::BufWriter;
+use itertools::Itertools;
+use crate::database::{quote_literal, InsertTarget, Schema, TableMode};
+use crate::error::{Categorize, Error, Result};
+use crate::import::{ImportSummary, ParsedRecords};
+use crate::staged::StagedFile;
+
+/// A psql script which creates the table and imports the dataset into it, written instead of
+/// connecting to the database, so that the statements can be reviewed and run by someone else.
+/// Rows are inlined in INSERT statements, or read from the CSV file by psql's \copy
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SqlScript {
+    pub(crate) table: String,
+    pub(crate) mode: TableMode,
+    /// The schema which the table is created in, if the config names one, which is created too
+    pub(crate) schema: Option<String>,
+    /// Described in a comment at the start of the script
+    pub(crate) source: String,
+    /// The file which \copy reads the rows from, unless they are inlined
+    pub(crate) copy_from: Option<CopyFrom>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CopyFrom {
+    /// Absolute, so that the script can be run from any directory
+    pub(crate) path: PathBuf,
+    /// The options of COPY for the dataset's dialect
+    pub(crate) dialect_options: String
+}
+
+impl SqlScript {
+
+    /// Writes the script, returning what happened to the records inlined in it. Only the header
+    /// is read if they are copied instead
+    pub(crate) async fn write(&self, mut records: ParsedRecords, output: &Path) -> Result<ImportSummary> {
+        if output.exists().await {
+            return Err(Error::OutputIo(eyre::eyre!("Delete existing file {} first", output.display())));
+        }
+        let (staged, file) = StagedFile::create(output).await.categorize(Error::OutputIo)?;
+        let mut writer = BufWriter::new(file);
+        let target = InsertTarget::created(records.schema());
+        writer.write_all(self.preamble(records.schema()).as_bytes()).await.categorize(Error::OutputIo)?;
+        let summary = match &self.copy_from {
+            Some(copy_from) => {
+                writer.write_all(self.copy_command(&target, copy_from).as_bytes()).await
+                    .categorize(Error::OutputIo)?;
+                ImportSummary::default()
+            },
+            None => {
+                while let Some(batch) = records.next_batch().await? {
+                    if !batch.is_empty() {
+                        writer.write_all(self.insert_statement(&target, &batch).as_bytes()).await
+                            .categorize(Error::OutputIo)?;
+                    }
+                }
+                records.summary()
+            }
+        };
+        writer.write_all(b"COMMIT;\n").await.categorize(Error::OutputIo)?;
+        writer.flush().await.categorize(Error::OutputIo)?;
+        staged.persist(writer.into_inner()).await.categorize(Error::OutputIo)?;
+        Ok(summary)
+    }
+
+    /// Stops at the first error, and runs everything in one transaction, so that a script which
+    /// fails leaves nothing behind. The settings make the literals mean the same on any server
+    fn preamble(&self, schema: &Schema) -> String {
+        let mut preamble = format!(
+            "-- Imports {} into {}. Run with psql -f\n\\set ON_ERROR_STOP on\n\
+            SET client_encoding = 'UTF8';\nSET standard_conforming_strings = on;\nBEGIN;\n",
+            self.source.replace('\n', " "), self.table
+        );
+        if let Some(schema) = &self.schema {
+            preamble.push_str(&format!("CREATE SCHEMA IF NOT EXISTS {};\n", schema));
+        }
+        for statement in schema.create_table_statements(&self.table, self.mode) {
+            preamble.push_str(&statement);
+            preamble.push_str(";\n");
+        }
+        preamble
+    }
+
+    /// One INSERT statement for the batch, with a line for each row
+    fn insert_statement(&self, target: &InsertTarget, records: &[StringRecord]) -> String {
+        let rows = records.iter()
+            .map(|record| {
+                let mut values = record.iter().enumerate()
+                    .map(|(column, value)| target.value(column, &quote_literal(value)));
+                format!("({})", values.join(", "))
+            })
+            .join(",\n");
+        format!("INSERT INTO {} ({}) VALUES\n{};\n", self.table, target.column_names_joined_by_commas(), rows)
+    }
+
+    /// \copy must be written on one line. Blank lines are read as rows by COPY, so a dataset with
+    /// blank lines between its records must be inlined instead
+    fn copy_command(&self, target: &InsertTarget, copy_from: &CopyFrom) -> String {
+        format!(
+            "\\copy {} ({}) FROM {} WITH ({}, HEADER, ENCODING 'UTF8'{})\n",
+            self.table, target.column_names_joined_by_commas(),
+            quote_literal(&copy_from.path.to_string_lossy()), copy_from.dialect_options, target.copy_null_options()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(mode: TableMode, copy_from: Option<CopyFrom>) -> SqlScript {
+        SqlScript {
+            table: String::from("analyst.data"),
+            mode,
+            schema: Some(String::from("analyst")),
+            source: String::from("/tmp/people.csv"),
+            copy_from
+        }
+    }
+
+    #[test]
+    fn create_the_table() {
+        let schema: Schema = ["name", "age"].into_iter().collect();
+        assert_eq!("\
+-- Imports /tmp/people.csv into analyst.data. Run with psql -f
+\\set ON_ERROR_STOP on
+SET client_encoding = 'UTF8';
+SET standard_conforming_strings = on;
+BEGIN;
+CREATE SCHEMA IF NOT EXISTS analyst;
+DROP TABLE IF EXISTS analyst.data;
+CREATE TABLE analyst.data (name TEXT NOT NULL, age TEXT NOT NULL);
+", script(TableMode::Replace, None).preamble(&schema));
+        let mut appended = script(TableMode::Append, None);
+        appended.schema = None;
+        assert!(appended.preamble(&schema).ends_with(
+            "BEGIN;\nCREATE TABLE IF NOT EXISTS analyst.data (name TEXT NOT NULL, age TEXT NOT NULL);\n"
+        ));
+    }
+
+    #[test]
+    fn inline_rows() {
+        let schema: Schema = ["name", "Order note"].into_iter().collect();
+        let records = [
+            StringRecord::from(vec!["O'Brien", "C:\\temp"]),
+            StringRecord::from(vec!["", "two\nlines"])
+        ];
+        assert_eq!(
+            "INSERT INTO analyst.data (name, \"Order note\") VALUES\n('O''Brien', 'C:\\temp'),\n('', 'two\nlines');\n",
+            script(TableMode::Replace, None).insert_statement(&InsertTarget::created(&schema), &records)
+        );
+    }
+
+    #[test]
+    fn copy_rows_from_the_file() {
+        let schema: Schema = ["name", "Age"].into_iter().collect();
+        let copy_from = CopyFrom {
+            path: PathBuf::from("/data/Bob's people.csv"),
+            dialect_options: String::from("FORMAT csv, QUOTE '\"', ESCAPE '\"'")
+        };
+        let script = script(TableMode::Append, Some(copy_from.clone()));
+        assert_eq!(
+            "\\copy analyst.data (name, \"Age\") FROM '/data/Bob''s people.csv' WITH (FORMAT csv, QUOTE '\"', \
+            ESCAPE '\"', HEADER, ENCODING 'UTF8', FORCE_NOT_NULL (name, \"Age\"))\n",
+            script.copy_command(&InsertTarget::created(&schema), &copy_from)
+        );
+    }
+}
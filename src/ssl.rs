@@ -0,0 +1,165 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::PathBuf;
+use std::str::FromStr;
+use eyre::{eyre, Result};
+use serde::{Serialize, Deserialize};
+use sqlx::postgres::{PgConnectOptions, PgSslMode};
+
+/// Whether the connection to the database is encrypted, and how far the server is trusted, as
+/// with libpq's sslmode
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SslMode {
+    /// Never encrypted
+    Disable,
+    /// Encrypted only if the server insists
+    Allow,
+    /// Encrypted if the server supports it, as Postgres does by default
+    Prefer,
+    /// Always encrypted, trusting any certificate
+    Require,
+    /// Always encrypted, with a certificate signed by a trusted authority
+    VerifyCa,
+    /// Always encrypted, with a certificate signed by a trusted authority for the host connected to
+    VerifyFull
+}
+
+impl SslMode {
+
+    fn to_sqlx(self) -> PgSslMode {
+        match self {
+            Self::Disable => PgSslMode::Disable,
+            Self::Allow => PgSslMode::Allow,
+            Self::Prefer => PgSslMode::Prefer,
+            Self::Require => PgSslMode::Require,
+            Self::VerifyCa => PgSslMode::VerifyCa,
+            Self::VerifyFull => PgSslMode::VerifyFull
+        }
+    }
+}
+
+impl FromStr for SslMode {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "disable" => Ok(Self::Disable),
+            "allow" => Ok(Self::Allow),
+            "prefer" => Ok(Self::Prefer),
+            "require" => Ok(Self::Require),
+            "verify-ca" => Ok(Self::VerifyCa),
+            "verify-full" => Ok(Self::VerifyFull),
+            _ => Err(eyre!(
+                "Invalid value {} for ssl.mode. Valid values are: disable, allow, prefer, require, verify-ca, \
+                verify-full", value
+            ))
+        }
+    }
+}
+
+/// How the connection to the database is secured. Whatever is left unset is taken from the
+/// postgres_url, such as from ?sslmode=require, or else from the Postgres defaults. There is
+/// no client certificate, which sqlx 0.5 cannot present, so other keys are refused rather than
+/// ignored
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SslConfig {
+    /// As with --ssl-mode
+    #[serde(default)]
+    pub mode: Option<SslMode>,
+    /// The PEM file of the authorities trusted to sign the server's certificate, in place of the
+    /// system's. Only checked with verify-ca and verify-full
+    #[serde(default)]
+    pub root_cert: Option<PathBuf>
+}
+
+impl SslConfig {
+
+    /// Applies these settings to the options for connecting, checking first that the root
+    /// certificate can be read, so that a mistaken path is not reported as a failed handshake
+    pub fn apply(&self, mut options: PgConnectOptions) -> Result<PgConnectOptions> {
+        if let Some(mode) = self.mode {
+            options = options.ssl_mode(mode.to_sqlx());
+        }
+        if let Some(root_cert) = &self.root_cert {
+            std::fs::File::open(root_cert)
+                .map_err(|error| eyre!("Unable to read ssl.root_cert {}: {}", root_cert.display(), error))?;
+            options = options.ssl_root_cert(root_cert);
+        }
+        Ok(options)
+    }
+}
+
+/// What went wrong with the TLS handshake, put plainly, if that is why the database could not
+/// be connected to
+pub fn explain_handshake(error: &sqlx::Error) -> Option<&'static str> {
+    let message = match error {
+        sqlx::Error::Tls(error) => error.to_string(),
+        sqlx::Error::Io(error) => error.to_string(),
+        _ => return None
+    };
+    if message.contains("does not support TLS") {
+        Some("The server does not support SSL. Enable it on the server, or connect without it \
+              by setting ssl.mode, or --ssl-mode, to prefer or disable")
+    } else if message.contains("invalid certificate") || message.contains("invalid peer certificate") {
+        Some("The server's certificate could not be verified. Check that ssl.root_cert is set to \
+              the authority which signed it and, with verify-full, that the certificate is for the host \
+              in the postgres_url")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(ssl: &SslConfig, url: &str) -> Result<String> {
+        Ok(format!("{:?}", ssl.apply(url.parse()?)?))
+    }
+
+    #[test]
+    fn parse_ssl_mode() {
+        assert_eq!(SslMode::VerifyFull, "verify-full".parse().unwrap());
+        assert_eq!(SslMode::Disable, "disable".parse().unwrap());
+        assert!("verify_full".parse::<SslMode>().is_err());
+    }
+
+    #[test]
+    fn mode_overrides_url() -> Result<()> {
+        let url = "postgres://user@localhost/db?sslmode=disable";
+        assert!(options(&SslConfig::default(), url)?.contains("ssl_mode: Disable"));
+        let ssl = SslConfig { mode: Some(SslMode::VerifyCa), root_cert: None };
+        assert!(options(&ssl, url)?.contains("ssl_mode: VerifyCa"));
+        Ok(())
+    }
+
+    #[test]
+    fn root_cert_must_be_readable() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root_cert = tempdir.path().join("root.crt");
+        let ssl = SslConfig { mode: Some(SslMode::VerifyFull), root_cert: Some(root_cert.clone()) };
+        let error = options(&ssl, "postgres://localhost/db").unwrap_err().to_string();
+        assert!(error.starts_with("Unable to read ssl.root_cert"), "{}", error);
+        std::fs::write(&root_cert, "")?;
+        let options = options(&ssl, "postgres://localhost/db")?;
+        assert!(options.contains("root.crt"), "{}", options);
+        Ok(())
+    }
+}
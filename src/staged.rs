@@ -0,0 +1,182 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io::ErrorKind;
+use async_std::fs::{self, File, OpenOptions};
+use async_std::path::{Path, PathBuf};
+use eyre::{eyre, Result, WrapErr};
+use futures_util::StreamExt;
+
+/// An output file written under a temporary name in its directory, .<name>.tmp-<pid>, and
+/// renamed to its own name only once it is complete. A file with the final name is thus never
+/// half-written. The temporary file is removed if this is dropped before then, such as when
+/// writing fails.
+#[derive(Debug)]
+pub(crate) struct StagedFile {
+    path: PathBuf,
+    temp_path: PathBuf,
+    persisted: bool
+}
+
+impl StagedFile {
+
+    /// Creates the temporary file for the output file. Refused if another process is writing
+    /// the same output file, which is known by its temporary file
+    pub(crate) async fn create(path: &Path) -> Result<(Self, File)> {
+        let temp_path = temp_path(path, std::process::id())?;
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path).await
+            .wrap_err_with(|| format!("Unable to create output file {}", temp_path.display()))?;
+        let staged = Self { path: path.to_owned(), temp_path, persisted: false };
+        // Looked for after creating this one, so that of two processes starting together, at
+        // least one sees the other
+        if let Some((pid, other)) = staged.other_writer().await? {
+            return Err(eyre!(
+                "Another data-sifter (process {}) is writing {}. If it is no longer running, delete {}",
+                pid, staged.path.display(), other.display()
+            ));
+        }
+        Ok((staged, file))
+    }
+
+    /// Syncs the file to disk, then gives it its final name. If the temporary file cannot be
+    /// renamed since the final name is on another filesystem, it is copied there instead.
+    pub(crate) async fn persist(mut self, file: File) -> Result<()> {
+        file.sync_all().await.wrap_err_with(|| format!("Unable to write output file {}", self.path.display()))?;
+        drop(file);
+        match fs::rename(&self.temp_path, &self.path).await {
+            Ok(()) => (),
+            Err(error) if error.kind() == ErrorKind::CrossesDevices => {
+                fs::copy(&self.temp_path, &self.path).await
+                    .wrap_err_with(|| format!("Unable to copy output file to {}", self.path.display()))?;
+                OpenOptions::new().write(true).open(&self.path).await?.sync_all().await?;
+                fs::remove_file(&self.temp_path).await?;
+            },
+            Err(error) => return Err(error)
+                .wrap_err_with(|| format!("Unable to rename output file to {}", self.path.display()))
+        }
+        self.persisted = true;
+        Ok(())
+    }
+
+    /// The temporary file of another process writing the same output file, along with the
+    /// process. Those of processes which are no longer running are removed
+    async fn other_writer(&self) -> Result<Option<(u32, PathBuf)>> {
+        let directory = match self.temp_path.parent().expect("Temporary files are in a directory") {
+            directory if directory.as_os_str().is_empty() => Path::new("."),
+            directory => directory
+        };
+        let prefix = temp_prefix(&self.path)?;
+        let mut entries = fs::read_dir(directory).await?;
+        while let Some(entry) = entries.next().await {
+            let path = entry?.path();
+            let pid = path.file_name()
+                .and_then(|name| name.to_str()?.strip_prefix(&prefix)?.parse::<u32>().ok());
+            match pid {
+                Some(pid) if pid != std::process::id() => {
+                    if is_running(pid).await {
+                        return Ok(Some((pid, path)));
+                    }
+                    // Left by a process which was killed while writing
+                    fs::remove_file(&path).await.ok();
+                },
+                _ => ()
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            // Nothing more can be done if it cannot be removed
+            std::fs::remove_file(&self.temp_path).ok();
+        }
+    }
+}
+
+/// The start of the names of temporary files for the output file, up to the process id
+fn temp_prefix(path: &Path) -> Result<String> {
+    let name = path.file_name().ok_or_else(|| eyre!("Output file {} has no file name", path.display()))?;
+    Ok(format!(".{}.tmp-", name.to_string_lossy()))
+}
+
+fn temp_path(path: &Path, pid: u32) -> Result<PathBuf> {
+    let name = format!("{}{}", temp_prefix(path)?, pid);
+    Ok(match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory.join(name),
+        _ => PathBuf::from(name)
+    })
+}
+
+/// Whether the process is running. Without /proc to tell, it is assumed to be
+async fn is_running(pid: u32) -> bool {
+    let proc = Path::new("/proc");
+    !proc.exists().await || proc.join(pid.to_string()).exists().await
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::WriteExt;
+    use super::*;
+
+    #[async_std::test]
+    async fn persist_or_remove() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = PathBuf::from(directory.path().join("results.csv"));
+        let temp = PathBuf::from(directory.path().join(format!(".results.csv.tmp-{}", std::process::id())));
+
+        // Writing fails part of the way through
+        let (staged, mut file) = StagedFile::create(&path).await?;
+        file.write_all(b"a,b\n1,").await?;
+        assert!(temp.exists().await);
+        drop(staged);
+        assert!(!temp.exists().await);
+        assert!(!path.exists().await);
+
+        let (staged, mut file) = StagedFile::create(&path).await?;
+        file.write_all(b"a,b\n1,2\n").await?;
+        assert!(!path.exists().await);
+        staged.persist(file).await?;
+        assert_eq!("a,b\n1,2\n", fs::read_to_string(&path).await?);
+        assert!(!temp.exists().await);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn refuse_concurrent_writers() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let path = PathBuf::from(directory.path().join("results.csv"));
+        // The parent of this process is running, unlike process u32::MAX
+        let running = std::os::unix::process::parent_id();
+        fs::write(temp_path(&path, running)?, "").await?;
+        let stale = temp_path(&path, u32::MAX)?;
+        fs::write(&stale, "").await?;
+
+        let error = StagedFile::create(&path).await.unwrap_err();
+        let expected = format!("Another data-sifter (process {}) is writing", running);
+        assert!(error.to_string().contains(&expected), "{}", error);
+        fs::remove_file(temp_path(&path, running)?).await?;
+        let (staged, _) = StagedFile::create(&path).await?;
+        assert!(!stale.exists().await);
+        drop(staged);
+        Ok(())
+    }
+}
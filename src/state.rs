@@ -0,0 +1,232 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt::Write as _;
+use std::time::UNIX_EPOCH;
+use async_std::fs::{self, File};
+use async_std::io::prelude::{ReadExt, SeekExt};
+use async_std::io::SeekFrom;
+use async_std::path::{Path, PathBuf};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use eyre::WrapErr;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use crate::database;
+
+/// How many bytes at either end of a file are hashed for its fingerprint
+const SAMPLED_BYTES: u64 = 64 * 1024;
+
+/// Tells whether a file is the same as before without reading all of it: by its size, its
+/// modification time, and a hash of its first and last bytes
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Fingerprint {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+    sampled_sha256: String
+}
+
+impl Fingerprint {
+
+    pub(crate) async fn of(path: &Path) -> std::io::Result<Self> {
+        let metadata = fs::metadata(path).await?;
+        let modified = metadata.modified().ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let size = metadata.len();
+        let mut file = File::open(path).await?;
+        let mut head = Vec::new();
+        (&mut file).take(SAMPLED_BYTES).read_to_end(&mut head).await?;
+        // Bytes are hashed once, even if the two ends overlap
+        let mut tail = Vec::new();
+        if size > SAMPLED_BYTES {
+            file.seek(SeekFrom::Start(SAMPLED_BYTES.max(size - SAMPLED_BYTES))).await?;
+            file.take(SAMPLED_BYTES).read_to_end(&mut tail).await?;
+        }
+        Ok(Self {
+            size,
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+            sampled_sha256: sampled_hash(&head, &tail)
+        })
+    }
+}
+
+/// The SHA-256 hash of the first and last bytes of a file, in lowercase hexadecimal
+fn sampled_hash(head: &[u8], tail: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(head);
+    hasher.update(tail);
+    let mut hex = String::new();
+    for byte in hasher.finalize() {
+        write!(hex, "{:02x}", byte).expect("Writing to a String");
+    }
+    hex
+}
+
+/// A finished import of a file, which a later session can reuse rather than import it again
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RecordedImport {
+    /// The file, canonicalized
+    pub(crate) input: std::path::PathBuf,
+    pub(crate) table: String,
+    pub(crate) fingerprint: Fingerprint,
+    /// How many rows the table had once the import finished
+    pub(crate) rows: u64,
+    /// When the import finished, in seconds since the Unix epoch
+    pub(crate) finished_secs: i64
+}
+
+impl RecordedImport {
+
+    /// Describes the table, e.g. data_ab12 (1.2M rows from 14:03). The date is left out when
+    /// the import finished today
+    pub(crate) fn summary<Tz: TimeZone>(&self, timezone: &Tz, today: NaiveDate) -> String
+        where Tz::Offset: std::fmt::Display {
+        let finished = DateTime::<Utc>::from_timestamp(self.finished_secs, 0).unwrap_or_default()
+            .with_timezone(timezone);
+        let when = if finished.date_naive() == today {
+            finished.format("%H:%M")
+        } else {
+            finished.format("%Y-%m-%d %H:%M")
+        };
+        format!("{} ({} rows from {})", self.table, database::approximate_count(self.rows), when)
+    }
+}
+
+/// What data-sifter remembers from one session to the next
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SessionState {
+    #[serde(default)]
+    imports: Vec<RecordedImport>
+}
+
+impl SessionState {
+
+    /// Where the session state is kept
+    pub(crate) fn path_in(data_dir: &Path) -> PathBuf {
+        data_dir.join("session-state.ron")
+    }
+
+    pub(crate) async fn load(path: &Path) -> eyre::Result<Self> {
+        if !path.exists().await {
+            return Ok(Self::default());
+        }
+        let state = fs::read_to_string(path).await?;
+        ron::from_str(&state)
+            .wrap_err_with(|| format!("Invalid session state {:?}. Delete it to start over", path))
+    }
+
+    pub(crate) async fn save(&self, path: &Path) -> eyre::Result<()> {
+        // Replaced in one step, as checkpoints are, so that another session never reads half of it
+        let mut temp_path = path.as_os_str().to_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        fs::write(&temp_path, ron::to_string(self)?).await?;
+        fs::rename(&temp_path, path).await?;
+        Ok(())
+    }
+
+    /// The recorded import of the file, if it has not changed since
+    pub(crate) fn import_of(&self, input: &std::path::Path, fingerprint: &Fingerprint) -> Option<&RecordedImport> {
+        self.imports.iter().find(|import| import.input == input && &import.fingerprint == fingerprint)
+    }
+
+    /// Records the import in place of any earlier one of the same file, or into the same table,
+    /// which the table no longer holds
+    pub(crate) fn record(&mut self, import: RecordedImport) {
+        self.imports.retain(|earlier| earlier.input != import.input && earlier.table != import.table);
+        self.imports.push(import);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::FixedOffset;
+    use super::*;
+
+    fn recorded(input: &str, table: &str) -> RecordedImport {
+        RecordedImport {
+            input: std::path::PathBuf::from(input),
+            table: String::from(table),
+            fingerprint: Fingerprint {
+                size: 12, modified_secs: 1_700_000_000, modified_nanos: 0, sampled_sha256: sampled_hash(b"a,b\n", b"")
+            },
+            rows: 1_234_567,
+            finished_secs: 1_700_000_000
+        }
+    }
+
+    #[test]
+    fn find_unchanged_imports() {
+        let mut state = SessionState::default();
+        state.record(recorded("/data/sales.csv", "sales"));
+        let import = recorded("/data/sales.csv", "sales");
+        assert_eq!(Some(&import), state.import_of(&import.input, &import.fingerprint));
+        assert_eq!(None, state.import_of(std::path::Path::new("/data/other.csv"), &import.fingerprint));
+        let changed = Fingerprint { size: 13, ..import.fingerprint.clone() };
+        assert_eq!(None, state.import_of(&import.input, &changed));
+    }
+
+    #[test]
+    fn replace_imports_of_the_file_or_table() {
+        let mut state = SessionState::default();
+        state.record(recorded("/data/sales.csv", "sales"));
+        state.record(recorded("/data/people.csv", "people"));
+        state.record(recorded("/data/sales.csv", "sales_2"));
+        state.record(recorded("/data/orders.csv", "people"));
+        let tables: Vec<&str> = state.imports.iter().map(|import| import.table.as_str()).collect();
+        assert_eq!(vec!["sales_2", "people"], tables);
+        assert_eq!("/data/orders.csv", state.imports[1].input.to_string_lossy());
+    }
+
+    #[test]
+    fn describe_recorded_import() {
+        let import = recorded("/data/sales.csv", "data_ab12");
+        let timezone = FixedOffset::east_opt(3600).unwrap();
+        let today = NaiveDate::from_ymd_opt(2023, 11, 14).unwrap();
+        assert_eq!("data_ab12 (1.2M rows from 23:13)", import.summary(&timezone, today));
+        let tomorrow = today.succ_opt().unwrap();
+        assert_eq!("data_ab12 (1.2M rows from 2023-11-14 23:13)", import.summary(&timezone, tomorrow));
+    }
+
+    #[test]
+    fn hash_both_ends() {
+        assert_eq!(sampled_hash(b"hello world", b""), sampled_hash(b"hello ", b"world"));
+        assert_ne!(sampled_hash(b"hello", b""), sampled_hash(b"help", b""));
+    }
+
+    #[async_std::test]
+    async fn fingerprint_changes_with_the_file() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = PathBuf::from(dir.path().join("sales.csv"));
+        let large = "x".repeat(3 * SAMPLED_BYTES as usize);
+        fs::write(&path, &large).await?;
+        let first = Fingerprint::of(&path).await?;
+        assert_eq!(first, Fingerprint::of(&path).await?);
+        // The same size and modification time, with other bytes at the end
+        let mut changed = large.clone();
+        changed.replace_range(changed.len() - 1.., "y");
+        fs::write(&path, &changed).await?;
+        let file = std::fs::File::options().write(true).open(&path)?;
+        file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::new(first.modified_secs, first.modified_nanos))?;
+        let second = Fingerprint::of(&path).await?;
+        assert_eq!((first.size, first.modified_secs), (second.size, second.modified_secs));
+        assert_ne!(first.sampled_sha256, second.sampled_sha256);
+        Ok(())
+    }
+}
@@ -0,0 +1,326 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// The first words of statements which change or drop existing data
+const DESTRUCTIVE: [&str; 5] = ["alter", "delete", "drop", "truncate", "update"];
+
+/// Splits the text into the statements separated by its semicolons, trimmed, leaving out those
+/// with nothing but whitespace and comments. Semicolons within string literals, quoted
+/// identifiers, dollar-quoted strings and comments do not separate statements.
+pub(crate) fn split(text: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+    // Whether the statement so far has more than whitespace and comments
+    let mut has_code = false;
+    scan(text, |index, byte| {
+        if byte == b';' {
+            if has_code {
+                statements.push(text[start..index].trim());
+            }
+            start = index + 1;
+            has_code = false;
+        } else {
+            has_code = true;
+        }
+    });
+    if has_code {
+        statements.push(text[start..].trim());
+    }
+    statements
+}
+
+/// How many parameters the statement takes, which is the highest of its $n placeholders.
+/// Placeholders within string literals, quoted identifiers and comments are not counted
+pub(crate) fn parameter_count(statement: &str) -> usize {
+    let bytes = statement.as_bytes();
+    let mut count = 0;
+    scan(statement, |index, byte| {
+        if byte == b'$' && (index == 0 || !is_identifier_byte(bytes[index - 1])) {
+            let digits = statement[index + 1..].split(|c: char| !c.is_ascii_digit()).next().unwrap_or_default();
+            if let Ok(number) = digits.parse::<usize>() {
+                count = count.max(number);
+            }
+        }
+    });
+    count
+}
+
+/// Visits the index and byte starting each token of the text, skipping whitespace and
+/// comments. Strings, quoted identifiers and dollar-quoted strings are visited only at their
+/// opening quote, so a semicolon visited always separates statements
+fn scan(text: &str, mut visit: impl FnMut(usize, u8)) {
+    let bytes = text.as_bytes();
+    let mut index = 0;
+    while index < bytes.len() {
+        let next = bytes.get(index + 1).copied();
+        let end = match bytes[index] {
+            b'\'' => {
+                // Backslashes only escape within E'...' strings
+                let escapes = index > 0 && bytes[index - 1].eq_ignore_ascii_case(&b'e')
+                    && (index < 2 || !is_identifier_byte(bytes[index - 2]));
+                end_of_quoted(bytes, index, b'\'', escapes)
+            },
+            b'"' => end_of_quoted(bytes, index, b'"', false),
+            b'-' if next == Some(b'-') => {
+                index = bytes[index..].iter().position(|byte| *byte == b'\n')
+                    .map_or(bytes.len(), |offset| index + offset);
+                continue;
+            },
+            b'/' if next == Some(b'*') => {
+                index = end_of_block_comment(bytes, index);
+                continue;
+            },
+            b'$' if index == 0 || !is_identifier_byte(bytes[index - 1]) => match dollar_tag(&text[index..]) {
+                Some(tag) => text[index + tag.len()..].find(tag)
+                    .map_or(bytes.len(), |offset| index + tag.len() + offset + tag.len()),
+                None => index + 1
+            },
+            byte if byte.is_ascii_whitespace() => {
+                index += 1;
+                continue;
+            },
+            _ => index + 1
+        };
+        visit(index, bytes[index]);
+        index = end;
+    }
+}
+
+/// Whether the statement orders its rows with an ORDER BY of its own. Those of subqueries,
+/// window definitions and aggregates are within parentheses, so they do not count
+pub(crate) fn is_ordered(statement: &str) -> bool {
+    top_level_words(statement).windows(2).any(|pair| pair[0] == "order" && pair[1] == "by")
+}
+
+/// Whether the statement limits how many rows it returns with a LIMIT or FETCH of its own.
+/// Those of subqueries and of the queries of a WITH are within parentheses, so they do not
+/// count, and neither does LIMIT ALL
+pub(crate) fn is_limited(statement: &str) -> bool {
+    let words = top_level_words(statement);
+    words.iter().enumerate().any(|(index, word)| match word.as_str() {
+        "limit" => words.get(index + 1).is_none_or(|next| next != "all"),
+        "fetch" => true,
+        _ => false
+    })
+}
+
+/// The words of the statement outside parentheses, in lower case, leaving out those within
+/// strings, quoted identifiers and comments
+fn top_level_words(statement: &str) -> Vec<String> {
+    let bytes = statement.as_bytes();
+    let mut depth = 0_usize;
+    let mut words = Vec::new();
+    scan(statement, |index, byte| match byte {
+        b'(' => depth += 1,
+        b')' => depth = depth.saturating_sub(1),
+        _ if depth == 0 && is_identifier_byte(byte) && (index == 0 || !is_identifier_byte(bytes[index - 1])) => {
+            let length = bytes[index..].iter().position(|byte| !is_identifier_byte(*byte))
+                .unwrap_or(bytes.len() - index);
+            words.push(statement[index..index + length].to_ascii_lowercase());
+        },
+        _ => ()
+    });
+    words
+}
+
+/// Whether the statement changes or drops existing data, judged by its first word. A statement
+/// with common table expressions is judged by the one they precede, and by those within them,
+/// which may change data too
+pub(crate) fn is_destructive(statement: &str) -> bool {
+    let words = top_level_words(statement);
+    match words.first().map(String::as_str) {
+        Some("with") => {
+            main_command(&words[1..]).is_some_and(|command| DESTRUCTIVE.contains(&command))
+                || parenthesized(statement).into_iter().any(is_destructive)
+        },
+        first_word => first_word.is_some_and(|word| DESTRUCTIVE.contains(&word))
+    }
+}
+
+/// The command which the common table expressions are followed by, given the words after WITH.
+/// Each is named, then AS, perhaps [NOT] MATERIALIZED, and its statement in parentheses
+fn main_command(words: &[String]) -> Option<&str> {
+    let mut index = usize::from(words.first().is_some_and(|word| word == "recursive"));
+    while words.get(index + 1).is_some_and(|word| word == "as") {
+        index += 2;
+        while words.get(index).is_some_and(|word| word == "not" || word == "materialized") {
+            index += 1;
+        }
+    }
+    words.get(index).map(String::as_str)
+}
+
+/// The text within each pair of parentheses outside any other, leaving out those within
+/// strings, quoted identifiers and comments
+fn parenthesized(statement: &str) -> Vec<&str> {
+    let mut depth = 0_usize;
+    let mut start = 0;
+    let mut groups = Vec::new();
+    scan(statement, |index, byte| match byte {
+        b'(' => {
+            if depth == 0 {
+                start = index + 1;
+            }
+            depth += 1;
+        },
+        b')' if depth > 0 => {
+            depth -= 1;
+            if depth == 0 {
+                groups.push(&statement[start..index]);
+            }
+        },
+        _ => ()
+    });
+    groups
+}
+
+fn is_identifier_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'$'
+}
+
+/// Where the string or identifier starting at the index ends, just past its closing quote. A
+/// doubled quote stands for one quote
+fn end_of_quoted(bytes: &[u8], start: usize, quote: u8, escapes: bool) -> usize {
+    let mut index = start + 1;
+    while index < bytes.len() {
+        match bytes[index] {
+            byte if byte == quote && bytes.get(index + 1) == Some(&quote) => index += 2,
+            byte if byte == quote => return index + 1,
+            b'\\' if escapes => index += 2,
+            _ => index += 1
+        }
+    }
+    bytes.len()
+}
+
+/// Where the block comment starting at the index ends. Block comments may be nested
+fn end_of_block_comment(bytes: &[u8], start: usize) -> usize {
+    let mut depth = 0;
+    let mut index = start;
+    while index + 1 < bytes.len() {
+        match (bytes[index], bytes[index + 1]) {
+            (b'/', b'*') => {
+                depth += 1;
+                index += 2;
+            },
+            (b'*', b'/') => {
+                depth -= 1;
+                index += 2;
+                if depth == 0 {
+                    return index;
+                }
+            },
+            _ => index += 1
+        }
+    }
+    bytes.len()
+}
+
+/// The tag opening a dollar-quoted string at the start of the text, such as $$ or $body$
+fn dollar_tag(text: &str) -> Option<&str> {
+    let name_length = text[1..].find(|c: char| !c.is_ascii_alphanumeric() && c != '_')?;
+    let name = &text[1..1 + name_length];
+    let valid = name.chars().next().is_none_or(|c| !c.is_ascii_digit());
+    (valid && text[1 + name_length..].starts_with('$')).then(|| &text[..name_length + 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_on_semicolons() {
+        assert_eq!(vec!["SET work_mem = '256MB'", "SELECT 1"], split("SET work_mem = '256MB';\nSELECT 1;\n"));
+        assert_eq!(vec!["SELECT 1"], split("SELECT 1"));
+        assert_eq!(vec!["SELECT 1", "SELECT 2 -- last"],
+                   split(";; SELECT 1 ;\n\n; SELECT 2 -- last\n; -- nothing more"));
+        assert!(split("  ;\n").is_empty());
+    }
+
+    #[test]
+    fn keep_semicolons_within_quotes() {
+        assert_eq!(vec!["SELECT 'a;b', 'it''s;', \"odd;name\"", "SELECT 2"],
+                   split("SELECT 'a;b', 'it''s;', \"odd;name\"; SELECT 2"));
+        assert_eq!(vec![r"SELECT E'\';', 'c:\'", "SELECT 2"], split(r"SELECT E'\';', 'c:\'; SELECT 2"));
+        assert_eq!(vec!["SELECT 1 -- a; b\n+ 1 /* c; /* nested; */ d; */", "SELECT 2"],
+                   split("SELECT 1 -- a; b\n+ 1 /* c; /* nested; */ d; */; SELECT 2"));
+        // An unterminated string runs to the end
+        assert_eq!(vec!["SELECT 'a; SELECT 2"], split("SELECT 'a; SELECT 2"));
+    }
+
+    #[test]
+    fn keep_semicolons_within_dollar_quotes() {
+        let function = "CREATE FUNCTION one() RETURNS int AS $$ BEGIN RETURN 1; END; $$ LANGUAGE plpgsql";
+        assert_eq!(vec![function, "SELECT one()"], split(&format!("{};\nSELECT one();", function)));
+        let tagged = "DO $body$ BEGIN RAISE NOTICE '$$;'; END $body$";
+        assert_eq!(vec![tagged, "SELECT 2"], split(&format!("{}; SELECT 2", tagged)));
+        // Parameters and identifiers containing $ are not tags
+        assert_eq!(vec!["SELECT $1, a$b$", "SELECT 2"], split("SELECT $1, a$b$; SELECT 2"));
+    }
+
+    #[test]
+    fn count_parameters() {
+        assert_eq!(0, parameter_count("SELECT * FROM sales"));
+        assert_eq!(2, parameter_count("SELECT * FROM sales WHERE region = $1 AND amount::numeric > $2"));
+        assert_eq!(10, parameter_count("SELECT $10, $1, $1"));
+        // Placeholders which are not parameters
+        assert_eq!(1, parameter_count("SELECT '$2', \"$3\", a$4, $$ $5 $$ -- $6\n /* $7 */ WHERE id = $1"));
+    }
+
+    #[test]
+    fn find_top_level_order_by() {
+        assert!(is_ordered("SELECT * FROM sales ORDER BY id"));
+        assert!(is_ordered("select region, sum(amount) from sales group by region order\n  by 2 desc limit 5"));
+        assert!(is_ordered("WITH recent AS (SELECT * FROM sales) SELECT * FROM recent ORDER BY id"));
+        assert!(!is_ordered("SELECT * FROM (SELECT * FROM sales ORDER BY id) AS ordered"));
+        assert!(!is_ordered("SELECT id, rank() OVER (ORDER BY amount), string_agg(x, ',' ORDER BY x) FROM sales"));
+        assert!(!is_ordered("SELECT 'ORDER BY', \"order by\" FROM sales -- ORDER BY id"));
+        assert!(!is_ordered("SELECT border, bylaw FROM sales"));
+    }
+
+    #[test]
+    fn limited_statements() {
+        assert!(is_limited("SELECT * FROM sales ORDER BY id LIMIT 10;"));
+        assert!(is_limited("select * from sales limit 10 offset 20"));
+        assert!(is_limited("SELECT * FROM sales FETCH FIRST 10 ROWS ONLY"));
+        assert!(is_limited("SELECT * FROM sales LIMIT (SELECT count(*) FROM regions) -- done"));
+        // A limit of a subquery or of a WITH query limits only that
+        assert!(!is_limited("SELECT * FROM sales WHERE id IN (SELECT id FROM recent LIMIT 5)"));
+        assert!(!is_limited("SELECT * FROM sales, (SELECT * FROM regions LIMIT 1) AS region"));
+        assert!(!is_limited("WITH recent AS (SELECT * FROM sales LIMIT 5) SELECT * FROM recent, sales"));
+        assert!(!is_limited("SELECT 'LIMIT 1', \"limit\" FROM sales /* LIMIT 1 */"));
+        assert!(!is_limited("SELECT * FROM sales LIMIT ALL"));
+    }
+
+    #[test]
+    fn recognize_destructive_statements() {
+        assert!(is_destructive("DROP TABLE sales"));
+        assert!(is_destructive("  delete FROM sales WHERE id = 1"));
+        assert!(is_destructive("Truncate sales"));
+        assert!(!is_destructive("SET work_mem = '256MB'"));
+        assert!(!is_destructive("CREATE EXTENSION IF NOT EXISTS tablefunc"));
+        assert!(!is_destructive("SELECT * FROM dropped"));
+        // Common table expressions are looked past, and into
+        assert!(is_destructive("WITH old AS (SELECT id FROM sales WHERE day < '2020-01-01') DELETE FROM sales \
+                               WHERE id IN (SELECT id FROM old)"));
+        assert!(is_destructive("WITH RECURSIVE a (n) AS (SELECT 1), b AS MATERIALIZED (SELECT 2) UPDATE sales SET n = 0"));
+        assert!(is_destructive("WITH gone AS (DELETE FROM sales RETURNING *) SELECT count(*) FROM gone"));
+        assert!(is_destructive("/* archive */ WITH gone AS NOT MATERIALIZED (\n  update sales SET n = 0 RETURNING *\n) TABLE gone"));
+        assert!(!is_destructive("WITH totals AS (SELECT store, sum(amount) FROM sales GROUP BY store) SELECT * FROM totals"));
+        assert!(!is_destructive("WITH deleted AS (SELECT 'drop' AS word) SELECT * FROM deleted WHERE word IN ('update')"));
+    }
+}
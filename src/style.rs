@@ -0,0 +1,85 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::borrow::Cow;
+
+/// Terminal styling for messages
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Style {
+    /// Questions asked of the user
+    Prompt,
+    /// Errors and warnings
+    Error,
+    /// Noteworthy facts, such as result summaries
+    Highlight
+}
+
+impl Style {
+    fn escape_code(self) -> &'static str {
+        match self {
+            Self::Prompt => "\x1b[1m",
+            Self::Error => "\x1b[1;31m",
+            Self::Highlight => "\x1b[36m"
+        }
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+
+/// Whether to color output written to a terminal. Color is disabled by the --no-color flag,
+/// by the NO_COLOR environment variable (see https://no-color.org), and when not writing to a terminal.
+pub fn use_color(no_color_flag: bool, is_terminal: bool) -> bool {
+    let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    is_terminal && !no_color_flag && !no_color_env
+}
+
+/// Applies a style to text, if color is enabled. Each line is styled separately
+/// so that escape codes never span a line break.
+pub fn paint(color: bool, style: Style, text: &str) -> Cow<'_, str> {
+    if !color || text.is_empty() {
+        return Cow::Borrowed(text);
+    }
+    let painted = text
+        .split('\n')
+        .map(|line| {
+            if line.is_empty() { String::new() } else { format!("{}{}{}", style.escape_code(), line, RESET) }
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    Cow::Owned(painted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_color_is_unchanged() {
+        assert!(matches!(paint(false, Style::Prompt, "Enter"), Cow::Borrowed("Enter")));
+    }
+
+    #[test]
+    fn paint_each_line() {
+        assert_eq!("\x1b[1mOne\x1b[0m\n\n\x1b[1mTwo\x1b[0m", paint(true, Style::Prompt, "One\n\nTwo"));
+    }
+
+    #[test]
+    fn color_needs_terminal() {
+        assert!(!use_color(false, false));
+        assert!(!use_color(true, true));
+    }
+}
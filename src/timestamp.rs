@@ -0,0 +1,167 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt::{Display, Write};
+use std::str::FromStr;
+use chrono::{DateTime, Local, SecondsFormat, TimeZone, Utc};
+use chrono::format::{Item, StrftimeItems};
+use eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+
+/// The timezone in which timestamptz values are written. Plain timestamps, which lack a
+/// time zone, are never converted.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Timezone {
+    #[default]
+    Utc,
+    /// The timezone of the computer running data-sifter
+    Local,
+    /// An IANA timezone, such as America/New_York
+    Named(chrono_tz::Tz)
+}
+
+impl FromStr for Timezone {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self> {
+        if value.eq_ignore_ascii_case("UTC") {
+            Ok(Self::Utc)
+        } else if value.eq_ignore_ascii_case("local") {
+            Ok(Self::Local)
+        } else {
+            value.parse().map(Self::Named).map_err(|_| eyre!(
+                "Invalid timezone {}. Use UTC, local or an IANA timezone such as America/New_York", value
+            ))
+        }
+    }
+}
+
+impl TryFrom<String> for Timezone {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<Timezone> for String {
+    fn from(timezone: Timezone) -> Self {
+        match timezone {
+            Timezone::Utc => String::from("UTC"),
+            Timezone::Local => String::from("local"),
+            Timezone::Named(timezone) => String::from(timezone.name())
+        }
+    }
+}
+
+/// A strftime-style format for timestamptz values, such as %Y-%m-%d %H:%M. Plain timestamps
+/// are written as they are
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimestampFormat(String);
+
+impl FromStr for TimestampFormat {
+    type Err = eyre::Report;
+
+    fn from_str(value: &str) -> Result<Self> {
+        if StrftimeItems::new(value).any(|item| matches!(item, Item::Error)) {
+            return Err(eyre!("Invalid timestamp format {}. See the strftime syntax, e.g. %Y-%m-%d %H:%M", value));
+        }
+        Ok(Self(String::from(value)))
+    }
+}
+
+impl TryFrom<String> for TimestampFormat {
+    type Error = eyre::Report;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<TimestampFormat> for String {
+    fn from(format: TimestampFormat) -> Self {
+        format.0
+    }
+}
+
+/// Formats a timestamptz value in the given timezone. Without a format, values are written
+/// in ISO-8601, e.g. 2022-03-13T07:00:00Z
+pub(crate) fn format_timestamptz(value: &DateTime<Utc>, timezone: Timezone,
+                                 format: Option<&TimestampFormat>) -> String {
+    match timezone {
+        Timezone::Utc => format_zoned(value, format),
+        Timezone::Local => format_zoned(&value.with_timezone(&Local), format),
+        Timezone::Named(timezone) => format_zoned(&value.with_timezone(&timezone), format)
+    }
+}
+
+fn format_zoned<Tz>(value: &DateTime<Tz>, format: Option<&TimestampFormat>) -> String
+    where Tz: TimeZone, Tz::Offset: Display {
+
+    if let Some(format) = format {
+        let mut formatted = String::new();
+        if write!(formatted, "{}", value.format(&format.0)).is_ok() {
+            return formatted;
+        }
+    }
+    value.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instant(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn utc_by_default() {
+        assert_eq!("2022-03-13T06:59:59Z",
+                   format_timestamptz(&instant("2022-03-13T06:59:59Z"), Timezone::default(), None));
+        assert_eq!("2022-03-13T06:59:59.250Z",
+                   format_timestamptz(&instant("2022-03-13T06:59:59.25Z"), Timezone::Utc, None));
+    }
+
+    #[test]
+    fn named_timezone_across_daylight_saving() {
+        let new_york = "America/New_York".parse().unwrap();
+        assert_eq!("2022-03-13T01:59:59-05:00",
+                   format_timestamptz(&instant("2022-03-13T06:59:59Z"), new_york, None));
+        assert_eq!("2022-03-13T03:00:00-04:00",
+                   format_timestamptz(&instant("2022-03-13T07:00:00Z"), new_york, None));
+    }
+
+    #[test]
+    fn custom_format() {
+        let format = "%Y-%m-%d %H:%M".parse().unwrap();
+        let tokyo = "Asia/Tokyo".parse().unwrap();
+        assert_eq!("2022-03-13 16:00",
+                   format_timestamptz(&instant("2022-03-13T07:00:00Z"), tokyo, Some(&format)));
+        assert!("%Y-%Q".parse::<TimestampFormat>().is_err());
+    }
+
+    #[test]
+    fn parse_timezones() {
+        assert_eq!(Timezone::Utc, "utc".parse().unwrap());
+        assert_eq!(Timezone::Local, "local".parse().unwrap());
+        assert_eq!("Europe/Paris", String::from("Europe/Paris".parse::<Timezone>().unwrap()));
+        assert!("Mars/Olympus_Mons".parse::<Timezone>().is_err());
+    }
+}
@@ -0,0 +1,131 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt;
+use std::time::Duration;
+use serde::Serialize;
+
+/// How long an import spent in each of its stages, so that a slow one can be told apart. Each
+/// stage is timed once per chunk or batch, rather than per row, which costs next to nothing.
+/// Chunks are parsed by several tasks at once, whose times are added up, so the stages may add
+/// up to more than the import took
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct StageTimings {
+    /// Reading the dataset and parsing its records
+    pub(crate) read: Duration,
+    /// Transforming, filtering, checking and profiling the records
+    pub(crate) transform: Duration,
+    /// Sending the rows to the database and waiting for it to write them
+    pub(crate) database: Duration,
+    pub(crate) commit: Duration
+}
+
+/// The seconds of each stage, as written in the run report
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct StageSeconds {
+    read_parse: f64,
+    transform: f64,
+    database: f64,
+    commit: f64
+}
+
+impl StageTimings {
+
+    /// Adds the times of a chunk, or of another task, to these
+    pub(crate) fn merge(&mut self, other: &Self) {
+        self.read += other.read;
+        self.transform += other.transform;
+        self.database += other.database;
+        self.commit += other.commit;
+    }
+
+    fn total(&self) -> Duration {
+        self.read + self.transform + self.database + self.commit
+    }
+
+    pub(crate) fn seconds(&self) -> StageSeconds {
+        StageSeconds {
+            read_parse: self.read.as_secs_f64(),
+            transform: self.transform.as_secs_f64(),
+            database: self.database.as_secs_f64(),
+            commit: self.commit.as_secs_f64()
+        }
+    }
+}
+
+impl fmt::Display for StageTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total().as_secs_f64();
+        let stages = [
+            ("reading and parsing", self.read),
+            ("transforming", self.transform),
+            ("the database", self.database),
+            ("committing", self.commit)
+        ];
+        for (index, (stage, time)) in stages.iter().enumerate() {
+            if index != 0 {
+                f.write_str(", ")?;
+            }
+            let share = if total > 0.0 { 100.0 * time.as_secs_f64() / total } else { 0.0 };
+            write!(f, "{} {:.2}s ({:.0}%)", stage, time.as_secs_f64(), share)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timings(read: u64, transform: u64, database: u64, commit: u64) -> StageTimings {
+        StageTimings {
+            read: Duration::from_millis(read),
+            transform: Duration::from_millis(transform),
+            database: Duration::from_millis(database),
+            commit: Duration::from_millis(commit)
+        }
+    }
+
+    #[test]
+    fn merge_adds_each_stage() {
+        let mut merged = StageTimings::default();
+        merged.merge(&timings(100, 20, 0, 0));
+        merged.merge(&timings(50, 5, 300, 0));
+        merged.merge(&timings(0, 0, 200, 25));
+        assert_eq!(timings(150, 25, 500, 25), merged);
+        merged.merge(&StageTimings::default());
+        assert_eq!(timings(150, 25, 500, 25), merged);
+    }
+
+    #[test]
+    fn describe_stages() {
+        assert_eq!(
+            "reading and parsing 1.20s (60%), transforming 0.20s (10%), the database 0.50s (25%), committing 0.10s (5%)",
+            timings(1200, 200, 500, 100).to_string()
+        );
+        assert_eq!(
+            "reading and parsing 0.00s (0%), transforming 0.00s (0%), the database 0.00s (0%), committing 0.00s (0%)",
+            StageTimings::default().to_string()
+        );
+    }
+
+    #[test]
+    fn seconds_of_stages() {
+        let json = serde_json::to_value(timings(1500, 250, 0, 10).seconds()).unwrap();
+        assert_eq!(serde_json::json!({"read_parse": 1.5, "transform": 0.25, "database": 0.0, "commit": 0.01}), json);
+    }
+}
@@ -0,0 +1,201 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use eyre::{eyre, Result};
+use sqlx::{Postgres, pool::PoolConnection};
+use crate::database::{column_argument, column_identifier, find_column, split_arguments};
+use crate::error::{self, Error};
+
+/// How many values are listed unless stated otherwise
+const DEFAULT_LIMIT: u64 = 20;
+
+/// The most common values of a column, written as `top <column> [N]`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TopValues {
+    pub(crate) table: String,
+    pub(crate) column: String,
+    pub(crate) limit: u64,
+    /// Whether to add the cumulative percentage of all rows
+    pub(crate) cumulative: bool,
+    /// Whether to list NULL and empty values as groups of their own, labeled (NULL) and
+    /// (empty), after the most common values rather than among them
+    pub(crate) group_blanks: bool
+}
+
+impl TopValues {
+
+    /// Parses the arguments following `top`. The column may be named in double quotes
+    pub(crate) fn parse(table: String, arguments: &str) -> Result<Self> {
+        let arguments = split_arguments(arguments, &[])?;
+        let mut arguments = arguments.iter();
+        let column = arguments.next().ok_or_else(|| eyre!("Usage: top <column> [N]"))?;
+        let limit = match arguments.next() {
+            Some(limit) => limit.parse().ok().filter(|limit| *limit > 0)
+                .ok_or_else(|| eyre!("Invalid number of values: {}", limit))?,
+            None => DEFAULT_LIMIT
+        };
+        if let Some(extra) = arguments.next() {
+            return Err(eyre!("Unexpected argument {}. Usage: top <column> [N]", extra));
+        }
+        Ok(Self {
+            table,
+            column: column_argument(column)?,
+            limit,
+            cumulative: false,
+            group_blanks: false
+        })
+    }
+
+    /// Finds the column in the table, then generates the query with the name it has there
+    pub(crate) async fn to_sql(&self, connection: &mut PoolConnection<Postgres>) -> error::Result<String> {
+        let columns: Vec<(String, bool)> = sqlx::query_as("\
+            SELECT attribute.attname::text, type.typcategory = 'S' \
+            FROM pg_catalog.pg_attribute attribute \
+            JOIN pg_catalog.pg_type type ON type.oid = attribute.atttypid \
+            WHERE attribute.attrelid = to_regclass($1) AND attribute.attnum > 0 AND NOT attribute.attisdropped \
+            ORDER BY attribute.attnum")
+            .bind(&self.table)
+            .fetch_all(connection).await
+            .map_err(|error| Error::from_database(error, Error::QueryRuntime))?;
+        let (column, is_text) = find_column(&self.table, &self.column, &columns, |(column, _)| column)?;
+        let resolved = Self { column: column.clone(), ..self.clone() };
+        Ok(resolved.sql_for(*is_text))
+    }
+
+    fn sql_for(&self, is_text: bool) -> String {
+        if self.group_blanks {
+            return self.sql_with_blanks(is_text);
+        }
+        let column = column_identifier(&self.column);
+        let mut sql = format!(
+            "SELECT {0} AS {0}, count(*) AS count,\n    round(100.0 * count(*) / sum(count(*)) OVER (), 2) AS percent",
+            column
+        );
+        if self.cumulative {
+            sql.push_str(&format!(
+                ",\n    round(100.0 * sum(count(*)) OVER (ORDER BY count(*) DESC, {} ROWS UNBOUNDED PRECEDING) \
+                / sum(count(*)) OVER (), 2) AS cumulative_percent", column
+            ));
+        }
+        sql.push_str(&format!(
+            "\nFROM {}\nGROUP BY 1\nORDER BY count DESC, 1\nLIMIT {}", self.table, self.limit
+        ));
+        sql
+    }
+
+    /// Ranks the values other than NULL and empty ones, which are counted apart and labeled,
+    /// since both would otherwise be written as nothing. Percentages are still of all rows
+    fn sql_with_blanks(&self, is_text: bool) -> String {
+        let column = column_identifier(&self.column);
+        let (blank, label) = if is_text {
+            (
+                format!("{0} IS NULL OR {0} = ''", column),
+                "CASE WHEN value IS NULL THEN '(NULL)' WHEN value = '' THEN '(empty)' ELSE value::text END"
+            )
+        } else {
+            (format!("{} IS NULL", column), "CASE WHEN value IS NULL THEN '(NULL)' ELSE value::text END")
+        };
+        let mut sql = format!("SELECT {} AS {}, count, percent", label, column);
+        if self.cumulative {
+            sql.push_str(", CASE WHEN NOT blank THEN cumulative_percent END AS cumulative_percent");
+        }
+        sql.push_str(&format!(
+            "\nFROM (\n\
+            SELECT {column} AS value, count(*) AS count,\n    \
+            round(100.0 * count(*) / sum(count(*)) OVER (), 2) AS percent,\n    \
+            round(100.0 * sum(count(*)) OVER (PARTITION BY {blank} ORDER BY count(*) DESC, {column} ROWS UNBOUNDED PRECEDING) \
+            / sum(count(*)) OVER (), 2) AS cumulative_percent,\n    \
+            {blank} AS blank,\n    \
+            row_number() OVER (PARTITION BY {blank} ORDER BY count(*) DESC, {column} NULLS FIRST) AS rank\n\
+            FROM {table}\nGROUP BY {column}\n\
+            ) AS counted\nWHERE blank OR rank <= {limit}\nORDER BY blank, rank",
+            column = column, blank = blank, table = self.table, limit = self.limit
+        ));
+        sql
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_top() {
+        let top = TopValues::parse(String::from("data"), "Country 50").unwrap();
+        assert_eq!(("Country", 50), (top.column.as_str(), top.limit));
+        let top = TopValues::parse(String::from("data"), "\"Order ID\" 5").unwrap();
+        assert_eq!(("Order ID", 5), (top.column.as_str(), top.limit));
+        assert_eq!(DEFAULT_LIMIT, TopValues::parse(String::from("data"), " country ").unwrap().limit);
+        assert!(TopValues::parse(String::from("data"), "").is_err());
+        assert!(TopValues::parse(String::from("data"), "country 0").is_err());
+        assert!(TopValues::parse(String::from("data"), "country 5 6").is_err());
+        assert!(TopValues::parse(String::from("data"), "\"country").is_err());
+    }
+
+    #[test]
+    fn top_sql() {
+        let mut top = TopValues::parse(String::from("data"), "country 5").unwrap();
+        top.cumulative = true;
+        assert_eq!("\
+SELECT country AS country, count(*) AS count,
+    round(100.0 * count(*) / sum(count(*)) OVER (), 2) AS percent,
+    round(100.0 * sum(count(*)) OVER (ORDER BY count(*) DESC, country ROWS UNBOUNDED PRECEDING) \
+/ sum(count(*)) OVER (), 2) AS cumulative_percent
+FROM data
+GROUP BY 1
+ORDER BY count DESC, 1
+LIMIT 5", top.sql_for(true));
+    }
+
+    #[test]
+    fn top_sql_with_blanks() {
+        let mut top = TopValues::parse(String::from("data"), "country 5").unwrap();
+        top.group_blanks = true;
+        assert_eq!("\
+SELECT CASE WHEN value IS NULL THEN '(NULL)' WHEN value = '' THEN '(empty)' ELSE value::text END AS country, count, percent
+FROM (
+SELECT country AS value, count(*) AS count,
+    round(100.0 * count(*) / sum(count(*)) OVER (), 2) AS percent,
+    round(100.0 * sum(count(*)) OVER (PARTITION BY country IS NULL OR country = '' ORDER BY count(*) DESC, country \
+ROWS UNBOUNDED PRECEDING) / sum(count(*)) OVER (), 2) AS cumulative_percent,
+    country IS NULL OR country = '' AS blank,
+    row_number() OVER (PARTITION BY country IS NULL OR country = '' ORDER BY count(*) DESC, country NULLS FIRST) AS rank
+FROM data
+GROUP BY country
+) AS counted
+WHERE blank OR rank <= 5
+ORDER BY blank, rank", top.sql_for(true));
+        top.cumulative = true;
+        let sql = top.sql_for(false);
+        assert!(sql.starts_with("SELECT CASE WHEN value IS NULL THEN '(NULL)' ELSE value::text END AS country, count, percent, \
+            CASE WHEN NOT blank THEN cumulative_percent END AS cumulative_percent"), "{}", sql);
+        assert!(sql.contains("PARTITION BY country IS NULL ORDER BY"), "{}", sql);
+    }
+
+    #[test]
+    fn top_sql_quotes_column() {
+        let mut top = TopValues::parse(String::from("data"), "\"Order ID\"").unwrap();
+        top.group_blanks = true;
+        let sql = top.sql_for(false);
+        assert!(sql.starts_with(
+            "SELECT CASE WHEN value IS NULL THEN '(NULL)' ELSE value::text END AS \"Order ID\", count, percent"
+        ), "{}", sql);
+        assert!(sql.contains("GROUP BY \"Order ID\"\n"), "{}", sql);
+        top.group_blanks = false;
+        assert!(top.sql_for(false).starts_with("SELECT \"Order ID\" AS \"Order ID\", count(*)"));
+    }
+}
@@ -0,0 +1,322 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::borrow::Cow;
+use std::fmt;
+use csv_async::StringRecord;
+use eyre::{eyre, Result};
+use itertools::Itertools;
+use crate::database::Schema;
+
+/// Changes made to a column's values as they are imported, in the order declared, such as
+/// `email: trim, lower`. The values are changed before they are filtered, profiled and cast
+/// to the types of an existing table's columns.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ColumnTransforms {
+    column: String,
+    transforms: Vec<Transform>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Transform {
+    /// Removes whitespace at either end
+    Trim,
+    Lower,
+    Upper,
+    /// Empties values equal to the text. An empty value is NULL in a typed column of an
+    /// existing table, and empty text otherwise
+    NullifyIf(String)
+}
+
+/// The transforms of each column, found in the dataset's header
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct BoundTransforms(Vec<(usize, Vec<Transform>)>);
+
+/// How many values the transforms changed, in each column which has them
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct TransformCounts(Vec<u64>);
+
+impl std::str::FromStr for ColumnTransforms {
+    type Err = String;
+
+    fn from_str(declaration: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = |problem: &str| format!("Invalid transform {}: {}", declaration, problem);
+        let (column, list) = declaration.split_once(':')
+            .ok_or_else(|| invalid("use the form 'column: transform, transform'"))?;
+        let column = column.trim();
+        if column.is_empty() {
+            return Err(invalid("it names no column"));
+        }
+        let transforms = parse_list(list).map_err(|problem| invalid(&problem))?;
+        Ok(Self { column: column.to_owned(), transforms })
+    }
+}
+
+/// Reads transforms separated by commas, the text of nullify_if being quoted
+fn parse_list(list: &str) -> std::result::Result<Vec<Transform>, String> {
+    let mut transforms = Vec::new();
+    let mut rest = list.trim_start();
+    loop {
+        let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+        let (name, after) = rest.split_at(end);
+        let (transform, after) = match name.to_ascii_lowercase().as_str() {
+            "" => return Err(String::from("expected a transform: trim, lower, upper or nullify_if(\"text\")")),
+            "trim" => (Transform::Trim, after),
+            "lower" => (Transform::Lower, after),
+            "upper" => (Transform::Upper, after),
+            "nullify_if" => {
+                let (text, after) = parse_argument(after.trim_start())?;
+                (Transform::NullifyIf(text), after)
+            },
+            other => return Err(format!("unknown transform {}. Transforms are trim, lower, upper and nullify_if", other))
+        };
+        transforms.push(transform);
+        let after = after.trim_start();
+        if after.is_empty() {
+            return Ok(transforms);
+        }
+        rest = after.strip_prefix(',')
+            .ok_or_else(|| format!("expected a comma before {}", after))?
+            .trim_start();
+    }
+}
+
+/// Reads `("text")` or `('text')`, where the quote is written twice to include it
+fn parse_argument(text: &str) -> std::result::Result<(String, &str), String> {
+    let missing = || String::from("nullify_if needs a quoted string in parentheses, such as nullify_if(\"N/A\")");
+    let inner = text.strip_prefix('(').ok_or_else(missing)?.trim_start();
+    let mut chars = inner.char_indices().peekable();
+    let quote = match chars.next() {
+        Some((_, quote)) if quote == '"' || quote == '\'' => quote,
+        _ => return Err(missing())
+    };
+    let mut argument = String::new();
+    let end = loop {
+        match chars.next() {
+            Some((index, c)) if c == quote => match chars.next_if(|(_, next)| *next == quote) {
+                Some(_) => argument.push(quote),
+                None => break index + c.len_utf8()
+            },
+            Some((_, c)) => argument.push(c),
+            None => return Err(format!("missing closing {}", quote))
+        }
+    };
+    let after = inner[end..].trim_start().strip_prefix(')').ok_or_else(|| String::from("missing )"))?;
+    Ok((argument, after))
+}
+
+impl fmt::Display for Transform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Trim => f.write_str("trim"),
+            Self::Lower => f.write_str("lower"),
+            Self::Upper => f.write_str("upper"),
+            Self::NullifyIf(text) => write!(f, "nullify_if({:?})", text)
+        }
+    }
+}
+
+impl fmt::Display for ColumnTransforms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.column, self.transforms.iter().join(", "))
+    }
+}
+
+impl Transform {
+    fn apply<'v>(&self, value: Cow<'v, str>) -> Cow<'v, str> {
+        match self {
+            Self::Trim => match value {
+                Cow::Borrowed(value) => Cow::Borrowed(value.trim()),
+                Cow::Owned(value) => Cow::Owned(value.trim().to_owned())
+            },
+            Self::Lower if value.chars().any(char::is_uppercase) => Cow::Owned(value.to_lowercase()),
+            Self::Upper if value.chars().any(char::is_lowercase) => Cow::Owned(value.to_uppercase()),
+            Self::Lower | Self::Upper => value,
+            Self::NullifyIf(text) if value == text.as_str() => Cow::Borrowed(""),
+            Self::NullifyIf(_) => value
+        }
+    }
+}
+
+impl ColumnTransforms {
+
+    /// Finds each declaration's column in the dataset's header, ignoring case as Postgres
+    /// does. A column may only be declared once, so that the order of its transforms is clear
+    pub(crate) fn bind(declarations: &[Self], schema: &Schema) -> Result<BoundTransforms> {
+        let mut bound: Vec<(usize, Vec<Transform>)> = Vec::new();
+        for declaration in declarations {
+            let column = (0..schema.len())
+                .find(|index| schema.column_name(*index).eq_ignore_ascii_case(&declaration.column))
+                .ok_or_else(|| eyre!(
+                    "The transform {} names a column which is not in the CSV file. Its columns are: {}",
+                    declaration, (0..schema.len()).map(|index| schema.column_name(index)).join(", ")
+                ))?;
+            if bound.iter().any(|(other, _)| *other == column) {
+                return Err(eyre!(
+                    "Column {} has more than one --transform. List all of its transforms in one", declaration.column
+                ));
+            }
+            bound.push((column, declaration.transforms.clone()));
+        }
+        Ok(BoundTransforms(bound))
+    }
+}
+
+impl BoundTransforms {
+
+    /// Transforms the record's values, counting those which changed. The record is only copied
+    /// if one did
+    pub(crate) fn apply(&self, record: StringRecord, counts: &mut TransformCounts) -> StringRecord {
+        if counts.0.len() < self.0.len() {
+            counts.0.resize(self.0.len(), 0);
+        }
+        let mut changed = Vec::new();
+        for (position, (column, transforms)) in self.0.iter().enumerate() {
+            let value = record.get(*column).unwrap_or_default();
+            let transformed = transforms.iter().fold(Cow::Borrowed(value), |value, transform| transform.apply(value));
+            if transformed != value {
+                counts.0[position] += 1;
+                changed.push((*column, transformed.into_owned()));
+            }
+        }
+        if changed.is_empty() {
+            return record;
+        }
+        let mut transformed = StringRecord::with_capacity(record.as_slice().len(), record.len());
+        for (column, value) in record.iter().enumerate() {
+            match changed.iter().find(|(changed_column, _)| *changed_column == column) {
+                Some((_, changed_value)) => transformed.push_field(changed_value),
+                None => transformed.push_field(value)
+            }
+        }
+        transformed.set_position(record.position().cloned());
+        transformed
+    }
+
+    /// How many values were changed in each column which has transforms, by column name
+    pub(crate) fn changed_values(&self, counts: &TransformCounts, schema: &Schema) -> Vec<(String, u64)> {
+        self.0.iter().enumerate()
+            .map(|(position, (column, _))| {
+                (schema.column_name(*column).to_owned(), counts.0.get(position).copied().unwrap_or_default())
+            })
+            .collect()
+    }
+}
+
+impl TransformCounts {
+    pub(crate) fn extend(&mut self, other: &Self) {
+        if self.0.len() < other.0.len() {
+            self.0.resize(other.0.len(), 0);
+        }
+        self.0.iter_mut().zip(&other.0).for_each(|(count, other)| *count += other);
+    }
+}
+
+/// Describes how many values were changed, e.g. Transformed 3 values of email and 1 of country
+pub(crate) fn describe_changes(changed: &[(String, u64)]) -> String {
+    let counts = changed.iter().enumerate().map(|(index, (column, values))| match index {
+        0 => format!("{} value{} of {}", values, if *values == 1 { "" } else { "s" }, column),
+        _ => format!("{} of {}", values, column)
+    });
+    let counts: Vec<String> = counts.collect();
+    match counts.split_last() {
+        Some((last, [])) => format!("Transformed {}", last),
+        Some((last, rest)) => format!("Transformed {} and {}", rest.join(", "), last),
+        None => String::from("Transformed no values")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transformed(declaration: &str, value: &str) -> String {
+        let declaration: ColumnTransforms = declaration.parse().unwrap();
+        declaration.transforms.iter()
+            .fold(Cow::Borrowed(value), |value, transform| transform.apply(value))
+            .into_owned()
+    }
+
+    #[test]
+    fn apply_in_order() {
+        assert_eq!("zoë@example.com", transformed("email: trim, lower", "  Zoë@Example.COM "));
+        assert_eq!("DE", transformed("country: upper", "de"));
+        assert_eq!("", transformed("amount: trim, nullify_if(\"N/A\")", " N/A "));
+        // Compared with the value as the earlier transforms left it
+        assert_eq!(" n/a ", transformed("amount: nullify_if(\"N/A\"), lower", " N/A "));
+        assert_eq!("", transformed("amount: upper, nullify_if('N/A')", "n/a"));
+        assert_eq!("none", transformed("amount: NULLIFY_IF('it''s, none'), Trim", " none"));
+        assert_eq!("", transformed("amount: nullify_if('it''s, none')", "it's, none"));
+    }
+
+    #[test]
+    fn display_declarations() {
+        let declaration: ColumnTransforms = " Email :trim,LOWER , nullify_if( 'say \"none\"' ) ".parse().unwrap();
+        assert_eq!(r#"Email: trim, lower, nullify_if("say \"none\"")"#, declaration.to_string());
+    }
+
+    #[test]
+    fn refuse_invalid_declarations() {
+        for (declaration, problem) in [
+            ("email", "use the form 'column: transform, transform'"),
+            (": trim", "it names no column"),
+            ("email:", "expected a transform: trim, lower, upper or nullify_if(\"text\")"),
+            ("email: trim,", "expected a transform: trim, lower, upper or nullify_if(\"text\")"),
+            ("email: strip", "unknown transform strip. Transforms are trim, lower, upper and nullify_if"),
+            ("email: trim lower", "expected a comma before lower"),
+            ("email: nullify_if", "nullify_if needs a quoted string in parentheses, such as nullify_if(\"N/A\")"),
+            ("email: nullify_if(N/A)", "nullify_if needs a quoted string in parentheses, such as nullify_if(\"N/A\")"),
+            ("email: nullify_if('N/A)", "missing closing '"),
+            ("email: nullify_if('N/A'", "missing )")
+        ] {
+            assert_eq!(Err(format!("Invalid transform {}: {}", declaration, problem)), declaration.parse::<ColumnTransforms>());
+        }
+    }
+
+    #[test]
+    fn transform_and_count_records() -> Result<()> {
+        let schema = ["id", "Email", "country"].into_iter().collect::<Schema>();
+        let declarations = ["email: trim, lower".parse().unwrap(), "COUNTRY: upper".parse().unwrap()];
+        let bound = ColumnTransforms::bind(&declarations, &schema)?;
+        let mut counts = TransformCounts::default();
+        let record = bound.apply(StringRecord::from(vec!["1", " A@B.de", "de"]), &mut counts);
+        assert_eq!(StringRecord::from(vec!["1", "a@b.de", "DE"]), record);
+        bound.apply(StringRecord::from(vec!["2", "c@d.fr", "fr"]), &mut counts);
+        let unchanged = bound.apply(StringRecord::from(vec!["3", "e@f.fr", "FR"]), &mut counts);
+        assert_eq!(StringRecord::from(vec!["3", "e@f.fr", "FR"]), unchanged);
+        let mut total = TransformCounts::default();
+        total.extend(&counts);
+        total.extend(&counts);
+        let changed = bound.changed_values(&total, &schema);
+        assert_eq!(vec![(String::from("Email"), 2), (String::from("country"), 4)], changed);
+        assert_eq!("Transformed 2 values of Email and 4 of country", describe_changes(&changed));
+        assert_eq!("Transformed 1 value of Email", describe_changes(&[(String::from("Email"), 1)]));
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_unknown_and_repeated_columns() {
+        let schema = ["id", "email"].into_iter().collect::<Schema>();
+        let unknown = ColumnTransforms::bind(&["mail: trim".parse().unwrap()], &schema).unwrap_err();
+        assert_eq!(
+            "The transform mail: trim names a column which is not in the CSV file. Its columns are: id, email",
+            unknown.to_string()
+        );
+        let repeated = ["email: trim".parse().unwrap(), "Email: lower".parse().unwrap()];
+        assert!(ColumnTransforms::bind(&repeated, &schema).is_err());
+    }
+}
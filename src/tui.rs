@@ -0,0 +1,585 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io::{self, Stdout};
+use std::time::Duration;
+use async_std::task;
+use csv_async::AsyncReaderBuilder;
+use futures_util::StreamExt;
+use ratatui::{Frame, Terminal};
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::{execute, terminal};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, LineGauge, List, ListItem, ListState, Paragraph, Row, Table};
+use crate::database;
+
+/// How many rows of results are shown, if the query has no LIMIT of its own
+pub(crate) const SHOWN_ROWS: u64 = 1000;
+/// The widest a column of results may be, in terminal columns
+const COLUMN_WIDTH: usize = 40;
+/// How long a key is waited for before drawing again, so that the import's progress moves
+const TICK: Duration = Duration::from_millis(200);
+
+/// The terminal, which the UI draws on in raw mode on the alternate screen. It is restored
+/// when suspended, left or dropped, so that it is never left in raw mode
+pub(crate) struct TerminalUi {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    suspended: bool
+}
+
+impl TerminalUi {
+
+    pub(crate) fn enter() -> io::Result<Self> {
+        let mut ui = Self { terminal: Terminal::new(CrosstermBackend::new(io::stdout()))?, suspended: true };
+        ui.resume()?;
+        Ok(ui)
+    }
+
+    /// Gives the terminal back, so that messages and prompts can be written to it as usual
+    pub(crate) fn suspend(&mut self) -> io::Result<()> {
+        if self.suspended {
+            return Ok(());
+        }
+        self.suspended = true;
+        terminal::disable_raw_mode()?;
+        execute!(self.terminal.backend_mut(), terminal::LeaveAlternateScreen)?;
+        self.terminal.show_cursor()
+    }
+
+    pub(crate) fn resume(&mut self) -> io::Result<()> {
+        if !self.suspended {
+            return Ok(());
+        }
+        terminal::enable_raw_mode()?;
+        self.suspended = false;
+        execute!(self.terminal.backend_mut(), terminal::EnterAlternateScreen)?;
+        // Whatever was drawn before is gone, so all of it is drawn again
+        self.terminal.clear()
+    }
+
+    /// Restores the terminal, reporting if it cannot
+    pub(crate) fn leave(mut self) -> io::Result<()> {
+        self.suspend()
+    }
+
+    pub(crate) fn draw(&mut self, view: &mut View) -> io::Result<()> {
+        self.terminal.draw(|frame| view.draw(frame))?;
+        Ok(())
+    }
+}
+
+impl Drop for TerminalUi {
+    fn drop(&mut self) {
+        let _ = self.suspend();
+    }
+}
+
+/// Waits a moment for a key to be pressed, returning none if it is not
+pub(crate) async fn next_key() -> io::Result<Option<KeyEvent>> {
+    task::spawn_blocking(|| {
+        if !event::poll(TICK)? {
+            return Ok(None);
+        }
+        Ok(match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => Some(key),
+            _ => None
+        })
+    }).await
+}
+
+/// What is to be done after a key is pressed
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Action {
+    Nothing,
+    /// Run the query written in the editor
+    Run(String),
+    Quit
+}
+
+/// The pane which keys go to
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+enum Pane {
+    Tables,
+    #[default]
+    Editor,
+    Results
+}
+
+impl Pane {
+    fn next(self) -> Self {
+        match self {
+            Self::Tables => Self::Editor,
+            Self::Editor => Self::Results,
+            Self::Results => Self::Tables
+        }
+    }
+
+    fn previous(self) -> Self {
+        self.next().next()
+    }
+}
+
+/// The results of a query, as the text of their values
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Results {
+    pub(crate) columns: Vec<String>,
+    pub(crate) rows: Vec<Vec<String>>
+}
+
+impl Results {
+
+    /// Reads results written as CSV, whose first record is the header. A statement which
+    /// returns no rows writes nothing at all
+    pub(crate) async fn from_csv(csv: &[u8]) -> csv_async::Result<Self> {
+        let mut reader = AsyncReaderBuilder::new().has_headers(false).create_reader(csv);
+        let mut records = reader.records().enumerate();
+        let mut results = Self::default();
+        while let Some((index, record)) = records.next().await {
+            let values = record?.iter().map(String::from).collect();
+            match index {
+                0 => results.columns = values,
+                _ => results.rows.push(values)
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// The query being written, as its lines, with the cursor before a character of one of them
+#[derive(Debug)]
+struct Editor {
+    lines: Vec<String>,
+    row: usize,
+    /// Counted in characters, rather than bytes
+    column: usize,
+    /// The first line and character shown
+    scroll: (usize, usize)
+}
+
+impl Default for Editor {
+    fn default() -> Self {
+        Self { lines: vec![String::new()], row: 0, column: 0, scroll: (0, 0) }
+    }
+}
+
+impl Editor {
+
+    fn text(&self) -> String {
+        self.lines.join("\n")
+    }
+
+    fn line_length(&self) -> usize {
+        self.lines[self.row].chars().count()
+    }
+
+    /// The byte of the line which the cursor is before
+    fn byte(&self) -> usize {
+        let line = &self.lines[self.row];
+        line.char_indices().nth(self.column).map_or(line.len(), |(byte, _)| byte)
+    }
+
+    fn insert(&mut self, text: &str) {
+        for c in text.chars() {
+            if c == '\n' {
+                self.break_line();
+            } else {
+                let byte = self.byte();
+                self.lines[self.row].insert(byte, c);
+                self.column += 1;
+            }
+        }
+    }
+
+    fn break_line(&mut self) {
+        let byte = self.byte();
+        let rest = self.lines[self.row].split_off(byte);
+        self.row += 1;
+        self.lines.insert(self.row, rest);
+        self.column = 0;
+    }
+
+    fn backspace(&mut self) {
+        if self.column > 0 {
+            self.column -= 1;
+            let byte = self.byte();
+            self.lines[self.row].remove(byte);
+        } else if self.row > 0 {
+            let line = self.lines.remove(self.row);
+            self.row -= 1;
+            self.column = self.line_length();
+            self.lines[self.row].push_str(&line);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.column < self.line_length() {
+            let byte = self.byte();
+            self.lines[self.row].remove(byte);
+        } else if self.row + 1 < self.lines.len() {
+            let line = self.lines.remove(self.row + 1);
+            self.lines[self.row].push_str(&line);
+        }
+    }
+
+    /// Moves the cursor, keeping it within the line it ends up on
+    fn move_cursor(&mut self, key: KeyCode) {
+        match key {
+            KeyCode::Left if self.column > 0 => self.column -= 1,
+            KeyCode::Left if self.row > 0 => {
+                self.row -= 1;
+                self.column = self.line_length();
+            },
+            KeyCode::Right if self.column < self.line_length() => self.column += 1,
+            KeyCode::Right if self.row + 1 < self.lines.len() => {
+                self.row += 1;
+                self.column = 0;
+            },
+            KeyCode::Up => self.row = self.row.saturating_sub(1),
+            KeyCode::Down => self.row = (self.row + 1).min(self.lines.len() - 1),
+            KeyCode::Home => self.column = 0,
+            KeyCode::End => self.column = self.line_length(),
+            _ => ()
+        }
+        self.column = self.column.min(self.line_length());
+    }
+}
+
+/// What the terminal UI shows: the loaded tables and their columns, the query being written,
+/// the results of the last query run, and a status bar, along with the progress of an import
+#[derive(Debug, Default)]
+pub(crate) struct View {
+    /// The loaded tables, each with its columns
+    tables: Vec<(String, Vec<String>)>,
+    /// The entry of the tables pane chosen, counting each table and then its columns
+    chosen: usize,
+    editor: Editor,
+    results: Results,
+    /// The first row and column of the results shown
+    scroll: (usize, usize),
+    /// How many rows of results fit in their pane, as last drawn
+    page: usize,
+    focus: Pane,
+    /// The last thing said, and whether it is an error
+    status: (String, bool),
+    /// What the import being waited for has done, and how much of its input it has read, if
+    /// that is known
+    import: Option<(String, Option<f64>)>,
+    /// Whether errors are colored
+    color: bool
+}
+
+impl View {
+
+    pub(crate) fn new(color: bool) -> Self {
+        Self { status: (String::from("Write a query, then press F5 to run it"), false), color, ..Self::default() }
+    }
+
+    pub(crate) fn set_tables(&mut self, tables: Vec<(String, Vec<String>)>) {
+        self.tables = tables;
+        self.chosen = self.chosen.min(self.entries().len().saturating_sub(1));
+    }
+
+    pub(crate) fn set_import(&mut self, import: Option<(String, Option<f64>)>) {
+        self.import = import;
+    }
+
+    pub(crate) fn show_results(&mut self, results: Results) {
+        self.results = results;
+        self.scroll = (0, 0);
+    }
+
+    pub(crate) fn say(&mut self, message: impl Into<String>) {
+        self.status = (message.into(), false);
+    }
+
+    pub(crate) fn fail(&mut self, message: impl Into<String>) {
+        self.status = (message.into(), true);
+    }
+
+    /// Each table, followed by its columns, as listed in the tables pane
+    fn entries(&self) -> Vec<(&str, Option<&str>)> {
+        self.tables.iter()
+            .flat_map(|(table, columns)| {
+                std::iter::once((table.as_str(), None))
+                    .chain(columns.iter().map(move |column| (table.as_str(), Some(column.as_str()))))
+            })
+            .collect()
+    }
+
+    pub(crate) fn handle(&mut self, key: KeyEvent) -> Action {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('q' | 'c') if control => return Action::Quit,
+            KeyCode::Char('r') if control => return self.run(),
+            KeyCode::F(5) => return self.run(),
+            KeyCode::Tab => self.focus = self.focus.next(),
+            KeyCode::BackTab => self.focus = self.focus.previous(),
+            KeyCode::Char(_) if control => (),
+            code => match self.focus {
+                Pane::Tables => self.choose(code),
+                Pane::Editor => self.edit(code),
+                Pane::Results => self.scroll_results(code)
+            }
+        }
+        Action::Nothing
+    }
+
+    fn run(&mut self) -> Action {
+        let query = self.editor.text();
+        if query.trim().is_empty() {
+            self.fail("There is no query to run. Write one in the editor");
+            return Action::Nothing;
+        }
+        Action::Run(query)
+    }
+
+    /// Moves through the tables pane, or inserts the name of the table or column chosen into
+    /// the query, where the cursor is
+    fn choose(&mut self, key: KeyCode) {
+        let entries = self.entries();
+        match key {
+            KeyCode::Up => self.chosen = self.chosen.saturating_sub(1),
+            KeyCode::Down => self.chosen = (self.chosen + 1).min(entries.len().saturating_sub(1)),
+            KeyCode::Enter => {
+                let name = match entries.get(self.chosen) {
+                    Some((_, Some(column))) => database::column_identifier(column),
+                    Some((table, None)) => String::from(*table),
+                    None => return
+                };
+                self.editor.insert(&name);
+                self.focus = Pane::Editor;
+            },
+            _ => ()
+        }
+    }
+
+    fn edit(&mut self, key: KeyCode) {
+        match key {
+            KeyCode::Char(c) => self.editor.insert(c.encode_utf8(&mut [0; 4])),
+            KeyCode::Enter => self.editor.break_line(),
+            KeyCode::Backspace => self.editor.backspace(),
+            KeyCode::Delete => self.editor.delete(),
+            key => self.editor.move_cursor(key)
+        }
+    }
+
+    fn scroll_results(&mut self, key: KeyCode) {
+        let last_row = self.results.rows.len().saturating_sub(1);
+        let last_column = self.results.columns.len().saturating_sub(1);
+        let (row, column) = &mut self.scroll;
+        let page = self.page.max(1);
+        match key {
+            KeyCode::Up => *row = row.saturating_sub(1),
+            KeyCode::Down => *row += 1,
+            KeyCode::PageUp => *row = row.saturating_sub(page),
+            KeyCode::PageDown => *row += page,
+            KeyCode::Home => *row = 0,
+            KeyCode::End => *row = last_row,
+            KeyCode::Left => *column = column.saturating_sub(1),
+            KeyCode::Right => *column += 1,
+            _ => ()
+        }
+        *row = (*row).min(last_row);
+        *column = (*column).min(last_column);
+    }
+
+    fn pane(&self, title: String, pane: Pane) -> Block<'static> {
+        let block = Block::bordered().title(title);
+        match self.focus == pane {
+            true => block.border_style(Style::new().add_modifier(Modifier::BOLD)),
+            false => block.border_style(Style::new().add_modifier(Modifier::DIM))
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let importing = u16::from(self.import.is_some());
+        let [main, gauge, status] = Layout::vertical(
+            [Constraint::Min(3), Constraint::Length(importing), Constraint::Length(1)]
+        ).areas(frame.area());
+        let [tables, right] = Layout::horizontal([Constraint::Percentage(25), Constraint::Min(20)]).areas(main);
+        let [editor, results] = Layout::vertical([Constraint::Percentage(35), Constraint::Min(4)]).areas(right);
+        self.draw_tables(frame, tables);
+        self.draw_editor(frame, editor);
+        self.draw_results(frame, results);
+
+        if let Some((label, fraction)) = &self.import {
+            match fraction {
+                Some(fraction) => frame.render_widget(
+                    LineGauge::default().ratio(*fraction).label(label.as_str())
+                        .filled_style(Style::new().add_modifier(Modifier::BOLD)),
+                    gauge
+                ),
+                None => frame.render_widget(Paragraph::new(label.as_str()), gauge)
+            }
+        }
+        let hint = match self.focus {
+            Pane::Tables => "↑↓ choose, Enter inserts the name into the query",
+            Pane::Editor => "F5 or Ctrl+R runs the query",
+            Pane::Results => "↑↓ PgUp PgDn scroll the rows, ←→ the columns"
+        };
+        let (message, failed) = &self.status;
+        let message_style = match (failed, self.color) {
+            (true, true) => Style::new().fg(Color::Red).add_modifier(Modifier::BOLD),
+            (true, false) => Style::new().add_modifier(Modifier::BOLD),
+            (false, _) => Style::new()
+        };
+        frame.render_widget(Paragraph::new(Line::from(vec![
+            Span::styled(message.as_str(), message_style),
+            Span::styled(
+                format!("  {} · Tab: next pane · Ctrl+Q: quit", hint), Style::new().add_modifier(Modifier::DIM)
+            )
+        ])), status);
+    }
+
+    fn draw_tables(&self, frame: &mut Frame, area: Rect) {
+        let items = self.entries().into_iter()
+            .map(|(table, column)| match column {
+                Some(column) => ListItem::new(format!("  {}", column)),
+                None => ListItem::new(Span::styled(table, Style::new().add_modifier(Modifier::BOLD)))
+            })
+            .collect::<Vec<_>>();
+        let list = List::new(items)
+            .block(self.pane(String::from("Tables"), Pane::Tables))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        let mut state = ListState::default().with_selected(Some(self.chosen).filter(|_| self.focus == Pane::Tables));
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+
+    fn draw_editor(&mut self, frame: &mut Frame, area: Rect) {
+        let block = self.pane(String::from("Query"), Pane::Editor);
+        let inner = block.inner(area);
+        let (height, width) = (usize::from(inner.height).max(1), usize::from(inner.width).max(1));
+        let editor = &mut self.editor;
+        // The cursor is kept in sight
+        editor.scroll.0 = editor.scroll.0.clamp((editor.row + 1).saturating_sub(height), editor.row);
+        editor.scroll.1 = editor.scroll.1.clamp((editor.column + 1).saturating_sub(width), editor.column);
+        let lines = editor.lines.iter().map(|line| Line::from(line.as_str())).collect::<Vec<_>>();
+        frame.render_widget(
+            Paragraph::new(lines).block(block).scroll((editor.scroll.0 as u16, editor.scroll.1 as u16)),
+            area
+        );
+        if self.focus == Pane::Editor {
+            frame.set_cursor_position((
+                inner.x + (editor.column - editor.scroll.1) as u16, inner.y + (editor.row - editor.scroll.0) as u16
+            ));
+        }
+    }
+
+    fn draw_results(&mut self, frame: &mut Frame, area: Rect) {
+        // The header takes a line of the pane, as do its borders
+        self.page = usize::from(area.height.saturating_sub(3));
+        let (first_row, first_column) = self.scroll;
+        let rows = &self.results.rows;
+        let title = match rows.len() {
+            0 => String::from("Results"),
+            count => format!(
+                "Results: rows {}-{} of {}", first_row + 1, (first_row + self.page).min(count), count
+            )
+        };
+        let block = self.pane(title, Pane::Results);
+        if self.results.columns.is_empty() {
+            frame.render_widget(block, area);
+            return;
+        }
+        let shown = &rows[first_row.min(rows.len())..(first_row + self.page).min(rows.len())];
+        let columns = &self.results.columns[first_column..];
+        let widths = columns.iter().enumerate()
+            .map(|(index, column)| {
+                let widest = shown.iter()
+                    .filter_map(|row| row.get(first_column + index))
+                    .chain(std::iter::once(column))
+                    .map(|value| value.chars().count())
+                    .max()
+                    .unwrap_or_default();
+                Constraint::Length(widest.min(COLUMN_WIDTH) as u16)
+            })
+            .collect::<Vec<_>>();
+        let table = Table::new(shown.iter().map(|row| Row::new(row[first_column..].to_vec())), widths)
+            .header(Row::new(columns.to_vec()).style(Style::new().add_modifier(Modifier::BOLD)))
+            .block(block);
+        frame.render_widget(table, area);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(view: &mut View, code: KeyCode) -> Action {
+        view.handle(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn type_text(view: &mut View, text: &str) {
+        for c in text.chars() {
+            press(view, if c == '\n' { KeyCode::Enter } else { KeyCode::Char(c) });
+        }
+    }
+
+    #[test]
+    fn write_a_query_over_lines() {
+        let mut view = View::new(false);
+        assert_eq!(Action::Nothing, press(&mut view, KeyCode::F(5)));
+        type_text(&mut view, "SELECT 1\nFROM data");
+        press(&mut view, KeyCode::Home);
+        press(&mut view, KeyCode::Backspace);
+        type_text(&mut view, " ");
+        press(&mut view, KeyCode::End);
+        press(&mut view, KeyCode::Backspace);
+        type_text(&mut view, "ä");
+        assert_eq!(Action::Run(String::from("SELECT 1 FROM datä")), press(&mut view, KeyCode::F(5)));
+        assert_eq!(Action::Quit, view.handle(KeyEvent::new(KeyCode::Char('q'), KeyModifiers::CONTROL)));
+    }
+
+    #[test]
+    fn insert_the_chosen_column() {
+        let mut view = View::new(false);
+        view.set_tables(vec![(String::from("data"), vec![String::from("id"), String::from("Total Amount")])]);
+        type_text(&mut view, "SELECT  FROM data");
+        for _ in 0.." FROM data".len() {
+            press(&mut view, KeyCode::Left);
+        }
+        press(&mut view, KeyCode::BackTab);
+        for _ in 0..3 {
+            press(&mut view, KeyCode::Down);
+        }
+        press(&mut view, KeyCode::Enter);
+        type_text(&mut view, ", ");
+        press(&mut view, KeyCode::BackTab);
+        press(&mut view, KeyCode::Up);
+        press(&mut view, KeyCode::Enter);
+        assert_eq!(
+            Action::Run(String::from("SELECT \"Total Amount\", id FROM data")),
+            press(&mut view, KeyCode::F(5))
+        );
+    }
+
+    #[async_std::test]
+    async fn read_results_written_as_csv() {
+        let results = Results::from_csv(b"id,name\n1,\"Smith, Jo\"\n2,\n").await.unwrap();
+        assert_eq!(Results {
+            columns: vec![String::from("id"), String::from("name")],
+            rows: vec![
+                vec![String::from("1"), String::from("Smith, Jo")],
+                vec![String::from("2"), String::new()]
+            ]
+        }, results);
+        assert_eq!(Results::default(), Results::from_csv(b"").await.unwrap());
+    }
+}
@@ -0,0 +1,186 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use itertools::Itertools;
+use crate::database::Schema;
+use crate::import::Reject;
+use crate::profile::{DatasetProfile, ValueKind};
+use crate::transform;
+
+/// How many rejected records are described in the report
+const REJECTS_SHOWN: usize = 10;
+
+/// The result of reading and parsing a CSV dataset without importing it
+#[derive(Debug)]
+pub(crate) struct Validation {
+    schema: Arc<Schema>,
+    /// Problems with the column names, which would stop the table being created
+    header_problems: Vec<String>,
+    profile: DatasetProfile,
+    rejected: u64,
+    /// How many records the filter left out, if there is one
+    filtered_out: Option<u64>,
+    /// How many values were changed in each column with transforms, by column name
+    pub(crate) transformed: Vec<(String, u64)>,
+    /// Descriptions of the first records which were rejected
+    rejects: Vec<String>,
+    /// Problems which would not prevent the import
+    pub(crate) warnings: Vec<String>
+}
+
+impl Validation {
+
+    pub(crate) fn new(schema: Arc<Schema>) -> Self {
+        let mut header_problems = Vec::new();
+        // Column names other than plain lower-case ones are quoted, so that any name is kept as
+        // it is, but a table cannot have a column without a name, nor two of the same name
+        let mut seen = HashMap::new();
+        for index in 0..schema.len() {
+            let name = schema.column_name(index);
+            if name.is_empty() {
+                header_problems.push(format!("Column {} has no name", index + 1));
+            } else if let Some(first) = seen.insert(name, index) {
+                header_problems.push(format!(
+                    "Column {} is named {}, which is the same as column {}", index + 1, name, first + 1
+                ));
+            }
+        }
+        let profile = DatasetProfile::new(&schema);
+        Self {
+            schema, header_problems, profile, rejected: 0, filtered_out: None, transformed: Vec::new(),
+            rejects: Vec::new(), warnings: Vec::new()
+        }
+    }
+
+    /// Adds the profile of records which were parsed
+    pub(crate) fn add_profile(&mut self, profile: &DatasetProfile) {
+        self.profile.extend(profile);
+    }
+
+    /// Counts records which were parsed, but did not match the filter
+    pub(crate) fn filter_out(&mut self, records: u64) {
+        *self.filtered_out.get_or_insert(0) += records;
+    }
+
+    pub(crate) fn reject(&mut self, reject: &Reject) {
+        self.rejected += 1;
+        if self.rejects.len() < REJECTS_SHOWN {
+            self.rejects.push(reject.describe(&self.schema));
+        }
+    }
+
+    /// The rows which would be imported, those the filter left out, if there is one, and those
+    /// which would be rejected
+    pub(crate) fn row_counts(&self) -> (u64, Option<u64>, u64) {
+        (self.profile.rows, self.filtered_out, self.rejected)
+    }
+
+    /// Whether the dataset could be imported in full
+    pub(crate) fn passed(&self) -> bool {
+        self.header_problems.is_empty() && self.rejected == 0
+    }
+
+    /// Describes the table which would be created and what was found in the dataset
+    pub(crate) fn report(&self, table: &str) -> String {
+        let mut report = format!("Would run: {}\n", self.schema.create_table_statement(table, false));
+        report.push_str("Columns:\n");
+        for column in &self.profile.columns {
+            let kind = column.kind.map_or("empty", ValueKind::name);
+            report.push_str(&format!(
+                "  {}: {}, widest value {} character{}{}\n",
+                column.name, kind, column.max_chars, if column.max_chars == 1 { "" } else { "s" },
+                if column.empty > 0 && column.kind.is_some() { ", some empty" } else { "" }
+            ));
+        }
+        report.push_str(&format!("Rows parsed: {}\n", self.profile.rows + self.filtered_out.unwrap_or(0)));
+        if self.filtered_out.is_some() {
+            report.push_str(&format!("Rows matching the filter: {}\n", self.profile.rows));
+        }
+        if !self.transformed.is_empty() {
+            report.push_str(&format!("{}\n", transform::describe_changes(&self.transformed)));
+        }
+        report.push_str(&format!("Rows rejected: {}", self.rejected));
+        for reject in &self.rejects {
+            report.push_str(&format!("\n  {}", reject));
+        }
+        if self.rejected > self.rejects.len() as u64 {
+            report.push_str(&format!("\n  and {} more", self.rejected - self.rejects.len() as u64));
+        }
+        if !self.header_problems.is_empty() {
+            report.push_str(&format!("\nHeader problems:\n  {}", self.header_problems.iter().join("\n  ")));
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv_async::StringRecord;
+    use crate::import::RejectReason;
+
+    #[test]
+    fn report() {
+        let schema = Arc::new(["id", "name", "ID", "my col", "id"].into_iter().collect::<Schema>());
+        let mut profile = DatasetProfile::new(&schema);
+        profile.record(&StringRecord::from(vec!["1", "Zoë", "2022-03-13", "", "a"]));
+        profile.record(&StringRecord::from(vec!["2.5", "", "2022-03-13 07:00:00", "", "true"]));
+        let mut validation = Validation::new(schema);
+        validation.add_profile(&profile);
+        for line in 4..16 {
+            validation.reject(&Reject { line, reason: RejectReason::FieldCount { fields: 3 } });
+        }
+        assert!(!validation.passed());
+        assert_eq!("\
+Would run: CREATE TABLE data (id TEXT NOT NULL, name TEXT NOT NULL, \"ID\" TEXT NOT NULL, \"my col\" TEXT NOT NULL, \
+id TEXT NOT NULL)
+Columns:
+  id: decimal, widest value 3 characters
+  name: text, widest value 3 characters, some empty
+  ID: timestamp, widest value 19 characters
+  my col: empty, widest value 0 characters
+  id: text, widest value 4 characters
+Rows parsed: 2
+Rows rejected: 12
+  line 4 has 3 fields, but the header has 5
+  line 5 has 3 fields, but the header has 5
+  line 6 has 3 fields, but the header has 5
+  line 7 has 3 fields, but the header has 5
+  line 8 has 3 fields, but the header has 5
+  line 9 has 3 fields, but the header has 5
+  line 10 has 3 fields, but the header has 5
+  line 11 has 3 fields, but the header has 5
+  line 12 has 3 fields, but the header has 5
+  line 13 has 3 fields, but the header has 5
+  and 2 more
+Header problems:
+  Column 5 is named id, which is the same as column 1",
+            validation.report("data"));
+    }
+
+    #[test]
+    fn clean_dataset_passes() {
+        let schema = Arc::new(["a", "b"].into_iter().collect::<Schema>());
+        let mut profile = DatasetProfile::new(&schema);
+        profile.record(&StringRecord::from(vec!["x", "y"]));
+        let mut validation = Validation::new(schema);
+        validation.add_profile(&profile);
+        assert!(validation.passed());
+    }
+}
@@ -0,0 +1,50 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::process::{Command, Output, Stdio};
+use tempfile::TempDir;
+
+/// The database used by tests requiring the integration-test feature
+pub fn test_url() -> String {
+    std::env::var("DATA_SIFTER_TEST_URL")
+        .unwrap_or_else(|_| String::from("postgres://postgres@localhost/postgres"))
+}
+
+/// A config home directory containing a data-sifter config with the given URL
+pub fn config_home(postgres_url: &str) -> TempDir {
+    let config_home = tempfile::tempdir().unwrap();
+    let config_dir = config_home.path().join("data-sifter");
+    fs::create_dir(&config_dir).unwrap();
+    fs::write(
+        config_dir.join("data-sifter.ron"),
+        format!("(postgres_url: {:?})", postgres_url)
+    ).unwrap();
+    config_home
+}
+
+pub fn run_data_sifter(config_home: &TempDir, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_data-sifter"))
+        .args(args)
+        .env("XDG_CONFIG_HOME", config_home.path())
+        .env_remove("RUST_BACKTRACE")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
@@ -0,0 +1,132 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Tests of the duckdb backend, which need no database server
+
+#![cfg(feature = "duckdb")]
+
+mod common;
+
+use std::fs;
+use tempfile::TempDir;
+use common::run_data_sifter;
+
+/// A config home directory containing a config for the duckdb backend, whose tables are kept
+/// in data-sifter.duckdb beside it
+fn duckdb_home() -> TempDir {
+    let config_home = tempfile::tempdir().unwrap();
+    let config_dir = config_home.path().join("data-sifter");
+    fs::create_dir(&config_dir).unwrap();
+    fs::write(config_dir.join("data-sifter.ron"), "(postgres_url: \"\", backend: duckdb)").unwrap();
+    config_home
+}
+
+#[test]
+fn import_then_write_results() {
+    let config_home = duckdb_home();
+    let directory = tempfile::tempdir().unwrap();
+    let csv_file = directory.path().join("sales.csv");
+    fs::write(&csv_file, "store,amount\nnorth,1.5\nsouth,2\nnorth,3\n").unwrap();
+    let output_file = directory.path().join("totals.csv");
+    let output = run_data_sifter(&config_home, &[
+        "--input", csv_file.to_str().unwrap(), "--table", "sales",
+        "--query", "SELECT store, sum(amount) AS total FROM sales GROUP BY store ORDER BY store",
+        "--format", "csv", "--output", output_file.to_str().unwrap()
+    ]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("DuckDB database"), "{}", stderr);
+    assert_eq!("store,total\nnorth,4.5\nsouth,2\n", fs::read_to_string(&output_file).unwrap());
+
+    // The table is kept in the database file for later sessions
+    let output = run_data_sifter(&config_home, &[
+        "--input", "KEEP", "--table", "sales", "--query", "SELECT count(*) AS rows FROM sales", "--format", "show"
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!("rows\n3\n", String::from_utf8(output.stdout).unwrap());
+}
+
+#[test]
+fn postgres_only_options_are_refused() {
+    let config_home = duckdb_home();
+    let directory = tempfile::tempdir().unwrap();
+    let csv_file = directory.path().join("sales.csv");
+    fs::write(&csv_file, "store,amount\nnorth,1\n").unwrap();
+    let output = run_data_sifter(&config_home, &[
+        "--input", csv_file.to_str().unwrap(), "--filter", "amount > 0", "--query", "SELECT 1", "--format", "show"
+    ]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(Some(2), output.status.code(), "{}", stderr);
+    assert!(stderr.contains("--filter needs the postgres backend"), "{}", stderr);
+
+    let output = run_data_sifter(&config_home, &[
+        "--input", "KEEP", "--query", "top store", "--format", "show"
+    ]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(Some(2), output.status.code(), "{}", stderr);
+    assert!(stderr.contains("'top' needs the postgres backend"), "{}", stderr);
+}
+
+#[test]
+fn query_errors_are_categorized() {
+    let config_home = duckdb_home();
+    let output = run_data_sifter(&config_home, &[
+        "--input", "KEEP", "--query", "SELECT missing FROM nowhere", "--format", "show"
+    ]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(Some(7), output.status.code(), "{}", stderr);
+    assert!(stderr.contains("nowhere"), "{}", stderr);
+}
+
+#[test]
+fn strict_schema_checks_existing_table() {
+    let config_home = duckdb_home();
+    let directory = tempfile::tempdir().unwrap();
+    let csv_file = directory.path().join("sales.csv");
+    fs::write(&csv_file, "store,amount\nnorth,1.5\n").unwrap();
+    let output = run_data_sifter(&config_home, &[
+        "--input", csv_file.to_str().unwrap(), "--table", "sales", "--strict-schema",
+        "--query", "SELECT 1", "--format", "show"
+    ]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(Some(5), output.status.code(), "{}", stderr);
+    assert!(stderr.contains("Table sales does not exist"), "{}", stderr);
+
+    let output = run_data_sifter(&config_home, &[
+        "--input", csv_file.to_str().unwrap(), "--table", "sales", "--query", "SELECT 1", "--format", "show"
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let extra_file = directory.path().join("extra.csv");
+    fs::write(&extra_file, "Store,amount,region\nsouth,2,west\n").unwrap();
+    let output = run_data_sifter(&config_home, &[
+        "--input", extra_file.to_str().unwrap(), "--table", "sales", "--strict-schema",
+        "--query", "SELECT count(*) AS rows FROM sales", "--format", "show"
+    ]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(Some(5), output.status.code(), "{}", stderr);
+    assert!(stderr.contains("region is in the CSV file, but not the table"), "{}", stderr);
+
+    let matching_file = directory.path().join("matching.csv");
+    fs::write(&matching_file, "Amount,store\n2,south\n").unwrap();
+    let output = run_data_sifter(&config_home, &[
+        "--input", matching_file.to_str().unwrap(), "--table", "sales", "--strict-schema",
+        "--query", "SELECT count(*) AS rows FROM sales", "--format", "show"
+    ]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!("rows\n2\n", String::from_utf8(output.stdout).unwrap());
+}
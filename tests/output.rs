@@ -0,0 +1,591 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Tests of how query results of various types are written
+
+#![cfg(feature = "integration-test")]
+
+mod common;
+
+use std::fs;
+use std::process::Output;
+use tempfile::TempDir;
+use common::{config_home, run_data_sifter, test_url};
+
+/// Imports a fixture into the given table, then runs a query against it
+fn query_fixture(table: &str, csv: &str, query: &str, extra_args: &[&str]) -> Output {
+    let config_home = config_home(&test_url());
+    let csv_dir = tempfile::tempdir().unwrap();
+    let csv_file = csv_dir.path().join("fixture.csv");
+    fs::write(&csv_file, csv).unwrap();
+    let mut args = vec![
+        "--input", csv_file.to_str().unwrap(), "--table", table, "--replace", "--query", query
+    ];
+    args.extend_from_slice(extra_args);
+    run_data_sifter(&config_home, &args)
+}
+
+fn stdout(output: Output) -> String {
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Runs a query with JSON output, returning the written JSON
+fn query_fixture_json(table: &str, csv: &str, query: &str, extra_args: &[&str]) -> serde_json::Value {
+    let output_dir = TempDir::new().unwrap();
+    let output_file = output_dir.path().join("output.json");
+    let mut args = vec!["--format", "json", "--output", output_file.to_str().unwrap()];
+    args.extend_from_slice(extra_args);
+    stdout(query_fixture(table, csv, query, &args));
+    serde_json::from_str(&fs::read_to_string(output_file).unwrap()).unwrap()
+}
+
+const NAMES: &str = "name\nbob\n\"a,b\"\nsay \"hi\"\n";
+
+#[test]
+fn array_agg_as_postgres_literal() {
+    let output = query_fixture(
+        "array_literal_fixture", NAMES,
+        "SELECT array_agg(name ORDER BY name) AS names FROM array_literal_fixture", &["--format", "show"]
+    );
+    assert_eq!("names\n\"{\"\"a,b\"\",bob,\"\"say \\\"\"hi\\\"\"\"\"}\"\n", stdout(output));
+}
+
+#[test]
+fn array_agg_as_json() {
+    let json = query_fixture_json(
+        "array_json_fixture", NAMES,
+        "SELECT array_agg(name ORDER BY name) AS names, ARRAY[1, NULL, 3] AS numbers FROM array_json_fixture",
+        &["--array-format", "json"]
+    );
+    assert_eq!(serde_json::json!([{
+        "names": ["a,b", "bob", "say \"hi\""],
+        "numbers": [1, null, 3]
+    }]), json);
+}
+
+#[test]
+fn scalar_arrays_with_nulls() {
+    let output = query_fixture(
+        "array_scalar_fixture", NAMES,
+        "SELECT ARRAY[1.5, NULL]::float8[] AS floats, ARRAY[true, false] AS bools, \
+        string_to_array('x,,NULL', ',') AS texts, string_to_array('x,y', ',', 'y') AS with_null",
+        &["--format", "show"]
+    );
+    assert_eq!(
+        "floats,bools,texts,with_null\n\"{1.5,NULL}\",\"{t,f}\",\"{x,\"\"\"\",\"\"NULL\"\"}\",\"{x,NULL}\"\n",
+        stdout(output)
+    );
+}
+
+#[test]
+fn nested_and_unsupported_arrays_cast_to_text() {
+    let output = query_fixture(
+        "array_nested_fixture", NAMES,
+        "SELECT ARRAY[ARRAY[1, 2], ARRAY[3, 4]] AS nested, ARRAY[1, 2] AS flat, \
+        ARRAY['1 day'::interval, NULL] AS intervals",
+        &["--format", "show"]
+    );
+    assert_eq!("nested,flat,intervals\n\"{{1,2},{3,4}}\",\"{1,2}\",\"{\"\"1 day\"\",NULL}\"\n", stdout(output));
+}
+
+#[test]
+fn nested_arrays_as_json_text() {
+    let json = query_fixture_json(
+        "array_nested_json_fixture", NAMES,
+        "SELECT grid FROM (VALUES (ARRAY[ARRAY['a', 'b'], ARRAY['c', NULL]]), (ARRAY[ARRAY['d']]), (ARRAY['e'])) AS grids(grid)",
+        &["--array-format", "json"]
+    );
+    assert_eq!(serde_json::json!([
+        {"grid": "{{a,b},{c,NULL}}"},
+        {"grid": "{{d}}"},
+        {"grid": ["e"]}
+    ]), json);
+}
+
+const EVENTS: &str = "id,payload\n\
+1,\"{\"\"id\"\": 10, \"\"user\"\": {\"\"name\"\": \"\"alice\"\"}, \"\"tags\"\": [\"\"a\"\", \"\"b\"\"]}\"\n\
+2,\"{\"\"kind\"\": \"\"click\"\"}\"\n\
+3,\"[1, 2]\"\n";
+
+#[test]
+fn jsonb_as_compact_text() {
+    let output = query_fixture(
+        "jsonb_text_fixture", EVENTS,
+        "SELECT payload::jsonb FROM jsonb_text_fixture WHERE id = '2'", &["--format", "show"]
+    );
+    assert_eq!("payload\n\"{\"\"kind\"\":\"\"click\"\"}\"\n", stdout(output));
+}
+
+#[test]
+fn jsonb_nested_in_json() {
+    let json = query_fixture_json(
+        "jsonb_nested_fixture", EVENTS,
+        "SELECT id, payload::jsonb FROM jsonb_nested_fixture WHERE id = '1'", &[]
+    );
+    assert_eq!(serde_json::json!([{
+        "id": "1",
+        "payload": {"id": 10, "user": {"name": "alice"}, "tags": ["a", "b"]}
+    }]), json);
+}
+
+#[test]
+fn expand_jsonb_column() {
+    let output = query_fixture(
+        "jsonb_expand_fixture", EVENTS,
+        "SELECT id, payload::json FROM jsonb_expand_fixture ORDER BY id",
+        &["--format", "show", "--expand-json", "payload"]
+    );
+    // The id key collides with the id column
+    assert_eq!("\
+id,payload.id,user,tags,kind
+1,10,\"{\"\"name\"\":\"\"alice\"\"}\",\"[\"\"a\"\",\"\"b\"\"]\",
+2,,,,click
+3,,,,
+", stdout(output));
+}
+
+#[test]
+fn expand_json_keys_after_scan() {
+    let output = query_fixture(
+        "jsonb_scan_fixture", EVENTS,
+        "SELECT payload::jsonb FROM jsonb_scan_fixture ORDER BY id",
+        &["--format", "show", "--expand-json", "payload", "--expand-json-scan", "1"]
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(stderr.contains("kind"), "{}", stderr);
+    // jsonb orders keys by length
+    assert_eq!("id,tags,user\n10,\"[\"\"a\"\",\"\"b\"\"]\",\"{\"\"name\"\":\"\"alice\"\"}\"\n,,\n,,\n", stdout(output));
+}
+
+#[test]
+fn expand_missing_column() {
+    let output = query_fixture(
+        "jsonb_missing_fixture", EVENTS, "SELECT id FROM jsonb_missing_fixture",
+        &["--format", "show", "--expand-json", "payload"]
+    );
+    assert_eq!(Some(2), output.status.code());
+}
+
+#[test]
+fn listed_columns_in_order() {
+    let json = query_fixture_json(
+        "column_order_fixture", "id,name,city\n1,ann,oslo\n",
+        "SELECT city, id AS \"Id\", name FROM column_order_fixture",
+        &["--columns", "name,Id", "--header-case", "upper"]
+    );
+    assert_eq!(serde_json::json!([{"NAME": "ann", "ID": "1"}]), json);
+    // Spaces around the listed names are ignored
+    let output = query_fixture(
+        "column_order_fixture", "id,name,city\n1,ann,oslo\n2,bo,rome\n",
+        "SELECT city, id AS \"Id\", name FROM column_order_fixture ORDER BY id",
+        &["--format", "show", "--columns", "name, Id"]
+    );
+    assert_eq!("name,Id\nann,1\nbo,2\n", stdout(output));
+}
+
+#[test]
+fn sorted_columns_lower_case() {
+    let output = query_fixture(
+        "column_sort_fixture", "id,name,city\n1,ann,oslo\n",
+        "SELECT name AS \"Name\", id, city AS \"CITY\" FROM column_sort_fixture",
+        &["--format", "show", "--sort-columns", "--header-case", "lower"]
+    );
+    // Names are sorted as the query gave them, before their case is changed
+    assert_eq!("city,name,id\noslo,ann,1\n", stdout(output));
+}
+
+#[test]
+fn unknown_listed_column() {
+    let output = query_fixture(
+        "column_unknown_fixture", "id,name\n1,ann\n", "SELECT * FROM column_unknown_fixture",
+        &["--format", "show", "--columns", "id,nmae"]
+    );
+    assert_eq!(Some(2), output.status.code());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Cannot write column nmae, which is not in the query results. They are: id, name"), "{}", stderr);
+}
+
+#[test]
+fn empty_results_have_header() {
+    let output_dir = TempDir::new().unwrap();
+    for (format, expected) in [("csv", "id,name\n"), ("json", "[]\n")] {
+        let output_file = output_dir.path().join(format!("empty.{}", format));
+        let output = query_fixture(
+            "empty_fixture", "id,name\n1,ann\n", "SELECT * FROM empty_fixture WHERE false",
+            &["--format", format, "--output", output_file.to_str().unwrap()]
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        assert_eq!("", stdout(output));
+        assert!(stderr.contains("Wrote 0 rows") && stderr.contains("No results"), "{}", stderr);
+        assert_eq!(expected, fs::read_to_string(&output_file).unwrap());
+    }
+    let output = query_fixture(
+        "empty_fixture", "id,name\n1,ann\n", "SELECT name, id AS \"Id\" FROM empty_fixture WHERE false",
+        &["--format", "show", "--sort-columns"]
+    );
+    assert_eq!("Id,name\n", stdout(output));
+}
+
+#[test]
+fn no_empty_file() {
+    let output_dir = TempDir::new().unwrap();
+    let output_file = output_dir.path().join("empty.csv");
+    let output = query_fixture(
+        "no_empty_fixture", "id,name\n1,ann\n", "SELECT * FROM no_empty_fixture WHERE false",
+        &["--format", "csv", "--output", output_file.to_str().unwrap(), "--no-empty-file"]
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert_eq!("", stdout(output));
+    assert!(stderr.contains("No results") && !stderr.contains("Wrote"), "{}", stderr);
+    assert!(!output_file.exists());
+}
+
+#[test]
+fn bytea_as_hex() {
+    let output = query_fixture(
+        "bytea_hex_fixture", NAMES,
+        "SELECT '\\xdeadbeef'::bytea AS literal, ARRAY['\\x01'::bytea, NULL] AS array, \
+        sha256(name::bytea) AS digest FROM bytea_hex_fixture WHERE name = 'bob'",
+        &["--format", "show"]
+    );
+    assert_eq!("\
+literal,array,digest
+\\xdeadbeef,\"{\"\"\\\\x01\"\",NULL}\",\\x81b637d8fcd2c6da6359e6963113a1170de795e4b725b84d1e0b4cfd9ec58ce9
+", stdout(output));
+}
+
+#[test]
+fn bytea_as_base64() {
+    let config_home = config_home(&test_url());
+    let output = run_data_sifter(&config_home, &["config", "set", "bytea_format", "base64"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let output = run_data_sifter(&config_home, &[
+        "--input", "KEEP", "--query", "SELECT '\\xdeadbeef00'::bytea AS bytes", "--format", "show"
+    ]);
+    assert_eq!("bytes\n3q2+7wA=\n", stdout(output));
+}
+
+#[test]
+fn timestamps_in_timezone() {
+    let query = "SELECT '2022-03-13 07:00:00+00'::timestamptz AS instant, \
+        '2022-03-13 07:00:00'::timestamp AS local_time, '2022-03-13'::date AS day";
+    let output = query_fixture("timestamp_fixture", NAMES, query, &["--format", "show"]);
+    assert_eq!("instant,local_time,day\n2022-03-13T07:00:00Z,2022-03-13 07:00:00,2022-03-13\n", stdout(output));
+
+    let output = query_fixture("timestamp_fixture", NAMES, query, &[
+        "--format", "show", "--timezone", "America/New_York", "--timestamp-format", "%Y-%m-%d %H:%M"
+    ]);
+    assert_eq!("instant,local_time,day\n2022-03-13 03:00,2022-03-13 07:00:00,2022-03-13\n", stdout(output));
+}
+
+#[test]
+fn numeric_formatting() {
+    let query = "SELECT 1.50::numeric AS exact, 0.1::float8 + 0.2::float8 AS sum, 1234567.125::numeric AS big";
+    let output = query_fixture("numeric_fixture", NAMES, query, &["--format", "show"]);
+    assert_eq!("exact,sum,big\n1.50,0.30000000000000004,1234567.125\n", stdout(output));
+
+    let output = query_fixture("numeric_fixture", NAMES, query, &[
+        "--format", "show", "--decimals", "2", "--decimals", "exact=0", "--thousands-separators"
+    ]);
+    assert_eq!("exact,sum,big\n2,0.30,\"1,234,567.12\"\n", stdout(output));
+
+    let json = query_fixture_json("numeric_fixture", NAMES, query, &["--decimals", "1", "--thousands-separators"]);
+    assert_eq!(r#"[{"exact":1.5,"sum":0.3,"big":1234567.1}]"#, json.to_string());
+}
+
+#[test]
+fn display_locale_only_applies_to_shown_results() {
+    let config_home = config_home(&test_url());
+    let output = run_data_sifter(&config_home, &["config", "set", "display_locale", "de-DE"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let csv_dir = tempfile::tempdir().unwrap();
+    let csv_file = csv_dir.path().join("fixture.csv");
+    fs::write(&csv_file, NAMES).unwrap();
+    let query = "SELECT 1234567.89::numeric AS amount, 1234 AS count, '2022-01-05'::date AS day";
+    let run = |extra_args: &[&str]| {
+        let mut args = vec!["--input", csv_file.to_str().unwrap(), "--table", "locale_fixture", "--replace",
+                            "--query", query];
+        args.extend_from_slice(extra_args);
+        run_data_sifter(&config_home, &args)
+    };
+    let output = run(&["--format", "show"]);
+    assert_eq!("amount,count,day\n\"1.234.567,89\",1.234,05.01.2022\n", stdout(output));
+
+    // Files are written the same whatever the locale, so that other programs can read them
+    let output_dir = TempDir::new().unwrap();
+    let csv_output = output_dir.path().join("output.csv");
+    stdout(run(&["--format", "csv", "--output", csv_output.to_str().unwrap()]));
+    assert_eq!("amount,count,day\n1234567.89,1234,2022-01-05\n", fs::read_to_string(csv_output).unwrap());
+    let json_output = output_dir.path().join("output.json");
+    stdout(run(&["--format", "json", "--output", json_output.to_str().unwrap()]));
+    assert_eq!("[\n{\"amount\":1234567.89,\"count\":1234,\"day\":\"2022-01-05\"}\n]\n",
+               fs::read_to_string(json_output).unwrap());
+}
+
+#[test]
+fn pivot_table() {
+    let sales = "month,region,amount\njan,east,1\njan,east,2\njan,west,5\nfeb,west,7\n";
+    let output = query_fixture("pivot_fixture", sales, "pivot", &[
+        "--format", "show", "--pivot-rows", "month", "--pivot-column", "region",
+        "--pivot-value", "amount", "--pivot-aggregate", "sum"
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(stderr.contains("FILTER (WHERE region::text = 'east')"), "{}", stderr);
+    assert_eq!("month,east,west\nfeb,,7\njan,3,5\n", stdout(output));
+}
+
+#[test]
+fn top_values() {
+    let countries = "country\nfr\nde\nfr\n\"\"\nfr\nde\nit\n";
+    let output = query_fixture("top_fixture", countries, "top country 2", &["--format", "show", "--top-cumulative"]);
+    assert_eq!("\
+country,count,percent,cumulative_percent
+fr,3,42.86,42.86
+de,2,28.57,71.43
+", stdout(output));
+
+    // NULL and empty values are listed apart, after the top values, and apart from each other
+    let output = query_fixture("top_fixture", countries, "top region 1", &[
+        "--format", "show", "--top-group-blanks", "--top-cumulative", "--computed", "region = NULLIF(country, 'it')"
+    ]);
+    assert_eq!("\
+region,count,percent,cumulative_percent
+fr,3,42.86,42.86
+(NULL),1,14.29,
+(empty),1,14.29,
+", stdout(output));
+
+    let output = query_fixture("top_fixture", countries, "top missing", &["--format", "show"]);
+    assert_eq!(Some(2), output.status.code());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Its columns are: country"));
+}
+
+#[test]
+fn histogram() {
+    let prices = "price,name\n1,a\n2,b\n2,c\n,d\n4,e\n";
+    let output = query_fixture("hist_fixture", prices, "hist price 3", &[]);
+    assert_eq!(format!("\
+[1.0, 2.0) 1 |{}
+[2.0, 3.0) 2 |{}
+[3.0, 4.0] 1 |{}
+NULL or empty: 1
+", "#".repeat(33), "#".repeat(66), "#".repeat(33)), stdout(output));
+
+    let output = query_fixture("hist_fixture", prices, "hist name", &[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Column name has values which are not numbers, such as \"a\", \"b\""), "{}", stderr);
+    assert!(stderr.contains("use 'top name' instead"), "{}", stderr);
+}
+
+#[test]
+fn distinct_value_counts() {
+    let people = "country,email\nfr,a@x\nde,b@x\nfr,c@x\n\"\",d@x\nfr,e@x\nde,f@x\nit,g@x\n";
+    let output = query_fixture("cardinality_fixture", people, "cardinality email, Country", &["--format", "show"]);
+    assert_eq!("column_name,approximate_distinct\nemail,7\ncountry,4\n", stdout(output));
+
+    let output = query_fixture("cardinality_fixture", people, "cardinality country", &[
+        "--format", "show", "--exact-cardinality"
+    ]);
+    assert_eq!("column_name,approximate_distinct,exact_distinct\ncountry,4,4\n", stdout(output));
+
+    let output = query_fixture("cardinality_fixture", people, "cardinality country missing", &["--format", "show"]);
+    assert_eq!(Some(2), output.status.code());
+    assert!(String::from_utf8(output.stderr).unwrap()
+        .contains("Column missing is not in table cardinality_fixture. Its columns are: country, email"));
+}
+
+#[test]
+fn time_buckets() {
+    let events = "at,amount\n2022-03-12T12:00:00Z,5\n2022-03-14T12:00:00Z,1.5\n2022-03-14T13:00:00Z,\n,3\n";
+    let output = query_fixture("bucket_fixture", events, "bucket at day", &[
+        "--format", "show", "--timezone", "America/New_York"
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(stderr.contains("Generated query:\nWITH buckets AS ("), "{}", stderr);
+    // The missing day is filled in, and the days follow the timezone across daylight saving
+    assert_eq!("\
+bucket,count
+2022-03-12T00:00:00-05:00,1
+2022-03-13T00:00:00-05:00,0
+2022-03-14T00:00:00-04:00,2
+", stdout(output));
+
+    let output = query_fixture("bucket_fixture", events, "bucket at month sum:amount", &["--format", "show"]);
+    assert_eq!("bucket,sum\n2022-03-01T00:00:00Z,6.5\n", stdout(output));
+
+    let output = query_fixture("bucket_fixture", events, "bucket amount day", &["--format", "show"]);
+    assert_eq!(Some(8), output.status.code());
+    assert!(String::from_utf8(output.stderr).unwrap().contains(
+        "Column amount has values which cannot be cast to timestamptz, such as \"1.5\", \"3\", \"5\""
+    ));
+}
+
+#[test]
+fn pivot_of_mixed_case_columns() {
+    let sales = "Month,Sales Region,Amount\njan,east,1\njan,east,2\njan,west,5\nfeb,west,7\n";
+    let output = query_fixture("pivot_case_fixture", sales, "pivot", &[
+        "--format", "show", "--pivot-rows", "month", "--pivot-column", "\"Sales Region\"",
+        "--pivot-value", "AMOUNT", "--pivot-aggregate", "sum"
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(stderr.contains("FILTER (WHERE \"Sales Region\"::text = 'east')"), "{}", stderr);
+    assert_eq!("Month,east,west\nfeb,,7\njan,3,5\n", stdout(output));
+}
+
+#[test]
+fn top_values_of_mixed_case_column() {
+    let countries = "Country Code\nfr\nde\nfr\nit\n";
+    let output = query_fixture("top_case_fixture", countries, "top \"Country Code\" 1", &["--format", "show"]);
+    assert_eq!("Country Code,count,percent\nfr,2,50.00\n", stdout(output));
+}
+
+#[test]
+fn histogram_of_mixed_case_column() {
+    let prices = "Unit Price\n1\n2\n2\n\"\"\n4\n";
+    let output = query_fixture("hist_case_fixture", prices, "hist \"unit price\" 3", &[]);
+    let stdout = stdout(output);
+    assert!(stdout.starts_with("[1.0, 2.0) 1 |"), "{}", stdout);
+    assert!(stdout.ends_with("NULL or empty: 1\n"), "{}", stdout);
+}
+
+#[test]
+fn distinct_value_counts_of_mixed_case_columns() {
+    let people = "Country,Email\nfr,a@x\nde,b@x\nfr,c@x\n";
+    let output = query_fixture("cardinality_case_fixture", people, "cardinality EMAIL, \"Country\"", &[
+        "--format", "show", "--exact-cardinality"
+    ]);
+    assert_eq!("column_name,approximate_distinct,exact_distinct\nEmail,3,3\nCountry,2,2\n", stdout(output));
+
+    let output = query_fixture("cardinality_case_fixture", people, "cardinality email Email", &["--format", "show"]);
+    assert_eq!(Some(2), output.status.code());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Column Email is named more than once"));
+}
+
+#[test]
+fn time_buckets_of_mixed_case_columns() {
+    let events = "Created At,Amount\n2022-03-12T12:00:00Z,5\n2022-03-14T12:00:00Z,1.5\n";
+    let output = query_fixture("bucket_case_fixture", events, "bucket \"Created At\" month sum:amount", &[
+        "--format", "show", "--timezone", "UTC"
+    ]);
+    assert_eq!("bucket,sum\n2022-03-01T00:00:00Z,6.5\n", stdout(output));
+}
+
+#[test]
+fn computed_column_with_mixed_case_name() {
+    let amounts = "Amount\n1.25\n0.5\n";
+    let output = query_fixture("computed_case_fixture", amounts, "SELECT * FROM computed_case_fixture", &[
+        "--format", "show", "--computed", "\"Amount Cents\" = round(\"Amount\"::numeric * 100)"
+    ]);
+    assert_eq!("Amount,Amount Cents\n1.25,125\n0.5,50\n", stdout(output));
+}
+
+#[test]
+fn manifest_beside_output() {
+    use sha2::{Digest, Sha256};
+
+    let output_dir = TempDir::new().unwrap();
+    let output_file = output_dir.path().join("names.csv");
+    let query = "SELECT name FROM manifest_fixture ORDER BY name";
+    let output = query_fixture("manifest_fixture", NAMES, query, &[
+        "--format", "csv", "--output", output_file.to_str().unwrap(), "--manifest"
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(stderr.contains("names.csv.manifest.json"), "{}", stderr);
+    stdout(output);
+
+    let manifest: serde_json::Value = serde_json::from_str(
+        &fs::read_to_string(output_dir.path().join("names.csv.manifest.json")).unwrap()
+    ).unwrap();
+    let digest = Sha256::digest(fs::read(&output_file).unwrap());
+    let digest: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    assert_eq!(digest, manifest["sha256"]);
+    assert_eq!(3, manifest["rows"]);
+    assert_eq!(serde_json::json!(["name"]), manifest["columns"]);
+    assert_eq!(query, manifest["query"]);
+    assert!(manifest["source"].as_str().unwrap().ends_with("fixture.csv"));
+    assert_eq!(serde_json::json!([
+        {"name": "name", "kind": "text", "max_bytes": 8, "max_chars": 8, "empty": 0}
+    ]), manifest["source_profile"]);
+}
+
+#[test]
+fn manifest_of_empty_results() {
+    use sha2::{Digest, Sha256};
+
+    let output_dir = TempDir::new().unwrap();
+    let output_file = output_dir.path().join("none.csv");
+    let query = "SELECT name FROM manifest_empty_fixture WHERE name = 'nobody'";
+    let output = query_fixture("manifest_empty_fixture", NAMES, query, &[
+        "--format", "csv", "--output", output_file.to_str().unwrap(), "--manifest"
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(stderr.contains("Wrote 0 rows of CSV"), "{}", stderr);
+    assert!(stderr.contains("none.csv.manifest.json"), "{}", stderr);
+    stdout(output);
+    let manifest: serde_json::Value = serde_json::from_str(
+        &fs::read_to_string(output_dir.path().join("none.csv.manifest.json")).unwrap()
+    ).unwrap();
+    let digest = Sha256::digest(fs::read(&output_file).unwrap());
+    let digest: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    assert_eq!(digest, manifest["sha256"]);
+    assert_eq!(0, manifest["rows"]);
+
+    // Without the header, neither file is written, and both are named
+    let output_file = output_dir.path().join("nothing.csv");
+    let output = query_fixture("manifest_empty_fixture", NAMES, query, &[
+        "--format", "csv", "--output", output_file.to_str().unwrap(), "--manifest", "--no-empty-file"
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(stderr.contains("nothing.csv.manifest.json was written"), "{}", stderr);
+    stdout(output);
+    assert!(!output_file.exists());
+    assert!(!output_dir.path().join("nothing.csv.manifest.json").exists());
+}
+
+#[test]
+fn output_dir_for_derived_files() {
+    let output_dir = TempDir::new().unwrap();
+    let results_dir = output_dir.path().join("results/names");
+    let query = "SELECT name FROM output_dir_fixture ORDER BY name";
+    let args = ["--format", "json", "--output-dir", results_dir.to_str().unwrap(), "--manifest"];
+    let output = query_fixture("output_dir_fixture", NAMES, query, &args);
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    assert!(stderr.contains("results/names/fixture.json"), "{}", stderr);
+    stdout(output);
+    let json: serde_json::Value = serde_json::from_str(
+        &fs::read_to_string(results_dir.join("fixture.json")).unwrap()
+    ).unwrap();
+    assert_eq!(3, json.as_array().unwrap().len());
+    assert!(results_dir.join("fixture.json.manifest.json").exists());
+
+    // Existing output is never overwritten
+    let output = query_fixture("output_dir_fixture", NAMES, query, &args);
+    assert_eq!(Some(9), output.status.code());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Delete existing file"));
+
+    // An explicit output file takes precedence
+    let output_file = output_dir.path().join("explicit.csv");
+    let output = query_fixture("output_dir_fixture", NAMES, query, &[
+        "--format", "csv", "--output-dir", results_dir.to_str().unwrap(), "--output", output_file.to_str().unwrap()
+    ]);
+    stdout(output);
+    assert!(output_file.exists());
+    assert!(!results_dir.join("fixture.csv").exists());
+}
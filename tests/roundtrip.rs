@@ -0,0 +1,107 @@
+/*
+ * data-sifter
+ * Copyright © 2022 Anand Beh
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Property tests checking that arbitrary values survive the trip from the CSV dataset,
+//! into the database, and back out as query results.
+
+#![cfg(feature = "integration-test")]
+
+mod common;
+
+use std::fs;
+use futures_util::StreamExt;
+use itertools::Itertools;
+use proptest::prelude::*;
+use common::{config_home, run_data_sifter, test_url};
+
+/// Characters which are troublesome for CSV or SQL
+const SPECIAL_CHARS: &[char] = &[
+    '"', '\'', ',', ';', '\n', '\r', '\t', '\\', ' ', '$', '%', '\u{301}', '\u{FEFF}', '\u{1F600}'
+];
+
+fn value() -> impl Strategy<Value = String> {
+    // Postgres text cannot contain NUL
+    let any_char = any::<char>().prop_filter("NUL", |c| *c != '\0');
+    let char = prop_oneof![
+        3 => prop::sample::select(SPECIAL_CHARS),
+        2 => any_char,
+        1 => prop::char::range('a', 'z')
+    ];
+    prop_oneof![
+        8 => prop::collection::vec(char.clone(), 0..24),
+        1 => prop::collection::vec(char, 256..2048)
+    ].prop_map(|chars| chars.into_iter().collect())
+}
+
+fn records() -> impl Strategy<Value = Vec<Vec<String>>> {
+    (1..4usize).prop_flat_map(|columns| {
+        prop::collection::vec(prop::collection::vec(value(), columns), 1..8)
+    })
+}
+
+/// Encodes records as CSV independently of the csv crate, by quoting every field
+fn encode_csv(header: &[String], records: &[Vec<String>]) -> String {
+    let encode_record = |record: &[String]| {
+        record.iter().map(|field| format!("\"{}\"", field.replace('"', "\"\""))).join(",")
+    };
+    let mut csv = encode_record(header);
+    for record in records {
+        csv.push('\n');
+        csv.push_str(&encode_record(record));
+    }
+    csv.push('\n');
+    csv
+}
+
+fn decode_csv(csv: &[u8]) -> (Vec<String>, Vec<Vec<String>>) {
+    async_std::task::block_on(async {
+        let mut reader = csv_async::AsyncReader::from_reader(csv);
+        let header = reader.headers().await.unwrap().iter().map(String::from).collect();
+        let records = reader.records()
+            .map(|record| record.unwrap().iter().map(String::from).collect())
+            .collect()
+            .await;
+        (header, records)
+    })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(32))]
+
+    #[test]
+    fn values_round_trip(mut records in records()) {
+        let header: Vec<String> = (0..records[0].len()).map(|index| format!("c{}", index)).collect();
+        let csv_dir = tempfile::tempdir().unwrap();
+        let csv_file = csv_dir.path().join("values.csv");
+        fs::write(&csv_file, encode_csv(&header, &records)).unwrap();
+
+        let config_home = config_home(&test_url());
+        let query = format!("SELECT {} FROM property_round_trip", header.join(", "));
+        let output = run_data_sifter(&config_home, &[
+            "--input", csv_file.to_str().unwrap(), "--table", "property_round_trip", "--replace",
+            "--query", &query, "--format", "show"
+        ]);
+        prop_assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+        let (output_header, mut output_records) = decode_csv(&output.stdout);
+        prop_assert_eq!(header, output_header);
+        // Rows are inserted concurrently, so their order is not preserved
+        records.sort();
+        output_records.sort();
+        prop_assert_eq!(records, output_records);
+    }
+}